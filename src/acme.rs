@@ -0,0 +1,875 @@
+//! ACME client (RFC 8555)
+//!
+//! `EspAcme` obtains a certificate for the public domain names of the device from an ACME
+//! certificate authority (Let's Encrypt by default), stores it in NVS, and renews it before it
+//! expires. Every new certificate, as well as the stored one on startup, is passed to a reload
+//! callback, which typically restarts the HTTPS server with it:
+//!
+//! ```ignore
+//! let mut http = EspHttpServer::new(&server::Configuration {
+//!     uri_match_wildcard: true,
+//!     ..Default::default()
+//! })?;
+//!
+//! let responder = Http01Responder::register(&mut http)?;
+//!
+//! let (sender, receiver) = mpsc::channel();
+//!
+//! let _acme = EspAcme::new(
+//!     &Configuration {
+//!         domains: vec!["device.example.com".into()],
+//!         contact: Some("admin@example.com".into()),
+//!         ..Default::default()
+//!     },
+//!     Challenge::Http01(responder),
+//!     nvs_partition.clone(),
+//!     move |certificate| {
+//!         let _ = sender.send(certificate.clone());
+//!     },
+//! )?;
+//!
+//! for certificate in receiver {
+//!     https.update_configuration(&certificate.apply(&https_conf))?;
+//! }
+//! ```
+//!
+//! The domains are validated with one of two challenges:
+//! - HTTP-01: the certificate authority fetches a token from port 80 of the device, served by
+//!   `Http01Responder` on an HTTP server with wildcard URI matching enabled
+//! - DNS-01: the certificate authority looks up a TXT record, which a `DnsSolver` publishes
+//!   through the API of the DNS provider; it is the only challenge for wildcard domains, and
+//!   for devices which cannot be reached from the Internet
+//!
+//! The expiry of the certificates is checked against the system time, which has to be set
+//! (e.g. with `EspSntp`).
+//!
+//! Note: This module requires the `experimental` cargo feature to be enabled.
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::collections::BTreeMap;
+use std::string::{String, ToString};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+
+use esp_idf_sys::*;
+
+use crate::http::client::{self, EspHttpConnection};
+use crate::http::server::EspHttpServer;
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+use crate::private::base64;
+use crate::private::json::{self, Json};
+use crate::private::mutex::Mutex;
+use crate::private::sha256;
+use crate::private::x509;
+use crate::tls::keys::{CsrConfiguration, Curve, KeyPair, KeyType};
+#[cfg(esp_idf_esp_https_server_enable)]
+use crate::tls::X509;
+
+/// The production directory of Let's Encrypt
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// The staging directory of Let's Encrypt, with higher rate limits and untrusted certificates
+pub const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+const NAMESPACE: &str = "acme";
+const ACCOUNT_KEY: &str = "account_key";
+const CERTIFICATE: &str = "certificate";
+const PRIVATE_KEY: &str = "private_key";
+
+/// Certificate chains are a few KB, anything larger is an error at best
+const MAX_RESPONSE_LEN: usize = 16384;
+
+const POLL_ATTEMPTS: u32 = 20;
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Publishes the TXT records of the DNS-01 challenges
+pub trait DnsSolver: Send {
+    /// Publishes the TXT record `name` (e.g. `_acme-challenge.device.example.com`) with `value`
+    fn publish(&mut self, name: &str, value: &str) -> Result<(), EspError>;
+
+    /// Removes the TXT record, once the challenge is over
+    fn remove(&mut self, name: &str, value: &str) -> Result<(), EspError>;
+}
+
+/// Serves the tokens of the HTTP-01 challenges under `/.well-known/acme-challenge/`
+#[derive(Clone)]
+pub struct Http01Responder {
+    tokens: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl Http01Responder {
+    /// Registers the challenge handler with an HTTP server listening on port 80
+    ///
+    /// The server must have `uri_match_wildcard` enabled, and should outlive the returned
+    /// instance.
+    pub fn register(server: &mut EspHttpServer) -> Result<Self, EspError> {
+        let this = Self {
+            tokens: Arc::new(Mutex::new(BTreeMap::new())),
+        };
+
+        let tokens = this.tokens.clone();
+
+        server.fn_handler(
+            "/.well-known/acme-challenge/*",
+            Method::Get,
+            move |mut request| {
+                let key_authorization = {
+                    let token = request.connection().path().rsplit('/').next().unwrap_or("");
+
+                    tokens.lock().get(token).cloned()
+                };
+
+                match key_authorization {
+                    Some(key_authorization) => {
+                        request
+                            .into_response(200, None, &[("Content-Type", "text/plain")])?
+                            .write_all(key_authorization.as_bytes())?;
+                    }
+                    None => {
+                        request.into_status_response(404)?;
+                    }
+                }
+
+                Ok(())
+            },
+        )?;
+
+        Ok(this)
+    }
+
+    fn insert(&self, token: &str, key_authorization: &str) {
+        self.tokens
+            .lock()
+            .insert(token.into(), key_authorization.into());
+    }
+
+    fn remove(&self, token: &str) {
+        self.tokens.lock().remove(token);
+    }
+}
+
+pub enum Challenge {
+    Http01(Http01Responder),
+    Dns01(Box<dyn DnsSolver>),
+}
+
+impl Challenge {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Http01(_) => "http-01",
+            Self::Dns01(_) => "dns-01",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The directory URL of the certificate authority
+    pub directory_url: String,
+    /// The domain names of the certificate, the first one being its common name
+    pub domains: Vec<String>,
+    /// An e-mail address, for the expiry notices of the certificate authority
+    pub contact: Option<String>,
+    /// How long before its expiry the certificate is renewed
+    pub renew_before: Duration,
+    /// How often the expiry of the certificate is checked
+    pub check_interval: Duration,
+    /// How long to wait after a failed issuance before retrying
+    pub retry_interval: Duration,
+    /// How long to wait after publishing the DNS-01 records, before the validation starts
+    pub dns_propagation: Duration,
+    pub timeout: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            directory_url: LETS_ENCRYPT.into(),
+            domains: Vec::new(),
+            contact: None,
+            renew_before: Duration::from_secs(30 * 24 * 3600),
+            check_interval: Duration::from_secs(12 * 3600),
+            retry_interval: Duration::from_secs(3600),
+            dns_propagation: Duration::from_secs(60),
+            timeout: Duration::from_secs(30),
+            stack_size: 12288,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Certificate {
+    /// The certificate followed by its issuers, PEM encoded
+    pub chain: String,
+    /// The private key of the certificate, PEM encoded
+    pub private_key: String,
+    /// The expiry of the certificate, since the UNIX epoch
+    pub not_after: Duration,
+}
+
+impl Certificate {
+    /// `conf` with the certificate, for `EspHttpServer::update_configuration`
+    ///
+    /// The server configuration borrows its certificate for `'static`, hence the certificate
+    /// and its key are leaked, i.e. a few KB on every renewal.
+    #[cfg(esp_idf_esp_https_server_enable)]
+    pub fn apply(
+        &self,
+        conf: &crate::http::server::Configuration,
+    ) -> crate::http::server::Configuration {
+        let leak = |pem: &str| -> &'static [u8] {
+            Box::leak(format!("{}\0", pem).into_bytes().into_boxed_slice())
+        };
+
+        crate::http::server::Configuration {
+            server_certificate: Some(X509::pem_until_nul(leak(&self.chain))),
+            private_key: Some(X509::pem_until_nul(leak(&self.private_key))),
+            ..*conf
+        }
+    }
+
+    fn is_due(&self, renew_before: Duration) -> bool {
+        // Without the system time, the certificate is assumed to be valid
+        now()
+            .map(|now| now + renew_before >= self.not_after)
+            .unwrap_or(false)
+    }
+}
+
+enum Command {
+    Renew,
+    Stop,
+}
+
+/// Keeps a certificate issued and renewed, from a background thread
+pub struct EspAcme {
+    certificate: Arc<Mutex<Option<Certificate>>>,
+    sender: mpsc::Sender<Command>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspAcme {
+    pub fn new<T, F>(
+        conf: &Configuration,
+        challenge: Challenge,
+        partition: EspNvsPartition<T>,
+        reload: F,
+    ) -> Result<Self, EspError>
+    where
+        T: NvsPartitionId + Send + 'static,
+        F: FnMut(&Certificate) + Send + 'static,
+    {
+        if conf.domains.is_empty() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+
+        let certificate = Arc::new(Mutex::new(load_certificate(&nvs)));
+
+        let (sender, receiver) = mpsc::channel();
+
+        let mut worker = Worker {
+            conf: conf.clone(),
+            challenge,
+            nvs,
+            certificate: certificate.clone(),
+            reload: Box::new(reload),
+        };
+
+        let join_handle = thread::Builder::new()
+            .name("acme".into())
+            .stack_size(conf.stack_size)
+            .spawn(move || worker.run(receiver))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        info!("Started ACME for {:?}", conf.domains);
+
+        Ok(Self {
+            certificate,
+            sender,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// The current certificate, if any
+    pub fn certificate(&self) -> Option<Certificate> {
+        self.certificate.lock().clone()
+    }
+
+    /// Renews the certificate right away, e.g. after its key leaked or its domains changed
+    pub fn renew(&self) {
+        let _ = self.sender.send(Command::Renew);
+    }
+}
+
+impl Drop for EspAcme {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Command::Stop);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+struct Worker<T: NvsPartitionId> {
+    conf: Configuration,
+    challenge: Challenge,
+    nvs: EspNvs<T>,
+    certificate: Arc<Mutex<Option<Certificate>>>,
+    reload: Box<dyn FnMut(&Certificate) + Send>,
+}
+
+impl<T: NvsPartitionId> Worker<T> {
+    fn run(&mut self, receiver: mpsc::Receiver<Command>) {
+        if let Some(certificate) = self.certificate.lock().clone() {
+            (self.reload)(&certificate);
+        }
+
+        let mut forced = false;
+
+        loop {
+            let due = forced
+                || self
+                    .certificate
+                    .lock()
+                    .as_ref()
+                    .map(|certificate| certificate.is_due(self.conf.renew_before))
+                    .unwrap_or(true);
+
+            let wait = if due {
+                match self.issue() {
+                    Ok(certificate) => {
+                        info!(
+                            "Issued a certificate for {:?}, valid until {}s",
+                            self.conf.domains,
+                            certificate.not_after.as_secs()
+                        );
+
+                        if let Err(e) = self.store(&certificate) {
+                            warn!("Failed to store the certificate: {}", e);
+                        }
+
+                        *self.certificate.lock() = Some(certificate.clone());
+
+                        (self.reload)(&certificate);
+
+                        forced = false;
+
+                        self.conf.check_interval
+                    }
+                    Err(e) => {
+                        warn!("Failed to issue a certificate: {}", e);
+
+                        self.conf.retry_interval
+                    }
+                }
+            } else {
+                self.conf.check_interval
+            };
+
+            match receiver.recv_timeout(wait) {
+                Ok(Command::Renew) => forced = true,
+                Ok(Command::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+            }
+        }
+    }
+
+    fn issue(&mut self) -> Result<Certificate, EspError> {
+        let mut account_key = self.account_key()?;
+
+        let mut session = Session::new(&self.conf, &mut account_key)?;
+
+        session.new_account(self.conf.contact.as_deref())?;
+
+        let (order_url, order) = session.new_order(&self.conf.domains)?;
+
+        let authorizations = order
+            .get("authorizations")
+            .and_then(Json::as_array)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|url| url.as_str().map(String::from))
+            .collect::<Vec<_>>();
+
+        for authorization in &authorizations {
+            self.authorize(&mut session, authorization)?;
+        }
+
+        let finalize = order
+            .get("finalize")
+            .and_then(Json::as_str)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?;
+
+        let mut key = KeyPair::generate(KeyType::Ec(Curve::P256))?;
+        let csr = key.csr_der(&CsrConfiguration {
+            subject: format!("CN={}", self.conf.domains[0]),
+            dns_names: self.conf.domains.clone(),
+            ..Default::default()
+        })?;
+
+        session.post(
+            finalize,
+            Some(&json::object([("csr", base64::encode_url(&csr).into())])),
+        )?;
+
+        let order = session.poll(&order_url)?;
+
+        let certificate_url = order
+            .get("certificate")
+            .and_then(Json::as_str)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?;
+
+        let chain = session.post(certificate_url, None)?.body;
+
+        let not_after = x509::not_after(&chain)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?;
+
+        Ok(Certificate {
+            chain,
+            private_key: key.private_key_pem()?,
+            not_after,
+        })
+    }
+
+    fn authorize(&mut self, session: &mut Session, url: &str) -> Result<(), EspError> {
+        let authorization = session.post_json(url)?;
+
+        match authorization.get("status").and_then(Json::as_str) {
+            // Validated by an earlier order
+            Some("valid") => return Ok(()),
+            Some("pending") => (),
+            status => {
+                warn!("Unexpected authorization status {:?}", status);
+
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+        }
+
+        let domain = authorization
+            .get("identifier")
+            .and_then(|identifier| identifier.get("value"))
+            .and_then(Json::as_str)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?;
+
+        let kind = self.challenge.kind();
+
+        let challenge = authorization
+            .get("challenges")
+            .and_then(Json::as_array)
+            .unwrap_or(&[])
+            .iter()
+            .find(|challenge| challenge.get("type").and_then(Json::as_str) == Some(kind))
+            .ok_or_else(|| {
+                warn!("No {} challenge offered for {}", kind, domain);
+
+                EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>()
+            })?;
+
+        let (token, challenge_url) = match (
+            challenge.get("token").and_then(Json::as_str),
+            challenge.get("url").and_then(Json::as_str),
+        ) {
+            (Some(token), Some(url)) => (token, url),
+            _ => return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>()),
+        };
+
+        let key_authorization = format!("{}.{}", token, session.thumbprint);
+
+        info!("Validating {} with {}", domain, kind);
+
+        // The wildcard domains are validated on their base domain
+        let record = format!("_acme-challenge.{}", domain.trim_start_matches("*."));
+        let record_value = base64::encode_url(&sha256::digest(key_authorization.as_bytes()));
+
+        match &mut self.challenge {
+            Challenge::Http01(responder) => responder.insert(token, &key_authorization),
+            Challenge::Dns01(solver) => {
+                solver.publish(&record, &record_value)?;
+
+                thread::sleep(self.conf.dns_propagation);
+            }
+        }
+
+        // An empty object starts the validation
+        let result = session
+            .post(challenge_url, Some(&Json::Object(Vec::new())))
+            .and_then(|_| session.poll(url))
+            .map(|_| ());
+
+        match &mut self.challenge {
+            Challenge::Http01(responder) => responder.remove(token),
+            Challenge::Dns01(solver) => {
+                if let Err(e) = solver.remove(&record, &record_value) {
+                    warn!("Failed to remove the DNS-01 record {}: {}", record, e);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// The account key, generated on the first issuance
+    fn account_key(&mut self) -> Result<KeyPair, EspError> {
+        if let Some(pem) = load(&self.nvs, ACCOUNT_KEY) {
+            return KeyPair::from_private_key(pem.as_bytes());
+        }
+
+        // ES256, the algorithm all the certificate authorities support
+        let mut key = KeyPair::generate(KeyType::Ec(Curve::P256))?;
+
+        self.nvs
+            .set_raw(ACCOUNT_KEY, key.private_key_pem()?.as_bytes())?;
+
+        Ok(key)
+    }
+
+    fn store(&mut self, certificate: &Certificate) -> Result<(), EspError> {
+        self.nvs
+            .set_raw(CERTIFICATE, certificate.chain.as_bytes())?;
+        self.nvs
+            .set_raw(PRIVATE_KEY, certificate.private_key.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+struct Response {
+    status: u16,
+    location: Option<String>,
+    body: String,
+}
+
+/// The requests of an issuance, signed with the account key
+struct Session<'a> {
+    connection: EspHttpConnection,
+    key: &'a mut KeyPair,
+    jwk: Json,
+    /// The JWK thumbprint of the account key (RFC 7638), part of the key authorizations
+    thumbprint: String,
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+    nonce: Option<String>,
+    /// The account URL, once registered
+    kid: Option<String>,
+}
+
+impl<'a> Session<'a> {
+    fn new(conf: &Configuration, key: &'a mut KeyPair) -> Result<Self, EspError> {
+        let connection = EspHttpConnection::new(&client::Configuration {
+            timeout: Some(conf.timeout),
+            #[cfg(all(not(esp_idf_version = "4.3"), esp_idf_mbedtls_certificate_bundle))]
+            crt_bundle_attach: Some(esp_crt_bundle_attach),
+            ..Default::default()
+        })?;
+
+        let (x, y) = key.public_point()?;
+
+        // The members in lexicographic order, as the thumbprint requires
+        let jwk = json::object([
+            ("crv", "P-256".into()),
+            ("kty", "EC".into()),
+            ("x", base64::encode_url(&x).into()),
+            ("y", base64::encode_url(&y).into()),
+        ]);
+
+        let thumbprint = base64::encode_url(&sha256::digest(jwk.to_string().as_bytes()));
+
+        let mut this = Self {
+            connection,
+            key,
+            jwk,
+            thumbprint,
+            new_nonce: String::new(),
+            new_account: String::new(),
+            new_order: String::new(),
+            nonce: None,
+            kid: None,
+        };
+
+        let response = this.request(Method::Get, &conf.directory_url, None)?;
+
+        let directory = parse(&response)?;
+
+        let url = |name| {
+            directory
+                .get(name)
+                .and_then(Json::as_str)
+                .map(String::from)
+                .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)
+        };
+
+        this.new_nonce = url("newNonce")?;
+        this.new_account = url("newAccount")?;
+        this.new_order = url("newOrder")?;
+
+        Ok(this)
+    }
+
+    /// Registers the account, or looks it up if the key is registered already
+    fn new_account(&mut self, contact: Option<&str>) -> Result<(), EspError> {
+        let contact = contact
+            .map(|contact| vec![format!("mailto:{}", contact).into()])
+            .unwrap_or_default();
+
+        let new_account = self.new_account.clone();
+
+        let response = self.post(
+            &new_account,
+            Some(&json::object([
+                ("termsOfServiceAgreed", true.into()),
+                ("contact", Json::Array(contact)),
+            ])),
+        )?;
+
+        self.kid = Some(
+            response
+                .location
+                .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?,
+        );
+
+        Ok(())
+    }
+
+    /// Returns the URL and the object of the new order
+    fn new_order(&mut self, domains: &[String]) -> Result<(String, Json), EspError> {
+        let identifiers = domains
+            .iter()
+            .map(|domain| json::object([("type", "dns".into()), ("value", domain.as_str().into())]))
+            .collect();
+
+        let new_order = self.new_order.clone();
+
+        let response = self.post(
+            &new_order,
+            Some(&json::object([("identifiers", Json::Array(identifiers))])),
+        )?;
+
+        let order = parse(&response)?;
+
+        let location = response
+            .location
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?;
+
+        Ok((location, order))
+    }
+
+    /// Polls an authorization or an order until it is valid
+    fn poll(&mut self, url: &str) -> Result<Json, EspError> {
+        for _ in 0..POLL_ATTEMPTS {
+            let object = self.post_json(url)?;
+
+            match object.get("status").and_then(Json::as_str) {
+                Some("valid") => return Ok(object),
+                Some("pending") | Some("processing") | Some("ready") => {
+                    thread::sleep(POLL_INTERVAL)
+                }
+                status => {
+                    warn!(
+                        "Validation failed with status {:?}: {}",
+                        status,
+                        object
+                            .get("challenges")
+                            .or_else(|| object.get("error"))
+                            .map(Json::to_string)
+                            .unwrap_or_default()
+                    );
+
+                    return Err(EspError::from_infallible::<ESP_FAIL>());
+                }
+            }
+        }
+
+        Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+    }
+
+    /// POST-as-GET, returning the JSON object
+    fn post_json(&mut self, url: &str) -> Result<Json, EspError> {
+        let response = self.post(url, None)?;
+
+        parse(&response)
+    }
+
+    /// Posts `payload` signed, or an empty payload for POST-as-GET, retrying once with a fresh
+    /// nonce if the server rejected the previous one
+    fn post(&mut self, url: &str, payload: Option<&Json>) -> Result<Response, EspError> {
+        let payload = payload.map(Json::to_string).unwrap_or_default();
+
+        for attempt in 0..2 {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => {
+                    let new_nonce = self.new_nonce.clone();
+
+                    self.request(Method::Head, &new_nonce, None)?;
+
+                    self.nonce
+                        .take()
+                        .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?
+                }
+            };
+
+            let jws = self.sign(url, &nonce, &payload)?;
+
+            let response = self.request(Method::Post, url, Some(&jws))?;
+
+            if (200..300).contains(&response.status) {
+                return Ok(response);
+            }
+
+            if response.status == 400 && response.body.contains("badNonce") && attempt == 0 {
+                continue;
+            }
+
+            warn!(
+                "ACME request to {} failed with status {}: {}",
+                url, response.status, response.body
+            );
+
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+
+        Err(EspError::from_infallible::<ESP_FAIL>())
+    }
+
+    /// The flattened JWS of `payload`, with the account key as JWK until the account is known
+    fn sign(&mut self, url: &str, nonce: &str, payload: &str) -> Result<String, EspError> {
+        let key = match &self.kid {
+            Some(kid) => ("kid", kid.as_str().into()),
+            None => ("jwk", self.jwk.clone()),
+        };
+
+        let protected = json::object([
+            ("alg", "ES256".into()),
+            key,
+            ("nonce", nonce.into()),
+            ("url", url.into()),
+        ]);
+
+        let protected = base64::encode_url(protected.to_string().as_bytes());
+        let payload = base64::encode_url(payload.as_bytes());
+
+        let signature = self
+            .key
+            .sign_es256(format!("{}.{}", protected, payload).as_bytes())?;
+
+        Ok(json::object([
+            ("protected", protected.into()),
+            ("payload", payload.into()),
+            ("signature", base64::encode_url(&signature).into()),
+        ])
+        .to_string())
+    }
+
+    fn request(
+        &mut self,
+        method: Method,
+        url: &str,
+        body: Option<&str>,
+    ) -> Result<Response, EspError> {
+        let body = body.unwrap_or("");
+        let len = body.len().to_string();
+
+        let headers = [
+            ("Content-Type", "application/jose+json"),
+            ("Content-Length", len.as_str()),
+        ];
+
+        let headers: &[(&str, &str)] = if method == Method::Post {
+            &headers
+        } else {
+            &[]
+        };
+
+        self.connection.initiate_request(method, url, headers)?;
+
+        let mut offset = 0;
+        while offset < body.len() {
+            offset += self.connection.write(&body.as_bytes()[offset..])?;
+        }
+
+        self.connection.initiate_response()?;
+
+        if let Some(nonce) = self.connection.header("Replay-Nonce") {
+            self.nonce = Some(nonce.into());
+        }
+
+        let status = self.connection.status();
+        let location = self.connection.header("Location").map(String::from);
+
+        let mut response = Vec::new();
+        let mut buf = [0; 512];
+
+        loop {
+            let len = self.connection.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+
+            if response.len() + len > MAX_RESPONSE_LEN {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+            }
+
+            response.extend_from_slice(&buf[..len]);
+        }
+
+        let body = String::from_utf8(response)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>())?;
+
+        Ok(Response {
+            status,
+            location,
+            body,
+        })
+    }
+}
+
+fn parse(response: &Response) -> Result<Json, EspError> {
+    Json::parse(&response.body).ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)
+}
+
+fn now() -> Option<Duration> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        // Before 2020, the time has not been set
+        .filter(|now| now.as_secs() > 1_577_836_800)
+}
+
+fn load<T: NvsPartitionId>(nvs: &EspNvs<T>, name: &str) -> Option<String> {
+    let len = nvs.blob_len(name).ok().flatten()?;
+
+    let mut buf = vec![0; len];
+
+    let value = nvs.get_raw(name, &mut buf).ok().flatten()?;
+
+    core::str::from_utf8(value).ok().map(String::from)
+}
+
+fn load_certificate<T: NvsPartitionId>(nvs: &EspNvs<T>) -> Option<Certificate> {
+    let chain = load(nvs, CERTIFICATE)?;
+    let private_key = load(nvs, PRIVATE_KEY)?;
+
+    let not_after = x509::not_after(&chain)?;
+
+    Some(Certificate {
+        chain,
+        private_key,
+        not_after,
+    })
+}
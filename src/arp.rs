@@ -0,0 +1,372 @@
+//! ARP table inspection, gratuitous ARP and IP conflict detection
+//!
+//! Helpers around the ARP layer of lwIP, for diagnosing the "device unreachable after a DHCP
+//! renewal" class of issues, which are usually caused by stale ARP caches on the network or by
+//! another host using the same address:
+//!
+//! - `entries` lists the ARP table entries of an interface, and `flush` discards them
+//! - `announce` sends a gratuitous ARP, refreshing the caches of the other hosts
+//! - `probe` asks whether a host answers for an address
+//!
+//! `EspArpAnnouncer` sends gratuitous ARPs each time an interface gets an address, after
+//! checking that no other host already uses it:
+//!
+//! ```ignore
+//! let _announcer = EspArpAnnouncer::new(&Default::default(), &sysloop, |ip, mac| {
+//!     warn!("{} is also used by {:02x?}", ip, mac);
+//! })?;
+//! ```
+//!
+//! The lwIP functions are run in the context of its TCP/IP thread, as required by lwIP.
+use core::ffi;
+use core::ptr;
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::ipv4;
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{EspSubscription, EspSystemEventLoop, System};
+use crate::netif::{Interface, IpEvent};
+use crate::private::common::*;
+
+/// The lwIP default, which ESP-IDF does not override
+const ARP_TABLE_SIZE: usize = 10;
+
+#[repr(C)]
+struct EthAddr {
+    addr: [u8; 6],
+}
+
+extern "C" {
+    fn etharp_get_entry(
+        i: usize,
+        ipaddr: *mut *mut esp_ip4_addr_t,
+        netif: *mut *mut ffi::c_void,
+        eth_ret: *mut *mut EthAddr,
+    ) -> ffi::c_int;
+
+    fn etharp_cleanup_netif(netif: *mut ffi::c_void);
+
+    fn etharp_request(netif: *mut ffi::c_void, ipaddr: *const esp_ip4_addr_t) -> i8;
+
+    fn tcpip_callback(
+        function: Option<unsafe extern "C" fn(ctx: *mut ffi::c_void)>,
+        ctx: *mut ffi::c_void,
+    ) -> i8;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ArpEntry {
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub ip: ipv4::Ipv4Addr,
+    pub mac: [u8; 6],
+}
+
+/// Returns the resolved entries of the ARP table of `interface`
+pub fn entries(interface: &Interface) -> Result<Vec<ArpEntry>, EspError> {
+    let netif = netif(interface)?;
+
+    tcpip_exec(move || {
+        let mut entries = Vec::new();
+
+        for index in 0..ARP_TABLE_SIZE {
+            let mut ip: *mut esp_ip4_addr_t = ptr::null_mut();
+            let mut entry_netif: *mut ffi::c_void = ptr::null_mut();
+            let mut mac: *mut EthAddr = ptr::null_mut();
+
+            let stable = unsafe { etharp_get_entry(index, &mut ip, &mut entry_netif, &mut mac) };
+
+            if stable != 0 && entry_netif as usize == netif && !ip.is_null() && !mac.is_null() {
+                entries.push(ArpEntry {
+                    ip: Newtype(unsafe { *ip }).into(),
+                    mac: unsafe { (*mac).addr },
+                });
+            }
+        }
+
+        entries
+    })
+}
+
+/// Discards all the ARP table entries of `interface`
+pub fn flush(interface: &Interface) -> Result<(), EspError> {
+    let netif = netif(interface)?;
+
+    tcpip_exec(move || unsafe { etharp_cleanup_netif(netif as *mut _) })?;
+
+    info!("Flushed the ARP table of {:?}", interface);
+
+    Ok(())
+}
+
+/// Sends a gratuitous ARP for the address of `interface`;
+/// fails with `ESP_ERR_INVALID_STATE` if it has no address
+pub fn announce(interface: &Interface) -> Result<(), EspError> {
+    let handle = interface.handle()?;
+
+    let ip = ip_of(handle)?;
+
+    request(handle, ip)
+}
+
+/// Sends an ARP request for `ip` on `interface`, and waits for `timeout` for a host to answer,
+/// returning its MAC address
+///
+/// When `ip` is the address of the interface itself, the answer comes from another host
+/// using the same address.
+pub fn probe(
+    interface: &Interface,
+    ip: ipv4::Ipv4Addr,
+    timeout: Duration,
+) -> Result<Option<[u8; 6]>, EspError> {
+    probe_handle(
+        interface.handle()?,
+        Newtype::<esp_ip4_addr_t>::from(ip).0,
+        timeout,
+    )
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The number of gratuitous ARPs sent each time an address is obtained
+    pub announcements: u8,
+    pub announce_interval: Duration,
+    /// Probe the address before announcing it, and skip the announcements if another host
+    /// answers for it
+    pub detect_conflicts: bool,
+    pub probe_timeout: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            announcements: 2,
+            announce_interval: Duration::from_secs(2),
+            detect_conflicts: true,
+            probe_timeout: Duration::from_secs(1),
+            stack_size: 4096,
+        }
+    }
+}
+
+struct Assignment {
+    handle: usize,
+    ip: esp_ip4_addr_t,
+}
+
+/// Announces the addresses obtained by the interfaces with gratuitous ARPs,
+/// reporting the conflicting ones to a callback
+pub struct EspArpAnnouncer {
+    subscription: Option<EspSubscription<System>>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspArpAnnouncer {
+    pub fn new(
+        conf: &Configuration,
+        sysloop: &EspSystemEventLoop,
+        on_conflict: impl Fn(ipv4::Ipv4Addr, [u8; 6]) + Send + 'static,
+    ) -> Result<Self, EspError> {
+        let (sender, receiver) = mpsc::channel();
+
+        let join_handle = {
+            let conf = conf.clone();
+
+            thread::Builder::new()
+                .name("arp".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, receiver, on_conflict))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        let subscription = sysloop.subscribe(move |event: &IpEvent| {
+            if let IpEvent::DhcpIpAssigned(assignment) = event {
+                let _ = sender.send(Assignment {
+                    handle: assignment.netif_handle as usize,
+                    ip: Newtype::<esp_ip4_addr_t>::from(assignment.ip_settings.ip).0,
+                });
+            }
+        })?;
+
+        Ok(Self {
+            subscription: Some(subscription),
+            join_handle: Some(join_handle),
+        })
+    }
+
+    fn run(
+        conf: Configuration,
+        receiver: mpsc::Receiver<Assignment>,
+        on_conflict: impl Fn(ipv4::Ipv4Addr, [u8; 6]),
+    ) {
+        // Ends when the subscription, and thus the sender, is dropped
+        while let Ok(assignment) = receiver.recv() {
+            let handle = assignment.handle as *mut esp_netif_t;
+            let ip: ipv4::Ipv4Addr = Newtype(assignment.ip).into();
+
+            if conf.detect_conflicts {
+                match probe_handle(handle, assignment.ip, conf.probe_timeout) {
+                    Ok(Some(mac)) => {
+                        warn!("IP conflict: {} is also used by {:02x?}", ip, mac);
+
+                        on_conflict(ip, mac);
+                        continue;
+                    }
+                    Ok(None) => (),
+                    Err(e) => warn!("Failed to probe {}: {}", ip, e),
+                }
+            }
+
+            for index in 0..conf.announcements {
+                if index > 0 {
+                    thread::sleep(conf.announce_interval);
+                }
+
+                if let Err(e) = request(handle, assignment.ip) {
+                    warn!("Failed to announce {}: {}", ip, e);
+                    break;
+                }
+            }
+
+            info!("Announced {}", ip);
+        }
+    }
+}
+
+impl Drop for EspArpAnnouncer {
+    fn drop(&mut self) {
+        self.subscription = None;
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+fn probe_handle(
+    handle: *mut esp_netif_t,
+    ip: esp_ip4_addr_t,
+    timeout: Duration,
+) -> Result<Option<[u8; 6]>, EspError> {
+    let netif = impl_of(handle)?;
+
+    let mut own_mac = [0_u8; 6];
+    esp!(unsafe { esp_netif_get_mac(handle, own_mac.as_mut_ptr()) })?;
+
+    request(handle, ip)?;
+
+    let started = Instant::now();
+
+    loop {
+        let answer = tcpip_exec(move || {
+            (0..ARP_TABLE_SIZE).find_map(|index| {
+                let mut entry_ip: *mut esp_ip4_addr_t = ptr::null_mut();
+                let mut entry_netif: *mut ffi::c_void = ptr::null_mut();
+                let mut mac: *mut EthAddr = ptr::null_mut();
+
+                let stable =
+                    unsafe { etharp_get_entry(index, &mut entry_ip, &mut entry_netif, &mut mac) };
+
+                if stable != 0
+                    && entry_netif as usize == netif
+                    && !entry_ip.is_null()
+                    && !mac.is_null()
+                    && unsafe { (*entry_ip).addr } == ip.addr
+                {
+                    Some(unsafe { (*mac).addr })
+                } else {
+                    None
+                }
+            })
+        })?;
+
+        match answer {
+            Some(mac) if mac != own_mac => return Ok(Some(mac)),
+            _ if started.elapsed() >= timeout => return Ok(None),
+            _ => thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+fn request(handle: *mut esp_netif_t, ip: esp_ip4_addr_t) -> Result<(), EspError> {
+    let netif = impl_of(handle)?;
+
+    let err = tcpip_exec(move || unsafe { etharp_request(netif as *mut _, &ip) })?;
+
+    if err != 0 {
+        Err(EspError::from_infallible::<ESP_FAIL>())
+    } else {
+        Ok(())
+    }
+}
+
+fn ip_of(handle: *mut esp_netif_t) -> Result<esp_ip4_addr_t, EspError> {
+    let mut ip_info: esp_netif_ip_info_t = Default::default();
+
+    esp!(unsafe { esp_netif_get_ip_info(handle, &mut ip_info) })?;
+
+    if ip_info.ip.addr == 0 {
+        Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+    } else {
+        Ok(ip_info.ip)
+    }
+}
+
+fn netif(interface: &Interface) -> Result<usize, EspError> {
+    impl_of(interface.handle()?)
+}
+
+/// The lwIP `netif` of an `esp_netif`, as an address, so that it can be moved to the TCP/IP thread
+pub(crate) fn impl_of(handle: *mut esp_netif_t) -> Result<usize, EspError> {
+    let netif = unsafe { esp_netif_get_netif_impl(handle) };
+
+    if netif.is_null() {
+        Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+    } else {
+        Ok(netif as usize)
+    }
+}
+
+/// Runs `f` in the TCP/IP thread of lwIP, and returns its result
+pub(crate) fn tcpip_exec<R>(f: impl FnOnce() -> R + Send + 'static) -> Result<R, EspError>
+where
+    R: Send + 'static,
+{
+    unsafe extern "C" fn call(ctx: *mut ffi::c_void) {
+        let f = Box::from_raw(ctx as *mut Box<dyn FnOnce() + Send>);
+
+        f();
+    }
+
+    let (sender, receiver) = mpsc::sync_channel(1);
+
+    let f: Box<Box<dyn FnOnce() + Send>> = Box::new(Box::new(move || {
+        let _ = sender.send(f());
+    }));
+
+    let ctx = Box::into_raw(f);
+
+    if unsafe { tcpip_callback(Some(call), ctx as *mut _) } != 0 {
+        drop(unsafe { Box::from_raw(ctx) });
+
+        return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+    }
+
+    receiver
+        .recv()
+        .map_err(|_| EspError::from_infallible::<ESP_FAIL>())
+}
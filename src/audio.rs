@@ -0,0 +1,604 @@
+//! I2S audio streaming
+//!
+//! `EspAudio` drives an I2S port with the legacy I2S driver: the samples captured from a
+//! microphone are buffered in a ring buffer, to be streamed to a client, and the samples
+//! written for playback are buffered in another one, feeding a DAC or amplifier. The audio is
+//! raw PCM (`audio/L16` for 16 bits samples), so that no codec is needed on the device.
+//!
+//! The HTTP `stream_handler` streams the captured audio as a chunked response, and
+//! `playback_handler` plays the body of a request; `ws_handler` does both over a WebSocket,
+//! with one binary frame per chunk:
+//!
+//! ```ignore
+//! let audio = Arc::new(EspAudio::new(
+//!     &Configuration {
+//!         sample_rate: 16000,
+//!         ..Default::default()
+//!     },
+//!     Pins {
+//!         bclk: pins.gpio26.downgrade(),
+//!         ws: pins.gpio25.downgrade(),
+//!         din: Some(pins.gpio33.downgrade()),
+//!         dout: Some(pins.gpio22.downgrade()),
+//!         mclk: None,
+//!     },
+//! )?);
+//!
+//! server.fn_handler("/listen", Method::Get, audio::stream_handler(audio.clone(), None))?;
+//! server.fn_handler("/speak", Method::Post, audio::playback_handler(audio.clone()))?;
+//! server.ws_handler("/intercom", audio::ws_handler(audio))?;
+//! ```
+//!
+//! The captured audio has a single consumer: concurrent streams get alternate chunks. When a
+//! consumer is too slow, the oldest captured samples are dropped.
+//!
+//! Note that the HTTP server handles one request at a time, so it is blocked while
+//! `stream_handler` streams; the WebSocket handler streams from a thread of its own instead.
+use core::fmt::Write as _;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::collections::VecDeque;
+use std::string::String;
+use std::sync::Arc;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_hal::delay::TickType;
+use esp_idf_hal::gpio::{AnyIOPin, Pin};
+
+use esp_idf_sys::*;
+
+use crate::private::waitable::*;
+
+/// How long the I2S threads block in the driver, between two checks whether they should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Channels {
+    /// The left channel only
+    Mono,
+    Stereo,
+}
+
+impl Channels {
+    fn count(&self) -> u8 {
+        match self {
+            Self::Mono => 1,
+            Self::Stereo => 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub port: u8,
+    pub sample_rate: u32,
+    /// 16, 24 or 32
+    pub bits_per_sample: u8,
+    pub channels: Channels,
+    pub dma_buffers: usize,
+    /// The number of frames of each DMA buffer
+    pub dma_frames: usize,
+    /// The size of each of the capture and playback ring buffers, in bytes
+    pub ring_size: usize,
+    /// The size of the chunks read from and written to the driver, and streamed
+    pub chunk_size: usize,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            port: 0,
+            sample_rate: 16000,
+            bits_per_sample: 16,
+            channels: Channels::Mono,
+            dma_buffers: 4,
+            dma_frames: 256,
+            ring_size: 16 * 1024,
+            chunk_size: 1024,
+            stack_size: 4096,
+        }
+    }
+}
+
+pub struct Pins {
+    pub bclk: AnyIOPin,
+    pub ws: AnyIOPin,
+    /// The data input, from a microphone: enables the capture
+    pub din: Option<AnyIOPin>,
+    /// The data output, to a DAC or an amplifier: enables the playback
+    pub dout: Option<AnyIOPin>,
+    pub mclk: Option<AnyIOPin>,
+}
+
+struct Ring {
+    data: VecDeque<u8>,
+    capacity: usize,
+    overruns: u32,
+}
+
+impl Ring {
+    fn new(capacity: usize) -> Self {
+        Self {
+            data: VecDeque::with_capacity(capacity),
+            capacity,
+            overruns: 0,
+        }
+    }
+
+    fn available(&self) -> usize {
+        self.capacity - self.data.len()
+    }
+
+    /// Appends `data`, dropping the oldest bytes if there is not enough room
+    fn push_overwrite(&mut self, data: &[u8]) {
+        let excess = (self.data.len() + data.len()).saturating_sub(self.capacity);
+
+        if excess > 0 {
+            self.data.drain(..excess.min(self.data.len()));
+            self.overruns += 1;
+        }
+
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+
+        self.data.extend(data);
+    }
+
+    fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.data.len());
+
+        for (dst, src) in buf.iter_mut().zip(self.data.drain(..len)) {
+            *dst = src;
+        }
+
+        len
+    }
+}
+
+pub struct EspAudio {
+    conf: Configuration,
+    capture: Option<Arc<Waitable<Ring>>>,
+    playback: Option<Arc<Waitable<Ring>>>,
+    running: Arc<AtomicBool>,
+    join_handles: Vec<thread::JoinHandle<()>>,
+    _pins: Pins,
+}
+
+impl EspAudio {
+    pub fn new(conf: &Configuration, pins: Pins) -> Result<Self, EspError> {
+        if !matches!(conf.bits_per_sample, 16 | 24 | 32)
+            || conf.sample_rate == 0
+            || conf.chunk_size == 0
+            || conf.ring_size < conf.chunk_size
+            || (pins.din.is_none() && pins.dout.is_none())
+        {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let port = conf.port as i2s_port_t;
+
+        let mut mode = i2s_mode_t_I2S_MODE_MASTER;
+
+        if pins.din.is_some() {
+            mode |= i2s_mode_t_I2S_MODE_RX;
+        }
+
+        if pins.dout.is_some() {
+            mode |= i2s_mode_t_I2S_MODE_TX;
+        }
+
+        #[allow(clippy::needless_update)]
+        let mut config = i2s_config_t {
+            mode,
+            sample_rate: conf.sample_rate,
+            bits_per_sample: conf.bits_per_sample as _,
+            channel_format: match conf.channels {
+                Channels::Mono => i2s_channel_fmt_t_I2S_CHANNEL_FMT_ONLY_LEFT,
+                Channels::Stereo => i2s_channel_fmt_t_I2S_CHANNEL_FMT_RIGHT_LEFT,
+            },
+            communication_format: i2s_comm_format_t_I2S_COMM_FORMAT_STAND_I2S,
+            intr_alloc_flags: ESP_INTR_FLAG_LEVEL1 as _,
+            use_apll: false,
+            tx_desc_auto_clear: true,
+            ..Default::default()
+        };
+
+        #[cfg(esp_idf_version = "4.3")]
+        {
+            config.dma_buf_count = conf.dma_buffers as _;
+            config.dma_buf_len = conf.dma_frames as _;
+        }
+
+        #[cfg(not(esp_idf_version = "4.3"))]
+        {
+            config.__bindgen_anon_1.dma_desc_num = conf.dma_buffers as _;
+            config.__bindgen_anon_2.dma_frame_num = conf.dma_frames as _;
+        }
+
+        let pin = |pin: &Option<AnyIOPin>| pin.as_ref().map(|pin| pin.pin()).unwrap_or(-1);
+
+        #[allow(clippy::needless_update)]
+        let mut pin_config = i2s_pin_config_t {
+            bck_io_num: pins.bclk.pin(),
+            ws_io_num: pins.ws.pin(),
+            data_out_num: pin(&pins.dout),
+            data_in_num: pin(&pins.din),
+            ..Default::default()
+        };
+
+        #[cfg(not(esp_idf_version = "4.3"))]
+        {
+            pin_config.mck_io_num = pin(&pins.mclk);
+        }
+
+        esp!(unsafe { i2s_driver_install(port, &config, 0, ptr::null_mut()) })?;
+
+        let mut audio = Self {
+            conf: conf.clone(),
+            capture: None,
+            playback: None,
+            running: Arc::new(AtomicBool::new(true)),
+            join_handles: Vec::new(),
+            _pins: pins,
+        };
+
+        // From now on, dropping `audio` uninstalls the driver
+        esp!(unsafe { i2s_set_pin(port, &pin_config) })?;
+
+        if audio._pins.din.is_some() {
+            let ring = Arc::new(Waitable::new(Ring::new(conf.ring_size)));
+
+            audio.capture = Some(ring.clone());
+            audio.spawn("audio-capture", move |conf, running| {
+                Self::run_capture(conf, ring, running)
+            })?;
+        }
+
+        if audio._pins.dout.is_some() {
+            let ring = Arc::new(Waitable::new(Ring::new(conf.ring_size)));
+
+            audio.playback = Some(ring.clone());
+            audio.spawn("audio-playback", move |conf, running| {
+                Self::run_playback(conf, ring, running)
+            })?;
+        }
+
+        info!(
+            "I2S audio started on port {}: {} Hz, {} bits, {} channel(s)",
+            conf.port,
+            conf.sample_rate,
+            conf.bits_per_sample,
+            conf.channels.count()
+        );
+
+        Ok(audio)
+    }
+
+    /// The MIME type of the audio, e.g. `audio/L16;rate=16000;channels=1`
+    pub fn content_type(&self) -> String {
+        let mut content_type = String::new();
+
+        write!(
+            &mut content_type,
+            "audio/L{};rate={};channels={}",
+            self.conf.bits_per_sample,
+            self.conf.sample_rate,
+            self.conf.channels.count()
+        )
+        .unwrap();
+
+        content_type
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.conf.chunk_size
+    }
+
+    /// Reads captured audio, waiting for some to be available, forever if `timeout` is
+    /// `None`, and returns the number of bytes read; always 0 without capture
+    pub fn read(&self, buf: &mut [u8], timeout: Option<Duration>) -> usize {
+        let ring = if let Some(ring) = &self.capture {
+            ring
+        } else {
+            return 0;
+        };
+
+        let condition = |ring: &Ring| ring.data.is_empty();
+
+        if let Some(timeout) = timeout {
+            ring.wait_timeout_while(timeout, condition);
+        } else {
+            ring.wait_while(condition);
+        }
+
+        ring.get_mut(|ring| ring.pop(buf))
+    }
+
+    /// Queues audio for playback, waiting for room in the playback ring buffer, forever if
+    /// `timeout` is `None`, and returns the number of bytes queued; always 0 without playback
+    pub fn write(&self, data: &[u8], timeout: Option<Duration>) -> usize {
+        let ring = if let Some(ring) = &self.playback {
+            ring
+        } else {
+            return 0;
+        };
+
+        let needed = data.len().min(ring.get(|ring| ring.capacity));
+        let condition = |ring: &Ring| ring.available() < needed;
+
+        if let Some(timeout) = timeout {
+            ring.wait_timeout_while(timeout, condition);
+        } else {
+            ring.wait_while(condition);
+        }
+
+        ring.get_mut(|ring| {
+            let len = data.len().min(ring.available());
+
+            ring.data.extend(&data[..len]);
+
+            len
+        })
+    }
+
+    /// The number of times captured audio was dropped because it was not read fast enough
+    pub fn overruns(&self) -> u32 {
+        self.capture
+            .as_ref()
+            .map(|ring| ring.get(|ring| ring.overruns))
+            .unwrap_or(0)
+    }
+
+    fn spawn(
+        &mut self,
+        name: &str,
+        f: impl FnOnce(Configuration, Arc<AtomicBool>) + Send + 'static,
+    ) -> Result<(), EspError> {
+        let conf = self.conf.clone();
+        let running = self.running.clone();
+
+        let join_handle = thread::Builder::new()
+            .name(name.into())
+            .stack_size(self.conf.stack_size)
+            .spawn(move || f(conf, running))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        self.join_handles.push(join_handle);
+
+        Ok(())
+    }
+
+    fn run_capture(conf: Configuration, ring: Arc<Waitable<Ring>>, running: Arc<AtomicBool>) {
+        let mut chunk = vec![0; conf.chunk_size];
+
+        while running.load(Ordering::SeqCst) {
+            let mut read = 0;
+
+            if let Err(e) = esp!(unsafe {
+                i2s_read(
+                    conf.port as _,
+                    chunk.as_mut_ptr() as *mut _,
+                    chunk.len(),
+                    &mut read,
+                    TickType::from(POLL_INTERVAL).0,
+                )
+            }) {
+                warn!("I2S read failed: {}", e);
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+
+            if read > 0 {
+                ring.get_mut(|ring| ring.push_overwrite(&chunk[..read]));
+                ring.cvar.notify_all();
+            }
+        }
+    }
+
+    fn run_playback(conf: Configuration, ring: Arc<Waitable<Ring>>, running: Arc<AtomicBool>) {
+        let mut chunk = vec![0; conf.chunk_size];
+
+        while running.load(Ordering::SeqCst) {
+            ring.wait_timeout_while(POLL_INTERVAL, |ring| ring.data.is_empty());
+
+            let len = ring.get_mut(|ring| ring.pop(&mut chunk));
+
+            if len == 0 {
+                continue;
+            }
+
+            ring.cvar.notify_all();
+
+            let mut written = 0;
+
+            while written < len && running.load(Ordering::SeqCst) {
+                let mut chunk_written = 0;
+
+                if let Err(e) = esp!(unsafe {
+                    i2s_write(
+                        conf.port as _,
+                        chunk[written..len].as_ptr() as *const _,
+                        len - written,
+                        &mut chunk_written,
+                        TickType::from(POLL_INTERVAL).0,
+                    )
+                }) {
+                    warn!("I2S write failed: {}", e);
+                    break;
+                }
+
+                written += chunk_written;
+            }
+        }
+    }
+}
+
+impl Drop for EspAudio {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+
+        for join_handle in self.join_handles.drain(..) {
+            let _ = join_handle.join();
+        }
+
+        esp!(unsafe { i2s_driver_uninstall(self.conf.port as _) }).unwrap();
+
+        info!("Dropped");
+    }
+}
+
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+pub use http::*;
+
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+mod http {
+    use core::time::Duration;
+
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use embedded_svc::http::server::{HandlerResult, Request};
+    use embedded_svc::io::{Read, Write};
+
+    use super::EspAudio;
+    use crate::http::server::EspHttpConnection;
+
+    /// An `EspHttpServer` handler streaming the captured audio, until the client disconnects
+    /// or for `duration`
+    pub fn stream_handler(
+        audio: Arc<EspAudio>,
+        duration: Option<Duration>,
+    ) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static
+    {
+        move |request| {
+            let content_type = audio.content_type();
+
+            let mut response = request.into_response(
+                200,
+                None,
+                &[
+                    ("Content-Type", content_type.as_str()),
+                    ("Cache-Control", "no-store"),
+                ],
+            )?;
+
+            let started = Instant::now();
+            let mut chunk = vec![0; audio.chunk_size()];
+
+            while duration
+                .map(|duration| started.elapsed() < duration)
+                .unwrap_or(true)
+            {
+                let len = audio.read(&mut chunk, Some(Duration::from_millis(500)));
+
+                if len > 0 && response.write_all(&chunk[..len]).is_err() {
+                    // The client is gone
+                    break;
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    /// An `EspHttpServer` handler playing the audio in the request body
+    pub fn playback_handler(
+        audio: Arc<EspAudio>,
+    ) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static
+    {
+        move |mut request| {
+            let mut chunk = vec![0; audio.chunk_size()];
+
+            loop {
+                let len = request.read(&mut chunk)?;
+                if len == 0 {
+                    break;
+                }
+
+                let mut queued = 0;
+
+                while queued < len {
+                    queued += audio.write(&chunk[queued..len], None);
+                }
+            }
+
+            request.into_ok_response()?;
+
+            Ok(())
+        }
+    }
+
+    #[cfg(esp_idf_httpd_ws_support)]
+    pub use ws::*;
+
+    #[cfg(esp_idf_httpd_ws_support)]
+    mod ws {
+        use core::time::Duration;
+
+        use std::sync::Arc;
+        use std::thread;
+
+        use ::log::*;
+
+        use embedded_svc::ws::FrameType;
+
+        use esp_idf_sys::*;
+
+        use super::super::EspAudio;
+        use crate::http::server::ws::EspHttpWsConnection;
+
+        /// An `EspHttpServer` WebSocket handler streaming the captured audio to the client in
+        /// binary frames, and playing the binary frames received from the client
+        pub fn ws_handler(
+            audio: Arc<EspAudio>,
+        ) -> impl for<'a> Fn(&'a mut EspHttpWsConnection) -> Result<(), EspError> + Send + Sync + 'static
+        {
+            move |connection| {
+                if connection.is_new() {
+                    let mut sender = connection.create_detached_sender()?;
+                    let audio = audio.clone();
+
+                    thread::Builder::new()
+                        .name("audio-ws".into())
+                        .stack_size(4096)
+                        .spawn(move || {
+                            let mut chunk = vec![0; audio.chunk_size()];
+
+                            while !sender.is_closed() {
+                                let len = audio.read(&mut chunk, Some(Duration::from_millis(500)));
+
+                                if len > 0
+                                    && sender
+                                        .send(FrameType::Binary(false), &chunk[..len])
+                                        .is_err()
+                                {
+                                    break;
+                                }
+                            }
+
+                            info!("Audio WebSocket stream closed");
+                        })
+                        .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+                } else if !connection.is_closed() {
+                    let mut chunk = vec![0; audio.chunk_size()];
+
+                    let (frame_type, len) = connection.recv(&mut chunk)?;
+
+                    if matches!(frame_type, FrameType::Binary(_)) && len <= chunk.len() {
+                        audio.write(&chunk[..len], Some(Duration::from_millis(500)));
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Crash loop detection and safe mode
+//!
+//! `EspBootCounter` counts the boots which did not reach a stable uptime, in NVS. Once this
+//! count reaches a threshold, i.e. the device keeps crashing (or being power cycled) shortly
+//! after boot, it reports `BootMode::Safe`, in which the application should skip its own
+//! logic and only bring up what is needed to fix the device remotely (connectivity,
+//! provisioning, OTA):
+//!
+//! ```ignore
+//! let boot = EspBootCounter::new(nvs_partition.clone(), &Default::default())?;
+//!
+//! boot.run(
+//!     || app_main(),
+//!     || {
+//!         start_wifi_provisioning();
+//!         start_ota_scheduler();
+//!     },
+//! );
+//! ```
+//!
+//! The count is reset once the device has been up for `Configuration::stable_after`, or
+//! explicitly with `EspBootCounter::mark_stable`.
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::sync::Arc;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+use crate::private::mutex::Mutex;
+use crate::timer::{EspTaskTimerService, EspTimer};
+
+const NAMESPACE: &str = "boot_counter";
+const COUNT_KEY: &str = "count";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The number of consecutive unstable boots entering the safe mode
+    pub threshold: u8,
+    /// The uptime after which a boot is considered stable; never if `None`,
+    /// i.e. only `EspBootCounter::mark_stable` resets the count
+    pub stable_after: Option<Duration>,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            stable_after: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum BootMode {
+    Normal,
+    Safe,
+}
+
+pub struct EspBootCounter<T: NvsPartitionId> {
+    nvs: Arc<Mutex<EspNvs<T>>>,
+    count: u8,
+    mode: BootMode,
+    _timer: Option<EspTimer>,
+}
+
+impl<T> EspBootCounter<T>
+where
+    T: NvsPartitionId + Send + 'static,
+{
+    /// Counts the current boot; to be called as early as possible
+    pub fn new(partition: EspNvsPartition<T>, conf: &Configuration) -> Result<Self, EspError> {
+        if conf.threshold == 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+
+        let count = nvs.get_u8(COUNT_KEY)?.unwrap_or(0).saturating_add(1);
+
+        nvs.set_u8(COUNT_KEY, count)?;
+
+        let mode = if count >= conf.threshold {
+            BootMode::Safe
+        } else {
+            BootMode::Normal
+        };
+
+        let reason = unsafe { esp_reset_reason() };
+
+        if mode == BootMode::Safe {
+            warn!(
+                "{} consecutive unstable boots (last reset reason: {}), entering safe mode",
+                count, reason
+            );
+        } else {
+            info!(
+                "Unstable boot {} of {} (reset reason: {})",
+                count, conf.threshold, reason
+            );
+        }
+
+        let nvs = Arc::new(Mutex::new(nvs));
+
+        let timer = if let Some(stable_after) = conf.stable_after {
+            let nvs = nvs.clone();
+
+            let timer = EspTaskTimerService::new()?.timer(move || {
+                if let Err(e) = Self::reset(&nvs) {
+                    warn!("Failed to reset the boot counter: {}", e);
+                }
+            })?;
+
+            timer.after(stable_after)?;
+
+            Some(timer)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            nvs,
+            count,
+            mode,
+            _timer: timer,
+        })
+    }
+
+    /// The number of consecutive unstable boots, including the current one
+    pub fn count(&self) -> u8 {
+        self.count
+    }
+
+    pub fn mode(&self) -> BootMode {
+        self.mode
+    }
+
+    pub fn is_safe_mode(&self) -> bool {
+        self.mode == BootMode::Safe
+    }
+
+    /// Runs `normal` or `safe`, depending on the boot mode
+    pub fn run<R>(&self, normal: impl FnOnce() -> R, safe: impl FnOnce() -> R) -> R {
+        match self.mode {
+            BootMode::Normal => normal(),
+            BootMode::Safe => safe(),
+        }
+    }
+
+    /// Resets the count, e.g. once the application has reached a known good state
+    ///
+    /// This does not leave the safe mode, which lasts until the next boot.
+    pub fn mark_stable(&self) -> Result<(), EspError> {
+        Self::reset(&self.nvs)
+    }
+
+    /// Resets the count and restarts, booting normally
+    pub fn exit_safe_mode(&self) -> Result<(), EspError> {
+        self.mark_stable()?;
+
+        info!("Leaving safe mode");
+
+        unsafe { esp_restart() };
+
+        Ok(())
+    }
+
+    fn reset(nvs: &Mutex<EspNvs<T>>) -> Result<(), EspError> {
+        let mut nvs = nvs.lock();
+
+        if nvs.get_u8(COUNT_KEY)?.unwrap_or(0) != 0 {
+            nvs.set_u8(COUNT_KEY, 0)?;
+
+            info!("Boot is stable, reset the boot counter");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+//! Brownout early warning
+//!
+//! The brownout detector of the chip fires when the supply voltage drops below its threshold.
+//! With a capacitor or battery backed supply, this leaves a few milliseconds before the power is
+//! lost, which is enough to commit pending NVS writes or close files.
+//!
+//! `EspBrownout` configures the detector with the hardware reset disabled, and runs a hook on a
+//! high priority task when it fires, restarting the device once the hook returns:
+//!
+//! ```ignore
+//! let _brownout = EspBrownout::new(&Default::default(), move || {
+//!     nvs.set_u32("counter", counter.load(Ordering::SeqCst)).unwrap();
+//! })?;
+//! ```
+//!
+//! The flash stays powered while the hook runs, the radio can be powered down to save some
+//! margin (`Configuration::rf_power_down`).
+//!
+//! The brownout detector of ESP-IDF (`CONFIG_ESP_BROWNOUT_DET`) restarts the device as soon as
+//! it fires, so it must be disabled in the sdkconfig for `EspBrownout` to be of any use.
+use core::ffi;
+use core::ptr;
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+use ::log::*;
+
+use esp_idf_hal::cpu::Core;
+use esp_idf_hal::task;
+
+use esp_idf_sys::*;
+
+use crate::private::cstr::RawCstrs;
+
+/// The brownout interrupt of the RTC controller (`RTC_CNTL_BROWN_OUT_INT_ENA_M`)
+const BROWNOUT_INTR_MASK: u32 = 1 << 9;
+
+const NOTIFICATION_BROWNOUT: u32 = 1;
+const NOTIFICATION_STOP: u32 = 2;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct brownout_hal_config_t {
+    threshold: ffi::c_int,
+    enabled: bool,
+    reset_enabled: bool,
+    flash_power_down: bool,
+    rf_power_down: bool,
+}
+
+type IsrHandler = Option<unsafe extern "C" fn(arg: *mut ffi::c_void)>;
+
+extern "C" {
+    fn brownout_hal_config(cfg: *const brownout_hal_config_t);
+
+    fn brownout_hal_intr_enable(enable: bool);
+
+    fn brownout_hal_intr_clear();
+
+    #[cfg(esp_idf_version_major = "4")]
+    fn rtc_isr_register(handler: IsrHandler, arg: *mut ffi::c_void, mask: u32) -> esp_err_t;
+
+    #[cfg(not(esp_idf_version_major = "4"))]
+    fn rtc_isr_register(
+        handler: IsrHandler,
+        arg: *mut ffi::c_void,
+        mask: u32,
+        flags: u32,
+    ) -> esp_err_t;
+
+    fn rtc_isr_deregister(handler: IsrHandler, arg: *mut ffi::c_void) -> esp_err_t;
+}
+
+/// The threshold of the detector
+///
+/// The voltages depend on the chip (see its datasheet); on the ESP32 they go from about 2.43V
+/// (`Level0`) to 2.80V (`Level7`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Threshold {
+    Level0,
+    Level1,
+    Level2,
+    Level3,
+    Level4,
+    Level5,
+    Level6,
+    Level7,
+}
+
+impl Default for Threshold {
+    fn default() -> Self {
+        // The default of `CONFIG_ESP_BROWNOUT_DET_LVL`
+        Self::Level0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Configuration<'a> {
+    pub threshold: Threshold,
+    /// Powers the radio down when the detector fires
+    pub rf_power_down: bool,
+    /// Restarts the device once the hook returns
+    ///
+    /// Otherwise the detector is re-armed, and the hook runs again on the next brownout.
+    pub restart: bool,
+    pub task_name: &'a str,
+    pub task_priority: u8,
+    pub task_stack_size: usize,
+    pub task_pin_to_core: Option<Core>,
+}
+
+impl<'a> Default for Configuration<'a> {
+    fn default() -> Self {
+        Self {
+            threshold: Default::default(),
+            rf_power_down: true,
+            restart: true,
+            task_name: "Brownout",
+            task_priority: (configMAX_PRIORITIES - 1) as _,
+            task_stack_size: 4096,
+            task_pin_to_core: None,
+        }
+    }
+}
+
+struct State {
+    hook: Box<dyn FnMut() + Send + 'static>,
+    restart: bool,
+}
+
+/// The brownout detector, running its hook until dropped
+pub struct EspBrownout {
+    task: TaskHandle_t,
+}
+
+impl EspBrownout {
+    pub fn new<F>(conf: &Configuration<'_>, hook: F) -> Result<Self, EspError>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        if cfg!(esp_idf_esp_brownout_det) {
+            error!("CONFIG_ESP_BROWNOUT_DET restarts the device before the hook could run");
+
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let mut rcs = RawCstrs::new();
+
+        let state = Box::into_raw(Box::new(State {
+            hook: Box::new(hook),
+            restart: conf.restart,
+        }));
+
+        let mut task: TaskHandle_t = ptr::null_mut();
+
+        let created = unsafe {
+            xTaskCreatePinnedToCore(
+                Some(Self::background_loop),
+                rcs.as_ptr(conf.task_name),
+                conf.task_stack_size as _,
+                state as *mut _,
+                conf.task_priority as _,
+                &mut task as *mut _,
+                conf.task_pin_to_core
+                    .map(|core| core as u32)
+                    .unwrap_or(tskNO_AFFINITY) as _,
+            ) != 0
+        };
+
+        if !created {
+            unsafe { drop(Box::from_raw(state)) };
+
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        #[cfg(esp_idf_version_major = "4")]
+        let registered =
+            unsafe { rtc_isr_register(Some(Self::isr), task as *mut _, BROWNOUT_INTR_MASK) };
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let registered =
+            unsafe { rtc_isr_register(Some(Self::isr), task as *mut _, BROWNOUT_INTR_MASK, 0) };
+
+        if let Err(e) = esp!(registered) {
+            // Only stops the task, as the interrupt is not enabled yet
+            unsafe { task::notify(task, NOTIFICATION_STOP) };
+
+            return Err(e);
+        }
+
+        unsafe {
+            brownout_hal_config(&brownout_hal_config_t {
+                threshold: conf.threshold as _,
+                enabled: true,
+                reset_enabled: false,
+                flash_power_down: false,
+                rf_power_down: conf.rf_power_down,
+            });
+
+            brownout_hal_intr_clear();
+            brownout_hal_intr_enable(true);
+        }
+
+        info!("Started, threshold: {:?}", conf.threshold);
+
+        Ok(Self { task })
+    }
+
+    unsafe extern "C" fn isr(arg: *mut ffi::c_void) {
+        // Disarmed until the hook returns, as the detector keeps firing while the voltage is low
+        brownout_hal_intr_enable(false);
+        brownout_hal_intr_clear();
+
+        task::notify(arg as TaskHandle_t, NOTIFICATION_BROWNOUT);
+    }
+
+    extern "C" fn background_loop(state: *mut ffi::c_void) {
+        let mut state = unsafe { Box::from_raw(state as *mut State) };
+
+        loop {
+            let notification = task::wait_notification(None).unwrap_or(0);
+
+            if notification & NOTIFICATION_STOP != 0 {
+                break;
+            }
+
+            if notification & NOTIFICATION_BROWNOUT != 0 {
+                warn!("Brownout detected");
+
+                (state.hook)();
+
+                if state.restart {
+                    unsafe { esp_restart() };
+                }
+
+                unsafe {
+                    brownout_hal_intr_clear();
+                    brownout_hal_intr_enable(true);
+                }
+            }
+        }
+
+        drop(state);
+
+        unsafe {
+            vTaskDelete(ptr::null_mut());
+        }
+    }
+}
+
+impl Drop for EspBrownout {
+    fn drop(&mut self) {
+        unsafe {
+            brownout_hal_intr_enable(false);
+
+            esp!(rtc_isr_deregister(Some(Self::isr), self.task as *mut _)).unwrap();
+
+            task::notify(self.task, NOTIFICATION_STOP);
+        }
+
+        info!("Dropped");
+    }
+}
+
+unsafe impl Send for EspBrownout {}
+
+/// Returns `true` if the last reset of the device was caused by a brownout
+pub fn last_reset_was_brownout() -> bool {
+    unsafe { esp_reset_reason() == esp_reset_reason_t_ESP_RST_BROWNOUT }
+}
@@ -0,0 +1,402 @@
+//! Captive portal DNS server
+//!
+//! A minimal DNS server which answers every `A` query with a single address
+//! (typically the IP of the SoftAP interface), so that clients connecting to the AP
+//! are directed to the portal served by the device itself.
+//!
+//! Specific hostnames can be resolved otherwise with `Configuration::overrides`: to an address
+//! of their own, or through an upstream DNS server, e.g. for the cloud authentication callbacks
+//! of an onboarding flow to reach their real servers through the STA connection:
+//!
+//! ```ignore
+//! let dns = EspCaptiveDns::new(&Configuration {
+//!     ip,
+//!     overrides: vec![
+//!         ("*.auth.example.com".into(), HostOverride::PassThrough),
+//!         ("portal.local".into(), HostOverride::Address(ip)),
+//!     ],
+//!     ..Default::default()
+//! })?;
+//! ```
+use core::time::Duration;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::string::String;
+use std::sync::mpsc;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::private::common::Newtype;
+
+const HEADER_LEN: usize = 12;
+
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+/// Response code for the pass-through queries which the upstream server did not answer
+const RCODE_SERVER_FAILURE: u8 = 2;
+/// Response code for queries which are not standard queries
+const RCODE_NOT_IMPLEMENTED: u8 = 4;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HostOverride {
+    /// Resolves the `A` queries to that address instead
+    Address(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] Ipv4Addr),
+    /// Forwards all the queries to the upstream DNS server, and relays its responses
+    PassThrough,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The address all queries are resolved to
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    /// The TTL of the answers; kept short so that clients do not cache
+    /// the hijacked addresses once the portal is gone
+    pub ttl: u32,
+    pub stack_size: usize,
+    /// The hostnames not resolved to `ip`, the first matching one applying
+    ///
+    /// A hostname matches itself, regardless of the case and of a trailing dot, and
+    /// `*.example.com` matches all the subdomains of `example.com` (but not `example.com`).
+    pub overrides: Vec<(String, HostOverride)>,
+    /// The DNS server the pass-through queries are forwarded to, by default that of lwIP, i.e.
+    /// the one the STA interface got
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub upstream: Option<Ipv4Addr>,
+    /// How long to wait for the upstream server, before answering with a server failure
+    pub upstream_timeout: Duration,
+}
+
+impl Configuration {
+    /// The override of `name`, if any
+    pub fn lookup(&self, name: &str) -> Option<HostOverride> {
+        let name = name.trim_end_matches('.');
+
+        self.overrides
+            .iter()
+            .find(|(pattern, _)| {
+                let pattern = pattern.trim_end_matches('.');
+
+                if let Some(domain) = pattern.strip_prefix("*.") {
+                    name.len() > domain.len() + 1
+                        && name.is_char_boundary(name.len() - domain.len() - 1)
+                        && name[name.len() - domain.len() - 1..]
+                            .strip_prefix('.')
+                            .map(|suffix| suffix.eq_ignore_ascii_case(domain))
+                            .unwrap_or(false)
+                } else {
+                    name.eq_ignore_ascii_case(pattern)
+                }
+            })
+            .map(|(_, host_override)| *host_override)
+    }
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            ip: Ipv4Addr::new(192, 168, 71, 1),
+            port: 53,
+            ttl: 60,
+            stack_size: 4096,
+            overrides: Vec::new(),
+            upstream: None,
+            upstream_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+pub struct EspCaptiveDns {
+    stop: mpsc::Sender<()>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspCaptiveDns {
+    pub fn new(conf: &Configuration) -> Result<Self, EspError> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, conf.port))
+            .and_then(|socket| {
+                // The stop channel is only checked between receives
+                socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+
+                Ok(socket)
+            })
+            .map_err(|e| {
+                warn!("Failed to bind captive DNS socket: {}", e);
+
+                EspError::from_infallible::<ESP_FAIL>()
+            })?;
+
+        let (stop, stopped) = mpsc::channel();
+
+        let join_handle = {
+            let conf = conf.clone();
+
+            thread::Builder::new()
+                .name("captive-dns".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, socket, stopped))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        info!("Started captive DNS server resolving to {}", conf.ip);
+
+        Ok(Self {
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    fn run(conf: Configuration, socket: UdpSocket, stopped: mpsc::Receiver<()>) {
+        let mut request = [0_u8; 512];
+        let mut response = [0_u8; 512];
+
+        loop {
+            match stopped.try_recv() {
+                Err(mpsc::TryRecvError::Empty) => (),
+                _ => break,
+            }
+
+            let (len, peer) = match socket.recv_from(&mut request) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    warn!("Captive DNS receive failed: {}", e);
+                    continue;
+                }
+            };
+
+            if let Some(response_len) = Self::answer(&conf, &request[..len], &mut response) {
+                if let Err(e) = socket.send_to(&response[..response_len], peer) {
+                    warn!("Captive DNS send to {} failed: {}", peer, e);
+                }
+            }
+        }
+    }
+
+    /// Builds the response to a query, returning its length, or `None` if the query is
+    /// malformed and should be dropped
+    fn answer(conf: &Configuration, request: &[u8], response: &mut [u8]) -> Option<usize> {
+        if request.len() < HEADER_LEN || request[2] & 0x80 != 0 {
+            // Too short, or not a query
+            return None;
+        }
+
+        let opcode = (request[2] >> 3) & 0x0f;
+        let questions = u16::from_be_bytes([request[4], request[5]]);
+
+        // Only the first question is answered; nobody sends more than one anyway
+        let question_end = if opcode == 0 && questions > 0 {
+            Some(Self::question_end(request)?)
+        } else {
+            None
+        };
+
+        let question = question_end.map(|end| &request[HEADER_LEN..end]);
+
+        let len = HEADER_LEN + question.map(|question| question.len()).unwrap_or(0);
+        if len + 16 > response.len() {
+            return None;
+        }
+
+        let host_override = match question {
+            Some(question) if !conf.overrides.is_empty() => conf.lookup(&Self::name(question)),
+            _ => None,
+        };
+
+        if host_override == Some(HostOverride::PassThrough) {
+            if let Some(response_len) = Self::forward(conf, request, response) {
+                return Some(response_len);
+            }
+        }
+
+        let rcode = match host_override {
+            _ if opcode != 0 => RCODE_NOT_IMPLEMENTED,
+            Some(HostOverride::PassThrough) => RCODE_SERVER_FAILURE,
+            _ => 0,
+        };
+
+        response[..2].copy_from_slice(&request[..2]);
+        // QR, AA and the RD bit of the query
+        response[2] = 0x84 | (request[2] & 0x01) | (opcode << 3);
+        response[3] = rcode;
+        response[4..6].copy_from_slice(&(question.is_some() as u16).to_be_bytes());
+        response[6..HEADER_LEN].fill(0);
+
+        let question = if let Some(question) = question {
+            question
+        } else {
+            return Some(HEADER_LEN);
+        };
+
+        response[HEADER_LEN..len].copy_from_slice(question);
+
+        if rcode != 0 {
+            return Some(len);
+        }
+
+        let ip = match host_override {
+            Some(HostOverride::Address(ip)) => ip,
+            _ => conf.ip,
+        };
+
+        let qtype =
+            u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+        let qclass =
+            u16::from_be_bytes([question[question.len() - 2], question[question.len() - 1]]);
+
+        if !matches!(qtype, TYPE_A | TYPE_ANY) || qclass != CLASS_IN {
+            // No such record, but the name exists
+            return Some(len);
+        }
+
+        response[7] = 1;
+
+        let answer = &mut response[len..len + 16];
+
+        // Pointer to the name in the question
+        answer[..2].copy_from_slice(&[0xc0, HEADER_LEN as u8]);
+        answer[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        answer[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        answer[6..10].copy_from_slice(&conf.ttl.to_be_bytes());
+        answer[10..12].copy_from_slice(&4_u16.to_be_bytes());
+        answer[12..16].copy_from_slice(&ip.octets());
+
+        debug!("Captive DNS resolved {:?} to {}", Self::name(question), ip);
+
+        Some(len + 16)
+    }
+
+    /// Relays the response of the upstream server to `request`, `None` if there is no
+    /// upstream server or it did not answer in time
+    fn forward(conf: &Configuration, request: &[u8], response: &mut [u8]) -> Option<usize> {
+        let upstream = conf.upstream.or_else(Self::upstream)?;
+
+        let result =
+            UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+                socket.set_read_timeout(Some(conf.upstream_timeout))?;
+                socket.send_to(request, SocketAddrV4::new(upstream, 53))?;
+
+                loop {
+                    let (len, from) = socket.recv_from(response)?;
+
+                    // Anything else is a late response to an earlier query, or a spoofed one
+                    if from.ip() == upstream && len >= HEADER_LEN && response[..2] == request[..2] {
+                        return Ok(len);
+                    }
+                }
+            });
+
+        match result {
+            Ok(len) => {
+                if len == response.len() {
+                    // Possibly cut, so the client retries over TCP or with a larger buffer
+                    response[2] |= 0x02;
+                }
+
+                debug!(
+                    "Captive DNS passed {:?} through",
+                    Self::name(&request[HEADER_LEN..])
+                );
+
+                Some(len)
+            }
+            Err(e) => {
+                warn!("Captive DNS upstream {} failed: {}", upstream, e);
+
+                None
+            }
+        }
+    }
+
+    /// The main DNS server of lwIP
+    fn upstream() -> Option<Ipv4Addr> {
+        let server = unsafe { dns_getserver(0).as_ref() }?;
+
+        if server.type_ != lwip_ip_addr_type_IPADDR_TYPE_V4 as u8 {
+            return None;
+        }
+
+        let server: Ipv4Addr = Newtype(unsafe { server.u_addr.ip4 }).into();
+
+        (!server.is_unspecified()).then(|| server)
+    }
+
+    /// Returns the offset just after the first question (its name, type and class)
+    fn question_end(request: &[u8]) -> Option<usize> {
+        let mut offset = HEADER_LEN;
+
+        loop {
+            let len = *request.get(offset)? as usize;
+
+            if len == 0 {
+                offset += 1;
+                break;
+            }
+
+            if len & 0xc0 != 0 {
+                // Compression is not used in the questions of a query
+                return None;
+            }
+
+            offset += 1 + len;
+        }
+
+        if offset + 4 > request.len() {
+            None
+        } else {
+            Some(offset + 4)
+        }
+    }
+
+    fn name(question: &[u8]) -> String {
+        let mut name = String::new();
+        let mut offset = 0;
+
+        while let Some(len) = question.get(offset).map(|len| *len as usize) {
+            if len == 0 || offset + 1 + len > question.len() {
+                break;
+            }
+
+            if !name.is_empty() {
+                name.push('.');
+            }
+
+            name.push_str(&String::from_utf8_lossy(
+                &question[offset + 1..offset + 1 + len],
+            ));
+
+            offset += 1 + len;
+        }
+
+        name
+    }
+}
+
+impl Drop for EspCaptiveDns {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
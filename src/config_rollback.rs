@@ -0,0 +1,241 @@
+//! Configuration rollback tied to the OTA slots
+//!
+//! A new firmware may migrate its configuration to a new schema, which the previous
+//! firmware does not understand anymore. If the new firmware is then rolled back by the
+//! bootloader, the device ends up running the old firmware with the new configuration.
+//!
+//! `EspConfigRollback` prevents this by snapshotting an NVS configuration namespace into
+//! another namespace when an update is applied, and by restoring the snapshot on boot
+//! if the device is running the slot the snapshot was taken from again:
+//!
+//! ```ignore
+//! let rollback = EspConfigRollback::new(nvs_partition, "config", "config_bak");
+//!
+//! // On boot, before reading the configuration
+//! rollback.check()?;
+//!
+//! // When applying an update
+//! rollback.snapshot()?;
+//! update.complete()?;
+//!
+//! // Once the new firmware is validated
+//! ota.mark_running_slot_valid()?;
+//! rollback.commit()?;
+//! ```
+//!
+//! Configuration changes made between the snapshot and the rollback are lost.
+extern crate alloc;
+use alloc::string::String;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::nvs::{EspNvs, EspNvsPartition, NvsDataType, NvsPartitionId};
+use crate::private::cstr::*;
+
+/// The label of the slot the snapshot was taken from
+const SLOT_KEY: &str = "~slot";
+/// The version of the firmware the snapshot was taken from, for diagnostics
+const VERSION_KEY: &str = "~version";
+
+/// The outcome of `EspConfigRollback::check`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum RollbackState {
+    /// There is no snapshot
+    Clean,
+    /// The snapshot is kept, as the running firmware is not validated yet
+    Pending,
+    /// The firmware was rolled back, and the configuration restored
+    Restored,
+    /// The running firmware is valid, and the snapshot was discarded
+    Committed,
+}
+
+pub struct EspConfigRollback<T: NvsPartitionId> {
+    partition: EspNvsPartition<T>,
+    namespace: String,
+    snapshot_namespace: String,
+}
+
+impl<T: NvsPartitionId> EspConfigRollback<T> {
+    pub fn new(partition: EspNvsPartition<T>, namespace: &str, snapshot_namespace: &str) -> Self {
+        Self {
+            partition,
+            namespace: namespace.into(),
+            snapshot_namespace: snapshot_namespace.into(),
+        }
+    }
+
+    /// Snapshots the configuration namespace, recording the running slot
+    ///
+    /// To be called when an update is applied, i.e. right before or after
+    /// `EspOtaUpdate::complete`.
+    pub fn snapshot(&self) -> Result<(), EspError> {
+        let config = EspNvs::new(self.partition.clone(), &self.namespace, false)?;
+        let mut snapshot = EspNvs::new(self.partition.clone(), &self.snapshot_namespace, true)?;
+
+        snapshot.remove_all()?;
+
+        self.copy(&self.namespace, &config, &mut snapshot)?;
+
+        let (slot, version) = Self::running()?;
+
+        snapshot.set_str(VERSION_KEY, &version)?;
+        // Written last, so that an interrupted snapshot is never restored
+        snapshot.set_str(SLOT_KEY, &slot)?;
+
+        info!(
+            "Snapshotted configuration of firmware {} in slot {}",
+            version, slot
+        );
+
+        Ok(())
+    }
+
+    /// Restores the snapshot if the slot it was taken from is running again (i.e. the update
+    /// was rolled back), or discards it if the running firmware is already validated
+    ///
+    /// To be called on boot, before the configuration is read.
+    pub fn check(&self) -> Result<RollbackState, EspError> {
+        let snapshot = EspNvs::new(self.partition.clone(), &self.snapshot_namespace, true)?;
+
+        let slot = if let Some(slot) = Self::get_str(&snapshot, SLOT_KEY)? {
+            slot
+        } else {
+            return Ok(RollbackState::Clean);
+        };
+
+        let (running, running_version) = Self::running()?;
+
+        if running == slot {
+            let version = Self::get_str(&snapshot, VERSION_KEY)?.unwrap_or_default();
+
+            warn!(
+                "Slot {} is running again, restoring the configuration of firmware {} (running {})",
+                slot, version, running_version
+            );
+
+            let mut config = EspNvs::new(self.partition.clone(), &self.namespace, true)?;
+
+            config.remove_all()?;
+
+            self.copy(&self.snapshot_namespace, &snapshot, &mut config)?;
+
+            // The metadata of the snapshot is copied along
+            config.remove(SLOT_KEY)?;
+            config.remove(VERSION_KEY)?;
+
+            drop(snapshot);
+            self.commit()?;
+
+            Ok(RollbackState::Restored)
+        } else if Self::running_valid()? {
+            drop(snapshot);
+            self.commit()?;
+
+            Ok(RollbackState::Committed)
+        } else {
+            Ok(RollbackState::Pending)
+        }
+    }
+
+    /// Discards the snapshot, once the new firmware is validated
+    pub fn commit(&self) -> Result<(), EspError> {
+        let mut snapshot = EspNvs::new(self.partition.clone(), &self.snapshot_namespace, true)?;
+
+        // The slot first, so that an interrupted removal leaves no restorable snapshot
+        snapshot.remove(SLOT_KEY)?;
+        snapshot.remove_all()?;
+
+        debug!("Discarded configuration snapshot");
+
+        Ok(())
+    }
+
+    fn copy(&self, namespace: &str, from: &EspNvs<T>, to: &mut EspNvs<T>) -> Result<(), EspError> {
+        for entry in self.partition.entries(Some(namespace))? {
+            let key = entry.key.as_str();
+
+            let result = match entry.data_type {
+                NvsDataType::U8 => from.get_u8(key)?.map(|v| to.set_u8(key, v)),
+                NvsDataType::I8 => from.get_i8(key)?.map(|v| to.set_i8(key, v)),
+                NvsDataType::U16 => from.get_u16(key)?.map(|v| to.set_u16(key, v)),
+                NvsDataType::I16 => from.get_i16(key)?.map(|v| to.set_i16(key, v)),
+                NvsDataType::U32 => from.get_u32(key)?.map(|v| to.set_u32(key, v)),
+                NvsDataType::I32 => from.get_i32(key)?.map(|v| to.set_i32(key, v)),
+                NvsDataType::U64 => from.get_u64(key)?.map(|v| to.set_u64(key, v)),
+                NvsDataType::I64 => from.get_i64(key)?.map(|v| to.set_i64(key, v)),
+                NvsDataType::Str => Self::get_str(from, key)?.map(|v| to.set_str(key, &v)),
+                NvsDataType::Blob => {
+                    let len = from.blob_len(key)?.unwrap_or(0);
+                    let mut buf = vec![0; len];
+
+                    from.get_blob(key, &mut buf)?.map(|v| to.set_blob(key, v))
+                }
+            };
+
+            result.transpose()?;
+        }
+
+        Ok(())
+    }
+
+    fn get_str(nvs: &EspNvs<T>, key: &str) -> Result<Option<String>, EspError> {
+        let len = if let Some(len) = nvs.str_len(key)? {
+            len
+        } else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0; len];
+
+        Ok(nvs
+            .get_str(key, &mut buf)?
+            .map(|s| s.trim_end_matches('\0').into()))
+    }
+
+    /// The label of the running slot, and the version of its firmware
+    fn running() -> Result<(String, String), EspError> {
+        let partition = unsafe { esp_ota_get_running_partition().as_ref() }
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_NOT_FOUND>)?;
+
+        #[cfg(esp_idf_version_major = "4")]
+        let app_desc = unsafe { esp_ota_get_app_description().as_ref() };
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let app_desc = unsafe { esp_app_get_description().as_ref() };
+
+        let version = app_desc
+            .map(|app_desc| unsafe { from_cstr_ptr(&app_desc.version as *const _) }.into())
+            .unwrap_or_default();
+
+        Ok((
+            unsafe { from_cstr_ptr(&partition.label as *const _ as *const _) }.into(),
+            version,
+        ))
+    }
+
+    #[allow(non_upper_case_globals)]
+    fn running_valid() -> Result<bool, EspError> {
+        let partition = unsafe { esp_ota_get_running_partition() };
+
+        let mut state: esp_ota_img_states_t = Default::default();
+
+        match unsafe { esp_ota_get_state_partition(partition, &mut state) } {
+            // The factory app, or no OTA data: there is nothing to roll back to
+            ESP_ERR_NOT_SUPPORTED | ESP_ERR_NOT_FOUND => Ok(true),
+            err => {
+                esp!(err)?;
+
+                // Without rollback support, the new images stay in the undefined state
+                Ok(matches!(
+                    state,
+                    esp_ota_img_states_t_ESP_OTA_IMG_VALID
+                        | esp_ota_img_states_t_ESP_OTA_IMG_UNDEFINED
+                ))
+            }
+        }
+    }
+}
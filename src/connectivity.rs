@@ -0,0 +1,283 @@
+//! Network connectivity monitor
+//!
+//! Periodically verifies real internet reachability (DNS resolution, HTTP probes or pings),
+//! publishes `ConnectivityEvent` transitions on the system event loop and optionally
+//! triggers recovery actions after a given number of consecutive failed checks.
+use core::ffi;
+use core::time::Duration;
+
+use std::net::ToSocketAddrs;
+use std::string::String;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::ipv4;
+use embedded_svc::ping;
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSystemEventLoop, EspTypedEventDeserializer,
+    EspTypedEventSerializer, EspTypedEventSource,
+};
+use crate::ping::EspPing;
+use crate::private::mutex::Mutex;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Probe {
+    /// Resolve the given host name
+    Dns(String),
+    /// Issue a GET request to the given URL and expect a `204 No Content` (or any 2xx) response
+    #[cfg(all(feature = "experimental", esp_idf_comp_esp_http_client_enabled))]
+    Http(String),
+    /// Ping the given address
+    Ping(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] ipv4::Ipv4Addr),
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub probes: Vec<Probe>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Reconnect the WiFi STA interface after this many consecutive offline checks
+    pub reconnect_wifi_after: Option<u32>,
+    /// Restart the chip after this many consecutive offline checks
+    pub restart_after: Option<u32>,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            probes: vec![
+                Probe::Dns("pool.ntp.org".into()),
+                #[cfg(all(feature = "experimental", esp_idf_comp_esp_http_client_enabled))]
+                Probe::Http("http://connectivitycheck.gstatic.com/generate_204".into()),
+            ],
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+            reconnect_wifi_after: Some(3),
+            restart_after: None,
+            stack_size: 6144,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum ConnectivityStatus {
+    Unknown,
+    /// All probes succeeded
+    Online,
+    /// Some, but not all probes succeeded
+    Degraded,
+    /// None of the probes succeeded
+    Offline,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct ConnectivityEvent {
+    pub previous: ConnectivityStatus,
+    pub current: ConnectivityStatus,
+}
+
+impl EspTypedEventSource for ConnectivityEvent {
+    fn source() -> *const ffi::c_char {
+        b"ESP-CONNECTIVITY\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<ConnectivityEvent> for ConnectivityEvent {
+    fn serialize<R>(
+        event: &ConnectivityEvent,
+        f: impl for<'a> FnOnce(&'a EspEventPostData) -> R,
+    ) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<ConnectivityEvent> for ConnectivityEvent {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a ConnectivityEvent) -> R,
+    ) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+pub struct ConnectivityMonitor {
+    status: Arc<Mutex<ConnectivityStatus>>,
+    stop: mpsc::Sender<()>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl ConnectivityMonitor {
+    pub fn new(conf: &Configuration, sysloop: EspSystemEventLoop) -> Result<Self, EspError> {
+        if conf.probes.is_empty() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let status = Arc::new(Mutex::new(ConnectivityStatus::Unknown));
+        let (stop, stopped) = mpsc::channel();
+
+        let join_handle = {
+            let conf = conf.clone();
+            let status = status.clone();
+
+            thread::Builder::new()
+                .name("connectivity".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, status, sysloop, stopped))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        info!("Started connectivity monitor");
+
+        Ok(Self {
+            status,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    pub fn status(&self) -> ConnectivityStatus {
+        *self.status.lock()
+    }
+
+    fn run(
+        conf: Configuration,
+        status: Arc<Mutex<ConnectivityStatus>>,
+        sysloop: EspSystemEventLoop,
+        stopped: mpsc::Receiver<()>,
+    ) {
+        let mut failures = 0_u32;
+
+        loop {
+            let succeeded = conf
+                .probes
+                .iter()
+                .filter(|probe| Self::check(probe, conf.timeout))
+                .count();
+
+            let current = if succeeded == conf.probes.len() {
+                ConnectivityStatus::Online
+            } else if succeeded > 0 {
+                ConnectivityStatus::Degraded
+            } else {
+                ConnectivityStatus::Offline
+            };
+
+            let previous = core::mem::replace(&mut *status.lock(), current);
+
+            if previous != current {
+                info!("Connectivity changed: {:?} -> {:?}", previous, current);
+
+                let event = ConnectivityEvent { previous, current };
+
+                if let Err(e) = sysloop.post(&event, None) {
+                    warn!("Failed to post connectivity event: {}", e);
+                }
+            }
+
+            if current == ConnectivityStatus::Offline {
+                failures += 1;
+
+                Self::recover(&conf, failures);
+            } else {
+                failures = 0;
+            }
+
+            match stopped.recv_timeout(conf.interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                _ => break,
+            }
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn recover(conf: &Configuration, failures: u32) {
+        if conf.restart_after == Some(failures) {
+            warn!("Offline for {} consecutive checks, restarting", failures);
+
+            unsafe { esp_restart() };
+        }
+
+        #[cfg(esp_idf_comp_esp_wifi_enabled)]
+        if let Some(reconnect_after) = conf.reconnect_wifi_after {
+            if reconnect_after > 0 && failures % reconnect_after == 0 {
+                warn!(
+                    "Offline for {} consecutive checks, reconnecting WiFi",
+                    failures
+                );
+
+                // Errors are ignored on purpose: the STA might not even be started
+                unsafe {
+                    esp_wifi_disconnect();
+                    esp_wifi_connect();
+                }
+            }
+        }
+    }
+
+    fn check(probe: &Probe, timeout: Duration) -> bool {
+        let result = match probe {
+            Probe::Dns(host) => (host.as_str(), 0)
+                .to_socket_addrs()
+                .map(|mut addrs| addrs.next().is_some())
+                .unwrap_or(false),
+            #[cfg(all(feature = "experimental", esp_idf_comp_esp_http_client_enabled))]
+            Probe::Http(url) => Self::check_http(url, timeout).unwrap_or(false),
+            Probe::Ping(addr) => EspPing::default()
+                .ping(
+                    *addr,
+                    &ping::Configuration {
+                        count: 1,
+                        timeout,
+                        ..Default::default()
+                    },
+                )
+                .map(|summary| summary.received > 0)
+                .unwrap_or(false),
+        };
+
+        debug!("Connectivity probe {:?}: {}", probe, result);
+
+        result
+    }
+
+    #[cfg(all(feature = "experimental", esp_idf_comp_esp_http_client_enabled))]
+    fn check_http(url: &str, timeout: Duration) -> Result<bool, EspError> {
+        use crate::http::client::{Configuration, EspHttpConnection};
+        use embedded_svc::http::Method;
+
+        let mut connection = EspHttpConnection::new(&Configuration {
+            timeout: Some(timeout),
+            ..Default::default()
+        })?;
+
+        connection.initiate_request(Method::Get, url, &[])?;
+        connection.initiate_response()?;
+
+        Ok((200..300).contains(&connection.status()))
+    }
+}
+
+impl Drop for ConnectivityMonitor {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
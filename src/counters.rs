@@ -0,0 +1,341 @@
+//! Persistent counters
+//!
+//! `EspCounters` keeps named `u64` counters in RTC memory, which survives software resets
+//! (`esp_restart`, panics, watchdogs) but not power loss, and checkpoints them to NVS
+//! periodically. Incrementing a counter is then cheap, and does not wear the flash:
+//!
+//! ```ignore
+//! let counters = EspCounters::new(nvs_partition.clone(), &Default::default())?;
+//!
+//! counters.increment("mqtt_rx")?;
+//!
+//! info!("Boots: {}, uptime: {}s", counters.get(BOOTS)?, counters.get(UPTIME)?);
+//! ```
+//!
+//! After a power loss, the counters are restored from their last checkpoint, so the
+//! increments since then are lost; `EspCounters::checkpoint` saves them right away, e.g. from
+//! a brownout or shutdown hook.
+//!
+//! The names are NVS keys, i.e. up to 15 characters, and at most `MAX_COUNTERS` counters can be
+//! used.
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::sync::Arc;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+use crate::private::mutex::{Mutex, RawMutex};
+use crate::timer::{EspTaskTimerService, EspTimer};
+
+/// How many counters fit in the RTC memory
+pub const MAX_COUNTERS: usize = 16;
+
+/// The number of boots, counted by `EspCounters::new`
+pub const BOOTS: &str = "boots";
+/// The cumulated uptime in seconds, updated on every checkpoint
+pub const UPTIME: &str = "uptime";
+
+const NAMESPACE: &str = "counters";
+const MAX_NAME_LEN: usize = 15;
+const MAGIC: u32 = 0x434e_5452;
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Slot {
+    name: [u8; MAX_NAME_LEN],
+    /// 0 for a free slot
+    name_len: u8,
+    value: u64,
+    /// The value in NVS
+    checkpointed: u64,
+}
+
+impl Slot {
+    const FREE: Self = Self {
+        name: [0; MAX_NAME_LEN],
+        name_len: 0,
+        value: 0,
+        checkpointed: 0,
+    };
+
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("")
+    }
+}
+
+#[repr(C)]
+struct Table {
+    magic: u32,
+    checksum: u32,
+    slots: [Slot; MAX_COUNTERS],
+}
+
+impl Table {
+    /// Whether the table survived the last reset, rather than being garbage after a power-on
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC
+            && self.checksum == self.compute_checksum()
+            && self
+                .slots
+                .iter()
+                .all(|slot| slot.name_len as usize <= MAX_NAME_LEN)
+    }
+
+    fn clear(&mut self) {
+        self.magic = MAGIC;
+        self.slots = [Slot::FREE; MAX_COUNTERS];
+        self.seal();
+    }
+
+    fn seal(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+
+    /// FNV-1a over the slots
+    fn compute_checksum(&self) -> u32 {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                self.slots.as_ptr() as *const u8,
+                core::mem::size_of_val(&self.slots),
+            )
+        };
+
+        bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+            (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+        })
+    }
+
+    /// Returns the slot of `name`, allocating it with its value in NVS if it is not used since
+    /// the last power-on
+    fn slot<T: NvsPartitionId>(
+        &mut self,
+        name: &str,
+        nvs: &EspNvs<T>,
+    ) -> Result<&mut Slot, EspError> {
+        let index = self
+            .slots
+            .iter()
+            .position(|slot| slot.name_len > 0 && slot.name() == name);
+
+        let index = match index {
+            Some(index) => index,
+            None => {
+                let index = self
+                    .slots
+                    .iter()
+                    .position(|slot| slot.name_len == 0)
+                    .ok_or_else(EspError::from_infallible::<ESP_ERR_NO_MEM>)?;
+
+                let checkpointed = nvs.get_u64(name)?.unwrap_or(0);
+
+                let slot = &mut self.slots[index];
+
+                slot.name[..name.len()].copy_from_slice(name.as_bytes());
+                slot.name_len = name.len() as _;
+                slot.value = checkpointed;
+                slot.checkpointed = checkpointed;
+
+                index
+            }
+        };
+
+        Ok(&mut self.slots[index])
+    }
+}
+
+/// Not initialized on boot, so that the counters survive software resets
+#[link_section = ".rtc_noinit"]
+static mut TABLE: Table = Table {
+    magic: 0,
+    checksum: 0,
+    slots: [Slot::FREE; MAX_COUNTERS],
+};
+
+/// Guards `TABLE`
+static LOCK: Mutex<()> = Mutex::wrap(RawMutex::new(), ());
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// How often the changed counters are saved to NVS
+    pub checkpoint_interval: Duration,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            checkpoint_interval: Duration::from_secs(600),
+        }
+    }
+}
+
+struct State<T: NvsPartitionId> {
+    nvs: EspNvs<T>,
+    /// The uptime at the last update of `UPTIME`, in microseconds
+    uptime: i64,
+}
+
+#[derive(Clone)]
+pub struct EspCounters<T: NvsPartitionId> {
+    state: Arc<Mutex<State<T>>>,
+    _timer: Arc<EspTimer>,
+}
+
+impl<T> EspCounters<T>
+where
+    T: NvsPartitionId + Send + 'static,
+{
+    /// Restores the counters, and counts the current boot
+    ///
+    /// Only one instance can exist, as all of them would share the RTC memory.
+    pub fn new(partition: EspNvsPartition<T>, conf: &Configuration) -> Result<Self, EspError> {
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let nvs = match EspNvs::new(partition, NAMESPACE, true) {
+            Ok(nvs) => nvs,
+            Err(e) => {
+                TAKEN.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+
+        {
+            let _guard = LOCK.lock();
+
+            let table = unsafe { &mut TABLE };
+
+            if table.is_valid() {
+                info!("Counters survived the reset");
+            } else {
+                info!("Counters lost, restoring them from NVS");
+                table.clear();
+            }
+        }
+
+        let state = Arc::new(Mutex::new(State {
+            nvs,
+            uptime: unsafe { esp_timer_get_time() },
+        }));
+
+        let timer = {
+            let state = state.clone();
+
+            EspTaskTimerService::new()?.timer(move || {
+                if let Err(e) = Self::checkpoint_state(&mut state.lock()) {
+                    warn!("Failed to checkpoint the counters: {}", e);
+                }
+            })?
+        };
+
+        timer.every(conf.checkpoint_interval)?;
+
+        let this = Self {
+            state,
+            _timer: Arc::new(timer),
+        };
+
+        this.increment(BOOTS)?;
+
+        // Not to lose the boot on a power loss shortly after
+        this.checkpoint()?;
+
+        Ok(this)
+    }
+
+    pub fn get(&self, name: &str) -> Result<u64, EspError> {
+        self.update(name, |value| value)
+    }
+
+    pub fn increment(&self, name: &str) -> Result<u64, EspError> {
+        self.add(name, 1)
+    }
+
+    /// Adds `delta` to the counter, and returns its new value
+    pub fn add(&self, name: &str, delta: u64) -> Result<u64, EspError> {
+        self.update(name, |value| value.wrapping_add(delta))
+    }
+
+    pub fn set(&self, name: &str, value: u64) -> Result<(), EspError> {
+        self.update(name, |_| value).map(|_| ())
+    }
+
+    pub fn reset(&self, name: &str) -> Result<(), EspError> {
+        self.set(name, 0)
+    }
+
+    /// Saves the changed counters to NVS
+    pub fn checkpoint(&self) -> Result<(), EspError> {
+        Self::checkpoint_state(&mut self.state.lock())
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(u64) -> u64) -> Result<u64, EspError> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let state = self.state.lock();
+
+        let _guard = LOCK.lock();
+
+        let table = unsafe { &mut TABLE };
+
+        let slot = table.slot(name, &state.nvs)?;
+
+        slot.value = f(slot.value);
+
+        let value = slot.value;
+
+        table.seal();
+
+        Ok(value)
+    }
+
+    fn checkpoint_state(state: &mut State<T>) -> Result<(), EspError> {
+        let now = unsafe { esp_timer_get_time() };
+
+        let _guard = LOCK.lock();
+
+        let table = unsafe { &mut TABLE };
+
+        let elapsed = ((now - state.uptime) / 1_000_000).max(0) as u64;
+
+        if elapsed > 0 {
+            // Only the whole seconds, the rest is counted on the next checkpoint
+            state.uptime += elapsed as i64 * 1_000_000;
+
+            table.slot(UPTIME, &state.nvs)?.value += elapsed;
+        }
+
+        let mut result = Ok(());
+
+        for slot in table.slots.iter_mut() {
+            if slot.name_len > 0 && slot.value != slot.checkpointed {
+                match state.nvs.set_u64(slot.name(), slot.value) {
+                    Ok(()) => slot.checkpointed = slot.value,
+                    Err(e) => result = Err(e),
+                }
+            }
+        }
+
+        table.seal();
+
+        result
+    }
+}
+
+impl<T: NvsPartitionId> Drop for State<T> {
+    fn drop(&mut self) {
+        TAKEN.store(false, Ordering::SeqCst);
+
+        info!("Dropped");
+    }
+}
@@ -0,0 +1,645 @@
+//! Hashes, MACs and ciphers, over the hardware accelerated mbedTLS implementations
+//!
+//! The types implement the RustCrypto traits (`digest::Digest`, `digest::Mac`,
+//! `aead::AeadInPlace` and `cipher::StreamCipher`), so they can replace the software
+//! implementations of the `sha2`, `hmac`, `aes-gcm` and `ctr` crates:
+//!
+//! ```ignore
+//! use esp_idf_svc::crypto::{Aes256Gcm, HmacSha256, Sha256};
+//! use esp_idf_svc::crypto::aead::{AeadInPlace, KeyInit};
+//! use esp_idf_svc::crypto::digest::{Digest, Mac};
+//!
+//! let digest = Sha256::digest(b"payload");
+//!
+//! let mut mac = HmacSha256::new_from_slice(b"secret")?;
+//! mac.update(b"payload");
+//! let tag = mac.finalize().into_bytes();
+//!
+//! let cipher = Aes256Gcm::new(&key.into());
+//! let tag = cipher.encrypt_in_place_detached(&nonce.into(), b"", &mut payload)?;
+//! ```
+//!
+//! The eFuse keys of the HMAC peripheral of the ESP32-S2, ESP32-S3 and ESP32-C3, which the
+//! firmware cannot read, are available through `efuse_hmac_sha256`.
+//!
+//! Note: This module requires the `crypto` cargo feature to be enabled.
+use core::mem::MaybeUninit;
+use core::ptr;
+
+pub use aead;
+pub use cipher;
+pub use digest;
+
+use aead::{AeadCore, AeadInPlace, Nonce, Tag};
+use cipher::inout::InOutBuf;
+use cipher::{
+    InvalidLength, Iv, IvSizeUser, Key, KeyInit, KeyIvInit, KeySizeUser, StreamCipher,
+    StreamCipherError,
+};
+use digest::consts::{U0, U12, U128, U16, U20, U32, U64};
+use digest::{
+    FixedOutput, FixedOutputReset, HashMarker, MacMarker, Output, OutputSizeUser, Reset, Update,
+};
+
+use esp_idf_sys::*;
+
+macro_rules! hash {
+    (
+        $(#[$meta:meta])*
+        $name:ident,
+        $context:ty,
+        $size:ty,
+        $init:ident,
+        $free:ident,
+        $clone:ident,
+        v4: ($starts_ret:ident, $update_ret:ident, $finish_ret:ident),
+        v5: ($starts:ident, $update:ident, $finish:ident)
+        $(, $variant:expr)?
+    ) => {
+        $(#[$meta])*
+        pub struct $name($context);
+
+        impl $name {
+            pub fn new() -> Self {
+                let mut context = MaybeUninit::<$context>::uninit();
+
+                unsafe {
+                    $init(context.as_mut_ptr());
+
+                    let mut hash = Self(context.assume_init());
+                    hash.start();
+
+                    hash
+                }
+            }
+
+            fn start(&mut self) {
+                unsafe {
+                    #[cfg(esp_idf_version_major = "4")]
+                    $starts_ret(&mut self.0 $(, $variant)?);
+                    #[cfg(not(esp_idf_version_major = "4"))]
+                    $starts(&mut self.0 $(, $variant)?);
+                }
+            }
+
+            fn finish(&mut self, out: &mut Output<Self>) {
+                unsafe {
+                    #[cfg(esp_idf_version_major = "4")]
+                    $finish_ret(&mut self.0, out.as_mut_ptr());
+                    #[cfg(not(esp_idf_version_major = "4"))]
+                    $finish(&mut self.0, out.as_mut_ptr());
+                }
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl Clone for $name {
+            fn clone(&self) -> Self {
+                let mut context = MaybeUninit::<$context>::uninit();
+
+                unsafe {
+                    $init(context.as_mut_ptr());
+                    $clone(context.as_mut_ptr(), &self.0);
+
+                    Self(context.assume_init())
+                }
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                unsafe { $free(&mut self.0) };
+            }
+        }
+
+        unsafe impl Send for $name {}
+
+        impl HashMarker for $name {}
+
+        impl OutputSizeUser for $name {
+            type OutputSize = $size;
+        }
+
+        impl Update for $name {
+            fn update(&mut self, data: &[u8]) {
+                unsafe {
+                    #[cfg(esp_idf_version_major = "4")]
+                    $update_ret(&mut self.0, data.as_ptr(), data.len() as _);
+                    #[cfg(not(esp_idf_version_major = "4"))]
+                    $update(&mut self.0, data.as_ptr(), data.len() as _);
+                }
+            }
+        }
+
+        impl FixedOutput for $name {
+            fn finalize_into(mut self, out: &mut Output<Self>) {
+                self.finish(out);
+            }
+        }
+
+        impl Reset for $name {
+            fn reset(&mut self) {
+                self.start();
+            }
+        }
+
+        impl FixedOutputReset for $name {
+            fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+                self.finish(out);
+                self.start();
+            }
+        }
+    };
+}
+
+hash!(
+    /// SHA-1, only for the legacy protocols which require it
+    Sha1,
+    mbedtls_sha1_context,
+    U20,
+    mbedtls_sha1_init,
+    mbedtls_sha1_free,
+    mbedtls_sha1_clone,
+    v4: (mbedtls_sha1_starts_ret, mbedtls_sha1_update_ret, mbedtls_sha1_finish_ret),
+    v5: (mbedtls_sha1_starts, mbedtls_sha1_update, mbedtls_sha1_finish)
+);
+
+hash!(
+    Sha256,
+    mbedtls_sha256_context,
+    U32,
+    mbedtls_sha256_init,
+    mbedtls_sha256_free,
+    mbedtls_sha256_clone,
+    v4: (mbedtls_sha256_starts_ret, mbedtls_sha256_update_ret, mbedtls_sha256_finish_ret),
+    v5: (mbedtls_sha256_starts, mbedtls_sha256_update, mbedtls_sha256_finish),
+    0
+);
+
+hash!(
+    Sha512,
+    mbedtls_sha512_context,
+    U64,
+    mbedtls_sha512_init,
+    mbedtls_sha512_free,
+    mbedtls_sha512_clone,
+    v4: (mbedtls_sha512_starts_ret, mbedtls_sha512_update_ret, mbedtls_sha512_finish_ret),
+    v5: (mbedtls_sha512_starts, mbedtls_sha512_update, mbedtls_sha512_finish),
+    0
+);
+
+macro_rules! hmac {
+    ($name:ident, $md:expr, $block_size:ty, $size:ty) => {
+        /// HMAC, with a key of any length
+        pub struct $name(mbedtls_md_context_t);
+
+        impl $name {
+            fn finish(&mut self, out: &mut Output<Self>) {
+                unsafe { mbedtls_md_hmac_finish(&mut self.0, out.as_mut_ptr()) };
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                unsafe { mbedtls_md_free(&mut self.0) };
+            }
+        }
+
+        unsafe impl Send for $name {}
+
+        impl MacMarker for $name {}
+
+        impl KeySizeUser for $name {
+            // The block size, as for the `hmac` crate
+            type KeySize = $block_size;
+        }
+
+        impl KeyInit for $name {
+            fn new(key: &Key<Self>) -> Self {
+                Self::new_from_slice(key).unwrap()
+            }
+
+            fn new_from_slice(key: &[u8]) -> Result<Self, InvalidLength> {
+                let mut context = MaybeUninit::<mbedtls_md_context_t>::uninit();
+
+                unsafe {
+                    mbedtls_md_init(context.as_mut_ptr());
+
+                    // Freed by the drop on failure
+                    let mut mac = Self(context.assume_init());
+
+                    if mbedtls_md_setup(&mut mac.0, mbedtls_md_info_from_type($md), 1) != 0
+                        || mbedtls_md_hmac_starts(&mut mac.0, key.as_ptr(), key.len() as _) != 0
+                    {
+                        return Err(InvalidLength);
+                    }
+
+                    Ok(mac)
+                }
+            }
+        }
+
+        impl OutputSizeUser for $name {
+            type OutputSize = $size;
+        }
+
+        impl Update for $name {
+            fn update(&mut self, data: &[u8]) {
+                unsafe {
+                    #[cfg(esp_idf_version_major = "4")]
+                    $update_ret(&mut self.0, data.as_ptr(), data.len() as _);
+                    #[cfg(not(esp_idf_version_major = "4"))]
+                    $update(&mut self.0, data.as_ptr(), data.len() as _);
+                }
+            }
+        }
+
+        impl FixedOutput for $name {
+            fn finalize_into(mut self, out: &mut Output<Self>) {
+                self.finish(out);
+            }
+        }
+
+        impl Reset for $name {
+            fn reset(&mut self) {
+                self.start();
+            }
+        }
+
+        impl FixedOutputReset for $name {
+            fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+                self.finish(out);
+                self.start();
+            }
+        }
+    };
+}
+
+hash!(
+    /// SHA-1, only for the legacy protocols which require it
+    Sha1,
+    mbedtls_sha1_context,
+    U20,
+    mbedtls_sha1_init,
+    mbedtls_sha1_free,
+    mbedtls_sha1_clone,
+    v4: (mbedtls_sha1_starts_ret, mbedtls_sha1_update_ret, mbedtls_sha1_finish_ret),
+    v5: (mbedtls_sha1_starts, mbedtls_sha1_update, mbedtls_sha1_finish)
+);
+
+hash!(
+    Sha256,
+    mbedtls_sha256_context,
+    U32,
+    mbedtls_sha256_init,
+    mbedtls_sha256_free,
+    mbedtls_sha256_clone,
+    v4: (mbedtls_sha256_starts_ret, mbedtls_sha256_update_ret, mbedtls_sha256_finish_ret),
+    v5: (mbedtls_sha256_starts, mbedtls_sha256_update, mbedtls_sha256_finish),
+    0
+);
+
+hash!(
+    Sha512,
+    mbedtls_sha512_context,
+    U64,
+    mbedtls_sha512_init,
+    mbedtls_sha512_free,
+    mbedtls_sha512_clone,
+    v4: (mbedtls_sha512_starts_ret, mbedtls_sha512_update_ret, mbedtls_sha512_finish_ret),
+    v5: (mbedtls_sha512_starts, mbedtls_sha512_update, mbedtls_sha512_finish),
+    0
+);
+
+macro_rules! hmac {
+    ($name:ident, $md:expr, $block_size:ty, $size:ty) => {
+        /// HMAC, with a key of any length
+        pub struct $name(mbedtls_md_context_t);
+
+        impl $name {
+            fn finish(&mut self, out: &mut Output<Self>) {
+                unsafe { mbedtls_md_hmac_finish(&mut self.0, out.as_mut_ptr()) };
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                unsafe { mbedtls_md_free(&mut self.0) };
+            }
+        }
+
+        unsafe impl Send for $name {}
+
+        impl MacMarker for $name {}
+
+        impl KeySizeUser for $name {
+            // The block size, as for the `hmac` crate
+            type KeySize = $block_size;
+        }
+
+        impl KeyInit for $name {
+            fn new(key: &Key<Self>) -> Self {
+                Self::new_from_slice(key).unwrap()
+            }
+
+            fn new_from_slice(key: &[u8]) -> Result<Self, InvalidLength> {
+                let mut context = MaybeUninit::<mbedtls_md_context_t>::uninit();
+
+                unsafe {
+                    mbedtls_md_init(context.as_mut_ptr());
+
+                    // Freed on failure as well
+                    let mac = Self(context.assume_init());
+
+                    let mut context = mac.0;
+
+                    if mbedtls_md_setup(&mut context, mbedtls_md_info_from_type($md), 1) != 0
+                        || mbedtls_md_hmac_starts(&mut context, key.as_ptr(), key.len() as _) != 0
+                    {
+                        mbedtls_md_free(&mut context);
+                        core::mem::forget(mac);
+
+                        return Err(InvalidLength);
+                    }
+
+                    core::mem::forget(mac);
+
+                    Ok(Self(context))
+                }
+            }
+        }
+
+        impl OutputSizeUser for $name {
+            type OutputSize = $size;
+        }
+
+        impl Update for $name {
+            fn update(&mut self, data: &[u8]) {
+                unsafe { mbedtls_md_hmac_update(&mut self.0, data.as_ptr(), data.len() as _) };
+            }
+        }
+
+        impl FixedOutput for $name {
+            fn finalize_into(mut self, out: &mut Output<Self>) {
+                self.finish(out);
+            }
+        }
+
+        impl Reset for $name {
+            fn reset(&mut self) {
+                unsafe { mbedtls_md_hmac_reset(&mut self.0) };
+            }
+        }
+
+        impl FixedOutputReset for $name {
+            fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+                self.finish(out);
+                self.reset();
+            }
+        }
+    };
+}
+
+hmac!(HmacSha1, mbedtls_md_type_t_MBEDTLS_MD_SHA1, U64, U20);
+hmac!(HmacSha256, mbedtls_md_type_t_MBEDTLS_MD_SHA256, U64, U32);
+hmac!(HmacSha512, mbedtls_md_type_t_MBEDTLS_MD_SHA512, U128, U64);
+
+macro_rules! aes_gcm {
+    ($name:ident, $key_size:ty) => {
+        /// AES-GCM, with 96-bit nonces and 128-bit tags
+        #[derive(Clone)]
+        pub struct $name(Key<Self>);
+
+        impl $name {
+            fn crypt(
+                &self,
+                nonce: &Nonce<Self>,
+                associated_data: &[u8],
+                buffer: &mut [u8],
+                tag: Option<&Tag<Self>>,
+            ) -> Result<Tag<Self>, aead::Error> {
+                let mut context: mbedtls_gcm_context = Default::default();
+                let mut computed = Tag::<Self>::default();
+
+                unsafe {
+                    mbedtls_gcm_init(&mut context);
+
+                    // Encrypting and decrypting in place is supported
+                    let result = if mbedtls_gcm_setkey(
+                        &mut context,
+                        mbedtls_cipher_id_t_MBEDTLS_CIPHER_ID_AES,
+                        self.0.as_ptr(),
+                        (self.0.len() * 8) as _,
+                    ) != 0
+                    {
+                        -1
+                    } else if let Some(tag) = tag {
+                        mbedtls_gcm_auth_decrypt(
+                            &mut context,
+                            buffer.len() as _,
+                            nonce.as_ptr(),
+                            nonce.len() as _,
+                            associated_data.as_ptr(),
+                            associated_data.len() as _,
+                            tag.as_ptr(),
+                            tag.len() as _,
+                            buffer.as_ptr(),
+                            buffer.as_mut_ptr(),
+                        )
+                    } else {
+                        mbedtls_gcm_crypt_and_tag(
+                            &mut context,
+                            MBEDTLS_GCM_ENCRYPT as _,
+                            buffer.len() as _,
+                            nonce.as_ptr(),
+                            nonce.len() as _,
+                            associated_data.as_ptr(),
+                            associated_data.len() as _,
+                            buffer.as_ptr(),
+                            buffer.as_mut_ptr(),
+                            computed.len() as _,
+                            computed.as_mut_ptr(),
+                        )
+                    };
+
+                    mbedtls_gcm_free(&mut context);
+
+                    if result != 0 {
+                        if tag.is_some() {
+                            // Never release an unauthenticated plaintext
+                            buffer.iter_mut().for_each(|byte| *byte = 0);
+                        }
+
+                        return Err(aead::Error);
+                    }
+                }
+
+                Ok(computed)
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                self.0
+                    .iter_mut()
+                    .for_each(|byte| unsafe { ptr::write_volatile(byte, 0) });
+            }
+        }
+
+        impl KeySizeUser for $name {
+            type KeySize = $key_size;
+        }
+
+        impl KeyInit for $name {
+            fn new(key: &Key<Self>) -> Self {
+                Self(key.clone())
+            }
+        }
+
+        impl AeadCore for $name {
+            type NonceSize = U12;
+            type TagSize = U16;
+            type CiphertextOverhead = U0;
+        }
+
+        impl AeadInPlace for $name {
+            fn encrypt_in_place_detached(
+                &self,
+                nonce: &Nonce<Self>,
+                associated_data: &[u8],
+                buffer: &mut [u8],
+            ) -> Result<Tag<Self>, aead::Error> {
+                self.crypt(nonce, associated_data, buffer, None)
+            }
+
+            fn decrypt_in_place_detached(
+                &self,
+                nonce: &Nonce<Self>,
+                associated_data: &[u8],
+                buffer: &mut [u8],
+                tag: &Tag<Self>,
+            ) -> Result<(), aead::Error> {
+                self.crypt(nonce, associated_data, buffer, Some(tag))
+                    .map(|_| ())
+            }
+        }
+    };
+}
+
+aes_gcm!(Aes128Gcm, U16);
+aes_gcm!(Aes256Gcm, U32);
+
+macro_rules! aes_ctr {
+    ($name:ident, $key_size:ty) => {
+        /// AES-CTR, with a 128-bit big endian counter block as the IV
+        pub struct $name {
+            context: mbedtls_aes_context,
+            counter: [u8; 16],
+            stream_block: [u8; 16],
+            offset: usize,
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                unsafe { mbedtls_aes_free(&mut self.context) };
+
+                self.stream_block
+                    .iter_mut()
+                    .for_each(|byte| unsafe { ptr::write_volatile(byte, 0) });
+            }
+        }
+
+        unsafe impl Send for $name {}
+
+        impl KeySizeUser for $name {
+            type KeySize = $key_size;
+        }
+
+        impl IvSizeUser for $name {
+            type IvSize = U16;
+        }
+
+        impl KeyIvInit for $name {
+            fn new(key: &Key<Self>, iv: &Iv<Self>) -> Self {
+                let mut context: mbedtls_aes_context = Default::default();
+
+                unsafe {
+                    mbedtls_aes_init(&mut context);
+
+                    // Cannot fail with the key sizes of AES
+                    mbedtls_aes_setkey_enc(&mut context, key.as_ptr(), (key.len() * 8) as _);
+                }
+
+                Self {
+                    context,
+                    counter: (*iv).into(),
+                    stream_block: [0; 16],
+                    offset: 0,
+                }
+            }
+        }
+
+        impl StreamCipher for $name {
+            fn try_apply_keystream_inout(
+                &mut self,
+                buf: InOutBuf<'_, '_, u8>,
+            ) -> Result<(), StreamCipherError> {
+                let len = buf.len();
+
+                // Encrypting and decrypting in place is supported
+                let (input, output) = buf.into_raw();
+
+                let result = unsafe {
+                    mbedtls_aes_crypt_ctr(
+                        &mut self.context,
+                        len as _,
+                        &mut self.offset,
+                        self.counter.as_mut_ptr(),
+                        self.stream_block.as_mut_ptr(),
+                        input,
+                        output,
+                    )
+                };
+
+                if result == 0 {
+                    Ok(())
+                } else {
+                    Err(StreamCipherError)
+                }
+            }
+        }
+    };
+}
+
+aes_ctr!(Aes128Ctr, U16);
+aes_ctr!(Aes256Ctr, U32);
+
+/// The HMAC-SHA256 of `message` with the eFuse key block `key` (0 to 5) of the HMAC peripheral,
+/// which must have been burnt with the `HMAC_UP` purpose
+///
+/// Fails with `ESP_ERR_INVALID_ARG` if `key` is out of range, and `ESP_FAIL` if the key block
+/// does not hold an HMAC key.
+#[cfg(any(esp32s2, esp32s3, esp32c3))]
+pub fn efuse_hmac_sha256(key: u8, message: &[u8]) -> Result<[u8; 32], EspError> {
+    if key > 5 {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+    }
+
+    let mut mac = [0_u8; 32];
+
+    esp!(unsafe {
+        esp_hmac_calculate(
+            hmac_key_id_t_HMAC_KEY0 + key as hmac_key_id_t,
+            message.as_ptr() as *const _,
+            message.len() as _,
+            mac.as_mut_ptr(),
+        )
+    })?;
+
+    Ok(mac)
+}
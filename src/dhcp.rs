@@ -0,0 +1,582 @@
+//! DHCP client lease monitoring
+//!
+//! `EspDhcpMonitor` follows the lifecycle of the lease of an interface, and posts a `DhcpEvent`
+//! on the system event loop on each of its transitions, with the details of the lease. This is
+//! meant for diagnosing the "IP silently changed" class of issues of the long-running devices:
+//!
+//! ```ignore
+//! let monitor = EspDhcpMonitor::new(&sysloop, Interface::Sta, &Default::default())?;
+//!
+//! let _subscription = sysloop.subscribe(|event: &DhcpEvent| {
+//!     info!("{:?}: {:?} {:?}", event.interface, event.state, event.lease);
+//! })?;
+//!
+//! // Or, from an async task
+//! let mut events = sysloop.as_async().subscribe::<DhcpEvent>()?;
+//! let event = events.recv().await;
+//!
+//! monitor.renew()?;
+//! ```
+//!
+//! A lease is `Bound` each time the DHCP server acknowledges it, then `Renewing` once half of
+//! its time has elapsed (T1), `Rebinding` once 87.5% of it has (T2), and `Lost` when it expires
+//! or when the address is lost, as in RFC 2131.
+//!
+//! The lease times and the server are only known with `CONFIG_LWIP_HOOK_DHCP_EXTRA_OPTION_CUSTOM`
+//! enabled, which lets the acknowledgements of the servers be parsed. Without it, the leases
+//! still go through `Bound` and `Lost`, but never through `Renewing` and `Rebinding`, as their
+//! timers are unknown.
+use core::ffi;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::sync::Arc;
+use std::thread;
+
+use ::log::*;
+
+use embedded_svc::ipv4;
+
+use esp_idf_sys::*;
+
+use crate::arp::{impl_of, tcpip_exec};
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSubscription, EspSystemEventLoop,
+    EspTypedEventDeserializer, EspTypedEventSerializer, EspTypedEventSource, System,
+};
+use crate::netif::{Interface, IpEvent};
+use crate::private::mutex::Mutex;
+#[cfg(esp_idf_lwip_hook_dhcp_extra_option_custom)]
+use crate::private::mutex::RawMutex;
+
+/// The number of interfaces whose DHCP acknowledgements are kept track of
+#[cfg(esp_idf_lwip_hook_dhcp_extra_option_custom)]
+const MAX_ACKS: usize = 4;
+
+/// A lease time meaning that the lease never expires
+#[cfg(esp_idf_lwip_hook_dhcp_extra_option_custom)]
+const INFINITE_LEASE: u32 = u32::MAX;
+
+extern "C" {
+    fn dhcp_renew(netif: *mut ffi::c_void) -> i8;
+
+    #[cfg(esp_idf_lwip_hook_dhcp_extra_option_custom)]
+    fn pbuf_copy_partial(
+        buf: *const ffi::c_void,
+        dataptr: *mut ffi::c_void,
+        len: u16,
+        offset: u16,
+    ) -> u16;
+}
+
+/// The last acknowledgement of a DHCP server received by an interface
+#[cfg(esp_idf_lwip_hook_dhcp_extra_option_custom)]
+#[derive(Copy, Clone, Debug)]
+struct Ack {
+    netif: usize,
+    msg: usize,
+    at: Duration,
+    server: Option<ipv4::Ipv4Addr>,
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+}
+
+#[cfg(esp_idf_lwip_hook_dhcp_extra_option_custom)]
+static ACKS: Mutex<[Option<Ack>; MAX_ACKS]> = Mutex::wrap(RawMutex::new(), [None; MAX_ACKS]);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum DhcpState {
+    /// The server acknowledged the lease
+    Bound,
+    /// The lease is being renewed with the server which granted it
+    Renewing,
+    /// The server did not renew the lease, which is being extended with any server
+    Rebinding,
+    /// The lease expired, was released, or its address was lost
+    Lost,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DhcpLease {
+    pub ip_info: ipv4::IpInfo,
+    /// The DHCP server which granted the lease
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub server: Option<ipv4::Ipv4Addr>,
+    /// `None` for the leases which never expire
+    pub lease_time: Option<Duration>,
+    /// The time after which the lease is renewed (T1)
+    pub renewal_time: Option<Duration>,
+    /// The time after which the lease is rebound (T2)
+    pub rebinding_time: Option<Duration>,
+    /// The time since boot at which the lease was acknowledged
+    pub obtained: Duration,
+}
+
+impl DhcpLease {
+    /// The state of the lease at the time since boot `now`, by its timers
+    pub fn state_at(&self, now: Duration) -> DhcpState {
+        let elapsed = now.saturating_sub(self.obtained);
+
+        let reached = |time: Option<Duration>| time.map(|time| elapsed >= time).unwrap_or(false);
+
+        if reached(self.lease_time) {
+            DhcpState::Lost
+        } else if reached(self.rebinding_time) {
+            DhcpState::Rebinding
+        } else if reached(self.renewal_time) {
+            DhcpState::Renewing
+        } else {
+            DhcpState::Bound
+        }
+    }
+
+    /// The time left until the lease expires
+    pub fn remaining(&self, now: Duration) -> Option<Duration> {
+        self.lease_time
+            .map(|lease_time| (self.obtained + lease_time).saturating_sub(now))
+    }
+}
+
+/// Posted on the system event loop on each transition of the lease of a monitored interface
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DhcpEvent {
+    pub interface: Interface,
+    pub state: DhcpState,
+    /// The lease, or the lost one with `DhcpState::Lost`
+    pub lease: Option<DhcpLease>,
+    /// Whether the address changed with the lease, with `DhcpState::Bound`
+    pub ip_changed: bool,
+}
+
+impl EspTypedEventSource for DhcpEvent {
+    fn source() -> *const ffi::c_char {
+        b"ESP-DHCP\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<DhcpEvent> for DhcpEvent {
+    fn serialize<R>(event: &DhcpEvent, f: impl for<'a> FnOnce(&'a EspEventPostData) -> R) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<DhcpEvent> for DhcpEvent {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a DhcpEvent) -> R,
+    ) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// How often the timers of the lease are checked
+    pub poll_interval: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(1),
+            stack_size: 3072,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Lifecycle {
+    state: Option<DhcpState>,
+    lease: Option<DhcpLease>,
+}
+
+struct Shared {
+    interface: Interface,
+    sysloop: EspSystemEventLoop,
+    lifecycle: Mutex<Lifecycle>,
+}
+
+impl Shared {
+    fn transition(
+        &self,
+        state: DhcpState,
+        lease: Option<DhcpLease>,
+        ip_changed: bool,
+        timeout: Option<Duration>,
+    ) {
+        {
+            let mut lifecycle = self.lifecycle.lock();
+
+            if state != DhcpState::Bound && lifecycle.state == Some(state) {
+                return;
+            }
+
+            lifecycle.state = Some(state);
+            lifecycle.lease = lease;
+        }
+
+        match (state, lease) {
+            (DhcpState::Bound, Some(lease)) => info!(
+                "{:?}: bound to {} by {:?} for {:?}",
+                self.interface, lease.ip_info.ip, lease.server, lease.lease_time
+            ),
+            (state, _) => info!("{:?}: {:?}", self.interface, state),
+        }
+
+        let event = DhcpEvent {
+            interface: self.interface,
+            state,
+            lease,
+            ip_changed,
+        };
+
+        if let Err(e) = self.sysloop.post(&event, timeout) {
+            warn!("Failed to post DHCP event: {}", e);
+        }
+    }
+
+    fn on_ip_event(&self, event: &IpEvent) {
+        let handle = match self.interface.handle() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+
+        if event.handle() != Some(handle) {
+            return;
+        }
+
+        // Not waiting, as this runs on the event loop itself
+        match event {
+            IpEvent::DhcpIpAssigned(assignment) => {
+                let lease = lease_of(handle, assignment.ip_settings);
+
+                self.transition(
+                    DhcpState::Bound,
+                    Some(lease),
+                    assignment.ip_changed,
+                    Some(Duration::ZERO),
+                );
+            }
+            IpEvent::DhcpIpDeassigned(_) => {
+                let lease = self.lifecycle.lock().lease;
+
+                self.transition(DhcpState::Lost, lease, false, Some(Duration::ZERO));
+            }
+            _ => (),
+        }
+    }
+
+    fn check_timers(&self) {
+        let (state, lease) = {
+            let lifecycle = self.lifecycle.lock();
+
+            match (lifecycle.state, lifecycle.lease) {
+                (Some(state), Some(lease)) if state != DhcpState::Lost => (state, lease),
+                _ => return,
+            }
+        };
+
+        let next = lease.state_at(now());
+
+        // A forced renewal is ahead of the timers
+        if next > state {
+            self.transition(next, Some(lease), false, None);
+        }
+    }
+}
+
+/// Follows the lease of the DHCP client of an interface, posting a `DhcpEvent` on each of its
+/// transitions
+pub struct EspDhcpMonitor {
+    shared: Arc<Shared>,
+    subscription: Option<EspSubscription<System>>,
+    running: Arc<AtomicBool>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspDhcpMonitor {
+    /// Starts monitoring `interface`, which does not need to be created yet
+    pub fn new(
+        sysloop: &EspSystemEventLoop,
+        interface: Interface,
+        conf: &Configuration,
+    ) -> Result<Self, EspError> {
+        let shared = Arc::new(Shared {
+            interface,
+            sysloop: sysloop.clone(),
+            lifecycle: Mutex::new(Default::default()),
+        });
+
+        let subscription = {
+            let shared = shared.clone();
+
+            sysloop.subscribe(move |event: &IpEvent| shared.on_ip_event(event))?
+        };
+
+        let running = Arc::new(AtomicBool::new(true));
+
+        let join_handle = {
+            let shared = shared.clone();
+            let running = running.clone();
+            let poll_interval = conf.poll_interval;
+
+            thread::Builder::new()
+                .name("dhcp".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || {
+                    while running.load(Ordering::SeqCst) {
+                        shared.check_timers();
+
+                        thread::sleep(poll_interval);
+                    }
+                })
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        info!("Monitoring the DHCP leases of {:?}", interface);
+
+        Ok(Self {
+            shared,
+            subscription: Some(subscription),
+            running,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    pub fn interface(&self) -> Interface {
+        self.shared.interface
+    }
+
+    /// `None` until a lease is first obtained
+    pub fn state(&self) -> Option<DhcpState> {
+        self.shared.lifecycle.lock().state
+    }
+
+    /// The current lease, or the lost one
+    pub fn lease(&self) -> Option<DhcpLease> {
+        self.shared.lifecycle.lock().lease
+    }
+
+    /// Renews the lease with its server right away, rather than at T1;
+    /// fails with `ESP_ERR_INVALID_STATE` if the DHCP client is not started
+    ///
+    /// The lease is `Bound` again once the server acknowledges it.
+    pub fn renew(&self) -> Result<(), EspError> {
+        let handle = self.shared.interface.handle()?;
+
+        if !is_dhcp_client_started(handle)? {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let netif = impl_of(handle)?;
+
+        let err = tcpip_exec(move || unsafe { dhcp_renew(netif as *mut _) })?;
+
+        if err != 0 {
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+
+        let lease = self.lease();
+
+        self.shared
+            .transition(DhcpState::Renewing, lease, false, None);
+
+        Ok(())
+    }
+
+    /// Releases the lease, and stops the DHCP client until `start` is called
+    pub fn release(&self) -> Result<(), EspError> {
+        let handle = self.shared.interface.handle()?;
+
+        esp!(unsafe { esp_netif_dhcpc_stop(handle) })?;
+
+        let lease = self.lease();
+
+        self.shared.transition(DhcpState::Lost, lease, false, None);
+
+        Ok(())
+    }
+
+    /// Starts the DHCP client again after a `release`, for it to obtain a new lease
+    pub fn start(&self) -> Result<(), EspError> {
+        let handle = self.shared.interface.handle()?;
+
+        esp!(unsafe { esp_netif_dhcpc_start(handle) })
+    }
+}
+
+impl Drop for EspDhcpMonitor {
+    fn drop(&mut self) {
+        self.subscription = None;
+
+        self.running.store(false, Ordering::SeqCst);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+fn is_dhcp_client_started(handle: *mut esp_netif_t) -> Result<bool, EspError> {
+    let mut status: esp_netif_dhcp_status_t = Default::default();
+    esp!(unsafe { esp_netif_dhcpc_get_status(handle, &mut status) })?;
+
+    Ok(status == esp_netif_dhcp_status_t_ESP_NETIF_DHCP_STARTED)
+}
+
+/// The lease of `ip_info`, with the times and the server of the last acknowledgement received
+/// by the interface, if known
+fn lease_of(handle: *mut esp_netif_t, ip_info: ipv4::IpInfo) -> DhcpLease {
+    #[cfg(esp_idf_lwip_hook_dhcp_extra_option_custom)]
+    if let Some(lease) = acked_lease(handle, ip_info) {
+        return lease;
+    }
+
+    let _ = handle;
+
+    DhcpLease {
+        ip_info,
+        server: None,
+        lease_time: None,
+        renewal_time: None,
+        rebinding_time: None,
+        obtained: now(),
+    }
+}
+
+#[cfg(esp_idf_lwip_hook_dhcp_extra_option_custom)]
+fn acked_lease(handle: *mut esp_netif_t, ip_info: ipv4::IpInfo) -> Option<DhcpLease> {
+    let netif = impl_of(handle).ok()?;
+
+    let ack = ACKS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|ack| ack.netif == netif)
+        .copied()?;
+
+    let lease_time = ack.lease_time.filter(|secs| *secs != INFINITE_LEASE);
+
+    // The defaults of RFC 2131, for the servers not sending the timers
+    let renewal_time = ack.renewal_time.or_else(|| lease_time.map(|secs| secs / 2));
+    let rebinding_time = ack
+        .rebinding_time
+        .or_else(|| lease_time.map(|secs| (secs as u64 * 7 / 8) as u32));
+
+    let secs = |secs: Option<u32>| {
+        lease_time
+            .and(secs)
+            .map(|secs| Duration::from_secs(secs as _))
+    };
+
+    Some(DhcpLease {
+        ip_info,
+        server: ack.server,
+        lease_time: secs(lease_time),
+        renewal_time: secs(renewal_time),
+        rebinding_time: secs(rebinding_time),
+        obtained: ack.at,
+    })
+}
+
+fn now() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as _)
+}
+
+/// Records the lease times and the server of the acknowledgements received by the DHCP clients
+///
+/// Called by lwIP in its TCP/IP thread for each option of each message received by a client.
+#[cfg(esp_idf_lwip_hook_dhcp_extra_option_custom)]
+#[no_mangle]
+unsafe extern "C" fn lwip_hook_dhcp_extra_option(
+    netif: *mut ffi::c_void,
+    _dhcp: *mut ffi::c_void,
+    _state: u8,
+    msg: *mut ffi::c_void,
+    msg_type: u8,
+    option: u8,
+    option_len: u8,
+    pbuf: *mut ffi::c_void,
+    option_value_offset: u16,
+) {
+    const DHCP_ACK: u8 = 5;
+
+    const OPTION_LEASE_TIME: u8 = 51;
+    const OPTION_SERVER_ID: u8 = 54;
+    const OPTION_T1: u8 = 58;
+    const OPTION_T2: u8 = 59;
+
+    if msg_type != DHCP_ACK
+        || option_len != 4
+        || !matches!(
+            option,
+            OPTION_LEASE_TIME | OPTION_SERVER_ID | OPTION_T1 | OPTION_T2
+        )
+    {
+        return;
+    }
+
+    let mut value = [0_u8; 4];
+
+    if pbuf_copy_partial(pbuf, value.as_mut_ptr() as *mut _, 4, option_value_offset) != 4 {
+        return;
+    }
+
+    let at = now();
+
+    let mut acks = ACKS.lock();
+
+    let index = acks
+        .iter()
+        .position(|ack| matches!(ack, Some(ack) if ack.netif == netif as usize))
+        .or_else(|| acks.iter().position(Option::is_none))
+        .unwrap_or_else(|| {
+            // Evicts the oldest acknowledgement
+            (0..MAX_ACKS)
+                .min_by_key(|index| acks[*index].map(|ack| ack.at))
+                .unwrap_or(0)
+        });
+
+    let slot = &mut acks[index];
+
+    // The options of a message are parsed one after the other, right after it is received
+    let same_msg = matches!(
+        slot,
+        Some(ack) if ack.netif == netif as usize
+            && ack.msg == msg as usize
+            && at.saturating_sub(ack.at) < Duration::from_secs(1)
+    );
+
+    if !same_msg {
+        *slot = Some(Ack {
+            netif: netif as usize,
+            msg: msg as usize,
+            at,
+            server: None,
+            lease_time: None,
+            renewal_time: None,
+            rebinding_time: None,
+        });
+    }
+
+    let ack = match slot {
+        Some(ack) => ack,
+        None => return,
+    };
+
+    match option {
+        OPTION_LEASE_TIME => ack.lease_time = Some(u32::from_be_bytes(value)),
+        OPTION_SERVER_ID => ack.server = Some(value.into()),
+        OPTION_T1 => ack.renewal_time = Some(u32::from_be_bytes(value)),
+        _ => ack.rebinding_time = Some(u32::from_be_bytes(value)),
+    }
+}
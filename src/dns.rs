@@ -0,0 +1,429 @@
+//! DNS cache
+//!
+//! Caches the IPv4 addresses of the resolved host names for as long as the TTLs of their DNS
+//! records allow, so that the repeated requests to the same hosts do not wait for a resolver
+//! round-trip each, which can take seconds on cellular links.
+//!
+//! With `CONFIG_LWIP_HOOK_NETCONN_EXT_RESOLVE_CUSTOM` enabled, all the lwIP name resolutions go
+//! through the cache while it is enabled, including those of the HTTP, MQTT and WebSocket
+//! clients. Without it, only the `resolve` calls use it.
+//!
+//! ```ignore
+//! let cache = EspDnsCache::new(&Configuration::default())?;
+//!
+//! let addr = cache.resolve("api.example.com")?;
+//! ```
+use core::time::Duration;
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::string::String;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::private::common::Newtype;
+use crate::private::mutex::{Mutex, RawMutex};
+
+const DNS_PORT: u16 = 53;
+
+const HEADER_LEN: usize = 12;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NXDOMAIN: u8 = 3;
+
+static CACHE: Mutex<Option<Cache>> = Mutex::wrap(RawMutex::new(), None);
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// Beyond which the entries expiring the soonest are evicted
+    pub max_entries: usize,
+    /// The TTL of the records with a shorter one, so that they get cached at all
+    pub min_ttl: Duration,
+    /// The TTL of the records with a longer one, so that the address changes get noticed
+    pub max_ttl: Duration,
+    /// The time to wait for the answer of the DNS server
+    pub timeout: Duration,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            max_entries: 16,
+            min_ttl: Duration::from_secs(5),
+            max_ttl: Duration::from_secs(3600),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+struct Entry {
+    host: String,
+    addr: Ipv4Addr,
+    /// The time since boot
+    expires: Duration,
+}
+
+struct Cache {
+    conf: Configuration,
+    entries: Vec<Entry>,
+}
+
+/// The DNS cache; there can be only one instance at a time
+pub struct EspDnsCache(());
+
+impl EspDnsCache {
+    /// Fails with `ESP_ERR_INVALID_STATE` if it is already enabled
+    pub fn new(conf: &Configuration) -> Result<Self, EspError> {
+        let mut cache = CACHE.lock();
+
+        if cache.is_some() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        *cache = Some(Cache {
+            conf: conf.clone(),
+            entries: Vec::new(),
+        });
+
+        info!("Enabled with up to {} entries", conf.max_entries);
+
+        Ok(Self(()))
+    }
+
+    /// The cached address of `host`, or the one resolved by the DNS server of the default
+    /// interface otherwise; fails with `ESP_ERR_NOT_FOUND` if the host does not exist, and
+    /// `ESP_ERR_TIMEOUT` if the server does not answer
+    pub fn resolve(&self, host: &str) -> Result<Ipv4Addr, EspError> {
+        resolve(host)
+    }
+
+    /// The cached address of `host` and the time it remains valid for, if any
+    pub fn lookup(&self, host: &str) -> Option<(Ipv4Addr, Duration)> {
+        let now = now();
+
+        CACHE
+            .lock()
+            .as_ref()?
+            .find(host, now)
+            .map(|entry| (entry.addr, entry.expires - now))
+    }
+
+    /// Forgets the address of `host`, e.g. after a connection to it failed
+    pub fn remove(&self, host: &str) {
+        if let Some(cache) = CACHE.lock().as_mut() {
+            cache
+                .entries
+                .retain(|entry| !entry.host.eq_ignore_ascii_case(host));
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Some(cache) = CACHE.lock().as_mut() {
+            cache.entries.clear();
+        }
+    }
+
+    /// The number of the cached entries, including the expired ones not purged yet
+    pub fn len(&self) -> usize {
+        CACHE
+            .lock()
+            .as_ref()
+            .map(|cache| cache.entries.len())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for EspDnsCache {
+    fn drop(&mut self) {
+        *CACHE.lock() = None;
+
+        info!("Dropped");
+    }
+}
+
+impl Cache {
+    fn find(&self, host: &str, now: Duration) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.host.eq_ignore_ascii_case(host) && entry.expires > now)
+    }
+
+    fn insert(&mut self, host: &str, addr: Ipv4Addr, ttl: Duration, now: Duration) {
+        let ttl = ttl.max(self.conf.min_ttl).min(self.conf.max_ttl);
+
+        self.entries
+            .retain(|entry| entry.expires > now && !entry.host.eq_ignore_ascii_case(host));
+
+        if self.conf.max_entries == 0 {
+            return;
+        }
+
+        while self.entries.len() >= self.conf.max_entries {
+            let soonest = self
+                .entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(index, _)| index)
+                .unwrap();
+
+            self.entries.remove(soonest);
+        }
+
+        self.entries.push(Entry {
+            host: host.into(),
+            addr,
+            expires: now + ttl,
+        });
+    }
+}
+
+fn resolve(host: &str) -> Result<Ipv4Addr, EspError> {
+    if let Ok(addr) = host.parse::<Ipv4Addr>() {
+        return Ok(addr);
+    }
+
+    let timeout = {
+        let cache = CACHE.lock();
+        let cache = cache
+            .as_ref()
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_STATE>)?;
+
+        if let Some(entry) = cache.find(host, now()) {
+            return Ok(entry.addr);
+        }
+
+        cache.conf.timeout
+    };
+
+    // Not under the lock, as it can take a while
+    let (addr, ttl) = query(host, timeout)?;
+
+    debug!("Resolved {} to {} (TTL {}s)", host, addr, ttl.as_secs());
+
+    if let Some(cache) = CACHE.lock().as_mut() {
+        cache.insert(host, addr, ttl, now());
+    }
+
+    Ok(addr)
+}
+
+/// Queries the DNS server of the default interface for the A record of `host`, returning its
+/// address and the lowest TTL of the answer, i.e. of the CNAME records leading to it as well
+fn query(host: &str, timeout: Duration) -> Result<(Ipv4Addr, Duration), EspError> {
+    let server = server()?;
+
+    let id = unsafe { esp_random() } as u16;
+
+    let mut request = Vec::with_capacity(HEADER_LEN + host.len() + 6);
+
+    // Recursion desired, one question
+    request.extend_from_slice(&id.to_be_bytes());
+    request.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        request.push(label.len() as u8);
+        request.extend_from_slice(label.as_bytes());
+    }
+
+    request.push(0);
+    request.extend_from_slice(&TYPE_A.to_be_bytes());
+    request.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).map_err(io_error)?;
+    socket.set_read_timeout(Some(timeout)).map_err(io_error)?;
+    socket
+        .send_to(&request, SocketAddrV4::new(server, DNS_PORT))
+        .map_err(io_error)?;
+
+    let mut response = vec![0_u8; 512];
+
+    loop {
+        let (len, from) = socket.recv_from(&mut response).map_err(|e| {
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) {
+                EspError::from_infallible::<ESP_ERR_TIMEOUT>()
+            } else {
+                io_error(e)
+            }
+        })?;
+
+        let response = &response[..len];
+
+        // Ignores the stray datagrams, and the answers to earlier queries
+        if from.ip() != IpAddr::V4(server)
+            || response.len() < HEADER_LEN
+            || response[..2] != id.to_be_bytes()
+            || response[2] & 0x80 == 0
+        {
+            continue;
+        }
+
+        return parse(response).ok_or_else(|| {
+            if response[3] & 0x0f == RCODE_NXDOMAIN {
+                EspError::from_infallible::<ESP_ERR_NOT_FOUND>()
+            } else {
+                EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>()
+            }
+        });
+    }
+}
+
+fn parse(response: &[u8]) -> Option<(Ipv4Addr, Duration)> {
+    let u16_at = |offset: usize| {
+        response
+            .get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+
+    if response[3] & 0x0f != 0 {
+        return None;
+    }
+
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut offset = HEADER_LEN;
+
+    for _ in 0..questions {
+        offset = skip_name(response, offset)? + 4;
+    }
+
+    let mut addr = None;
+    let mut ttl = u32::MAX;
+
+    for _ in 0..answers {
+        offset = skip_name(response, offset)?;
+
+        let record_type = u16_at(offset)?;
+        let class = u16_at(offset + 2)?;
+        let record_ttl = response.get(offset + 4..offset + 8)?;
+        let record_ttl =
+            u32::from_be_bytes([record_ttl[0], record_ttl[1], record_ttl[2], record_ttl[3]]);
+        let len = u16_at(offset + 8)? as usize;
+        let data = response.get(offset + 10..offset + 10 + len)?;
+
+        offset += 10 + len;
+
+        if class != CLASS_IN {
+            continue;
+        }
+
+        ttl = ttl.min(record_ttl);
+
+        if record_type == TYPE_A && len == 4 {
+            addr = Some(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+
+            break;
+        }
+    }
+
+    addr.map(|addr| (addr, Duration::from_secs(ttl as _)))
+}
+
+/// The offset past the (possibly compressed) name at `offset`
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+
+        if len == 0 {
+            return Some(offset + 1);
+        } else if len & 0xc0 == 0xc0 {
+            // A pointer ends the name
+            return Some(offset + 2);
+        } else {
+            offset += 1 + len as usize;
+        }
+    }
+}
+
+/// The main DNS server of the default interface
+fn server() -> Result<Ipv4Addr, EspError> {
+    let netif = unsafe { esp_netif_get_default_netif() };
+    if netif.is_null() {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+    }
+
+    let mut dns_info: esp_netif_dns_info_t = Default::default();
+
+    esp!(unsafe {
+        esp_netif_get_dns_info(
+            netif,
+            esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN,
+            &mut dns_info,
+        )
+    })?;
+
+    let server: Ipv4Addr = Newtype(unsafe { dns_info.ip.u_addr.ip4 }).into();
+
+    if server.is_unspecified() {
+        Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+    } else {
+        Ok(server)
+    }
+}
+
+fn now() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as _)
+}
+
+fn io_error(e: io::Error) -> EspError {
+    warn!("DNS query failed: {}", e);
+
+    EspError::from_infallible::<ESP_FAIL>()
+}
+
+/// Answers the name resolutions of lwIP from the cache (resolving and caching the misses),
+/// while it is enabled
+///
+/// Falls back to the resolver of lwIP otherwise, and for the IPv6 lookups.
+#[cfg(esp_idf_lwip_hook_netconn_ext_resolve_custom)]
+#[no_mangle]
+unsafe extern "C" fn lwip_hook_netconn_external_resolve(
+    name: *const core::ffi::c_char,
+    addr: *mut ip_addr_t,
+    addrtype: u8,
+    err: *mut err_t,
+) -> core::ffi::c_int {
+    // NETCONN_DNS_IPV4 and NETCONN_DNS_IPV4_IPV6
+    if CACHE.lock().is_none() || !(addrtype == 0 || addrtype == 2) {
+        return 0;
+    }
+
+    let host = match core::ffi::CStr::from_ptr(name).to_str() {
+        Ok(host) if host.parse::<Ipv4Addr>().is_err() => host,
+        _ => return 0,
+    };
+
+    match resolve(host) {
+        Ok(resolved) => {
+            (*addr).u_addr.ip4 = Newtype::<ip4_addr_t>::from(resolved).0;
+            (*addr).type_ = lwip_ip_addr_type_IPADDR_TYPE_V4 as _;
+
+            *err = err_enum_t_ERR_OK as _;
+
+            1
+        }
+        Err(e) => {
+            debug!("Resolving {} failed: {}, falling back to lwIP", host, e);
+
+            0
+        }
+    }
+}
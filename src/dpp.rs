@@ -0,0 +1,345 @@
+//! Wi-Fi Easy Connect (DPP) enrollee
+//!
+//! Device Provisioning Protocol allows provisioning the device with the Wi-Fi credentials
+//! of a network by scanning a QR code (the bootstrapping URI generated by the device)
+//! with a DPP-capable configurator, like a phone or an access point.
+//!
+//! The Wi-Fi driver needs to be started in station mode before listening
+//! for a configurator.
+use core::fmt::{self, Display, Formatter};
+use core::future::Future;
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use core::{ffi, fmt::Write as _};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use ::log::*;
+
+use embedded_svc::wifi::ClientConfiguration;
+
+use esp_idf_sys::*;
+
+use crate::private::common::*;
+use crate::private::cstr::*;
+use crate::private::mutex::{Mutex, RawMutex};
+use crate::private::waitable::*;
+use crate::wifi::WifiDriver;
+
+static STATE: Mutex<Option<Arc<Waitable<State>>>> = Mutex::wrap(RawMutex::new(), None);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DppError {
+    /// The DPP exchange with the configurator failed
+    Failure,
+    /// A DPP frame could not be transmitted
+    TxFailure,
+    /// The configurator sent a frame with an invalid attribute
+    InvalidAttribute,
+    /// No credentials were received in time
+    Timeout,
+    /// Any other failure reported by the supplicant
+    Other(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] EspError),
+}
+
+impl DppError {
+    fn from_reason(reason: esp_err_t) -> Self {
+        match reason as u32 {
+            ESP_ERR_DPP_FAILURE => Self::Failure,
+            ESP_ERR_DPP_TX_FAILURE => Self::TxFailure,
+            ESP_ERR_DPP_INVALID_ATTR => Self::InvalidAttribute,
+            _ => EspError::from(reason)
+                .map(Self::Other)
+                .unwrap_or(Self::Failure),
+        }
+    }
+}
+
+impl Display for DppError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Failure => write!(f, "DPP failure"),
+            Self::TxFailure => write!(f, "DPP frame transmission failure"),
+            Self::InvalidAttribute => write!(f, "DPP invalid attribute"),
+            Self::Timeout => write!(f, "DPP timeout"),
+            Self::Other(e) => write!(f, "DPP error: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DppError {}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration<'a> {
+    /// The channels to listen on for the configurator; these are also advertised in the QR code
+    pub channels: &'a [u8],
+    /// The private key of the bootstrapping key pair, as a hex-encoded DER;
+    /// if not set, a new key pair is generated
+    pub key: Option<&'a str>,
+    /// Additional information which is put in the QR code
+    pub info: Option<&'a str>,
+}
+
+impl<'a> Default for Configuration<'a> {
+    fn default() -> Self {
+        Self {
+            channels: &[6],
+            key: None,
+            info: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    uri: Option<String>,
+    result: Option<Result<ClientConfiguration, DppError>>,
+    waker: Option<Waker>,
+}
+
+impl State {
+    fn notify(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+pub struct EspDpp<'w> {
+    state: Arc<Waitable<State>>,
+    listening: bool,
+    _wifi: PhantomData<&'w mut ()>,
+}
+
+impl<'w> EspDpp<'w> {
+    /// Initializes DPP and generates the bootstrapping URI of the device
+    pub fn new<'d>(_wifi: &'w mut WifiDriver<'d>, conf: &Configuration) -> Result<Self, EspError> {
+        let state = Arc::new(Waitable::new(State::default()));
+
+        {
+            let mut global = STATE.lock();
+
+            if global.is_some() {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+
+            *global = Some(state.clone());
+        }
+
+        let dpp = Self {
+            state,
+            listening: false,
+            _wifi: PhantomData,
+        };
+
+        esp!(unsafe { esp_supp_dpp_init(Some(Self::handle)) }).map_err(|e| {
+            *STATE.lock() = None;
+            e
+        })?;
+
+        let mut channels = String::new();
+
+        for (index, channel) in conf.channels.iter().enumerate() {
+            if index > 0 {
+                channels.push(',');
+            }
+
+            write!(&mut channels, "{}", channel).unwrap();
+        }
+
+        let channels = CString::new(channels).unwrap();
+        let key = conf.key.map(|key| CString::new(key).unwrap());
+        let info = conf.info.map(|info| CString::new(info).unwrap());
+
+        esp!(unsafe {
+            esp_supp_dpp_bootstrap_gen(
+                channels.as_ptr(),
+                esp_supp_dpp_bootstrap_t_DPP_BOOTSTRAP_QR_CODE,
+                key.as_ref()
+                    .map(|key| key.as_ptr())
+                    .unwrap_or(core::ptr::null()),
+                info.as_ref()
+                    .map(|info| info.as_ptr())
+                    .unwrap_or(core::ptr::null()),
+            )
+        })?;
+
+        info!("DPP initialized");
+
+        Ok(dpp)
+    }
+
+    /// Returns the bootstrapping URI, which is to be rendered as a QR code
+    /// and scanned by the configurator
+    ///
+    /// The URI is generated in the background, so this waits until it is available.
+    pub fn uri(&self) -> Result<String, DppError> {
+        let (timeout, uri) = self.state.wait_timeout_while_and_get(
+            Duration::from_secs(10),
+            |state| state.uri.is_none() && state.result.is_none(),
+            |state| {
+                state.uri.clone().ok_or_else(|| match &state.result {
+                    Some(Err(e)) => *e,
+                    _ => DppError::Failure,
+                })
+            },
+        );
+
+        if timeout {
+            Err(DppError::Timeout)
+        } else {
+            uri
+        }
+    }
+
+    /// Starts listening for the configurator on the configured channels
+    ///
+    /// After a failure, listening can be started again.
+    pub fn start_listen(&mut self) -> Result<(), EspError> {
+        self.state.get_mut(|state| state.result = None);
+
+        esp!(unsafe { esp_supp_dpp_start_listen() })?;
+
+        self.listening = true;
+
+        Ok(())
+    }
+
+    pub fn stop_listen(&mut self) -> Result<(), EspError> {
+        if self.listening {
+            unsafe {
+                esp_supp_dpp_stop_listen();
+            }
+
+            self.listening = false;
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the configurator has sent the credentials of the network,
+    /// or until the exchange fails
+    pub fn wait_for_credentials(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<ClientConfiguration, DppError> {
+        let condition = |state: &State| state.result.is_none();
+        let getter = |state: &State| state.result.clone();
+
+        let result = if let Some(timeout) = timeout {
+            match self
+                .state
+                .wait_timeout_while_and_get(timeout, condition, getter)
+            {
+                (false, Some(result)) => result,
+                _ => Err(DppError::Timeout),
+            }
+        } else {
+            self.state.wait_while_and_get(condition, getter).unwrap()
+        };
+
+        self.listening = false;
+
+        result
+    }
+
+    /// Returns a future which resolves once the configurator has sent the credentials of the
+    /// network, or once the exchange fails
+    pub fn credentials(&mut self) -> DppCredentials<'_, 'w> {
+        DppCredentials(self)
+    }
+
+    unsafe extern "C" fn handle(event: esp_supp_dpp_event_t, data: *mut ffi::c_void) {
+        let state = STATE.lock().clone();
+
+        let state = if let Some(state) = state {
+            state
+        } else {
+            return;
+        };
+
+        #[allow(non_upper_case_globals)]
+        match event {
+            esp_supp_dpp_event_t_ESP_SUPP_DPP_URI_READY => {
+                let uri = CStr::from_ptr(data as *const c_char).to_string_lossy();
+
+                info!("Got DPP URI: {}", uri);
+
+                state.get_mut(|state| {
+                    state.uri = Some(uri.into_owned());
+                    state.notify();
+                });
+            }
+            esp_supp_dpp_event_t_ESP_SUPP_DPP_CFG_RECVD => {
+                let conf = &*(data as *const wifi_config_t);
+                let conf: ClientConfiguration = Newtype(conf.sta).into();
+
+                info!("Got DPP credentials for SSID {}", conf.ssid);
+
+                state.get_mut(|state| {
+                    state.result = Some(Ok(conf));
+                    state.notify();
+                });
+            }
+            esp_supp_dpp_event_t_ESP_SUPP_DPP_FAIL => {
+                let error = DppError::from_reason(data as isize as esp_err_t);
+
+                warn!("DPP failed: {}", error);
+
+                state.get_mut(|state| {
+                    state.result = Some(Err(error));
+                    state.notify();
+                });
+            }
+            _ => (),
+        }
+
+        state.cvar.notify_all();
+    }
+}
+
+impl<'w> Drop for EspDpp<'w> {
+    fn drop(&mut self) {
+        self.stop_listen().unwrap();
+
+        unsafe {
+            esp_supp_dpp_deinit();
+        }
+
+        *STATE.lock() = None;
+
+        info!("Dropped");
+    }
+}
+
+pub struct DppCredentials<'a, 'w>(&'a mut EspDpp<'w>);
+
+impl<'a, 'w> Future for DppCredentials<'a, 'w> {
+    type Output = Result<ClientConfiguration, DppError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = self.0.state.get_mut(|state| {
+            if let Some(result) = state.result.clone() {
+                Some(result)
+            } else {
+                state.waker = Some(cx.waker().clone());
+                None
+            }
+        });
+
+        if let Some(result) = result {
+            self.get_mut().0.listening = false;
+
+            Poll::Ready(result)
+        } else {
+            Poll::Pending
+        }
+    }
+}
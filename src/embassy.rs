@@ -0,0 +1,143 @@
+//! embassy-executor integration
+//!
+//! `EspExecutor` runs an `embassy-executor` executor on a FreeRTOS task. The task sleeps on
+//! its notification while no future is ready, and is notified by the executor pender, which
+//! may be called from any task or ISR. The wakers of the async services of the crate (e.g.
+//! `AsyncWifi`, the async HTTP, MQTT and WebSocket clients, and the timers) are invoked from
+//! the event loop and `esp_timer` tasks, or from ISRs, hence they can all be awaited from
+//! embassy tasks.
+//!
+//! The `embassy` feature also enables the `embassy-time` driver and timer queue of
+//! `timer::embassy_time`, backed by `esp_timer`, so that `embassy_time::Timer` works as well:
+//!
+//! ```ignore
+//! #[embassy_executor::task]
+//! async fn blink(mut led: PinDriver<'static, Gpio2, Output>) {
+//!     loop {
+//!         led.toggle().unwrap();
+//!         Timer::after(Duration::from_millis(500)).await;
+//!     }
+//! }
+//!
+//! fn main() {
+//!     esp_idf_sys::link_patches();
+//!     esp_idf_svc::embassy::link();
+//!
+//!     embassy::spawn(&Default::default(), move |spawner| {
+//!         spawner.spawn(blink(led)).unwrap();
+//!     })
+//!     .unwrap();
+//! }
+//! ```
+//!
+//! Note: This module requires the `embassy` cargo feature to be enabled.
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+use esp_idf_hal::task;
+
+use esp_idf_sys::*;
+
+use embassy_executor::raw;
+
+pub use embassy_executor::{SendSpawner, Spawner};
+
+/// Keeps the `embassy-time` driver and queue implementations from being removed by the linker
+pub fn link() {
+    let _ = crate::timer::embassy_time::queue::link();
+}
+
+/// An executor polling its tasks from the FreeRTOS task which calls `run`
+pub struct EspExecutor {
+    inner: raw::Executor,
+    /// The task to notify when a future is ready, or null while it is not running yet
+    task: &'static AtomicPtr<tskTaskControlBlock>,
+    not_send: PhantomData<*mut ()>,
+}
+
+impl EspExecutor {
+    pub fn new() -> Self {
+        let task = Box::leak(Box::new(AtomicPtr::new(ptr::null_mut())));
+
+        Self {
+            inner: raw::Executor::new(Self::pend, task as *mut AtomicPtr<_> as *mut ()),
+            task,
+            not_send: PhantomData,
+        }
+    }
+
+    /// Runs the executor on the calling task, after calling `init` to spawn the initial tasks
+    pub fn run(&'static mut self, init: impl FnOnce(Spawner)) -> ! {
+        self.task.store(
+            task::current().expect("Cannot run an executor from an ISR"),
+            Ordering::SeqCst,
+        );
+
+        init(self.inner.spawner());
+
+        loop {
+            unsafe { self.inner.poll() };
+
+            task::wait_any_notification();
+        }
+    }
+
+    /// The pender of the executor, i.e. the signal function of the raw executor
+    fn pend(context: *mut ()) {
+        let task = unsafe { &*(context as *const AtomicPtr<tskTaskControlBlock>) };
+
+        let task = task.load(Ordering::SeqCst);
+
+        // Not running yet, the tasks are polled once it starts anyway
+        if !task.is_null() {
+            unsafe { task::notify(task, 1) };
+        }
+    }
+}
+
+impl Default for EspExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub name: &'static str,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            name: "embassy",
+            stack_size: 8192,
+        }
+    }
+}
+
+/// Runs an executor on a new thread, after calling `init` on it to spawn the initial tasks
+///
+/// The thread runs forever.
+#[cfg(feature = "std")]
+pub fn spawn<F>(conf: &Configuration, init: F) -> Result<(), EspError>
+where
+    F: FnOnce(Spawner) + Send + 'static,
+{
+    std::thread::Builder::new()
+        .name(conf.name.into())
+        .stack_size(conf.stack_size)
+        .spawn(move || {
+            let executor: &'static mut EspExecutor = Box::leak(Box::new(EspExecutor::new()));
+
+            executor.run(init)
+        })
+        .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+    Ok(())
+}
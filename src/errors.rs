@@ -2,9 +2,11 @@
 
 use core::fmt::{self, Display, Formatter};
 
+use ::log::*;
+
 use embedded_svc::io::{Error, ErrorKind};
 
-use esp_idf_sys::EspError;
+use esp_idf_sys::{esp_err_t, EspError};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct EspIOError(pub EspError);
@@ -29,3 +31,124 @@ impl Display for EspIOError {
 
 #[cfg(feature = "std")]
 impl std::error::Error for EspIOError {}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for EspIOError {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "EspIOError({=i32})", self.0.code())
+    }
+}
+
+/// An `EspError` tagged with the subsystem (e.g. `"wifi"`) and the ESP-IDF call
+/// (e.g. `"esp_wifi_connect"`) which produced it
+///
+/// The public APIs of the services keep returning a plain `EspError`; the services only use
+/// this internally, to log which call failed at the debug level before dropping the tag.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SvcError {
+    error: EspError,
+    subsystem: &'static str,
+    operation: &'static str,
+}
+
+impl SvcError {
+    pub const fn new(error: EspError, subsystem: &'static str, operation: &'static str) -> Self {
+        Self {
+            error,
+            subsystem,
+            operation,
+        }
+    }
+
+    pub fn error(&self) -> EspError {
+        self.error
+    }
+
+    /// The raw `esp_err_t` code
+    pub fn code(&self) -> esp_err_t {
+        self.error.code()
+    }
+
+    pub fn subsystem(&self) -> &'static str {
+        self.subsystem
+    }
+
+    pub fn operation(&self) -> &'static str {
+        self.operation
+    }
+
+    /// Logs the subsystem and the operation, for the APIs which return a plain `EspError`
+    ///
+    /// Only at the debug level, as the caller may well handle the error as a normal outcome.
+    pub(crate) fn into_logged(self) -> EspError {
+        debug!("{}", self);
+
+        self.error
+    }
+}
+
+impl Display for SvcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} failed: {}",
+            self.subsystem, self.operation, self.error
+        )
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for SvcError {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "{=str}: {=str} failed: {=i32}",
+            self.subsystem,
+            self.operation,
+            self.error.code()
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SvcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<SvcError> for EspError {
+    fn from(e: SvcError) -> Self {
+        e.error
+    }
+}
+
+impl From<SvcError> for EspIOError {
+    fn from(e: SvcError) -> Self {
+        EspIOError(e.error)
+    }
+}
+
+/// Attaches the subsystem and operation to the error of a failed ESP-IDF call
+pub trait Context<T> {
+    fn context(self, subsystem: &'static str, operation: &'static str) -> Result<T, SvcError>;
+}
+
+impl<T> Context<T> for Result<T, EspError> {
+    fn context(self, subsystem: &'static str, operation: &'static str) -> Result<T, SvcError> {
+        self.map_err(|error| SvcError::new(error, subsystem, operation))
+    }
+}
+
+/// Same as `esp!`, but tags the error with the subsystem and the name of the called function
+macro_rules! esp_svc {
+    ($subsystem:literal, $function:ident($($arg:expr),* $(,)?)) => {
+        $crate::errors::Context::context(
+            ::esp_idf_sys::esp!(unsafe { $function($($arg),*) }),
+            $subsystem,
+            stringify!($function),
+        )
+    };
+}
+
+pub(crate) use esp_svc;
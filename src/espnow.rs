@@ -28,6 +28,7 @@ static SEND_CALLBACK: Singleton<dyn FnMut(&[u8], SendStatus) + Send> =
 static TAKEN: Mutex<bool> = Mutex::wrap(RawMutex::new(), false);
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SendStatus {
     SUCCESS = 0,
     FAIL,
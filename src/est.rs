@@ -0,0 +1,470 @@
+//! EST enrollment client (RFC 7030)
+//!
+//! `EspEst` enrolls the device with an EST server for a TLS client certificate, which the
+//! MQTT and HTTP clients then use for mutual TLS. The key pair is generated on the device,
+//! and only its CSR leaves it:
+//!
+//! ```ignore
+//! let mut est = EspEst::new(
+//!     &Configuration {
+//!         url: "https://est.example.com".into(),
+//!         subject: "CN=sensor-1,O=Example".into(),
+//!         credentials: Some(("sensor-1".into(), "one-time-secret".into())),
+//!         ..Default::default()
+//!     },
+//!     nvs_partition.clone(),
+//! )?;
+//!
+//! // Enrolls on the first boot, re-enrolls when the certificate is about to expire
+//! let enrollment = est.ensure()?;
+//!
+//! let mqtt_conf = MqttClientConfiguration {
+//!     client_credentials: Some(Arc::new(enrollment.mqtt_credentials())),
+//!     ..Default::default()
+//! };
+//! ```
+//!
+//! The initial enrollment is authenticated with HTTP Basic credentials, or with a bootstrap
+//! certificate provisioned in the factory; the re-enrollments with the current certificate.
+//! The server is authenticated with the certificate bundle or the global CA store.
+//!
+//! The issued certificate and its key are stored in NVS, as the `CERTIFICATE` and
+//! `PRIVATE_KEY` blobs of the `NAMESPACE` namespace, so that they can also be loaded with
+//! `MqttClientCredentials::from_nvs`. The key is generated with mbedTLS, and is an EC P-256 one
+//! by default; NVS encryption should be enabled.
+//!
+//! Note: This module requires the `experimental` cargo feature to be enabled.
+use core::fmt::{self, Debug, Formatter};
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::string::{String, ToString};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+
+use esp_idf_sys::*;
+
+use crate::http::client::{self, EspHttpConnection};
+#[cfg(all(esp_idf_comp_mqtt_enabled, esp_idf_comp_esp_event_enabled))]
+use crate::mqtt::client::MqttClientCredentials;
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+use crate::private::base64;
+use crate::private::x509;
+use crate::tls::keys::{CsrConfiguration, Curve, KeyPair, KeyType};
+use crate::tls::X509;
+
+pub const NAMESPACE: &str = "est";
+/// The NVS blob of the issued certificate, PEM encoded
+pub const CERTIFICATE: &str = "certificate";
+/// The NVS blob of the private key of the certificate, PEM encoded
+pub const PRIVATE_KEY: &str = "private_key";
+
+/// Certificates are a few KB, anything larger is an error at best
+const MAX_RESPONSE_LEN: usize = 16384;
+
+#[derive(Clone, Debug)]
+pub struct Configuration {
+    /// The URL of the server, without the `/.well-known/est` path
+    pub url: String,
+    /// The optional CA label of the server, for servers with several CAs
+    pub label: Option<String>,
+    /// The subject of the certificate, e.g. `CN=sensor-1,O=Example`
+    pub subject: String,
+    /// The DNS names of the certificate, if any
+    pub dns_names: Vec<String>,
+    /// The type of the key pairs, generated on every enrollment
+    pub key_type: KeyType,
+    /// The HTTP Basic credentials of the initial enrollment
+    pub credentials: Option<(String, String)>,
+    /// The certificate of the initial enrollment, e.g. provisioned in the factory
+    pub bootstrap_certificate: Option<X509<'static>>,
+    pub bootstrap_private_key: Option<X509<'static>>,
+    pub use_global_ca_store: bool,
+    /// How long before its expiry the certificate is renewed by `EspEst::ensure`
+    pub renew_before: Duration,
+    /// How many times an enrollment pending manual approval (`202 Accepted`) is retried
+    pub pending_retries: u32,
+    pub timeout: Duration,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            label: None,
+            subject: String::new(),
+            dns_names: Vec::new(),
+            key_type: KeyType::Ec(Curve::P256),
+            credentials: None,
+            bootstrap_certificate: None,
+            bootstrap_private_key: None,
+            use_global_ca_store: false,
+            renew_before: Duration::from_secs(30 * 24 * 3600),
+            pending_retries: 3,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Enrollment {
+    /// The issued certificate, PEM encoded
+    pub certificate: String,
+    /// Its private key, PEM encoded
+    pub private_key: String,
+    /// The expiry of the certificate, since the UNIX epoch
+    pub not_after: Duration,
+}
+
+impl Enrollment {
+    /// The credentials of the MQTT client, owning the certificate and the key
+    #[cfg(all(esp_idf_comp_mqtt_enabled, esp_idf_comp_esp_event_enabled))]
+    pub fn mqtt_credentials(&self) -> MqttClientCredentials {
+        MqttClientCredentials::new(
+            self.certificate.as_bytes().to_vec(),
+            self.private_key.as_bytes().to_vec(),
+        )
+    }
+
+    /// The certificate and the key, for the `client_certificate` and `private_key` of the
+    /// HTTP client configuration
+    ///
+    /// The configuration borrows them for `'static`, hence they are leaked, i.e. a few KB on
+    /// every call.
+    pub fn x509(&self) -> (X509<'static>, X509<'static>) {
+        (
+            X509::pem_until_nul(leak(&self.certificate)),
+            X509::pem_until_nul(leak(&self.private_key)),
+        )
+    }
+
+    fn is_due(&self, renew_before: Duration) -> bool {
+        // Without the system time, the certificate is assumed to be valid
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .filter(|now| now.as_secs() > 1_577_836_800)
+            .map(|now| now + renew_before >= self.not_after)
+            .unwrap_or(false)
+    }
+}
+
+impl Debug for Enrollment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Enrollment")
+            .field("certificate", &self.certificate)
+            .field("private_key", &"<redacted>")
+            .field("not_after", &self.not_after)
+            .finish()
+    }
+}
+
+struct Response {
+    status: u16,
+    retry_after: Option<Duration>,
+    body: String,
+}
+
+pub struct EspEst<T: NvsPartitionId> {
+    conf: Configuration,
+    nvs: EspNvs<T>,
+}
+
+impl<T: NvsPartitionId> EspEst<T> {
+    pub fn new(conf: &Configuration, partition: EspNvsPartition<T>) -> Result<Self, EspError> {
+        if conf.url.is_empty() || conf.subject.is_empty() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        Ok(Self {
+            conf: conf.clone(),
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    /// The stored enrollment, if any
+    pub fn enrollment(&self) -> Option<Enrollment> {
+        let certificate = self.load(CERTIFICATE)?;
+        let private_key = self.load(PRIVATE_KEY)?;
+
+        let not_after = x509::not_after(&certificate)?;
+
+        Some(Enrollment {
+            certificate,
+            private_key,
+            not_after,
+        })
+    }
+
+    /// Returns the stored enrollment, enrolling or re-enrolling first if there is none or if
+    /// it is about to expire
+    pub fn ensure(&mut self) -> Result<Enrollment, EspError> {
+        match self.enrollment() {
+            Some(enrollment) if !enrollment.is_due(self.conf.renew_before) => Ok(enrollment),
+            Some(_) => self.reenroll().or_else(|e| {
+                warn!("Failed to re-enroll, enrolling again: {}", e);
+
+                self.enroll()
+            }),
+            None => self.enroll(),
+        }
+    }
+
+    /// The PEM encoded certificates of the CA, e.g. to check the servers of the fleet
+    pub fn ca_certificates(&self) -> Result<String, EspError> {
+        let response = self.request(Method::Get, "cacerts", None, None)?;
+
+        if response.status != 200 {
+            warn!("EST cacerts failed with status {}", response.status);
+
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+
+        let ders = Self::certificates(&response.body)?;
+
+        Ok(ders
+            .iter()
+            .map(|der| x509::to_pem(der, "CERTIFICATE"))
+            .collect())
+    }
+
+    /// Enrolls with the credentials or the bootstrap certificate of the configuration, with a
+    /// new key pair
+    pub fn enroll(&mut self) -> Result<Enrollment, EspError> {
+        let client_auth = self
+            .conf
+            .bootstrap_certificate
+            .zip(self.conf.bootstrap_private_key);
+
+        self.enroll_with("simpleenroll", client_auth)
+    }
+
+    /// Re-enrolls with the current certificate, with a new key pair; fails with
+    /// `ESP_ERR_INVALID_STATE` if the device is not enrolled
+    pub fn reenroll(&mut self) -> Result<Enrollment, EspError> {
+        let current = self
+            .enrollment()
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_STATE>)?;
+
+        let certificate = format!("{}\0", current.certificate);
+        let private_key = format!("{}\0", current.private_key);
+
+        self.enroll_with(
+            "simplereenroll",
+            Some((
+                X509::pem_until_nul(certificate.as_bytes()),
+                X509::pem_until_nul(private_key.as_bytes()),
+            )),
+        )
+    }
+
+    fn enroll_with(
+        &mut self,
+        operation: &str,
+        client_auth: Option<(X509<'_>, X509<'_>)>,
+    ) -> Result<Enrollment, EspError> {
+        let mut key = KeyPair::generate(self.conf.key_type)?;
+
+        let csr = key.csr_der(&CsrConfiguration {
+            subject: self.conf.subject.clone(),
+            dns_names: self.conf.dns_names.clone(),
+            ..Default::default()
+        })?;
+        let csr = base64::encode(&csr);
+
+        let mut attempt = 0;
+
+        let response = loop {
+            let response = self.request(Method::Post, operation, Some(&csr), client_auth)?;
+
+            match response.status {
+                200 => break response,
+                202 if attempt < self.conf.pending_retries => {
+                    let retry_after = response.retry_after.unwrap_or(Duration::from_secs(60));
+
+                    info!(
+                        "EST {} pending approval, retrying in {}s",
+                        operation,
+                        retry_after.as_secs()
+                    );
+
+                    attempt += 1;
+
+                    thread::sleep(retry_after);
+                }
+                202 => return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>()),
+                status => {
+                    warn!(
+                        "EST {} failed with status {}: {}",
+                        operation, status, response.body
+                    );
+
+                    return Err(EspError::from_infallible::<ESP_FAIL>());
+                }
+            }
+        };
+
+        let ders = Self::certificates(&response.body)?;
+
+        let certificate = x509::to_pem(
+            ders.first()
+                .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?,
+            "CERTIFICATE",
+        );
+
+        let not_after = x509::not_after(&certificate)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?;
+
+        let enrollment = Enrollment {
+            certificate,
+            private_key: key.private_key_pem()?,
+            not_after,
+        };
+
+        self.nvs
+            .set_raw(CERTIFICATE, enrollment.certificate.as_bytes())?;
+        self.nvs
+            .set_raw(PRIVATE_KEY, enrollment.private_key.as_bytes())?;
+
+        info!(
+            "Enrolled {}, valid until {}s",
+            self.conf.subject,
+            enrollment.not_after.as_secs()
+        );
+
+        Ok(enrollment)
+    }
+
+    /// The DER certificates of a base64 encoded, certs-only PKCS #7 response
+    fn certificates(body: &str) -> Result<Vec<Vec<u8>>, EspError> {
+        let der = base64::decode(body)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?;
+
+        let ders = x509::pkcs7_certificates(&der)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?;
+
+        Ok(ders.into_iter().map(<[u8]>::to_vec).collect())
+    }
+
+    fn request(
+        &self,
+        method: Method,
+        operation: &str,
+        csr: Option<&str>,
+        client_auth: Option<(X509<'_>, X509<'_>)>,
+    ) -> Result<Response, EspError> {
+        // The connection does not outlive the certificate and the key, and only reads them
+        // while connecting
+        let client_auth = client_auth.map(|(certificate, private_key)| unsafe {
+            (
+                core::mem::transmute::<X509<'_>, X509<'static>>(certificate),
+                core::mem::transmute::<X509<'_>, X509<'static>>(private_key),
+            )
+        });
+
+        let mut connection = EspHttpConnection::new(&client::Configuration {
+            timeout: Some(self.conf.timeout),
+            client_certificate: client_auth.map(|(certificate, _)| certificate),
+            private_key: client_auth.map(|(_, private_key)| private_key),
+            use_global_ca_store: self.conf.use_global_ca_store,
+            #[cfg(all(not(esp_idf_version = "4.3"), esp_idf_mbedtls_certificate_bundle))]
+            crt_bundle_attach: Some(esp_crt_bundle_attach),
+            ..Default::default()
+        })?;
+
+        let url = match &self.conf.label {
+            Some(label) => format!(
+                "{}/.well-known/est/{}/{}",
+                self.conf.url.trim_end_matches('/'),
+                label,
+                operation
+            ),
+            None => format!(
+                "{}/.well-known/est/{}",
+                self.conf.url.trim_end_matches('/'),
+                operation
+            ),
+        };
+
+        let body = csr.unwrap_or("");
+        let len = body.len().to_string();
+
+        let authorization = self.conf.credentials.as_ref().map(|(username, password)| {
+            format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password).as_bytes())
+            )
+        });
+
+        let mut headers = Vec::new();
+
+        if csr.is_some() {
+            headers.push(("Content-Type", "application/pkcs10"));
+            headers.push(("Content-Transfer-Encoding", "base64"));
+            headers.push(("Content-Length", len.as_str()));
+        }
+
+        if let Some(authorization) = authorization.as_deref() {
+            headers.push(("Authorization", authorization));
+        }
+
+        connection.initiate_request(method, &url, &headers)?;
+
+        let mut offset = 0;
+        while offset < body.len() {
+            offset += connection.write(&body.as_bytes()[offset..])?;
+        }
+
+        connection.initiate_response()?;
+
+        let status = connection.status();
+        let retry_after = connection
+            .header("Retry-After")
+            .and_then(|retry_after| retry_after.trim().parse().ok())
+            .map(Duration::from_secs);
+
+        let mut response = Vec::new();
+        let mut buf = [0; 512];
+
+        loop {
+            let len = connection.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+
+            if response.len() + len > MAX_RESPONSE_LEN {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+            }
+
+            response.extend_from_slice(&buf[..len]);
+        }
+
+        let body = String::from_utf8(response)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>())?;
+
+        Ok(Response {
+            status,
+            retry_after,
+            body,
+        })
+    }
+
+    fn load(&self, name: &str) -> Option<String> {
+        let len = self.nvs.blob_len(name).ok().flatten()?;
+
+        let mut buf = vec![0; len];
+
+        let value = self.nvs.get_raw(name, &mut buf).ok().flatten()?;
+
+        core::str::from_utf8(value).ok().map(String::from)
+    }
+}
+
+fn leak(pem: &str) -> &'static [u8] {
+    Box::leak(format!("{}\0", pem).into_bytes().into_boxed_slice())
+}
@@ -43,6 +43,7 @@ use crate::private::*;
 
 #[cfg(all(esp32, esp_idf_eth_use_esp32_emac))]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum RmiiEthChipset {
     IP101,
     RTL8201,
@@ -110,6 +111,7 @@ where
     esp_idf_eth_spi_ethernet_ksz8851snl
 ))]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SpiEthChipset {
     #[cfg(esp_idf_eth_spi_ethernet_dm9051)]
     DM9051,
@@ -923,6 +925,7 @@ impl<'d> Eth for EspEth<'d> {
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum EthEvent {
     Started(esp_eth_handle_t),
     Stopped(esp_eth_handle_t),
@@ -0,0 +1,273 @@
+//! Factory reset
+//!
+//! `EspFactoryReset` wipes exactly what its `FactoryResetPolicy` lists, in stages: the NVS
+//! namespaces first, then the data partitions, then the switch to the factory app, and
+//! finally the restart. Before the first stage, a marker is written to NVS, so that a reset
+//! interrupted by a power loss is completed by `EspFactoryReset::resume` on the next boot:
+//!
+//! ```ignore
+//! let reset = EspFactoryReset::new(
+//!     nvs_partition,
+//!     FactoryResetPolicy {
+//!         nvs_namespaces: vec!["wifi".into(), "config".into()],
+//!         data_partitions: vec!["storage".into()],
+//!         ..Default::default()
+//!     },
+//! );
+//!
+//! // On boot, before anything reads the configuration
+//! reset.resume()?;
+//!
+//! // Reset when the boot button is held for five seconds
+//! let mut button = PinDriver::input(peripherals.pins.gpio0)?;
+//! button.set_pull(Pull::Up)?;
+//!
+//! let _button = EspFactoryResetButton::new(reset, button, Level::Low, Duration::from_secs(5))?;
+//! ```
+//!
+//! Data partitions are erased raw, so they must not be mounted (or the file system
+//! should be unmounted) when the reset is triggered.
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+use crate::ota::EspOta;
+use crate::private::cstr::*;
+
+const NAMESPACE: &str = "factory_reset";
+const PENDING_KEY: &str = "pending";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FactoryResetPolicy {
+    /// The NVS namespaces erased, in the partition the reset is created with
+    pub nvs_namespaces: Vec<String>,
+    /// The labels of the data partitions erased entirely, e.g. SPIFFS or FAT partitions
+    pub data_partitions: Vec<String>,
+    /// Switch the boot partition back to the factory app, if there is one
+    pub boot_factory: bool,
+    pub restart: bool,
+}
+
+impl Default for FactoryResetPolicy {
+    fn default() -> Self {
+        Self {
+            nvs_namespaces: Vec::new(),
+            data_partitions: Vec::new(),
+            boot_factory: true,
+            restart: true,
+        }
+    }
+}
+
+pub struct EspFactoryReset<T: NvsPartitionId> {
+    partition: EspNvsPartition<T>,
+    policy: FactoryResetPolicy,
+}
+
+impl<T: NvsPartitionId> EspFactoryReset<T> {
+    pub fn new(partition: EspNvsPartition<T>, policy: FactoryResetPolicy) -> Self {
+        Self { partition, policy }
+    }
+
+    pub fn policy(&self) -> &FactoryResetPolicy {
+        &self.policy
+    }
+
+    /// Completes a reset interrupted before the restart, returning whether there was one
+    ///
+    /// To be called on boot.
+    pub fn resume(&self) -> Result<bool, EspError> {
+        let nvs = EspNvs::new(self.partition.clone(), NAMESPACE, true)?;
+
+        if nvs.get_u8(PENDING_KEY)?.is_none() {
+            return Ok(false);
+        }
+
+        drop(nvs);
+
+        warn!("Resuming an interrupted factory reset");
+
+        self.reset()?;
+
+        Ok(true)
+    }
+
+    /// Wipes everything the policy lists, and then restarts if the policy says so
+    pub fn reset(&self) -> Result<(), EspError> {
+        warn!("Factory reset: {:?}", self.policy);
+
+        let mut nvs = EspNvs::new(self.partition.clone(), NAMESPACE, true)?;
+
+        nvs.set_u8(PENDING_KEY, 1)?;
+
+        for namespace in &self.policy.nvs_namespaces {
+            info!("Erasing NVS namespace {}", namespace);
+
+            EspNvs::new(self.partition.clone(), namespace, true)?.remove_all()?;
+        }
+
+        for label in &self.policy.data_partitions {
+            info!("Erasing partition {}", label);
+
+            Self::erase_partition(label)?;
+        }
+
+        if self.policy.boot_factory {
+            let mut ota = EspOta::new()?;
+
+            if ota.is_factory_reset_supported()? {
+                info!("Switching to the factory app");
+
+                ota.factory_reset()?;
+            } else {
+                warn!("No factory app to switch to, keeping the boot partition");
+            }
+        }
+
+        // Only once all stages are done
+        nvs.remove(PENDING_KEY)?;
+
+        info!("Factory reset complete");
+
+        if self.policy.restart {
+            unsafe { esp_restart() };
+        }
+
+        Ok(())
+    }
+
+    fn erase_partition(label: &str) -> Result<(), EspError> {
+        let c_label = CString::new(label).unwrap();
+
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                c_label.as_ptr(),
+            )
+            .as_ref()
+        }
+        .ok_or_else(|| {
+            warn!("Partition {} not found", label);
+
+            EspError::from_infallible::<ESP_ERR_NOT_FOUND>()
+        })?;
+
+        esp!(unsafe { esp_partition_erase_range(partition, 0, partition.size as _) })
+    }
+}
+
+#[cfg(feature = "std")]
+pub use button::*;
+
+#[cfg(feature = "std")]
+mod button {
+    use core::time::Duration;
+
+    use std::sync::mpsc;
+    use std::thread;
+
+    use ::log::*;
+
+    use esp_idf_hal::gpio::{Input, InputPin, Level, PinDriver};
+
+    use esp_idf_sys::*;
+
+    use super::EspFactoryReset;
+    use crate::nvs::{EspNvsPartition, NvsPartitionId};
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Triggers a factory reset when a button is held for a given duration
+    pub struct EspFactoryResetButton {
+        stop: mpsc::Sender<()>,
+        join_handle: Option<thread::JoinHandle<()>>,
+    }
+
+    impl EspFactoryResetButton {
+        /// `pressed` is the level of the pin while the button is pressed, i.e.
+        /// `Level::Low` for a button wired to the ground with a pull-up
+        pub fn new<T, P>(
+            reset: EspFactoryReset<T>,
+            pin: PinDriver<'static, P, Input>,
+            pressed: Level,
+            hold: Duration,
+        ) -> Result<Self, EspError>
+        where
+            T: NvsPartitionId + Send + Sync + 'static,
+            EspNvsPartition<T>: Send,
+            P: InputPin,
+        {
+            let (stop, stopped) = mpsc::channel();
+
+            let join_handle = thread::Builder::new()
+                .name("factory-reset".into())
+                .stack_size(4096)
+                .spawn(move || Self::run(reset, pin, pressed, hold, stopped))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+            Ok(Self {
+                stop,
+                join_handle: Some(join_handle),
+            })
+        }
+
+        fn run<T: NvsPartitionId, P: InputPin>(
+            reset: EspFactoryReset<T>,
+            pin: PinDriver<'static, P, Input>,
+            pressed: Level,
+            hold: Duration,
+            stopped: mpsc::Receiver<()>,
+        ) {
+            let mut held = Duration::ZERO;
+
+            loop {
+                if pin.get_level() == pressed {
+                    held += POLL_INTERVAL;
+
+                    if held >= hold {
+                        if let Err(e) = reset.reset() {
+                            error!("Factory reset failed: {}", e);
+                        }
+
+                        // Wait for the release, so as not to reset in a loop
+                        held = Duration::ZERO;
+
+                        while pin.get_level() == pressed {
+                            if stopped.recv_timeout(POLL_INTERVAL)
+                                != Err(mpsc::RecvTimeoutError::Timeout)
+                            {
+                                return;
+                            }
+                        }
+                    }
+                } else {
+                    held = Duration::ZERO;
+                }
+
+                match stopped.recv_timeout(POLL_INTERVAL) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => (),
+                    _ => break,
+                }
+            }
+        }
+    }
+
+    impl Drop for EspFactoryResetButton {
+        fn drop(&mut self) {
+            let _ = self.stop.send(());
+
+            if let Some(join_handle) = self.join_handle.take() {
+                let _ = join_handle.join();
+            }
+
+            info!("Dropped");
+        }
+    }
+}
@@ -0,0 +1,529 @@
+//! NMEA GPS receiver
+//!
+//! `EspGps` parses the NMEA sentences received from a GPS (or any GNSS) receiver over an
+//! `EspUart` and posts a `Fix` on the system event loop for every position update. The `GGA`
+//! and `RMC` sentences of the same update - from any constellation - are merged into one fix:
+//!
+//! ```ignore
+//! let uart = EspUart::new(
+//!     &uart::Configuration {
+//!         framing: Framing::Line { delimiter: b'\n', max_len: 96 },
+//!         ..Default::default()
+//!     },
+//!     driver,
+//! )?;
+//!
+//! let gps = EspGps::new(
+//!     &Configuration {
+//!         set_system_time: true,
+//!         ..Default::default()
+//!     },
+//!     uart,
+//!     sysloop.clone(),
+//! )?;
+//!
+//! let _subscription = sysloop.subscribe(|fix: &Fix| {
+//!     if fix.quality.is_valid() {
+//!         info!("At {}, {}", fix.latitude, fix.longitude);
+//!     }
+//! })?;
+//! ```
+//!
+//! The UART must be configured with line framing.
+//!
+//! With `Configuration::set_system_time`, the system time is set from the valid fixes when it
+//! is off by more than `Configuration::time_threshold`, so that devices without network time
+//! get a correct clock.
+use core::ffi;
+use core::time::Duration;
+
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSystemEventLoop, EspTypedEventDeserializer,
+    EspTypedEventSerializer, EspTypedEventSource,
+};
+use crate::private::civil::*;
+use crate::private::mutex::Mutex;
+use crate::uart::EspUart;
+
+/// How often the reader thread checks whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// Set the system time from the fixes
+    pub set_system_time: bool,
+    /// How far the system time must be off to be set
+    pub time_threshold: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            set_system_time: false,
+            time_threshold: Duration::from_secs(1),
+            stack_size: 4096,
+        }
+    }
+}
+
+/// The fix quality, as reported by `GGA` sentences
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum FixQuality {
+    Invalid,
+    Gps,
+    Dgps,
+    Pps,
+    Rtk,
+    FloatRtk,
+    /// Dead reckoning
+    Estimated,
+    Manual,
+    Simulation,
+}
+
+impl FixQuality {
+    pub fn is_valid(&self) -> bool {
+        !matches!(self, Self::Invalid | Self::Manual | Self::Simulation)
+    }
+
+    fn from_nmea(quality: &str) -> Self {
+        match quality {
+            "1" => Self::Gps,
+            "2" => Self::Dgps,
+            "3" => Self::Pps,
+            "4" => Self::Rtk,
+            "5" => Self::FloatRtk,
+            "6" => Self::Estimated,
+            "7" => Self::Manual,
+            "8" => Self::Simulation,
+            _ => Self::Invalid,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Fix {
+    pub quality: FixQuality,
+    /// In degrees, positive north
+    pub latitude: f64,
+    /// In degrees, positive east
+    pub longitude: f64,
+    /// In meters above the mean sea level
+    pub altitude: Option<f32>,
+    /// In meters per second
+    pub speed: Option<f32>,
+    /// In degrees from the true north
+    pub course: Option<f32>,
+    pub satellites: Option<u8>,
+    pub hdop: Option<f32>,
+    /// The UTC time of the fix, since the UNIX epoch; only set when the receiver knows the date
+    pub time: Option<Duration>,
+}
+
+impl Default for Fix {
+    fn default() -> Self {
+        Self {
+            quality: FixQuality::Invalid,
+            latitude: 0.0,
+            longitude: 0.0,
+            altitude: None,
+            speed: None,
+            course: None,
+            satellites: None,
+            hdop: None,
+            time: None,
+        }
+    }
+}
+
+impl EspTypedEventSource for Fix {
+    fn source() -> *const ffi::c_char {
+        b"ESP-GPS\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<Fix> for Fix {
+    fn serialize<R>(event: &Fix, f: impl for<'a> FnOnce(&'a EspEventPostData) -> R) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<Fix> for Fix {
+    fn deserialize<R>(data: &EspEventFetchData, f: &mut impl for<'a> FnMut(&'a Fix) -> R) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+pub struct EspGps {
+    last_fix: Arc<Mutex<Option<Fix>>>,
+    stop: mpsc::Sender<()>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspGps {
+    pub fn new(
+        conf: &Configuration,
+        uart: EspUart,
+        sysloop: EspSystemEventLoop,
+    ) -> Result<Self, EspError> {
+        let last_fix = Arc::new(Mutex::new(None));
+
+        let (stop, stopped) = mpsc::channel();
+
+        let join_handle = {
+            let conf = conf.clone();
+            let last_fix = last_fix.clone();
+
+            thread::Builder::new()
+                .name("gps".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, uart, sysloop, last_fix, stopped))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        Ok(Self {
+            last_fix,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    pub fn last_fix(&self) -> Option<Fix> {
+        *self.last_fix.lock()
+    }
+
+    fn run(
+        conf: Configuration,
+        uart: EspUart,
+        sysloop: EspSystemEventLoop,
+        last_fix: Arc<Mutex<Option<Fix>>>,
+        stopped: mpsc::Receiver<()>,
+    ) {
+        let mut assembler = Assembler::new();
+
+        loop {
+            match stopped.try_recv() {
+                Err(mpsc::TryRecvError::Empty) => (),
+                _ => break,
+            }
+
+            let line = if let Some(line) = uart.recv_timeout(Some(POLL_INTERVAL)) {
+                line
+            } else {
+                continue;
+            };
+
+            let sentence = core::str::from_utf8(&line)
+                .ok()
+                .and_then(|line| Sentence::parse(line.trim()));
+
+            let fix = if let Some(sentence) = sentence {
+                assembler.push(sentence)
+            } else {
+                continue;
+            };
+
+            if let Some(fix) = fix {
+                debug!("Fix: {:?}", fix);
+
+                *last_fix.lock() = Some(fix);
+
+                if conf.set_system_time && fix.quality.is_valid() {
+                    if let Some(time) = fix.time {
+                        Self::set_system_time(time, conf.time_threshold);
+                    }
+                }
+
+                if let Err(e) = sysloop.post(&fix, None) {
+                    warn!("Failed to post GPS fix: {}", e);
+                }
+            }
+        }
+    }
+
+    fn set_system_time(time: Duration, threshold: Duration) {
+        let mut now: timeval = Default::default();
+
+        unsafe { gettimeofday(&mut now, core::ptr::null_mut()) };
+
+        let now = Duration::from_secs(now.tv_sec.max(0) as u64)
+            + Duration::from_micros(now.tv_usec.max(0) as u64);
+
+        let drift = if now > time { now - time } else { time - now };
+
+        if drift > threshold {
+            info!("Setting the system time from GPS, drift was {:?}", drift);
+
+            let tv = timeval {
+                tv_sec: time.as_secs() as _,
+                tv_usec: time.subsec_micros() as _,
+            };
+
+            unsafe { settimeofday(&tv, core::ptr::null()) };
+        }
+    }
+}
+
+impl Drop for EspGps {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+/// The fields of the supported NMEA sentences
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Sentence {
+    Gga {
+        /// Milliseconds since midnight
+        time: u32,
+        position: Option<(f64, f64)>,
+        quality: FixQuality,
+        satellites: Option<u8>,
+        hdop: Option<f32>,
+        altitude: Option<f32>,
+    },
+    Rmc {
+        time: u32,
+        valid: bool,
+        position: Option<(f64, f64)>,
+        speed: Option<f32>,
+        course: Option<f32>,
+        /// Days since the UNIX epoch
+        date: Option<i64>,
+    },
+}
+
+impl Sentence {
+    /// Parses a sentence like `$GPGGA,...*47`, verifying its checksum
+    fn parse(line: &str) -> Option<Self> {
+        let (body, checksum) = line.strip_prefix('$')?.split_once('*')?;
+
+        let checksum = u8::from_str_radix(checksum.get(..2)?, 16).ok()?;
+
+        if body.bytes().fold(0, |sum, byte| sum ^ byte) != checksum {
+            debug!("Invalid NMEA checksum: {}", line);
+            return None;
+        }
+
+        let mut fields = body.split(',');
+
+        let kind = fields.next()?;
+        let fields = fields.collect::<std::vec::Vec<_>>();
+
+        let field = |index: usize| fields.get(index).copied().unwrap_or("");
+
+        // Any talker, e.g. GP, GN, GL
+        match kind.get(2..)? {
+            "GGA" => Some(Self::Gga {
+                time: Self::time(field(0))?,
+                position: Self::position(field(1), field(2), field(3), field(4)),
+                quality: FixQuality::from_nmea(field(5)),
+                satellites: field(6).parse().ok(),
+                hdop: field(7).parse().ok(),
+                altitude: field(8).parse().ok(),
+            }),
+            "RMC" => Some(Self::Rmc {
+                time: Self::time(field(0))?,
+                valid: field(1) == "A",
+                position: Self::position(field(2), field(3), field(4), field(5)),
+                speed: field(6)
+                    .parse::<f32>()
+                    .ok()
+                    .map(|knots| knots * 1852.0 / 3600.0),
+                course: field(7).parse().ok(),
+                date: Self::date(field(8)),
+            }),
+            _ => None,
+        }
+    }
+
+    /// `hhmmss.sss`
+    fn time(time: &str) -> Option<u32> {
+        let hours: u32 = time.get(0..2)?.parse().ok()?;
+        let minutes: u32 = time.get(2..4)?.parse().ok()?;
+        let seconds: f32 = time.get(4..)?.parse().ok()?;
+
+        if hours > 23 || minutes > 59 || !(0.0..61.0).contains(&seconds) {
+            return None;
+        }
+
+        Some((hours * 3600 + minutes * 60) * 1000 + (seconds * 1000.0) as u32)
+    }
+
+    /// `ddmmyy`
+    fn date(date: &str) -> Option<i64> {
+        let day: u32 = date.get(0..2)?.parse().ok()?;
+        let month: u32 = date.get(2..4)?.parse().ok()?;
+        let year: i64 = date.get(4..6)?.parse().ok()?;
+
+        if !(1..=31).contains(&day) || !(1..=12).contains(&month) {
+            return None;
+        }
+
+        let year = if year < 80 { 2000 + year } else { 1900 + year };
+
+        Some(days_from_civil(year, month, day))
+    }
+
+    /// `ddmm.mmmm,N,dddmm.mmmm,E`
+    fn position(lat: &str, north: &str, lon: &str, east: &str) -> Option<(f64, f64)> {
+        let degrees = |value: &str, width: usize| -> Option<f64> {
+            let degrees: f64 = value.get(..width)?.parse().ok()?;
+            let minutes: f64 = value.get(width..)?.parse().ok()?;
+
+            Some(degrees + minutes / 60.0)
+        };
+
+        let lat = degrees(lat, 2)?;
+        let lon = degrees(lon, 3)?;
+
+        let lat = match north {
+            "N" => lat,
+            "S" => -lat,
+            _ => return None,
+        };
+
+        let lon = match east {
+            "E" => lon,
+            "W" => -lon,
+            _ => return None,
+        };
+
+        Some((lat, lon))
+    }
+}
+
+/// Merges the sentences of the same update into fixes
+struct Assembler {
+    fix: Fix,
+    time: Option<u32>,
+    gga: bool,
+    rmc: bool,
+    /// The time of the last complete update, whose further sentences are ignored
+    completed: Option<u32>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Self {
+            fix: Default::default(),
+            time: None,
+            gga: false,
+            rmc: false,
+            completed: None,
+        }
+    }
+
+    /// Returns the fix of the previous update when a sentence of a new update starts, or the
+    /// fix of the current update once both its `GGA` and `RMC` sentences are received
+    fn push(&mut self, sentence: Sentence) -> Option<Fix> {
+        let time = match sentence {
+            Sentence::Gga { time, .. } | Sentence::Rmc { time, .. } => time,
+        };
+
+        if self.completed == Some(time) {
+            return None;
+        }
+
+        let previous = if self.time.is_some() && self.time != Some(time) {
+            self.take()
+        } else {
+            None
+        };
+
+        self.time = Some(time);
+
+        match sentence {
+            Sentence::Gga {
+                position,
+                quality,
+                satellites,
+                hdop,
+                altitude,
+                ..
+            } => {
+                self.gga = true;
+
+                self.fix.quality = quality;
+                self.fix.satellites = satellites;
+                self.fix.hdop = hdop;
+                self.fix.altitude = altitude;
+
+                if let Some((latitude, longitude)) = position {
+                    self.fix.latitude = latitude;
+                    self.fix.longitude = longitude;
+                }
+            }
+            Sentence::Rmc {
+                valid,
+                position,
+                speed,
+                course,
+                date,
+                ..
+            } => {
+                self.rmc = true;
+
+                self.fix.speed = speed;
+                self.fix.course = course;
+                self.fix.time = date.map(|date| {
+                    Duration::from_secs(date.max(0) as u64 * 86400)
+                        + Duration::from_millis(time as u64)
+                });
+
+                if !self.gga {
+                    self.fix.quality = if valid {
+                        FixQuality::Gps
+                    } else {
+                        FixQuality::Invalid
+                    };
+                }
+
+                if let Some((latitude, longitude)) = position {
+                    self.fix.latitude = latitude;
+                    self.fix.longitude = longitude;
+                }
+            }
+        }
+
+        if previous.is_some() {
+            previous
+        } else if self.gga && self.rmc {
+            self.completed = Some(time);
+
+            self.take()
+        } else {
+            None
+        }
+    }
+
+    fn take(&mut self) -> Option<Fix> {
+        let fix = core::mem::take(&mut self.fix);
+
+        let complete = self.gga || self.rmc;
+
+        self.time = None;
+        self.gga = false;
+        self.rmc = false;
+
+        complete.then(|| fix)
+    }
+}
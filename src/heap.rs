@@ -0,0 +1,110 @@
+//! Placement of the large buffers of the services
+//!
+//! The HTTP client and server, the MQTT and WebSocket clients and the raw TLS client
+//! allocate their RX/TX and TLS record buffers with `malloc` when they are created
+//! (respectively, when they connect). With PSRAM available and `CONFIG_SPIRAM_USE_MALLOC`
+//! enabled, these buffers can be moved to SPI RAM, keeping the scarce internal RAM
+//! for WiFi and lwIP which cannot use PSRAM.
+//!
+//! Note that only the allocations done while the service is created (or connects) are affected;
+//! buffers allocated later on, e.g. per HTTP server session, follow the `malloc` policy
+//! of the sdkconfig. As the `malloc` threshold of ESP-IDF is global, the allocations of the
+//! other tasks meanwhile are placed the same way; for the same reason, the threshold should
+//! not be changed with `heap_caps_malloc_extmem_enable` elsewhere, as it is set back to
+//! `CONFIG_SPIRAM_MALLOC_ALWAYSINTERNAL` once no service is being created.
+#[allow(unused_imports)]
+use esp_idf_sys::*;
+
+#[allow(unused_imports)]
+use crate::private::mutex::{Mutex, RawMutex};
+
+/// The numbers of `Internal` and `Spiram` scopes in progress, as the `malloc` threshold is global
+#[cfg(esp_idf_spiram_use_malloc)]
+static SCOPES: Mutex<(usize, usize)> = Mutex::wrap(RawMutex::new(), (0, 0));
+
+/// Where a service allocates its large buffers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum BufferMemory {
+    /// Follow the `malloc` policy of the sdkconfig, i.e. allocations up to
+    /// `CONFIG_SPIRAM_MALLOC_ALWAYSINTERNAL` bytes go to internal RAM
+    Default,
+    /// Prefer internal RAM, regardless of the size of the buffers
+    Internal,
+    /// Prefer SPI RAM, regardless of the size of the buffers; same as `Default`
+    /// if `CONFIG_SPIRAM_USE_MALLOC` is not enabled
+    Spiram,
+}
+
+impl Default for BufferMemory {
+    fn default() -> Self {
+        Self::Default
+    }
+}
+
+impl BufferMemory {
+    /// Runs `f` with the allocations done by `malloc` placed according to `self`
+    ///
+    /// The scopes may be nested, or run concurrently from several tasks; while an `Internal`
+    /// one is in progress, the `Spiram` ones also get internal RAM, which suits any buffer.
+    #[allow(dead_code)]
+    pub(crate) fn scope<R>(self, f: impl FnOnce() -> R) -> R {
+        #[cfg(esp_idf_spiram_use_malloc)]
+        let _guard = if self != Self::Default {
+            Some(ScopeGuard::new(self))
+        } else {
+            None
+        };
+
+        f()
+    }
+}
+
+/// Restores the `malloc` threshold at the end of a scope, even if it panics
+#[cfg(esp_idf_spiram_use_malloc)]
+struct ScopeGuard(BufferMemory);
+
+#[cfg(esp_idf_spiram_use_malloc)]
+impl ScopeGuard {
+    fn new(memory: BufferMemory) -> Self {
+        Self::update(memory, true);
+
+        Self(memory)
+    }
+
+    fn update(memory: BufferMemory, enter: bool) {
+        let mut scopes = SCOPES.lock();
+
+        let count = if memory == BufferMemory::Internal {
+            &mut scopes.0
+        } else {
+            &mut scopes.1
+        };
+
+        if enter {
+            *count += 1;
+        } else {
+            *count -= 1;
+        }
+
+        let limit = if scopes.0 > 0 {
+            usize::MAX
+        } else if scopes.1 > 0 {
+            0
+        } else {
+            CONFIG_SPIRAM_MALLOC_ALWAYSINTERNAL as usize
+        };
+
+        unsafe {
+            heap_caps_malloc_extmem_enable(limit as _);
+        }
+    }
+}
+
+#[cfg(esp_idf_spiram_use_malloc)]
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        Self::update(self.0, false);
+    }
+}
@@ -1,7 +1,56 @@
 //! Experimental HTTP server and client
 //!
 //! Note: This module requires the `experimental` cargo feature to be enabled.
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_esp_http_server_enabled,
+    esp_idf_comp_app_update_enabled,
+    esp_idf_comp_spi_flash_enabled,
+    esp_idf_comp_esp_netif_enabled,
+    esp_idf_comp_esp_event_enabled
+))]
+pub mod api;
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_http_server_enabled))]
+pub mod cache;
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_http_client_enabled))]
 pub mod client;
+#[cfg(feature = "alloc")]
+pub mod cookies;
+#[cfg(all(feature = "std", esp_idf_comp_esp_http_client_enabled))]
+pub mod download;
+#[cfg(all(feature = "std", esp_idf_comp_esp_http_server_enabled))]
+pub mod files;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_espressif__sh2lib_enabled,
+    esp_idf_comp_esp_timer_enabled
+))]
+pub mod grpc;
+#[cfg(all(feature = "alloc", esp_idf_comp_espressif__sh2lib_enabled))]
+pub mod http2;
+#[cfg(all(feature = "std", esp_idf_comp_esp_http_server_enabled))]
+pub mod mjpeg;
+#[cfg(all(feature = "mock", feature = "alloc"))]
+pub mod mock;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_esp_http_server_enabled,
+    esp_idf_comp_app_update_enabled,
+    esp_idf_comp_spi_flash_enabled,
+    esp_idf_comp_esp_event_enabled
+))]
+pub mod ota_upload;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_esp_http_client_enabled,
+    esp_idf_comp_lwip_enabled
+))]
+pub mod probe;
+#[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+pub mod proxy;
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_http_server_enabled))]
 pub mod server;
+#[cfg(feature = "templates")]
+pub mod template;
+#[cfg(feature = "alloc")]
+pub mod uri;
@@ -0,0 +1,290 @@
+//! Management API endpoints
+//!
+//! Ready-made `EspHttpServer` handlers exposing the firmware and network state of the device as
+//! JSON, and accepting firmware uploads, so that a minimal management surface can be mounted
+//! with a single call:
+//!
+//! - `GET /api/ota/status`: the running, boot and next update slots, and the progress of the
+//!   current upload
+//! - `POST /api/ota/upload`: installs the firmware image of the request body, with the handler
+//!   of `ota_upload`
+//! - `GET /api/net/status`: the state and addresses of the network interfaces, and the access
+//!   point the WiFi station is connected to
+//!
+//! ```ignore
+//! api::register(&mut server, &Default::default(), Some(sysloop.clone()))?;
+//! ```
+//!
+//! The handlers do not authenticate the requests; the server should only be reachable from
+//! trusted networks, or the handlers be wrapped in an authenticating handler.
+use core::ffi;
+use core::ptr;
+
+use std::string::String;
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::http::server::{HandlerResult, Request};
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+use embedded_svc::ipv4;
+
+use esp_idf_sys::*;
+
+use crate::eventloop::EspSystemEventLoop;
+use crate::netif::Interface;
+use crate::private::common::*;
+use crate::private::cstr::from_cstr_ptr;
+use crate::private::json::{self, Json};
+
+use super::ota_upload;
+use super::server::{EspHttpConnection, EspHttpServer};
+
+pub use super::ota_upload::{upload_progress, UploadProgress, UploadState};
+
+#[derive(Clone, Debug)]
+pub struct Configuration {
+    /// The prefix of the URIs of the endpoints
+    pub prefix: &'static str,
+    /// The interfaces reported by `/net/status`
+    pub interfaces: &'static [Interface],
+    /// The configuration of `/ota/upload`, or `None` not to register it
+    pub upload: Option<ota_upload::Configuration>,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            prefix: "/api",
+            interfaces: &[Interface::Sta, Interface::Ap, Interface::Eth],
+            upload: Some(Default::default()),
+        }
+    }
+}
+
+/// Registers the endpoints below `Configuration::prefix`
+///
+/// The upload progress is published on `sysloop`, when given (see `ota_upload`).
+pub fn register(
+    server: &mut EspHttpServer,
+    conf: &Configuration,
+    sysloop: Option<EspSystemEventLoop>,
+) -> Result<(), EspError> {
+    let prefix = conf.prefix.trim_end_matches('/');
+
+    server.fn_handler(
+        &format!("{}/ota/status", prefix),
+        Method::Get,
+        ota_status_handler,
+    )?;
+
+    if let Some(upload) = &conf.upload {
+        server.fn_handler(
+            &format!("{}/ota/upload", prefix),
+            Method::Post,
+            ota_upload::handler(upload.clone(), sysloop),
+        )?;
+    }
+
+    let interfaces = conf.interfaces;
+
+    server.fn_handler(
+        &format!("{}/net/status", prefix),
+        Method::Get,
+        move |request| net_status_handler(request, interfaces),
+    )?;
+
+    info!("Registered the management API on {}", prefix);
+
+    Ok(())
+}
+
+/// Responds with the state of the OTA slots, and the progress of the upload
+pub fn ota_status_handler(request: Request<&mut EspHttpConnection>) -> HandlerResult {
+    let running = unsafe { esp_ota_get_running_partition() };
+    let boot = unsafe { esp_ota_get_boot_partition() };
+    let update = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+    let invalid = unsafe { esp_ota_get_last_invalid_partition() };
+
+    let progress = upload_progress();
+
+    let status = json::object([
+        ("running", partition_json(running)),
+        ("boot", partition_json(boot)),
+        ("update", partition_json(update)),
+        ("last_invalid", partition_json(invalid)),
+        (
+            "upload",
+            json::object([
+                ("state", progress.state.as_str().into()),
+                ("received", Json::from(progress.received)),
+                (
+                    "total",
+                    progress.total.map(Json::from).unwrap_or(Json::Null),
+                ),
+            ]),
+        ),
+    ]);
+
+    respond_json(request, &status)
+}
+
+/// Responds with the state of `interfaces`, and of the WiFi station connection
+pub fn net_status_handler(
+    request: Request<&mut EspHttpConnection>,
+    interfaces: &[Interface],
+) -> HandlerResult {
+    let interfaces = interfaces
+        .iter()
+        .filter_map(|interface| {
+            interface
+                .handle()
+                .ok()
+                .map(|handle| netif_json(interface, handle))
+        })
+        .collect();
+
+    let status = json::object([
+        ("interfaces", Json::Array(interfaces)),
+        ("wifi", wifi_json()),
+    ]);
+
+    respond_json(request, &status)
+}
+
+fn partition_json(partition: *const esp_partition_t) -> Json {
+    let partition = match unsafe { partition.as_ref() } {
+        Some(partition) => partition,
+        None => return Json::Null,
+    };
+
+    let mut app_desc: esp_app_desc_t = Default::default();
+
+    let version =
+        if unsafe { esp_ota_get_partition_description(partition, &mut app_desc) } == ESP_OK {
+            Json::from(unsafe { from_cstr_ptr(&app_desc.version as *const _) })
+        } else {
+            Json::Null
+        };
+
+    let mut state: esp_ota_img_states_t = Default::default();
+
+    #[allow(non_upper_case_globals)]
+    let state = if unsafe { esp_ota_get_state_partition(partition, &mut state) } == ESP_OK {
+        match state {
+            esp_ota_img_states_t_ESP_OTA_IMG_NEW => "new",
+            esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY => "pending_verify",
+            esp_ota_img_states_t_ESP_OTA_IMG_VALID => "valid",
+            esp_ota_img_states_t_ESP_OTA_IMG_INVALID => "invalid",
+            esp_ota_img_states_t_ESP_OTA_IMG_ABORTED => "aborted",
+            _ => "undefined",
+        }
+    } else {
+        // The factory partition, or a slot never written to
+        "undefined"
+    };
+
+    json::object([
+        (
+            "label",
+            Json::from(unsafe { from_cstr_ptr(&partition.label as *const _ as *const _) }),
+        ),
+        ("version", version),
+        ("state", state.into()),
+    ])
+}
+
+fn netif_json(interface: &Interface, handle: *mut esp_netif_t) -> Json {
+    let up = unsafe { esp_netif_is_netif_up(handle) };
+
+    let mut ip_info: esp_netif_ip_info_t = Default::default();
+    unsafe { esp_netif_get_ip_info(handle, &mut ip_info) };
+
+    let ip = |ip: esp_ip4_addr_t| {
+        if ip.addr == 0 {
+            Json::Null
+        } else {
+            let ip: ipv4::Ipv4Addr = Newtype(ip).into();
+
+            Json::from(ip.to_string())
+        }
+    };
+
+    let mut mac = [0_u8; 6];
+    let mac = if unsafe { esp_netif_get_mac(handle, mac.as_mut_ptr()) } == ESP_OK {
+        Json::from(
+            mac.iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(":"),
+        )
+    } else {
+        Json::Null
+    };
+
+    let mut hostname: *const ffi::c_char = ptr::null();
+    let hostname = if unsafe { esp_netif_get_hostname(handle, &mut hostname) } == ESP_OK
+        && !hostname.is_null()
+    {
+        Json::from(unsafe { from_cstr_ptr(hostname) })
+    } else {
+        Json::Null
+    };
+
+    json::object([
+        ("key", interface.key().into()),
+        ("up", Json::Bool(up)),
+        ("ip", ip(ip_info.ip)),
+        ("netmask", ip(ip_info.netmask)),
+        ("gateway", ip(ip_info.gw)),
+        ("mac", mac),
+        ("hostname", hostname),
+    ])
+}
+
+#[cfg(esp_idf_comp_esp_wifi_enabled)]
+fn wifi_json() -> Json {
+    let mut ap_info: wifi_ap_record_t = Default::default();
+
+    // Fails when the station is not connected, or WiFi is not started
+    if unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) } != ESP_OK {
+        return json::object([("connected", Json::Bool(false))]);
+    }
+
+    let ssid_len = ap_info
+        .ssid
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(ap_info.ssid.len());
+
+    json::object([
+        ("connected", Json::Bool(true)),
+        (
+            "ssid",
+            Json::from(String::from_utf8_lossy(&ap_info.ssid[..ssid_len]).into_owned()),
+        ),
+        ("rssi", Json::from(ap_info.rssi as i64)),
+        ("channel", Json::from(ap_info.primary as u64)),
+    ])
+}
+
+#[cfg(not(esp_idf_comp_esp_wifi_enabled))]
+fn wifi_json() -> Json {
+    Json::Null
+}
+
+fn respond_json(request: Request<&mut EspHttpConnection>, json: &Json) -> HandlerResult {
+    request
+        .into_response(
+            200,
+            None,
+            &[
+                ("Content-Type", "application/json"),
+                ("Cache-Control", "no-store"),
+            ],
+        )?
+        .write_all(json.to_string().as_bytes())?;
+
+    Ok(())
+}
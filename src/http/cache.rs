@@ -0,0 +1,218 @@
+//! HTTP caching helpers
+//!
+//! `ETag`s and `Cache-Control` policies for the responses of `EspHttpServer` handlers, so that
+//! browsers and API clients revalidate the configuration blobs and assets they already have
+//! with `If-None-Match`, and get an empty `304 Not Modified` when these did not change:
+//!
+//! ```ignore
+//! server.fn_handler("/api/config", Method::Get, |request| {
+//!     let config = load_config_json();
+//!
+//!     cache::respond(
+//!         request,
+//!         "application/json",
+//!         &ETag::from_content(config.as_bytes()),
+//!         CachePolicy::NoCache,
+//!         config.as_bytes(),
+//!     )
+//! })?;
+//!
+//! // Assets embedded in the firmware only change with it
+//! server.fn_handler("/app.js", Method::Get, |request| {
+//!     cache::respond(
+//!         request,
+//!         "application/javascript",
+//!         &ETag::from_build(),
+//!         CachePolicy::Public(Duration::from_secs(3600)),
+//!         APP_JS,
+//!     )
+//! })?;
+//! ```
+use core::fmt::{self, Display};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::{String, ToString};
+
+use embedded_svc::http::server::{HandlerResult, Request};
+use embedded_svc::http::Headers;
+use embedded_svc::io::Write;
+
+use esp_idf_sys::*;
+
+use crate::private::hex;
+
+use super::server::EspHttpConnection;
+
+/// The `Cache-Control` policy of a response
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum CachePolicy {
+    /// Never cached, e.g. live status
+    NoStore,
+    /// Cached, but revalidated with the server on each use
+    NoCache,
+    /// Cached by the browser only, for the given duration
+    Private(Duration),
+    /// Cached by the browser and the proxies, for the given duration
+    Public(Duration),
+    /// Never revalidated, e.g. assets with a version in their URI
+    Immutable,
+}
+
+impl Display for CachePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoStore => write!(f, "no-store"),
+            Self::NoCache => write!(f, "no-cache"),
+            Self::Private(max_age) => write!(f, "private, max-age={}", max_age.as_secs()),
+            Self::Public(max_age) => write!(f, "public, max-age={}", max_age.as_secs()),
+            Self::Immutable => write!(f, "public, max-age=31536000, immutable"),
+        }
+    }
+}
+
+/// An entity tag, as sent in the `ETag` header: quoted, and prefixed with `W/` if weak
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ETag(String);
+
+impl ETag {
+    /// A strong tag, derived from a hash of the content
+    pub fn from_content(content: &[u8]) -> Self {
+        Self::new(&Self::hash(content), false)
+    }
+
+    /// A strong tag identifying the running firmware, for the content embedded in it
+    pub fn from_build() -> Self {
+        #[cfg(esp_idf_version_major = "4")]
+        let app_desc = unsafe { esp_ota_get_app_description().as_ref() };
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let app_desc = unsafe { esp_app_get_description().as_ref() };
+
+        let tag = app_desc
+            .map(|app_desc| hex::encode(&app_desc.app_elf_sha256[..8]))
+            .unwrap_or_default();
+
+        Self::new(&tag, false)
+    }
+
+    /// A tag from a version of the content maintained by the application,
+    /// e.g. a configuration revision
+    pub fn from_version(version: u64) -> Self {
+        Self::new(&format!("{:x}", version), false)
+    }
+
+    /// A weak tag: the content is equivalent, but not necessarily byte for byte identical,
+    /// e.g. when it is regenerated on each request
+    pub fn weak(tag: &str) -> Self {
+        Self::new(tag, true)
+    }
+
+    /// The value of the `ETag` header
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether an `If-None-Match` header matches the tag, i.e. the client has the content
+    ///
+    /// Uses the weak comparison of RFC 9110, as required for `If-None-Match`.
+    pub fn matches(&self, if_none_match: &str) -> bool {
+        let own = Self::opaque(&self.0);
+
+        if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || Self::opaque(tag) == own)
+    }
+
+    /// Whether the request has an `If-None-Match` header matching the tag
+    pub fn is_fresh<C: Headers>(&self, request: &Request<C>) -> bool {
+        request
+            .header("If-None-Match")
+            .map(|if_none_match| self.matches(if_none_match))
+            .unwrap_or(false)
+    }
+
+    fn new(tag: &str, weak: bool) -> Self {
+        let tag = tag.replace('"', "");
+
+        if weak {
+            Self(format!("W/\"{}\"", tag))
+        } else {
+            Self(format!("\"{}\"", tag))
+        }
+    }
+
+    fn opaque(tag: &str) -> &str {
+        tag.strip_prefix("W/").unwrap_or(tag)
+    }
+
+    /// FNV-1a, which is enough to tell versions of the same resource apart
+    fn hash(content: &[u8]) -> String {
+        let hash = content
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+            });
+
+        format!("{:016x}", hash)
+    }
+}
+
+impl Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Responds with `304 Not Modified` if the client has the content,
+/// or with `200 OK` and the content otherwise
+pub fn respond(
+    request: Request<&mut EspHttpConnection>,
+    content_type: &str,
+    etag: &ETag,
+    policy: CachePolicy,
+    content: &[u8],
+) -> HandlerResult {
+    if etag.is_fresh(&request) {
+        return not_modified(request, etag, policy);
+    }
+
+    let policy = policy.to_string();
+    let len = content.len().to_string();
+
+    request
+        .into_response(
+            200,
+            None,
+            &[
+                ("Content-Type", content_type),
+                ("Content-Length", len.as_str()),
+                ("ETag", etag.as_str()),
+                ("Cache-Control", policy.as_str()),
+            ],
+        )?
+        .write_all(content)?;
+
+    Ok(())
+}
+
+/// Responds with `304 Not Modified`, for handlers streaming their content,
+/// which check `ETag::is_fresh` themselves
+pub fn not_modified(
+    request: Request<&mut EspHttpConnection>,
+    etag: &ETag,
+    policy: CachePolicy,
+) -> HandlerResult {
+    let policy = policy.to_string();
+
+    request.into_response(
+        304,
+        None,
+        &[("ETag", etag.as_str()), ("Cache-Control", policy.as_str())],
+    )?;
+
+    Ok(())
+}
@@ -6,12 +6,17 @@
 //! [`examples/http_request.rs`](https://github.com/esp-rs/esp-idf-svc/blob/master/examples/http_request.rs).
 
 use core::cell::UnsafeCell;
+use core::mem;
+use core::time::Duration;
 
 extern crate alloc;
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::format;
 use alloc::string::String;
 use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use ::log::*;
 
@@ -23,11 +28,49 @@ use esp_idf_sys::*;
 
 use uncased::{Uncased, UncasedStr};
 
-use crate::errors::EspIOError;
+use super::cookies::CookieJar;
+#[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+use super::proxy::{self, EspHttpProxyForwarder};
+use super::uri;
+
+use crate::errors::{esp_svc, Context, EspIOError, SvcError};
 use crate::handle::RawHandle;
+use crate::heap::BufferMemory;
+#[cfg(feature = "metrics")]
+use crate::metrics::Counter;
+#[cfg(all(not(esp_idf_version = "4.3"), esp_idf_comp_esp_netif_enabled))]
+use crate::netif::Interface;
+#[cfg(feature = "heapless-config")]
+use crate::private::common::bounded_str;
 use crate::private::common::Newtype;
 use crate::private::cstr::*;
+use crate::private::mutex::Mutex;
 use crate::tls::X509;
+#[cfg(feature = "traffic")]
+use crate::traffic::{self, Service, Throttle};
+
+#[cfg(feature = "metrics")]
+static REQUESTS: Counter = Counter::new("esp_http_client_requests", "HTTP client requests");
+#[cfg(feature = "metrics")]
+static RESPONSES: Counter = Counter::new(
+    "esp_http_client_responses",
+    "HTTP client responses, by status class",
+);
+#[cfg(feature = "metrics")]
+static ERRORS: Counter = Counter::new(
+    "esp_http_client_errors",
+    "HTTP client requests failed before a response",
+);
+
+/// The redirects followed by a request at most, when not configured, as in ESP-IDF
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// The RX buffer size of the client of ESP-IDF, when not configured
+const DEFAULT_BUFFER_SIZE: usize = 512;
+
+/// The timeout of the socket operations of the client of ESP-IDF, when not configured
+#[cfg(not(esp_idf_version_major = "4"))]
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5000);
 
 impl From<Method> for Newtype<(esp_http_client_method_t, ())> {
     fn from(method: Method) -> Self {
@@ -58,6 +101,7 @@ impl From<Method> for Newtype<(esp_http_client_method_t, ())> {
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(feature = "std", derive(Hash))]
 pub enum FollowRedirectsPolicy {
     FollowNone,
@@ -71,18 +115,142 @@ impl Default for FollowRedirectsPolicy {
     }
 }
 
+/// How the credentials of `Configuration` are sent
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum AuthType {
+    /// Only in answer to the challenge of a `401` response, with the scheme it asks for
+    None,
+    /// With every request, without waiting for a challenge
+    Basic,
+    /// In answer to the `Digest` challenge of a `401` response, and with every later request
+    Digest,
+}
+
+impl Default for AuthType {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl From<AuthType> for Newtype<esp_http_client_auth_type_t> {
+    fn from(auth_type: AuthType) -> Self {
+        Self(match auth_type {
+            AuthType::None => esp_http_client_auth_type_t_HTTP_AUTH_TYPE_NONE,
+            AuthType::Basic => esp_http_client_auth_type_t_HTTP_AUTH_TYPE_BASIC,
+            AuthType::Digest => esp_http_client_auth_type_t_HTTP_AUTH_TYPE_DIGEST,
+        })
+    }
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 pub struct Configuration {
     pub buffer_size: Option<usize>,
     pub buffer_size_tx: Option<usize>,
     pub timeout: Option<core::time::Duration>,
     pub follow_redirects_policy: FollowRedirectsPolicy,
+    /// The redirects followed by a request at most, 10 by default; the response of the one
+    /// after fails with `ESP_ERR_HTTP_MAX_REDIRECT`
+    pub max_redirects: Option<usize>,
+    /// Follows the `307` and `308` redirects with the method of the request, rather than with a
+    /// `GET`
+    ///
+    /// As its body cannot be sent again, a request with one is not redirected then, and gets
+    /// the `307` or `308` response instead.
+    pub preserve_method_on_redirect: bool,
     pub client_certificate: Option<X509<'static>>,
     pub private_key: Option<X509<'static>>,
+    /// The credentials of the HTTP authentication, see also
+    /// `EspHttpConnection::set_credentials`
+    ///
+    /// On a `401` response to a request without a body, the challenge is answered and the
+    /// request sent again, once. As a body cannot be sent again, a request with one only gets
+    /// through if the credentials are sent upfront: with `AuthType::Basic`, or with
+    /// `AuthType::Digest` after an earlier request of the connection got the challenge.
+    pub username: Option<&'static str>,
+    pub password: Option<&'static str>,
+    pub auth_type: AuthType,
+
+    /// The name the certificate of the server must match, and the SNI sent, instead of the host
+    /// of the URL, e.g. for the `https` requests through a proxy
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub common_name: Option<&'static str>,
 
     pub use_global_ca_store: bool,
     #[cfg(not(esp_idf_version = "4.3"))]
     pub crt_bundle_attach: Option<unsafe extern "C" fn(conf: *mut core::ffi::c_void) -> esp_err_t>,
+    /// Binds the connection to the given network interface instead of using the default route
+    #[cfg(all(not(esp_idf_version = "4.3"), esp_idf_comp_esp_netif_enabled))]
+    pub interface: Option<Interface>,
+    /// Where the RX/TX buffers and the TLS buffers of the connection are allocated
+    pub buffer_memory: BufferMemory,
+    /// Keeps the connection open after a response, for the next request to the same scheme,
+    /// host and port to reuse it instead of connecting (and doing the TLS handshake) anew
+    ///
+    /// The rest of a response not read to its end is drained before the next request. A request
+    /// to another host, or after the server asked for `Connection: close`, still reconnects.
+    /// Without it, the connection is closed after every response.
+    pub keep_alive: bool,
+    /// Resumes the TLS session when the connection reconnects to the same host, e.g. on the next
+    /// request after the server closed the idle connection, which skips most of the handshake
+    ///
+    /// The session is kept for all the requests of this connection; the raw `crate::tls::EspTls`
+    /// connections share theirs through a `crate::tls::SessionCache` instead.
+    ///
+    /// Requires `CONFIG_ESP_TLS_CLIENT_SESSION_TICKETS`.
+    #[cfg(all(
+        not(esp_idf_version_major = "4"),
+        esp_idf_esp_tls_client_session_tickets
+    ))]
+    pub save_client_session: bool,
+    /// Stores the cookies of the responses, and sends the matching ones with the requests
+    ///
+    /// A `Cookie` header passed to `initiate_request` replaces those of the jar for that
+    /// request.
+    pub cookie_jar: Option<&'static CookieJar>,
+    /// Sends the requests through an HTTP proxy, tunneled with `CONNECT`
+    ///
+    /// The client connects to the local end of the tunnel, so over TLS it sends and checks the
+    /// server name of `common_name` rather than the host of the requests; with a proxy, the
+    /// `https` requests of a connection are thus limited to that host. See also the
+    /// `proxy` module.
+    #[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+    pub proxy: Option<proxy::Configuration>,
+    /// The service the traffic of the connection is accounted to
+    #[cfg(feature = "traffic")]
+    pub service: Service,
+    /// Limits the bandwidth of the connection, e.g. for background downloads
+    #[cfg(feature = "traffic")]
+    pub throttle: Option<&'static Throttle>,
+}
+
+/// A client configuration together with the URL of the requests, owned in a fixed-capacity
+/// buffer of `U` bytes, so that it can be stored without any heap allocation
+#[cfg(feature = "heapless-config")]
+#[derive(Clone, Debug, Default)]
+pub struct BoundedConfiguration<const U: usize = 256> {
+    pub url: heapless::String<U>,
+    pub conf: Configuration,
+}
+
+#[cfg(feature = "heapless-config")]
+impl<const U: usize> BoundedConfiguration<U> {
+    /// Fails with `ESP_ERR_INVALID_SIZE` if the URL does not fit its buffer
+    pub fn new(url: &str, conf: Configuration) -> Result<Self, EspError> {
+        Ok(Self {
+            url: bounded_str(url)?,
+            conf,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn configuration(&self) -> &Configuration {
+        &self.conf
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -92,16 +260,111 @@ enum State {
     Response,
 }
 
-#[allow(clippy::type_complexity)]
+/// Aborts the request in flight of an `EspHttpConnection`, from another thread
+///
+/// See `EspHttpConnection::cancel_handle`.
+#[derive(Clone)]
+pub struct CancelHandle(Arc<Mutex<Cancellation>>);
+
+impl CancelHandle {
+    /// Aborts the request in flight, if any, and returns whether there was one
+    ///
+    /// The call of the connection blocked on the request, or else its next call on it, fails
+    /// with `ESP_ERR_INVALID_STATE`, the connection being closed, ready for the next request.
+    /// On ESP-IDF 5.0 or earlier, a blocked call is not woken up, but fails once it returns.
+    pub fn cancel(&self) -> bool {
+        let mut cancellation = self.0.lock();
+
+        match cancellation.raw_client {
+            Some(raw_client) => {
+                cancellation.cancelled = true;
+
+                // Closes the socket, which wakes up the call blocked on it
+                #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+                unsafe {
+                    esp_http_client_cancel_request(raw_client.0)
+                };
+
+                #[cfg(any(esp_idf_version_major = "4", esp_idf_version = "5.0"))]
+                let _ = raw_client;
+
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl core::fmt::Debug for CancelHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CancelHandle")
+            .field("cancelled", &self.0.lock().cancelled)
+            .finish()
+    }
+}
+
+#[derive(Copy, Clone)]
+struct RawClient(esp_http_client_handle_t);
+
+unsafe impl Send for RawClient {}
+
+#[derive(Default)]
+struct Cancellation {
+    /// The client, while a request is in flight
+    raw_client: Option<RawClient>,
+    cancelled: bool,
+}
+
+/// `Send`, as it moves along with the connection
+type EventHandler = Box<dyn Fn(&esp_http_client_event_t) -> esp_err_t + Send>;
+
 pub struct EspHttpConnection {
     raw_client: esp_http_client_handle_t,
     follow_redirects_policy: FollowRedirectsPolicy,
-    event_handler: Box<Option<Box<dyn Fn(&esp_http_client_event_t) -> esp_err_t>>>,
+    max_redirects: usize,
+    preserve_method_on_redirect: bool,
+    keep_alive: bool,
+    event_handler: Box<Option<EventHandler>>,
     state: State,
     request_content_len: u64,
+    /// Whether the body of the request is sent with the chunked transfer encoding
+    chunked: bool,
+    /// Whether there are credentials to answer the authentication challenges with
+    credentials: bool,
+    /// Whether the request was sent again with the credentials already
+    authenticated: bool,
     follow_redirects: bool,
+    /// The locations the current request was redirected to
+    redirects: Vec<String>,
     headers: BTreeMap<Uncased<'static>, String>,
     content_len_header: UnsafeCell<Option<Option<String>>>,
+    url: String,
+    buffer_memory: BufferMemory,
+    /// The buffer `read_borrowed` lends, allocated on its first call
+    read_buffer: Vec<u8>,
+    read_buffer_size: usize,
+    cookie_jar: Option<&'static CookieJar>,
+    /// Whether the `Cookie` header of the current request comes from the jar
+    attach_cookies: bool,
+    /// The `Set-Cookie` headers, which the map of the headers only keeps the last of
+    set_cookies: Vec<String>,
+    #[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+    proxy: Option<EspHttpProxyForwarder>,
+    /// The timeout of the socket operations of the configuration
+    #[cfg(not(esp_idf_version_major = "4"))]
+    timeout: Duration,
+    /// The timeout of the socket operations of the client now, shortened by a deadline
+    #[cfg(not(esp_idf_version_major = "4"))]
+    current_timeout: Duration,
+    /// The timeout of the next request, see `set_request_timeout`
+    request_timeout: Option<Duration>,
+    /// When the request in flight times out, in the `esp_timer` time
+    deadline: Option<Duration>,
+    cancellation: Arc<Mutex<Cancellation>>,
+    #[cfg(feature = "traffic")]
+    service: Service,
+    #[cfg(feature = "traffic")]
+    throttle: Option<&'static Throttle>,
 }
 
 impl EspHttpConnection {
@@ -118,14 +381,51 @@ impl EspHttpConnection {
             use_global_ca_store: configuration.use_global_ca_store,
             #[cfg(not(esp_idf_version = "4.3"))]
             crt_bundle_attach: configuration.crt_bundle_attach,
+            #[cfg(all(
+                not(esp_idf_version_major = "4"),
+                esp_idf_esp_tls_client_session_tickets
+            ))]
+            save_client_session: configuration.save_client_session,
+
+            auth_type: Newtype::<esp_http_client_auth_type_t>::from(configuration.auth_type).0,
 
             ..Default::default()
         };
 
+        // Both are copied during initialization
+        let username = configuration
+            .username
+            .map(|username| CString::new(username).unwrap());
+        let password = configuration
+            .password
+            .map(|password| CString::new(password).unwrap());
+
+        if let Some(username) = &username {
+            native_config.username = username.as_ptr() as _;
+        }
+
+        if let Some(password) = &password {
+            native_config.password = password.as_ptr() as _;
+        }
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let common_name = configuration
+            .common_name
+            .map(|common_name| CString::new(common_name).unwrap());
+        #[cfg(not(esp_idf_version_major = "4"))]
+        if let Some(common_name) = &common_name {
+            native_config.common_name = common_name.as_ptr() as _;
+        }
+
         if let Some(buffer_size) = configuration.buffer_size {
             native_config.buffer_size = buffer_size as _;
         };
 
+        // For the redirects without a `Location` header, which the client follows itself
+        if let Some(max_redirects) = configuration.max_redirects {
+            native_config.max_redirection_count = max_redirects as _;
+        }
+
         if let Some(buffer_size_tx) = configuration.buffer_size_tx {
             native_config.buffer_size_tx = buffer_size_tx as _;
         }
@@ -144,23 +444,110 @@ impl EspHttpConnection {
             native_config.client_key_len = private_key.as_esp_idf_raw_len();
         }
 
-        let raw_client = unsafe { esp_http_client_init(&native_config) };
+        // The client copies the interface name during initialization
+        #[cfg(all(not(esp_idf_version = "4.3"), esp_idf_comp_esp_netif_enabled))]
+        let mut ifreq = configuration
+            .interface
+            .map(|interface| interface.ifreq())
+            .transpose()?;
+        #[cfg(all(not(esp_idf_version = "4.3"), esp_idf_comp_esp_netif_enabled))]
+        if let Some(ifreq) = ifreq.as_mut() {
+            native_config.if_name = ifreq as *mut _;
+        }
+
+        #[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+        let proxy = configuration
+            .proxy
+            .map(|proxy| EspHttpProxyForwarder::new(&proxy))
+            .transpose()?;
+
+        let raw_client = configuration
+            .buffer_memory
+            .scope(|| unsafe { esp_http_client_init(&native_config) });
         if raw_client.is_null() {
             Err(EspError::from_infallible::<ESP_FAIL>())
         } else {
             Ok(Self {
                 raw_client,
                 follow_redirects_policy: configuration.follow_redirects_policy,
+                max_redirects: configuration.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS),
+                preserve_method_on_redirect: configuration.preserve_method_on_redirect,
+                keep_alive: configuration.keep_alive,
                 event_handler,
                 state: State::New,
                 request_content_len: 0,
+                chunked: false,
+                credentials: configuration.username.is_some(),
+                authenticated: false,
                 follow_redirects: false,
+                redirects: Vec::new(),
+                url: String::new(),
                 headers: BTreeMap::new(),
                 content_len_header: UnsafeCell::new(None),
+                buffer_memory: configuration.buffer_memory,
+                read_buffer: Vec::new(),
+                read_buffer_size: configuration.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+                cookie_jar: configuration.cookie_jar,
+                attach_cookies: false,
+                set_cookies: Vec::new(),
+                #[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+                proxy,
+                #[cfg(not(esp_idf_version_major = "4"))]
+                timeout: configuration.timeout.unwrap_or(DEFAULT_TIMEOUT),
+                #[cfg(not(esp_idf_version_major = "4"))]
+                current_timeout: configuration.timeout.unwrap_or(DEFAULT_TIMEOUT),
+                request_timeout: None,
+                deadline: None,
+                cancellation: Arc::new(Mutex::new(Default::default())),
+                #[cfg(feature = "traffic")]
+                service: configuration.service,
+                #[cfg(feature = "traffic")]
+                throttle: configuration.throttle,
             })
         }
     }
 
+    /// Changes the credentials of the HTTP authentication, e.g. once read from the NVS
+    pub fn set_credentials(
+        &mut self,
+        username: &str,
+        password: &str,
+        auth_type: AuthType,
+    ) -> Result<(), EspError> {
+        let c_username = CString::new(username).unwrap();
+        let c_password = CString::new(password).unwrap();
+
+        esp!(unsafe { esp_http_client_set_username(self.raw_client, c_username.as_ptr() as _) })?;
+        esp!(unsafe { esp_http_client_set_password(self.raw_client, c_password.as_ptr() as _) })?;
+        esp!(unsafe {
+            esp_http_client_set_authtype(
+                self.raw_client,
+                Newtype::<esp_http_client_auth_type_t>::from(auth_type).0,
+            )
+        })?;
+
+        self.credentials = true;
+
+        Ok(())
+    }
+
+    /// Limits the next request, from `initiate_request` to the end of its response, to
+    /// `timeout`
+    ///
+    /// The timeouts of the socket operations are shortened to the time left, so that the
+    /// request fails with `ESP_ERR_TIMEOUT` once it is over, whatever the timeout of the
+    /// configuration, and the connection is then closed. On ESP-IDF 4, where these timeouts
+    /// cannot be changed, the time left is only checked between the calls.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    /// A handle to abort the requests of the connection from another thread, e.g. on user
+    /// request or on shutdown
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle(self.cancellation.clone())
+    }
+
     pub fn status(&self) -> u16 {
         self.assert_response();
         unsafe { esp_http_client_get_status_code(self.raw_client) as _ }
@@ -171,6 +558,18 @@ impl EspHttpConnection {
         None
     }
 
+    /// The URL of the current request or, once its response is initiated, that of the response
+    /// after the redirects followed
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The locations the current request was redirected to, in order, the last one being the
+    /// URL of the response
+    pub fn redirects(&self) -> &[String] {
+        &self.redirects
+    }
+
     pub fn header(&self, name: &str) -> Option<&str> {
         self.assert_response();
 
@@ -202,21 +601,55 @@ impl EspHttpConnection {
         uri: &'a str,
         headers: &'a [(&'a str, &'a str)],
     ) -> Result<(), EspError> {
+        self.initiate_request_ctx(method, uri, headers)
+            .map_err(SvcError::into_logged)
+    }
+
+    fn initiate_request_ctx(
+        &mut self,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), SvcError> {
         self.assert_initial();
 
-        let c_uri = CString::new(uri).unwrap();
+        if self.state == State::Response {
+            self.finish_response(uri)?;
+        }
 
-        esp!(unsafe { esp_http_client_set_url(self.raw_client, c_uri.as_ptr() as _) })?;
-        esp!(unsafe {
+        self.deadline = self
+            .request_timeout
+            .take()
+            .map(|timeout| uptime() + timeout);
+
+        *self.cancellation.lock() = Cancellation {
+            raw_client: Some(RawClient(self.raw_client)),
+            cancelled: false,
+        };
+
+        self.url.clear();
+        self.url.push_str(uri);
+        self.redirects.clear();
+
+        self.set_url()?;
+
+        esp_svc!(
+            "http",
             esp_http_client_set_method(
                 self.raw_client,
                 Newtype::<(esp_http_client_method_t, ())>::from(method).0 .0,
             )
-        })?;
+        )?;
 
         let mut content_len = None;
+        let mut cookie = false;
+        let mut chunked = false;
 
         for (name, value) in headers {
+            cookie |= name.eq_ignore_ascii_case("Cookie");
+            chunked |= name.eq_ignore_ascii_case("Transfer-Encoding")
+                && value.eq_ignore_ascii_case("chunked");
+
             if name.eq_ignore_ascii_case("Content-Length") {
                 if let Ok(len) = value.parse::<u64>() {
                     content_len = Some(len);
@@ -228,24 +661,60 @@ impl EspHttpConnection {
             // TODO: Replace with a proper conversion from UTF8 to ISO-8859-1
             let c_value = CString::new(*value).unwrap();
 
-            esp!(unsafe {
+            esp_svc!(
+                "http",
                 esp_http_client_set_header(
                     self.raw_client,
                     c_name.as_ptr() as _,
                     c_value.as_ptr() as _,
                 )
-            })?;
+            )?;
         }
 
+        self.attach_cookies = self.cookie_jar.is_some() && !cookie;
+        self.attach_cookies()?;
+
         self.follow_redirects = match self.follow_redirects_policy {
             FollowRedirectsPolicy::FollowAll => true,
             FollowRedirectsPolicy::FollowGetHead => method == Method::Get || method == Method::Head,
             _ => false,
         };
 
+        if self.chunked && !chunked {
+            self.remove_chunked();
+        }
+
         self.request_content_len = content_len.unwrap_or(0);
+        self.chunked = chunked;
+        self.authenticated = false;
+
+        #[cfg(feature = "metrics")]
+        REQUESTS.inc(&[("method", &format!("{:?}", method).to_uppercase())]);
 
-        esp!(unsafe { esp_http_client_open(self.raw_client, self.request_content_len as _) })?;
+        // A negative length makes the client send the body with the chunked encoding, the
+        // framing of the chunks being left to the writer
+        let write_len = if chunked {
+            -1
+        } else {
+            self.request_content_len as _
+        };
+
+        // The TLS buffers are allocated on connecting
+        let result = self.guarded(|connection| {
+            connection.buffer_memory.scope(|| {
+                esp_svc!(
+                    "http",
+                    esp_http_client_open(connection.raw_client, write_len)
+                )
+            })
+        });
+
+        #[cfg(feature = "metrics")]
+        if result.is_err() {
+            ERRORS.inc(&[]);
+        }
+
+        result?;
 
         self.state = State::Request;
 
@@ -257,9 +726,29 @@ impl EspHttpConnection {
     }
 
     pub fn initiate_response(&mut self) -> Result<(), EspError> {
+        self.initiate_response_ctx().map_err(SvcError::into_logged)
+    }
+
+    fn initiate_response_ctx(&mut self) -> Result<(), SvcError> {
         self.assert_request();
 
-        self.fetch_headers()?;
+        let result = self.guarded(Self::fetch_headers);
+
+        if result.is_err() && self.state == State::Request {
+            // The response cannot be read, and the next request must not find the connection
+            // half-way through this one
+            unsafe { esp_http_client_close(self.raw_client) };
+
+            self.state = State::New;
+        }
+
+        #[cfg(feature = "metrics")]
+        match &result {
+            Ok(()) => RESPONSES.inc(&[("status", &format!("{}xx", self.status() / 100))]),
+            Err(_) => ERRORS.inc(&[]),
+        }
+
+        result?;
 
         self.state = State::Response;
 
@@ -283,17 +772,67 @@ impl EspHttpConnection {
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
         self.assert_response();
 
-        Self::check(unsafe {
-            esp_http_client_read_response(self.raw_client, buf.as_mut_ptr() as _, buf.len() as _)
-        })
+        let size = self.guarded(|connection| {
+            Self::check(unsafe {
+                esp_http_client_read_response(
+                    connection.raw_client,
+                    buf.as_mut_ptr() as _,
+                    buf.len() as _,
+                )
+            })
+            .context("http", "esp_http_client_read_response")
+        })?;
+
+        #[cfg(feature = "traffic")]
+        self.account(size, traffic::record_rx);
+
+        Ok(size)
+    }
+
+    /// Reads the next piece of the response body into a buffer of the connection, and lends it,
+    /// instead of copying it into a buffer of the caller; the body ends with an empty slice
+    ///
+    /// The ESP-IDF client still copies the body out of its RX buffer, where it decodes the
+    /// chunked encoding, so the copy saved is the one the application would do. The buffer is
+    /// `Configuration::buffer_size` long and allocated on the first call, according to
+    /// `Configuration::buffer_memory`; the slice is released by the next call on the connection.
+    pub fn read_borrowed(&mut self) -> Result<&[u8], EspError> {
+        if self.read_buffer.is_empty() {
+            let size = self.read_buffer_size.max(1);
+
+            self.read_buffer = self.buffer_memory.scope(|| vec![0; size]);
+        }
+
+        let mut buf = mem::take(&mut self.read_buffer);
+        let result = self.read(&mut buf);
+        self.read_buffer = buf;
+
+        Ok(&self.read_buffer[..result?])
     }
 
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
         self.assert_request();
 
-        Self::check(unsafe {
-            esp_http_client_write(self.raw_client, buf.as_ptr() as _, buf.len() as _)
-        })
+        let size = self.guarded(|connection| {
+            Self::check(unsafe {
+                esp_http_client_write(connection.raw_client, buf.as_ptr() as _, buf.len() as _)
+            })
+            .context("http", "esp_http_client_write")
+        })?;
+
+        #[cfg(feature = "traffic")]
+        self.account(size, traffic::record_tx);
+
+        Ok(size)
+    }
+
+    #[cfg(feature = "traffic")]
+    fn account(&self, size: usize, record: fn(Service, usize)) {
+        record(self.service, size);
+
+        if let Some(throttle) = self.throttle {
+            throttle.acquire(size);
+        }
     }
 
     pub fn flush(&mut self) -> Result<(), EspError> {
@@ -302,6 +841,71 @@ impl EspHttpConnection {
         Ok(())
     }
 
+    /// Runs a blocking call of the client within the deadline of the request, and aborts the
+    /// request when cancelled or past its deadline
+    fn guarded<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, SvcError>,
+    ) -> Result<T, SvcError> {
+        self.check_aborted()?;
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        {
+            let timeout = match self.deadline {
+                Some(deadline) => self.timeout.min(deadline.saturating_sub(uptime())),
+                None => self.timeout,
+            };
+
+            if timeout != self.current_timeout {
+                // At least a millisecond, as 0 would not time out at all
+                let timeout_ms = (timeout.as_millis() as i32).max(1);
+
+                esp_svc!(
+                    "http",
+                    esp_http_client_set_timeout_ms(self.raw_client, timeout_ms)
+                )?;
+
+                self.current_timeout = timeout;
+            }
+        }
+
+        let result = f(self);
+
+        self.check_aborted()?;
+
+        result
+    }
+
+    /// Closes the connection and fails if the request was cancelled or is past its deadline
+    fn check_aborted(&mut self) -> Result<(), SvcError> {
+        let cancelled = self.cancellation.lock().cancelled;
+
+        let error = if cancelled {
+            warn!("Request to {} cancelled", self.url);
+
+            EspError::from_infallible::<ESP_ERR_INVALID_STATE>()
+        } else if self
+            .deadline
+            .map(|deadline| uptime() >= deadline)
+            .unwrap_or(false)
+        {
+            warn!("Request to {} timed out", self.url);
+
+            EspError::from_infallible::<ESP_ERR_TIMEOUT>()
+        } else {
+            return Ok(());
+        };
+
+        *self.cancellation.lock() = Default::default();
+        self.deadline = None;
+
+        unsafe { esp_http_client_close(self.raw_client) };
+
+        self.state = State::New;
+
+        Err(error).context("http", "request aborted")
+    }
+
     fn check(result: i32) -> Result<usize, EspError> {
         match EspError::from(result) {
             Some(err) if result < 0 => Err(err),
@@ -312,8 +916,7 @@ impl EspHttpConnection {
     extern "C" fn on_events(event: *mut esp_http_client_event_t) -> esp_err_t {
         match unsafe { event.as_mut() } {
             Some(event) => {
-                let handler = event.user_data
-                    as *const Option<Box<dyn Fn(&esp_http_client_event_t) -> esp_err_t>>;
+                let handler = event.user_data as *const Option<EventHandler>;
                 if let Some(handler) = unsafe { handler.as_ref() } {
                     if let Some(handler) = handler.as_ref() {
                         return handler(event);
@@ -326,23 +929,35 @@ impl EspHttpConnection {
         }
     }
 
-    fn fetch_headers(&mut self) -> Result<(), EspError> {
+    fn fetch_headers(&mut self) -> Result<(), SvcError> {
         self.headers.clear();
         *self.content_len_header.get_mut() = None;
 
         loop {
             // TODO: Implement a mechanism where the client can declare in which header it is interested
-            let headers_ptr = &mut self.headers as *mut BTreeMap<Uncased, String>;
+            // As addresses, for the handler to be `Send`; both outlive its registration
+            let headers_ptr = &mut self.headers as *mut BTreeMap<Uncased, String> as usize;
+            let set_cookies_ptr = &mut self.set_cookies as *mut Vec<String> as usize;
+            let cookies = self.cookie_jar.is_some();
 
             let handler = move |event: &esp_http_client_event_t| {
                 if event.event_id == esp_http_client_event_id_t_HTTP_EVENT_ON_HEADER {
                     unsafe {
                         // TODO: Replace with a proper conversion from ISO-8859-1 to UTF8
-
-                        headers_ptr.as_mut().unwrap().insert(
-                            Uncased::from(from_cstr_ptr(event.header_key).to_string()),
-                            from_cstr_ptr(event.header_value).to_string(),
-                        );
+                        let key = from_cstr_ptr(event.header_key);
+                        let value = from_cstr_ptr(event.header_value);
+
+                        if cookies && key.eq_ignore_ascii_case("Set-Cookie") {
+                            (set_cookies_ptr as *mut Vec<String>)
+                                .as_mut()
+                                .unwrap()
+                                .push(value.to_string());
+                        }
+
+                        (headers_ptr as *mut BTreeMap<Uncased, String>)
+                            .as_mut()
+                            .unwrap()
+                            .insert(Uncased::from(key.to_string()), value.to_string());
                     }
                 }
 
@@ -355,28 +970,83 @@ impl EspHttpConnection {
 
             self.deregister_handler();
 
-            Self::check(result as _)?;
+            Self::check(result as _).context("http", "esp_http_client_fetch_headers")?;
 
             trace!("Fetched headers: {:?}", self.headers);
 
+            self.store_cookies();
+
+            #[cfg(not(esp_idf_version = "4.3"))]
+            if self.authenticate()? {
+                continue;
+            }
+
             if self.follow_redirects {
                 let status = unsafe { esp_http_client_get_status_code(self.raw_client) as u16 };
 
-                if status::REDIRECT.contains(&status) && status != 304 {
+                let preserve_method =
+                    self.preserve_method_on_redirect && (status == 307 || status == 308);
+
+                if status::REDIRECT.contains(&status)
+                    && status != 304
+                    && !(preserve_method && (self.chunked || self.request_content_len > 0))
+                {
+                    if self.redirects.len() >= self.max_redirects {
+                        warn!(
+                            "Got response {} after {} redirects, giving up",
+                            status,
+                            self.redirects.len()
+                        );
+
+                        return Err(EspError::from_infallible::<ESP_ERR_HTTP_MAX_REDIRECT>())
+                            .context("http", "fetch_headers");
+                    }
+
                     info!("Got response {}, about to follow redirect", status);
 
                     let mut len = 0_i32;
-                    esp!(unsafe { esp_http_client_flush_response(self.raw_client, &mut len) })?;
-                    esp!(unsafe {
-                        esp_http_client_set_method(
-                            self.raw_client,
-                            esp_http_client_method_t_HTTP_METHOD_GET,
+                    esp_svc!(
+                        "http",
+                        esp_http_client_flush_response(self.raw_client, &mut len)
+                    )?;
+
+                    if !preserve_method {
+                        esp_svc!(
+                            "http",
+                            esp_http_client_set_method(
+                                self.raw_client,
+                                esp_http_client_method_t_HTTP_METHOD_GET,
+                            )
+                        )?;
+                    }
+
+                    if let Some(location) = self.headers.get(UncasedStr::new("Location")) {
+                        self.url = uri::join(&self.url, location);
+                        self.redirects.push(self.url.clone());
+
+                        info!("Redirecting to {}", self.url);
+
+                        self.set_url()?;
+                    } else {
+                        esp_svc!("http", esp_http_client_set_redirection(self.raw_client))?;
+                    }
+
+                    self.attach_cookies()?;
+
+                    // The redirected request has no body
+                    if self.chunked {
+                        self.remove_chunked();
+                        self.chunked = false;
+                    }
+
+                    self.request_content_len = 0;
+
+                    self.buffer_memory.scope(|| {
+                        esp_svc!(
+                            "http",
+                            esp_http_client_open(self.raw_client, self.request_content_len as _)
                         )
                     })?;
-                    esp!(unsafe { esp_http_client_set_redirection(self.raw_client) })?;
-                    esp!(unsafe {
-                        esp_http_client_open(self.raw_client, self.request_content_len as _)
-                    })?;
 
                     self.headers.clear();
 
@@ -390,9 +1060,184 @@ impl EspHttpConnection {
         Ok(())
     }
 
+    /// Answers the challenge of a `401` response, sending the request again, unless it has a
+    /// body or it was sent with the credentials already
+    ///
+    /// Returns whether the request was sent again.
+    #[cfg(not(esp_idf_version = "4.3"))]
+    fn authenticate(&mut self) -> Result<bool, SvcError> {
+        let status = unsafe { esp_http_client_get_status_code(self.raw_client) };
+
+        if status != 401
+            || !self.credentials
+            || self.authenticated
+            || self.chunked
+            || self.request_content_len > 0
+        {
+            return Ok(false);
+        }
+
+        info!("Got response 401, about to authenticate");
+
+        self.authenticated = true;
+
+        let close = self
+            .headers
+            .get(UncasedStr::new("Connection"))
+            .map(|connection| connection.eq_ignore_ascii_case("close"))
+            .unwrap_or(false);
+
+        if close {
+            esp_svc!("http", esp_http_client_close(self.raw_client))?;
+        } else {
+            let mut len = 0_i32;
+            esp_svc!(
+                "http",
+                esp_http_client_flush_response(self.raw_client, &mut len)
+            )?;
+        }
+
+        // Parses the `WWW-Authenticate` header the client kept, and sets the `Authorization`
+        // one
+        esp_svc!("http", esp_http_client_add_auth(self.raw_client))?;
+
+        self.attach_cookies()?;
+
+        self.buffer_memory.scope(|| {
+            esp_svc!(
+                "http",
+                esp_http_client_open(self.raw_client, self.request_content_len as _)
+            )
+        })?;
+
+        self.headers.clear();
+
+        Ok(true)
+    }
+
+    /// Points the client to `self.url` or, through a proxy, to the local end of the tunnel to
+    /// its host
+    fn set_url(&self) -> Result<(), SvcError> {
+        #[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+        if let Some(proxy) = self.proxy.as_ref() {
+            let (host, port, authority, url) = proxy::forwarded_url(&self.url, proxy.port())
+                .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_ARG>)
+                .context("http", "proxy::forwarded_url")?;
+
+            // The client sees the same local host for all the targets, and would keep the
+            // connection tunneled to the previous one
+            if proxy.set_target(host, port) {
+                esp_svc!("http", esp_http_client_close(self.raw_client))?;
+            }
+
+            let c_url = CString::new(url).unwrap();
+
+            esp_svc!(
+                "http",
+                esp_http_client_set_url(self.raw_client, c_url.as_ptr() as _)
+            )?;
+
+            let c_host = CString::new(authority).unwrap();
+
+            esp_svc!(
+                "http",
+                esp_http_client_set_header(
+                    self.raw_client,
+                    b"Host\0".as_ptr() as _,
+                    c_host.as_ptr() as _,
+                )
+            )?;
+
+            return Ok(());
+        }
+
+        let c_url = CString::new(self.url.as_str()).unwrap();
+
+        esp_svc!(
+            "http",
+            esp_http_client_set_url(self.raw_client, c_url.as_ptr() as _)
+        )?;
+
+        Ok(())
+    }
+
+    /// Sets the `Cookie` header of the request to `self.url` from the jar, or removes that of
+    /// the previous request if no cookie matches
+    fn attach_cookies(&self) -> Result<(), SvcError> {
+        let header = match self.cookie_jar {
+            Some(jar) if self.attach_cookies => jar.header(&self.url),
+            _ => return Ok(()),
+        };
+
+        match header {
+            Some(header) => {
+                let c_header = CString::new(header).unwrap();
+
+                esp_svc!(
+                    "http",
+                    esp_http_client_set_header(
+                        self.raw_client,
+                        b"Cookie\0".as_ptr() as _,
+                        c_header.as_ptr() as _,
+                    )
+                )?;
+            }
+            None => {
+                // Fails with `ESP_ERR_NOT_FOUND` if there is no such header, which is fine
+                unsafe {
+                    esp_http_client_delete_header(self.raw_client, b"Cookie\0".as_ptr() as _)
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the `Transfer-Encoding` header a chunked request left behind
+    fn remove_chunked(&self) {
+        unsafe {
+            esp_http_client_delete_header(self.raw_client, b"Transfer-Encoding\0".as_ptr() as _)
+        };
+    }
+
+    fn store_cookies(&mut self) {
+        if let Some(jar) = self.cookie_jar {
+            for set_cookie in self.set_cookies.drain(..) {
+                if !jar.set(&self.url, &set_cookie) {
+                    warn!("Ignoring an invalid cookie from {}", self.url);
+                }
+            }
+        }
+    }
+
+    /// Drains the previous response for its connection to be reused by the request to `uri`,
+    /// or closes the connection if it cannot or should not be
+    fn finish_response(&mut self, uri: &str) -> Result<(), SvcError> {
+        let reuse = self.keep_alive
+            && origin(&self.url).eq_ignore_ascii_case(origin(uri))
+            && !self
+                .header("Connection")
+                .map(|connection| connection.eq_ignore_ascii_case("close"))
+                .unwrap_or(false);
+
+        if reuse {
+            let mut len = 0_i32;
+
+            if unsafe { esp_http_client_flush_response(self.raw_client, &mut len) } == ESP_OK {
+                debug!("Reusing the connection to {}", origin(uri));
+
+                return Ok(());
+            }
+        }
+
+        esp_svc!("http", esp_http_client_close(self.raw_client))?;
+
+        Ok(())
+    }
+
     fn register_handler(
         &mut self,
-        handler: impl Fn(&esp_http_client_event_t) -> esp_err_t + 'static,
+        handler: impl Fn(&esp_http_client_event_t) -> esp_err_t + Send + 'static,
     ) {
         *self.event_handler = Some(Box::new(handler));
     }
@@ -420,13 +1265,33 @@ impl EspHttpConnection {
     }
 }
 
+fn uptime() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as _)
+}
+
+/// The scheme and the authority of `url`, e.g. `https://example.com:8443`
+fn origin(url: &str) -> &str {
+    match uri::split_absolute(url) {
+        Some((_, _, rest)) => &url[..url.len() - rest.len()],
+        None => url,
+    }
+}
+
 impl Drop for EspHttpConnection {
     fn drop(&mut self) {
+        // The handles must not cancel the requests of a client gone
+        *self.cancellation.lock() = Default::default();
+
         esp!(unsafe { esp_http_client_cleanup(self.raw_client) })
             .expect("Unable to stop the client cleanly");
     }
 }
 
+// SAFETY: The raw client handle is not bound to the task which created it, and it is only used
+// through `&mut self` (or by the `CancelHandle`, which ESP-IDF allows from another task). The
+// event handler is `Send`, and the other fields are `Send` on their own.
+unsafe impl Send for EspHttpConnection {}
+
 impl RawHandle for EspHttpConnection {
     type Handle = esp_http_client_handle_t;
 
@@ -513,3 +1378,311 @@ impl Connection for EspHttpConnection {
         Err(EspError::from_infallible::<ESP_FAIL>().into())
     }
 }
+
+/// The `Read` of a file part, with its error type erased
+trait PartRead {
+    fn read_part(&mut self, buf: &mut [u8]) -> Result<usize, EspError>;
+}
+
+impl<R> PartRead for R
+where
+    R: Read,
+{
+    fn read_part(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        self.read(buf).map_err(|e| {
+            warn!("Reading a multipart file failed: {:?}", e);
+
+            EspError::from_infallible::<ESP_FAIL>()
+        })
+    }
+}
+
+enum PartBody<'a> {
+    Bytes(&'a [u8]),
+    Read(&'a mut dyn PartRead, Option<u64>),
+}
+
+struct Part<'a> {
+    /// The boundary delimiter and the headers of the part
+    head: String,
+    body: PartBody<'a>,
+}
+
+/// A `multipart/form-data` request body, e.g. for uploading a file read from the flash
+///
+/// The parts are streamed through `EspHttpConnection::write`, the files straight from their
+/// `Read`. The body is sent with a `Content-Length` if the lengths of all its files are known,
+/// and with the chunked transfer encoding otherwise.
+///
+/// ```ignore
+/// let mut file = File::open("/spiffs/log.txt")?;
+///
+/// let mut multipart = MultipartRequest::new();
+/// multipart
+///     .field("device", "sensor-12")
+///     .file("log", "log.txt", Some("text/plain"), &mut file, None);
+///
+/// multipart.send(&mut connection, Method::Post, "https://example.com/upload", &[])?;
+/// connection.initiate_response()?;
+/// ```
+pub struct MultipartRequest<'a> {
+    boundary: String,
+    parts: Vec<Part<'a>>,
+}
+
+impl<'a> MultipartRequest<'a> {
+    /// A request with a random boundary
+    pub fn new() -> Self {
+        Self::with_boundary(&format!(
+            "esp-idf-svc-{:08x}{:08x}",
+            crate::random::next_u32(),
+            crate::random::next_u32()
+        ))
+    }
+
+    /// A request with the given boundary, which must not occur in any part
+    pub fn with_boundary(boundary: &str) -> Self {
+        Self {
+            boundary: boundary.into(),
+            parts: Vec::new(),
+        }
+    }
+
+    pub fn boundary(&self) -> &str {
+        &self.boundary
+    }
+
+    /// The value of the `Content-Type` header of the request
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    /// The length of the body, `None` if a file of unknown length makes it chunked
+    pub fn content_len(&self) -> Option<u64> {
+        let mut len = self.boundary.len() as u64 + 6;
+
+        for part in &self.parts {
+            len += part.head.len() as u64 + 2;
+
+            len += match &part.body {
+                PartBody::Bytes(bytes) => bytes.len() as u64,
+                PartBody::Read(_, len) => (*len)?,
+            };
+        }
+
+        Some(len)
+    }
+
+    pub fn field(&mut self, name: &str, value: &'a str) -> &mut Self {
+        self.part(name, None, None, PartBody::Bytes(value.as_bytes()))
+    }
+
+    /// A file part with its content in memory
+    pub fn file_bytes(
+        &mut self,
+        name: &str,
+        filename: &str,
+        content_type: Option<&str>,
+        content: &'a [u8],
+    ) -> &mut Self {
+        self.part(name, Some(filename), content_type, PartBody::Bytes(content))
+    }
+
+    /// A file part read from `read` until its end, `len` being its length if known
+    ///
+    /// If `read` ends before or after `len` bytes, the request fails with
+    /// `ESP_ERR_INVALID_SIZE`, as its body is corrupt.
+    pub fn file<R>(
+        &mut self,
+        name: &str,
+        filename: &str,
+        content_type: Option<&str>,
+        read: &'a mut R,
+        len: Option<u64>,
+    ) -> &mut Self
+    where
+        R: Read,
+    {
+        self.part(
+            name,
+            Some(filename),
+            content_type,
+            PartBody::Read(read, len),
+        )
+    }
+
+    /// Initiates the request on `connection` and sends the body, so that
+    /// `EspHttpConnection::initiate_response` is the next call
+    ///
+    /// The `Content-Type` and the `Content-Length` (or `Transfer-Encoding`) headers are added
+    /// to `headers`.
+    pub fn send(
+        &mut self,
+        connection: &mut EspHttpConnection,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), EspError> {
+        self.send_ctx(connection, method, uri, headers)
+            .map_err(SvcError::into_logged)
+    }
+
+    fn send_ctx(
+        &mut self,
+        connection: &mut EspHttpConnection,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), SvcError> {
+        let content_type = self.content_type();
+        let content_len = self.content_len().map(|len| len.to_string());
+
+        let mut all_headers = headers.to_vec();
+        all_headers.push(("Content-Type", content_type.as_str()));
+        all_headers.push(match &content_len {
+            Some(len) => ("Content-Length", len.as_str()),
+            None => ("Transfer-Encoding", "chunked"),
+        });
+
+        connection.initiate_request_ctx(method, uri, &all_headers)?;
+
+        let mut writer = BodyWriter {
+            connection,
+            chunked: content_len.is_none(),
+        };
+
+        let mut buf = [0_u8; 512];
+
+        for part in &mut self.parts {
+            writer.write(part.head.as_bytes())?;
+
+            match &mut part.body {
+                PartBody::Bytes(bytes) => writer.write(bytes)?,
+                PartBody::Read(read, len) => {
+                    let mut read_len = 0;
+
+                    loop {
+                        let size = read.read_part(&mut buf).context("http", "multipart")?;
+                        if size == 0 {
+                            break;
+                        }
+
+                        writer.write(&buf[..size])?;
+                        read_len += size as u64;
+                    }
+
+                    if len.map(|len| len != read_len).unwrap_or(false) {
+                        warn!("Multipart file of {} bytes instead of {:?}", read_len, len);
+
+                        return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())
+                            .context("http", "multipart");
+                    }
+                }
+            }
+
+            writer.write(b"\r\n")?;
+        }
+
+        writer.write(b"--")?;
+        writer.write(self.boundary.as_bytes())?;
+        writer.write(b"--\r\n")?;
+
+        if writer.chunked {
+            writer.write_raw(b"0\r\n\r\n")?;
+        }
+
+        Ok(())
+    }
+
+    fn part(
+        &mut self,
+        name: &str,
+        filename: Option<&str>,
+        content_type: Option<&str>,
+        body: PartBody<'a>,
+    ) -> &mut Self {
+        let mut head = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"",
+            self.boundary
+        );
+        push_quoted(&mut head, name);
+        head.push('"');
+
+        if let Some(filename) = filename {
+            head.push_str("; filename=\"");
+            push_quoted(&mut head, filename);
+            head.push('"');
+        }
+
+        head.push_str("\r\n");
+
+        if let Some(content_type) = content_type {
+            head.push_str("Content-Type: ");
+            head.push_str(content_type);
+            head.push_str("\r\n");
+        }
+
+        head.push_str("\r\n");
+
+        self.parts.push(Part { head, body });
+
+        self
+    }
+}
+
+impl<'a> Default for MultipartRequest<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Pushes a name or a filename escaped as the quoted strings of the `Content-Disposition`
+/// headers of the HTML forms
+fn push_quoted(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("%22"),
+            '\r' => out.push_str("%0D"),
+            '\n' => out.push_str("%0A"),
+            c => out.push(c),
+        }
+    }
+}
+
+struct BodyWriter<'c> {
+    connection: &'c mut EspHttpConnection,
+    chunked: bool,
+}
+
+impl<'c> BodyWriter<'c> {
+    fn write(&mut self, data: &[u8]) -> Result<(), SvcError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        if self.chunked {
+            self.write_raw(format!("{:x}\r\n", data.len()).as_bytes())?;
+            self.write_raw(data)?;
+            self.write_raw(b"\r\n")
+        } else {
+            self.write_raw(data)
+        }
+    }
+
+    fn write_raw(&mut self, mut data: &[u8]) -> Result<(), SvcError> {
+        while !data.is_empty() {
+            let size = self
+                .connection
+                .write(data)
+                .context("http", "esp_http_client_write")?;
+            if size == 0 {
+                return Err(EspError::from_infallible::<ESP_FAIL>())
+                    .context("http", "esp_http_client_write");
+            }
+
+            data = &data[size..];
+        }
+
+        Ok(())
+    }
+}
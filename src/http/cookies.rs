@@ -0,0 +1,419 @@
+//! In-memory cookie store for the HTTP client
+//!
+//! A `CookieJar` keeps the cookies set by the `Set-Cookie` headers of the responses, and the
+//! HTTP client attaches the ones matching the URL of every request to it as a `Cookie` header,
+//! e.g. to keep the session of a web API which authenticates with cookies:
+//!
+//! ```ignore
+//! static COOKIES: CookieJar = CookieJar::new(16);
+//!
+//! let mut client = EspHttpConnection::new(&Configuration {
+//!     cookie_jar: Some(&COOKIES),
+//!     ..Default::default()
+//! })?;
+//!
+//! // ... log in, then make the authenticated requests
+//!
+//! for cookie in COOKIES.cookies() {
+//!     info!("{}={} for {}{}", cookie.name, cookie.value, cookie.domain, cookie.path);
+//! }
+//!
+//! COOKIES.clear();
+//! ```
+//!
+//! The cookies follow RFC 6265, except for the public suffix list, which is not checked: a
+//! `Domain` attribute is only rejected if it does not match the host of the response, or has
+//! no dot in it.
+use core::fmt;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use esp_idf_sys::*;
+
+use super::uri;
+
+use crate::private::civil::days_from_civil;
+use crate::private::mutex::{Mutex, RawMutex};
+
+/// 2020-01-01: before that, the system time has not been set
+const TIME_SET_AFTER: u64 = 1_577_836_800;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// Lowercase, without a leading dot
+    pub domain: String,
+    /// Whether the cookie is only sent to `domain` itself, and not to its subdomains, i.e. it
+    /// was set without a `Domain` attribute
+    pub host_only: bool,
+    pub path: String,
+    /// Whether the cookie is only sent over `https`
+    pub secure: bool,
+    pub http_only: bool,
+    /// The time since boot at which the cookie expires, `None` for a session cookie
+    pub expires: Option<Duration>,
+}
+
+impl Cookie {
+    /// Parses the value of a `Set-Cookie` header of the response to `url`, `None` if it is
+    /// malformed or its `Domain` attribute does not match the host of `url`
+    pub fn parse(url: &str, set_cookie: &str) -> Option<Self> {
+        let (_, host, request_path) = split_url(url)?;
+
+        let mut attributes = set_cookie.split(';');
+
+        let (name, value) = attributes.next()?.split_once('=')?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut cookie = Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: host.to_ascii_lowercase(),
+            host_only: true,
+            path: default_path(request_path).to_string(),
+            secure: false,
+            http_only: false,
+            expires: None,
+        };
+
+        let mut max_age = None;
+        let mut expires = None;
+
+        for attribute in attributes {
+            let (attribute, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let (attribute, value) = (attribute.trim(), value.trim());
+
+            if attribute.eq_ignore_ascii_case("Max-Age") {
+                max_age = value.parse::<i64>().ok().or(max_age);
+            } else if attribute.eq_ignore_ascii_case("Expires") {
+                expires = parse_date(value).or(expires);
+            } else if attribute.eq_ignore_ascii_case("Domain") {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+
+                if !domain.is_empty() {
+                    if !domain_matches(&cookie.domain, &domain)
+                        || (!domain.contains('.') && domain != cookie.domain)
+                    {
+                        return None;
+                    }
+
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+            } else if attribute.eq_ignore_ascii_case("Path") {
+                if value.starts_with('/') {
+                    cookie.path = value.to_string();
+                }
+            } else if attribute.eq_ignore_ascii_case("Secure") {
+                cookie.secure = true;
+            } else if attribute.eq_ignore_ascii_case("HttpOnly") {
+                cookie.http_only = true;
+            }
+        }
+
+        let now = uptime();
+
+        // Max-Age takes precedence over Expires
+        cookie.expires = if let Some(max_age) = max_age {
+            Some(now + Duration::from_secs(max_age.max(0) as _))
+        } else if let Some(expires) = expires {
+            match system_time() {
+                Some(system_now) => {
+                    Some(now + Duration::from_secs(expires.saturating_sub(system_now)))
+                }
+                // The past dates removing a cookie are still recognized as such
+                None if expires <= TIME_SET_AFTER => Some(now),
+                // Otherwise, an Expires date cannot be related to the uptime
+                None => None,
+            }
+        } else {
+            None
+        };
+
+        Some(cookie)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .map(|expires| expires <= uptime())
+            .unwrap_or(false)
+    }
+
+    /// Whether the cookie is sent with a request to `url`
+    pub fn matches(&self, url: &str) -> bool {
+        let (https, host, path) = match split_url(url) {
+            Some(split) => split,
+            None => return false,
+        };
+
+        let host = host.to_ascii_lowercase();
+
+        let domain_matches = if self.host_only {
+            host == self.domain
+        } else {
+            domain_matches(&host, &self.domain)
+        };
+
+        domain_matches && path_matches(path, &self.path) && (https || !self.secure)
+    }
+}
+
+/// A store of up to a fixed number of cookies, shared by the HTTP connections referring to it
+/// in their `Configuration::cookie_jar`
+///
+/// When the jar is full, the expired cookies are evicted first, and then the least recently
+/// set ones.
+pub struct CookieJar {
+    capacity: usize,
+    /// The most recently set last
+    cookies: Mutex<Vec<Cookie>>,
+}
+
+impl CookieJar {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            cookies: Mutex::wrap(RawMutex::new(), Vec::new()),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of cookies, expired ones included until they are evicted
+    pub fn len(&self) -> usize {
+        self.cookies.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The cookies which have not expired yet
+    pub fn cookies(&self) -> Vec<Cookie> {
+        self.cookies
+            .lock()
+            .iter()
+            .filter(|cookie| !cookie.is_expired())
+            .cloned()
+            .collect()
+    }
+
+    /// Stores the cookie of a `Set-Cookie` header of the response to `url`, replacing the one
+    /// with the same name, domain and path
+    ///
+    /// A cookie already expired, e.g. with `Max-Age=0`, only removes the one it replaces.
+    /// Returns `false` if the header is malformed or not valid for `url`.
+    pub fn set(&self, url: &str, set_cookie: &str) -> bool {
+        match Cookie::parse(url, set_cookie) {
+            Some(cookie) => {
+                self.insert(cookie);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Inserts `cookie`, replacing the one with the same name, domain and path
+    pub fn insert(&self, cookie: Cookie) {
+        let mut cookies = self.cookies.lock();
+
+        cookies.retain(|other| {
+            !(other.name == cookie.name
+                && other.domain == cookie.domain
+                && other.path == cookie.path)
+        });
+
+        if cookie.is_expired() {
+            return;
+        }
+
+        if cookies.len() >= self.capacity {
+            cookies.retain(|other| !other.is_expired());
+        }
+
+        while !cookies.is_empty() && cookies.len() >= self.capacity {
+            cookies.remove(0);
+        }
+
+        if self.capacity > 0 {
+            cookies.push(cookie);
+        }
+    }
+
+    /// The value of the `Cookie` header of a request to `url`, `None` if no cookie matches it
+    pub fn header(&self, url: &str) -> Option<String> {
+        let cookies = self.cookies.lock();
+
+        let mut matching = cookies
+            .iter()
+            .filter(|cookie| !cookie.is_expired() && cookie.matches(url))
+            .collect::<Vec<_>>();
+
+        // The cookies with the longer paths first, as per RFC 6265
+        matching.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+
+        let mut header = String::new();
+
+        for cookie in matching {
+            if !header.is_empty() {
+                header.push_str("; ");
+            }
+
+            header.push_str(&cookie.name);
+            header.push('=');
+            header.push_str(&cookie.value);
+        }
+
+        (!header.is_empty()).then(|| header)
+    }
+
+    /// Removes the cookies named `name` set for `domain`, whatever their path
+    pub fn remove(&self, domain: &str, name: &str) {
+        let domain = domain.trim_start_matches('.');
+
+        self.cookies
+            .lock()
+            .retain(|cookie| !(cookie.name == name && cookie.domain.eq_ignore_ascii_case(domain)));
+    }
+
+    /// Removes the session cookies, i.e. those without an expiry, e.g. on logging out
+    pub fn clear_session(&self) {
+        self.cookies
+            .lock()
+            .retain(|cookie| cookie.expires.is_some());
+    }
+
+    pub fn clear(&self) {
+        self.cookies.lock().clear();
+    }
+}
+
+impl fmt::Debug for CookieJar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CookieJar")
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// Whether `url` is an `https` one, and its host and path
+fn split_url(url: &str) -> Option<(bool, &str, &str)> {
+    let (scheme, authority, rest) = uri::split_absolute(url)?;
+
+    let path = uri::split(rest).0;
+
+    Some((
+        scheme.eq_ignore_ascii_case("https"),
+        authority.host,
+        if path.is_empty() { "/" } else { path },
+    ))
+}
+
+/// The directory of `path`, the default path of the cookies set by a response to it
+fn default_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(index) => &path[..index],
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    if host == domain {
+        return true;
+    }
+
+    // The IP addresses only match themselves
+    let ip = host.contains(':') || host.bytes().all(|b| b.is_ascii_digit() || b == b'.');
+
+    !ip && host.len() > domain.len()
+        && host.ends_with(domain)
+        && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+}
+
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path.as_bytes()[cookie_path.len()] == b'/'))
+}
+
+/// Parses the date of an `Expires` attribute into seconds since the UNIX epoch, leniently as
+/// per RFC 6265, so that the RFC 1123, RFC 850 and asctime formats are all accepted
+fn parse_date(date: &str) -> Option<u64> {
+    let mut time = None;
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+
+    let tokens = date
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != ':')
+        .filter(|token| !token.is_empty());
+
+    for token in tokens {
+        if time.is_none() && token.contains(':') {
+            let mut fields = token.split(':').map(|field| field.parse::<u32>().ok());
+
+            if let (Some(Some(hours)), Some(Some(minutes)), Some(Some(seconds))) =
+                (fields.next(), fields.next(), fields.next())
+            {
+                time = Some((hours, minutes, seconds));
+            }
+        } else if month.is_none() && token.len() >= 3 && !token.as_bytes()[0].is_ascii_digit() {
+            month = MONTHS
+                .iter()
+                .position(|month| token[..3].eq_ignore_ascii_case(month))
+                .map(|index| index as u32 + 1);
+        } else if let Ok(number) = token.parse::<u32>() {
+            if day.is_none() && token.len() <= 2 {
+                day = Some(number);
+            } else if year.is_none() && (token.len() == 2 || token.len() == 4) {
+                year = Some(number);
+            }
+        }
+    }
+
+    let (hours, minutes, seconds) = time?;
+    let (day, month, year) = (day?, month?, year?);
+
+    let year = match year {
+        0..=69 => year + 2000,
+        70..=99 => year + 1900,
+        year => year,
+    };
+
+    if !(1..=31).contains(&day) || year < 1970 || hours > 23 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+
+    let days = days_from_civil(year as _, month, day) as u64;
+
+    Some(days * 86400 + hours as u64 * 3600 + minutes as u64 * 60 + seconds as u64)
+}
+
+fn uptime() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as _)
+}
+
+/// The seconds since the UNIX epoch, `None` if the system time has not been set
+fn system_time() -> Option<u64> {
+    let mut tv: timeval = Default::default();
+
+    unsafe { gettimeofday(&mut tv, core::ptr::null_mut()) };
+
+    (tv.tv_sec as u64 > TIME_SET_AFTER).then(|| tv.tv_sec as _)
+}
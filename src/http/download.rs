@@ -0,0 +1,414 @@
+//! Resumable downloads
+//!
+//! A `Download` fetches a resource with `Range` requests, so that an interrupted transfer
+//! resumes from where it stopped rather than from the start, e.g. for pulling multi-megabyte
+//! assets over a flaky Wi-Fi link. The transfers interrupted while running are retried right
+//! away; the state of a download can also be saved (e.g. in the NVS) for it to be resumed
+//! after a reboot:
+//!
+//! ```ignore
+//! let mut download = match saved_state {
+//!     Some(state) => Download::resume("https://example.com/asset.bin", &conf, state),
+//!     None => Download::new("https://example.com/asset.bin", &conf),
+//! };
+//!
+//! let result = download.run(&mut connection, |offset, data| {
+//!     file.seek(SeekFrom::Start(offset))?;
+//!     file.write_all(data)?;
+//!     Ok(())
+//! });
+//!
+//! match result {
+//!     Ok(()) => info!("Downloaded {:?} bytes", download.state().total),
+//!     Err(DownloadError::Changed) => file.set_len(0)?,
+//!     Err(_) => save(download.state()),
+//! }
+//! ```
+//!
+//! Each resumed transfer checks the `Content-Range` of the response against the offset it asked
+//! for, and its `ETag` and total length against those of the start of the download; the
+//! resource having changed in between fails the download with `DownloadError::Changed`, as the
+//! bytes already downloaded belong to its former version.
+use core::fmt::Write as _;
+use core::time::Duration;
+
+use std::string::String;
+use std::thread;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+
+use esp_idf_sys::*;
+
+use super::client::EspHttpConnection;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// Requests ranges of at most that many bytes, each with a request of its own, rather than
+    /// the rest of the resource at once
+    pub chunk_size: Option<u64>,
+    /// The retries of a transfer which failed without downloading anything
+    pub max_retries: u32,
+    pub retry_delay: Duration,
+    pub buffer_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            chunk_size: None,
+            max_retries: 5,
+            retry_delay: Duration::from_secs(2),
+            buffer_size: 1024,
+        }
+    }
+}
+
+/// The progress of a download, for it to be resumed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct DownloadState {
+    /// The bytes downloaded so far
+    pub offset: u64,
+    /// The length of the resource, once known
+    pub total: Option<u64>,
+    /// The `ETag` of the resource, if the server sent one
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub etag: Option<String>,
+}
+
+impl DownloadState {
+    pub fn is_complete(&self) -> bool {
+        self.total == Some(self.offset)
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DownloadError {
+    /// The resource changed since the download started, which is reset to start over
+    Changed,
+    /// The `Content-Range` of a response is missing, invalid, or not the range asked for
+    Range,
+    /// The server answered with an unexpected status
+    Status(u16),
+    /// The transfer ended before the end of the range
+    Interrupted,
+    /// The sink of the data failed
+    Sink(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] EspError),
+    Other(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] EspError),
+}
+
+impl DownloadError {
+    /// Whether the transfer can be retried from where it stopped
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Interrupted | Self::Other(_) => true,
+            Self::Status(status) => *status == 408 || *status == 429 || *status >= 500,
+            Self::Changed | Self::Range | Self::Sink(_) => false,
+        }
+    }
+}
+
+impl core::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Changed => write!(f, "The resource changed"),
+            Self::Range => write!(f, "Invalid content range"),
+            Self::Status(status) => write!(f, "Unexpected status {}", status),
+            Self::Interrupted => write!(f, "Transfer interrupted"),
+            Self::Sink(e) => write!(f, "Sink failed: {}", e),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DownloadError {}
+
+impl From<EspError> for DownloadError {
+    fn from(e: EspError) -> Self {
+        Self::Other(e)
+    }
+}
+
+pub struct Download {
+    url: String,
+    conf: Configuration,
+    state: DownloadState,
+}
+
+impl Download {
+    pub fn new(url: &str, conf: &Configuration) -> Self {
+        Self::resume(url, conf, Default::default())
+    }
+
+    /// Resumes the download of `url` from a saved state
+    pub fn resume(url: &str, conf: &Configuration, state: DownloadState) -> Self {
+        Self {
+            url: url.into(),
+            conf: conf.clone(),
+            state,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn state(&self) -> &DownloadState {
+        &self.state
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state.is_complete()
+    }
+
+    /// Downloads the rest of the resource, passing each of its pieces to `sink` with its offset
+    ///
+    /// The interrupted transfers are resumed as long as they make progress, and up to
+    /// `Configuration::max_retries` times in a row otherwise. On an error, the state is that of
+    /// the bytes passed to `sink`, ready to be saved and resumed from.
+    pub fn run(
+        &mut self,
+        connection: &mut EspHttpConnection,
+        mut sink: impl FnMut(u64, &[u8]) -> Result<(), EspError>,
+    ) -> Result<(), DownloadError> {
+        let mut buf = vec![0_u8; self.conf.buffer_size.max(1)];
+        let mut retries = 0;
+
+        while !self.state.is_complete() {
+            let offset = self.state.offset;
+
+            match self.fetch(connection, &mut buf, &mut sink) {
+                Ok(()) => retries = 0,
+                Err(DownloadError::Changed) => {
+                    warn!("{} changed, starting over", self.url);
+
+                    self.state = Default::default();
+
+                    return Err(DownloadError::Changed);
+                }
+                Err(e) if e.is_retryable() => {
+                    if self.state.offset > offset {
+                        retries = 0;
+                    }
+
+                    if retries >= self.conf.max_retries {
+                        return Err(e);
+                    }
+
+                    retries += 1;
+
+                    warn!(
+                        "Downloading {} failed at {}: {}, retrying",
+                        self.url, self.state.offset, e
+                    );
+
+                    thread::sleep(self.conf.retry_delay);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        info!("Downloaded {} ({} bytes)", self.url, self.state.offset);
+
+        Ok(())
+    }
+
+    /// Requests the rest of the resource, or its next chunk, and passes its bytes to `sink`
+    fn fetch(
+        &mut self,
+        connection: &mut EspHttpConnection,
+        buf: &mut [u8],
+        sink: &mut impl FnMut(u64, &[u8]) -> Result<(), EspError>,
+    ) -> Result<(), DownloadError> {
+        let offset = self.state.offset;
+
+        let mut range = format!("bytes={}-", offset);
+
+        if let Some(chunk_size) = self.conf.chunk_size.filter(|size| *size > 0) {
+            let mut end = offset + chunk_size - 1;
+
+            if let Some(total) = self.state.total {
+                end = end.min(total.saturating_sub(1));
+            }
+
+            let _ = write!(range, "{}", end);
+        }
+
+        let mut headers = vec![("Range", range.as_str())];
+
+        // Only the strong validators can be used for a range, and with one the server sends the
+        // whole of a changed resource rather than a range of it
+        if let Some(etag) = self.state.etag.as_deref() {
+            if !etag.starts_with("W/") {
+                headers.push(("If-Range", etag));
+            }
+        }
+
+        connection.initiate_request(Method::Get, &self.url, &headers)?;
+        connection.initiate_response()?;
+
+        let status = connection.status();
+
+        let etag = connection.header("ETag").map(String::from);
+
+        if let (Some(saved), Some(etag)) = (self.state.etag.as_ref(), etag.as_ref()) {
+            if saved != etag {
+                return Err(DownloadError::Changed);
+            }
+        }
+
+        let (mut skip, end) = match status {
+            206 => {
+                let (start, end, total) = connection
+                    .header("Content-Range")
+                    .and_then(parse_content_range)
+                    .ok_or(DownloadError::Range)?;
+
+                let (start, end) = match (start, end) {
+                    (Some(start), Some(end)) if start == offset && end >= start => (start, end),
+                    _ => return Err(DownloadError::Range),
+                };
+
+                self.update_total(total)?;
+
+                (0, Some(end - start + 1))
+            }
+            200 => {
+                let total = connection
+                    .header("Content-Length")
+                    .and_then(|len| len.parse().ok());
+
+                self.update_total(total)?;
+
+                if offset > 0 {
+                    warn!(
+                        "{} does not support ranges, skipping the first {} bytes",
+                        self.url, offset
+                    );
+                }
+
+                (offset, None)
+            }
+            416 => {
+                // The offset is already at the end of the resource
+                let total = connection
+                    .header("Content-Range")
+                    .and_then(parse_content_range)
+                    .and_then(|(_, _, total)| total)
+                    .or(self.state.total);
+
+                if total != Some(offset) {
+                    return Err(DownloadError::Range);
+                }
+
+                self.state.total = total;
+
+                return Ok(());
+            }
+            status => return Err(DownloadError::Status(status)),
+        };
+
+        if self.state.etag.is_none() {
+            self.state.etag = etag;
+        }
+
+        let mut received = 0_u64;
+
+        loop {
+            let len = connection.read(buf)?;
+            if len == 0 {
+                break;
+            }
+
+            let mut data = &buf[..len];
+
+            if skip > 0 {
+                let skipped = (skip as usize).min(data.len());
+
+                data = &data[skipped..];
+                skip -= skipped as u64;
+            }
+
+            if data.is_empty() {
+                continue;
+            }
+
+            sink(self.state.offset, data).map_err(DownloadError::Sink)?;
+
+            self.state.offset += data.len() as u64;
+            received += data.len() as u64;
+        }
+
+        if skip > 0 || end.map(|end| received < end).unwrap_or(false) {
+            return Err(DownloadError::Interrupted);
+        }
+
+        match self.state.total {
+            Some(total) if self.state.offset < total && end.is_none() => {
+                Err(DownloadError::Interrupted)
+            }
+            Some(_) => Ok(()),
+            None => {
+                // Without a length, the resource ends with a body shorter than asked for
+                let ended = end.is_none()
+                    || !self
+                        .conf
+                        .chunk_size
+                        .map(|chunk_size| received == chunk_size)
+                        .unwrap_or(false);
+
+                if ended {
+                    self.state.total = Some(self.state.offset);
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Records the total length of the resource, failing if it changed
+    fn update_total(&mut self, total: Option<u64>) -> Result<(), DownloadError> {
+        match (self.state.total, total) {
+            (Some(saved), Some(total)) if saved != total => Err(DownloadError::Changed),
+            (_, Some(total)) => {
+                self.state.total = Some(total);
+
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Parses a `Content-Range` header, e.g. `bytes 0-1023/4096`, `bytes 0-1023/*` or `bytes */4096`,
+/// into its first and last byte and its total length
+fn parse_content_range(header: &str) -> Option<(Option<u64>, Option<u64>, Option<u64>)> {
+    let range = header.trim().strip_prefix("bytes")?.trim_start();
+
+    let (range, total) = range.split_once('/')?;
+
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+
+    let (start, end) = match range.trim() {
+        "*" => (None, None),
+        range => {
+            let (start, end) = range.split_once('-')?;
+
+            (
+                Some(start.trim().parse().ok()?),
+                Some(end.trim().parse().ok()?),
+            )
+        }
+    };
+
+    Some((start, end, total))
+}
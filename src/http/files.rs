@@ -0,0 +1,437 @@
+//! HTTP file server
+//!
+//! Exposes a directory of a mounted VFS (e.g. an SD card or a SPIFFS/FAT partition) over HTTP:
+//!
+//! - `GET` downloads a file, or lists a directory (as HTML, or as JSON if requested with `Accept: application/json`)
+//! - `PUT` uploads a file, replacing any existing one
+//! - `DELETE` removes a file or an empty directory
+//! - `MKCOL` creates a directory
+//!
+//! The handlers are registered with a wildcard URI, so the server needs to be created with
+//! `Configuration::uri_match_wildcard` enabled.
+use std::fs;
+use std::io::{self, ErrorKind, Read as _, Write as _};
+use std::path::{Path, PathBuf};
+use std::string::String;
+
+use ::log::*;
+
+use embedded_svc::http::server::{HandlerResult, Request};
+use embedded_svc::http::{Headers, Method, Query};
+use embedded_svc::io::Write;
+
+use esp_idf_sys::*;
+
+use super::cache::{self, CachePolicy, ETag};
+use super::server::{EspHttpConnection, EspHttpServer};
+use super::uri;
+
+/// Size of the buffer used for streaming file contents
+const CHUNK_SIZE: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub struct Configuration {
+    /// Only serve `GET` requests
+    pub read_only: bool,
+    /// Allow listing directories
+    pub listing: bool,
+    /// Uploads larger than this are rejected with `413 Payload Too Large`
+    pub max_upload_size: Option<u64>,
+    /// The `Cache-Control` policy of the downloaded files, which are revalidated with
+    /// an `ETag` derived from their size and modification time
+    pub cache_policy: CachePolicy,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            listing: true,
+            max_upload_size: None,
+            cache_policy: CachePolicy::NoCache,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FileServer {
+    prefix: String,
+    root: PathBuf,
+    conf: Configuration,
+}
+
+/// Registers the file server handlers for all URIs below `prefix` (e.g. `/files`), serving the
+/// contents of the `root` directory (e.g. `/sdcard`)
+pub fn register(
+    server: &mut EspHttpServer,
+    prefix: &str,
+    root: impl Into<PathBuf>,
+    conf: &Configuration,
+) -> Result<(), EspError> {
+    let prefix = prefix.trim_end_matches('/');
+    let uri = format!("{}/*", prefix);
+
+    let file_server = FileServer {
+        prefix: prefix.into(),
+        root: root.into(),
+        conf: conf.clone(),
+    };
+
+    let mut methods = vec![Method::Get];
+
+    if !conf.read_only {
+        methods.extend_from_slice(&[Method::Put, Method::Delete, Method::MkCol]);
+    }
+
+    for method in methods {
+        let file_server = file_server.clone();
+
+        server.fn_handler(&uri, method, move |request| file_server.handle(request))?;
+    }
+
+    info!(
+        "Serving files from {:?} on {}",
+        file_server.root, file_server.prefix
+    );
+
+    Ok(())
+}
+
+impl FileServer {
+    fn handle(&self, mut request: Request<&mut EspHttpConnection>) -> HandlerResult {
+        let method = request.method();
+
+        let path = match self.resolve(request.connection().path()) {
+            Some(path) => path,
+            None => return Self::status(request, 400, "Invalid path"),
+        };
+
+        let result = match method {
+            Method::Get => return self.get(request, &path),
+            Method::Put => return self.put(request, &path),
+            Method::Delete => Self::delete(&path).map(|_| (204, "")),
+            Method::MkCol => fs::create_dir(&path).map(|_| (201, "")),
+            _ => Ok((405, "Method not allowed")),
+        };
+
+        match result {
+            Ok((status, message)) => Self::status(request, status, message),
+            Err(e) => Self::error(request, e),
+        }
+    }
+
+    /// Maps the request path to a path below the root directory,
+    /// refusing anything that would escape it
+    fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let relative = request_path.strip_prefix(self.prefix.as_str())?;
+        let relative = uri::decode(relative)?;
+
+        let mut path = self.root.clone();
+
+        for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+            if segment == "." || segment == ".." || segment.contains('\\') {
+                return None;
+            }
+
+            path.push(segment);
+        }
+
+        Some(path)
+    }
+
+    fn get(&self, request: Request<&mut EspHttpConnection>, path: &Path) -> HandlerResult {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => return Self::error(request, e),
+        };
+
+        if metadata.is_dir() {
+            if self.conf.listing {
+                self.list(request, path)
+            } else {
+                Self::status(request, 403, "Listing is disabled")
+            }
+        } else {
+            let etag = Self::etag(&metadata);
+
+            if etag.is_fresh(&request) {
+                return cache::not_modified(request, &etag, self.conf.cache_policy);
+            }
+
+            let mut file = match fs::File::open(path) {
+                Ok(file) => file,
+                Err(e) => return Self::error(request, e),
+            };
+
+            let len = metadata.len().to_string();
+            let cache_policy = self.conf.cache_policy.to_string();
+
+            let mut response = request.into_response(
+                200,
+                None,
+                &[
+                    ("Content-Type", Self::content_type(path)),
+                    ("Content-Length", len.as_str()),
+                    ("ETag", etag.as_str()),
+                    ("Cache-Control", cache_policy.as_str()),
+                ],
+            )?;
+
+            let mut buf = [0_u8; CHUNK_SIZE];
+
+            loop {
+                let read = file.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+
+                response.write_all(&buf[..read])?;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// A weak tag, as files with the same size and modification time are only likely identical
+    fn etag(metadata: &fs::Metadata) -> ETag {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs())
+            .unwrap_or(0);
+
+        ETag::weak(&format!("{:x}-{:x}", metadata.len(), modified))
+    }
+
+    fn list(&self, mut request: Request<&mut EspHttpConnection>, path: &Path) -> HandlerResult {
+        let mut entries = Vec::new();
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+
+            entries.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                metadata.is_dir(),
+                metadata.len(),
+            ));
+        }
+
+        entries.sort();
+
+        let json = request
+            .header("Accept")
+            .map(|accept| accept.contains("application/json"))
+            .unwrap_or(false);
+
+        let mut body = String::new();
+
+        if json {
+            body.push('[');
+
+            for (index, (name, dir, size)) in entries.iter().enumerate() {
+                if index > 0 {
+                    body.push(',');
+                }
+
+                body.push_str(&format!(
+                    "{{\"name\":\"{}\",\"dir\":{},\"size\":{}}}",
+                    Self::escape_json(name),
+                    dir,
+                    size
+                ));
+            }
+
+            body.push(']');
+        } else {
+            let base = request.connection().path().trim_end_matches('/').to_owned();
+
+            body.push_str("<!DOCTYPE html><html><body><ul>");
+
+            for (name, dir, size) in &entries {
+                let href = uri::encode(name);
+                let name = Self::escape_html(name);
+
+                if *dir {
+                    body.push_str(&format!(
+                        "<li><a href=\"{}/{}/\">{}/</a></li>",
+                        base, href, name
+                    ));
+                } else {
+                    body.push_str(&format!(
+                        "<li><a href=\"{}/{}\">{}</a> ({} bytes)</li>",
+                        base, href, name, size
+                    ));
+                }
+            }
+
+            body.push_str("</ul></body></html>");
+        }
+
+        let content_type = if json {
+            "application/json"
+        } else {
+            "text/html"
+        };
+
+        request
+            .into_response(200, None, &[("Content-Type", content_type)])?
+            .write_all(body.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn put(&self, mut request: Request<&mut EspHttpConnection>, path: &Path) -> HandlerResult {
+        if path.is_dir() {
+            return Self::status(request, 409, "Path is a directory");
+        }
+
+        // Write to a temporary file first, so that an interrupted upload does not clobber the existing file
+        let mut part = path.as_os_str().to_owned();
+        part.push(".part");
+        let part = PathBuf::from(part);
+
+        let mut file = match fs::File::create(&part) {
+            Ok(file) => file,
+            Err(e) => return Self::error(request, e),
+        };
+
+        let mut buf = [0_u8; CHUNK_SIZE];
+        let mut size = 0_u64;
+
+        loop {
+            let read = match request.connection().read(&mut buf) {
+                Ok(read) => read,
+                Err(e) => {
+                    let _ = fs::remove_file(&part);
+                    return Err(e.into());
+                }
+            };
+
+            if read == 0 {
+                break;
+            }
+
+            size += read as u64;
+
+            if self
+                .conf
+                .max_upload_size
+                .map(|max| size > max)
+                .unwrap_or(false)
+            {
+                drop(file);
+                let _ = fs::remove_file(&part);
+
+                return Self::status(request, 413, "Payload too large");
+            }
+
+            if let Err(e) = file.write_all(&buf[..read]) {
+                drop(file);
+                let _ = fs::remove_file(&part);
+
+                return Self::error(request, e);
+            }
+        }
+
+        drop(file);
+
+        let existed = path.exists();
+
+        // FAT does not support renaming over an existing file
+        if existed {
+            fs::remove_file(path)?;
+        }
+
+        fs::rename(&part, path)?;
+
+        info!("Uploaded {:?} ({} bytes)", path, size);
+
+        if existed {
+            Self::status(request, 204, "")
+        } else {
+            Self::status(request, 201, "")
+        }
+    }
+
+    fn delete(path: &Path) -> io::Result<()> {
+        if fs::metadata(path)?.is_dir() {
+            fs::remove_dir(path)
+        } else {
+            fs::remove_file(path)
+        }
+    }
+
+    fn status(
+        request: Request<&mut EspHttpConnection>,
+        status: u16,
+        message: &str,
+    ) -> HandlerResult {
+        request
+            .into_status_response(status)?
+            .write_all(message.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn error(request: Request<&mut EspHttpConnection>, error: io::Error) -> HandlerResult {
+        let (status, message) = match error.kind() {
+            ErrorKind::NotFound => (404, "Not found"),
+            ErrorKind::AlreadyExists => (409, "Already exists"),
+            ErrorKind::PermissionDenied => (403, "Forbidden"),
+            _ => {
+                warn!("File server error: {}", error);
+
+                (500, "Internal error")
+            }
+        };
+
+        Self::status(request, status, message)
+    }
+
+    fn content_type(path: &Path) -> &'static str {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("");
+
+        match extension.to_ascii_lowercase().as_str() {
+            "html" | "htm" => "text/html",
+            "css" => "text/css",
+            "js" => "application/javascript",
+            "json" => "application/json",
+            "txt" | "log" => "text/plain",
+            "csv" => "text/csv",
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "svg" => "image/svg+xml",
+            "ico" => "image/x-icon",
+            "gz" => "application/gzip",
+            _ => "application/octet-stream",
+        }
+    }
+
+    fn escape_html(s: &str) -> String {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+
+    fn escape_json(s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+
+        out
+    }
+}
@@ -0,0 +1,174 @@
+//! gRPC client
+//!
+//! A minimal client for unary gRPC calls on top of the HTTP/2 client.
+//!
+//! Messages are passed as already encoded protobuf bytes; with the `prost` feature enabled,
+//! `EspGrpcClient::call` encodes and decodes `prost` messages directly.
+//!
+//! Note that the underlying `sh2lib` component does not report response trailers,
+//! so the `grpc-status` of a call cannot be inspected: a call is considered successful
+//! if the server completes the stream with a well-formed response message.
+use core::fmt::Write;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+
+use esp_idf_hal::delay::FreeRtos;
+
+use esp_idf_sys::*;
+
+use super::http2::{Configuration, EspHttp2Connection, StreamStatus};
+
+/// Length of the prefix (compression flag and message length) of each gRPC message
+const PREFIX_LEN: usize = 5;
+
+pub struct EspGrpcClient {
+    connection: EspHttp2Connection,
+    default_deadline: Option<Duration>,
+}
+
+impl EspGrpcClient {
+    /// Connects to the gRPC server at the given `https://` URI
+    pub fn new(uri: &str, conf: &Configuration) -> Result<Self, EspError> {
+        Ok(Self {
+            connection: EspHttp2Connection::new(uri, conf)?,
+            default_deadline: None,
+        })
+    }
+
+    /// Sets the deadline used by calls which do not specify their own
+    pub fn set_default_deadline(&mut self, deadline: Option<Duration>) {
+        self.default_deadline = deadline;
+    }
+
+    /// Performs a unary call of the given method (e.g. `/helloworld.Greeter/SayHello`)
+    ///
+    /// `metadata` is sent as custom request headers, so the keys need to be lowercase.
+    /// If the deadline is exceeded, the call is cancelled and fails with `ESP_ERR_TIMEOUT`.
+    pub fn unary(
+        &mut self,
+        method: &str,
+        request: &[u8],
+        metadata: &[(&str, &str)],
+        deadline: Option<Duration>,
+    ) -> Result<Vec<u8>, EspError> {
+        if metadata.iter().any(|(key, _)| !Self::is_valid_key(key)) {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let deadline = deadline.or(self.default_deadline);
+
+        let mut timeout = String::new();
+        if let Some(deadline) = deadline {
+            // The grpc-timeout value is limited to 8 digits
+            write!(&mut timeout, "{}m", deadline.as_millis().min(99_999_999)).unwrap();
+        }
+
+        let mut headers = vec![("content-type", "application/grpc"), ("te", "trailers")];
+
+        if deadline.is_some() {
+            headers.push(("grpc-timeout", timeout.as_str()));
+        }
+
+        headers.extend_from_slice(metadata);
+
+        let mut body = Vec::with_capacity(PREFIX_LEN + request.len());
+        body.push(0);
+        body.extend_from_slice(&(request.len() as u32).to_be_bytes());
+        body.extend_from_slice(request);
+
+        let expires = deadline.map(|deadline| Self::now() + deadline);
+
+        let id = self
+            .connection
+            .submit(Method::Post, method, &headers, Some(body))?;
+
+        loop {
+            if let Err(e) = self.connection.execute() {
+                self.connection.cancel(id);
+
+                return Err(e);
+            }
+
+            if self.connection.status(id) != Some(StreamStatus::Pending) {
+                break;
+            }
+
+            if expires
+                .map(|expires| Self::now() > expires)
+                .unwrap_or(false)
+            {
+                warn!("gRPC call {} exceeded its deadline", method);
+
+                self.connection.cancel(id);
+
+                return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+            }
+
+            FreeRtos::delay_ms(10);
+        }
+
+        let response = self.connection.take_response(id)?;
+
+        Self::unframe(response)
+    }
+
+    /// Performs a unary call with `prost` request and response messages
+    #[cfg(feature = "prost")]
+    pub fn call<Q, R>(
+        &mut self,
+        method: &str,
+        request: &Q,
+        metadata: &[(&str, &str)],
+        deadline: Option<Duration>,
+    ) -> Result<R, EspError>
+    where
+        Q: prost::Message,
+        R: prost::Message + Default,
+    {
+        let response = self.unary(method, &request.encode_to_vec(), metadata, deadline)?;
+
+        R::decode(response.as_slice()).map_err(|_| EspError::from_infallible::<ESP_FAIL>())
+    }
+
+    fn unframe(mut response: Vec<u8>) -> Result<Vec<u8>, EspError> {
+        if response.len() < PREFIX_LEN {
+            // Typically a Trailers-Only response, i.e. the call failed
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+
+        if response[0] != 0 {
+            // No grpc-accept-encoding is sent, so the server should never compress
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>());
+        }
+
+        let len = u32::from_be_bytes([response[1], response[2], response[3], response[4]]) as usize;
+
+        if response.len() < PREFIX_LEN + len {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        response.truncate(PREFIX_LEN + len);
+        response.drain(..PREFIX_LEN);
+
+        Ok(response)
+    }
+
+    fn is_valid_key(key: &str) -> bool {
+        !key.is_empty()
+            && !key.starts_with("grpc-")
+            && key.bytes().all(|b| {
+                b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'-' | b'_' | b'.')
+            })
+    }
+
+    fn now() -> Duration {
+        Duration::from_micros(unsafe { esp_timer_get_time() as _ })
+    }
+}
@@ -0,0 +1,375 @@
+//! HTTP/2 client
+//!
+//! A thin wrapper around the `sh2lib` component (which is itself built on top of `nghttp2`),
+//! allowing several requests to be multiplexed over a single TLS connection.
+//!
+//! `sh2lib` is not part of ESP-IDF proper; it has to be added to the build as an extra
+//! component (e.g. from the ESP Component Registry, or from the `http2_request` example of ESP-IDF),
+//! together with a bindings header including `sh2lib.h`.
+//!
+//! Note that `sh2lib` does not report the response headers and discards pushed streams,
+//! so responses are exposed as bodies only and server push is not supported.
+use core::ffi::{c_char, c_int, CStr};
+use core::{mem, ptr, slice};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+
+use esp_idf_hal::delay::FreeRtos;
+
+use esp_idf_sys::*;
+
+use crate::private::cstr::*;
+use crate::tls::X509;
+
+/// Maximum number of streams open at the same time on a single connection
+pub const MAX_STREAMS: usize = 8;
+
+/// `sh2lib` flags passed to the receive callback
+const DATA_RECV_RST_STREAM: c_int = 1;
+const DATA_RECV_FRAME_COMPLETE: c_int = 2;
+
+/// `nghttp2` flag marking the end of the request body
+const DATA_FLAG_EOF: u32 = 0x01;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Configuration {
+    pub ca_cert: Option<X509<'static>>,
+    pub crt_bundle_attach: Option<unsafe extern "C" fn(conf: *mut core::ffi::c_void) -> esp_err_t>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct StreamId(usize);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum StreamStatus {
+    /// The response has not been fully received yet
+    Pending,
+    /// The response has been fully received
+    Complete,
+    /// The stream has been reset by the server
+    Reset,
+}
+
+#[derive(Debug)]
+struct Stream {
+    status: StreamStatus,
+    body: Vec<u8>,
+    sent: usize,
+    response: Vec<u8>,
+    /// The stream has been cancelled by the user, but its slot cannot be reused
+    /// until the server is done with it
+    cancelled: bool,
+}
+
+/// `sh2lib` passes only its own handle to the data callbacks, so the handle is the first field
+/// of this structure (which allows getting hold of the streams from the handle) and each stream
+/// slot has its own callbacks (which allows telling the streams apart)
+#[repr(C)]
+struct Connection {
+    handle: sh2lib_handle,
+    streams: [Option<Stream>; MAX_STREAMS],
+}
+
+impl Connection {
+    unsafe fn slot<'a>(handle: *mut sh2lib_handle, index: usize) -> &'a mut Option<Stream> {
+        &mut (*(handle as *mut Connection)).streams[index]
+    }
+
+    unsafe fn stream<'a>(handle: *mut sh2lib_handle, index: usize) -> Option<&'a mut Stream> {
+        Self::slot(handle, index).as_mut()
+    }
+}
+
+unsafe extern "C" fn on_data<const N: usize>(
+    handle: *mut sh2lib_handle,
+    data: *const c_char,
+    len: usize,
+    flags: c_int,
+) -> c_int {
+    if let Some(stream) = Connection::stream(handle, N) {
+        if len > 0 {
+            stream
+                .response
+                .extend_from_slice(slice::from_raw_parts(data as *const u8, len));
+        }
+
+        if flags == DATA_RECV_FRAME_COMPLETE {
+            stream.status = StreamStatus::Complete;
+        } else if flags == DATA_RECV_RST_STREAM {
+            stream.status = StreamStatus::Reset;
+        }
+
+        if stream.cancelled && stream.status != StreamStatus::Pending {
+            *Connection::slot(handle, N) = None;
+        }
+    }
+
+    0
+}
+
+unsafe extern "C" fn on_send<const N: usize>(
+    handle: *mut sh2lib_handle,
+    data: *mut c_char,
+    len: usize,
+    data_flags: *mut u32,
+) -> c_int {
+    if let Some(stream) = Connection::stream(handle, N) {
+        let remaining = &stream.body[stream.sent..];
+        let chunk = remaining.len().min(len);
+
+        ptr::copy_nonoverlapping(remaining.as_ptr(), data as *mut u8, chunk);
+        stream.sent += chunk;
+
+        if stream.sent == stream.body.len() {
+            *data_flags |= DATA_FLAG_EOF;
+        }
+
+        chunk as _
+    } else {
+        *data_flags |= DATA_FLAG_EOF;
+
+        0
+    }
+}
+
+const RECV_CALLBACKS: [sh2lib_frame_data_recv_cb_t; MAX_STREAMS] = [
+    Some(on_data::<0>),
+    Some(on_data::<1>),
+    Some(on_data::<2>),
+    Some(on_data::<3>),
+    Some(on_data::<4>),
+    Some(on_data::<5>),
+    Some(on_data::<6>),
+    Some(on_data::<7>),
+];
+
+const SEND_CALLBACKS: [sh2lib_putpost_data_cb_t; MAX_STREAMS] = [
+    Some(on_send::<0>),
+    Some(on_send::<1>),
+    Some(on_send::<2>),
+    Some(on_send::<3>),
+    Some(on_send::<4>),
+    Some(on_send::<5>),
+    Some(on_send::<6>),
+    Some(on_send::<7>),
+];
+
+fn method_name(method: Method) -> Option<&'static str> {
+    Some(match method {
+        Method::Get => "GET",
+        Method::Post => "POST",
+        Method::Put => "PUT",
+        Method::Delete => "DELETE",
+        Method::Head => "HEAD",
+        Method::Options => "OPTIONS",
+        Method::Patch => "PATCH",
+        _ => return None,
+    })
+}
+
+fn nv(name: &str, value: &str) -> nghttp2_nv {
+    nghttp2_nv {
+        name: name.as_ptr() as *mut _,
+        value: value.as_ptr() as *mut _,
+        namelen: name.len() as _,
+        valuelen: value.len() as _,
+        flags: 0,
+    }
+}
+
+pub struct EspHttp2Connection(Box<Connection>);
+
+impl EspHttp2Connection {
+    /// Connects to the server at the given `https://` URI
+    pub fn new(uri: &str, conf: &Configuration) -> Result<Self, EspError> {
+        let uri = CString::new(uri).unwrap();
+
+        let mut cfg = sh2lib_config_t {
+            uri: uri.as_ptr(),
+            crt_bundle_attach: conf.crt_bundle_attach,
+            ..Default::default()
+        };
+
+        if let Some(ca_cert) = conf.ca_cert {
+            cfg.cacert_buf = ca_cert.data().as_ptr();
+            cfg.cacert_bytes = ca_cert.data().len() as _;
+        }
+
+        let mut connection = Box::new(Connection {
+            handle: unsafe { mem::zeroed() },
+            streams: Default::default(),
+        });
+
+        if unsafe { sh2lib_connect(&mut cfg, &mut connection.handle) } != 0 {
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+
+        info!("Connected to {:?}", uri);
+
+        Ok(Self(connection))
+    }
+
+    /// Submits a request on a new stream; the request is sent and its response received by
+    /// subsequent calls to `execute`
+    pub fn submit(
+        &mut self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<Vec<u8>>,
+    ) -> Result<StreamId, EspError> {
+        let method =
+            method_name(method).ok_or_else(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>)?;
+
+        let index = self
+            .0
+            .streams
+            .iter()
+            .position(Option::is_none)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_NO_MEM>)?;
+
+        let authority = unsafe { CStr::from_ptr(self.0.handle.hostname) }
+            .to_str()
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+        let mut nva = vec![
+            nv(":method", method),
+            nv(":scheme", "https"),
+            nv(":path", path),
+            nv(":authority", authority),
+        ];
+
+        nva.extend(headers.iter().map(|(name, value)| nv(name, value)));
+
+        let has_body = body.is_some();
+
+        self.0.streams[index] = Some(Stream {
+            status: StreamStatus::Pending,
+            body: body.unwrap_or_default(),
+            sent: 0,
+            response: Vec::new(),
+            cancelled: false,
+        });
+
+        // nghttp2 copies the header names and values, so these need not outlive the call
+        let result = unsafe {
+            if has_body {
+                sh2lib_do_putpost_with_nv(
+                    &mut self.0.handle,
+                    nva.as_ptr(),
+                    nva.len() as _,
+                    SEND_CALLBACKS[index],
+                    RECV_CALLBACKS[index],
+                )
+            } else {
+                sh2lib_do_get_with_nv(
+                    &mut self.0.handle,
+                    nva.as_ptr(),
+                    nva.len() as _,
+                    RECV_CALLBACKS[index],
+                )
+            }
+        };
+
+        if result < 0 {
+            self.0.streams[index] = None;
+
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        } else {
+            Ok(StreamId(index))
+        }
+    }
+
+    /// Sends pending frames and processes the frames received so far
+    pub fn execute(&mut self) -> Result<(), EspError> {
+        if unsafe { sh2lib_execute(&mut self.0.handle) } != 0 {
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn status(&self, id: StreamId) -> Option<StreamStatus> {
+        self.0.streams[id.0]
+            .as_ref()
+            .filter(|stream| !stream.cancelled)
+            .map(|stream| stream.status)
+    }
+
+    /// Stops tracking a stream; whatever the server still sends on it is discarded
+    pub fn cancel(&mut self, id: StreamId) {
+        if let Some(stream) = self.0.streams[id.0].as_mut() {
+            if stream.status == StreamStatus::Pending {
+                stream.cancelled = true;
+                stream.response = Vec::new();
+            } else {
+                self.0.streams[id.0] = None;
+            }
+        }
+    }
+
+    /// Returns the response body of a completed stream and releases the stream
+    pub fn take_response(&mut self, id: StreamId) -> Result<Vec<u8>, EspError> {
+        match self.status(id) {
+            Some(StreamStatus::Complete) => Ok(self.0.streams[id.0].take().unwrap().response),
+            Some(StreamStatus::Reset) => {
+                self.0.streams[id.0] = None;
+
+                Err(EspError::from_infallible::<ESP_FAIL>())
+            }
+            Some(StreamStatus::Pending) => {
+                Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+            }
+            None => Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>()),
+        }
+    }
+
+    /// Submits a request and drives the connection until its response is complete
+    pub fn request(
+        &mut self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, EspError> {
+        let id = self.submit(method, path, headers, body)?;
+
+        loop {
+            if let Err(e) = self.execute() {
+                self.cancel(id);
+
+                return Err(e);
+            }
+
+            if self.status(id) != Some(StreamStatus::Pending) {
+                break;
+            }
+
+            FreeRtos::delay_ms(10);
+        }
+
+        self.take_response(id)
+    }
+}
+
+impl Drop for EspHttp2Connection {
+    fn drop(&mut self) {
+        unsafe {
+            sh2lib_free(&mut self.0.handle);
+        }
+
+        info!("Dropped");
+    }
+}
+
+unsafe impl Send for EspHttp2Connection {}
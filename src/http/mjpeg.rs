@@ -0,0 +1,208 @@
+//! MJPEG over HTTP
+//!
+//! Streams JPEG frames as a `multipart/x-mixed-replace` response, which browsers display as a
+//! live video in an `<img>` element; the usual way to view the output of an ESP32-CAM.
+//!
+//! The frames come from a user-provided source callback, which captures a frame and passes its
+//! JPEG data to the closure it is given, returning `false` if no frame is available:
+//!
+//! ```ignore
+//! let source = |frame: &mut dyn FnMut(&[u8])| {
+//!     let fb = unsafe { esp_camera_fb_get() };
+//!     if fb.is_null() {
+//!         return false;
+//!     }
+//!
+//!     frame(unsafe { core::slice::from_raw_parts((*fb).buf, (*fb).len) });
+//!
+//!     unsafe { esp_camera_fb_return(fb) };
+//!
+//!     true
+//! };
+//!
+//! let source = Arc::new(source);
+//!
+//! server.fn_handler("/stream", Method::Get, mjpeg::handler(&Default::default(), source.clone()))?;
+//! server.fn_handler("/snapshot", Method::Get, mjpeg::snapshot_handler(source))?;
+//! ```
+//!
+//! Each client can ask for its own frame rate with the `fps` query parameter (e.g.
+//! `/stream?fps=5`), capped to `Configuration::max_fps`.
+//!
+//! Note that the HTTP server handles one request at a time, so it is blocked while a stream
+//! is being served; consider a dedicated `EspHttpServer` instance for the stream.
+use core::cmp;
+use core::time::Duration;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use ::log::*;
+
+use embedded_svc::http::server::{HandlerResult, Request};
+use embedded_svc::io::Write;
+
+use super::server::EspHttpConnection;
+
+const BOUNDARY: &str = "esp-mjpeg-frame";
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The frame rate of clients not asking for one
+    pub fps: u32,
+    pub max_fps: u32,
+    /// How long to wait before asking the source again when it has no frame available
+    pub retry_interval: Duration,
+    /// End the stream after this long, letting the HTTP server handle other requests
+    pub max_duration: Option<Duration>,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            fps: 10,
+            max_fps: 30,
+            retry_interval: Duration::from_millis(10),
+            max_duration: None,
+        }
+    }
+}
+
+/// An `EspHttpServer` handler streaming the frames of `source`, until the client disconnects
+pub fn handler<S>(
+    conf: &Configuration,
+    source: Arc<S>,
+) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static
+where
+    S: Fn(&mut dyn FnMut(&[u8])) -> bool + Send + Sync + 'static,
+{
+    let conf = conf.clone();
+
+    move |request| {
+        let fps = request
+            .connection()
+            .query_param("fps")
+            .and_then(|fps| fps.parse::<u32>().ok())
+            .unwrap_or(conf.fps)
+            .clamp(1, cmp::max(conf.max_fps, 1));
+
+        let interval = Duration::from_secs(1) / fps;
+
+        let content_type = format!("multipart/x-mixed-replace;boundary={}", BOUNDARY);
+
+        let mut response = request.into_response(
+            200,
+            None,
+            &[
+                ("Content-Type", content_type.as_str()),
+                ("Cache-Control", "no-store"),
+                ("Pragma", "no-cache"),
+            ],
+        )?;
+
+        info!("MJPEG stream started at {} fps", fps);
+
+        let started = Instant::now();
+        let mut frames = 0_u32;
+
+        loop {
+            if conf
+                .max_duration
+                .map(|max_duration| started.elapsed() >= max_duration)
+                .unwrap_or(false)
+            {
+                break;
+            }
+
+            let frame_started = Instant::now();
+
+            let mut result = Ok(());
+
+            let captured = source(&mut |frame| {
+                result = write_part(&mut response, frame);
+            });
+
+            if !captured {
+                thread::sleep(conf.retry_interval);
+                continue;
+            }
+
+            if result.is_err() {
+                // The client is gone
+                break;
+            }
+
+            frames += 1;
+
+            if let Some(remaining) = interval.checked_sub(frame_started.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+
+        info!(
+            "MJPEG stream ended after {} frames in {:?}",
+            frames,
+            started.elapsed()
+        );
+
+        Ok(())
+    }
+}
+
+/// An `EspHttpServer` handler responding with a single frame of `source`, as `image/jpeg`
+pub fn snapshot_handler<S>(
+    source: Arc<S>,
+) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static
+where
+    S: Fn(&mut dyn FnMut(&[u8])) -> bool + Send + Sync + 'static,
+{
+    move |request| {
+        let mut request = Some(request);
+        let mut result = Ok(());
+
+        let captured = source(&mut |frame| {
+            if let Some(request) = request.take() {
+                let len = frame.len().to_string();
+
+                result = request
+                    .into_response(
+                        200,
+                        None,
+                        &[
+                            ("Content-Type", "image/jpeg"),
+                            ("Content-Length", len.as_str()),
+                            ("Cache-Control", "no-store"),
+                        ],
+                    )
+                    .and_then(|mut response| response.write_all(frame));
+            }
+        });
+
+        if !captured {
+            if let Some(request) = request {
+                request
+                    .into_response(503, Some("No frame available"), &[])?
+                    .write_all(b"No frame available")?;
+            }
+        }
+
+        result?;
+
+        Ok(())
+    }
+}
+
+fn write_part<W: Write>(response: &mut W, frame: &[u8]) -> Result<(), W::Error> {
+    let header = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        frame.len()
+    );
+
+    response.write_all(header.as_bytes())?;
+    response.write_all(frame)?;
+    response.write_all(b"\r\n")?;
+    response.flush()
+}
@@ -0,0 +1,378 @@
+//! Mock HTTP client connection
+//!
+//! An in-memory implementation of the HTTP client `Connection` trait, which allows unit-testing
+//! code written against that trait (e.g. on top of `embedded_svc::http::client::Client`)
+//! without a network: each request is answered with the next canned response, and all requests
+//! are recorded so that tests can make assertions on them.
+//!
+//! ```ignore
+//! let mut connection = MockHttpConnection::new();
+//! connection.expect(Method::Get, "http://example.com/status", MockResponse::new(200).body("ok"));
+//!
+//! let mut client = Client::wrap(&mut connection);
+//! // ... exercise the code under test with `client` ...
+//!
+//! connection.assert_done();
+//! assert_eq!(connection.requests()[0].header("Accept"), Some("text/plain"));
+//! ```
+//!
+//! Note: This module requires the `mock` cargo feature to be enabled.
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use embedded_svc::http::client::*;
+use embedded_svc::http::*;
+use embedded_svc::io::{Io, Read, Write};
+
+use esp_idf_sys::*;
+
+use crate::errors::EspIOError;
+
+/// A canned response
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MockResponse {
+    pub status: u16,
+    pub status_message: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockResponse {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            ..Default::default()
+        }
+    }
+
+    pub fn status_message(mut self, message: &str) -> Self {
+        self.status_message = Some(message.into());
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets the body, as well as the `Content-Length` header
+    pub fn body(mut self, body: impl AsRef<[u8]>) -> Self {
+        self.body = body.as_ref().to_vec();
+        self.headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Length"));
+        self.headers
+            .push(("Content-Length".into(), self.body.len().to_string()));
+        self
+    }
+}
+
+/// A request issued on the mock connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    /// Returns the value of the first header with the given (case-insensitive) name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+#[derive(Debug)]
+struct Expectation {
+    method: Option<Method>,
+    uri: Option<String>,
+    response: Result<MockResponse, EspError>,
+}
+
+#[derive(Debug)]
+enum State {
+    New,
+    Request(RecordedRequest),
+    Response(MockResponse, usize),
+}
+
+#[derive(Debug)]
+pub struct MockHttpConnection {
+    expectations: VecDeque<Expectation>,
+    requests: Vec<RecordedRequest>,
+    state: State,
+}
+
+impl MockHttpConnection {
+    pub fn new() -> Self {
+        Self {
+            expectations: VecDeque::new(),
+            requests: Vec::new(),
+            state: State::New,
+        }
+    }
+
+    /// Answers the next request with `response`, asserting that it is
+    /// a request with the given method and URI
+    pub fn expect(&mut self, method: Method, uri: &str, response: MockResponse) -> &mut Self {
+        self.expectations.push_back(Expectation {
+            method: Some(method),
+            uri: Some(uri.into()),
+            response: Ok(response),
+        });
+
+        self
+    }
+
+    /// Answers the next request, whatever it is, with `response`
+    pub fn respond(&mut self, response: MockResponse) -> &mut Self {
+        self.expectations.push_back(Expectation {
+            method: None,
+            uri: None,
+            response: Ok(response),
+        });
+
+        self
+    }
+
+    /// Fails the next request with `error` once its response is initiated,
+    /// simulating e.g. a connection or a timeout error
+    pub fn fail(&mut self, error: EspError) -> &mut Self {
+        self.expectations.push_back(Expectation {
+            method: None,
+            uri: None,
+            response: Err(error),
+        });
+
+        self
+    }
+
+    /// Returns all requests issued so far, including their bodies
+    pub fn requests(&self) -> &[RecordedRequest] {
+        &self.requests
+    }
+
+    /// Panics if some of the canned responses have not been consumed
+    pub fn assert_done(&self) {
+        assert!(
+            self.expectations.is_empty(),
+            "{} expected HTTP request(s) not issued: {:?}",
+            self.expectations.len(),
+            self.expectations
+        );
+    }
+
+    /// Same as `Connection::initiate_request`
+    pub fn initiate_request(
+        &mut self,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), EspError> {
+        if let Some(expectation) = self.expectations.front() {
+            if let Some(expected) = expectation.method {
+                assert_eq!(
+                    expected, method,
+                    "Unexpected method for HTTP request {}",
+                    uri
+                );
+            }
+
+            if let Some(expected) = &expectation.uri {
+                assert_eq!(expected, uri, "Unexpected HTTP request URI");
+            }
+        } else {
+            panic!("Unexpected HTTP request: {:?} {}", method, uri);
+        }
+
+        self.state = State::Request(RecordedRequest {
+            method,
+            uri: uri.into(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| ((*name).into(), (*value).into()))
+                .collect(),
+            body: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    pub fn is_request_initiated(&self) -> bool {
+        matches!(self.state, State::Request(_))
+    }
+
+    /// Same as `Connection::initiate_response`: completes the request
+    /// and switches to its canned response
+    pub fn initiate_response(&mut self) -> Result<(), EspError> {
+        let request = match core::mem::replace(&mut self.state, State::New) {
+            State::Request(request) => request,
+            state => {
+                self.state = state;
+
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+        };
+
+        self.requests.push(request);
+
+        let expectation = self.expectations.pop_front().unwrap();
+
+        self.state = State::Response(expectation.response?, 0);
+
+        Ok(())
+    }
+
+    pub fn is_response_initiated(&self) -> bool {
+        matches!(self.state, State::Response(..))
+    }
+
+    pub fn status(&self) -> u16 {
+        match &self.state {
+            State::Response(response, _) => response.status,
+            _ => 0,
+        }
+    }
+
+    pub fn status_message(&self) -> Option<&str> {
+        match &self.state {
+            State::Response(response, _) => response.status_message.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        match &self.state {
+            State::Response(response, _) => find_header(&response.headers, name),
+            _ => None,
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        match &mut self.state {
+            State::Response(response, offset) => {
+                let remaining = &response.body[*offset..];
+                let len = remaining.len().min(buf.len());
+
+                buf[..len].copy_from_slice(&remaining[..len]);
+                *offset += len;
+
+                Ok(len)
+            }
+            _ => Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()),
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
+        match &mut self.state {
+            State::Request(request) => {
+                request.body.extend_from_slice(buf);
+
+                Ok(buf.len())
+            }
+            _ => Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()),
+        }
+    }
+}
+
+impl Default for MockHttpConnection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Status for MockHttpConnection {
+    fn status(&self) -> u16 {
+        MockHttpConnection::status(self)
+    }
+
+    fn status_message(&self) -> Option<&str> {
+        MockHttpConnection::status_message(self)
+    }
+}
+
+impl Headers for MockHttpConnection {
+    fn header(&self, name: &str) -> Option<&str> {
+        MockHttpConnection::header(self, name)
+    }
+}
+
+impl Io for MockHttpConnection {
+    type Error = EspIOError;
+}
+
+impl Read for MockHttpConnection {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let size = MockHttpConnection::read(self, buf)?;
+
+        Ok(size)
+    }
+}
+
+impl Write for MockHttpConnection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let size = MockHttpConnection::write(self, buf)?;
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl Connection for MockHttpConnection {
+    type Headers = Self;
+
+    type Read = Self;
+
+    type RawConnectionError = EspIOError;
+
+    type RawConnection = Self;
+
+    fn initiate_request<'a>(
+        &'a mut self,
+        method: Method,
+        uri: &'a str,
+        headers: &'a [(&'a str, &'a str)],
+    ) -> Result<(), Self::Error> {
+        MockHttpConnection::initiate_request(self, method, uri, headers)?;
+
+        Ok(())
+    }
+
+    fn is_request_initiated(&self) -> bool {
+        MockHttpConnection::is_request_initiated(self)
+    }
+
+    fn initiate_response(&mut self) -> Result<(), Self::Error> {
+        MockHttpConnection::initiate_response(self)?;
+
+        Ok(())
+    }
+
+    fn is_response_initiated(&self) -> bool {
+        MockHttpConnection::is_response_initiated(self)
+    }
+
+    fn split(&mut self) -> (&Self::Headers, &mut Self::Read) {
+        let headers_ptr: *const MockHttpConnection = self as *const _;
+
+        let headers = unsafe { headers_ptr.as_ref().unwrap() };
+
+        (headers, self)
+    }
+
+    fn raw_connection(&mut self) -> Result<&mut Self::RawConnection, Self::Error> {
+        Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>().into())
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
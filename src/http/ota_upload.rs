@@ -0,0 +1,733 @@
+//! Firmware upload over HTTP
+//!
+//! An `EspHttpServer` handler receiving firmware updates pushed to the device, e.g. with
+//! `curl --data-binary @firmware.bin http://<device>/update`, or from an HTML upload form:
+//!
+//! - the body is either the raw application image, or a `multipart/form-data` form, of which
+//!   the first file is the image
+//! - the header of the image is checked before anything is written to flash: it has to be an
+//!   application image for this chip and, unless configured otherwise, for this project and
+//!   not older than the running firmware
+//! - the image is streamed to the next update slot, and checked against the SHA-256 of the
+//!   optional `X-Firmware-SHA256` header
+//! - the device restarts into the new firmware once the response is sent
+//!
+//! ```ignore
+//! server.fn_handler(
+//!     "/update",
+//!     Method::Post,
+//!     ota_upload::handler(Default::default(), Some(sysloop.clone())),
+//! )?;
+//! ```
+//!
+//! The progress is published as `OtaUploadEvent`s on the system event loop, when one is given,
+//! and is available with `upload_progress`.
+use core::cmp::Ordering;
+use core::ffi;
+use core::mem;
+use core::ptr;
+use core::time::Duration;
+
+use std::string::String;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::http::server::{HandlerResult, Request};
+use embedded_svc::http::Headers;
+use embedded_svc::io::Write;
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSystemEventLoop, EspTypedEventDeserializer,
+    EspTypedEventSerializer, EspTypedEventSource,
+};
+use crate::ota::{EspOta, EspOtaUpdate};
+use crate::private::hex;
+use crate::private::mutex::{Mutex, RawMutex};
+use crate::private::sha256::Sha256;
+
+use super::server::EspHttpConnection;
+
+const CHUNK_SIZE: usize = 1024;
+
+/// The first byte of an ESP image
+const IMAGE_MAGIC: u8 = 0xe9;
+
+/// The offset of the application description: after the image and first segment headers
+const APP_DESC_OFFSET: usize = 24 + 8;
+
+/// The multipart part headers longer than this are rejected
+const MAX_PART_HEADERS_LEN: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// Images larger than this are rejected; the size of the update slot by default
+    pub max_size: Option<u64>,
+    /// Accept images of other projects than the running one
+    pub allow_other_projects: bool,
+    /// Accept images with an older version than the running one
+    pub allow_downgrade: bool,
+    /// The delay between the response and the restart, or `None` not to restart
+    pub reboot_delay: Option<Duration>,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            allow_other_projects: false,
+            allow_downgrade: false,
+            reboot_delay: Some(Duration::from_secs(2)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum UploadFailure {
+    /// Another update is in progress
+    Busy,
+    TooLarge,
+    /// Not an application image, or not one for this chip
+    InvalidImage,
+    WrongProject,
+    Downgrade,
+    /// The body ended before the image, or the multipart form
+    Incomplete,
+    /// The image does not match `X-Firmware-SHA256`, or fails the verification of ESP-IDF
+    Verification,
+    Io,
+}
+
+impl UploadFailure {
+    fn status(&self) -> (u16, &'static str) {
+        match self {
+            Self::Busy => (409, "Another update is in progress"),
+            Self::TooLarge => (413, "Image too large"),
+            Self::InvalidImage => (400, "Not an application image for this chip"),
+            Self::WrongProject => (400, "Image of another project"),
+            Self::Downgrade => (400, "Image older than the running firmware"),
+            Self::Incomplete => (400, "Incomplete upload"),
+            Self::Verification => (400, "Image verification failed"),
+            Self::Io => (500, "Update failed"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum OtaUploadEvent {
+    Started {
+        total: Option<u32>,
+    },
+    Progress {
+        received: u32,
+        total: Option<u32>,
+    },
+    /// The image is written, and will be booted on the next restart
+    Completed {
+        size: u32,
+    },
+    Failed(UploadFailure),
+    /// The device is about to restart into the new firmware
+    Restarting,
+}
+
+impl EspTypedEventSource for OtaUploadEvent {
+    fn source() -> *const ffi::c_char {
+        b"ESP-OTA-UPLOAD\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<OtaUploadEvent> for OtaUploadEvent {
+    fn serialize<R>(
+        event: &OtaUploadEvent,
+        f: impl for<'a> FnOnce(&'a EspEventPostData) -> R,
+    ) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<OtaUploadEvent> for OtaUploadEvent {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a OtaUploadEvent) -> R,
+    ) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum UploadState {
+    Idle,
+    Receiving,
+    /// The image is written, and will be booted on the next restart
+    Complete,
+    Failed,
+}
+
+impl UploadState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Idle => "idle",
+            Self::Receiving => "receiving",
+            Self::Complete => "complete",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UploadProgress {
+    pub state: UploadState,
+    /// The bytes of the image received so far
+    pub received: u64,
+    /// The size of the image, when the upload is not a multipart form and has a
+    /// `Content-Length`
+    pub total: Option<u64>,
+}
+
+static PROGRESS: Mutex<UploadProgress> = Mutex::wrap(
+    RawMutex::new(),
+    UploadProgress {
+        state: UploadState::Idle,
+        received: 0,
+        total: None,
+    },
+);
+
+/// The progress of the last firmware upload
+pub fn upload_progress() -> UploadProgress {
+    *PROGRESS.lock()
+}
+
+/// An `EspHttpServer` handler for `POST` requests with a firmware image
+pub fn handler(
+    conf: Configuration,
+    sysloop: Option<EspSystemEventLoop>,
+) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static {
+    move |request| {
+        let mut upload = Upload {
+            conf: &conf,
+            sysloop: sysloop.as_ref(),
+            total: None,
+            received: 0,
+            reported: 0,
+        };
+
+        upload.handle(request)
+    }
+}
+
+struct Upload<'a> {
+    conf: &'a Configuration,
+    sysloop: Option<&'a EspSystemEventLoop>,
+    total: Option<u64>,
+    received: u64,
+    /// The received bytes when the last progress event was posted
+    reported: u64,
+}
+
+impl<'a> Upload<'a> {
+    fn handle(&mut self, mut request: Request<&mut EspHttpConnection>) -> HandlerResult {
+        let multipart = request.header("Content-Type").and_then(Multipart::new);
+
+        if multipart.is_none() {
+            self.total = request
+                .header("Content-Length")
+                .and_then(|len| len.parse::<u64>().ok());
+        }
+
+        let sha256 = request
+            .header("X-Firmware-SHA256")
+            .map(|sha256| sha256.trim().to_ascii_lowercase());
+
+        self.set_progress(UploadState::Receiving);
+        self.post(OtaUploadEvent::Started {
+            total: self.total.map(|total| total as u32),
+        });
+
+        let result = match EspOta::new() {
+            Ok(mut ota) => self.receive(&mut request, &mut ota, multipart, sha256.as_deref()),
+            Err(_) => Err(UploadFailure::Busy),
+        };
+
+        match result {
+            Ok(()) => {
+                info!("Uploaded a {} bytes image", self.received);
+
+                self.set_progress(UploadState::Complete);
+                self.post(OtaUploadEvent::Completed {
+                    size: self.received as u32,
+                });
+
+                let message = if self.conf.reboot_delay.is_some() {
+                    "Update complete, restarting"
+                } else {
+                    "Update complete, restart to apply"
+                };
+
+                request.into_ok_response()?.write_all(message.as_bytes())?;
+
+                if let Some(delay) = self.conf.reboot_delay {
+                    self.schedule_restart(delay);
+                }
+            }
+            Err(failure) => {
+                warn!("Firmware upload failed: {:?}", failure);
+
+                self.set_progress(UploadState::Failed);
+                self.post(OtaUploadEvent::Failed(failure));
+
+                let (status, message) = failure.status();
+
+                request
+                    .into_status_response(status)?
+                    .write_all(message.as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn receive(
+        &mut self,
+        request: &mut Request<&mut EspHttpConnection>,
+        ota: &mut EspOta,
+        mut multipart: Option<Multipart>,
+        sha256: Option<&str>,
+    ) -> Result<(), UploadFailure> {
+        let mut sink = Sink {
+            max_size: self.max_size(),
+            conf: self.conf,
+            header: Vec::new(),
+            started: false,
+            sha256: Sha256::new(),
+            written: 0,
+        };
+
+        let mut buf = [0_u8; CHUNK_SIZE];
+
+        let result = loop {
+            let read = match request.connection().read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(read) => read,
+                Err(_) => break Err(UploadFailure::Io),
+            };
+
+            let result = match multipart.as_mut() {
+                Some(multipart) => multipart.feed(&buf[..read], &mut |data| sink.write(ota, data)),
+                None => sink.write(ota, &buf[..read]),
+            };
+
+            if let Err(failure) = result {
+                break Err(failure);
+            }
+
+            self.received = sink.written + sink.header.len() as u64;
+            self.progress();
+        };
+
+        let result = result.and_then(|_| {
+            if multipart
+                .map(|multipart| !multipart.is_done())
+                .unwrap_or(false)
+                || self
+                    .total
+                    .map(|total| total != self.received)
+                    .unwrap_or(false)
+            {
+                Err(UploadFailure::Incomplete)
+            } else {
+                sink.complete(ota, sha256)
+            }
+        });
+
+        if result.is_err() {
+            if let Some(update) = ota.get_update() {
+                let _ = update.abort();
+            }
+        }
+
+        result
+    }
+
+    fn max_size(&self) -> u64 {
+        let slot = unsafe { esp_ota_get_next_update_partition(ptr::null()).as_ref() }
+            .map(|partition| partition.size as u64)
+            .unwrap_or(0);
+
+        self.conf.max_size.map(|max| max.min(slot)).unwrap_or(slot)
+    }
+
+    /// Publishes the progress, every 5% or 64KB
+    fn progress(&mut self) {
+        self.set_progress(UploadState::Receiving);
+
+        let step = self
+            .total
+            .map(|total| total / 20)
+            .unwrap_or(0)
+            .max(64 * 1024);
+
+        if self.received >= self.reported + step {
+            self.reported = self.received;
+
+            self.post(OtaUploadEvent::Progress {
+                received: self.received as u32,
+                total: self.total.map(|total| total as u32),
+            });
+        }
+    }
+
+    fn set_progress(&self, state: UploadState) {
+        *PROGRESS.lock() = UploadProgress {
+            state,
+            received: self.received,
+            total: self.total,
+        };
+    }
+
+    fn post(&self, event: OtaUploadEvent) {
+        if let Some(sysloop) = self.sysloop {
+            if let Err(e) = sysloop.post(&event, None) {
+                warn!("Failed to post OTA upload event: {}", e);
+            }
+        }
+    }
+
+    fn schedule_restart(&self, delay: Duration) {
+        self.post(OtaUploadEvent::Restarting);
+
+        let spawned = thread::Builder::new()
+            .name("ota-restart".into())
+            .stack_size(2048)
+            .spawn(move || {
+                // Let the response reach the client
+                thread::sleep(delay);
+
+                info!("Restarting into the new firmware");
+
+                unsafe { esp_restart() };
+            });
+
+        if spawned.is_err() {
+            warn!("Failed to schedule the restart, restart to apply the update");
+        }
+    }
+}
+
+/// Checks the header of the image, and streams it to the update slot
+struct Sink<'a> {
+    conf: &'a Configuration,
+    max_size: u64,
+    /// The start of the image, until it is long enough to be checked
+    header: Vec<u8>,
+    /// Whether the update is initiated, after the header was checked
+    started: bool,
+    sha256: Sha256,
+    written: u64,
+}
+
+impl<'a> Sink<'a> {
+    fn write(&mut self, ota: &mut EspOta, data: &[u8]) -> Result<(), UploadFailure> {
+        if self.written + self.header.len() as u64 + data.len() as u64 > self.max_size {
+            return Err(UploadFailure::TooLarge);
+        }
+
+        self.sha256.update(data);
+
+        if !self.started {
+            self.header.extend_from_slice(data);
+
+            if self.header.len() < APP_DESC_OFFSET + mem::size_of::<esp_app_desc_t>() {
+                return Ok(());
+            }
+
+            self.check_header()?;
+
+            // Only now that the image looks right is the update slot erased
+            ota.initiate_update().map_err(|_| UploadFailure::Io)?;
+            self.started = true;
+
+            let header = mem::take(&mut self.header);
+
+            return self.write_update(ota, &header);
+        }
+
+        self.write_update(ota, data)
+    }
+
+    fn write_update(&mut self, ota: &mut EspOta, data: &[u8]) -> Result<(), UploadFailure> {
+        let update: &mut EspOtaUpdate = ota.get_update().ok_or(UploadFailure::Io)?;
+
+        update.write(data).map_err(|e| {
+            warn!("Failed to write the image: {}", e);
+            UploadFailure::Io
+        })?;
+
+        self.written += data.len() as u64;
+
+        Ok(())
+    }
+
+    fn complete(self, ota: &mut EspOta, sha256: Option<&str>) -> Result<(), UploadFailure> {
+        if !self.started {
+            // Shorter than the headers
+            return Err(UploadFailure::InvalidImage);
+        }
+
+        let digest = hex::encode(&self.sha256.finish());
+
+        if sha256.map(|sha256| sha256 != digest).unwrap_or(false) {
+            return Err(UploadFailure::Verification);
+        }
+
+        let update = ota.get_update().ok_or(UploadFailure::Io)?;
+
+        // Verifies the image, and sets it as the boot slot
+        update.complete().map_err(|e| {
+            warn!("Failed to complete the update: {}", e);
+            UploadFailure::Verification
+        })
+    }
+
+    fn check_header(&self) -> Result<(), UploadFailure> {
+        let header = &self.header;
+
+        if header[0] != IMAGE_MAGIC {
+            return Err(UploadFailure::InvalidImage);
+        }
+
+        let chip_id = u16::from_le_bytes([header[12], header[13]]);
+
+        if chip_id as u32 != CONFIG_IDF_FIRMWARE_CHIP_ID {
+            return Err(UploadFailure::InvalidImage);
+        }
+
+        let app_desc: esp_app_desc_t = unsafe {
+            ptr::read_unaligned(header[APP_DESC_OFFSET..].as_ptr() as *const esp_app_desc_t)
+        };
+
+        if app_desc.magic_word != ESP_APP_DESC_MAGIC_WORD {
+            return Err(UploadFailure::InvalidImage);
+        }
+
+        #[cfg(esp_idf_version_major = "4")]
+        let running = unsafe { esp_ota_get_app_description().as_ref() };
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let running = unsafe { esp_app_get_description().as_ref() };
+
+        let running = match running {
+            Some(running) => running,
+            None => return Ok(()),
+        };
+
+        let name = |desc: &esp_app_desc_t| to_string(&desc.project_name);
+        let version = |desc: &esp_app_desc_t| to_string(&desc.version);
+
+        if !self.conf.allow_other_projects && name(&app_desc) != name(running) {
+            info!(
+                "Rejected an image of project {} instead of {}",
+                name(&app_desc),
+                name(running)
+            );
+
+            return Err(UploadFailure::WrongProject);
+        }
+
+        if !self.conf.allow_downgrade
+            && compare_versions(&version(&app_desc), &version(running)) == Ordering::Less
+        {
+            info!(
+                "Rejected an image of version {}, older than {}",
+                version(&app_desc),
+                version(running)
+            );
+
+            return Err(UploadFailure::Downgrade);
+        }
+
+        info!("Receiving version {}", version(&app_desc));
+
+        Ok(())
+    }
+}
+
+/// A field of the application description of the uploaded image, which may not be terminated
+fn to_string(chars: &[ffi::c_char]) -> String {
+    let bytes: Vec<u8> = chars
+        .iter()
+        .map(|c| *c as u8)
+        .take_while(|byte| *byte != 0)
+        .collect();
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Compares the numeric components of two versions (e.g. `v1.10.2-rc1`), in order
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let components = |version: &str| -> Vec<u64> {
+        let mut components: Vec<u64> = version
+            .split(|c: char| !c.is_ascii_digit())
+            .filter(|component| !component.is_empty())
+            .map(|component| component.parse().unwrap_or(0))
+            .collect();
+
+        // `1.2` is `1.2.0`
+        while components.last() == Some(&0) {
+            components.pop();
+        }
+
+        components
+    };
+
+    components(a).cmp(&components(b))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum MultipartState {
+    /// Before the first delimiter
+    Preamble,
+    Headers,
+    Body {
+        file: bool,
+    },
+    /// After the image
+    Done,
+}
+
+/// A streaming parser of `multipart/form-data` bodies, extracting the content of the first file
+struct Multipart {
+    /// `\r\n--<boundary>`
+    delimiter: Vec<u8>,
+    state: MultipartState,
+    /// The data which may be the start of a delimiter, or of the end of the part headers
+    pending: Vec<u8>,
+}
+
+impl Multipart {
+    fn new(content_type: &str) -> Option<Self> {
+        let mut params = content_type.split(';').map(str::trim);
+
+        if !params.next()?.eq_ignore_ascii_case("multipart/form-data") {
+            return None;
+        }
+
+        let boundary = params
+            .find_map(|param| param.strip_prefix("boundary="))?
+            .trim_matches('"');
+
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+
+        Some(Self {
+            delimiter,
+            state: MultipartState::Preamble,
+            // The first delimiter is usually not preceded by a line break
+            pending: b"\r\n".to_vec(),
+        })
+    }
+
+    fn is_done(&self) -> bool {
+        self.state == MultipartState::Done
+    }
+
+    fn feed(
+        &mut self,
+        data: &[u8],
+        out: &mut dyn FnMut(&[u8]) -> Result<(), UploadFailure>,
+    ) -> Result<(), UploadFailure> {
+        self.pending.extend_from_slice(data);
+
+        loop {
+            match self.state {
+                MultipartState::Preamble => match find(&self.pending, &self.delimiter) {
+                    Some(offset) => {
+                        self.pending.drain(..offset + self.delimiter.len());
+                        self.state = MultipartState::Headers;
+                    }
+                    None => {
+                        self.keep_tail(self.delimiter.len() - 1);
+                        return Ok(());
+                    }
+                },
+                MultipartState::Headers => match find(&self.pending, b"\r\n\r\n") {
+                    Some(offset) => {
+                        let headers = String::from_utf8_lossy(&self.pending[..offset]);
+
+                        // The closing delimiter, `--<boundary>--`
+                        if headers.starts_with("--") {
+                            return Err(UploadFailure::Incomplete);
+                        }
+
+                        let file = headers.contains("filename=");
+
+                        self.pending.drain(..offset + 4);
+                        self.state = MultipartState::Body { file };
+                    }
+                    None if self.pending.len() > MAX_PART_HEADERS_LEN => {
+                        return Err(UploadFailure::InvalidImage)
+                    }
+                    None => return Ok(()),
+                },
+                MultipartState::Body { file } => match find(&self.pending, &self.delimiter) {
+                    Some(offset) => {
+                        if file {
+                            out(&self.pending[..offset])?;
+                        }
+
+                        self.pending.drain(..offset + self.delimiter.len());
+
+                        self.state = if file {
+                            MultipartState::Done
+                        } else {
+                            MultipartState::Headers
+                        };
+                    }
+                    None => {
+                        let keep = self.delimiter.len() - 1;
+
+                        if self.pending.len() > keep {
+                            let len = self.pending.len() - keep;
+
+                            if file {
+                                out(&self.pending[..len])?;
+                            }
+
+                            self.pending.drain(..len);
+                        }
+
+                        return Ok(());
+                    }
+                },
+                MultipartState::Done => {
+                    self.pending.clear();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Drops the pending data, but for its last `len` bytes
+    fn keep_tail(&mut self, len: usize) {
+        if self.pending.len() > len {
+            self.pending.drain(..self.pending.len() - len);
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
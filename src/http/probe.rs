@@ -0,0 +1,170 @@
+//! Link probe
+//!
+//! `measure_link` times the phases of a GET request to a test URL: the DNS resolution, the TCP
+//! connection, the TLS handshake, the time to the first byte of the response, and the download
+//! of (at most `Configuration::max_bytes` of) its body, e.g. for an OTA scheduler or a telemetry
+//! service to size their transfers to the link:
+//!
+//! ```ignore
+//! let metrics = probe::measure_link("https://example.com/probe.bin")?;
+//!
+//! if metrics.throughput().unwrap_or(0) < 16 * 1024 {
+//!     // Send smaller batches
+//! }
+//! ```
+//!
+//! As the HTTP client of ESP-IDF connects without reporting the phases of the connection, the
+//! TCP connection is timed on a connection of its own, made just before the request; the DNS
+//! resolution of the request is answered from the lwIP cache then, and its TLS handshake is
+//! estimated as the time it took to connect minus that of the TCP connection.
+use core::time::Duration;
+
+use std::io;
+use std::time::Instant;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+
+use esp_idf_sys::*;
+
+use super::client::{self, EspHttpConnection};
+use super::uri;
+
+use crate::tcp;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The timeout of every phase
+    pub timeout: Duration,
+    /// The body is only downloaded up to that many bytes
+    pub max_bytes: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            max_bytes: 64 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkMetrics {
+    pub dns: Duration,
+    pub connect: Duration,
+    /// `None` for the `http` URLs
+    pub tls: Option<Duration>,
+    /// From sending the request to receiving the headers of the response
+    pub ttfb: Duration,
+    pub status: u16,
+    /// The bytes of the body downloaded
+    pub bytes: usize,
+    /// The time the download of the body took
+    pub transfer: Duration,
+}
+
+impl LinkMetrics {
+    /// The download throughput, in bytes per second
+    pub fn throughput(&self) -> Option<u64> {
+        let micros = self.transfer.as_micros() as u64;
+
+        (self.bytes > 0 && micros > 0).then(|| self.bytes as u64 * 1_000_000 / micros)
+    }
+}
+
+/// Probes the link with a GET request to `url`, with the default configuration
+pub fn measure_link(url: &str) -> Result<LinkMetrics, EspError> {
+    measure_link_with(url, &Configuration::default())
+}
+
+pub fn measure_link_with(url: &str, conf: &Configuration) -> Result<LinkMetrics, EspError> {
+    let (tls, host, port) =
+        split_url(url).ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_ARG>)?;
+
+    let mut metrics = LinkMetrics::default();
+
+    let started = Instant::now();
+    let addrs = tcp::resolve(host, port, true).map_err(io_error)?;
+    metrics.dns = started.elapsed();
+
+    let started = Instant::now();
+    let stream = tcp::connect_to(
+        &addrs,
+        &tcp::Configuration {
+            timeout: conf.timeout,
+            ..Default::default()
+        },
+    )
+    .map_err(io_error)?;
+    metrics.connect = started.elapsed();
+
+    drop(stream);
+
+    let mut connection = EspHttpConnection::new(&client::Configuration {
+        timeout: Some(conf.timeout),
+        #[cfg(all(not(esp_idf_version = "4.3"), esp_idf_mbedtls_certificate_bundle))]
+        crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+
+    // Connects, and sends the request
+    let started = Instant::now();
+    connection.initiate_request(Method::Get, url, &[])?;
+    let opened = started.elapsed();
+
+    if tls {
+        metrics.tls = Some(opened.saturating_sub(metrics.connect));
+    }
+
+    let started = Instant::now();
+    connection.initiate_response()?;
+    metrics.ttfb = started.elapsed();
+
+    metrics.status = connection.status();
+
+    let mut buf = [0_u8; 1024];
+
+    let started = Instant::now();
+
+    while metrics.bytes < conf.max_bytes {
+        let len = connection.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        metrics.bytes += len;
+    }
+
+    metrics.transfer = started.elapsed();
+
+    info!("Probed {}: {:?}", url, metrics);
+
+    Ok(metrics)
+}
+
+/// Whether `url` is an `https` one, and its host and port
+fn split_url(url: &str) -> Option<(bool, &str, u16)> {
+    let (scheme, authority, _) = uri::split_absolute(url)?;
+
+    let default_port = uri::default_port(scheme)?;
+
+    Some((
+        scheme.eq_ignore_ascii_case("https"),
+        authority.host,
+        authority.port.unwrap_or(default_port),
+    ))
+}
+
+fn io_error(e: io::Error) -> EspError {
+    warn!("Probing failed: {}", e);
+
+    match e.kind() {
+        io::ErrorKind::TimedOut => EspError::from_infallible::<ESP_ERR_TIMEOUT>(),
+        io::ErrorKind::NotFound => EspError::from_infallible::<ESP_ERR_NOT_FOUND>(),
+        _ => EspError::from_infallible::<ESP_FAIL>(),
+    }
+}
@@ -0,0 +1,324 @@
+//! HTTP proxies
+//!
+//! `connect` opens a TCP connection tunneled through an HTTP proxy with the `CONNECT` method,
+//! optionally authenticating with a username and a password (`Proxy-Authorization: Basic`):
+//!
+//! ```ignore
+//! let conf = Configuration {
+//!     host: "proxy.example.com",
+//!     port: 3128,
+//!     username: Some("device"),
+//!     password: Some("secret"),
+//!     ..Default::default()
+//! };
+//!
+//! let mut stream = proxy::connect(&conf, "example.com", 443)?;
+//! ```
+//!
+//! The HTTP client goes through the proxy of `client::Configuration::proxy`. As the client of
+//! ESP-IDF opens its own sockets, it connects to the local port of an `EspHttpProxyForwarder`,
+//! which forwards its connections through a tunnel to the host of the current request. Both the
+//! `https` and the `http` requests are tunneled, so the proxy must allow `CONNECT` to the port
+//! of the latter too (e.g. port 80 is not in the `SSL_ports` of the default Squid setup).
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::string::String;
+use std::sync::Arc;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use super::uri;
+
+use crate::private::base64;
+use crate::private::mutex::Mutex;
+use crate::tcp;
+
+/// The longest response to a `CONNECT` request accepted
+const MAX_RESPONSE_LEN: usize = 4096;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct Configuration {
+    pub host: &'static str,
+    pub port: u16,
+    /// The credentials, if the proxy requires them
+    pub username: Option<&'static str>,
+    pub password: Option<&'static str>,
+    /// The timeout of connecting to the proxy and of opening the tunnel
+    pub timeout: Duration,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            host: "",
+            port: 8080,
+            username: None,
+            password: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Connects to `host:port` through the proxy
+pub fn connect(conf: &Configuration, host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut stream = tcp::connect(
+        conf.host,
+        conf.port,
+        &tcp::Configuration {
+            timeout: conf.timeout,
+            ..Default::default()
+        },
+    )?;
+
+    stream.set_read_timeout(Some(conf.timeout))?;
+    stream.set_write_timeout(Some(conf.timeout))?;
+
+    let authority = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+
+    if let Some(username) = conf.username {
+        let credentials = format!("{}:{}", username, conf.password.unwrap_or(""));
+
+        request.push_str("Proxy-Authorization: Basic ");
+        request.push_str(&base64::encode(credentials.as_bytes()));
+        request.push_str("\r\n");
+    }
+
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes())?;
+
+    let status = read_response(&mut stream)?;
+
+    match status {
+        200..=299 => (),
+        407 => {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "The proxy requires authentication",
+            ))
+        }
+        status => {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("The proxy refused the tunnel with status {}", status),
+            ))
+        }
+    }
+
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+
+    debug!(
+        "Connected to {} through {}:{}",
+        authority, conf.host, conf.port
+    );
+
+    Ok(stream)
+}
+
+/// Reads the response to the `CONNECT` request up to the end of its headers, and returns its
+/// status
+///
+/// The response is read byte by byte, so that none of the data of the tunnel is read with it.
+fn read_response(stream: &mut TcpStream) -> io::Result<u16> {
+    let mut response = Vec::new();
+    let mut byte = [0_u8; 1];
+
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "The response of the proxy is too long",
+            ));
+        }
+
+        stream.read_exact(&mut byte)?;
+        response.push(byte[0]);
+    }
+
+    // E.g. `HTTP/1.1 200 Connection established`
+    let status = core::str::from_utf8(&response)
+        .ok()
+        .and_then(|response| {
+            let mut parts = response.split(' ');
+
+            parts
+                .next()
+                .filter(|version| version.starts_with("HTTP/"))?;
+            parts.next()?.parse::<u16>().ok()
+        })
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Not an HTTP proxy"))?;
+
+    Ok(status)
+}
+
+/// Forwards the connections to a local port through tunnels to a target, which can be changed
+/// between the connections
+pub struct EspHttpProxyForwarder {
+    port: u16,
+    target: Arc<Mutex<Option<(String, u16)>>>,
+    running: Arc<AtomicBool>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspHttpProxyForwarder {
+    /// Listens on a free local port, to be used by the clients
+    pub fn new(conf: &Configuration) -> Result<Self, EspError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+
+        let local_port = listener
+            .local_addr()
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?
+            .port();
+
+        let target = Arc::new(Mutex::new(None));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let join_handle = {
+            let conf = *conf;
+            let target = target.clone();
+            let running = running.clone();
+
+            thread::Builder::new()
+                .name("http-proxy".into())
+                .stack_size(4096)
+                .spawn(move || Self::run(conf, listener, target, running))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        info!(
+            "Forwarding 127.0.0.1:{} through {}:{}",
+            local_port, conf.host, conf.port
+        );
+
+        Ok(Self {
+            port: local_port,
+            target,
+            running,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// The local port
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, self.port).into()
+    }
+
+    /// Sets the target of the next connections, returning whether it changed
+    ///
+    /// The connections already forwarded stay tunneled to their target.
+    pub fn set_target(&self, host: &str, port: u16) -> bool {
+        let mut target = self.target.lock();
+
+        if let Some((current_host, current_port)) = target.as_ref() {
+            if current_host.eq_ignore_ascii_case(host) && *current_port == port {
+                return false;
+            }
+        }
+
+        *target = Some((host.into(), port));
+
+        true
+    }
+
+    fn run(
+        conf: Configuration,
+        listener: TcpListener,
+        target: Arc<Mutex<Option<(String, u16)>>>,
+        running: Arc<AtomicBool>,
+    ) {
+        for client in listener.incoming() {
+            if !running.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let client = match client {
+                Ok(client) => client,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+
+            let (host, port) = match target.lock().clone() {
+                Some(target) => target,
+                None => {
+                    warn!("Dropping a connection, as there is no target yet");
+                    continue;
+                }
+            };
+
+            let upstream = match connect(&conf, &host, port) {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    warn!("Failed to connect to {}:{}: {}", host, port, e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = tcp::relay("http-proxy", client, upstream) {
+                warn!("Failed to relay a connection: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for EspHttpProxyForwarder {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+
+        // Wakes the accepting thread up
+        let _ = TcpStream::connect(self.local_addr());
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+/// The host, the port and the authority (for the `Host` header) of an `http` or `https` URL,
+/// and the URL with its host and port replaced by `127.0.0.1:local_port`, for the client to
+/// connect to a forwarder
+pub(crate) fn forwarded_url(url: &str, local_port: u16) -> Option<(&str, u16, &str, String)> {
+    let (scheme, authority, path) = uri::split_absolute(url)?;
+
+    let default_port = uri::default_port(scheme)?;
+
+    if authority.host.is_empty() {
+        return None;
+    }
+
+    let forwarded = match authority.userinfo {
+        Some(userinfo) => format!("{}://{}@127.0.0.1:{}{}", scheme, userinfo, local_port, path),
+        None => format!("{}://127.0.0.1:{}{}", scheme, local_port, path),
+    };
+
+    Some((
+        authority.host,
+        authority.port.unwrap_or(default_port),
+        authority.host_port,
+        forwarded,
+    ))
+}
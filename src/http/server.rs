@@ -3,7 +3,7 @@ use core::cell::UnsafeCell;
 use core::fmt::{Debug, Display};
 use core::sync::atomic::{AtomicBool, Ordering};
 use core::time::*;
-use core::{ffi, ptr};
+use core::{ffi, mem, ptr};
 
 extern crate alloc;
 use alloc::borrow::ToOwned;
@@ -22,20 +22,33 @@ use embedded_svc::http::server::{
 };
 use embedded_svc::http::*;
 use embedded_svc::io::{Io, Read, Write};
+use embedded_svc::ipv4::{IpAddr, Ipv4Addr, Ipv6Addr};
 use embedded_svc::utils::http::server::registration::{ChainHandler, ChainRoot};
 
 use esp_idf_sys::*;
 
 use uncased::{Uncased, UncasedStr};
 
+use super::uri;
+
 use crate::errors::EspIOError;
 use crate::handle::RawHandle;
+use crate::heap::BufferMemory;
 use crate::private::common::Newtype;
 use crate::private::cstr::{CStr, CString};
 use crate::private::mutex::{Mutex, RawMutex};
 #[cfg(esp_idf_esp_https_server_enable)]
 use crate::tls::X509;
 
+/// Per-client request rate limit: at most `requests` requests from a single
+/// client IP address are served within each `period`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Configuration {
     pub http_port: u16,
@@ -48,6 +61,22 @@ pub struct Configuration {
     pub max_resp_handlers: usize,
     pub lru_purge_enable: bool,
     pub uri_match_wildcard: bool,
+    /// Socket receive timeout; connections that do not deliver data within this timeout are closed
+    pub recv_timeout: Duration,
+    /// Socket send timeout; connections that do not accept data within this timeout are closed
+    pub send_timeout: Duration,
+    /// Default limit on the time a handler can spend serving a single request; can be
+    /// overridden per route with `EspHttpServer::handler_with_timeout`
+    pub handler_timeout: Option<Duration>,
+    /// Maximum number of client connections served concurrently; requests arriving
+    /// on connections beyond this limit are answered with `503 Service Unavailable`
+    pub max_connections: Option<usize>,
+    /// Maximum number of concurrent connections a single client IP address may hold
+    pub max_connections_per_ip: Option<usize>,
+    /// Requests exceeding the rate limit are answered with `429 Too Many Requests`
+    pub rate_limit: Option<RateLimit>,
+    /// Where the buffers allocated on starting the server, including the TLS ones, are placed
+    pub buffer_memory: BufferMemory,
     #[cfg(esp_idf_esp_https_server_enable)]
     pub server_certificate: Option<X509<'static>>,
     #[cfg(esp_idf_esp_https_server_enable)]
@@ -70,6 +99,13 @@ impl Default for Configuration {
             max_resp_handlers: 8,
             lru_purge_enable: true,
             uri_match_wildcard: false,
+            recv_timeout: Duration::from_secs(5),
+            send_timeout: Duration::from_secs(5),
+            handler_timeout: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            rate_limit: None,
+            buffer_memory: BufferMemory::Default,
             #[cfg(esp_idf_esp_https_server_enable)]
             server_certificate: None,
             #[cfg(esp_idf_esp_https_server_enable)]
@@ -92,8 +128,8 @@ impl From<&Configuration> for Newtype<httpd_config_t> {
             max_resp_headers: conf.max_resp_handlers as _,
             backlog_conn: 5,
             lru_purge_enable: conf.lru_purge_enable,
-            recv_wait_timeout: 5,
-            send_wait_timeout: 5,
+            recv_wait_timeout: conf.recv_timeout.as_secs().max(1) as _,
+            send_wait_timeout: conf.send_timeout.as_secs().max(1) as _,
             global_user_ctx: ptr::null_mut(),
             global_user_ctx_free_fn: None,
             global_transport_ctx: ptr::null_mut(),
@@ -232,21 +268,172 @@ impl From<Method> for Newtype<ffi::c_uint> {
     }
 }
 
+/// The size of the buffer `EspHttpConnection::read_borrowed` lends, i.e. one TCP segment
+pub const READ_BUFFER_SIZE: usize = 1460;
+
 static OPEN_SESSIONS: Mutex<BTreeMap<(u32, ffi::c_int), Arc<AtomicBool>>> =
     Mutex::wrap(RawMutex::new(), BTreeMap::new());
 static CLOSE_HANDLERS: Mutex<BTreeMap<u32, Vec<CloseHandler>>> =
     Mutex::wrap(RawMutex::new(), BTreeMap::new());
+static LIMITERS: Mutex<BTreeMap<u32, Limiter>> = Mutex::wrap(RawMutex::new(), BTreeMap::new());
 
 type NativeHandler = Box<dyn Fn(*mut httpd_req_t) -> ffi::c_int>;
 type CloseHandler = Box<dyn Fn(ffi::c_int) + Send>;
 
+#[derive(Copy, Clone)]
+enum Rejection {
+    TooManyRequests(Duration),
+    TooManyConnections,
+}
+
+struct Limiter {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    rate_limit: Option<RateLimit>,
+    connections: BTreeMap<ffi::c_int, IpAddr>,
+    requests: BTreeMap<IpAddr, (Duration, u32)>,
+}
+
+impl Limiter {
+    fn new(conf: &Configuration) -> Option<Self> {
+        if conf.max_connections.is_some()
+            || conf.max_connections_per_ip.is_some()
+            || conf.rate_limit.is_some()
+        {
+            Some(Self {
+                max_connections: conf.max_connections,
+                max_connections_per_ip: conf.max_connections_per_ip,
+                rate_limit: conf.rate_limit,
+                connections: BTreeMap::new(),
+                requests: BTreeMap::new(),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn check(&mut self, sockfd: ffi::c_int, addr: IpAddr, now: Duration) -> Result<(), Rejection> {
+        if !self.connections.contains_key(&sockfd) {
+            if let Some(max) = self.max_connections {
+                if self.connections.len() >= max {
+                    return Err(Rejection::TooManyConnections);
+                }
+            }
+
+            if let Some(max) = self.max_connections_per_ip {
+                if self.connections.values().filter(|a| **a == addr).count() >= max {
+                    return Err(Rejection::TooManyConnections);
+                }
+            }
+
+            self.connections.insert(sockfd, addr);
+        }
+
+        if let Some(rate_limit) = self.rate_limit {
+            self.requests
+                .retain(|_, (start, _)| now.saturating_sub(*start) < rate_limit.period);
+
+            let (start, count) = self.requests.entry(addr).or_insert((now, 0));
+
+            if *count >= rate_limit.requests {
+                return Err(Rejection::TooManyRequests(
+                    rate_limit.period.saturating_sub(now.saturating_sub(*start)),
+                ));
+            }
+
+            *count += 1;
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self, sockfd: ffi::c_int) {
+        self.connections.remove(&sockfd);
+    }
+}
+
+fn peer_addr(sockfd: ffi::c_int) -> Option<IpAddr> {
+    let mut addr: sockaddr_in6 = Default::default();
+    let mut len = core::mem::size_of::<sockaddr_in6>() as socklen_t;
+
+    if unsafe { lwip_getpeername(sockfd, &mut addr as *mut _ as *mut _, &mut len) } != 0 {
+        return None;
+    }
+
+    match addr.sin6_family as u32 {
+        AF_INET => {
+            let addr = unsafe { (&addr as *const _ as *const sockaddr_in).as_ref() }.unwrap();
+
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))))
+        }
+        AF_INET6 => {
+            let addr = Ipv6Addr::from(unsafe { addr.sin6_addr.un.u8_addr });
+
+            // The server listens on a dual-stack socket, so IPv4 clients show up as IPv4-mapped addresses
+            Some(if let Some(addr) = addr.to_ipv4() {
+                IpAddr::V4(addr)
+            } else {
+                IpAddr::V6(addr)
+            })
+        }
+        _ => None,
+    }
+}
+
 pub struct EspHttpServer {
     sd: httpd_handle_t,
     registrations: Vec<(CString, esp_idf_sys::httpd_uri_t)>,
+    handler_timeout: Option<Duration>,
 }
 
 impl EspHttpServer {
     pub fn new(conf: &Configuration) -> Result<Self, EspIOError> {
+        let server = EspHttpServer {
+            sd: Self::start(conf)?,
+            registrations: Vec::new(),
+            handler_timeout: conf.handler_timeout,
+        };
+
+        Ok(server)
+    }
+
+    /// Restarts the server with a new configuration, e.g. another port or renewed
+    /// TLS certificates, keeping all registered handlers
+    ///
+    /// Open connections are closed. The handler timeout of the new configuration only
+    /// applies to the handlers registered afterwards. The WS close handlers are kept, and so are
+    /// the request counts of the rate limit, if the new configuration has one.
+    pub fn update_configuration(&mut self, conf: &Configuration) -> Result<(), EspIOError> {
+        info!("Reconfiguring Httpd server");
+
+        let (close_handlers, limiter) = self.stop_daemon()?;
+
+        self.sd = Self::start(conf)?;
+        self.handler_timeout = conf.handler_timeout;
+
+        CLOSE_HANDLERS
+            .lock()
+            .insert(self.sd as _, close_handlers.unwrap_or_default());
+
+        if let (Some(old), Some(new)) = (limiter, LIMITERS.lock().get_mut(&(self.sd as u32))) {
+            if new.rate_limit.is_some() {
+                new.requests = old.requests;
+            }
+        }
+
+        for (uri, registration) in &self.registrations {
+            esp!(unsafe { esp_idf_sys::httpd_register_uri_handler(self.sd, registration) })?;
+
+            info!(
+                "Re-registered Httpd server handler for URI \"{}\"",
+                uri.to_str().unwrap()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn start(conf: &Configuration) -> Result<httpd_handle_t, EspIOError> {
         let mut handle: httpd_handle_t = ptr::null_mut();
         let handle_ref = &mut handle;
 
@@ -254,7 +441,8 @@ impl EspHttpServer {
         {
             let mut config: Newtype<httpd_config_t> = conf.into();
             config.0.close_fn = Some(Self::close_fn);
-            esp!(unsafe { httpd_start(handle_ref, &config.0 as *const _) })?;
+            conf.buffer_memory
+                .scope(|| esp!(unsafe { httpd_start(handle_ref, &config.0 as *const _) }))?;
         }
 
         #[cfg(esp_idf_esp_https_server_enable)]
@@ -283,22 +471,23 @@ impl EspHttpServer {
                 config.0.prvtkey_pem = private_key.as_esp_idf_raw_ptr() as _;
                 config.0.prvtkey_len = private_key.as_esp_idf_raw_len();
 
-                esp!(unsafe { httpd_ssl_start(handle_ref, &mut config.0) })?;
+                conf.buffer_memory
+                    .scope(|| esp!(unsafe { httpd_ssl_start(handle_ref, &mut config.0) }))?;
             } else {
-                esp!(unsafe { httpd_ssl_start(handle_ref, &mut config.0) })?;
+                conf.buffer_memory
+                    .scope(|| esp!(unsafe { httpd_ssl_start(handle_ref, &mut config.0) }))?;
             }
         }
 
         info!("Started Httpd server with config {:?}", conf);
 
-        let server = EspHttpServer {
-            sd: handle,
-            registrations: Vec::new(),
-        };
+        CLOSE_HANDLERS.lock().insert(handle as _, Vec::new());
 
-        CLOSE_HANDLERS.lock().insert(server.sd as _, Vec::new());
+        if let Some(limiter) = Limiter::new(conf) {
+            LIMITERS.lock().insert(handle as _, limiter);
+        }
 
-        Ok(server)
+        Ok(handle)
     }
 
     fn unregister(&mut self, uri: CString, conf: httpd_uri_t) -> Result<(), EspIOError> {
@@ -328,22 +517,8 @@ impl EspHttpServer {
 
                 self.unregister(uri, registration)?;
             }
-            // Maybe its better to always call httpd_stop because httpd_ssl_stop directly wraps httpd_stop anyways
-            // https://github.com/espressif/esp-idf/blob/e6fda46a02c41777f1d116a023fbec6a1efaffb9/components/esp_https_server/src/https_server.c#L268
-            #[cfg(not(esp_idf_esp_https_server_enable))]
-            esp!(unsafe { esp_idf_sys::httpd_stop(self.sd) })?;
-            // httpd_ssl_stop doesn't return EspErr for some reason. It returns void.
-            #[cfg(all(esp_idf_esp_https_server_enable, esp_idf_version_major = "4"))]
-            unsafe {
-                esp_idf_sys::httpd_ssl_stop(self.sd)
-            };
-            // esp-idf version 5 does return EspErr
-            #[cfg(all(esp_idf_esp_https_server_enable, not(esp_idf_version_major = "4")))]
-            esp!(unsafe { esp_idf_sys::httpd_ssl_stop(self.sd) })?;
-
-            CLOSE_HANDLERS.lock().remove(&(self.sd as u32));
 
-            self.sd = ptr::null_mut();
+            self.stop_daemon()?;
         }
 
         info!("Httpd server stopped");
@@ -351,6 +526,46 @@ impl EspHttpServer {
         Ok(())
     }
 
+    /// Stops the server, without unregistering (and dropping) the handlers
+    ///
+    /// Returns the WS close handlers and the limiter of the server, for a restarted server to
+    /// take them over.
+    fn stop_daemon(&mut self) -> Result<(Option<Vec<CloseHandler>>, Option<Limiter>), EspIOError> {
+        if self.sd.is_null() {
+            return Ok((None, None));
+        }
+
+        // The detached senders of the sessions must not use the handle once it is freed
+        OPEN_SESSIONS.lock().retain(|(sd, _), closed| {
+            if *sd == self.sd as u32 {
+                closed.store(true, Ordering::SeqCst);
+                false
+            } else {
+                true
+            }
+        });
+
+        // Maybe its better to always call httpd_stop because httpd_ssl_stop directly wraps httpd_stop anyways
+        // https://github.com/espressif/esp-idf/blob/e6fda46a02c41777f1d116a023fbec6a1efaffb9/components/esp_https_server/src/https_server.c#L268
+        #[cfg(not(esp_idf_esp_https_server_enable))]
+        esp!(unsafe { esp_idf_sys::httpd_stop(self.sd) })?;
+        // httpd_ssl_stop doesn't return EspErr for some reason. It returns void.
+        #[cfg(all(esp_idf_esp_https_server_enable, esp_idf_version_major = "4"))]
+        unsafe {
+            esp_idf_sys::httpd_ssl_stop(self.sd)
+        };
+        // esp-idf version 5 does return EspErr
+        #[cfg(all(esp_idf_esp_https_server_enable, not(esp_idf_version_major = "4")))]
+        esp!(unsafe { esp_idf_sys::httpd_ssl_stop(self.sd) })?;
+
+        let close_handlers = CLOSE_HANDLERS.lock().remove(&(self.sd as u32));
+        let limiter = LIMITERS.lock().remove(&(self.sd as u32));
+
+        self.sd = ptr::null_mut();
+
+        Ok((close_handlers, limiter))
+    }
+
     pub fn handler_chain<C>(&mut self, chain: C) -> Result<&mut Self, EspError>
     where
         C: EspHttpTraversableChain,
@@ -366,6 +581,25 @@ impl EspHttpServer {
         method: Method,
         handler: H,
     ) -> Result<&mut Self, EspError>
+    where
+        H: for<'a> Handler<EspHttpConnection<'a>> + 'static,
+    {
+        let timeout = self.handler_timeout;
+
+        self.handler_with_timeout(uri, method, timeout, handler)
+    }
+
+    /// Same as `handler`, but with a route-specific handler timeout.
+    ///
+    /// Once the timeout expires, any further reads or writes performed by the handler
+    /// fail with `ESP_ERR_TIMEOUT` and the connection is closed after the handler returns.
+    pub fn handler_with_timeout<H>(
+        &mut self,
+        uri: &str,
+        method: Method,
+        timeout: Option<Duration>,
+        handler: H,
+    ) -> Result<&mut Self, EspError>
     where
         H: for<'a> Handler<EspHttpConnection<'a>> + 'static,
     {
@@ -375,7 +609,7 @@ impl EspHttpServer {
         let conf = httpd_uri_t {
             uri: c_str.as_ptr() as _,
             method: Newtype::<ffi::c_uint>::from(method).0,
-            user_ctx: Box::into_raw(Box::new(self.to_native_handler(handler))) as *mut _,
+            user_ctx: Box::into_raw(Box::new(self.to_native_handler(timeout, handler))) as *mut _,
             handler: Some(EspHttpServer::handle_req),
             ..Default::default()
         };
@@ -400,12 +634,21 @@ impl EspHttpServer {
         self.handler(uri, method, FnHandler::new(f))
     }
 
-    fn to_native_handler<H>(&self, handler: H) -> NativeHandler
+    fn to_native_handler<H>(&self, timeout: Option<Duration>, handler: H) -> NativeHandler
     where
         H: for<'a> Handler<EspHttpConnection<'a>> + 'static,
     {
+        // The handle of the server is taken from the request rather than captured, as it
+        // changes when the server is reconfigured
         Box::new(move |raw_req| {
-            let mut connection = EspHttpConnection::new(unsafe { raw_req.as_mut().unwrap() });
+            let sd = unsafe { raw_req.as_ref() }.unwrap().handle;
+
+            let mut connection =
+                EspHttpConnection::new(unsafe { raw_req.as_mut().unwrap() }, timeout);
+
+            if let Err(rejection) = Self::check_limits(sd, raw_req) {
+                return connection.reject(rejection);
+            }
 
             let mut result = EspHttpConnection::handle(&mut connection, &handler);
 
@@ -417,10 +660,39 @@ impl EspHttpServer {
                 connection.handle_error(e);
             }
 
-            ESP_OK as _
+            if connection.is_timed_out() {
+                warn!("Handler timed out, closing the connection");
+
+                // Returning an error from the handler makes the server close the session
+                ESP_FAIL as _
+            } else {
+                ESP_OK as _
+            }
         })
     }
 
+    fn check_limits(sd: httpd_handle_t, raw_req: *mut httpd_req_t) -> Result<(), Rejection> {
+        let mut limiters = LIMITERS.lock();
+
+        if let Some(limiter) = limiters.get_mut(&(sd as u32)) {
+            let sockfd = unsafe { httpd_req_to_sockfd(raw_req) };
+
+            if let Some(addr) = peer_addr(sockfd) {
+                let now = Duration::from_micros(unsafe { esp_timer_get_time() as _ });
+
+                let result = limiter.check(sockfd, addr, now);
+
+                if result.is_err() {
+                    warn!("Rate limiting client {}", addr);
+                }
+
+                return result;
+            }
+        }
+
+        Ok(())
+    }
+
     extern "C" fn handle_req(raw_req: *mut httpd_req_t) -> ffi::c_int {
         let handler_ptr = (unsafe { *raw_req }).user_ctx as *mut NativeHandler;
 
@@ -438,6 +710,10 @@ impl EspHttpServer {
             }
         }
 
+        if let Some(limiter) = LIMITERS.lock().get_mut(&(sd as u32)) {
+            limiter.close(sockfd);
+        }
+
         let all_close_handlers = CLOSE_HANDLERS.lock();
 
         let close_handlers = all_close_handlers.get(&(sd as u32)).unwrap();
@@ -544,14 +820,19 @@ pub struct EspHttpConnection<'a> {
     request: EspHttpRequest<'a>,
     headers: Option<UnsafeCell<EspHttpHeaders>>,
     response_headers: Option<Vec<CString>>,
+    deadline: Option<Duration>,
+    /// The buffer `read_borrowed` lends, allocated on its first call
+    read_buffer: Vec<u8>,
 }
 
 impl<'a> EspHttpConnection<'a> {
-    fn new(raw_req: &'a mut httpd_req_t) -> Self {
+    fn new(raw_req: &'a mut httpd_req_t, timeout: Option<Duration>) -> Self {
         Self {
             request: EspHttpRequest(raw_req),
             headers: Some(UnsafeCell::new(EspHttpHeaders::new())),
             response_headers: None,
+            deadline: timeout.map(|timeout| Self::now() + timeout),
+            read_buffer: Vec::new(),
         }
     }
 
@@ -569,6 +850,22 @@ impl<'a> EspHttpConnection<'a> {
         Method::from(Newtype(self.request.0.method as u32))
     }
 
+    /// The path part of the request URI, without the query string
+    pub fn path(&self) -> &str {
+        uri::split(self.uri()).0
+    }
+
+    /// The raw (percent-encoded) query string of the request URI, if any
+    pub fn query_string(&self) -> Option<&str> {
+        uri::split(self.uri()).1
+    }
+
+    /// The decoded value of the query parameter with the given name, if any
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_string()
+            .and_then(|query| uri::query_param(query, name))
+    }
+
     pub fn header(&self, name: &str) -> Option<&str> {
         self.assert_request();
 
@@ -690,6 +987,7 @@ impl<'a> EspHttpConnection<'a> {
 
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
         self.assert_request();
+        self.check_deadline()?;
 
         unsafe {
             let len = httpd_req_recv(self.request.0, buf.as_mut_ptr() as *mut _, buf.len());
@@ -702,8 +1000,27 @@ impl<'a> EspHttpConnection<'a> {
         }
     }
 
+    /// Reads the next piece of the request body into a buffer of the connection, and lends it,
+    /// instead of copying it into a buffer of the caller; the body ends with an empty slice
+    ///
+    /// The server has no RX buffer of its own, as it receives straight from the socket, so the
+    /// copy saved is the one the application would do. The buffer is `READ_BUFFER_SIZE` long
+    /// and allocated on the first call; the slice is released by the next call on the connection.
+    pub fn read_borrowed(&mut self) -> Result<&[u8], EspError> {
+        if self.read_buffer.is_empty() {
+            self.read_buffer = vec![0; READ_BUFFER_SIZE];
+        }
+
+        let mut buf = mem::take(&mut self.read_buffer);
+        let result = self.read(&mut buf);
+        self.read_buffer = buf;
+
+        Ok(&self.read_buffer[..result?])
+    }
+
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
         self.assert_response();
+        self.check_deadline()?;
 
         if !buf.is_empty() {
             esp!(unsafe {
@@ -730,7 +1047,7 @@ impl<'a> EspHttpConnection<'a> {
     where
         H: Handler<Self>,
     {
-        // TODO info!("About to handle query string {:?}", self.query_string());
+        debug!("About to handle query string {:?}", self.query_string());
 
         handler.handle(self)?;
 
@@ -751,6 +1068,33 @@ impl<'a> EspHttpConnection<'a> {
         Ok(())
     }
 
+    fn reject(&mut self, rejection: Rejection) -> ffi::c_int {
+        let result = match rejection {
+            Rejection::TooManyRequests(retry_after) => {
+                let retry_after = retry_after.as_secs().max(1).to_string();
+
+                self.initiate_response(
+                    429,
+                    Some("Too Many Requests"),
+                    &[("Retry-After", retry_after.as_str())],
+                )
+            }
+            Rejection::TooManyConnections => {
+                self.initiate_response(503, Some("Service Unavailable"), &[("Connection", "close")])
+            }
+        };
+
+        if let Err(e) = result.map_err(HandlerError::from).and_then(|_| self.complete()) {
+            warn!("Error while rejecting request: {}", e);
+        }
+
+        match rejection {
+            // Returning an error from the handler makes the server close the session
+            Rejection::TooManyConnections => ESP_FAIL as _,
+            Rejection::TooManyRequests(_) => ESP_OK as _,
+        }
+    }
+
     fn handle_error<E>(&mut self, error: E)
     where
         E: Display,
@@ -800,6 +1144,24 @@ impl<'a> EspHttpConnection<'a> {
         Ok(())
     }
 
+    fn now() -> Duration {
+        Duration::from_micros(unsafe { esp_timer_get_time() as _ })
+    }
+
+    fn is_timed_out(&self) -> bool {
+        self.deadline
+            .map(|deadline| Self::now() > deadline)
+            .unwrap_or(false)
+    }
+
+    fn check_deadline(&self) -> Result<(), EspError> {
+        if self.is_timed_out() {
+            Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+        } else {
+            Ok(())
+        }
+    }
+
     fn assert_request(&self) {
         if self.headers.is_none() {
             panic!("connection is not in request phase");
@@ -1213,7 +1575,7 @@ pub mod ws {
         {
             let c_str = CString::new(uri).unwrap();
 
-            let (req_handler, close_handler) = self.to_native_ws_handler(self.sd, handler);
+            let (req_handler, close_handler) = self.to_native_ws_handler(handler);
 
             let conf = httpd_uri_t {
                 uri: c_str.as_ptr() as _,
@@ -1267,11 +1629,7 @@ pub mod ws {
             ESP_OK as _
         }
 
-        fn to_native_ws_handler<H, E>(
-            &self,
-            server_handle: httpd_handle_t,
-            handler: H,
-        ) -> (NativeHandler, CloseHandler)
+        fn to_native_ws_handler<H, E>(&self, handler: H) -> (NativeHandler, CloseHandler)
         where
             H: for<'a> Fn(&'a mut EspHttpWsConnection) -> Result<(), E> + Send + Sync + 'static,
             E: Debug,
@@ -1291,6 +1649,9 @@ pub mod ws {
                 Box::new(move |raw_req: *mut httpd_req_t| {
                     let req = unsafe { raw_req.as_ref() }.unwrap();
 
+                    // Not captured, as it changes when the server is reconfigured
+                    let server_handle = req.handle;
+
                     (boxed_handler)(if req.method == http_method_HTTP_GET as i32 {
                         EspHttpWsConnection::New(server_handle, raw_req)
                     } else {
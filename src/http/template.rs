@@ -0,0 +1,361 @@
+//! HTML templates
+//!
+//! A tiny template engine, implementing the subset of Mustache needed by status and
+//! configuration pages, which renders directly into the response of an `EspHttpServer` handler
+//! rather than into a heap string:
+//!
+//! - `{{name}}` inserts a value, HTML-escaped; `{{{name}}}` or `{{&name}}` inserts it as is
+//! - `{{#name}}...{{/name}}` renders its content if the value is `true`, once per item if it
+//!   is a list, and with the names of the value in scope if it is a `Context`
+//! - `{{^name}}...{{/name}}` renders its content if the value is missing, `false` or an empty list
+//! - `{{! comment }}` is skipped
+//!
+//! Values are borrowed `Display` implementations, so numbers are formatted as they are written.
+//! The template is parsed once, when the handler is registered:
+//!
+//! ```ignore
+//! let page = Template::new(include_str!("status.html"))?;
+//!
+//! server.fn_handler("/", Method::Get, move |request| {
+//!     let uptime = uptime().as_secs();
+//!     let ip = wifi.sta_netif().get_ip_info()?.ip;
+//!
+//!     template::respond(
+//!         request,
+//!         &page,
+//!         &[
+//!             ("uptime", Value::Display(&uptime)),
+//!             ("ip", Value::Display(&ip)),
+//!             ("connected", Value::Bool(wifi.is_connected()?)),
+//!         ],
+//!     )
+//! })?;
+//! ```
+//!
+//! Note: This module requires the `templates` cargo feature to be enabled.
+use core::fmt::{self, Display};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use embedded_svc::io::Write;
+
+/// A value of a `Context`
+#[derive(Copy, Clone)]
+pub enum Value<'a> {
+    Display(&'a dyn Display),
+    Bool(bool),
+    List(&'a [&'a dyn Context]),
+    /// A nested context, e.g. the details of one of the interfaces of a page
+    Context(&'a dyn Context),
+}
+
+/// The values of the names of a template
+pub trait Context {
+    fn get(&self, name: &str) -> Option<Value<'_>>;
+}
+
+impl<'a> Context for [(&'a str, Value<'a>)] {
+    fn get(&self, name: &str) -> Option<Value<'_>> {
+        self.iter()
+            .find(|(value_name, _)| *value_name == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+impl<'a, const N: usize> Context for [(&'a str, Value<'a>); N] {
+    fn get(&self, name: &str) -> Option<Value<'_>> {
+        self[..].get(name)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TemplateError {
+    /// The offset of the error in the source of the template
+    pub offset: usize,
+    pub message: &'static str,
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TemplateError {}
+
+#[derive(Debug)]
+enum Node<'a> {
+    Text(&'a str),
+    Value {
+        name: &'a str,
+        escape: bool,
+    },
+    Section {
+        name: &'a str,
+        inverted: bool,
+        children: Vec<Node<'a>>,
+    },
+}
+
+/// A parsed template, borrowing its source
+#[derive(Debug)]
+pub struct Template<'a> {
+    nodes: Vec<Node<'a>>,
+}
+
+impl<'a> Template<'a> {
+    pub fn new(source: &'a str) -> Result<Self, TemplateError> {
+        // The open sections: their name, whether they are inverted, their offset,
+        // and the nodes of their parent
+        let mut sections: Vec<(&'a str, bool, usize, Vec<Node<'a>>)> = Vec::new();
+        let mut nodes = Vec::new();
+
+        let mut offset = 0;
+
+        while let Some(start) = source[offset..].find("{{").map(|start| offset + start) {
+            if start > offset {
+                nodes.push(Node::Text(&source[offset..start]));
+            }
+
+            let raw = source[start..].starts_with("{{{");
+            let (open, close) = if raw { (3, "}}}") } else { (2, "}}") };
+
+            let len = source[start + open..].find(close).ok_or(TemplateError {
+                offset: start,
+                message: "Unclosed tag",
+            })?;
+
+            let tag = source[start + open..start + open + len].trim();
+            let end = start + open + len + close.len();
+
+            let sigil = if raw { None } else { tag.chars().next() };
+
+            match sigil {
+                Some('!') => (),
+                Some('#') | Some('^') => {
+                    let name = tag[1..].trim();
+
+                    sections.push((name, sigil == Some('^'), start, core::mem::take(&mut nodes)));
+                }
+                Some('/') => {
+                    let name = tag[1..].trim();
+
+                    match sections.pop() {
+                        Some((open, inverted, _, parent)) if open == name => {
+                            let children = core::mem::replace(&mut nodes, parent);
+
+                            nodes.push(Node::Section {
+                                name,
+                                inverted,
+                                children,
+                            });
+                        }
+                        _ => {
+                            return Err(TemplateError {
+                                offset: start,
+                                message: "Unexpected section end",
+                            })
+                        }
+                    }
+                }
+                Some('&') => nodes.push(Node::Value {
+                    name: tag[1..].trim(),
+                    escape: false,
+                }),
+                _ => nodes.push(Node::Value {
+                    name: tag,
+                    escape: !raw,
+                }),
+            }
+
+            offset = end;
+        }
+
+        if let Some((_, _, offset, _)) = sections.last() {
+            return Err(TemplateError {
+                offset: *offset,
+                message: "Unclosed section",
+            });
+        }
+
+        if offset < source.len() {
+            nodes.push(Node::Text(&source[offset..]));
+        }
+
+        Ok(Self { nodes })
+    }
+
+    /// Renders the template with the values of `context` into `writer`
+    pub fn render<W: Write>(&self, context: &dyn Context, mut writer: W) -> Result<(), W::Error> {
+        let scope = Scope {
+            context,
+            parent: None,
+        };
+
+        Self::render_nodes(&self.nodes, &scope, &mut writer)
+    }
+
+    fn render_nodes<W: Write>(
+        nodes: &[Node<'_>],
+        scope: &Scope<'_>,
+        writer: &mut W,
+    ) -> Result<(), W::Error> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => writer.write_all(text.as_bytes())?,
+                Node::Value { name, escape } => match scope.get(name) {
+                    Some(Value::Display(value)) => Self::write_value(writer, value, *escape)?,
+                    Some(Value::Bool(value)) => Self::write_value(writer, &value, false)?,
+                    _ => (),
+                },
+                Node::Section {
+                    name,
+                    inverted: false,
+                    children,
+                } => match scope.get(name) {
+                    Some(Value::Display(_)) | Some(Value::Bool(true)) => {
+                        Self::render_nodes(children, scope, writer)?
+                    }
+                    Some(Value::List(items)) => {
+                        for item in items {
+                            let scope = Scope {
+                                context: *item,
+                                parent: Some(scope),
+                            };
+
+                            Self::render_nodes(children, &scope, writer)?;
+                        }
+                    }
+                    Some(Value::Context(context)) => {
+                        let scope = Scope {
+                            context,
+                            parent: Some(scope),
+                        };
+
+                        Self::render_nodes(children, &scope, writer)?;
+                    }
+                    _ => (),
+                },
+                Node::Section {
+                    name,
+                    inverted: true,
+                    children,
+                } => match scope.get(name) {
+                    None | Some(Value::Bool(false)) => Self::render_nodes(children, scope, writer)?,
+                    Some(Value::List(items)) if items.is_empty() => {
+                        Self::render_nodes(children, scope, writer)?
+                    }
+                    _ => (),
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_value<W: Write>(
+        writer: &mut W,
+        value: &dyn Display,
+        escape: bool,
+    ) -> Result<(), W::Error> {
+        let mut adapter = Adapter {
+            writer,
+            escape,
+            error: None,
+        };
+
+        if fmt::write(&mut adapter, format_args!("{}", value)).is_err() {
+            if let Some(error) = adapter.error {
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The contexts in scope: the one of the innermost section first
+struct Scope<'s> {
+    context: &'s dyn Context,
+    parent: Option<&'s Scope<'s>>,
+}
+
+impl<'s> Scope<'s> {
+    fn get(&self, name: &str) -> Option<Value<'_>> {
+        self.context
+            .get(name)
+            .or_else(|| self.parent.and_then(|parent| parent.get(name)))
+    }
+}
+
+/// Writes formatted values to an `embedded_svc` writer, escaping them for HTML
+struct Adapter<'w, W: Write> {
+    writer: &'w mut W,
+    escape: bool,
+    error: Option<W::Error>,
+}
+
+impl<'w, W: Write> fmt::Write for Adapter<'w, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let result = if self.escape {
+            let mut result = Ok(());
+            let mut start = 0;
+
+            for (index, c) in s.char_indices() {
+                let entity = match c {
+                    '&' => "&amp;",
+                    '<' => "&lt;",
+                    '>' => "&gt;",
+                    '"' => "&quot;",
+                    '\'' => "&#39;",
+                    _ => continue,
+                };
+
+                result = self
+                    .writer
+                    .write_all(s[start..index].as_bytes())
+                    .and_then(|_| self.writer.write_all(entity.as_bytes()));
+
+                start = index + 1;
+
+                if result.is_err() {
+                    break;
+                }
+            }
+
+            result.and_then(|_| self.writer.write_all(s[start..].as_bytes()))
+        } else {
+            self.writer.write_all(s.as_bytes())
+        };
+
+        result.map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+/// Responds to `request` with the HTML page rendered from `template`,
+/// streamed to the client as it is rendered
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+pub fn respond(
+    request: embedded_svc::http::server::Request<&mut super::server::EspHttpConnection>,
+    template: &Template<'_>,
+    context: &dyn Context,
+) -> embedded_svc::http::server::HandlerResult {
+    let mut response = request.into_response(
+        200,
+        None,
+        &[
+            ("Content-Type", "text/html; charset=utf-8"),
+            ("Cache-Control", "no-store"),
+        ],
+    )?;
+
+    template.render(context, &mut response)?;
+
+    Ok(())
+}
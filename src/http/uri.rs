@@ -0,0 +1,364 @@
+//! URI utilities
+//!
+//! Percent-encoding and decoding, query string building and parsing, splitting of the
+//! authority (user information, host and port), and resolution of relative references (as used
+//! when following redirects), shared by the HTTP client and server.
+
+use core::fmt::{self, Display, Write};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub use crate::private::authority::Authority;
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+fn encode_into(out: &mut String, s: &str, keep: impl Fn(u8) -> bool) {
+    for b in s.bytes() {
+        if keep(b) {
+            out.push(b as char);
+        } else {
+            out.push('%');
+            out.push(HEX[(b >> 4) as usize] as char);
+            out.push(HEX[(b & 0x0f) as usize] as char);
+        }
+    }
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn decode_bytes(s: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hi = hex_value(*bytes.get(i + 1)?)?;
+                let lo = hex_value(*bytes.get(i + 2)?)?;
+
+                out.push((hi << 4) | lo);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(out).ok()
+}
+
+/// Percent-encodes all characters except the RFC 3986 unreserved ones,
+/// making the result safe to use as a path segment, query key or query value
+pub fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    encode_into(&mut out, s, is_unreserved);
+
+    out
+}
+
+/// Same as `encode`, but keeps the `/` path separators intact
+pub fn encode_path(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    encode_into(&mut out, s, |b| is_unreserved(b) || b == b'/');
+
+    out
+}
+
+/// Decodes a percent-encoded string;
+/// returns `None` if the string contains a malformed escape or does not decode to valid UTF-8
+pub fn decode(s: &str) -> Option<String> {
+    decode_bytes(s, false)
+}
+
+/// Same as `decode`, but also decodes `+` as a space, as used by
+/// `application/x-www-form-urlencoded` query strings and bodies
+pub fn decode_form(s: &str) -> Option<String> {
+    decode_bytes(s, true)
+}
+
+/// Splits a URI into its path (including scheme and authority, if present), query and fragment parts
+pub fn split(uri: &str) -> (&str, Option<&str>, Option<&str>) {
+    let (rest, fragment) = match uri.find('#') {
+        Some(index) => (&uri[..index], Some(&uri[index + 1..])),
+        None => (uri, None),
+    };
+
+    let (path, query) = match rest.find('?') {
+        Some(index) => (&rest[..index], Some(&rest[index + 1..])),
+        None => (rest, None),
+    };
+
+    (path, query, fragment)
+}
+
+/// Splits an absolute URI, e.g. `https://user@host:8443/path?query`, into its scheme, its
+/// authority and the rest; `None` if it lacks either of the first two, or its port is invalid
+pub fn split_absolute(uri: &str) -> Option<(&str, Authority<'_>, &str)> {
+    let scheme = &uri[..scheme_len(uri)?];
+
+    let (authority, rest) = split_authority(&uri[scheme.len() + 1..]);
+    let authority = authority.strip_prefix("//")?;
+
+    Some((scheme, Authority::parse(authority)?, rest))
+}
+
+/// The default port of the `http` and `https` schemes, `None` for the other ones
+pub fn default_port(scheme: &str) -> Option<u16> {
+    if scheme.eq_ignore_ascii_case("https") {
+        Some(443)
+    } else if scheme.eq_ignore_ascii_case("http") {
+        Some(80)
+    } else {
+        None
+    }
+}
+
+/// Iterates over the raw (not decoded) key-value pairs of a query string
+pub fn query_pairs(query: &str) -> impl Iterator<Item = (&str, &str)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(index) => (&pair[..index], &pair[index + 1..]),
+            None => (pair, ""),
+        })
+}
+
+/// Returns the decoded value of the first query parameter with the given (decoded) name
+pub fn query_param(query: &str, name: &str) -> Option<String> {
+    query_pairs(query)
+        .find(|(key, _)| decode_form(key).map(|key| key == name).unwrap_or(false))
+        .and_then(|(_, value)| decode_form(value))
+}
+
+/// Builds a percent-encoded query string
+///
+/// ```ignore
+/// let query = QueryBuilder::new().param("ssid", "My Network").param("retry", "3").build();
+///
+/// assert_eq!(query, "ssid=My%20Network&retry=3");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueryBuilder(String);
+
+impl QueryBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn param(mut self, name: &str, value: &str) -> Self {
+        if !self.0.is_empty() {
+            self.0.push('&');
+        }
+
+        encode_into(&mut self.0, name, is_unreserved);
+        self.0.push('=');
+        encode_into(&mut self.0, value, is_unreserved);
+
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn build(self) -> String {
+        self.0
+    }
+}
+
+impl Display for QueryBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Returns the length of the scheme (without the trailing `:`) if the URI starts with one
+fn scheme_len(uri: &str) -> Option<usize> {
+    let index = uri.find(':')?;
+    let scheme = &uri[..index];
+
+    let mut chars = scheme.chars();
+
+    if chars.next()?.is_ascii_alphabetic()
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+/// Splits a URI without scheme into its authority (including the leading `//`, if present) and the rest
+fn split_authority(s: &str) -> (&str, &str) {
+    if let Some(rest) = s.strip_prefix("//") {
+        let end = rest.find(['/', '?', '#'].as_ref()).unwrap_or(rest.len());
+
+        (&s[..end + 2], &s[end + 2..])
+    } else {
+        ("", s)
+    }
+}
+
+/// Implements the "remove_dot_segments" algorithm of RFC 3986, section 5.2.4
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+
+    let absolute = path.starts_with('/');
+    let mut parts = path.split('/').peekable();
+
+    if absolute {
+        parts.next();
+    }
+
+    let mut trailing_slash = false;
+
+    while let Some(part) = parts.next() {
+        let last = parts.peek().is_none();
+
+        match part {
+            "." => trailing_slash = last,
+            ".." => {
+                segments.pop();
+                trailing_slash = last;
+            }
+            part => {
+                segments.push(part);
+                trailing_slash = false;
+            }
+        }
+    }
+
+    let mut out = String::with_capacity(path.len());
+
+    if absolute {
+        out.push('/');
+    }
+
+    for (index, segment) in segments.iter().enumerate() {
+        if index > 0 {
+            out.push('/');
+        }
+
+        out.push_str(segment);
+    }
+
+    if trailing_slash && !out.ends_with('/') {
+        out.push('/');
+    }
+
+    out
+}
+
+/// Resolves a (possibly relative) URI reference against a base URI, as per RFC 3986, section 5.2
+///
+/// This is what HTTP clients do with the `Location` header of a redirect response:
+/// `join("http://host/a/b?x=1", "../c")` yields `http://host/c`.
+pub fn join(base: &str, reference: &str) -> String {
+    if scheme_len(reference).is_some() {
+        let (path, query, fragment) = split(reference);
+        let scheme_len = scheme_len(path).unwrap_or(0);
+        let (authority, path) = split_authority(&path[scheme_len + 1..]);
+
+        return compose(
+            &reference[..scheme_len + 1],
+            authority,
+            &remove_dot_segments(path),
+            query,
+            fragment,
+        );
+    }
+
+    let (base_path, base_query, _) = split(base);
+    let base_scheme_len = scheme_len(base_path).map(|len| len + 1).unwrap_or(0);
+    let base_scheme = &base_path[..base_scheme_len];
+    let (base_authority, base_path) = split_authority(&base_path[base_scheme_len..]);
+
+    let (ref_path, ref_query, ref_fragment) = split(reference);
+
+    if ref_path.starts_with("//") {
+        let (authority, path) = split_authority(ref_path);
+
+        return compose(
+            base_scheme,
+            authority,
+            &remove_dot_segments(path),
+            ref_query,
+            ref_fragment,
+        );
+    }
+
+    let (path, query) = if ref_path.is_empty() {
+        (String::from(base_path), ref_query.or(base_query))
+    } else if ref_path.starts_with('/') {
+        (remove_dot_segments(ref_path), ref_query)
+    } else {
+        let merged = if !base_authority.is_empty() && base_path.is_empty() {
+            let mut merged = String::from("/");
+            merged.push_str(ref_path);
+
+            merged
+        } else {
+            let dir = &base_path[..base_path.rfind('/').map(|index| index + 1).unwrap_or(0)];
+
+            let mut merged = String::from(dir);
+            merged.push_str(ref_path);
+
+            merged
+        };
+
+        (remove_dot_segments(&merged), ref_query)
+    };
+
+    compose(base_scheme, base_authority, &path, query, ref_fragment)
+}
+
+fn compose(
+    scheme: &str,
+    authority: &str,
+    path: &str,
+    query: Option<&str>,
+    fragment: Option<&str>,
+) -> String {
+    let mut out = String::with_capacity(scheme.len() + authority.len() + path.len() + 16);
+
+    out.push_str(scheme);
+    out.push_str(authority);
+    out.push_str(path);
+
+    if let Some(query) = query {
+        write!(&mut out, "?{query}").unwrap();
+    }
+
+    if let Some(fragment) = fragment {
+        write!(&mut out, "#{fragment}").unwrap();
+    }
+
+    out
+}
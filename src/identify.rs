@@ -0,0 +1,318 @@
+//! Status LED and identification
+//!
+//! `EspIdentify` drives a status LED with the blink pattern of the current `Indication`:
+//!
+//! | Indication     | Pattern                                                   |
+//! |----------------|-----------------------------------------------------------|
+//! | `Off` / `On`   | Steady                                                    |
+//! | `Provisioning` | Slow blink (500ms on, 500ms off)                          |
+//! | `Connecting`   | Fast blink (100ms on, 100ms off)                          |
+//! | `Error(n)`     | `n` short blinks, then a 1.5s pause                       |
+//! | `Identify`     | Very fast blink, for `Configuration::identify_duration`   |
+//!
+//! `Identify` is temporary and overrides the other indications, so that a device can be located
+//! without losing its status. The indication is changed with an `IdentifyHandle`, by posting an
+//! `Indication` on the system event loop, or remotely by feeding commands to
+//! `IdentifyHandle::command` (e.g. from an MQTT subscription) or with the HTTP `handler`:
+//!
+//! ```ignore
+//! let identify = EspIdentify::new(&Default::default(), led, Level::High, sysloop.clone())?;
+//!
+//! sysloop.post(&Indication::Provisioning, None)?;
+//!
+//! server.fn_handler("/identify", Method::Post, identify::handler(identify.handle()))?;
+//! ```
+use core::ffi;
+use core::str::FromStr;
+use core::time::Duration;
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use ::log::*;
+
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+use embedded_svc::http::server::{HandlerResult, Request};
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+use embedded_svc::io::{Read, Write};
+
+use esp_idf_hal::gpio::{AnyOutputPin, Level, Output, PinDriver};
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSystemEventLoop, EspSystemSubscription,
+    EspTypedEventDeserializer, EspTypedEventSerializer, EspTypedEventSource,
+};
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+use crate::http::server::EspHttpConnection;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub identify_duration: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            identify_duration: Duration::from_secs(10),
+            stack_size: 3072,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Indication {
+    Off,
+    On,
+    Provisioning,
+    Connecting,
+    /// A blink code
+    Error(u8),
+    Identify,
+}
+
+impl FromStr for Indication {
+    type Err = EspError;
+
+    /// Parses `off`, `on`, `provisioning`, `connecting`, `error:<code>` or `identify`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let indication = match s.trim() {
+            "off" => Self::Off,
+            "on" => Self::On,
+            "provisioning" => Self::Provisioning,
+            "connecting" => Self::Connecting,
+            "identify" | "" => Self::Identify,
+            other => other
+                .strip_prefix("error:")
+                .and_then(|code| code.parse().ok())
+                .map(Self::Error)
+                .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_ARG>)?,
+        };
+
+        Ok(indication)
+    }
+}
+
+impl EspTypedEventSource for Indication {
+    fn source() -> *const ffi::c_char {
+        b"ESP-IDENTIFY\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<Indication> for Indication {
+    fn serialize<R>(event: &Indication, f: impl for<'a> FnOnce(&'a EspEventPostData) -> R) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<Indication> for Indication {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a Indication) -> R,
+    ) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+enum Command {
+    Indicate(Indication),
+    Stop,
+}
+
+/// Changes the indication of an `EspIdentify` from other threads
+#[derive(Clone)]
+pub struct IdentifyHandle(mpsc::Sender<Command>);
+
+impl IdentifyHandle {
+    pub fn indicate(&self, indication: Indication) {
+        let _ = self.0.send(Command::Indicate(indication));
+    }
+
+    pub fn identify(&self) {
+        self.indicate(Indication::Identify);
+    }
+
+    /// Applies a textual command, as parsed by `Indication::from_str`
+    pub fn command(&self, command: &str) -> Result<(), EspError> {
+        self.indicate(command.parse()?);
+
+        Ok(())
+    }
+}
+
+pub struct EspIdentify {
+    handle: IdentifyHandle,
+    _subscription: EspSystemSubscription,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspIdentify {
+    /// `on` is the level lighting the LED
+    pub fn new(
+        conf: &Configuration,
+        led: PinDriver<'static, AnyOutputPin, Output>,
+        on: Level,
+        sysloop: EspSystemEventLoop,
+    ) -> Result<Self, EspError> {
+        let (sender, receiver) = mpsc::channel();
+
+        let handle = IdentifyHandle(sender);
+
+        let subscription = {
+            let handle = handle.clone();
+
+            sysloop.subscribe(move |indication: &Indication| handle.indicate(*indication))?
+        };
+
+        let join_handle = {
+            let conf = conf.clone();
+
+            thread::Builder::new()
+                .name("identify".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, led, on, receiver))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        Ok(Self {
+            handle,
+            _subscription: subscription,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    pub fn handle(&self) -> IdentifyHandle {
+        self.handle.clone()
+    }
+
+    fn run(
+        conf: Configuration,
+        mut led: PinDriver<'static, AnyOutputPin, Output>,
+        on: Level,
+        commands: mpsc::Receiver<Command>,
+    ) {
+        let off = if on == Level::High {
+            Level::Low
+        } else {
+            Level::High
+        };
+
+        let mut current = Indication::Off;
+        let mut identify_until = None::<Instant>;
+        let mut step = 0;
+
+        loop {
+            let indication = match identify_until {
+                Some(until) if Instant::now() < until => Indication::Identify,
+                _ => {
+                    identify_until = None;
+                    current
+                }
+            };
+
+            let (lit, duration) = Self::step(indication, step);
+
+            if let Err(e) = led.set_level(if lit { on } else { off }) {
+                warn!("Failed to set the LED: {}", e);
+            }
+
+            match commands.recv_timeout(duration) {
+                Ok(Command::Indicate(Indication::Identify)) => {
+                    info!("Identifying");
+
+                    identify_until = Some(Instant::now() + conf.identify_duration);
+                    step = 0;
+                }
+                Ok(Command::Indicate(indication)) => {
+                    debug!("Indicating {:?}", indication);
+
+                    current = indication;
+                    step = 0;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => step += 1,
+                _ => break,
+            }
+        }
+
+        let _ = led.set_level(off);
+    }
+
+    /// Whether the LED is lit at step `step` of the pattern of `indication`, and for how long
+    fn step(indication: Indication, step: usize) -> (bool, Duration) {
+        let ms = Duration::from_millis;
+
+        match indication {
+            Indication::Off => (false, ms(1000)),
+            Indication::On => (true, ms(1000)),
+            Indication::Provisioning => (step % 2 == 0, ms(500)),
+            Indication::Connecting => (step % 2 == 0, ms(100)),
+            Indication::Identify => (step % 2 == 0, ms(50)),
+            Indication::Error(code) => {
+                // `code` blinks, i.e. `code` times on and off, and a pause
+                let step = step % (code as usize * 2 + 1);
+
+                if step == code as usize * 2 {
+                    (false, ms(1500))
+                } else {
+                    (step % 2 == 0, ms(200))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for EspIdentify {
+    fn drop(&mut self) {
+        let _ = self.handle.0.send(Command::Stop);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+/// An `EspHttpServer` handler applying the command in the request body (see
+/// `IdentifyHandle::command`), or identifying if the body is empty
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+pub fn handler(
+    handle: IdentifyHandle,
+) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static {
+    move |mut request| {
+        let mut buf = [0; 32];
+        let mut len = 0;
+
+        while len < buf.len() {
+            let read = request.read(&mut buf[len..])?;
+            if read == 0 {
+                break;
+            }
+
+            len += read;
+        }
+
+        let result = core::str::from_utf8(&buf[..len])
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+            .and_then(|command| handle.command(command));
+
+        match result {
+            Ok(()) => {
+                request.into_ok_response()?;
+            }
+            Err(_) => {
+                request
+                    .into_status_response(400)?
+                    .write_all(b"Unknown command")?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+//! Debounced buttons
+//!
+//! `EspInput` polls a set of buttons, debounces them and posts `InputEvent`s on the system event
+//! loop, so that actions like provisioning, factory resets or identification can be bound to
+//! button gestures by subscribing to the event loop:
+//!
+//! ```ignore
+//! let mut boot = PinDriver::input(peripherals.pins.gpio0.downgrade_input())?;
+//! boot.set_pull(Pull::Up)?;
+//!
+//! let _input = EspInput::new(
+//!     &Configuration::default(),
+//!     vec![Button::new(boot, Level::Low)],
+//!     sysloop.clone(),
+//! )?;
+//!
+//! let _subscription = sysloop.subscribe(|event: &InputEvent| {
+//!     if event.button == 0 && event.press == Press::Long {
+//!         start_provisioning();
+//!     }
+//! })?;
+//! ```
+use core::ffi;
+use core::time::Duration;
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_hal::gpio::{AnyInputPin, Input, Level, PinDriver};
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSystemEventLoop, EspTypedEventDeserializer,
+    EspTypedEventSerializer, EspTypedEventSource,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub poll_interval: Duration,
+    /// How long a level must be stable to be taken into account
+    pub debounce: Duration,
+    /// How long a button must be held for a `Press::Long`
+    pub long_press: Duration,
+    /// The maximum delay between the release of the first press and the second press of a
+    /// `Press::Double`; single presses are reported with this delay, or without any if `ZERO`
+    pub double_press: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_millis(10),
+            debounce: Duration::from_millis(30),
+            long_press: Duration::from_secs(1),
+            double_press: Duration::from_millis(300),
+            stack_size: 3072,
+        }
+    }
+}
+
+pub struct Button {
+    pin: PinDriver<'static, AnyInputPin, Input>,
+    pressed: Level,
+}
+
+impl Button {
+    /// `pressed` is the level of the pin while the button is pressed, i.e.
+    /// `Level::Low` for a button wired to the ground with a pull-up
+    pub fn new(pin: PinDriver<'static, AnyInputPin, Input>, pressed: Level) -> Self {
+        Self { pin, pressed }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Press {
+    Short,
+    /// Reported while the button is still held, once held for long enough
+    Long,
+    Double,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct InputEvent {
+    /// The index of the button, in the order passed to `EspInput::new`
+    pub button: u8,
+    pub press: Press,
+}
+
+impl EspTypedEventSource for InputEvent {
+    fn source() -> *const ffi::c_char {
+        b"ESP-INPUT\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<InputEvent> for InputEvent {
+    fn serialize<R>(event: &InputEvent, f: impl for<'a> FnOnce(&'a EspEventPostData) -> R) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<InputEvent> for InputEvent {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a InputEvent) -> R,
+    ) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+pub struct EspInput {
+    stop: mpsc::Sender<()>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspInput {
+    pub fn new(
+        conf: &Configuration,
+        buttons: Vec<Button>,
+        sysloop: EspSystemEventLoop,
+    ) -> Result<Self, EspError> {
+        if buttons.is_empty() || buttons.len() > u8::MAX as usize {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let (stop, stopped) = mpsc::channel();
+
+        let join_handle = {
+            let conf = conf.clone();
+
+            thread::Builder::new()
+                .name("input".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, buttons, sysloop, stopped))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        Ok(Self {
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    fn run(
+        conf: Configuration,
+        buttons: Vec<Button>,
+        sysloop: EspSystemEventLoop,
+        stopped: mpsc::Receiver<()>,
+    ) {
+        let start = Instant::now();
+
+        let mut debouncers = buttons.iter().map(|_| Debouncer::new()).collect::<Vec<_>>();
+
+        loop {
+            let now = start.elapsed();
+
+            for (index, (button, debouncer)) in buttons.iter().zip(&mut debouncers).enumerate() {
+                let pressed = button.pin.get_level() == button.pressed;
+
+                if let Some(press) = debouncer.update(&conf, now, pressed) {
+                    debug!("Button {}: {:?}", index, press);
+
+                    let event = InputEvent {
+                        button: index as u8,
+                        press,
+                    };
+
+                    if let Err(e) = sysloop.post(&event, None) {
+                        warn!("Failed to post input event: {}", e);
+                    }
+                }
+            }
+
+            match stopped.recv_timeout(conf.poll_interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                _ => break,
+            }
+        }
+    }
+}
+
+impl Drop for EspInput {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+/// The gesture recognition of one button, fed with its raw level and the time of the sample
+struct Debouncer {
+    /// The debounced state
+    pressed: bool,
+    /// The raw state, and since when it is stable
+    raw: (bool, Duration),
+    /// When the current press started
+    pressed_at: Duration,
+    /// Whether `Press::Long` was reported for the current press
+    long: bool,
+    /// The release time of a single press which might still become a double press
+    released_at: Option<Duration>,
+}
+
+impl Debouncer {
+    fn new() -> Self {
+        Self {
+            pressed: false,
+            raw: (false, Duration::ZERO),
+            pressed_at: Duration::ZERO,
+            long: false,
+            released_at: None,
+        }
+    }
+
+    fn update(&mut self, conf: &Configuration, now: Duration, pressed: bool) -> Option<Press> {
+        if pressed != self.raw.0 {
+            self.raw = (pressed, now);
+        }
+
+        if self.raw.0 != self.pressed && now - self.raw.1 >= conf.debounce {
+            self.pressed = self.raw.0;
+
+            if self.pressed {
+                self.pressed_at = now;
+                self.long = false;
+            } else if !self.long {
+                if self.released_at.take().is_some() {
+                    return Some(Press::Double);
+                } else if conf.double_press == Duration::ZERO {
+                    return Some(Press::Short);
+                } else {
+                    self.released_at = Some(now);
+                }
+            }
+        }
+
+        if self.pressed {
+            if !self.long && now - self.pressed_at >= conf.long_press {
+                // A long second press is not a double press: report the pending single
+                // press first, and the long press on the next update
+                if self.released_at.take().is_some() {
+                    return Some(Press::Short);
+                }
+
+                self.long = true;
+
+                return Some(Press::Long);
+            }
+        } else if let Some(released_at) = self.released_at {
+            if now - released_at > conf.double_press {
+                self.released_at = None;
+
+                return Some(Press::Short);
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,404 @@
+//! Fleet diagnostics agent
+//!
+//! `EspInsights` reports the health of the device through `EspTelemetry`, i.e. over any of the
+//! telemetry sinks (an MQTT topic, an HTTP endpoint, ...), as JSON records with a `type`:
+//! - `boot`: on start, the reset reason, the application and ESP-IDF versions and - when the
+//!   core dump is stored in flash in the ELF format - the summary of the crash which caused the
+//!   reset, whose core dump is then erased
+//! - `log`: the log records at or above `Configuration::log_level` (warnings by default)
+//! - `metrics`: every `Configuration::metrics_interval`, the uptime and heap usage, along with
+//!   the OpenMetrics rendering of the metrics registry when the `metrics` feature is enabled
+//! - `event`: the custom events reported with `EspInsights::event`
+//!
+//! ```ignore
+//! let insights = EspInsights::new(
+//!     &Default::default(),
+//!     MqttSink::new(client, "devices/sensor-1/insights", QoS::AtLeastOnce),
+//! )?;
+//!
+//! insights.event("door", json!({ "open": true }));
+//! ```
+//!
+//! Only the records logged through the `log` crate (with `EspLogger`) are captured, not those of
+//! the ESP-IDF components.
+//!
+//! Note: This module requires the `insights` cargo feature to be enabled.
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use std::collections::VecDeque;
+use std::string::String;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Instant;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use serde_json::{json, Value};
+
+use crate::private::cstr::from_cstr_ptr;
+use crate::private::mutex::{Mutex, RawMutex};
+use crate::telemetry::{self, EspTelemetry, Sink};
+
+/// The log records captured since the last drain, or `None` while no agent is running
+static LOGS: Mutex<Option<VecDeque<Value>>> = Mutex::wrap(RawMutex::new(), None);
+
+/// The last captured log records, formatted, which are kept after being drained
+static HISTORY: Mutex<Vec<String>> = Mutex::wrap(RawMutex::new(), Vec::new());
+
+static LOG_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Debug)]
+pub struct Configuration {
+    /// The most verbose level of the captured log records
+    pub log_level: LevelFilter,
+    /// The maximum number of log records captured between two drains; the oldest ones are
+    /// dropped beyond that
+    pub log_capacity: usize,
+    /// How often the captured log records are handed to the telemetry buffer
+    pub interval: Duration,
+    pub metrics_interval: Duration,
+    pub telemetry: telemetry::Configuration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            log_level: LevelFilter::Warn,
+            log_capacity: 32,
+            interval: Duration::from_secs(10),
+            metrics_interval: Duration::from_secs(300),
+            telemetry: Default::default(),
+            stack_size: 4096,
+        }
+    }
+}
+
+pub struct EspInsights {
+    telemetry: Arc<EspTelemetry<Value>>,
+    stop: mpsc::Sender<()>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspInsights {
+    /// Starts the agent, reporting the current boot
+    ///
+    /// Only one agent can run at a time, as the log records are captured globally.
+    pub fn new<K>(conf: &Configuration, sink: K) -> Result<Self, EspError>
+    where
+        K: Sink + Send + 'static,
+    {
+        {
+            let mut logs = LOGS.lock();
+
+            if logs.is_some() {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+
+            LOG_CAPACITY.store(conf.log_capacity, Ordering::SeqCst);
+            LOG_LEVEL.store(conf.log_level as usize, Ordering::SeqCst);
+
+            *logs = Some(VecDeque::new());
+        }
+
+        let telemetry = match EspTelemetry::new(&conf.telemetry, sink) {
+            Ok(telemetry) => Arc::new(telemetry),
+            Err(e) => {
+                *LOGS.lock() = None;
+                return Err(e);
+            }
+        };
+
+        telemetry.push(boot_record());
+        telemetry.flush();
+
+        let (stop, stopped) = mpsc::channel();
+
+        let spawned = {
+            let conf = conf.clone();
+            let telemetry = telemetry.clone();
+
+            thread::Builder::new()
+                .name("insights".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, telemetry, stopped))
+        };
+
+        let join_handle = match spawned {
+            Ok(join_handle) => join_handle,
+            Err(_) => {
+                *LOGS.lock() = None;
+                return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+            }
+        };
+
+        info!("Started insights");
+
+        Ok(Self {
+            telemetry,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// Reports a custom event
+    pub fn event(&self, name: &str, data: Value) {
+        self.telemetry.push(json!({
+            "type": "event",
+            "uptime_ms": uptime().as_millis() as u64,
+            "name": name,
+            "data": data,
+        }));
+    }
+
+    /// Reports the metrics, and ships the buffered records, now
+    pub fn flush(&self) {
+        drain_logs(&self.telemetry);
+
+        self.telemetry.push(metrics_record());
+        self.telemetry.flush();
+    }
+
+    pub fn stats(&self) -> telemetry::Stats {
+        self.telemetry.stats()
+    }
+
+    fn run(conf: Configuration, telemetry: Arc<EspTelemetry<Value>>, stopped: mpsc::Receiver<()>) {
+        let mut last_metrics = Instant::now();
+
+        loop {
+            let stop = !matches!(
+                stopped.recv_timeout(conf.interval),
+                Err(mpsc::RecvTimeoutError::Timeout)
+            );
+
+            drain_logs(&telemetry);
+
+            if last_metrics.elapsed() >= conf.metrics_interval {
+                last_metrics = Instant::now();
+
+                telemetry.push(metrics_record());
+            }
+
+            if stop {
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for EspInsights {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        drain_logs(&self.telemetry);
+
+        *LOGS.lock() = None;
+        HISTORY.lock().clear();
+
+        info!("Dropped");
+    }
+}
+
+/// Captures a log record, if an agent is running; called by `EspLogger`
+pub(crate) fn capture(record: &Record) {
+    let capacity = LOG_CAPACITY.load(Ordering::Relaxed);
+
+    if capacity == 0 || record.level() as usize > LOG_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut message = String::new();
+    let _ = write!(message, "{}", record.args());
+
+    let level = match record.level() {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    };
+
+    let uptime_ms = uptime().as_millis() as u64;
+
+    let line = format!("{} ({}) {}: {}", level, uptime_ms, record.target(), message);
+
+    let value = json!({
+        "type": "log",
+        "uptime_ms": uptime_ms,
+        "level": level,
+        "target": record.target(),
+        "message": message,
+    });
+
+    let mut logs = LOGS.lock();
+
+    if let Some(logs) = logs.as_mut() {
+        if logs.len() >= capacity {
+            logs.pop_front();
+        }
+
+        logs.push_back(value);
+
+        let mut history = HISTORY.lock();
+
+        if history.len() >= capacity {
+            history.remove(0);
+        }
+
+        history.push(line);
+    }
+}
+
+/// The last captured log records, oldest first, as in `warn (1234) target: message`
+pub fn recent_logs() -> Vec<String> {
+    HISTORY.lock().clone()
+}
+
+fn drain_logs(telemetry: &EspTelemetry<Value>) {
+    // Not pushing while holding the lock, as pushing may log
+    let logs: Vec<_> = LOGS
+        .lock()
+        .as_mut()
+        .map(|logs| logs.drain(..).collect())
+        .unwrap_or_default();
+
+    for log in logs {
+        telemetry.push(log);
+    }
+}
+
+fn uptime() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as u64)
+}
+
+fn boot_record() -> Value {
+    #[cfg(esp_idf_version_major = "4")]
+    let app_desc = unsafe { esp_ota_get_app_description().as_ref() };
+    #[cfg(not(esp_idf_version_major = "4"))]
+    let app_desc = unsafe { esp_app_get_description().as_ref() };
+
+    let (app_version, idf_version) = app_desc
+        .map(|app_desc| unsafe {
+            (
+                from_cstr_ptr(&app_desc.version as *const _),
+                from_cstr_ptr(&app_desc.idf_ver as *const _),
+            )
+        })
+        .unwrap_or_default();
+
+    let mut mac = [0_u8; 6];
+    unsafe { esp_efuse_mac_get_default(mac.as_mut_ptr()) };
+
+    let mac = mac
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":");
+
+    json!({
+        "type": "boot",
+        "reset_reason": reset_reason(),
+        "app_version": app_version,
+        "idf_version": idf_version,
+        "mac": mac,
+        "crash": crash(),
+    })
+}
+
+fn metrics_record() -> Value {
+    #[allow(unused_mut)]
+    let mut record = json!({
+        "type": "metrics",
+        "uptime_s": uptime().as_secs(),
+        "free_heap": unsafe { esp_get_free_heap_size() },
+        "min_free_heap": unsafe { esp_get_minimum_free_heap_size() },
+        "largest_free_block": unsafe { heap_caps_get_largest_free_block(MALLOC_CAP_8BIT) },
+    });
+
+    #[cfg(feature = "metrics")]
+    {
+        record["metrics"] = Value::String(crate::metrics::render());
+    }
+
+    record
+}
+
+#[allow(non_upper_case_globals)]
+fn reset_reason() -> &'static str {
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "poweron",
+        esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "int_wdt",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_wdt",
+        esp_reset_reason_t_ESP_RST_WDT => "wdt",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deepsleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+/// The summary of the core dump in flash, if any, which is erased so as to be reported once
+#[cfg(all(
+    not(esp_idf_version = "4.3"),
+    esp_idf_comp_espcoredump_enabled,
+    esp_idf_esp_coredump_enable_to_flash,
+    esp_idf_esp_coredump_data_format_elf
+))]
+fn crash() -> Value {
+    if unsafe { esp_core_dump_image_check() } != ESP_OK {
+        return Value::Null;
+    }
+
+    let mut summary: esp_core_dump_summary_t = Default::default();
+
+    if let Err(e) = esp!(unsafe { esp_core_dump_get_summary(&mut summary) }) {
+        warn!("Failed to read the core dump summary: {}", e);
+        return Value::Null;
+    }
+
+    #[allow(unused_mut)]
+    let mut crash = json!({
+        "task": unsafe { from_cstr_ptr(&summary.exc_task as *const _) },
+        "pc": summary.exc_pc,
+        "elf_sha256": unsafe { from_cstr_ptr(&summary.app_elf_sha256 as *const _) },
+    });
+
+    #[cfg(any(esp32, esp32s2, esp32s3))]
+    {
+        let bt_info = &summary.exc_bt_info;
+        let depth = (bt_info.depth as usize).min(bt_info.bt.len());
+
+        crash["backtrace"] = bt_info.bt[..depth].iter().copied().collect();
+        crash["backtrace_corrupted"] = bt_info.corrupted.into();
+    }
+
+    if let Err(e) = esp!(unsafe { esp_core_dump_image_erase() }) {
+        warn!("Failed to erase the core dump: {}", e);
+    }
+
+    crash
+}
+
+#[cfg(not(all(
+    not(esp_idf_version = "4.3"),
+    esp_idf_comp_espcoredump_enabled,
+    esp_idf_esp_coredump_enable_to_flash,
+    esp_idf_esp_coredump_data_format_elf
+)))]
+fn crash() -> Value {
+    Value::Null
+}
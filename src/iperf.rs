@@ -0,0 +1,545 @@
+//! iperf-compatible throughput tests
+//!
+//! A TCP and UDP throughput test server and client, compatible with iperf 2 (`iperf -s`,
+//! `iperf -c <device>`, with `-u` for UDP) and with the `iperf` example of ESP-IDF, so that the
+//! link performance of WiFi or Ethernet configurations can be measured from Rust:
+//!
+//! ```ignore
+//! // On the device...
+//! let _server = EspIperf::new(&Configuration::default(), |report| info!("{}", report))?;
+//!
+//! // ... and on the PC: iperf -c <device> -i 1 -t 10
+//! ```
+//!
+//! The test reports the throughput every `Configuration::interval`, and once done. Servers
+//! run until dropped, testing one client at a time; clients stop after
+//! `Configuration::duration`.
+use core::fmt::{self, Display};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::private::mutex::Mutex;
+
+/// How often the test threads check whether they should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The size of the iperf 2 UDP datagram header: id, seconds and microseconds
+const UDP_HEADER_LEN: usize = 12;
+
+/// The size of the server report appended by the server to the final UDP datagram
+const UDP_SERVER_REPORT_LEN: usize = 40;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Role {
+    Server,
+    /// Sends to the server with the given address
+    Client(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] SocketAddr),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub protocol: Protocol,
+    pub role: Role,
+    /// The port the server listens on
+    pub port: u16,
+    /// The duration of the test, for clients
+    pub duration: Duration,
+    pub interval: Duration,
+    /// The size of the reads and writes; for UDP, the size of the datagrams
+    pub buffer_size: usize,
+    /// The target bandwidth of UDP clients, in bits per second
+    pub bandwidth: u32,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            protocol: Protocol::Tcp,
+            role: Role::Server,
+            port: 5001,
+            duration: Duration::from_secs(10),
+            interval: Duration::from_secs(1),
+            buffer_size: 16 * 1024,
+            bandwidth: 1_000_000,
+            stack_size: 4096,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Report {
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub peer: SocketAddr,
+    /// The start of the reported interval, since the start of the test
+    pub start: Duration,
+    pub end: Duration,
+    pub bytes: u64,
+    /// The datagrams lost during the whole test, as reported by the server, for UDP
+    pub lost: Option<u64>,
+    /// Whether this is the report of the whole test
+    pub complete: bool,
+}
+
+impl Report {
+    pub fn bits_per_second(&self) -> f64 {
+        let secs = (self.end - self.start).as_secs_f64();
+
+        if secs > 0.0 {
+            self.bytes as f64 * 8.0 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {:.1}-{:.1} sec {} KBytes {:.2} Mbits/sec",
+            self.peer,
+            self.start.as_secs_f64(),
+            self.end.as_secs_f64(),
+            self.bytes / 1024,
+            self.bits_per_second() / 1_000_000.0
+        )?;
+
+        if let Some(lost) = self.lost {
+            write!(f, " {} lost", lost)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct EspIperf {
+    last_report: Arc<Mutex<Option<Report>>>,
+    stop: Arc<AtomicBool>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspIperf {
+    pub fn new(
+        conf: &Configuration,
+        on_report: impl FnMut(&Report) + Send + 'static,
+    ) -> Result<Self, EspError> {
+        if conf.buffer_size < UDP_HEADER_LEN + UDP_SERVER_REPORT_LEN
+            || conf.interval == Duration::ZERO
+        {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let last_report = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let mut test = Test {
+            conf: conf.clone(),
+            stop: stop.clone(),
+            last_report: last_report.clone(),
+            on_report: Box::new(on_report),
+        };
+
+        let join_handle = thread::Builder::new()
+            .name("iperf".into())
+            .stack_size(conf.stack_size)
+            .spawn(move || {
+                let result = match (test.conf.protocol, test.conf.role) {
+                    (Protocol::Tcp, Role::Server) => test.tcp_server(),
+                    (Protocol::Tcp, Role::Client(server)) => test.tcp_client(server),
+                    (Protocol::Udp, Role::Server) => test.udp_server(),
+                    (Protocol::Udp, Role::Client(server)) => test.udp_client(server),
+                };
+
+                if let Err(e) = result {
+                    warn!("iperf test failed: {}", e);
+                }
+            })
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        info!("Started iperf {:?} {:?}", conf.protocol, conf.role);
+
+        Ok(Self {
+            last_report,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// The last report of the test, interval or complete
+    pub fn last_report(&self) -> Option<Report> {
+        self.last_report.lock().clone()
+    }
+
+    /// Whether the test is over; servers only end when dropped, or on errors
+    pub fn is_finished(&self) -> bool {
+        self.join_handle
+            .as_ref()
+            .map(|join_handle| join_handle.is_finished())
+            .unwrap_or(true)
+    }
+
+    /// Waits for the test to end, and returns its complete report
+    pub fn wait(mut self) -> Option<Report> {
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        self.last_report().filter(|report| report.complete)
+    }
+}
+
+impl Drop for EspIperf {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+struct Meter {
+    peer: SocketAddr,
+    started: Instant,
+    interval_start: Duration,
+    interval_bytes: u64,
+    total_bytes: u64,
+}
+
+impl Meter {
+    fn new(peer: SocketAddr) -> Self {
+        Self {
+            peer,
+            started: Instant::now(),
+            interval_start: Duration::ZERO,
+            interval_bytes: 0,
+            total_bytes: 0,
+        }
+    }
+
+    fn add(&mut self, bytes: usize) {
+        self.interval_bytes += bytes as u64;
+        self.total_bytes += bytes as u64;
+    }
+
+    /// The report of the current interval, if it is over
+    fn interval(&mut self, interval: Duration) -> Option<Report> {
+        let now = self.started.elapsed();
+
+        if now < self.interval_start + interval {
+            return None;
+        }
+
+        let report = Report {
+            peer: self.peer,
+            start: self.interval_start,
+            end: now,
+            bytes: self.interval_bytes,
+            lost: None,
+            complete: false,
+        };
+
+        self.interval_start = now;
+        self.interval_bytes = 0;
+
+        Some(report)
+    }
+
+    fn complete(&self, lost: Option<u64>) -> Report {
+        Report {
+            peer: self.peer,
+            start: Duration::ZERO,
+            end: self.started.elapsed(),
+            bytes: self.total_bytes,
+            lost,
+            complete: true,
+        }
+    }
+}
+
+struct Test {
+    conf: Configuration,
+    stop: Arc<AtomicBool>,
+    last_report: Arc<Mutex<Option<Report>>>,
+    on_report: Box<dyn FnMut(&Report) + Send>,
+}
+
+impl Test {
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    fn report(&mut self, report: Report) {
+        (self.on_report)(&report);
+
+        *self.last_report.lock() = Some(report);
+    }
+
+    fn tcp_server(&mut self) -> std::io::Result<()> {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, self.conf.port))?;
+        listener.set_nonblocking(true)?;
+
+        let mut buf = vec![0; self.conf.buffer_size];
+
+        while !self.stopped() {
+            let (mut stream, peer) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            stream.set_nonblocking(false)?;
+            stream.set_read_timeout(Some(POLL_INTERVAL))?;
+
+            let mut meter = Meter::new(peer);
+
+            while !self.stopped() {
+                match stream.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => meter.add(len),
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                    Err(e) => {
+                        warn!("iperf connection from {} failed: {}", peer, e);
+                        break;
+                    }
+                }
+
+                if let Some(report) = meter.interval(self.conf.interval) {
+                    self.report(report);
+                }
+            }
+
+            self.report(meter.complete(None));
+        }
+
+        Ok(())
+    }
+
+    fn tcp_client(&mut self, server: SocketAddr) -> std::io::Result<()> {
+        let mut stream = TcpStream::connect(server)?;
+        stream.set_nodelay(true)?;
+
+        let buf: Vec<u8> = (0..self.conf.buffer_size)
+            .map(|i| b'0' + (i % 10) as u8)
+            .collect();
+
+        let mut meter = Meter::new(server);
+
+        while !self.stopped() && meter.started.elapsed() < self.conf.duration {
+            stream.write_all(&buf)?;
+            meter.add(buf.len());
+
+            if let Some(report) = meter.interval(self.conf.interval) {
+                self.report(report);
+            }
+        }
+
+        drop(stream);
+
+        self.report(meter.complete(None));
+
+        Ok(())
+    }
+
+    fn udp_server(&mut self) -> std::io::Result<()> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, self.conf.port))?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let mut buf = vec![0; self.conf.buffer_size.max(2048)];
+
+        // The client, the meter, the highest datagram id, and the number of datagrams
+        let mut session: Option<(SocketAddr, Meter, i32, u64)> = None;
+
+        while !self.stopped() {
+            match socket.recv_from(&mut buf) {
+                Ok((len, peer)) if len >= UDP_HEADER_LEN => {
+                    let id = i32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+
+                    if session.as_ref().map(|session| session.0) != Some(peer) {
+                        session = Some((peer, Meter::new(peer), 0, 0));
+                    }
+
+                    let (_, meter, max_id, datagrams) = session.as_mut().unwrap();
+
+                    if id >= 0 {
+                        meter.add(len);
+                        *max_id = (*max_id).max(id);
+                        *datagrams += 1;
+                    } else {
+                        // The final datagram, repeated until the client gets the server report
+                        let max_id = *max_id;
+                        let datagrams = *datagrams;
+
+                        let lost = (max_id as u64 + 1).saturating_sub(datagrams);
+                        let report = meter.complete(Some(lost));
+
+                        let reply = Self::udp_server_report(&buf[..len], &report, datagrams);
+                        socket.send_to(&reply, peer)?;
+
+                        // Only report once, and not for the repeated final datagrams
+                        if datagrams > 0 {
+                            self.report(report);
+                        }
+
+                        session = Some((peer, Meter::new(peer), max_id, 0));
+                        continue;
+                    }
+                }
+                Ok(_) => (),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+                Err(e) => return Err(e),
+            }
+
+            let interval = self.conf.interval;
+
+            if let Some(report) = session
+                .as_mut()
+                .filter(|session| session.3 > 0)
+                .and_then(|session| session.1.interval(interval))
+            {
+                self.report(report);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The final datagram of the client, followed by the iperf 2 server report
+    fn udp_server_report(datagram: &[u8], report: &Report, datagrams: u64) -> Vec<u8> {
+        let mut reply = datagram[..UDP_HEADER_LEN].to_vec();
+
+        let lost = report.lost.unwrap_or(0);
+
+        // Flags: the report is valid
+        reply.extend_from_slice(&0x8000_0000_u32.to_be_bytes());
+        reply.extend_from_slice(&((report.bytes >> 32) as u32).to_be_bytes());
+        reply.extend_from_slice(&(report.bytes as u32).to_be_bytes());
+        reply.extend_from_slice(&(report.end.as_secs() as u32).to_be_bytes());
+        reply.extend_from_slice(&report.end.subsec_micros().to_be_bytes());
+        reply.extend_from_slice(&(lost as u32).to_be_bytes());
+        // Out of order datagrams, and datagrams
+        reply.extend_from_slice(&0_u32.to_be_bytes());
+        reply.extend_from_slice(&((datagrams + lost) as u32).to_be_bytes());
+        // Jitter, which is not measured
+        reply.extend_from_slice(&0_u32.to_be_bytes());
+        reply.extend_from_slice(&0_u32.to_be_bytes());
+
+        reply
+    }
+
+    fn udp_client(&mut self, server: SocketAddr) -> std::io::Result<()> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.connect(server)?;
+
+        let mut buf = vec![0_u8; self.conf.buffer_size.min(1470)];
+
+        // The time between two datagrams, at the target bandwidth
+        let pace =
+            Duration::from_secs_f64(buf.len() as f64 * 8.0 / self.conf.bandwidth.max(1) as f64);
+
+        let mut meter = Meter::new(server);
+        let mut id = 0_i32;
+
+        while !self.stopped() && meter.started.elapsed() < self.conf.duration {
+            Self::udp_header(&mut buf, id);
+
+            match socket.send(&buf) {
+                Ok(len) => meter.add(len),
+                // Out of buffers: the link is saturated
+                Err(e)
+                    if e.kind() == ErrorKind::OutOfMemory
+                        || e.raw_os_error() == Some(ENOMEM as _) =>
+                {
+                    thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+
+            id = id.wrapping_add(1);
+
+            if let Some(report) = meter.interval(self.conf.interval) {
+                self.report(report);
+            }
+
+            let due = pace * id as u32;
+            if let Some(ahead) = due.checked_sub(meter.started.elapsed()) {
+                thread::sleep(ahead);
+            }
+        }
+
+        // The final datagram, until the server answers with its report
+        socket.set_read_timeout(Some(Duration::from_millis(250)))?;
+
+        let mut lost = None;
+
+        for _ in 0..10 {
+            Self::udp_header(&mut buf, -id);
+            socket.send(&buf)?;
+
+            let mut reply = [0_u8; UDP_HEADER_LEN + UDP_SERVER_REPORT_LEN];
+
+            if let Ok(len) = socket.recv(&mut reply) {
+                if len >= UDP_HEADER_LEN + 24 {
+                    let offset = UDP_HEADER_LEN + 20;
+
+                    lost = Some(u32::from_be_bytes([
+                        reply[offset],
+                        reply[offset + 1],
+                        reply[offset + 2],
+                        reply[offset + 3],
+                    ]) as u64);
+                }
+
+                break;
+            }
+        }
+
+        if lost.is_none() {
+            warn!("No report from the iperf server {}", server);
+        }
+
+        self.report(meter.complete(lost));
+
+        Ok(())
+    }
+
+    fn udp_header(buf: &mut [u8], id: i32) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        buf[0..4].copy_from_slice(&id.to_be_bytes());
+        buf[4..8].copy_from_slice(&(now.as_secs() as u32).to_be_bytes());
+        buf[8..12].copy_from_slice(&now.subsec_micros().to_be_bytes());
+    }
+}
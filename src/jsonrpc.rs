@@ -0,0 +1,346 @@
+//! JSON-RPC 2.0
+//!
+//! Dispatches JSON-RPC 2.0 requests - single or batched - to typed method handlers, whose
+//! parameters and results are (de)serialized with serde. The requests are received by an
+//! `EspHttpServer` handler, as the body of `POST` requests, or by a WebSocket handler, as text
+//! messages:
+//!
+//! ```ignore
+//! #[derive(Deserialize)]
+//! struct SetLed {
+//!     on: bool,
+//! }
+//!
+//! let mut rpc = JsonRpc::new();
+//!
+//! rpc.method("uptime", |_: ()| Ok(uptime().as_secs()));
+//! rpc.method("set_led", move |params: SetLed| {
+//!     led.lock().set_state(params.on).map_err(RpcError::internal)
+//! });
+//!
+//! let rpc = Arc::new(rpc);
+//!
+//! server.fn_handler("/rpc", Method::Post, jsonrpc::handler(rpc.clone()))?;
+//! server.ws_handler("/rpc/ws", jsonrpc::ws_handler(rpc))?;
+//! ```
+//!
+//! Parameters which do not deserialize into the type of the handler are answered with an
+//! `Invalid params` error; methods without parameters take `()`, or an `Option`.
+//!
+//! Note: This module requires the `jsonrpc` cargo feature to be enabled.
+use core::fmt::{self, Debug, Display};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+pub const PARSE_ERROR: i32 = -32700;
+pub const INVALID_REQUEST: i32 = -32600;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+pub const INTERNAL_ERROR: i32 = -32603;
+
+/// The default maximum size of a request, or of a batch of requests
+pub const DEFAULT_MAX_REQUEST_LEN: usize = 4096;
+
+/// A JSON-RPC error; the application errors use codes outside of -32768..=-32000
+#[derive(Clone, Debug, PartialEq)]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn invalid_params(error: impl Display) -> Self {
+        Self::new(INVALID_PARAMS, error.to_string())
+    }
+
+    /// An `Internal error`, with the error of the handler as its message
+    pub fn internal(error: impl Debug) -> Self {
+        Self::new(INTERNAL_ERROR, alloc::format!("{:?}", error))
+    }
+
+    pub fn with_data(mut self, data: impl Serialize) -> Self {
+        self.data = serde_json::to_value(data).ok();
+        self
+    }
+
+    fn to_json(&self) -> Value {
+        match &self.data {
+            Some(data) => json!({ "code": self.code, "message": self.message, "data": data }),
+            None => json!({ "code": self.code, "message": self.message }),
+        }
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RpcError {}
+
+type Method = Box<dyn Fn(Value) -> Result<Value, RpcError> + Send + Sync + 'static>;
+
+/// The registered methods
+#[derive(Default)]
+pub struct JsonRpc {
+    methods: BTreeMap<String, Method>,
+}
+
+impl JsonRpc {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Registers the handler of `name`, replacing any existing one
+    pub fn method<P, R, F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        P: DeserializeOwned,
+        R: Serialize,
+        F: Fn(P) -> Result<R, RpcError> + Send + Sync + 'static,
+    {
+        self.methods.insert(
+            name.into(),
+            Box::new(move |params| {
+                let params = serde_json::from_value(params).map_err(RpcError::invalid_params)?;
+
+                serde_json::to_value(f(params)?).map_err(RpcError::internal)
+            }),
+        );
+
+        self
+    }
+
+    pub fn methods(&self) -> impl Iterator<Item = &str> {
+        self.methods.keys().map(String::as_str)
+    }
+
+    /// Handles a request, or a batch of requests, returning the response to send back, if any:
+    /// notifications are not answered
+    pub fn handle(&self, request: &[u8]) -> Option<Vec<u8>> {
+        let response = match serde_json::from_slice::<Value>(request) {
+            Ok(request) => self.handle_value(request)?,
+            Err(e) => Self::error_response(Value::Null, &RpcError::new(PARSE_ERROR, e.to_string())),
+        };
+
+        serde_json::to_vec(&response).ok()
+    }
+
+    /// Like `handle`, for a request which is already parsed
+    pub fn handle_value(&self, request: Value) -> Option<Value> {
+        match request {
+            Value::Array(requests) if requests.is_empty() => Some(Self::error_response(
+                Value::Null,
+                &RpcError::new(INVALID_REQUEST, "Empty batch"),
+            )),
+            Value::Array(requests) => {
+                let responses: Vec<Value> = requests
+                    .into_iter()
+                    .filter_map(|request| self.call(request))
+                    .collect();
+
+                if responses.is_empty() {
+                    None
+                } else {
+                    Some(Value::Array(responses))
+                }
+            }
+            request => self.call(request),
+        }
+    }
+
+    fn call(&self, request: Value) -> Option<Value> {
+        let mut request = match request {
+            Value::Object(request) => request,
+            _ => {
+                return Some(Self::error_response(
+                    Value::Null,
+                    &RpcError::new(INVALID_REQUEST, "Not an object"),
+                ))
+            }
+        };
+
+        let id = request.remove("id");
+
+        let valid_id = matches!(
+            id,
+            None | Some(Value::Null) | Some(Value::Number(_)) | Some(Value::String(_))
+        );
+
+        let method = match request.remove("method") {
+            Some(Value::String(method))
+                if valid_id && request.get("jsonrpc").and_then(Value::as_str) == Some("2.0") =>
+            {
+                method
+            }
+            _ => {
+                return Some(Self::error_response(
+                    id.filter(|_| valid_id).unwrap_or(Value::Null),
+                    &RpcError::new(INVALID_REQUEST, "Invalid request"),
+                ))
+            }
+        };
+
+        let params = match request.remove("params") {
+            None => Value::Null,
+            Some(params @ Value::Array(_)) | Some(params @ Value::Object(_)) => params,
+            Some(_) => {
+                return id.map(|id| {
+                    Self::error_response(id, &RpcError::new(INVALID_REQUEST, "Invalid params"))
+                })
+            }
+        };
+
+        let result = match self.methods.get(&method) {
+            Some(handler) => handler(params),
+            None => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+        };
+
+        // Notifications are not answered, not even with errors
+        let id = id?;
+
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(error) => Self::error_response(id, &error),
+        })
+    }
+
+    fn error_response(id: Value, error: &RpcError) -> Value {
+        json!({ "jsonrpc": "2.0", "error": error.to_json(), "id": id })
+    }
+}
+
+impl Debug for JsonRpc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonRpc")
+            .field("methods", &self.methods.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+pub use server::*;
+
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+mod server {
+    use alloc::sync::Arc;
+    use alloc::vec;
+
+    use embedded_svc::http::server::{HandlerResult, Request};
+    use embedded_svc::io::Write;
+
+    use crate::http::server::EspHttpConnection;
+
+    use super::{JsonRpc, DEFAULT_MAX_REQUEST_LEN};
+
+    /// An `EspHttpServer` handler for `POST` requests with a JSON-RPC request body
+    ///
+    /// Requests larger than `DEFAULT_MAX_REQUEST_LEN` are rejected with
+    /// `413 Payload Too Large`.
+    pub fn handler(
+        rpc: Arc<JsonRpc>,
+    ) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static
+    {
+        move |mut request| {
+            let mut body = vec![0; DEFAULT_MAX_REQUEST_LEN + 1];
+            let mut len = 0;
+
+            while len < body.len() {
+                let read = request.connection().read(&mut body[len..])?;
+                if read == 0 {
+                    break;
+                }
+
+                len += read;
+            }
+
+            if len > DEFAULT_MAX_REQUEST_LEN {
+                request
+                    .into_status_response(413)?
+                    .write_all(b"Payload too large")?;
+
+                return Ok(());
+            }
+
+            match rpc.handle(&body[..len]) {
+                Some(response) => {
+                    request
+                        .into_response(200, None, &[("Content-Type", "application/json")])?
+                        .write_all(&response)?;
+                }
+                None => {
+                    request.into_status_response(204)?;
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    #[cfg(esp_idf_httpd_ws_support)]
+    pub use ws::*;
+
+    #[cfg(esp_idf_httpd_ws_support)]
+    mod ws {
+        use alloc::sync::Arc;
+        use alloc::vec;
+
+        use embedded_svc::ws::FrameType;
+
+        use esp_idf_sys::*;
+
+        use crate::http::server::ws::EspHttpWsConnection;
+
+        use super::super::{JsonRpc, DEFAULT_MAX_REQUEST_LEN};
+
+        /// An `EspHttpServer` WebSocket handler answering the JSON-RPC requests received as text
+        /// messages
+        pub fn ws_handler(
+            rpc: Arc<JsonRpc>,
+        ) -> impl for<'a> Fn(&'a mut EspHttpWsConnection) -> Result<(), EspError> + Send + Sync + 'static
+        {
+            move |connection| {
+                if connection.is_new() || connection.is_closed() {
+                    return Ok(());
+                }
+
+                let mut buf = vec![0; DEFAULT_MAX_REQUEST_LEN];
+
+                let (frame_type, len) = connection.recv(&mut buf)?;
+
+                if len > buf.len() {
+                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+                }
+
+                if let FrameType::Text(false) = frame_type {
+                    // Text frames may be terminated by the C string NUL
+                    let request = buf[..len].strip_suffix(&[0]).unwrap_or(&buf[..len]);
+
+                    if let Some(response) = rpc.handle(request) {
+                        connection.send(FrameType::Text(false), &response)?;
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,558 @@
+//! JSON Web Tokens (RFC 7519)
+//!
+//! `Signer` creates tokens signed with HS256, RS256 or ES256, e.g. the short-lived per-connection
+//! tokens of the cloud IoT brokers, which take them as the MQTT password:
+//!
+//! ```ignore
+//! let key = KeyPair::from_private_key(include_bytes!("device.key"))?;
+//!
+//! let claims = Claims::expiring("my-project", Duration::from_secs(3600))?;
+//! let token = Signer::es256(key)?.sign(&claims)?;
+//! ```
+//!
+//! `Verifier` validates the tokens of incoming requests, e.g. in the HTTP server handlers:
+//!
+//! ```ignore
+//! let verifier = Verifier::es256(include_str!("issuer.pem"));
+//!
+//! server.fn_handler("/api/config", Method::Get, move |request| {
+//!     match verifier.verify_request(&request, &Validation::default()) {
+//!         Ok(claims) => request.into_ok_response()?.write_all(b"...")?,
+//!         Err(_) => {
+//!             request.into_status_response(401)?;
+//!         }
+//!     }
+//!
+//!     Ok(())
+//! })?;
+//! ```
+//!
+//! The expiry and the start of validity of the tokens are checked against the system time, with
+//! a leeway for the clock skew which grows with the time since the last SNTP synchronization, as
+//! the local clock drifts. Until SNTP has synchronized the time, the `unsynchronized` policy of
+//! the validation applies.
+//!
+//! Note: This module requires the `jwt` cargo feature to be enabled.
+use core::fmt::{self, Display, Formatter};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use embedded_svc::http::Headers;
+
+use esp_idf_sys::*;
+
+use crate::private::base64;
+use crate::private::json::{self, Json};
+use crate::private::sha256;
+use crate::tls::keys::{Curve, KeyPair, KeyType};
+
+/// How far a crystal may drift, in parts per million
+#[cfg(esp_idf_comp_esp_netif_enabled)]
+const MAX_DRIFT_PPM: u64 = 100;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Algorithm {
+    /// HMAC with SHA-256, with a shared secret
+    HS256,
+    /// RSASSA-PKCS1-v1_5 with SHA-256
+    RS256,
+    /// ECDSA with P-256 and SHA-256
+    ES256,
+}
+
+impl Algorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::HS256 => "HS256",
+            Self::RS256 => "RS256",
+            Self::ES256 => "ES256",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum JwtError {
+    /// Not three base64url encoded parts, or not JSON
+    Malformed,
+    /// Another algorithm than the one of the verifier, which prevents algorithm confusion
+    Algorithm,
+    Signature,
+    Expired,
+    NotYetValid,
+    Audience,
+    Issuer,
+    /// The system time is not synchronized, and the validation rejects the tokens then
+    Unsynchronized,
+    /// The key was rejected by mbedTLS, or is of the wrong type
+    Key,
+}
+
+impl Display for JwtError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::Malformed => "Malformed token",
+            Self::Algorithm => "Unexpected algorithm",
+            Self::Signature => "Invalid signature",
+            Self::Expired => "Token expired",
+            Self::NotYetValid => "Token not yet valid",
+            Self::Audience => "Unexpected audience",
+            Self::Issuer => "Unexpected issuer",
+            Self::Unsynchronized => "System time not synchronized",
+            Self::Key => "Invalid key",
+        };
+
+        write!(f, "{}", message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for JwtError {}
+
+/// The registered claims, plus any other string-valued ones
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Claims {
+    pub iss: Option<String>,
+    pub sub: Option<String>,
+    pub aud: Option<String>,
+    /// The expiry, in seconds since the UNIX epoch
+    pub exp: Option<u64>,
+    /// The start of validity, in seconds since the UNIX epoch
+    pub nbf: Option<u64>,
+    /// The issuance, in seconds since the UNIX epoch
+    pub iat: Option<u64>,
+    pub jti: Option<String>,
+    pub custom: Vec<(String, String)>,
+}
+
+impl Claims {
+    /// Claims for `audience`, issued now and expiring after `lifetime`; fails with
+    /// `ESP_ERR_INVALID_STATE` if the system time is not set
+    pub fn expiring(audience: &str, lifetime: Duration) -> Result<Self, EspError> {
+        let now = system_time()
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_STATE>)?
+            .as_secs();
+
+        Ok(Self {
+            aud: Some(audience.into()),
+            iat: Some(now),
+            exp: Some(now + lifetime.as_secs()),
+            ..Default::default()
+        })
+    }
+
+    fn to_json(&self) -> Json {
+        let mut members = Vec::new();
+
+        let strings = [
+            ("iss", &self.iss),
+            ("sub", &self.sub),
+            ("aud", &self.aud),
+            ("jti", &self.jti),
+        ];
+
+        for (name, value) in strings {
+            if let Some(value) = value {
+                members.push((name.into(), value.as_str().into()));
+            }
+        }
+
+        let times = [("exp", self.exp), ("nbf", self.nbf), ("iat", self.iat)];
+
+        for (name, value) in times {
+            if let Some(value) = value {
+                members.push((name.into(), value.into()));
+            }
+        }
+
+        for (name, value) in &self.custom {
+            members.push((name.clone(), value.as_str().into()));
+        }
+
+        Json::Object(members)
+    }
+
+    fn from_json(json: &Json) -> Option<Self> {
+        let mut claims = Self::default();
+
+        for (name, value) in json.as_object()? {
+            let string = || value.as_str().map(String::from);
+
+            match name.as_str() {
+                "iss" => claims.iss = string(),
+                "sub" => claims.sub = string(),
+                // Only the single audience form
+                "aud" => claims.aud = string(),
+                "jti" => claims.jti = string(),
+                "exp" => claims.exp = Some(value.as_u64()?),
+                "nbf" => claims.nbf = Some(value.as_u64()?),
+                "iat" => claims.iat = Some(value.as_u64()?),
+                _ => {
+                    if let Some(value) = value.as_str() {
+                        claims.custom.push((name.clone(), value.into()));
+                    }
+                }
+            }
+        }
+
+        Some(claims)
+    }
+}
+
+enum SigningKey {
+    Secret(Vec<u8>),
+    KeyPair(KeyPair),
+}
+
+pub struct Signer {
+    algorithm: Algorithm,
+    key: SigningKey,
+}
+
+impl Signer {
+    pub fn hs256(secret: &[u8]) -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            key: SigningKey::Secret(secret.to_vec()),
+        }
+    }
+
+    /// Fails with `ESP_ERR_INVALID_ARG` if the key is not an RSA one
+    pub fn rs256(key: KeyPair) -> Result<Self, EspError> {
+        match key.key_type() {
+            Some(KeyType::Rsa(_)) => Ok(Self {
+                algorithm: Algorithm::RS256,
+                key: SigningKey::KeyPair(key),
+            }),
+            _ => Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>()),
+        }
+    }
+
+    /// Fails with `ESP_ERR_INVALID_ARG` if the key is not a P-256 one
+    pub fn es256(key: KeyPair) -> Result<Self, EspError> {
+        match key.key_type() {
+            Some(KeyType::Ec(Curve::P256)) => Ok(Self {
+                algorithm: Algorithm::ES256,
+                key: SigningKey::KeyPair(key),
+            }),
+            _ => Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>()),
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    pub fn sign(&mut self, claims: &Claims) -> Result<String, EspError> {
+        let header = json::object([("alg", self.algorithm.name().into()), ("typ", "JWT".into())]);
+
+        let message = format!(
+            "{}.{}",
+            base64::encode_url(header.to_string().as_bytes()),
+            base64::encode_url(claims.to_json().to_string().as_bytes())
+        );
+
+        let signature = match (&mut self.key, self.algorithm) {
+            (SigningKey::Secret(secret), _) => hmac_sha256(secret, message.as_bytes())?.to_vec(),
+            (SigningKey::KeyPair(key), Algorithm::ES256) => {
+                key.sign_es256(message.as_bytes())?.to_vec()
+            }
+            (SigningKey::KeyPair(key), _) => key.sign_sha256(message.as_bytes())?,
+        };
+
+        Ok(format!("{}.{}", message, base64::encode_url(&signature)))
+    }
+}
+
+/// What to do with the time claims while the system time is not synchronized by SNTP
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Unsynchronized {
+    /// Rejects all the tokens with time claims
+    Reject,
+    /// Checks the claims against the system time anyway, if it is set at all, e.g. from an RTC
+    /// or a GPS receiver
+    TrustSystemTime,
+    /// Accepts the tokens whatever their time claims
+    SkipTimeChecks,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Validation {
+    /// The clock skew tolerated right after an SNTP synchronization
+    pub leeway: Duration,
+    /// The expected `aud` claim, if any
+    pub audience: Option<String>,
+    /// The expected `iss` claim, if any
+    pub issuer: Option<String>,
+    /// Rejects the tokens without an `exp` claim
+    pub require_expiry: bool,
+    pub unsynchronized: Unsynchronized,
+}
+
+impl Default for Validation {
+    fn default() -> Self {
+        Self {
+            leeway: Duration::from_secs(60),
+            audience: None,
+            issuer: None,
+            require_expiry: true,
+            unsynchronized: Unsynchronized::Reject,
+        }
+    }
+}
+
+enum VerifyingKey {
+    Secret(Vec<u8>),
+    PublicKey(String),
+}
+
+pub struct Verifier {
+    algorithm: Algorithm,
+    key: VerifyingKey,
+}
+
+impl Verifier {
+    pub fn hs256(secret: &[u8]) -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            key: VerifyingKey::Secret(secret.to_vec()),
+        }
+    }
+
+    /// With a PEM encoded RSA public key
+    pub fn rs256(public_key: &str) -> Self {
+        Self {
+            algorithm: Algorithm::RS256,
+            key: VerifyingKey::PublicKey(public_key.into()),
+        }
+    }
+
+    /// With a PEM encoded P-256 public key
+    pub fn es256(public_key: &str) -> Self {
+        Self {
+            algorithm: Algorithm::ES256,
+            key: VerifyingKey::PublicKey(public_key.into()),
+        }
+    }
+
+    /// Verifies the signature and the claims of `token`, returning its claims
+    pub fn verify(&self, token: &str, validation: &Validation) -> Result<Claims, JwtError> {
+        let token = token.trim();
+
+        let mut parts = token.split('.');
+
+        let (header, payload, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(payload), Some(signature)) if parts.next().is_none() => {
+                (header, payload, signature)
+            }
+            _ => return Err(JwtError::Malformed),
+        };
+
+        let header = decode_json(header)?;
+
+        if header.get("alg").and_then(Json::as_str) != Some(self.algorithm.name()) {
+            return Err(JwtError::Algorithm);
+        }
+
+        // All but the signature
+        let message = &token[..token.len() - signature.len() - 1];
+        let signature = base64::decode(signature).ok_or(JwtError::Malformed)?;
+
+        self.verify_signature(message.as_bytes(), &signature)?;
+
+        let claims = Claims::from_json(&decode_json(payload)?).ok_or(JwtError::Malformed)?;
+
+        validate(&claims, validation)?;
+
+        Ok(claims)
+    }
+
+    /// Verifies the bearer token of the `Authorization` header of a request
+    pub fn verify_request<H>(
+        &self,
+        request: &H,
+        validation: &Validation,
+    ) -> Result<Claims, JwtError>
+    where
+        H: Headers,
+    {
+        let token = request
+            .header("Authorization")
+            .and_then(|authorization| {
+                authorization
+                    .strip_prefix("Bearer ")
+                    .or_else(|| authorization.strip_prefix("bearer "))
+            })
+            .ok_or(JwtError::Malformed)?;
+
+        self.verify(token, validation)
+    }
+
+    fn verify_signature(&self, message: &[u8], signature: &[u8]) -> Result<(), JwtError> {
+        match &self.key {
+            VerifyingKey::Secret(secret) => {
+                let expected = hmac_sha256(secret, message).map_err(|_| JwtError::Key)?;
+
+                // In constant time
+                let difference = expected
+                    .iter()
+                    .zip(signature)
+                    .fold(0, |difference, (a, b)| difference | (a ^ b));
+
+                if signature.len() == expected.len() && difference == 0 {
+                    Ok(())
+                } else {
+                    Err(JwtError::Signature)
+                }
+            }
+            VerifyingKey::PublicKey(public_key) => {
+                let signature = if self.algorithm == Algorithm::ES256 {
+                    raw_to_der(signature).ok_or(JwtError::Signature)?
+                } else {
+                    signature.to_vec()
+                };
+
+                sha256::verify_signature(public_key, message, &signature).map_err(|e| {
+                    if e.code() == ESP_ERR_INVALID_ARG {
+                        JwtError::Key
+                    } else {
+                        JwtError::Signature
+                    }
+                })
+            }
+        }
+    }
+}
+
+fn validate(claims: &Claims, validation: &Validation) -> Result<(), JwtError> {
+    if let Some(audience) = &validation.audience {
+        if claims.aud.as_ref() != Some(audience) {
+            return Err(JwtError::Audience);
+        }
+    }
+
+    if let Some(issuer) = &validation.issuer {
+        if claims.iss.as_ref() != Some(issuer) {
+            return Err(JwtError::Issuer);
+        }
+    }
+
+    if validation.require_expiry && claims.exp.is_none() {
+        return Err(JwtError::Expired);
+    }
+
+    if claims.exp.is_none() && claims.nbf.is_none() {
+        return Ok(());
+    }
+
+    let (now, leeway) = match clock(validation) {
+        Some(clock) => clock,
+        None if validation.unsynchronized == Unsynchronized::SkipTimeChecks => return Ok(()),
+        None => return Err(JwtError::Unsynchronized),
+    };
+
+    let (now, leeway) = (now.as_secs(), leeway.as_secs());
+
+    if claims.exp.map(|exp| now > exp + leeway).unwrap_or(false) {
+        return Err(JwtError::Expired);
+    }
+
+    if claims.nbf.map(|nbf| now + leeway < nbf).unwrap_or(false) {
+        return Err(JwtError::NotYetValid);
+    }
+
+    Ok(())
+}
+
+/// The system time and the clock skew to tolerate, if the time can be trusted
+fn clock(validation: &Validation) -> Option<(Duration, Duration)> {
+    let now = system_time()?;
+
+    #[cfg(esp_idf_comp_esp_netif_enabled)]
+    if let Some(since_last_sync) = crate::sntp::since_last_sync() {
+        let drift = since_last_sync * MAX_DRIFT_PPM as u32 / 1_000_000;
+
+        return Some((now, validation.leeway + drift));
+    }
+
+    (validation.unsynchronized == Unsynchronized::TrustSystemTime).then(|| (now, validation.leeway))
+}
+
+fn system_time() -> Option<Duration> {
+    let mut tv: timeval = Default::default();
+
+    unsafe { gettimeofday(&mut tv, core::ptr::null_mut()) };
+
+    // Before 2020, the time has not been set
+    (tv.tv_sec > 1_577_836_800).then(|| Duration::from_secs(tv.tv_sec as _))
+}
+
+fn decode_json(part: &str) -> Result<Json, JwtError> {
+    let decoded = base64::decode(part).ok_or(JwtError::Malformed)?;
+
+    core::str::from_utf8(&decoded)
+        .ok()
+        .and_then(Json::parse)
+        .ok_or(JwtError::Malformed)
+}
+
+fn hmac_sha256(secret: &[u8], message: &[u8]) -> Result<[u8; 32], EspError> {
+    let mut mac = [0_u8; 32];
+
+    let result = unsafe {
+        mbedtls_md_hmac(
+            mbedtls_md_info_from_type(mbedtls_md_type_t_MBEDTLS_MD_SHA256),
+            secret.as_ptr(),
+            secret.len() as _,
+            message.as_ptr(),
+            message.len() as _,
+            mac.as_mut_ptr(),
+        )
+    };
+
+    if result == 0 {
+        Ok(mac)
+    } else {
+        Err(EspError::from_infallible::<ESP_FAIL>())
+    }
+}
+
+/// Converts a raw `r || s` ECDSA P-256 signature to DER, i.e. `SEQUENCE { INTEGER r, INTEGER s }`
+fn raw_to_der(raw: &[u8]) -> Option<Vec<u8>> {
+    if raw.len() != 64 {
+        return None;
+    }
+
+    let mut integers = Vec::with_capacity(70);
+
+    for half in raw.chunks(32) {
+        let half = &half[half.iter().take_while(|byte| **byte == 0).count()..];
+
+        // A sign byte keeps the integers positive
+        let sign = half.first().map(|byte| *byte >= 0x80).unwrap_or(true);
+
+        integers.push(0x02);
+        integers.push((half.len() + sign as usize) as u8);
+
+        if sign {
+            integers.push(0);
+        }
+
+        integers.extend_from_slice(half);
+    }
+
+    let mut der = vec![0x30, integers.len() as u8];
+    der.extend_from_slice(&integers);
+
+    Some(der)
+}
@@ -11,6 +11,24 @@
 //!   client.
 //! - `embassy-time-driver`
 //! - `embassy-time-isr-queue`
+//! - `embassy`: Enable an `embassy-executor` executor running on a FreeRTOS task, along with the
+//!   `esp_timer` based `embassy-time` driver and queue.
+//! - `sparkplug`: Enable Sparkplug B support on top of the MQTT client.
+//! - `prost`: Enable `prost` message support in the gRPC client.
+//! - `rand_core`: Implement `rand_core::RngCore` (and `rand_core::CryptoRng`) for the hardware RNG.
+//! - `mock`: Enable a mock HTTP client connection for unit-testing code using the HTTP client.
+//! - `metrics`: Enable the metrics registry and its OpenMetrics rendering, and the publishing
+//!   of the HTTP client, MQTT client and Wi-Fi metrics into it.
+//! - `telemetry`: Enable the telemetry batching service.
+//! - `insights`: Enable the diagnostics agent, reporting crashes, reset reasons, logs and metrics
+//!   through the telemetry service.
+//! - `crypto`: Enable the hardware accelerated hashes, MACs and ciphers, implementing the
+//!   RustCrypto traits.
+//! - `jwt`: Enable the signing and the validation of JSON Web Tokens, with mbedTLS.
+//! - `heapless-config`: Enable MQTT and HTTP client configurations which own their URL and
+//!   credentials in fixed-capacity `heapless` buffers. The WiFi configurations are `heapless`-based
+//!   already.
+//! - `defmt`: Implement `defmt::Format` for the configurations, events and errors of the crate.
 #![cfg_attr(not(feature = "std"), no_std)]
 #![feature(cfg_version)]
 #![cfg_attr(
@@ -25,6 +43,78 @@
 #[macro_use]
 extern crate alloc;
 
+#[cfg(all(
+    feature = "std",
+    feature = "experimental",
+    esp_idf_comp_esp_http_client_enabled,
+    esp_idf_comp_esp_http_server_enabled,
+    esp_idf_comp_mbedtls_enabled,
+    esp_idf_comp_nvs_flash_enabled
+))]
+pub mod acme;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_lwip_enabled,
+    esp_idf_comp_esp_netif_enabled,
+    esp_idf_comp_esp_event_enabled
+))]
+pub mod arp;
+#[cfg(feature = "std")]
+pub mod audio;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_nvs_flash_enabled,
+    esp_idf_comp_esp_timer_enabled
+))]
+pub mod boot_counter;
+#[cfg(all(feature = "alloc", any(esp32, esp32s2, esp32s3, esp32c3)))]
+pub mod brownout;
+#[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+pub mod captive;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_nvs_flash_enabled,
+    esp_idf_comp_app_update_enabled,
+    esp_idf_comp_spi_flash_enabled
+))]
+pub mod config_rollback;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_esp_netif_enabled,
+    esp_idf_comp_esp_event_enabled
+))]
+pub mod connectivity;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_nvs_flash_enabled,
+    esp_idf_comp_esp_timer_enabled
+))]
+pub mod counters;
+#[cfg(all(feature = "crypto", esp_idf_comp_mbedtls_enabled))]
+pub mod crypto;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_lwip_enabled,
+    esp_idf_comp_esp_netif_enabled,
+    esp_idf_comp_esp_event_enabled
+))]
+pub mod dhcp;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_lwip_enabled,
+    esp_idf_comp_esp_netif_enabled
+))]
+pub mod dns;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_wpa_supplicant_enabled,
+    esp_idf_comp_esp_wifi_enabled,
+    esp_idf_comp_esp_event_enabled,
+    esp_idf_wpa_dpp_support,
+))]
+pub mod dpp;
+#[cfg(all(feature = "embassy", esp_idf_comp_esp_timer_enabled))]
+pub mod embassy;
 pub mod errors;
 #[cfg(all(
     feature = "alloc",
@@ -32,6 +122,14 @@ pub mod errors;
     esp_idf_comp_esp_event_enabled,
 ))]
 pub mod espnow;
+#[cfg(all(
+    feature = "std",
+    feature = "experimental",
+    esp_idf_comp_esp_http_client_enabled,
+    esp_idf_comp_mbedtls_enabled,
+    esp_idf_comp_nvs_flash_enabled
+))]
+pub mod est;
 #[cfg(all(
     feature = "alloc",
     esp_idf_comp_esp_eth_enabled,
@@ -49,15 +147,54 @@ pub mod espnow;
 pub mod eth;
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_event_enabled))]
 pub mod eventloop;
+#[cfg(all(
+    feature = "experimental",
+    feature = "alloc",
+    esp_idf_comp_nvs_flash_enabled,
+    esp_idf_comp_app_update_enabled,
+    esp_idf_comp_spi_flash_enabled
+))]
+pub mod factory_reset;
+#[cfg(all(feature = "std", esp_idf_comp_esp_event_enabled))]
+pub mod gps;
 pub mod handle;
+pub mod heap;
 #[cfg(all(feature = "experimental", feature = "alloc"))]
 pub mod http;
 #[cfg(all(feature = "std", esp_idf_comp_esp_http_server_enabled))]
 pub mod httpd;
+#[cfg(all(feature = "std", esp_idf_comp_esp_event_enabled))]
+pub mod identify;
+#[cfg(all(feature = "std", esp_idf_comp_esp_event_enabled))]
+pub mod input;
+#[cfg(feature = "insights")]
+pub mod insights;
+#[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+pub mod iperf;
+#[cfg(feature = "jsonrpc")]
+pub mod jsonrpc;
+#[cfg(all(feature = "jwt", esp_idf_comp_mbedtls_enabled))]
+pub mod jwt;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_lwip_enabled,
+    esp_idf_comp_esp_netif_enabled
+))]
+pub mod llmnr;
 #[cfg(feature = "alloc")]
 pub mod log;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_mdns_enabled,
+    esp_idf_comp_nvs_flash_enabled
+))]
+pub mod matter;
 #[cfg(all(feature = "alloc", esp_idf_comp_mdns_enabled))]
 pub mod mdns;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+pub mod modbus;
 #[cfg(all(
     feature = "alloc",
     esp_idf_comp_mqtt_enabled,
@@ -68,6 +205,8 @@ pub mod mqtt;
 pub mod napt;
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_netif_enabled))]
 pub mod netif;
+#[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+pub mod netstat;
 #[cfg(all(feature = "experimental", feature = "alloc"))]
 pub mod notify;
 #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
@@ -78,20 +217,125 @@ pub mod nvs;
     esp_idf_comp_spi_flash_enabled
 ))]
 pub mod ota;
+#[cfg(all(
+    feature = "experimental",
+    feature = "alloc",
+    esp_idf_comp_app_update_enabled,
+    esp_idf_comp_spi_flash_enabled,
+    esp_idf_comp_esp_http_client_enabled,
+    esp_idf_comp_nvs_flash_enabled,
+    esp_idf_comp_mbedtls_enabled
+))]
+pub mod ota_bundle;
+#[cfg(all(
+    feature = "experimental",
+    feature = "std",
+    esp_idf_comp_app_update_enabled,
+    esp_idf_comp_spi_flash_enabled,
+    esp_idf_comp_esp_http_client_enabled,
+    esp_idf_comp_esp_event_enabled
+))]
+pub mod ota_scheduler;
+#[cfg(all(
+    feature = "experimental",
+    feature = "alloc",
+    esp_idf_comp_mdns_enabled,
+    esp_idf_comp_esp_http_server_enabled,
+    esp_idf_comp_nvs_flash_enabled,
+    esp_idf_comp_esp_event_enabled,
+    esp_idf_comp_esp_timer_enabled
+))]
+pub mod pairing;
 #[cfg(esp_idf_comp_esp_netif_enabled)]
 pub mod ping;
+#[cfg(all(
+    feature = "experimental",
+    feature = "std",
+    esp_idf_comp_esp_wifi_enabled,
+    esp_idf_comp_esp_event_enabled,
+    esp_idf_comp_esp_http_server_enabled,
+    esp_idf_comp_nvs_flash_enabled,
+    esp_idf_comp_lwip_enabled
+))]
+pub mod portal;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_esp_event_enabled,
+    any(esp_idf_comp_esp_adc_cal_enabled, esp_idf_comp_esp_adc_enabled)
+))]
+pub mod power;
+#[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+pub mod ptp;
+pub mod random;
+#[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+pub mod rtsp;
+#[cfg(all(
+    feature = "std",
+    feature = "experimental",
+    esp_idf_comp_mqtt_enabled,
+    esp_idf_comp_esp_event_enabled,
+    esp_idf_comp_esp_http_client_enabled,
+    esp_idf_comp_nvs_flash_enabled
+))]
+pub mod rules;
+#[cfg(all(feature = "std", esp_idf_comp_nvs_flash_enabled))]
+pub mod scheduler;
+pub mod sendable;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_lwip_enabled,
+    esp_idf_comp_esp_netif_enabled,
+    esp_idf_comp_esp_timer_enabled
+))]
+pub mod snmp;
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_netif_enabled))]
 pub mod sntp;
+#[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+pub mod sntp_server;
+#[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+pub mod socks5;
+#[cfg(feature = "std")]
+pub mod supervisor;
+#[cfg(all(feature = "std", esp_idf_comp_esp_event_enabled))]
+pub mod system;
 pub mod systime;
+#[cfg(all(feature = "std", esp_idf_comp_lwip_enabled))]
+pub mod tcp;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_timer_enabled))]
 pub mod timer;
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_timer_enabled))]
+pub mod timesync;
 pub mod tls;
+#[cfg(all(feature = "std", esp_idf_comp_esp_timer_enabled))]
+pub mod trace;
+#[cfg(feature = "traffic")]
+pub mod traffic;
+#[cfg(feature = "std")]
+pub mod twai;
+#[cfg(feature = "std")]
+pub mod uart;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_nvs_flash_enabled,
+    esp_idf_comp_mbedtls_enabled
+))]
+pub mod vault;
 #[cfg(all(
     feature = "alloc",
     esp_idf_comp_esp_wifi_enabled,
     esp_idf_comp_esp_event_enabled,
 ))]
 pub mod wifi;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_esp_wifi_enabled,
+    esp_idf_comp_esp_event_enabled,
+))]
+pub mod wifi_metrics;
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_wireguard_enabled))]
+pub mod wireguard;
 pub mod ws;
 
 mod private;
@@ -0,0 +1,408 @@
+//! LLMNR and NetBIOS name service responders
+//!
+//! Tiny responders answering the Link-Local Multicast Name Resolution (RFC 4795)
+//! and NetBIOS name (RFC 1002) queries for the hostname of a network interface,
+//! so that the device can be reached by its name from Windows machines
+//! on the LAN which do not speak mDNS.
+//!
+//! The hostname and the address are read from the interface on every query, so
+//! the responders follow `NetifConfiguration` hostname and DHCP address changes.
+//! Only IPv4 is supported.
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::string::String;
+use std::sync::Arc;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::netif::Interface;
+use crate::private::cstr::*;
+
+const LLMNR_PORT: u16 = 5355;
+const LLMNR_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 252);
+
+const NBNS_PORT: u16 = 137;
+
+const HEADER_LEN: usize = 12;
+
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const TYPE_NB: u16 = 0x20;
+const CLASS_IN: u16 = 1;
+
+/// The length of an encoded NetBIOS name: a length byte, 32 characters and the root label
+const NETBIOS_NAME_LEN: usize = 34;
+
+#[derive(Clone, Debug)]
+pub struct Configuration {
+    /// The interface the hostname and the address are taken from
+    pub interface: Interface,
+    /// The name to answer for instead of the hostname of the interface
+    pub hostname: Option<String>,
+    pub llmnr: bool,
+    pub nbns: bool,
+    pub ttl: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            interface: Interface::Sta,
+            hostname: None,
+            llmnr: true,
+            nbns: true,
+            ttl: Duration::from_secs(30),
+            stack_size: 4096,
+        }
+    }
+}
+
+pub struct EspNameResponder {
+    stop: Arc<AtomicBool>,
+    join_handles: Vec<thread::JoinHandle<()>>,
+}
+
+impl EspNameResponder {
+    /// Starts the enabled responders
+    ///
+    /// The LLMNR multicast group is joined on the address the interface has at that point,
+    /// so the interface should preferably be up already.
+    pub fn new(conf: &Configuration) -> Result<Self, EspError> {
+        let mut responder = Self {
+            stop: Arc::new(AtomicBool::new(false)),
+            join_handles: Vec::new(),
+        };
+
+        if conf.llmnr {
+            let socket = Self::bind(LLMNR_PORT).and_then(|socket| {
+                let ip = Self::identity(conf)
+                    .map(|(_, ip)| ip)
+                    .unwrap_or(Ipv4Addr::UNSPECIFIED);
+
+                socket.join_multicast_v4(&LLMNR_GROUP, &ip)?;
+
+                Ok(socket)
+            });
+
+            responder.spawn("llmnr", conf, socket, Self::answer_llmnr)?;
+        }
+
+        if conf.nbns {
+            let socket = Self::bind(NBNS_PORT).and_then(|socket| {
+                socket.set_broadcast(true)?;
+
+                Ok(socket)
+            });
+
+            responder.spawn("nbns", conf, socket, Self::answer_nbns)?;
+        }
+
+        info!(
+            "Started name responders for {:?} (LLMNR: {}, NBNS: {})",
+            conf.interface, conf.llmnr, conf.nbns
+        );
+
+        Ok(responder)
+    }
+
+    fn bind(port: u16) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))?;
+
+        // The stop flag is only checked between receives
+        socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+
+        Ok(socket)
+    }
+
+    fn spawn(
+        &mut self,
+        name: &'static str,
+        conf: &Configuration,
+        socket: io::Result<UdpSocket>,
+        answer: fn(&str, Ipv4Addr, u32, &[u8], &mut [u8]) -> Option<usize>,
+    ) -> Result<(), EspError> {
+        let socket = socket.map_err(|e| {
+            warn!("Failed to bind {} socket: {}", name, e);
+
+            EspError::from_infallible::<ESP_FAIL>()
+        })?;
+
+        let conf = conf.clone();
+        let stop = self.stop.clone();
+
+        let join_handle = thread::Builder::new()
+            .name(name.into())
+            .stack_size(conf.stack_size)
+            .spawn(move || Self::run(name, conf, socket, stop, answer))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        self.join_handles.push(join_handle);
+
+        Ok(())
+    }
+
+    fn run(
+        name: &str,
+        conf: Configuration,
+        socket: UdpSocket,
+        stop: Arc<AtomicBool>,
+        answer: fn(&str, Ipv4Addr, u32, &[u8], &mut [u8]) -> Option<usize>,
+    ) {
+        let mut request = [0_u8; 512];
+        let mut response = [0_u8; 512];
+
+        while !stop.load(Ordering::SeqCst) {
+            let (len, peer) = match socket.recv_from(&mut request) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    warn!("{} receive failed: {}", name, e);
+                    continue;
+                }
+            };
+
+            let (hostname, ip) = if let Some(identity) = Self::identity(&conf) {
+                identity
+            } else {
+                // No address to answer with (yet)
+                continue;
+            };
+
+            let ttl = conf.ttl.as_secs() as u32;
+
+            if let Some(response_len) = answer(&hostname, ip, ttl, &request[..len], &mut response) {
+                debug!("{} answered {} with {}", name, peer, ip);
+
+                if let Err(e) = socket.send_to(&response[..response_len], peer) {
+                    warn!("{} send to {} failed: {}", name, peer, e);
+                }
+            }
+        }
+    }
+
+    /// Returns the name to answer for and the current address of the interface
+    fn identity(conf: &Configuration) -> Option<(String, Ipv4Addr)> {
+        let handle = conf.interface.handle().ok()?;
+
+        let mut ip_info: esp_netif_ip_info_t = Default::default();
+        esp!(unsafe { esp_netif_get_ip_info(handle, &mut ip_info) }).ok()?;
+
+        let ip = Ipv4Addr::from(ip_info.ip.addr.to_le_bytes());
+        if ip.is_unspecified() {
+            return None;
+        }
+
+        let hostname = if let Some(hostname) = conf.hostname.as_ref() {
+            hostname.clone()
+        } else {
+            let mut ptr: *const c_char = core::ptr::null();
+            esp!(unsafe { esp_netif_get_hostname(handle, &mut ptr) }).ok()?;
+
+            if ptr.is_null() {
+                return None;
+            }
+
+            unsafe { CStr::from_ptr(ptr) }
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        Some((hostname, ip))
+    }
+
+    /// Builds the answer to an LLMNR query for `hostname`, returning its length,
+    /// or `None` if the query is not for `hostname` or malformed
+    fn answer_llmnr(
+        hostname: &str,
+        ip: Ipv4Addr,
+        ttl: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Option<usize> {
+        // Only standard queries with a single question
+        if request.len() < HEADER_LEN
+            || request[2] & 0xf8 != 0
+            || u16::from_be_bytes([request[4], request[5]]) != 1
+        {
+            return None;
+        }
+
+        let (name, end) = Self::dns_name(request, HEADER_LEN)?;
+        if !name.eq_ignore_ascii_case(hostname) || end + 4 > request.len() {
+            return None;
+        }
+
+        let question = &request[HEADER_LEN..end + 4];
+
+        let qtype = u16::from_be_bytes([request[end], request[end + 1]]);
+        let qclass = u16::from_be_bytes([request[end + 2], request[end + 3]]);
+
+        let len = HEADER_LEN + question.len();
+        if len + 16 > response.len() {
+            return None;
+        }
+
+        response[..2].copy_from_slice(&request[..2]);
+        // QR; the conflict and tentative bits are left clear as the name is assumed unique
+        response[2] = 0x80;
+        response[3] = 0;
+        response[4..6].copy_from_slice(&1_u16.to_be_bytes());
+        response[6..HEADER_LEN].fill(0);
+        response[HEADER_LEN..len].copy_from_slice(question);
+
+        if !matches!(qtype, TYPE_A | TYPE_ANY) || qclass != CLASS_IN {
+            // The name is ours, but there is no such record
+            return Some(len);
+        }
+
+        response[7] = 1;
+
+        let answer = &mut response[len..len + 16];
+
+        // Pointer to the name in the question
+        answer[..2].copy_from_slice(&[0xc0, HEADER_LEN as u8]);
+        answer[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        answer[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        answer[6..10].copy_from_slice(&ttl.to_be_bytes());
+        answer[10..12].copy_from_slice(&4_u16.to_be_bytes());
+        answer[12..16].copy_from_slice(&ip.octets());
+
+        Some(len + 16)
+    }
+
+    /// Builds the answer to a NetBIOS name query for `hostname`, returning its length,
+    /// or `None` if the query is not for `hostname` or malformed
+    fn answer_nbns(
+        hostname: &str,
+        ip: Ipv4Addr,
+        ttl: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Option<usize> {
+        let end = HEADER_LEN + NETBIOS_NAME_LEN;
+
+        // Only name queries (opcode 0) with a single question
+        if request.len() < end + 4
+            || request[2] & 0xf8 != 0
+            || u16::from_be_bytes([request[4], request[5]]) != 1
+            || request[HEADER_LEN] != 32
+            || request[end - 1] != 0
+        {
+            return None;
+        }
+
+        let qtype = u16::from_be_bytes([request[end], request[end + 1]]);
+        let qclass = u16::from_be_bytes([request[end + 2], request[end + 3]]);
+        if qtype != TYPE_NB || qclass != CLASS_IN {
+            return None;
+        }
+
+        let mut name = [0_u8; 16];
+        for (index, byte) in name.iter_mut().enumerate() {
+            let high = request[HEADER_LEN + 1 + index * 2].wrapping_sub(b'A');
+            let low = request[HEADER_LEN + 2 + index * 2].wrapping_sub(b'A');
+
+            if high > 0x0f || low > 0x0f {
+                return None;
+            }
+
+            *byte = (high << 4) | low;
+        }
+
+        // The workstation and the server service suffixes
+        if !matches!(name[15], 0x00 | 0x20) {
+            return None;
+        }
+
+        let requested = core::str::from_utf8(&name[..15])
+            .ok()?
+            .trim_end_matches(' ');
+
+        // NetBIOS names are at most 15 characters long, and case-insensitive
+        let hostname = hostname.get(..15).unwrap_or(hostname);
+        if requested.is_empty() || !requested.eq_ignore_ascii_case(hostname) {
+            return None;
+        }
+
+        let len = end + 16;
+        if len > response.len() {
+            return None;
+        }
+
+        response[..2].copy_from_slice(&request[..2]);
+        // Response, authoritative answer, recursion desired
+        response[2..4].copy_from_slice(&0x8500_u16.to_be_bytes());
+        response[4..6].fill(0);
+        response[6..8].copy_from_slice(&1_u16.to_be_bytes());
+        response[8..HEADER_LEN].fill(0);
+        response[HEADER_LEN..end].copy_from_slice(&request[HEADER_LEN..end]);
+
+        let answer = &mut response[end..len];
+
+        answer[..2].copy_from_slice(&TYPE_NB.to_be_bytes());
+        answer[2..4].copy_from_slice(&CLASS_IN.to_be_bytes());
+        answer[4..8].copy_from_slice(&ttl.to_be_bytes());
+        answer[8..10].copy_from_slice(&6_u16.to_be_bytes());
+        // B-node, unique name
+        answer[10..12].fill(0);
+        answer[12..16].copy_from_slice(&ip.octets());
+
+        Some(len)
+    }
+
+    /// Decodes the uncompressed DNS name at `offset`, returning it together
+    /// with the offset just after it
+    fn dns_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+        let mut name = String::new();
+
+        loop {
+            let len = *packet.get(offset)? as usize;
+            offset += 1;
+
+            if len == 0 {
+                break;
+            }
+
+            if len & 0xc0 != 0 || offset + len > packet.len() {
+                return None;
+            }
+
+            if !name.is_empty() {
+                name.push('.');
+            }
+
+            name.push_str(core::str::from_utf8(&packet[offset..offset + len]).ok()?);
+
+            offset += len;
+        }
+
+        Some((name, offset))
+    }
+}
+
+impl Drop for EspNameResponder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        for join_handle in self.join_handles.drain(..) {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
@@ -171,6 +171,9 @@ impl ::log::Log for EspLogger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) && Self::should_log(record) {
+            #[cfg(feature = "insights")]
+            crate::insights::capture(record);
+
             if let Some(color) = Self::get_color(record.level()) {
                 writeln!(
                     EspStdout,
@@ -0,0 +1,458 @@
+//! Matter commissioning groundwork
+//!
+//! Building blocks for experimenting with Matter (formerly CHIP) devices:
+//! - The onboarding payloads (the QR code and the manual pairing code) derived
+//!   from the setup passcode and the discriminator
+//! - The DNS-SD advertisement of a commissionable node on the WiFi/Ethernet
+//!   network, using `EspMdns`
+//! - The storage of the operational credentials of the commissioned fabrics in NVS
+//!
+//! The BLE commissioning advertisement and the PASE (SPAKE2+) session setup are not
+//! provided: this crate has no BLE service to build the former on, and the latter needs
+//! a SPAKE2+ implementation, which is best taken from a complete Matter stack.
+//! Until then, commissioners have to find the device on the network, with the
+//! `OnNetwork` rendezvous.
+use core::convert::TryInto;
+use core::fmt::Write;
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::mdns::EspMdns;
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+
+/// The UDP port of the Matter operational and commissioning protocols
+pub const MATTER_PORT: u16 = 5540;
+
+const COMMISSIONABLE_SERVICE: &str = "_matterc";
+const PROTO: &str = "_udp";
+
+const BASE38_ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ-.";
+
+/// The passcodes which are too trivial to be used, as per the specification
+const INVALID_PASSCODES: &[u32] = &[
+    0, 11111111, 22222222, 33333333, 44444444, 55555555, 66666666, 77777777, 88888888, 99999999,
+    12345678, 87654321,
+];
+
+/// How the commissioner can reach the device for commissioning
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct Rendezvous {
+    pub soft_ap: bool,
+    pub ble: bool,
+    pub on_network: bool,
+}
+
+impl Rendezvous {
+    fn bits(&self) -> u8 {
+        self.soft_ap as u8 | (self.ble as u8) << 1 | (self.on_network as u8) << 2
+    }
+}
+
+impl Default for Rendezvous {
+    fn default() -> Self {
+        Self {
+            soft_ap: false,
+            ble: false,
+            on_network: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CommissioningConfiguration {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// The 12-bit discriminator, used to tell apart devices being commissioned together
+    pub discriminator: u16,
+    /// The 27-bit setup passcode
+    pub passcode: u32,
+    pub rendezvous: Rendezvous,
+    /// The primary device type, advertised if set
+    pub device_type: Option<u32>,
+    /// The device name, advertised if set
+    pub device_name: Option<String>,
+    /// The pairing hint bitmap; 0x21 (power cycle or see the manual) if unsure
+    pub pairing_hint: u16,
+}
+
+impl Default for CommissioningConfiguration {
+    fn default() -> Self {
+        // The test vendor and product, and the test passcode and discriminator
+        // of the Matter SDK examples
+        Self {
+            vendor_id: 0xfff1,
+            product_id: 0x8000,
+            discriminator: 3840,
+            passcode: 20202021,
+            rendezvous: Default::default(),
+            device_type: None,
+            device_name: None,
+            pairing_hint: 0x21,
+        }
+    }
+}
+
+impl CommissioningConfiguration {
+    fn check(&self) -> Result<(), EspError> {
+        if self.discriminator > 0xfff
+            || self.passcode > 99999998
+            || INVALID_PASSCODES.contains(&self.passcode)
+        {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the QR code payload, as in `MT:Y.K9042C00KA0648G00`
+    pub fn qr_code(&self) -> Result<String, EspError> {
+        self.check()?;
+
+        // Version 0, the standard commissioning flow, and no TLV data
+        let mut bits = BitWriter::default();
+        bits.write(0, 3);
+        bits.write(self.vendor_id as u64, 16);
+        bits.write(self.product_id as u64, 16);
+        bits.write(0, 2);
+        bits.write(self.rendezvous.bits() as u64, 8);
+        bits.write(self.discriminator as u64, 12);
+        bits.write(self.passcode as u64, 27);
+        bits.write(0, 4);
+
+        let mut payload = String::from("MT:");
+
+        for chunk in bits.bytes.chunks(3) {
+            let (mut value, digits) = match chunk.len() {
+                3 => (
+                    chunk[0] as u32 | (chunk[1] as u32) << 8 | (chunk[2] as u32) << 16,
+                    5,
+                ),
+                2 => (chunk[0] as u32 | (chunk[1] as u32) << 8, 4),
+                _ => (chunk[0] as u32, 2),
+            };
+
+            for _ in 0..digits {
+                payload.push(BASE38_ALPHABET[(value % 38) as usize] as char);
+                value /= 38;
+            }
+        }
+
+        Ok(payload)
+    }
+
+    /// Returns the 11-digit manual pairing code, as in `34970112332`
+    pub fn manual_pairing_code(&self) -> Result<String, EspError> {
+        self.check()?;
+
+        let short_discriminator = self.discriminator >> 8;
+
+        let mut code = String::new();
+
+        write!(
+            &mut code,
+            "{}{:05}{:04}",
+            short_discriminator >> 2,
+            (short_discriminator as u32 & 0x3) << 14 | (self.passcode & 0x3fff),
+            self.passcode >> 14
+        )
+        .unwrap();
+
+        code.push(verhoeff_check_digit(&code));
+
+        Ok(code)
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    /// Appends the `count` lowest bits of `value`, least significant bit first
+    fn write(&mut self, value: u64, count: usize) {
+        for bit in 0..count {
+            if self.len % 8 == 0 {
+                self.bytes.push(0);
+            }
+
+            if value & (1 << bit) != 0 {
+                *self.bytes.last_mut().unwrap() |= 1 << (self.len % 8);
+            }
+
+            self.len += 1;
+        }
+    }
+}
+
+fn verhoeff_check_digit(digits: &str) -> char {
+    const D: [[u8; 10]; 10] = [
+        [0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+        [1, 2, 3, 4, 0, 6, 7, 8, 9, 5],
+        [2, 3, 4, 0, 1, 7, 8, 9, 5, 6],
+        [3, 4, 0, 1, 2, 8, 9, 5, 6, 7],
+        [4, 0, 1, 2, 3, 9, 5, 6, 7, 8],
+        [5, 9, 8, 7, 6, 0, 4, 3, 2, 1],
+        [6, 5, 9, 8, 7, 1, 0, 4, 3, 2],
+        [7, 6, 5, 9, 8, 2, 1, 0, 4, 3],
+        [8, 7, 6, 5, 9, 3, 2, 1, 0, 4],
+        [9, 8, 7, 6, 5, 4, 3, 2, 1, 0],
+    ];
+
+    const P: [[u8; 10]; 8] = [
+        [0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+        [1, 5, 7, 6, 2, 8, 3, 0, 9, 4],
+        [5, 8, 0, 3, 7, 9, 6, 1, 4, 2],
+        [8, 9, 1, 6, 0, 4, 3, 5, 2, 7],
+        [9, 4, 5, 3, 1, 2, 6, 8, 7, 0],
+        [4, 2, 8, 6, 5, 7, 3, 9, 0, 1],
+        [2, 7, 9, 3, 8, 0, 6, 4, 1, 5],
+        [7, 0, 4, 6, 9, 1, 3, 2, 5, 8],
+    ];
+
+    const INV: [u8; 10] = [0, 4, 3, 2, 1, 5, 6, 7, 8, 9];
+
+    let check = digits
+        .bytes()
+        .rev()
+        .enumerate()
+        .fold(0, |check, (i, digit)| {
+            D[check as usize][P[(i + 1) % 8][(digit - b'0') as usize] as usize]
+        });
+
+    (b'0' + INV[check as usize]) as char
+}
+
+/// Advertises the device as a commissionable node over DNS-SD
+///
+/// The advertisement is removed when dropped, which should be done once
+/// the device is commissioned or the commissioning window is closed.
+pub struct EspCommissionableAdvertisement<'a> {
+    mdns: &'a mut EspMdns,
+}
+
+impl<'a> EspCommissionableAdvertisement<'a> {
+    pub fn new(mdns: &'a mut EspMdns, conf: &CommissioningConfiguration) -> Result<Self, EspError> {
+        conf.check()?;
+
+        let mut instance_id = [0_u8; 8];
+        unsafe { esp_fill_random(instance_id.as_mut_ptr() as *mut _, instance_id.len() as _) };
+
+        let instance_name = instance_id
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<String>();
+
+        let discriminator = conf.discriminator.to_string();
+        let vendor_product = format!("{}+{}", conf.vendor_id, conf.product_id);
+        let device_type = conf.device_type.map(|device_type| device_type.to_string());
+        let pairing_hint = conf.pairing_hint.to_string();
+
+        let mut txt = vec![
+            ("D", discriminator.as_str()),
+            ("VP", vendor_product.as_str()),
+            // Commissioning mode: open with the passcode
+            ("CM", "1"),
+            ("PH", pairing_hint.as_str()),
+        ];
+
+        if let Some(device_type) = &device_type {
+            txt.push(("DT", device_type));
+        }
+
+        if let Some(device_name) = &conf.device_name {
+            txt.push(("DN", device_name));
+        }
+
+        mdns.add_service(
+            Some(&instance_name),
+            COMMISSIONABLE_SERVICE,
+            PROTO,
+            MATTER_PORT,
+            &txt,
+        )?;
+
+        info!(
+            "Advertising Matter commissionable node {} with discriminator {}",
+            instance_name, conf.discriminator
+        );
+
+        Ok(Self { mdns })
+    }
+}
+
+impl<'a> Drop for EspCommissionableAdvertisement<'a> {
+    fn drop(&mut self) {
+        let _ = self.mdns.remove_service(COMMISSIONABLE_SERVICE, PROTO);
+
+        info!("Dropped");
+    }
+}
+
+/// The operational credentials of the device on a fabric
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Fabric {
+    /// The fabric index, from 1 to 254
+    pub index: u8,
+    pub fabric_id: u64,
+    pub node_id: u64,
+    pub vendor_id: u16,
+    /// The root certificate (RCAC), in the Matter TLV certificate encoding
+    pub root_certificate: Vec<u8>,
+    /// The intermediate certificate (ICAC), if the fabric uses one
+    pub intermediate_certificate: Option<Vec<u8>>,
+    /// The node operational certificate (NOC)
+    pub operational_certificate: Vec<u8>,
+    /// The identity protection key epoch key
+    pub ipk: [u8; 16],
+    pub label: String,
+}
+
+/// The operational credentials of all fabrics the device is commissioned into,
+/// persisted in an NVS namespace
+///
+/// The operational private key is not handled here, as it should rather be
+/// kept in a secure element, or at least in an encrypted NVS partition.
+pub struct EspFabricStore<T: NvsPartitionId>(EspNvs<T>);
+
+impl<T: NvsPartitionId> EspFabricStore<T> {
+    const INDICES: &'static str = "fabrics";
+
+    pub fn new(partition: EspNvsPartition<T>, namespace: &str) -> Result<Self, EspError> {
+        Ok(Self(EspNvs::new(partition, namespace, true)?))
+    }
+
+    /// Returns the indices of the stored fabrics
+    pub fn indices(&self) -> Result<Vec<u8>, EspError> {
+        Ok(self.get(Self::INDICES)?.unwrap_or_default())
+    }
+
+    pub fn load(&self, index: u8) -> Result<Option<Fabric>, EspError> {
+        let ids = if let Some(ids) = self.get(&Self::key(index, "ids"))? {
+            ids
+        } else {
+            return Ok(None);
+        };
+
+        let ipk = self.get(&Self::key(index, "ipk"))?.unwrap_or_default();
+
+        if ids.len() != 18 || ipk.len() != 16 {
+            warn!("Corrupted Matter fabric {}", index);
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let label = self.get(&Self::key(index, "label"))?.unwrap_or_default();
+
+        Ok(Some(Fabric {
+            index,
+            fabric_id: u64::from_le_bytes(ids[..8].try_into().unwrap()),
+            node_id: u64::from_le_bytes(ids[8..16].try_into().unwrap()),
+            vendor_id: u16::from_le_bytes([ids[16], ids[17]]),
+            root_certificate: self.get(&Self::key(index, "rcac"))?.unwrap_or_default(),
+            intermediate_certificate: self.get(&Self::key(index, "icac"))?,
+            operational_certificate: self.get(&Self::key(index, "noc"))?.unwrap_or_default(),
+            ipk: ipk.try_into().unwrap(),
+            label: String::from_utf8_lossy(&label).into_owned(),
+        }))
+    }
+
+    /// Stores the fabric, replacing any fabric with the same index
+    pub fn store(&mut self, fabric: &Fabric) -> Result<(), EspError> {
+        if fabric.index == 0 || fabric.index == 255 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let index = fabric.index;
+
+        let mut ids = Vec::with_capacity(18);
+        ids.extend_from_slice(&fabric.fabric_id.to_le_bytes());
+        ids.extend_from_slice(&fabric.node_id.to_le_bytes());
+        ids.extend_from_slice(&fabric.vendor_id.to_le_bytes());
+
+        self.0
+            .set_blob(&Self::key(index, "rcac"), &fabric.root_certificate)?;
+
+        if let Some(icac) = &fabric.intermediate_certificate {
+            self.0.set_blob(&Self::key(index, "icac"), icac)?;
+        } else {
+            self.0.remove(&Self::key(index, "icac"))?;
+        }
+
+        self.0
+            .set_blob(&Self::key(index, "noc"), &fabric.operational_certificate)?;
+        self.0.set_blob(&Self::key(index, "ipk"), &fabric.ipk)?;
+        self.0
+            .set_blob(&Self::key(index, "label"), fabric.label.as_bytes())?;
+
+        // Written last, so that a fabric is only listed once complete
+        self.0.set_blob(&Self::key(index, "ids"), &ids)?;
+
+        let mut indices = self.indices()?;
+        if !indices.contains(&index) {
+            indices.push(index);
+            self.0.set_blob(Self::INDICES, &indices)?;
+        }
+
+        info!("Stored Matter fabric {}", index);
+
+        Ok(())
+    }
+
+    /// Removes the fabric, returning `false` if it was not stored
+    pub fn remove(&mut self, index: u8) -> Result<bool, EspError> {
+        let mut indices = self.indices()?;
+
+        let found = indices.contains(&index);
+        if found {
+            indices.retain(|other| *other != index);
+            self.0.set_blob(Self::INDICES, &indices)?;
+        }
+
+        for name in ["ids", "rcac", "icac", "noc", "ipk", "label"] {
+            self.0.remove(&Self::key(index, name))?;
+        }
+
+        Ok(found)
+    }
+
+    /// Removes all fabrics, as done by a factory reset
+    pub fn clear(&mut self) -> Result<(), EspError> {
+        for index in self.indices()? {
+            self.remove(index)?;
+        }
+
+        Ok(())
+    }
+
+    fn key(index: u8, name: &str) -> String {
+        format!("f{}.{}", index, name)
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, EspError> {
+        let len = if let Some(len) = self.0.blob_len(name)? {
+            len
+        } else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0; len];
+        let len = self.0.get_blob(name, &mut buf)?.map(|blob| blob.len());
+
+        Ok(len.map(|len| {
+            buf.truncate(len);
+            buf
+        }))
+    }
+}
@@ -18,19 +18,33 @@ use crate::private::cstr::{CStr, CString};
 use crate::private::mutex::{Mutex, RawMutex};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Interface {
     STA,
     AP,
     ETH,
 }
 
+#[cfg(not(esp_idf_version_major = "4"))]
+impl From<Interface> for crate::netif::Interface {
+    fn from(interface: Interface) -> Self {
+        match interface {
+            Interface::STA => Self::Sta,
+            Interface::AP => Self::Ap,
+            Interface::ETH => Self::Eth,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Protocol {
     V4,
     V6,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Type {
     A = MDNS_TYPE_A as _,
     AAAA = MDNS_TYPE_AAAA as _,
@@ -313,6 +327,73 @@ impl EspMdns {
         esp!(unsafe { mdns_service_remove_all() })
     }
 
+    /// Enables or disables the responder on the given interface, so that the device
+    /// is only advertised on (and answers queries from) the networks it should be visible on
+    ///
+    /// The interface has to be created already. Note that the responder re-enables
+    /// the interfaces predefined in the sdkconfig (`CONFIG_MDNS_PREDEF_NETIF_*`)
+    /// whenever they get an IP, so these should be turned off for interfaces
+    /// which are to stay disabled.
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub fn set_interface_enabled(
+        &mut self,
+        interface: impl Into<crate::netif::Interface>,
+        enabled: bool,
+    ) -> Result<(), EspError> {
+        let interface = interface.into();
+        let handle = interface.handle()?;
+
+        let (ip4, ip6) = if enabled {
+            match esp!(unsafe { mdns_register_netif(handle) }) {
+                // Already registered, e.g. because it is a predefined interface
+                Err(e) if e.code() == ESP_ERR_INVALID_STATE as esp_err_t => (),
+                result => result?,
+            }
+
+            (
+                mdns_event_actions_t_MDNS_EVENT_ENABLE_IP4,
+                mdns_event_actions_t_MDNS_EVENT_ENABLE_IP6,
+            )
+        } else {
+            (
+                mdns_event_actions_t_MDNS_EVENT_DISABLE_IP4,
+                mdns_event_actions_t_MDNS_EVENT_DISABLE_IP6,
+            )
+        };
+
+        esp!(unsafe { mdns_netif_action(handle, Self::actions(ip4, ip6)) })?;
+
+        if enabled {
+            // Announce right away, rather than on the next IP change
+            esp!(unsafe {
+                mdns_netif_action(
+                    handle,
+                    Self::actions(
+                        mdns_event_actions_t_MDNS_EVENT_ANNOUNCE_IP4,
+                        mdns_event_actions_t_MDNS_EVENT_ANNOUNCE_IP6,
+                    ),
+                )
+            })?;
+        }
+
+        info!(
+            "MDNS responder {} on {:?}",
+            if enabled { "enabled" } else { "disabled" },
+            interface
+        );
+
+        Ok(())
+    }
+
+    #[cfg(not(esp_idf_version_major = "4"))]
+    fn actions(ip4: mdns_event_actions_t, ip6: mdns_event_actions_t) -> mdns_event_actions_t {
+        if cfg!(esp_idf_lwip_ipv6) {
+            ip4 | ip6
+        } else {
+            ip4
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn query(
         &self,
@@ -323,6 +404,75 @@ impl EspMdns {
         timeout: Duration,
         max_results: usize,
         results: &mut [QueryResult],
+    ) -> Result<usize, EspError> {
+        self.query_filtered(
+            name,
+            service_type,
+            proto,
+            mdns_type,
+            timeout,
+            max_results,
+            results,
+            |_| true,
+        )
+    }
+
+    /// Same as `query`, but only returns the answers received over the given interface
+    ///
+    /// The query itself is sent on all interfaces the responder is enabled on.
+    #[allow(clippy::too_many_arguments)]
+    pub fn query_on(
+        &self,
+        interface: Interface,
+        name: Option<&str>,
+        service_type: Option<&str>,
+        proto: Option<&str>,
+        mdns_type: Type,
+        timeout: Duration,
+        max_results: usize,
+        results: &mut [QueryResult],
+    ) -> Result<usize, EspError> {
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let filter = {
+            let handle = crate::netif::Interface::from(interface).handle()?;
+
+            move |result: &mdns_result_t| result.esp_netif == handle
+        };
+
+        #[cfg(esp_idf_version_major = "4")]
+        let filter = {
+            let tcpip_if = match interface {
+                Interface::STA => mdns_if_internal_MDNS_IF_STA,
+                Interface::AP => mdns_if_internal_MDNS_IF_AP,
+                Interface::ETH => mdns_if_internal_MDNS_IF_ETH,
+            };
+
+            move |result: &mdns_result_t| result.tcpip_if == tcpip_if
+        };
+
+        self.query_filtered(
+            name,
+            service_type,
+            proto,
+            mdns_type,
+            timeout,
+            max_results,
+            results,
+            filter,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn query_filtered(
+        &self,
+        name: Option<&str>,
+        service_type: Option<&str>,
+        proto: Option<&str>,
+        mdns_type: Type,
+        timeout: Duration,
+        max_results: usize,
+        results: &mut [QueryResult],
+        filter: impl Fn(&mdns_result_t) -> bool,
     ) -> Result<usize, EspError> {
         let name = name.map(|x| CString::new(x.to_string()).unwrap());
         let service_type = service_type.map(|x| CString::new(x.to_string()).unwrap());
@@ -346,6 +496,7 @@ impl EspMdns {
         Ok(copy_query_results(
             unsafe { Box::from_raw(result) },
             results,
+            filter,
         ))
     }
 
@@ -401,6 +552,7 @@ impl EspMdns {
         Ok(copy_query_results(
             unsafe { Box::from_raw(result) },
             results,
+            |_| true,
         ))
     }
 
@@ -430,6 +582,7 @@ impl EspMdns {
         Ok(copy_query_results(
             unsafe { Box::from_raw(result) },
             results,
+            |_| true,
         ))
     }
 
@@ -458,6 +611,7 @@ impl EspMdns {
         Ok(copy_query_results(
             unsafe { Box::from_raw(result) },
             results,
+            |_| true,
         ))
     }
 }
@@ -472,14 +626,21 @@ impl Drop for EspMdns {
     }
 }
 
-fn copy_query_results(src: Box<mdns_result_t>, dst: &mut [QueryResult]) -> usize {
+fn copy_query_results(
+    src: Box<mdns_result_t>,
+    dst: &mut [QueryResult],
+    filter: impl Fn(&mdns_result_t) -> bool,
+) -> usize {
     let src = Box::into_raw(src);
     let mut p = src;
     let mut i = 0;
     while !p.is_null() && i < dst.len() {
-        dst[i] = QueryResult::from(unsafe { *p });
+        if filter(unsafe { &*p }) {
+            dst[i] = QueryResult::from(unsafe { *p });
+            i += 1;
+        }
+
         p = unsafe { (*p).next };
-        i += 1;
     }
 
     unsafe { mdns_query_results_free(src) };
@@ -0,0 +1,238 @@
+//! Metrics registry and OpenMetrics exposition
+//!
+//! A process-wide registry of counters and gauges, which the HTTP client, the MQTT client
+//! and the Wi-Fi metrics collector publish into, and which can be rendered in the
+//! OpenMetrics (Prometheus) text format, e.g. by the `handler` of an `EspHttpServer`:
+//!
+//! ```ignore
+//! server.fn_handler("/metrics", Method::Get, metrics::handler)?;
+//! ```
+//!
+//! Application metrics are declared as statics and published the same way:
+//!
+//! ```ignore
+//! static SENSOR_READS: Counter = Counter::new("sensor_reads", "Sensor reads");
+//!
+//! SENSOR_READS.inc(&[("sensor", "bme280")]);
+//! ```
+//!
+//! The heap gauges are sampled on every rendering.
+//!
+//! Note: This module requires the `metrics` cargo feature to be enabled.
+use core::fmt::Write;
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+use embedded_svc::http::server::{HandlerResult, Request};
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+use embedded_svc::io::Write as _;
+
+use esp_idf_sys::*;
+
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+use crate::http::server::EspHttpConnection;
+use crate::private::mutex::{Mutex, RawMutex};
+
+/// The content type of the rendered metrics
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+struct Family {
+    kind: Kind,
+    help: &'static str,
+    /// The values, keyed by their rendered label set
+    samples: BTreeMap<String, f64>,
+}
+
+static REGISTRY: Mutex<BTreeMap<&'static str, Family>> =
+    Mutex::wrap(RawMutex::new(), BTreeMap::new());
+
+static COLLECTORS: Mutex<Vec<fn()>> = Mutex::wrap(RawMutex::new(), Vec::new());
+
+/// A monotonically increasing value, rendered with the `_total` suffix
+#[derive(Debug)]
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self { name, help }
+    }
+
+    pub fn inc(&self, labels: &[(&str, &str)]) {
+        self.add(labels, 1);
+    }
+
+    pub fn add(&self, labels: &[(&str, &str)], value: u64) {
+        update(self.name, self.help, Kind::Counter, labels, |sample| {
+            *sample += value as f64
+        });
+    }
+}
+
+/// A value which can go up and down
+#[derive(Debug)]
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self { name, help }
+    }
+
+    pub fn set(&self, labels: &[(&str, &str)], value: f64) {
+        update(self.name, self.help, Kind::Gauge, labels, |sample| {
+            *sample = value
+        });
+    }
+
+    pub fn add(&self, labels: &[(&str, &str)], value: f64) {
+        update(self.name, self.help, Kind::Gauge, labels, |sample| {
+            *sample += value
+        });
+    }
+
+    /// Stops exposing the value with these labels, e.g. once the
+    /// entity it measures is gone
+    pub fn remove(&self, labels: &[(&str, &str)]) {
+        if let Some(family) = REGISTRY.lock().get_mut(self.name) {
+            family.samples.remove(&render_labels(labels));
+        }
+    }
+}
+
+fn update(
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    labels: &[(&str, &str)],
+    f: impl FnOnce(&mut f64),
+) {
+    let key = render_labels(labels);
+
+    let mut registry = REGISTRY.lock();
+
+    let family = registry.entry(name).or_insert_with(|| Family {
+        kind,
+        help,
+        samples: BTreeMap::new(),
+    });
+
+    f(family.samples.entry(key).or_insert(0.0));
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    let mut rendered = String::new();
+
+    for (index, (name, value)) in labels.iter().enumerate() {
+        rendered.push(if index == 0 { '{' } else { ',' });
+        rendered.push_str(name);
+        rendered.push_str("=\"");
+
+        for c in value.chars() {
+            match c {
+                '\\' => rendered.push_str("\\\\"),
+                '"' => rendered.push_str("\\\""),
+                '\n' => rendered.push_str("\\n"),
+                c => rendered.push(c),
+            }
+        }
+
+        rendered.push('"');
+    }
+
+    if !rendered.is_empty() {
+        rendered.push('}');
+    }
+
+    rendered
+}
+
+/// Registers a function which is called before every rendering, to refresh
+/// gauges which are cheaper to sample on demand than to keep up to date
+pub fn register_collector(collector: fn()) {
+    COLLECTORS.lock().push(collector);
+}
+
+/// Removes all values, e.g. after they were pushed elsewhere
+pub fn reset() {
+    REGISTRY.lock().clear();
+}
+
+/// Renders all metrics in the OpenMetrics text format
+pub fn render() -> String {
+    collect_heap();
+
+    let collectors = COLLECTORS.lock().clone();
+    for collector in collectors {
+        collector();
+    }
+
+    let registry = REGISTRY.lock();
+
+    let mut out = String::new();
+
+    for (name, family) in registry.iter() {
+        let (kind, suffix) = match family.kind {
+            Kind::Counter => ("counter", "_total"),
+            Kind::Gauge => ("gauge", ""),
+        };
+
+        writeln!(&mut out, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(&mut out, "# HELP {} {}", name, family.help).unwrap();
+
+        for (labels, value) in &family.samples {
+            writeln!(&mut out, "{}{}{} {}", name, suffix, labels, value).unwrap();
+        }
+    }
+
+    out.push_str("# EOF\n");
+
+    out
+}
+
+static HEAP_FREE: Gauge = Gauge::new("esp_heap_free_bytes", "Free heap");
+static HEAP_MIN_FREE: Gauge = Gauge::new("esp_heap_min_free_bytes", "Lowest free heap since boot");
+static HEAP_LARGEST_FREE_BLOCK: Gauge = Gauge::new(
+    "esp_heap_largest_free_block_bytes",
+    "Largest free heap block",
+);
+#[cfg(esp_idf_comp_esp_timer_enabled)]
+static UPTIME: Gauge = Gauge::new("esp_uptime_seconds", "Time since boot");
+
+fn collect_heap() {
+    HEAP_FREE.set(&[], unsafe { esp_get_free_heap_size() } as f64);
+    HEAP_MIN_FREE.set(&[], unsafe { esp_get_minimum_free_heap_size() } as f64);
+    HEAP_LARGEST_FREE_BLOCK.set(
+        &[],
+        unsafe { heap_caps_get_largest_free_block(MALLOC_CAP_8BIT) } as f64,
+    );
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    UPTIME.set(&[], unsafe { esp_timer_get_time() } as f64 / 1_000_000.0);
+}
+
+/// An `EspHttpServer` handler serving the rendered metrics
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+pub fn handler(request: Request<&mut EspHttpConnection>) -> HandlerResult {
+    let metrics = render();
+
+    request
+        .into_response(200, None, &[("Content-Type", CONTENT_TYPE)])?
+        .write_all(metrics.as_bytes())?;
+
+    Ok(())
+}
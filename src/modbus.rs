@@ -0,0 +1,939 @@
+//! Modbus TCP server and RTU master
+//!
+//! `EspModbusTcpServer` serves a register map - anything implementing
+//! `RegisterMap`, such as the table-backed `Registers` - to Modbus TCP clients,
+//! and `EspModbusRtuMaster` issues typed requests to Modbus RTU devices
+//! on a serial line driven by a `UartDriver`.
+//!
+//! Both implement the protocol natively on top of the socket and UART drivers
+//! rather than wrapping the esp-modbus component, which has no bindings in
+//! `esp-idf-sys`. The public function codes 1 to 6, 15 and 16 are supported.
+use core::cmp::min;
+use core::fmt::{self, Display, Formatter};
+use core::time::Duration;
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_hal::delay::TickType;
+use esp_idf_hal::uart::UartDriver;
+
+use esp_idf_sys::*;
+
+use crate::private::mutex::{Mutex, RawMutex};
+
+const READ_COILS: u8 = 0x01;
+const READ_DISCRETE_INPUTS: u8 = 0x02;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_COILS: u8 = 0x0f;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+const MAX_READ_BITS: u16 = 2000;
+const MAX_READ_REGISTERS: u16 = 125;
+const MAX_WRITE_BITS: u16 = 1968;
+const MAX_WRITE_REGISTERS: u16 = 123;
+
+/// The maximum length of a PDU (function code and data)
+const MAX_PDU_LEN: usize = 253;
+
+const MBAP_HEADER_LEN: usize = 7;
+
+const COIL_ON: u16 = 0xff00;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Exception {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+    ServerDeviceFailure = 4,
+    Acknowledge = 5,
+    ServerDeviceBusy = 6,
+    GatewayPathUnavailable = 10,
+    GatewayTargetFailedToRespond = 11,
+}
+
+impl Exception {
+    fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            1 => Self::IllegalFunction,
+            2 => Self::IllegalDataAddress,
+            3 => Self::IllegalDataValue,
+            4 => Self::ServerDeviceFailure,
+            5 => Self::Acknowledge,
+            6 => Self::ServerDeviceBusy,
+            10 => Self::GatewayPathUnavailable,
+            11 => Self::GatewayTargetFailedToRespond,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ModbusError {
+    /// The device answered with an exception
+    Exception(Exception),
+    /// The device did not answer in time
+    Timeout,
+    /// The response was corrupted or does not match the request
+    InvalidResponse,
+    /// The request parameters are out of the range allowed by the protocol
+    InvalidRequest,
+    /// Any other failure of the underlying driver
+    Other(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] EspError),
+}
+
+impl From<EspError> for ModbusError {
+    fn from(e: EspError) -> Self {
+        Self::Other(e)
+    }
+}
+
+impl Display for ModbusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exception(exception) => write!(f, "Modbus exception: {:?}", exception),
+            Self::Timeout => write!(f, "Modbus timeout"),
+            Self::InvalidResponse => write!(f, "Modbus invalid response"),
+            Self::InvalidRequest => write!(f, "Modbus invalid request"),
+            Self::Other(e) => write!(f, "Modbus error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ModbusError {}
+
+/// The data model served by `EspModbusTcpServer`
+///
+/// Each method handles a single item; the default implementations report
+/// the address as not existing, so implementations only need to override
+/// the tables they actually have.
+pub trait RegisterMap {
+    fn coil(&self, _address: u16) -> Result<bool, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn set_coil(&mut self, _address: u16, _value: bool) -> Result<(), Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn discrete_input(&self, _address: u16) -> Result<bool, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn holding_register(&self, _address: u16) -> Result<u16, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn set_holding_register(&mut self, _address: u16, _value: u16) -> Result<(), Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn input_register(&self, _address: u16) -> Result<u16, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+}
+
+/// A register map backed by plain tables, addressed from 0
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub coils: Vec<bool>,
+    pub discrete_inputs: Vec<bool>,
+    pub holding_registers: Vec<u16>,
+    pub input_registers: Vec<u16>,
+}
+
+impl Registers {
+    pub fn new(
+        coils: usize,
+        discrete_inputs: usize,
+        holding_registers: usize,
+        input_registers: usize,
+    ) -> Self {
+        Self {
+            coils: vec![false; coils],
+            discrete_inputs: vec![false; discrete_inputs],
+            holding_registers: vec![0; holding_registers],
+            input_registers: vec![0; input_registers],
+        }
+    }
+}
+
+fn table_get<T: Copy>(table: &[T], address: u16) -> Result<T, Exception> {
+    table
+        .get(address as usize)
+        .copied()
+        .ok_or(Exception::IllegalDataAddress)
+}
+
+fn table_set<T>(table: &mut [T], address: u16, value: T) -> Result<(), Exception> {
+    *table
+        .get_mut(address as usize)
+        .ok_or(Exception::IllegalDataAddress)? = value;
+
+    Ok(())
+}
+
+impl RegisterMap for Registers {
+    fn coil(&self, address: u16) -> Result<bool, Exception> {
+        table_get(&self.coils, address)
+    }
+
+    fn set_coil(&mut self, address: u16, value: bool) -> Result<(), Exception> {
+        table_set(&mut self.coils, address, value)
+    }
+
+    fn discrete_input(&self, address: u16) -> Result<bool, Exception> {
+        table_get(&self.discrete_inputs, address)
+    }
+
+    fn holding_register(&self, address: u16) -> Result<u16, Exception> {
+        table_get(&self.holding_registers, address)
+    }
+
+    fn set_holding_register(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+        table_set(&mut self.holding_registers, address, value)
+    }
+
+    fn input_register(&self, address: u16) -> Result<u16, Exception> {
+        table_get(&self.input_registers, address)
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TcpServerConfiguration {
+    pub port: u16,
+    /// Only requests for this unit identifier are answered; `None` answers all of them
+    pub unit_id: Option<u8>,
+    pub max_connections: usize,
+    /// Connections idle for longer than this are closed
+    pub idle_timeout: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for TcpServerConfiguration {
+    fn default() -> Self {
+        Self {
+            port: 502,
+            unit_id: None,
+            max_connections: 2,
+            idle_timeout: Duration::from_secs(60),
+            stack_size: 4096,
+        }
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    idle: Duration,
+}
+
+pub struct EspModbusTcpServer<M>
+where
+    M: RegisterMap + Send + 'static,
+{
+    registers: Arc<Mutex<M>>,
+    stop: Arc<AtomicBool>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl<M> EspModbusTcpServer<M>
+where
+    M: RegisterMap + Send + 'static,
+{
+    pub fn new(conf: &TcpServerConfiguration, registers: M) -> Result<Self, EspError> {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, conf.port))
+            .and_then(|listener| {
+                // Connections are polled from a single thread, which also checks the stop flag
+                listener.set_nonblocking(true)?;
+
+                Ok(listener)
+            })
+            .map_err(|e| {
+                warn!("Failed to bind Modbus TCP socket: {}", e);
+
+                EspError::from_infallible::<ESP_FAIL>()
+            })?;
+
+        let registers = Arc::new(Mutex::wrap(RawMutex::new(), registers));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let join_handle = {
+            let conf = conf.clone();
+            let registers = registers.clone();
+            let stop = stop.clone();
+
+            thread::Builder::new()
+                .name("modbus-tcp".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, listener, registers, stop))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        info!("Started Modbus TCP server on port {}", conf.port);
+
+        Ok(Self {
+            registers,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// Runs `f` with exclusive access to the served register map,
+    /// e.g. to update the input registers with fresh measurements
+    pub fn with_registers<R>(&self, f: impl FnOnce(&mut M) -> R) -> R {
+        f(&mut self.registers.lock())
+    }
+
+    fn run(
+        conf: TcpServerConfiguration,
+        listener: TcpListener,
+        registers: Arc<Mutex<M>>,
+        stop: Arc<AtomicBool>,
+    ) {
+        const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+        let mut clients: Vec<Client> = Vec::new();
+        let mut buf = [0_u8; MBAP_HEADER_LEN + MAX_PDU_LEN];
+
+        while !stop.load(Ordering::SeqCst) {
+            let mut active = false;
+
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    active = true;
+
+                    if clients.len() >= conf.max_connections {
+                        debug!("Rejected Modbus TCP connection from {}", peer);
+                    } else if stream.set_nonblocking(true).is_ok() {
+                        debug!("Accepted Modbus TCP connection from {}", peer);
+
+                        clients.push(Client {
+                            stream,
+                            buf: Vec::new(),
+                            idle: Duration::ZERO,
+                        });
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => warn!("Modbus TCP accept failed: {}", e),
+            }
+
+            clients.retain_mut(|client| {
+                let open = match client.stream.read(&mut buf) {
+                    Ok(0) => false,
+                    Ok(len) => {
+                        active = true;
+                        client.idle = Duration::ZERO;
+                        client.buf.extend_from_slice(&buf[..len]);
+
+                        Self::serve(&conf, &registers, client)
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        client.idle += POLL_INTERVAL;
+                        client.idle < conf.idle_timeout
+                    }
+                    Err(e) => {
+                        debug!("Modbus TCP receive failed: {}", e);
+                        false
+                    }
+                };
+
+                if !open {
+                    debug!("Closed Modbus TCP connection");
+                }
+
+                open
+            });
+
+            if !active {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    /// Answers all complete frames received on the connection, returning
+    /// `false` if the connection should be closed
+    fn serve(conf: &TcpServerConfiguration, registers: &Mutex<M>, client: &mut Client) -> bool {
+        while client.buf.len() >= MBAP_HEADER_LEN {
+            let protocol = u16::from_be_bytes([client.buf[2], client.buf[3]]);
+            let len = u16::from_be_bytes([client.buf[4], client.buf[5]]) as usize;
+
+            if protocol != 0 || len < 2 || len > MAX_PDU_LEN + 1 {
+                // Not Modbus, and there is no way to resynchronize the stream
+                return false;
+            }
+
+            if client.buf.len() < 6 + len {
+                break;
+            }
+
+            let frame: Vec<u8> = client.buf.drain(..6 + len).collect();
+            let unit_id = frame[6];
+
+            if conf.unit_id.map(|id| id != unit_id).unwrap_or(false) {
+                continue;
+            }
+
+            let mut response = Vec::with_capacity(MBAP_HEADER_LEN + MAX_PDU_LEN);
+            response.extend_from_slice(&frame[..6]);
+            response.push(unit_id);
+
+            process(
+                &mut *registers.lock(),
+                &frame[MBAP_HEADER_LEN..],
+                &mut response,
+            );
+
+            let pdu_len = (response.len() - 6) as u16;
+            response[4..6].copy_from_slice(&pdu_len.to_be_bytes());
+
+            // The responses are tiny, so the socket buffer is never full in practice
+            if let Err(e) = client.stream.write_all(&response) {
+                debug!("Modbus TCP send failed: {}", e);
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl<M> Drop for EspModbusTcpServer<M>
+where
+    M: RegisterMap + Send + 'static,
+{
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+/// Executes the request in `pdu` against `registers`, appending the response PDU to `response`
+fn process<M: RegisterMap + ?Sized>(registers: &mut M, pdu: &[u8], response: &mut Vec<u8>) {
+    let function = pdu[0];
+    let start = response.len();
+
+    response.push(function);
+
+    if let Err(exception) = execute(registers, function, &pdu[1..], response) {
+        response.truncate(start);
+        response.push(function | 0x80);
+        response.push(exception as u8);
+    }
+}
+
+fn execute<M: RegisterMap + ?Sized>(
+    registers: &mut M,
+    function: u8,
+    data: &[u8],
+    response: &mut Vec<u8>,
+) -> Result<(), Exception> {
+    let word = |offset: usize| -> Result<u16, Exception> {
+        data.get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or(Exception::IllegalDataValue)
+    };
+
+    match function {
+        READ_COILS | READ_DISCRETE_INPUTS => {
+            let address = word(0)?;
+            let count = word(2)?;
+
+            check_range(address, count, MAX_READ_BITS)?;
+
+            let mut bytes = vec![0_u8; (count as usize + 7) / 8];
+
+            for index in 0..count {
+                let value = if function == READ_COILS {
+                    registers.coil(address + index)?
+                } else {
+                    registers.discrete_input(address + index)?
+                };
+
+                if value {
+                    bytes[index as usize / 8] |= 1 << (index % 8);
+                }
+            }
+
+            response.push(bytes.len() as u8);
+            response.extend_from_slice(&bytes);
+        }
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            let address = word(0)?;
+            let count = word(2)?;
+
+            check_range(address, count, MAX_READ_REGISTERS)?;
+
+            response.push((count * 2) as u8);
+
+            for index in 0..count {
+                let value = if function == READ_HOLDING_REGISTERS {
+                    registers.holding_register(address + index)?
+                } else {
+                    registers.input_register(address + index)?
+                };
+
+                response.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        WRITE_SINGLE_COIL => {
+            let address = word(0)?;
+            let value = match word(2)? {
+                COIL_ON => true,
+                0 => false,
+                _ => return Err(Exception::IllegalDataValue),
+            };
+
+            registers.set_coil(address, value)?;
+
+            response.extend_from_slice(&data[..4]);
+        }
+        WRITE_SINGLE_REGISTER => {
+            let address = word(0)?;
+            let value = word(2)?;
+
+            registers.set_holding_register(address, value)?;
+
+            response.extend_from_slice(&data[..4]);
+        }
+        WRITE_MULTIPLE_COILS => {
+            let address = word(0)?;
+            let count = word(2)?;
+
+            check_range(address, count, MAX_WRITE_BITS)?;
+
+            let bytes = data.get(5..).ok_or(Exception::IllegalDataValue)?;
+            if data[4] as usize != (count as usize + 7) / 8 || bytes.len() != data[4] as usize {
+                return Err(Exception::IllegalDataValue);
+            }
+
+            for index in 0..count {
+                let value = bytes[index as usize / 8] & (1 << (index % 8)) != 0;
+
+                registers.set_coil(address + index, value)?;
+            }
+
+            response.extend_from_slice(&data[..4]);
+        }
+        WRITE_MULTIPLE_REGISTERS => {
+            let address = word(0)?;
+            let count = word(2)?;
+
+            check_range(address, count, MAX_WRITE_REGISTERS)?;
+
+            if data.get(4).map(|len| *len as usize) != Some(count as usize * 2)
+                || data.len() != 5 + count as usize * 2
+            {
+                return Err(Exception::IllegalDataValue);
+            }
+
+            for index in 0..count {
+                registers.set_holding_register(address + index, word(5 + index as usize * 2)?)?;
+            }
+
+            response.extend_from_slice(&data[..4]);
+        }
+        _ => return Err(Exception::IllegalFunction),
+    }
+
+    Ok(())
+}
+
+fn check_range(address: u16, count: u16, max: u16) -> Result<(), Exception> {
+    if count == 0 || count > max {
+        Err(Exception::IllegalDataValue)
+    } else if address.checked_add(count - 1).is_none() {
+        Err(Exception::IllegalDataAddress)
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RtuMasterConfiguration {
+    /// How long to wait for the response of a device
+    pub response_timeout: Duration,
+    /// The silent interval between frames; derived from the baudrate of the UART if `None`
+    pub frame_delay: Option<Duration>,
+    /// How many times a request is resent when the device does not answer or
+    /// the response is corrupted
+    pub retries: u8,
+}
+
+impl Default for RtuMasterConfiguration {
+    fn default() -> Self {
+        Self {
+            response_timeout: Duration::from_millis(500),
+            frame_delay: None,
+            retries: 1,
+        }
+    }
+}
+
+/// A Modbus RTU master
+///
+/// The UART should be configured with the baudrate and framing of the bus
+/// (and, for RS485, in the half-duplex mode driving the RTS pin);
+/// the master only issues the requests and decodes the responses.
+pub struct EspModbusRtuMaster<'d> {
+    uart: UartDriver<'d>,
+    conf: RtuMasterConfiguration,
+    frame_delay: Duration,
+}
+
+impl<'d> EspModbusRtuMaster<'d> {
+    pub fn new(uart: UartDriver<'d>, conf: &RtuMasterConfiguration) -> Result<Self, EspError> {
+        let frame_delay = if let Some(frame_delay) = conf.frame_delay {
+            frame_delay
+        } else {
+            let baudrate = uart.baudrate()?.0;
+
+            // 3.5 characters of 11 bits, but fixed above 19200 bauds as per the specification
+            if baudrate > 19200 {
+                Duration::from_micros(1750)
+            } else {
+                Duration::from_micros(38_500_000 / baudrate as u64 + 1)
+            }
+        };
+
+        Ok(Self {
+            uart,
+            conf: conf.clone(),
+            frame_delay,
+        })
+    }
+
+    pub fn uart(&self) -> &UartDriver<'d> {
+        &self.uart
+    }
+
+    pub fn release(self) -> UartDriver<'d> {
+        self.uart
+    }
+
+    pub fn read_coils(
+        &mut self,
+        unit: u8,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<bool>, ModbusError> {
+        self.read_bits(unit, READ_COILS, address, count)
+    }
+
+    pub fn read_discrete_inputs(
+        &mut self,
+        unit: u8,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<bool>, ModbusError> {
+        self.read_bits(unit, READ_DISCRETE_INPUTS, address, count)
+    }
+
+    pub fn read_holding_registers(
+        &mut self,
+        unit: u8,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, ModbusError> {
+        self.read_registers(unit, READ_HOLDING_REGISTERS, address, count)
+    }
+
+    pub fn read_input_registers(
+        &mut self,
+        unit: u8,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, ModbusError> {
+        self.read_registers(unit, READ_INPUT_REGISTERS, address, count)
+    }
+
+    /// Writes a coil; `unit` 0 broadcasts the request to all devices, none of which answers
+    pub fn write_single_coil(
+        &mut self,
+        unit: u8,
+        address: u16,
+        value: bool,
+    ) -> Result<(), ModbusError> {
+        let value = if value { COIL_ON } else { 0 };
+
+        self.write(unit, WRITE_SINGLE_COIL, address, value, &[])
+    }
+
+    pub fn write_single_register(
+        &mut self,
+        unit: u8,
+        address: u16,
+        value: u16,
+    ) -> Result<(), ModbusError> {
+        self.write(unit, WRITE_SINGLE_REGISTER, address, value, &[])
+    }
+
+    pub fn write_multiple_coils(
+        &mut self,
+        unit: u8,
+        address: u16,
+        values: &[bool],
+    ) -> Result<(), ModbusError> {
+        let count = Self::count(address, values.len(), MAX_WRITE_BITS)?;
+
+        let mut bytes = vec![0_u8; (values.len() + 7) / 8];
+        for (index, value) in values.iter().enumerate() {
+            if *value {
+                bytes[index / 8] |= 1 << (index % 8);
+            }
+        }
+
+        self.write(unit, WRITE_MULTIPLE_COILS, address, count, &bytes)
+    }
+
+    pub fn write_multiple_registers(
+        &mut self,
+        unit: u8,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), ModbusError> {
+        let count = Self::count(address, values.len(), MAX_WRITE_REGISTERS)?;
+
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+
+        self.write(unit, WRITE_MULTIPLE_REGISTERS, address, count, &bytes)
+    }
+
+    fn read_bits(
+        &mut self,
+        unit: u8,
+        function: u8,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<bool>, ModbusError> {
+        Self::count(address, count as usize, MAX_READ_BITS)?;
+
+        let byte_count = (count as usize + 7) / 8;
+
+        let mut request = [function, 0, 0, 0, 0];
+        request[1..3].copy_from_slice(&address.to_be_bytes());
+        request[3..5].copy_from_slice(&count.to_be_bytes());
+
+        let response = self.transact(unit, &request, 2 + byte_count)?;
+
+        if response[1] as usize != byte_count {
+            return Err(ModbusError::InvalidResponse);
+        }
+
+        Ok((0..count as usize)
+            .map(|index| response[2 + index / 8] & (1 << (index % 8)) != 0)
+            .collect())
+    }
+
+    fn read_registers(
+        &mut self,
+        unit: u8,
+        function: u8,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, ModbusError> {
+        Self::count(address, count as usize, MAX_READ_REGISTERS)?;
+
+        let mut request = [function, 0, 0, 0, 0];
+        request[1..3].copy_from_slice(&address.to_be_bytes());
+        request[3..5].copy_from_slice(&count.to_be_bytes());
+
+        let response = self.transact(unit, &request, 2 + count as usize * 2)?;
+
+        if response[1] as usize != count as usize * 2 {
+            return Err(ModbusError::InvalidResponse);
+        }
+
+        Ok(response[2..]
+            .chunks_exact(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .collect())
+    }
+
+    fn write(
+        &mut self,
+        unit: u8,
+        function: u8,
+        address: u16,
+        value: u16,
+        data: &[u8],
+    ) -> Result<(), ModbusError> {
+        let mut request = Vec::with_capacity(6 + data.len());
+        request.push(function);
+        request.extend_from_slice(&address.to_be_bytes());
+        request.extend_from_slice(&value.to_be_bytes());
+
+        if !data.is_empty() {
+            request.push(data.len() as u8);
+            request.extend_from_slice(data);
+        }
+
+        let response = self.transact(unit, &request, 5)?;
+
+        // Single writes echo the request, multiple writes echo the address and the count
+        if unit != 0 && response[1..5] != request[1..5] {
+            return Err(ModbusError::InvalidResponse);
+        }
+
+        Ok(())
+    }
+
+    fn count(address: u16, count: usize, max: u16) -> Result<u16, ModbusError> {
+        if count == 0 || count > max as usize || address.checked_add(count as u16 - 1).is_none() {
+            Err(ModbusError::InvalidRequest)
+        } else {
+            Ok(count as u16)
+        }
+    }
+
+    /// Sends `pdu` to `unit` and returns the PDU of its response, which is expected
+    /// to be `response_len` long unless it is an exception
+    ///
+    /// Broadcasts return an empty, zero-filled PDU of the expected length.
+    fn transact(
+        &mut self,
+        unit: u8,
+        pdu: &[u8],
+        response_len: usize,
+    ) -> Result<Vec<u8>, ModbusError> {
+        let mut frame = Vec::with_capacity(pdu.len() + 3);
+        frame.push(unit);
+        frame.extend_from_slice(pdu);
+        frame.extend_from_slice(&crc(&frame).to_le_bytes());
+
+        let mut attempt = 0;
+
+        loop {
+            let result = self.send(&frame).and_then(|_| {
+                if unit == 0 {
+                    // Broadcasts are not answered; give the devices time to process them
+                    thread::sleep(self.conf.response_timeout / 2);
+
+                    Ok(vec![0; response_len])
+                } else {
+                    self.receive(unit, pdu[0], response_len)
+                }
+            });
+
+            match result {
+                Err(ModbusError::Timeout) | Err(ModbusError::InvalidResponse)
+                    if attempt < self.conf.retries =>
+                {
+                    attempt += 1;
+                    debug!("Retrying Modbus RTU request to unit {}", unit);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), ModbusError> {
+        thread::sleep(self.frame_delay);
+
+        // Drop the remains of any previous late or unsolicited response
+        self.uart.flush_read()?;
+
+        let mut offset = 0;
+        while offset < frame.len() {
+            offset += self.uart.write(&frame[offset..])?;
+        }
+
+        Ok(())
+    }
+
+    fn receive(
+        &mut self,
+        unit: u8,
+        function: u8,
+        response_len: usize,
+    ) -> Result<Vec<u8>, ModbusError> {
+        // Unit, function code and data (only the exception code for an exception), and the CRC
+        let mut frame = vec![0_u8; 1 + response_len + 2];
+
+        self.read_exact(&mut frame[..3], self.conf.response_timeout)?;
+
+        let len = if frame[1] == function | 0x80 {
+            5
+        } else {
+            frame.len()
+        };
+
+        let inter_char_timeout = self.frame_delay * 4 + Duration::from_millis(10);
+        self.read_exact(
+            &mut frame[3..len],
+            min(inter_char_timeout, self.conf.response_timeout),
+        )?;
+
+        let frame = &frame[..len];
+
+        if crc(&frame[..len - 2]).to_le_bytes() != frame[len - 2..] {
+            warn!("Modbus RTU response with an invalid CRC from unit {}", unit);
+            return Err(ModbusError::InvalidResponse);
+        }
+
+        if frame[0] != unit || frame[1] & 0x7f != function {
+            return Err(ModbusError::InvalidResponse);
+        }
+
+        if frame[1] & 0x80 != 0 {
+            return Err(Exception::from_code(frame[2])
+                .map(ModbusError::Exception)
+                .unwrap_or(ModbusError::InvalidResponse));
+        }
+
+        Ok(frame[1..len - 2].to_vec())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8], timeout: Duration) -> Result<(), ModbusError> {
+        let mut offset = 0;
+
+        while offset < buf.len() {
+            let len = self
+                .uart
+                .read(&mut buf[offset..], TickType::from(timeout).0)?;
+
+            if len == 0 {
+                return Err(ModbusError::Timeout);
+            }
+
+            offset += len;
+        }
+
+        Ok(())
+    }
+}
+
+/// The CRC-16/MODBUS of `data`
+fn crc(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff_u16, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u16, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
@@ -2,4 +2,21 @@
 //!
 //! MQTT is a lightweight publish/subscribe messaging protocol.
 
+#[cfg(all(feature = "std", feature = "experimental"))]
+pub mod bridge;
 pub mod client;
+#[cfg(feature = "std")]
+pub mod command;
+#[cfg(all(
+    feature = "std",
+    feature = "experimental",
+    esp_idf_comp_app_update_enabled,
+    esp_idf_comp_spi_flash_enabled,
+    esp_idf_comp_esp_http_client_enabled,
+    esp_idf_comp_mbedtls_enabled
+))]
+pub mod ota;
+#[cfg(feature = "shadow")]
+pub mod shadow;
+#[cfg(feature = "sparkplug")]
+pub mod sparkplug;
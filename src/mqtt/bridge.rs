@@ -0,0 +1,316 @@
+//! MQTT bridge
+//!
+//! Maintains connections to two brokers (e.g. a local one and a cloud one) and
+//! republishes messages between them according to a set of topic mappings.
+use std::collections::VecDeque;
+use std::string::String;
+use std::sync::mpsc;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::mqtt::client::{Details, Event, QoS};
+
+use esp_idf_sys::*;
+
+use super::client::{EspMqttClient, EspMqttMessage, MqttClientConfiguration};
+
+/// How many recently forwarded messages are remembered per broker for loop prevention
+const RECENT_MESSAGES: usize = 32;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Side {
+    Local,
+    Remote,
+}
+
+impl Side {
+    fn other(&self) -> Self {
+        match self {
+            Self::Local => Self::Remote,
+            Self::Remote => Self::Local,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    LocalToRemote,
+    RemoteToLocal,
+    Both,
+}
+
+impl Direction {
+    fn forwards_from(&self, side: Side) -> bool {
+        matches!(
+            (self, side),
+            (Self::Both, _)
+                | (Self::LocalToRemote, Side::Local)
+                | (Self::RemoteToLocal, Side::Remote)
+        )
+    }
+}
+
+/// Maps all topics below `local_prefix` on the local broker to the same topics below
+/// `remote_prefix` on the remote broker (and vice versa, depending on `direction`)
+///
+/// Prefixes should either be empty (all topics) or end with a `/`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TopicMapping {
+    pub direction: Direction,
+    pub local_prefix: String,
+    pub remote_prefix: String,
+    pub qos: QoS,
+}
+
+impl TopicMapping {
+    fn prefix(&self, side: Side) -> &str {
+        match side {
+            Side::Local => &self.local_prefix,
+            Side::Remote => &self.remote_prefix,
+        }
+    }
+
+    fn filter(&self, side: Side) -> String {
+        format!("{}#", self.prefix(side))
+    }
+
+    fn rewrite(&self, from: Side, topic: &str) -> Option<String> {
+        topic
+            .strip_prefix(self.prefix(from))
+            .map(|rest| format!("{}{}", self.prefix(from.other()), rest))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Configuration {
+    pub mappings: Vec<TopicMapping>,
+    /// The stack of the forwarding thread, which rewrites the topics and publishes with both
+    /// clients
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            mappings: Vec::new(),
+            stack_size: 6144,
+        }
+    }
+}
+
+enum Command {
+    Connected(Side),
+    Received {
+        from: Side,
+        topic: String,
+        payload: Vec<u8>,
+        retain: bool,
+    },
+    Stop,
+}
+
+pub struct MqttBridge {
+    sender: mpsc::Sender<Command>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl MqttBridge {
+    pub fn new<'a>(
+        local_url: &str,
+        local_conf: &'a MqttClientConfiguration<'a>,
+        remote_url: &str,
+        remote_conf: &'a MqttClientConfiguration<'a>,
+        conf: &Configuration,
+    ) -> Result<Self, EspError> {
+        let (sender, receiver) = mpsc::channel();
+
+        let local = Self::connect(local_url, local_conf, Side::Local, sender.clone())?;
+        let remote = Self::connect(remote_url, remote_conf, Side::Remote, sender.clone())?;
+
+        let mut forwarder = Forwarder {
+            local,
+            remote,
+            mappings: conf.mappings.clone(),
+            recent_local: VecDeque::with_capacity(RECENT_MESSAGES),
+            recent_remote: VecDeque::with_capacity(RECENT_MESSAGES),
+        };
+
+        let join_handle = thread::Builder::new()
+            .name("mqtt-bridge".into())
+            .stack_size(conf.stack_size)
+            .spawn(move || forwarder.run(receiver))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        info!(
+            "Started MQTT bridge between {} and {}",
+            local_url, remote_url
+        );
+
+        Ok(Self {
+            sender,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    fn connect<'a>(
+        url: &str,
+        conf: &'a MqttClientConfiguration<'a>,
+        side: Side,
+        sender: mpsc::Sender<Command>,
+    ) -> Result<EspMqttClient, EspError> {
+        // Messages are only queued here and forwarded from the bridge thread, because
+        // publishing to one client from within the event callback of the other might deadlock
+        EspMqttClient::new(url, conf, move |event| {
+            let command = match event {
+                Ok(Event::Connected(_)) => Command::Connected(side),
+                Ok(Event::Received(message)) => match Self::to_command(side, message) {
+                    Some(command) => command,
+                    None => return,
+                },
+                Ok(_) => return,
+                Err(e) => {
+                    warn!("MQTT bridge {:?} client error: {:?}", side, e);
+                    return;
+                }
+            };
+
+            let _ = sender.send(command);
+        })
+    }
+
+    fn to_command(from: Side, message: &EspMqttMessage) -> Option<Command> {
+        if message.details() != &Details::Complete {
+            warn!("MQTT bridge cannot forward chunked messages, skipping");
+            return None;
+        }
+
+        Some(Command::Received {
+            from,
+            topic: message.topic()?.into(),
+            payload: message.data().to_vec(),
+            retain: message.retain(),
+        })
+    }
+}
+
+impl Drop for MqttBridge {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Command::Stop);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+struct Forwarder {
+    local: EspMqttClient,
+    remote: EspMqttClient,
+    mappings: Vec<TopicMapping>,
+    recent_local: VecDeque<u64>,
+    recent_remote: VecDeque<u64>,
+}
+
+impl Forwarder {
+    fn run(&mut self, receiver: mpsc::Receiver<Command>) {
+        while let Ok(command) = receiver.recv() {
+            match command {
+                Command::Connected(side) => self.subscribe(side),
+                Command::Received {
+                    from,
+                    topic,
+                    payload,
+                    retain,
+                } => self.forward(from, &topic, &payload, retain),
+                Command::Stop => break,
+            }
+        }
+    }
+
+    fn subscribe(&mut self, side: Side) {
+        for index in 0..self.mappings.len() {
+            let mapping = &self.mappings[index];
+
+            if mapping.direction.forwards_from(side) {
+                let filter = mapping.filter(side);
+                let qos = mapping.qos;
+
+                if let Err(e) = self.client(side).subscribe(&filter, qos) {
+                    warn!("MQTT bridge failed to subscribe to {}: {}", filter, e);
+                }
+            }
+        }
+    }
+
+    fn forward(&mut self, from: Side, topic: &str, payload: &[u8], retain: bool) {
+        let fingerprint = Self::fingerprint(topic, payload);
+
+        // Drop messages which the bridge itself has just published on this broker
+        let recent = self.recent(from);
+        if let Some(index) = recent.iter().position(|f| *f == fingerprint) {
+            recent.remove(index);
+            return;
+        }
+
+        let to = from.other();
+
+        let targets = self
+            .mappings
+            .iter()
+            .filter(|mapping| mapping.direction.forwards_from(from))
+            .filter_map(|mapping| {
+                mapping
+                    .rewrite(from, topic)
+                    .map(|target| (target, mapping.qos))
+            })
+            .collect::<Vec<_>>();
+
+        for (target, qos) in targets {
+            match self.client(to).enqueue(&target, qos, retain, payload) {
+                Ok(_) => {
+                    let recent = self.recent(to);
+
+                    if recent.len() == RECENT_MESSAGES {
+                        recent.pop_front();
+                    }
+
+                    recent.push_back(Self::fingerprint(&target, payload));
+                }
+                Err(e) => warn!(
+                    "MQTT bridge failed to forward {} to {}: {}",
+                    topic, target, e
+                ),
+            }
+        }
+    }
+
+    fn client(&mut self, side: Side) -> &mut EspMqttClient {
+        match side {
+            Side::Local => &mut self.local,
+            Side::Remote => &mut self.remote,
+        }
+    }
+
+    fn recent(&mut self, side: Side) -> &mut VecDeque<u64> {
+        match side {
+            Side::Local => &mut self.recent_local,
+            Side::Remote => &mut self.recent_remote,
+        }
+    }
+
+    fn fingerprint(topic: &str, payload: &[u8]) -> u64 {
+        // FNV-1a
+        topic
+            .as_bytes()
+            .iter()
+            .chain(&[0])
+            .chain(payload)
+            .fold(0xcbf29ce484222325, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+            })
+    }
+}
@@ -7,6 +7,9 @@ use core::{mem, slice, time};
 extern crate alloc;
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::*;
 
 use embedded_svc::mqtt::client::{self, ErrorType, MessageImpl};
 use embedded_svc::utils::mqtt::client::{ConnState, ConnStateGuard, Connection, Postbox};
@@ -14,17 +17,58 @@ use embedded_svc::utils::mqtt::client::{ConnState, ConnStateGuard, Connection, P
 use esp_idf_sys::*;
 
 use crate::handle::RawHandle;
+use crate::heap::BufferMemory;
+#[cfg(feature = "metrics")]
+use crate::metrics::Counter;
+#[cfg(all(
+    not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")),
+    esp_idf_comp_esp_netif_enabled
+))]
+use crate::netif::Interface;
+#[cfg(esp_idf_comp_nvs_flash_enabled)]
+use crate::nvs::{EspNvs, NvsPartitionId};
 use crate::private::mutex::RawCondvar;
+use crate::private::waitable::Waitable;
 
 #[cfg(all(feature = "nightly", feature = "experimental"))]
 pub use asyncify::*;
 
+#[cfg(feature = "heapless-config")]
+use crate::private::common::bounded_str;
 use crate::private::cstr::*;
+#[cfg(not(esp_idf_version_major = "4"))]
+use crate::tls::TcpKeepAlive;
 use crate::tls::X509;
+#[cfg(feature = "traffic")]
+use crate::traffic::{self, Service};
 
 pub use client::{Details, MessageId};
 
+#[cfg(feature = "metrics")]
+static EVENTS: Counter = Counter::new("esp_mqtt_client_events", "MQTT client events, by type");
+#[cfg(feature = "metrics")]
+static PUBLISHED_MESSAGES: Counter = Counter::new(
+    "esp_mqtt_client_published_messages",
+    "MQTT messages published or enqueued",
+);
+#[cfg(feature = "metrics")]
+static PUBLISHED_BYTES: Counter = Counter::new(
+    "esp_mqtt_client_published_bytes",
+    "Payload bytes of the MQTT messages published or enqueued",
+);
+#[cfg(feature = "metrics")]
+static RECEIVED_MESSAGES: Counter = Counter::new(
+    "esp_mqtt_client_received_messages",
+    "MQTT messages received",
+);
+#[cfg(feature = "metrics")]
+static RECEIVED_BYTES: Counter = Counter::new(
+    "esp_mqtt_client_received_bytes",
+    "Payload bytes of the MQTT messages received",
+);
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum MqttProtocolVersion {
     V3_1,
     V3_1_1,
@@ -39,7 +83,7 @@ impl From<MqttProtocolVersion> for esp_mqtt_protocol_ver_t {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct LwtConfiguration<'a> {
     pub topic: &'a str,
     pub payload: &'a [u8],
@@ -47,7 +91,7 @@ pub struct LwtConfiguration<'a> {
     pub retain: bool,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct MqttClientConfiguration<'a> {
     pub protocol_version: Option<MqttProtocolVersion>,
 
@@ -80,6 +124,24 @@ pub struct MqttClientConfiguration<'a> {
     pub client_certificate: Option<X509<'static>>,
     pub private_key: Option<X509<'static>>,
     pub private_key_password: Option<&'a str>,
+    /// TCP keepalive of the connection to the broker; only supported for `mqtt://` and `mqtts://` URLs
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub tcp_keep_alive: Option<TcpKeepAlive>,
+    /// Disables Nagle's algorithm on the connection to the broker
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub tcp_nodelay: bool,
+    /// Client certificate and private key owned by the client; takes precedence
+    /// over `client_certificate` and `private_key` when set
+    pub client_credentials: Option<Arc<MqttClientCredentials>>,
+    /// Binds the client to the given network interface instead of using the default route
+    #[cfg(all(
+        not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")),
+        esp_idf_comp_esp_netif_enabled
+    ))]
+    pub interface: Option<Interface>,
+    /// Where the RX/TX buffers of the client are allocated; the TLS buffers are allocated
+    /// later on by the client task and follow the `malloc` policy of the sdkconfig
+    pub buffer_memory: BufferMemory,
     // TODO: Future
     // pub psk_hint_key: KeyHint,
     // pub alpn_protos: &'a [&'a str],
@@ -122,10 +184,297 @@ impl<'a> Default for MqttClientConfiguration<'a> {
             client_certificate: None,
             private_key: None,
             private_key_password: None,
+            client_credentials: None,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            tcp_keep_alive: None,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            tcp_nodelay: false,
+            #[cfg(all(
+                not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")),
+                esp_idf_comp_esp_netif_enabled
+            ))]
+            interface: None,
+            buffer_memory: BufferMemory::Default,
         }
     }
 }
 
+impl<'a> MqttClientConfiguration<'a> {
+    fn client_auth(&self) -> Option<(X509<'_>, X509<'_>)> {
+        if let Some(credentials) = self.client_credentials.as_ref() {
+            Some((credentials.certificate(), credentials.private_key()))
+        } else if let (Some(cert), Some(private_key)) = (self.client_certificate, self.private_key)
+        {
+            Some((cert, private_key))
+        } else {
+            None
+        }
+    }
+
+    #[cfg(all(
+        not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")),
+        esp_idf_comp_esp_netif_enabled
+    ))]
+    fn ifreq(&self) -> Result<Option<ifreq>, EspError> {
+        self.interface
+            .map(|interface| interface.ifreq())
+            .transpose()
+    }
+}
+
+/// An MQTT client configuration owning its URL and credentials in fixed-capacity buffers
+///
+/// Unlike `MqttClientConfiguration`, this does not borrow its strings, so it can be
+/// stored in a `static` or loaded from NVS without any heap allocation. `U` is the
+/// capacity of the URL, and `N` the capacity of the client ID, username and password.
+#[cfg(feature = "heapless-config")]
+#[derive(Clone, Debug, Default)]
+pub struct BoundedMqttClientConfiguration<const U: usize = 128, const N: usize = 64> {
+    pub url: heapless::String<U>,
+    pub client_id: Option<heapless::String<N>>,
+    pub username: Option<heapless::String<N>>,
+    pub password: Option<heapless::String<N>>,
+    /// The remaining settings; its client ID, username and password are ignored
+    pub conf: MqttClientConfiguration<'static>,
+}
+
+#[cfg(feature = "heapless-config")]
+impl<const U: usize, const N: usize> BoundedMqttClientConfiguration<U, N> {
+    /// Fails with `ESP_ERR_INVALID_SIZE` if the URL does not fit its buffer
+    pub fn new(url: &str) -> Result<Self, EspError> {
+        Ok(Self {
+            url: bounded_str(url)?,
+            client_id: None,
+            username: None,
+            password: None,
+            conf: Default::default(),
+        })
+    }
+
+    pub fn set_client_id(&mut self, client_id: &str) -> Result<&mut Self, EspError> {
+        self.client_id = Some(bounded_str(client_id)?);
+
+        Ok(self)
+    }
+
+    pub fn set_credentials(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> Result<&mut Self, EspError> {
+        self.username = Some(bounded_str(username)?);
+        self.password = Some(bounded_str(password)?);
+
+        Ok(self)
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the configuration to pass to `EspMqttClient::new` together with `url()`
+    pub fn as_configuration(&self) -> MqttClientConfiguration<'_> {
+        MqttClientConfiguration {
+            client_id: self.client_id.as_deref(),
+            username: self.username.as_deref(),
+            password: self.password.as_deref(),
+            ..self.conf.clone()
+        }
+    }
+}
+
+#[cfg(not(esp_idf_version_major = "4"))]
+impl<'a> MqttClientConfiguration<'a> {
+    fn needs_transport(&self) -> bool {
+        self.tcp_keep_alive.is_some() || self.tcp_nodelay
+    }
+
+    /// Creates the transport ESP-MQTT would otherwise create itself, but with socket options applied
+    ///
+    /// Returns the transport, and whether it is a TLS one.
+    fn create_transport(&self, url: &str) -> Result<(esp_transport_handle_t, bool), EspError> {
+        let scheme = url.split(':').next().unwrap_or("");
+
+        let (transport, tls) = if scheme.eq_ignore_ascii_case("mqtt") {
+            let transport = unsafe { esp_transport_tcp_init() };
+            if transport.is_null() {
+                return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+            }
+
+            unsafe { esp_transport_set_default_port(transport, 1883) };
+
+            (transport, false)
+        } else if scheme.eq_ignore_ascii_case("mqtts") {
+            let transport = unsafe { esp_transport_ssl_init() };
+            if transport.is_null() {
+                return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+            }
+
+            unsafe { esp_transport_set_default_port(transport, 8883) };
+
+            (transport, true)
+        } else {
+            warn!("TCP socket options are not supported for {} URLs", scheme);
+
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>());
+        };
+
+        unsafe { self.configure_transport(transport, tls) };
+
+        Ok((transport, tls))
+    }
+
+    /// Applies the socket options and, to a TLS transport, the TLS settings of the configuration
+    ///
+    /// ESP-MQTT does not apply its own TLS settings to a custom transport, so these are replicated
+    /// here. The transport does not copy the certificates and the key, which must outlive its
+    /// connections.
+    ///
+    /// # Safety
+    ///
+    /// `transport` must be a valid TCP transport, or a valid TLS one if `tls` is set.
+    unsafe fn configure_transport(&self, transport: esp_transport_handle_t, tls: bool) {
+        let mut keep_alive = esp_transport_keep_alive_t {
+            keep_alive_enable: self.tcp_keep_alive.is_some(),
+            ..Default::default()
+        };
+
+        if let Some(tcp_keep_alive) = self.tcp_keep_alive {
+            keep_alive.keep_alive_idle = tcp_keep_alive.idle.as_secs() as _;
+            keep_alive.keep_alive_interval = tcp_keep_alive.interval.as_secs() as _;
+            keep_alive.keep_alive_count = tcp_keep_alive.count as _;
+        }
+
+        if !tls {
+            esp_transport_tcp_set_keep_alive(transport, &mut keep_alive);
+
+            return;
+        }
+
+        esp_transport_ssl_set_keep_alive(transport, &mut keep_alive);
+
+        if self.use_global_ca_store {
+            esp_transport_ssl_enable_global_ca_store(transport);
+        }
+
+        #[cfg(not(esp_idf_version = "4.3"))]
+        if let Some(crt_bundle_attach) = self.crt_bundle_attach {
+            esp_transport_ssl_crt_bundle_attach(transport, Some(crt_bundle_attach));
+        }
+
+        if self.skip_cert_common_name_check {
+            esp_transport_ssl_skip_common_name_check(transport);
+        }
+
+        // mbedTLS detects PEM data by its trailing NUL, so the DER variants work for both
+        if let Some(cert) = self.server_certificate {
+            esp_transport_ssl_set_cert_data_der(
+                transport,
+                cert.as_esp_idf_raw_ptr(),
+                cert.as_esp_idf_raw_len() as _,
+            );
+        }
+
+        if let Some((cert, private_key)) = self.client_auth() {
+            esp_transport_ssl_set_client_cert_data_der(
+                transport,
+                cert.as_esp_idf_raw_ptr(),
+                cert.as_esp_idf_raw_len() as _,
+            );
+            esp_transport_ssl_set_client_key_data_der(
+                transport,
+                private_key.as_esp_idf_raw_ptr(),
+                private_key.as_esp_idf_raw_len() as _,
+            );
+        } else {
+            // Or the transport would keep referring to the former credentials
+            esp_transport_ssl_set_client_cert_data_der(transport, core::ptr::null(), 0);
+            esp_transport_ssl_set_client_key_data_der(transport, core::ptr::null(), 0);
+        }
+    }
+}
+
+/// Client certificate and private key material, owned by the MQTT client
+///
+/// Unlike `MqttClientConfiguration::client_certificate` and `MqttClientConfiguration::private_key`,
+/// which need to be `'static`, these can be loaded at runtime (e.g. from NVS) and later rotated
+/// with `EspMqttClient::reconfigure`.
+///
+/// Both PEM and DER encodings are supported. PEM data is NUL-terminated if necessary.
+pub struct MqttClientCredentials {
+    certificate: Vec<u8>,
+    private_key: Vec<u8>,
+}
+
+impl MqttClientCredentials {
+    pub fn new(certificate: Vec<u8>, private_key: Vec<u8>) -> Self {
+        Self {
+            certificate: Self::normalize(certificate),
+            private_key: Self::normalize(private_key),
+        }
+    }
+
+    /// Loads the client certificate and private key from the NVS blobs with the given names;
+    /// fails with `ESP_ERR_NOT_FOUND` if any of these is missing
+    #[cfg(esp_idf_comp_nvs_flash_enabled)]
+    pub fn from_nvs<T: NvsPartitionId>(
+        nvs: &EspNvs<T>,
+        certificate_name: &str,
+        private_key_name: &str,
+    ) -> Result<Self, EspError> {
+        let certificate = Self::load_blob(nvs, certificate_name)?;
+        let private_key = Self::load_blob(nvs, private_key_name)?;
+
+        info!(
+            "Loaded MQTT client credentials from NVS blobs \"{}\" and \"{}\"",
+            certificate_name, private_key_name
+        );
+
+        Ok(Self::new(certificate, private_key))
+    }
+
+    pub fn certificate(&self) -> X509<'_> {
+        X509::der(&self.certificate)
+    }
+
+    pub fn private_key(&self) -> X509<'_> {
+        X509::der(&self.private_key)
+    }
+
+    #[cfg(esp_idf_comp_nvs_flash_enabled)]
+    fn load_blob<T: NvsPartitionId>(nvs: &EspNvs<T>, name: &str) -> Result<Vec<u8>, EspError> {
+        let len = nvs
+            .blob_len(name)?
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_NOT_FOUND>)?;
+
+        let mut buf = vec![0; len];
+
+        let len = nvs
+            .get_blob(name, &mut buf)?
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_NOT_FOUND>)?
+            .len();
+
+        buf.truncate(len);
+
+        Ok(buf)
+    }
+
+    fn normalize(mut data: Vec<u8>) -> Vec<u8> {
+        // ESP-IDF expects the length of PEM data to include the terminating NUL
+        if data.starts_with(b"-----BEGIN") && data.last() != Some(&0) {
+            data.push(0);
+        }
+
+        data
+    }
+}
+
+impl Debug for MqttClientCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MqttClientCredentials(...)")
+    }
+}
+
 #[cfg(esp_idf_version_major = "4")]
 impl<'a> From<&'a MqttClientConfiguration<'a>> for (esp_mqtt_client_config_t, RawCstrs) {
     fn from(conf: &'a MqttClientConfiguration<'a>) -> Self {
@@ -187,7 +536,7 @@ impl<'a> From<&'a MqttClientConfiguration<'a>> for (esp_mqtt_client_config_t, Ra
             c_conf.cert_len = cert.as_esp_idf_raw_len();
         }
 
-        if let (Some(cert), Some(private_key)) = (conf.client_certificate, conf.private_key) {
+        if let Some((cert, private_key)) = conf.client_auth() {
             c_conf.client_cert_pem = cert.as_esp_idf_raw_ptr() as _;
             c_conf.client_cert_len = cert.as_esp_idf_raw_len();
 
@@ -287,7 +636,7 @@ impl<'a> From<&'a MqttClientConfiguration<'a>> for (esp_mqtt_client_config_t, Ra
             c_conf.broker.verification.certificate_len = cert.as_esp_idf_raw_len();
         }
 
-        if let (Some(cert), Some(private_key)) = (conf.client_certificate, conf.private_key) {
+        if let Some((cert, private_key)) = conf.client_auth() {
             c_conf.credentials.authentication.certificate = cert.as_esp_idf_raw_ptr() as _;
             c_conf.credentials.authentication.certificate_len = cert.as_esp_idf_raw_len();
 
@@ -330,6 +679,12 @@ pub struct EspMqttClient<S = ()> {
     raw_client: esp_mqtt_client_handle_t,
     conn_state_guard: Option<Arc<ConnStateGuard<RawCondvar, S>>>,
     _boxed_raw_callback: Box<dyn FnMut(esp_mqtt_event_handle_t)>,
+    credentials: Option<Arc<MqttClientCredentials>>,
+    #[cfg(not(esp_idf_version_major = "4"))]
+    transport: esp_transport_handle_t,
+    /// Whether `transport` is a TLS one
+    #[cfg(not(esp_idf_version_major = "4"))]
+    tls_transport: bool,
 }
 
 impl<S> RawHandle for EspMqttClient<S> {
@@ -400,6 +755,54 @@ impl EspMqttClient<()> {
     }
 }
 
+impl EspMqttClient<()> {
+    /// Creates a client together with a connection handing out the events in place, i.e.
+    /// with the received data borrowed from the RX buffer of the MQTT task instead of copied
+    ///
+    /// The MQTT task waits for each event to be released (dropped) before processing the
+    /// next one, so events should not be held for long. In particular, an event must not be
+    /// held while the client is dropped.
+    pub fn new_with_zero_copy_conn<'a>(
+        url: impl AsRef<str>,
+        conf: &'a MqttClientConfiguration<'a>,
+    ) -> Result<(Self, EspMqttZeroCopyConnection), EspError>
+    where
+        Self: Sized,
+    {
+        let handoff = Arc::new(Waitable::new(Handoff {
+            event: None,
+            closed: false,
+        }));
+
+        let closer = HandoffCloser(handoff.clone());
+
+        let client = Self::new_raw(
+            url,
+            conf,
+            Box::new(move |event_handle| {
+                let handoff = &closer.0;
+
+                if handoff.get_mut(|state| {
+                    if !state.closed {
+                        state.event = Some(RawEvent(event_handle));
+                    }
+
+                    state.closed
+                }) {
+                    return;
+                }
+
+                handoff.cvar.notify_all();
+
+                handoff.wait_while(|state| state.event.is_some() && !state.closed);
+            }),
+            None,
+        )?;
+
+        Ok((client, EspMqttZeroCopyConnection(handoff)))
+    }
+}
+
 impl<S> EspMqttClient<S> {
     pub fn new_generic<'a>(
         url: impl AsRef<str>,
@@ -427,12 +830,37 @@ impl<S> EspMqttClient<S> {
     fn new_raw<'a>(
         url: impl AsRef<str> + 'a,
         conf: &'a MqttClientConfiguration<'a>,
-        raw_callback: Box<dyn FnMut(esp_mqtt_event_handle_t)>,
+        #[allow(unused_mut)] mut raw_callback: Box<dyn FnMut(esp_mqtt_event_handle_t)>,
         conn_state_guard: Option<Arc<ConnStateGuard<RawCondvar, S>>>,
     ) -> Result<Self, EspError>
     where
         Self: Sized,
     {
+        // Owned (and destroyed) by the ESP-MQTT client once it is initialized
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let (transport, tls_transport) = if conf.needs_transport() {
+            conf.create_transport(url.as_ref())?
+        } else {
+            (core::ptr::null_mut(), false)
+        };
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        if conf.tcp_nodelay {
+            let mut callback = raw_callback;
+
+            raw_callback = Box::new(move |event: esp_mqtt_event_handle_t| {
+                if unsafe { (*event).event_id } == esp_mqtt_event_id_t_MQTT_EVENT_CONNECTED {
+                    let fd = unsafe { esp_transport_get_socket(transport) };
+
+                    if let Err(e) = crate::private::socket::set_nodelay(fd, true) {
+                        warn!("Failed to set TCP_NODELAY on the MQTT connection: {}", e);
+                    }
+                }
+
+                callback(event);
+            });
+        }
+
         let mut boxed_raw_callback = Box::new(raw_callback);
 
         let unsafe_callback = UnsafeCallback::from(&mut boxed_raw_callback);
@@ -447,10 +875,32 @@ impl<S> EspMqttClient<S> {
         #[cfg(not(esp_idf_version_major = "4"))]
         {
             c_conf.broker.address.uri = cstrs.as_ptr(url);
+            c_conf.network.transport = transport;
+        }
+
+        // The client copies the interface name during initialization
+        #[cfg(all(
+            not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")),
+            esp_idf_comp_esp_netif_enabled
+        ))]
+        let mut ifreq = conf.ifreq()?;
+        #[cfg(all(
+            not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")),
+            esp_idf_comp_esp_netif_enabled
+        ))]
+        if let Some(ifreq) = ifreq.as_mut() {
+            c_conf.network.if_name = ifreq as *mut _;
         }
 
-        let raw_client = unsafe { esp_mqtt_client_init(&c_conf as *const _) };
+        let raw_client = conf
+            .buffer_memory
+            .scope(|| unsafe { esp_mqtt_client_init(&c_conf as *const _) });
         if raw_client.is_null() {
+            #[cfg(not(esp_idf_version_major = "4"))]
+            if !transport.is_null() {
+                unsafe { esp_transport_destroy(transport) };
+            }
+
             return Err(EspError::from_infallible::<ESP_FAIL>());
         }
 
@@ -458,6 +908,11 @@ impl<S> EspMqttClient<S> {
             raw_client,
             _boxed_raw_callback: boxed_raw_callback,
             conn_state_guard,
+            credentials: conf.client_credentials.clone(),
+            #[cfg(not(esp_idf_version_major = "4"))]
+            transport,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            tls_transport,
         };
 
         esp!(unsafe {
@@ -474,6 +929,90 @@ impl<S> EspMqttClient<S> {
         Ok(client)
     }
 
+    /// Applies a new configuration to a running client: the client is disconnected,
+    /// the configuration (including the TLS client credentials) is swapped and the client is reconnected.
+    ///
+    /// This allows rotating short-lived client certificates without recreating the client.
+    /// The broker URL, the event callback and the TCP socket options are preserved.
+    pub fn reconfigure<'a>(
+        &mut self,
+        conf: &'a MqttClientConfiguration<'a>,
+    ) -> Result<(), EspError> {
+        self.apply_configuration(None, conf)
+    }
+
+    /// Like `reconfigure`, but also switches to another broker URL, e.g. after
+    /// a settings change from a web UI
+    ///
+    /// The client keeps its event callback (and connection), so that only the
+    /// network connection to the broker is re-established. The subscriptions are
+    /// not restored on the new broker session by the client itself.
+    ///
+    /// When the client uses a custom transport (as for TCP_NODELAY or binding to an
+    /// interface), the scheme of the new URL must be the same as of the original one.
+    pub fn update_configuration<'a>(
+        &mut self,
+        url: &str,
+        conf: &'a MqttClientConfiguration<'a>,
+    ) -> Result<(), EspError> {
+        self.apply_configuration(Some(url), conf)
+    }
+
+    fn apply_configuration<'a>(
+        &mut self,
+        url: Option<&str>,
+        conf: &'a MqttClientConfiguration<'a>,
+    ) -> Result<(), EspError> {
+        info!("About to reconfigure MQTT client");
+
+        esp!(unsafe { esp_mqtt_client_stop(self.raw_client) })?;
+
+        if let Some(url) = url {
+            let c_url = CString::new(url).unwrap();
+
+            esp!(unsafe { esp_mqtt_client_set_uri(self.raw_client, c_url.as_ptr()) })?;
+
+            info!("MQTT broker URL changed to {}", url);
+        }
+
+        #[allow(unused_mut)]
+        let (mut c_conf, _cstrs) = conf.into();
+
+        // ESP-MQTT ignores the TLS settings of the configuration with a custom transport, which
+        // thus gets those of the new configuration (and stops using the old credentials) here
+        #[cfg(not(esp_idf_version_major = "4"))]
+        if !self.transport.is_null() {
+            unsafe { conf.configure_transport(self.transport, self.tls_transport) };
+
+            c_conf.network.transport = self.transport;
+        }
+
+        #[cfg(all(
+            not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")),
+            esp_idf_comp_esp_netif_enabled
+        ))]
+        let mut ifreq = conf.ifreq()?;
+        #[cfg(all(
+            not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")),
+            esp_idf_comp_esp_netif_enabled
+        ))]
+        if let Some(ifreq) = ifreq.as_mut() {
+            c_conf.network.if_name = ifreq as *mut _;
+        }
+
+        esp!(unsafe { esp_mqtt_set_config(self.raw_client, &c_conf as *const _) })?;
+
+        // The client and its transport refer to the new credentials now, but the old ones are
+        // only dropped once the client has restarted
+        let _old_credentials = mem::replace(&mut self.credentials, conf.client_credentials.clone());
+
+        esp!(unsafe { esp_mqtt_client_start(self.raw_client) })?;
+
+        info!("MQTT client reconfigured");
+
+        Ok(())
+    }
+
     pub fn subscribe(
         &mut self,
         topic: &str,
@@ -492,6 +1031,13 @@ impl<S> EspMqttClient<S> {
         Self::check(unsafe { esp_mqtt_client_unsubscribe(self.raw_client, c_topic.as_ptr()) })
     }
 
+    /// Disconnects gracefully from the broker, which then discards the last will of the client
+    ///
+    /// The client reconnects after its reconnect timeout, unless reconnecting is disabled.
+    pub fn disconnect(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_mqtt_client_disconnect(self.raw_client) })
+    }
+
     pub fn publish(
         &mut self,
         topic: &str,
@@ -506,7 +1052,7 @@ impl<S> EspMqttClient<S> {
             _ => payload.as_ptr(),
         };
 
-        Self::check(unsafe {
+        let result = Self::check(unsafe {
             esp_mqtt_client_publish(
                 self.raw_client,
                 c_topic.as_ptr(),
@@ -515,7 +1061,20 @@ impl<S> EspMqttClient<S> {
                 qos as _,
                 retain as _,
             )
-        })
+        });
+
+        #[cfg(feature = "metrics")]
+        if result.is_ok() {
+            PUBLISHED_MESSAGES.inc(&[]);
+            PUBLISHED_BYTES.add(&[], payload.len() as _);
+        }
+
+        #[cfg(feature = "traffic")]
+        if result.is_ok() {
+            traffic::record_tx(Service::Mqtt, payload.len());
+        }
+
+        result
     }
 
     pub fn enqueue(
@@ -532,7 +1091,7 @@ impl<S> EspMqttClient<S> {
             _ => payload.as_ptr(),
         };
 
-        Self::check(unsafe {
+        let result = Self::check(unsafe {
             esp_mqtt_client_enqueue(
                 self.raw_client,
                 c_topic.as_ptr(),
@@ -542,7 +1101,20 @@ impl<S> EspMqttClient<S> {
                 retain as _,
                 true,
             )
-        })
+        });
+
+        #[cfg(feature = "metrics")]
+        if result.is_ok() {
+            PUBLISHED_MESSAGES.inc(&[]);
+            PUBLISHED_BYTES.add(&[], payload.len() as _);
+        }
+
+        #[cfg(feature = "traffic")]
+        if result.is_ok() {
+            traffic::record_tx(Service::Mqtt, payload.len());
+        }
+
+        result
     }
 
     extern "C" fn handle(
@@ -551,11 +1123,53 @@ impl<S> EspMqttClient<S> {
         _event_id: i32,
         event_data: *mut c_void,
     ) {
+        #[cfg(feature = "metrics")]
+        Self::record(unsafe { (event_data as esp_mqtt_event_handle_t).as_ref() });
+
+        #[cfg(feature = "traffic")]
+        if let Some(event) = unsafe { (event_data as esp_mqtt_event_handle_t).as_ref() } {
+            if event.event_id == esp_mqtt_event_id_t_MQTT_EVENT_DATA {
+                traffic::record_rx(Service::Mqtt, event.data_len as _);
+            }
+        }
+
         unsafe {
             UnsafeCallback::from_ptr(event_handler_arg).call(event_data as _);
         }
     }
 
+    #[cfg(feature = "metrics")]
+    fn record(event: Option<&esp_mqtt_event_t>) {
+        let event = if let Some(event) = event {
+            event
+        } else {
+            return;
+        };
+
+        let name = match event.event_id {
+            esp_mqtt_event_id_t_MQTT_EVENT_ERROR => "error",
+            esp_mqtt_event_id_t_MQTT_EVENT_CONNECTED => "connected",
+            esp_mqtt_event_id_t_MQTT_EVENT_DISCONNECTED => "disconnected",
+            esp_mqtt_event_id_t_MQTT_EVENT_SUBSCRIBED => "subscribed",
+            esp_mqtt_event_id_t_MQTT_EVENT_UNSUBSCRIBED => "unsubscribed",
+            esp_mqtt_event_id_t_MQTT_EVENT_PUBLISHED => "published",
+            esp_mqtt_event_id_t_MQTT_EVENT_DELETED => "deleted",
+            esp_mqtt_event_id_t_MQTT_EVENT_DATA => {
+                // Large messages are delivered in several fragments
+                if event.current_data_offset == 0 {
+                    RECEIVED_MESSAGES.inc(&[]);
+                }
+
+                RECEIVED_BYTES.add(&[], event.data_len as _);
+
+                return;
+            }
+            _ => return,
+        };
+
+        EVENTS.inc(&[("event", name)]);
+    }
+
     fn check(result: i32) -> Result<client::MessageId, EspError> {
         match EspError::from(result) {
             Some(err) if result < 0 => Err(err),
@@ -619,6 +1233,74 @@ impl<P> client::Enqueue for EspMqttClient<P> {
 
 unsafe impl<P> Send for EspMqttClient<P> {}
 
+struct RawEvent(esp_mqtt_event_handle_t);
+
+unsafe impl Send for RawEvent {}
+
+struct Handoff {
+    event: Option<RawEvent>,
+    closed: bool,
+}
+
+/// Closes the handoff when the raw callback owning it is dropped, i.e. with the client
+struct HandoffCloser(Arc<Waitable<Handoff>>);
+
+impl Drop for HandoffCloser {
+    fn drop(&mut self) {
+        self.0.get_mut(|state| state.closed = true);
+        self.0.cvar.notify_all();
+    }
+}
+
+/// The connection of `EspMqttClient::new_with_zero_copy_conn`
+pub struct EspMqttZeroCopyConnection(Arc<Waitable<Handoff>>);
+
+impl EspMqttZeroCopyConnection {
+    /// Waits for the next event; returns `None` once the client is dropped
+    pub fn next(&mut self) -> Option<EspMqttBorrowedEvent<'_>> {
+        let event = self.0.wait_while_and_get(
+            |state| state.event.is_none() && !state.closed,
+            |state| state.event.as_ref().map(|event| event.0),
+        );
+
+        event.map(|event| EspMqttBorrowedEvent {
+            handoff: &self.0,
+            event,
+        })
+    }
+}
+
+impl Drop for EspMqttZeroCopyConnection {
+    fn drop(&mut self) {
+        // Unblocks the MQTT task, which then drops the events
+        self.0.get_mut(|state| state.closed = true);
+        self.0.cvar.notify_all();
+    }
+}
+
+/// An event borrowed from the MQTT task, released when dropped
+pub struct EspMqttBorrowedEvent<'a> {
+    handoff: &'a Waitable<Handoff>,
+    event: esp_mqtt_event_handle_t,
+}
+
+impl<'a> EspMqttBorrowedEvent<'a> {
+    /// The event; the data of received messages is borrowed from the RX buffer
+    pub fn event(&self) -> Result<client::Event<EspMqttMessage<'_>>, EspError> {
+        EspMqttMessage::new_event(unsafe { self.event.as_ref() }.unwrap())
+    }
+
+    /// Releases the event, letting the MQTT task proceed; same as dropping it
+    pub fn release(self) {}
+}
+
+impl<'a> Drop for EspMqttBorrowedEvent<'a> {
+    fn drop(&mut self) {
+        self.handoff.get_mut(|state| state.event = None);
+        self.handoff.cvar.notify_all();
+    }
+}
+
 pub struct EspMqttMessage<'a> {
     event: &'a esp_mqtt_event_t,
     details: client::Details,
@@ -681,6 +1363,10 @@ impl<'a> EspMqttMessage<'a> {
         }
     }
 
+    pub fn retain(&self) -> bool {
+        self.event.retain
+    }
+
     pub fn topic(&self) -> Option<&str> {
         let ptr = self.event.topic;
 
@@ -0,0 +1,363 @@
+//! Remote commands
+//!
+//! `EspMqttCommands` runs the commands requested on a command topic, and publishes their
+//! results on a response topic. Only the commands registered in `Commands` can run, which makes
+//! it an allow-listed alternative to a remote shell, for fleet operations.
+//!
+//! The requests and responses are JSON objects, correlated by the `id` of the request:
+//!
+//! ```text
+//! -> {"id": "42", "command": "set_log_level", "args": ["wifi", "debug"]}
+//! <- {"id": "42", "ok": true, "result": "wifi: DEBUG"}
+//!
+//! -> {"id": "43", "command": "format_flash"}
+//! <- {"id": "43", "ok": false, "error": "Unknown command: format_flash"}
+//! ```
+//!
+//! ```ignore
+//! let commands = Commands::with_builtins().command("relay", move |args| {
+//!     match args {
+//!         ["on"] => relay.lock().set_high().map_err(|e| e.to_string())?,
+//!         ["off"] => relay.lock().set_low().map_err(|e| e.to_string())?,
+//!         _ => return Err("Usage: relay on|off".into()),
+//!     }
+//!
+//!     Ok(String::new())
+//! });
+//!
+//! let _commands = EspMqttCommands::new(
+//!     "mqtts://broker:8883",
+//!     &mqtt_conf,
+//!     &Configuration {
+//!         command_topic: "devices/sensor-1/commands".into(),
+//!         response_topic: "devices/sensor-1/responses".into(),
+//!         ..Default::default()
+//!     },
+//!     commands,
+//! )?;
+//! ```
+//!
+//! The broker ACLs should only let the backend publish on the command topic.
+use core::str::FromStr;
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::string::{String, ToString};
+use std::sync::mpsc;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::mqtt::client::{Details, Event, QoS};
+
+use esp_idf_sys::*;
+
+use crate::private::json::{self, Json};
+
+use super::client::{EspMqttClient, EspMqttMessage, MqttClientConfiguration};
+
+/// A command, called with the arguments of the request, returning its result or error message
+pub type Handler = Box<dyn FnMut(&[&str]) -> Result<String, String> + Send + 'static>;
+
+/// The allow-list of the commands, by name
+#[derive(Default)]
+pub struct Commands {
+    handlers: Vec<(String, Handler)>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The built-in commands:
+    /// - `set_log_level [target] <level>`: sets the log level of `target`, or of all targets
+    /// - `diagnostics`: the uptime, reset reason and heap usage
+    /// - `logs`: the last captured log records, with the `insights` feature
+    /// - `reboot`: restarts the device, after having published the response
+    pub fn with_builtins() -> Self {
+        #[allow(unused_mut)]
+        let mut commands = Self::new()
+            .command("set_log_level", set_log_level)
+            .command("diagnostics", |_| Ok(diagnostics()))
+            .command("reboot", |_| reboot());
+
+        #[cfg(feature = "insights")]
+        {
+            commands = commands.command("logs", |_| Ok(crate::insights::recent_logs().join("\n")));
+        }
+
+        commands
+    }
+
+    /// Registers the `name` command, replacing any command with the same name
+    pub fn command<F>(mut self, name: impl Into<String>, handler: F) -> Self
+    where
+        F: FnMut(&[&str]) -> Result<String, String> + Send + 'static,
+    {
+        let name = name.into();
+
+        self.handlers.retain(|(other, _)| *other != name);
+        self.handlers.push((name, Box::new(handler)));
+
+        self
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.handlers.iter().map(|(name, _)| name.as_str())
+    }
+
+    fn run(&mut self, name: &str, args: &[&str]) -> Result<String, String> {
+        let handler = self
+            .handlers
+            .iter_mut()
+            .find(|(other, _)| other == name)
+            .map(|(_, handler)| handler)
+            .ok_or_else(|| format!("Unknown command: {}", name))?;
+
+        handler(args)
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub command_topic: String,
+    pub response_topic: String,
+    pub qos: QoS,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            command_topic: String::new(),
+            response_topic: String::new(),
+            qos: QoS::AtLeastOnce,
+            stack_size: 6144,
+        }
+    }
+}
+
+enum Command {
+    Connected,
+    Received(Vec<u8>),
+    Stop,
+}
+
+pub struct EspMqttCommands {
+    sender: mpsc::Sender<Command>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspMqttCommands {
+    pub fn new<'a>(
+        url: &str,
+        mqtt_conf: &'a MqttClientConfiguration<'a>,
+        conf: &Configuration,
+        commands: Commands,
+    ) -> Result<Self, EspError> {
+        if conf.command_topic.is_empty() || conf.response_topic.is_empty() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let (sender, receiver) = mpsc::channel();
+
+        let callback_sender = sender.clone();
+
+        // The commands may take a while, and publishing from within the event callback might
+        // deadlock, hence they run on the command thread
+        let client = EspMqttClient::new(url, mqtt_conf, move |event| {
+            let command = match event {
+                Ok(Event::Connected(_)) => Command::Connected,
+                Ok(Event::Received(message)) => match Self::to_command(message) {
+                    Some(command) => command,
+                    None => return,
+                },
+                Ok(_) => return,
+                Err(e) => {
+                    warn!("Command MQTT client error: {:?}", e);
+                    return;
+                }
+            };
+
+            let _ = callback_sender.send(command);
+        })?;
+
+        let mut executor = Executor {
+            client,
+            conf: conf.clone(),
+            commands,
+        };
+
+        let join_handle = thread::Builder::new()
+            .name("mqtt-commands".into())
+            .stack_size(conf.stack_size)
+            .spawn(move || executor.run(receiver))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        info!("Started MQTT commands on {}", conf.command_topic);
+
+        Ok(Self {
+            sender,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    fn to_command(message: &EspMqttMessage) -> Option<Command> {
+        if message.details() != &Details::Complete {
+            warn!("Command requests must fit in a single message, skipping");
+            return None;
+        }
+
+        Some(Command::Received(message.data().to_vec()))
+    }
+}
+
+impl Drop for EspMqttCommands {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Command::Stop);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+struct Executor {
+    client: EspMqttClient,
+    conf: Configuration,
+    commands: Commands,
+}
+
+impl Executor {
+    fn run(&mut self, receiver: mpsc::Receiver<Command>) {
+        while let Ok(command) = receiver.recv() {
+            match command {
+                Command::Connected => {
+                    if let Err(e) = self
+                        .client
+                        .subscribe(&self.conf.command_topic, self.conf.qos)
+                    {
+                        warn!("Failed to subscribe to {}: {}", self.conf.command_topic, e);
+                    }
+                }
+                Command::Received(payload) => self.received(&payload),
+                Command::Stop => break,
+            }
+        }
+    }
+
+    fn received(&mut self, payload: &[u8]) {
+        let request = core::str::from_utf8(payload).ok().and_then(Json::parse);
+
+        let request = match request {
+            Some(request) => request,
+            None => {
+                warn!("Invalid command request, skipping");
+                return;
+            }
+        };
+
+        let id = request.get("id").cloned().unwrap_or(Json::Null);
+
+        let result = match request.get("command").and_then(Json::as_str) {
+            Some(name) => {
+                let args = request
+                    .get("args")
+                    .and_then(Json::as_array)
+                    .unwrap_or(&[])
+                    .iter()
+                    .map(|arg| {
+                        arg.as_str()
+                            .ok_or_else(|| "Arguments must be strings".into())
+                    })
+                    .collect::<Result<Vec<_>, String>>();
+
+                info!("Running command {}", name);
+
+                args.and_then(|args| self.commands.run(name, &args))
+            }
+            None => Err("Missing command".into()),
+        };
+
+        let response = match result {
+            Ok(result) => {
+                json::object([("id", id), ("ok", true.into()), ("result", result.into())])
+            }
+            Err(error) => {
+                warn!("Command failed: {}", error);
+
+                json::object([("id", id), ("ok", false.into()), ("error", error.into())])
+            }
+        };
+
+        if let Err(e) = self.client.publish(
+            &self.conf.response_topic,
+            self.conf.qos,
+            false,
+            response.to_string().as_bytes(),
+        ) {
+            warn!("Failed to publish the command response: {}", e);
+        }
+    }
+}
+
+fn set_log_level(args: &[&str]) -> Result<String, String> {
+    let (target, level) = match args {
+        [level] => ("*", *level),
+        [target, level] => (*target, *level),
+        _ => return Err("Usage: set_log_level [target] <level>".into()),
+    };
+
+    let level = ::log::LevelFilter::from_str(level).map_err(|_| "Invalid log level".to_string())?;
+
+    crate::log::set_target_level(target, level);
+
+    Ok(format!("{}: {}", target, level))
+}
+
+fn diagnostics() -> String {
+    let uptime = Duration::from_micros(unsafe { esp_timer_get_time() } as u64);
+
+    json::object([
+        ("uptime_s", uptime.as_secs().into()),
+        (
+            "reset_reason",
+            (unsafe { esp_reset_reason() } as u64).into(),
+        ),
+        (
+            "free_heap",
+            (unsafe { esp_get_free_heap_size() } as u64).into(),
+        ),
+        (
+            "min_free_heap",
+            (unsafe { esp_get_minimum_free_heap_size() } as u64).into(),
+        ),
+    ])
+    .to_string()
+}
+
+fn reboot() -> Result<String, String> {
+    // Restarting from another thread, once the response is published
+    thread::Builder::new()
+        .name("reboot".into())
+        .stack_size(4096)
+        .spawn(|| {
+            thread::sleep(Duration::from_secs(1));
+
+            #[cfg(esp_idf_comp_esp_event_enabled)]
+            crate::system::reboot_after(|| ());
+
+            #[cfg(not(esp_idf_comp_esp_event_enabled))]
+            unsafe {
+                esp_restart()
+            };
+        })
+        .map_err(|_| "Failed to schedule the reboot".to_string())?;
+
+    Ok("Rebooting".into())
+}
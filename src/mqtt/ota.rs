@@ -0,0 +1,608 @@
+//! MQTT-triggered OTA updates
+//!
+//! `EspMqttOta` subscribes to a command topic, and installs the firmware announced by the signed
+//! job documents published there, reporting the progress and the result on a status topic.
+//!
+//! A job document is a JSON object of the form:
+//!
+//! ```json
+//! {
+//!     "job_id": "2024-05-rollout-3",
+//!     "url": "https://updates.example.com/sensor/firmware-1.2.0.bin",
+//!     "version": "1.2.0",
+//!     "sha256": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+//!     "size": 1048576,
+//!     "signature": "MEUCIQ..."
+//! }
+//! ```
+//!
+//! where `size` is optional, and `signature` is the base64 of a DER encoded signature (ECDSA or
+//! RSA, over SHA-256) of the `job_id`, `url`, `version`, `sha256` and `size` fields, joined with
+//! newlines, and with an empty `size` if there is none:
+//!
+//! ```text
+//! printf '%s\n%s\n%s\n%s\n%s' "$JOB_ID" "$URL" "$VERSION" "$SHA256" "$SIZE" \
+//!     | openssl dgst -sha256 -sign key.pem | base64 -w0
+//! ```
+//!
+//! Jobs with an invalid signature, a plain HTTP URL, or a version which is not newer than both
+//! the running firmware and the last one installed are rejected without downloading anything,
+//! so that replaying a former signed job cannot roll the firmware back. The last installed
+//! version is kept in the NVS for that. The status is published as JSON objects with the
+//! `job_id`, the `state` (`rejected`, `downloading`, `installed` or `failed`), the `progress` in
+//! percents while downloading, and the `error` of the rejected and failed jobs:
+//!
+//! ```ignore
+//! let ota = EspMqttOta::new(
+//!     "mqtts://broker.example.com",
+//!     &MqttClientConfiguration::default(),
+//!     &Configuration {
+//!         command_topic: "devices/sensor-1/ota/job",
+//!         status_topic: "devices/sensor-1/ota/status",
+//!         public_key: include_str!("ota_key.pem"),
+//!         ..Default::default()
+//!     },
+//!     EspDefaultNvsPartition::take()?,
+//! )?;
+//! ```
+//!
+//! The job is downloaded and installed from the thread of `EspMqttOta`, so the jobs received
+//! meanwhile are queued, and handled once it is done.
+use core::cmp::Ordering;
+use core::str;
+use core::time::Duration;
+
+use std::string::String;
+use std::sync::mpsc;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+use embedded_svc::mqtt::client::{Details, Event, QoS};
+
+use esp_idf_sys::*;
+
+use crate::http::client::{self, EspHttpConnection};
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+use crate::ota::EspOta;
+use crate::private::base64;
+use crate::private::cstr::*;
+use crate::private::hex;
+use crate::private::json::{self, Json};
+use crate::private::sha256::{self, Sha256};
+#[cfg(feature = "traffic")]
+use crate::traffic::{Service, Throttle};
+
+use super::client::{EspMqttClient, EspMqttMessage, MqttClientConfiguration};
+
+const NAMESPACE: &str = "mqtt_ota";
+const VERSION_KEY: &str = "version";
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The topic of the job documents
+    pub command_topic: &'static str,
+    /// The topic of the status reports
+    pub status_topic: &'static str,
+    /// The PEM encoded public key verifying the signatures of the job documents
+    pub public_key: &'static str,
+    pub timeout: Duration,
+    /// The size of the HTTP read buffer, i.e. of the OTA writes
+    pub buffer_size: usize,
+    /// Restart into the new firmware this long after it is installed, or never if `None`
+    pub reboot_delay: Option<Duration>,
+    pub stack_size: usize,
+    /// Limits the bandwidth of the downloads
+    #[cfg(feature = "traffic")]
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub throttle: Option<&'static Throttle>,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            command_topic: "",
+            status_topic: "",
+            public_key: "",
+            timeout: Duration::from_secs(30),
+            buffer_size: 4096,
+            reboot_delay: Some(Duration::from_secs(2)),
+            stack_size: 8192,
+            #[cfg(feature = "traffic")]
+            throttle: None,
+        }
+    }
+}
+
+/// A verified job document
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OtaJob {
+    pub job_id: String,
+    pub url: String,
+    pub version: String,
+    /// Lowercase hex
+    pub sha256: String,
+    pub size: Option<u32>,
+}
+
+impl OtaJob {
+    /// Parses a job document, and verifies its signature with `public_key`
+    pub fn parse(public_key: &str, document: &[u8]) -> Result<Self, JobError> {
+        let json = str::from_utf8(document)
+            .ok()
+            .and_then(Json::parse)
+            .ok_or(JobError::InvalidDocument)?;
+
+        let field = |name| {
+            json.get(name)
+                .and_then(Json::as_str)
+                .ok_or(JobError::InvalidDocument)
+        };
+
+        let job = Self {
+            job_id: field("job_id")?.into(),
+            url: field("url")?.into(),
+            version: field("version")?.into(),
+            sha256: field("sha256")?.to_ascii_lowercase(),
+            size: json
+                .get("size")
+                .and_then(Json::as_u64)
+                .map(|size| size as u32),
+        };
+
+        let signature = base64::decode(field("signature")?).ok_or(JobError::InvalidDocument)?;
+
+        if job.sha256.len() != 64 || !job.sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(JobError::InvalidDocument);
+        }
+
+        let size = job.size.map(|size| size.to_string()).unwrap_or_default();
+
+        let signed = format!(
+            "{}\n{}\n{}\n{}\n{}",
+            job.job_id,
+            job.url,
+            job.version,
+            field("sha256")?,
+            size
+        );
+
+        sha256::verify_signature(public_key, signed.as_bytes(), &signature)
+            .map_err(|_| JobError::Signature)?;
+
+        Ok(job)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum JobError {
+    /// The job document is not valid JSON, or lacks one of the mandatory fields
+    InvalidDocument,
+    Signature,
+    /// The firmware URL is not an HTTPS one
+    Insecure,
+    /// The announced version is not newer than the running firmware, or than the last installed
+    Outdated,
+    Download,
+    /// The size or the SHA-256 of the image do not match the job document
+    Verification,
+    /// The OTA API rejected the image, or the flash could not be written
+    Install,
+}
+
+impl JobError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::InvalidDocument => "invalid_document",
+            Self::Signature => "signature",
+            Self::Insecure => "insecure",
+            Self::Outdated => "outdated",
+            Self::Download => "download",
+            Self::Verification => "verification",
+            Self::Install => "install",
+        }
+    }
+
+    /// Whether the job was rejected before anything was downloaded
+    pub fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            Self::InvalidDocument | Self::Signature | Self::Insecure | Self::Outdated
+        )
+    }
+}
+
+enum Command {
+    Connected,
+    Job(Vec<u8>),
+    Stop,
+}
+
+pub struct EspMqttOta {
+    sender: mpsc::Sender<Command>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspMqttOta {
+    /// Keeps the last installed version in the `mqtt_ota` namespace of `partition`
+    pub fn new<'a, T>(
+        url: &str,
+        mqtt_conf: &'a MqttClientConfiguration<'a>,
+        conf: &Configuration,
+        partition: EspNvsPartition<T>,
+    ) -> Result<Self, EspError>
+    where
+        T: NvsPartitionId + Send + 'static,
+    {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+
+        let (sender, receiver) = mpsc::channel();
+
+        let callback_sender = sender.clone();
+        let command_topic = conf.command_topic;
+
+        // The jobs are only queued here, as they are installed from the OTA thread, which also
+        // publishes the status: publishing from within the event callback might deadlock
+        let client = EspMqttClient::new(url, mqtt_conf, move |event| {
+            let command = match event {
+                Ok(Event::Connected(_)) => Command::Connected,
+                Ok(Event::Received(message)) => match Self::to_command(command_topic, message) {
+                    Some(command) => command,
+                    None => return,
+                },
+                Ok(_) => return,
+                Err(e) => {
+                    warn!("MQTT OTA client error: {:?}", e);
+                    return;
+                }
+            };
+
+            let _ = callback_sender.send(command);
+        })?;
+
+        let mut installer = Installer {
+            client,
+            conf: conf.clone(),
+            nvs,
+        };
+
+        let join_handle = thread::Builder::new()
+            .name("mqtt-ota".into())
+            .stack_size(conf.stack_size)
+            .spawn(move || installer.run(receiver))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        info!("Started MQTT OTA on {}", conf.command_topic);
+
+        Ok(Self {
+            sender,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    fn to_command(command_topic: &str, message: &EspMqttMessage) -> Option<Command> {
+        if message.topic() != Some(command_topic) {
+            return None;
+        }
+
+        if message.details() != &Details::Complete {
+            warn!("MQTT OTA job documents must fit in a single message, skipping");
+            return None;
+        }
+
+        Some(Command::Job(message.data().to_vec()))
+    }
+}
+
+impl Drop for EspMqttOta {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Command::Stop);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+struct Installer<T: NvsPartitionId> {
+    client: EspMqttClient,
+    conf: Configuration,
+    nvs: EspNvs<T>,
+}
+
+impl<T: NvsPartitionId> Installer<T> {
+    fn run(&mut self, receiver: mpsc::Receiver<Command>) {
+        while let Ok(command) = receiver.recv() {
+            match command {
+                Command::Connected => {
+                    if let Err(e) = self
+                        .client
+                        .subscribe(self.conf.command_topic, QoS::AtLeastOnce)
+                    {
+                        warn!(
+                            "MQTT OTA failed to subscribe to {}: {}",
+                            self.conf.command_topic, e
+                        );
+                    }
+                }
+                Command::Job(document) => self.handle(&document),
+                Command::Stop => break,
+            }
+        }
+    }
+
+    fn handle(&mut self, document: &[u8]) {
+        let job = match OtaJob::parse(self.conf.public_key, document).and_then(|job| {
+            self.check(&job)?;
+            Ok(job)
+        }) {
+            Ok(job) => job,
+            Err(error) => {
+                warn!("Rejected OTA job: {:?}", error);
+
+                // The job id of an unverified document is echoed only to correlate the status
+                let job_id = str::from_utf8(document)
+                    .ok()
+                    .and_then(Json::parse)
+                    .and_then(|json| json.get("job_id").and_then(Json::as_str).map(Into::into));
+
+                self.publish(job_id.as_deref(), "rejected", None, Some(error));
+                return;
+            }
+        };
+
+        info!(
+            "Installing OTA job {} (version {})",
+            job.job_id, job.version
+        );
+
+        match self.install(&job) {
+            Ok(()) => {
+                info!("Installed OTA job {}", job.job_id);
+
+                if let Err(e) = self.nvs.set_str(VERSION_KEY, &job.version) {
+                    warn!(
+                        "Failed to save the installed version {}: {}",
+                        job.version, e
+                    );
+                }
+
+                self.publish(Some(&job.job_id), "installed", Some(100), None);
+
+                if let Some(delay) = self.conf.reboot_delay {
+                    // Leaves time for the status to be delivered
+                    thread::sleep(delay);
+
+                    info!("Restarting into the new firmware");
+
+                    unsafe { esp_restart() };
+                }
+            }
+            Err(error) => {
+                warn!("OTA job {} failed: {:?}", job.job_id, error);
+
+                self.publish(Some(&job.job_id), "failed", None, Some(error));
+            }
+        }
+    }
+
+    fn check(&self, job: &OtaJob) -> Result<(), JobError> {
+        if !job
+            .url
+            .get(..8)
+            .map(|scheme| scheme.eq_ignore_ascii_case("https://"))
+            .unwrap_or(false)
+        {
+            return Err(JobError::Insecure);
+        }
+
+        let running = running_version();
+
+        if compare_versions(&job.version, &running) != Ordering::Greater {
+            info!(
+                "Rejected OTA job {}: version {} is not newer than the running {}",
+                job.job_id, job.version, running
+            );
+
+            return Err(JobError::Outdated);
+        }
+
+        // The running firmware may be older than the last installed one, if that was rolled back
+        let mut buf = [0_u8; 33];
+
+        if let Some(installed) = self.nvs.get_str(VERSION_KEY, &mut buf).ok().flatten() {
+            if compare_versions(&job.version, installed) != Ordering::Greater {
+                info!(
+                    "Rejected OTA job {}: version {} is not newer than the installed {}",
+                    job.job_id, job.version, installed
+                );
+
+                return Err(JobError::Outdated);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn install(&mut self, job: &OtaJob) -> Result<(), JobError> {
+        let mut connection = self.connect().map_err(|_| JobError::Download)?;
+
+        connection
+            .initiate_request(Method::Get, &job.url, &[])
+            .map_err(|_| JobError::Download)?;
+        connection
+            .initiate_response()
+            .map_err(|_| JobError::Download)?;
+
+        if connection.status() != 200 {
+            warn!(
+                "Firmware request failed with status {}",
+                connection.status()
+            );
+
+            return Err(JobError::Download);
+        }
+
+        let total = job.size.or_else(|| {
+            connection
+                .header("Content-Length")
+                .and_then(|len| len.parse().ok())
+        });
+
+        if let (Some(expected), Some(total)) = (job.size, total) {
+            if expected != total {
+                return Err(JobError::Verification);
+            }
+        }
+
+        let mut ota = EspOta::new().map_err(|_| JobError::Install)?;
+        let update = ota.initiate_update().map_err(|_| JobError::Install)?;
+
+        let mut sha256 = Sha256::new();
+
+        let mut buf = vec![0; self.conf.buffer_size];
+        let mut downloaded = 0_u32;
+        let mut reported = 0;
+
+        self.publish(Some(&job.job_id), "downloading", Some(0), None);
+
+        let result = loop {
+            let len = match connection.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(len) => len,
+                Err(_) => break Err(JobError::Download),
+            };
+
+            if update.write(&buf[..len]).is_err() {
+                break Err(JobError::Install);
+            }
+
+            sha256.update(&buf[..len]);
+
+            downloaded += len as u32;
+
+            // Every 10%, if the size is known
+            if let Some(total) = total {
+                let progress = (downloaded as u64 * 10 / total.max(1) as u64).min(9) as u8 * 10;
+
+                if progress != reported {
+                    reported = progress;
+
+                    self.publish(Some(&job.job_id), "downloading", Some(progress), None);
+                }
+            }
+        };
+
+        let result = result.and_then(|_| {
+            if total.map(|total| total != downloaded).unwrap_or(false) {
+                return Err(JobError::Download);
+            }
+
+            let actual = hex::encode(&sha256.finish());
+
+            if actual != job.sha256 {
+                warn!("Firmware SHA-256 mismatch: {} != {}", actual, job.sha256);
+
+                return Err(JobError::Verification);
+            }
+
+            Ok(())
+        });
+
+        match result {
+            // Validates the image, and switches the boot slot
+            Ok(()) => update.complete().map_err(|_| JobError::Install),
+            Err(error) => {
+                let _ = update.abort();
+
+                Err(error)
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<EspHttpConnection, EspError> {
+        EspHttpConnection::new(&client::Configuration {
+            timeout: Some(self.conf.timeout),
+            #[cfg(all(not(esp_idf_version = "4.3"), esp_idf_mbedtls_certificate_bundle))]
+            crt_bundle_attach: Some(esp_crt_bundle_attach),
+            #[cfg(feature = "traffic")]
+            service: Service::Ota,
+            #[cfg(feature = "traffic")]
+            throttle: self.conf.throttle,
+            ..Default::default()
+        })
+    }
+
+    fn publish(
+        &mut self,
+        job_id: Option<&str>,
+        state: &str,
+        progress: Option<u8>,
+        error: Option<JobError>,
+    ) {
+        let status = json::object([
+            ("job_id", job_id.map(Json::from).unwrap_or(Json::Null)),
+            ("state", state.into()),
+            (
+                "progress",
+                progress
+                    .map(|progress| Json::from(progress as u64))
+                    .unwrap_or(Json::Null),
+            ),
+            (
+                "error",
+                error
+                    .map(|error| Json::from(error.as_str()))
+                    .unwrap_or(Json::Null),
+            ),
+        ]);
+
+        if let Err(e) = self.client.enqueue(
+            self.conf.status_topic,
+            QoS::AtLeastOnce,
+            false,
+            status.to_string().as_bytes(),
+        ) {
+            warn!("MQTT OTA failed to publish the status: {}", e);
+        }
+    }
+}
+
+fn running_version() -> String {
+    #[cfg(esp_idf_version_major = "4")]
+    let app_desc = unsafe { esp_ota_get_app_description().as_ref() };
+    #[cfg(not(esp_idf_version_major = "4"))]
+    let app_desc = unsafe { esp_app_get_description().as_ref() };
+
+    app_desc
+        .map(|app_desc| unsafe { from_cstr_ptr(&app_desc.version as *const _) }.into())
+        .unwrap_or_default()
+}
+
+/// Compares the numeric components of two versions (e.g. `v1.10.2-rc1`), in order
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let components = |version: &str| -> Vec<u64> {
+        let mut components: Vec<u64> = version
+            .split(|c: char| !c.is_ascii_digit())
+            .filter(|component| !component.is_empty())
+            .map(|component| component.parse().unwrap_or(0))
+            .collect();
+
+        // `1.2` is `1.2.0`
+        while components.last() == Some(&0) {
+            components.pop();
+        }
+
+        components
+    };
+
+    components(a).cmp(&components(b))
+}
@@ -0,0 +1,480 @@
+//! Device shadows
+//!
+//! `EspShadow` keeps a typed device state in sync with its cloud-side document, following the
+//! desired/reported state pattern: the backend publishes the state it wants the device to be
+//! in, the device applies the difference to its current state, and reports the resulting state
+//! back. Two flavors of the pattern are supported:
+//!
+//! - `Flavor::Aws`, the AWS IoT Device Shadow service, with classic and named shadows: the device
+//!   fetches the shadow document on every connection, applies the `delta` of the desired and
+//!   reported states, and reports its state on `.../shadow/update`
+//! - `Flavor::Generic`, for any broker: the desired state is a JSON merge patch (RFC 7396)
+//!   published (usually retained) on `<prefix>/desired`, and the complete reported state is
+//!   published retained on `<prefix>/reported`
+//!
+//! The state is any serde (de)serializable struct. The desired state is obtained by merging
+//! the delta into the current state, and is submitted to the resolution hook, which applies it
+//! to the device, and decides which state is reported:
+//!
+//! ```ignore
+//! #[derive(Clone, PartialEq, Serialize, Deserialize)]
+//! struct State {
+//!     led: bool,
+//!     brightness: u8,
+//! }
+//!
+//! let shadow = EspShadow::new(
+//!     "mqtts://xxxxxxxx-ats.iot.eu-west-1.amazonaws.com",
+//!     &mqtt_conf,
+//!     &Configuration {
+//!         flavor: Flavor::Aws {
+//!             thing_name: "sensor-1".into(),
+//!             shadow_name: None,
+//!         },
+//!         ..Default::default()
+//!     },
+//!     State { led: false, brightness: 100 },
+//!     move |_current, desired: &State| {
+//!         if desired.brightness > 100 {
+//!             return Resolution::Reject;
+//!         }
+//!
+//!         led.lock().set(desired.led, desired.brightness);
+//!
+//!         Resolution::Accept
+//!     },
+//! )?;
+//!
+//! // Changes which originate on the device are reported too
+//! shadow.report(State { led: true, ..shadow.state() })?;
+//! ```
+//!
+//! Note: This module requires the `shadow` cargo feature to be enabled.
+use std::string::String;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::mqtt::client::{Details, Event, QoS};
+
+use esp_idf_sys::*;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::private::mutex::Mutex;
+
+use super::client::{EspMqttClient, EspMqttMessage, MqttClientConfiguration};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Flavor {
+    /// The AWS IoT Device Shadow service; the classic shadow of the thing if `shadow_name`
+    /// is `None`
+    Aws {
+        thing_name: String,
+        shadow_name: Option<String>,
+    },
+    /// The desired and reported states below `prefix` (e.g. `devices/sensor-1/shadow`)
+    Generic { prefix: String },
+}
+
+impl Flavor {
+    fn topic(&self, suffix: &str) -> String {
+        match self {
+            Self::Aws {
+                thing_name,
+                shadow_name: None,
+            } => format!("$aws/things/{}/shadow/{}", thing_name, suffix),
+            Self::Aws {
+                thing_name,
+                shadow_name: Some(shadow_name),
+            } => format!(
+                "$aws/things/{}/shadow/name/{}/{}",
+                thing_name, shadow_name, suffix
+            ),
+            Self::Generic { prefix } => format!("{}/{}", prefix.trim_end_matches('/'), suffix),
+        }
+    }
+
+    /// The topics to subscribe to
+    fn subscriptions(&self) -> Vec<String> {
+        match self {
+            Self::Aws { .. } => vec![
+                self.topic("get/accepted"),
+                self.topic("get/rejected"),
+                self.topic("update/delta"),
+                self.topic("update/rejected"),
+            ],
+            Self::Generic { .. } => vec![self.topic("desired")],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub flavor: Flavor,
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub qos: QoS,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            flavor: Flavor::Generic {
+                prefix: String::new(),
+            },
+            qos: QoS::AtLeastOnce,
+            stack_size: 6144,
+        }
+    }
+}
+
+/// The decision of the resolution hook on a desired state
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Resolution<S> {
+    /// The desired state was applied, and becomes the reported state
+    Accept,
+    /// Another state (e.g. the desired one, with out of range values clamped) was applied
+    Replace(S),
+    /// Nothing was applied; the current state is reported again, so that the backend sees that
+    /// the desired state is still pending
+    Reject,
+}
+
+enum Command<S> {
+    Connected,
+    Received { topic: String, payload: Vec<u8> },
+    Report(S),
+    Stop,
+}
+
+pub struct EspShadow<S>
+where
+    S: Send + 'static,
+{
+    state: Arc<Mutex<S>>,
+    sender: mpsc::Sender<Command<S>>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl<S> EspShadow<S>
+where
+    S: Serialize + DeserializeOwned + Clone + PartialEq + Send + 'static,
+{
+    /// Connects to the broker at `url`, starting from the `initial` state of the device
+    ///
+    /// `resolve` is called with the current and the desired states, every time a change of
+    /// the state is requested by the backend.
+    pub fn new<'a, F>(
+        url: &str,
+        mqtt_conf: &'a MqttClientConfiguration<'a>,
+        conf: &Configuration,
+        initial: S,
+        resolve: F,
+    ) -> Result<Self, EspError>
+    where
+        F: FnMut(&S, &S) -> Resolution<S> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        let callback_sender = sender.clone();
+
+        // Publishing from within the event callback might deadlock, hence all messages are
+        // handled from the shadow thread
+        let client = EspMqttClient::new(url, mqtt_conf, move |event| {
+            let command = match event {
+                Ok(Event::Connected(_)) => Command::Connected,
+                Ok(Event::Received(message)) => match Self::to_command(message) {
+                    Some(command) => command,
+                    None => return,
+                },
+                Ok(_) => return,
+                Err(e) => {
+                    warn!("Shadow MQTT client error: {:?}", e);
+                    return;
+                }
+            };
+
+            let _ = callback_sender.send(command);
+        })?;
+
+        let state = Arc::new(Mutex::new(initial));
+
+        let mut synchronizer = Synchronizer {
+            client,
+            flavor: conf.flavor.clone(),
+            qos: conf.qos,
+            state: state.clone(),
+            version: None,
+            resolve,
+        };
+
+        let join_handle = thread::Builder::new()
+            .name("shadow".into())
+            .stack_size(conf.stack_size)
+            .spawn(move || synchronizer.run(receiver))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        info!("Started shadow on {}", conf.flavor.topic(""));
+
+        Ok(Self {
+            state,
+            sender,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// The current state of the device, i.e. the last reported one
+    pub fn state(&self) -> S {
+        self.state.lock().clone()
+    }
+
+    /// Updates the state of the device, and reports it to the backend
+    pub fn report(&self, state: S) -> Result<(), EspError> {
+        *self.state.lock() = state.clone();
+
+        self.sender
+            .send(Command::Report(state))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+    }
+
+    fn to_command(message: &EspMqttMessage) -> Option<Command<S>> {
+        if message.details() != &Details::Complete {
+            warn!("Shadow documents must fit in a single message, skipping");
+            return None;
+        }
+
+        Some(Command::Received {
+            topic: message.topic()?.into(),
+            payload: message.data().to_vec(),
+        })
+    }
+}
+
+impl<S> Drop for EspShadow<S>
+where
+    S: Send + 'static,
+{
+    fn drop(&mut self) {
+        let _ = self.sender.send(Command::Stop);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+struct Synchronizer<S, F> {
+    client: EspMqttClient,
+    flavor: Flavor,
+    qos: QoS,
+    state: Arc<Mutex<S>>,
+    /// The version of the last AWS shadow document applied
+    version: Option<u64>,
+    resolve: F,
+}
+
+impl<S, F> Synchronizer<S, F>
+where
+    S: Serialize + DeserializeOwned + Clone + PartialEq,
+    F: FnMut(&S, &S) -> Resolution<S>,
+{
+    fn run(&mut self, receiver: mpsc::Receiver<Command<S>>) {
+        while let Ok(command) = receiver.recv() {
+            match command {
+                Command::Connected => self.connected(),
+                Command::Received { topic, payload } => self.received(&topic, &payload),
+                Command::Report(state) => self.publish_reported(&state),
+                Command::Stop => break,
+            }
+        }
+    }
+
+    fn connected(&mut self) {
+        for topic in self.flavor.subscriptions() {
+            if let Err(e) = self.client.subscribe(&topic, self.qos) {
+                warn!("Shadow failed to subscribe to {}: {}", topic, e);
+            }
+        }
+
+        match self.flavor {
+            // The reported state is published once the document is received, as it may
+            // contain a delta to apply first
+            Flavor::Aws { .. } => self.publish(&self.flavor.topic("get"), b"{}", false),
+            Flavor::Generic { .. } => {
+                let state = self.state.lock().clone();
+
+                self.publish_reported(&state);
+            }
+        }
+    }
+
+    fn received(&mut self, topic: &str, payload: &[u8]) {
+        let document = match serde_json::from_slice::<Value>(payload) {
+            Ok(document) => document,
+            Err(e) => {
+                warn!("Invalid shadow document on {}: {}", topic, e);
+                return;
+            }
+        };
+
+        let suffix = match topic.strip_prefix(self.flavor.topic("").as_str()) {
+            Some(suffix) => suffix,
+            None => return,
+        };
+
+        match (&self.flavor, suffix) {
+            (Flavor::Aws { .. }, "get/accepted") => {
+                // The complete document supersedes any delta received before
+                self.version = document.get("version").and_then(Value::as_u64);
+
+                match document.pointer("/state/delta") {
+                    Some(delta) => self.apply(delta),
+                    None => {
+                        // In sync, but the document may not know all of the reported fields yet
+                        let state = self.state.lock().clone();
+
+                        self.publish_reported(&state);
+                    }
+                }
+            }
+            (Flavor::Aws { .. }, "update/delta") => {
+                if self.is_newer(&document) {
+                    if let Some(delta) = document.get("state") {
+                        self.apply(delta);
+                    }
+                }
+            }
+            (Flavor::Aws { .. }, "get/rejected") => {
+                // 404: there is no shadow yet, create it with the reported state
+                if document.get("code").and_then(Value::as_u64) == Some(404) {
+                    let state = self.state.lock().clone();
+
+                    self.publish_reported(&state);
+                } else {
+                    warn!("Shadow get rejected: {}", document);
+                }
+            }
+            (Flavor::Aws { .. }, "update/rejected") => {
+                warn!("Shadow update rejected: {}", document);
+            }
+            (Flavor::Generic { .. }, "desired") => self.apply(&document),
+            _ => (),
+        }
+    }
+
+    /// Whether the version of an AWS shadow document is newer than the one of the last
+    /// applied delta, as deltas may be delivered out of order
+    fn is_newer(&mut self, document: &Value) -> bool {
+        match document.get("version").and_then(Value::as_u64) {
+            Some(version) if self.version.map(|last| version <= last).unwrap_or(false) => false,
+            Some(version) => {
+                self.version = Some(version);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Merges `delta` into the current state, and submits the result to the resolution hook
+    fn apply(&mut self, delta: &Value) {
+        let current = self.state.lock().clone();
+
+        let desired = serde_json::to_value(&current).map(|mut desired| {
+            merge(&mut desired, delta);
+            desired
+        });
+
+        let desired = match desired.and_then(serde_json::from_value::<S>) {
+            Ok(desired) => desired,
+            Err(e) => {
+                warn!("Rejected the desired shadow state: {}", e);
+
+                self.publish_reported(&current);
+                return;
+            }
+        };
+
+        let reported = if desired == current {
+            current
+        } else {
+            match (self.resolve)(&current, &desired) {
+                Resolution::Accept => desired,
+                Resolution::Replace(state) => state,
+                Resolution::Reject => {
+                    info!("The desired shadow state was rejected");
+
+                    current
+                }
+            }
+        };
+
+        *self.state.lock() = reported.clone();
+
+        self.publish_reported(&reported);
+    }
+
+    fn publish_reported(&mut self, state: &S) {
+        let state = match serde_json::to_value(state) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Failed to serialize the shadow state: {}", e);
+                return;
+            }
+        };
+
+        match self.flavor {
+            Flavor::Aws { .. } => {
+                let document = json!({ "state": { "reported": state } });
+
+                self.publish(
+                    &self.flavor.topic("update"),
+                    document.to_string().as_bytes(),
+                    false,
+                );
+            }
+            Flavor::Generic { .. } => self.publish(
+                &self.flavor.topic("reported"),
+                state.to_string().as_bytes(),
+                true,
+            ),
+        }
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        if let Err(e) = self.client.enqueue(topic, self.qos, retain, payload) {
+            warn!("Shadow failed to publish to {}: {}", topic, e);
+        }
+    }
+}
+
+/// Applies a JSON merge patch (RFC 7396) to `target`
+fn merge(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Default::default());
+            }
+
+            if let Value::Object(target) = target {
+                for (key, value) in patch {
+                    if value.is_null() {
+                        target.remove(key);
+                    } else {
+                        merge(target.entry(key.clone()).or_insert(Value::Null), value);
+                    }
+                }
+            }
+        }
+        patch => *target = patch.clone(),
+    }
+}
@@ -0,0 +1,755 @@
+//! Sparkplug B support
+//!
+//! Sparkplug B topic namespace, birth/death certificates and protobuf payload encoding,
+//! layered on top of any MQTT client implementing `embedded_svc::mqtt::client::Publish`.
+//!
+//! Note: This module requires the `sparkplug` cargo feature to be enabled.
+use core::fmt::{self, Display};
+
+extern crate alloc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::mqtt::client::{MessageId, Publish, QoS};
+
+use esp_idf_sys::*;
+
+use crate::systime::EspSystemTime;
+
+pub const NAMESPACE: &str = "spBv1.0";
+
+/// Name of the birth/death sequence metric, which has to be part of every NBIRTH and NDEATH message
+pub const BD_SEQ: &str = "bdSeq";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum MessageType {
+    NodeBirth,
+    NodeDeath,
+    DeviceBirth,
+    DeviceDeath,
+    NodeData,
+    DeviceData,
+    NodeCommand,
+    DeviceCommand,
+    State,
+}
+
+impl MessageType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NodeBirth => "NBIRTH",
+            Self::NodeDeath => "NDEATH",
+            Self::DeviceBirth => "DBIRTH",
+            Self::DeviceDeath => "DDEATH",
+            Self::NodeData => "NDATA",
+            Self::DeviceData => "DDATA",
+            Self::NodeCommand => "NCMD",
+            Self::DeviceCommand => "DCMD",
+            Self::State => "STATE",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "NBIRTH" => Self::NodeBirth,
+            "NDEATH" => Self::NodeDeath,
+            "DBIRTH" => Self::DeviceBirth,
+            "DDEATH" => Self::DeviceDeath,
+            "NDATA" => Self::NodeData,
+            "DDATA" => Self::DeviceData,
+            "NCMD" => Self::NodeCommand,
+            "DCMD" => Self::DeviceCommand,
+            "STATE" => Self::State,
+            _ => return None,
+        })
+    }
+}
+
+/// A Sparkplug B topic: `spBv1.0/<group_id>/<message_type>/<edge_node_id>[/<device_id>]`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Topic<'a> {
+    pub group_id: &'a str,
+    pub message_type: MessageType,
+    pub edge_node_id: &'a str,
+    pub device_id: Option<&'a str>,
+}
+
+impl<'a> Topic<'a> {
+    pub fn parse(topic: &'a str) -> Option<Self> {
+        let mut parts = topic.split('/');
+
+        if parts.next()? != NAMESPACE {
+            return None;
+        }
+
+        let group_id = parts.next()?;
+        let message_type = MessageType::from_name(parts.next()?)?;
+        let edge_node_id = parts.next()?;
+        let device_id = parts.next();
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(Self {
+            group_id,
+            message_type,
+            edge_node_id,
+            device_id,
+        })
+    }
+}
+
+impl<'a> Display for Topic<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}/{}",
+            NAMESPACE,
+            self.group_id,
+            self.message_type.as_str(),
+            self.edge_node_id
+        )?;
+
+        if let Some(device_id) = self.device_id {
+            write!(f, "/{device_id}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Hash))]
+#[repr(u32)]
+pub enum DataType {
+    Int8 = 1,
+    Int16 = 2,
+    Int32 = 3,
+    Int64 = 4,
+    UInt8 = 5,
+    UInt16 = 6,
+    UInt32 = 7,
+    UInt64 = 8,
+    Float = 9,
+    Double = 10,
+    Boolean = 11,
+    String = 12,
+    DateTime = 13,
+    Text = 14,
+    Bytes = 17,
+}
+
+impl DataType {
+    fn from_u32(value: u32) -> Option<Self> {
+        Some(match value {
+            1 => Self::Int8,
+            2 => Self::Int16,
+            3 => Self::Int32,
+            4 => Self::Int64,
+            5 => Self::UInt8,
+            6 => Self::UInt16,
+            7 => Self::UInt32,
+            8 => Self::UInt64,
+            9 => Self::Float,
+            10 => Self::Double,
+            11 => Self::Boolean,
+            12 => Self::String,
+            13 => Self::DateTime,
+            14 => Self::Text,
+            17 => Self::Bytes,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetricValue {
+    Int8(i8),
+    Int16(i16),
+    Int32(i32),
+    Int64(i64),
+    UInt8(u8),
+    UInt16(u16),
+    UInt32(u32),
+    UInt64(u64),
+    Float(f32),
+    Double(f64),
+    Boolean(bool),
+    String(String),
+    /// Milliseconds since the Unix epoch
+    DateTime(u64),
+    Text(String),
+    Bytes(Vec<u8>),
+    Null(DataType),
+}
+
+impl MetricValue {
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Self::Int8(_) => DataType::Int8,
+            Self::Int16(_) => DataType::Int16,
+            Self::Int32(_) => DataType::Int32,
+            Self::Int64(_) => DataType::Int64,
+            Self::UInt8(_) => DataType::UInt8,
+            Self::UInt16(_) => DataType::UInt16,
+            Self::UInt32(_) => DataType::UInt32,
+            Self::UInt64(_) => DataType::UInt64,
+            Self::Float(_) => DataType::Float,
+            Self::Double(_) => DataType::Double,
+            Self::Boolean(_) => DataType::Boolean,
+            Self::String(_) => DataType::String,
+            Self::DateTime(_) => DataType::DateTime,
+            Self::Text(_) => DataType::Text,
+            Self::Bytes(_) => DataType::Bytes,
+            Self::Null(data_type) => *data_type,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Metric {
+    pub name: Option<String>,
+    pub alias: Option<u64>,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: Option<u64>,
+    pub value: MetricValue,
+}
+
+impl Metric {
+    pub fn new(name: impl Into<String>, value: MetricValue) -> Self {
+        Self {
+            name: Some(name.into()),
+            alias: None,
+            timestamp: None,
+            value,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Payload {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: Option<u64>,
+    pub metrics: Vec<Metric>,
+    pub seq: Option<u64>,
+    pub uuid: Option<String>,
+    pub body: Option<Vec<u8>>,
+}
+
+mod field {
+    pub const PAYLOAD_TIMESTAMP: u32 = 1;
+    pub const PAYLOAD_METRICS: u32 = 2;
+    pub const PAYLOAD_SEQ: u32 = 3;
+    pub const PAYLOAD_UUID: u32 = 4;
+    pub const PAYLOAD_BODY: u32 = 5;
+
+    pub const METRIC_NAME: u32 = 1;
+    pub const METRIC_ALIAS: u32 = 2;
+    pub const METRIC_TIMESTAMP: u32 = 3;
+    pub const METRIC_DATATYPE: u32 = 4;
+    pub const METRIC_IS_NULL: u32 = 7;
+    pub const METRIC_INT_VALUE: u32 = 10;
+    pub const METRIC_LONG_VALUE: u32 = 11;
+    pub const METRIC_FLOAT_VALUE: u32 = 12;
+    pub const METRIC_DOUBLE_VALUE: u32 = 13;
+    pub const METRIC_BOOLEAN_VALUE: u32 = 14;
+    pub const METRIC_STRING_VALUE: u32 = 15;
+    pub const METRIC_BYTES_VALUE: u32 = 16;
+}
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_64BIT: u32 = 1;
+const WIRE_LEN: u32 = 2;
+const WIRE_32BIT: u32 = 5;
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(((field << 3) | wire_type) as u64);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.key(field, WIRE_VARINT);
+        self.varint(value);
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, WIRE_LEN);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn fixed32(&mut self, field: u32, value: u32) {
+        self.key(field, WIRE_32BIT);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn fixed64(&mut self, field: u32, value: u64) {
+        self.key(field, WIRE_64BIT);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn metric(&mut self, metric: &Metric) {
+        use field::*;
+
+        if let Some(name) = metric.name.as_ref() {
+            self.bytes(METRIC_NAME, name.as_bytes());
+        }
+
+        if let Some(alias) = metric.alias {
+            self.uint(METRIC_ALIAS, alias);
+        }
+
+        if let Some(timestamp) = metric.timestamp {
+            self.uint(METRIC_TIMESTAMP, timestamp);
+        }
+
+        self.uint(METRIC_DATATYPE, metric.value.data_type() as u32 as _);
+
+        // Signed values are transmitted in their two's complement, unsigned representation
+        match &metric.value {
+            MetricValue::Int8(value) => self.uint(METRIC_INT_VALUE, *value as u8 as _),
+            MetricValue::Int16(value) => self.uint(METRIC_INT_VALUE, *value as u16 as _),
+            MetricValue::Int32(value) => self.uint(METRIC_INT_VALUE, *value as u32 as _),
+            MetricValue::Int64(value) => self.uint(METRIC_LONG_VALUE, *value as u64),
+            MetricValue::UInt8(value) => self.uint(METRIC_INT_VALUE, *value as _),
+            MetricValue::UInt16(value) => self.uint(METRIC_INT_VALUE, *value as _),
+            MetricValue::UInt32(value) => self.uint(METRIC_INT_VALUE, *value as _),
+            MetricValue::UInt64(value) | MetricValue::DateTime(value) => {
+                self.uint(METRIC_LONG_VALUE, *value)
+            }
+            MetricValue::Float(value) => self.fixed32(METRIC_FLOAT_VALUE, value.to_bits()),
+            MetricValue::Double(value) => self.fixed64(METRIC_DOUBLE_VALUE, value.to_bits()),
+            MetricValue::Boolean(value) => self.uint(METRIC_BOOLEAN_VALUE, *value as _),
+            MetricValue::String(value) | MetricValue::Text(value) => {
+                self.bytes(METRIC_STRING_VALUE, value.as_bytes())
+            }
+            MetricValue::Bytes(value) => self.bytes(METRIC_BYTES_VALUE, value),
+            MetricValue::Null(_) => self.uint(METRIC_IS_NULL, 1),
+        }
+    }
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn varint(&mut self) -> Result<u64, EspError> {
+        let mut value = 0_u64;
+
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = self.0.split_first().ok_or_else(Self::error)?;
+            self.0 = rest;
+
+            value |= ((byte & 0x7f) as u64) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(Self::error())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], EspError> {
+        if self.0.len() < len {
+            Err(Self::error())
+        } else {
+            let (data, rest) = self.0.split_at(len);
+            self.0 = rest;
+
+            Ok(data)
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<(u32, Value<'a>)>, EspError> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+
+        let key = self.varint()?;
+        let field = (key >> 3) as u32;
+
+        let value = match (key & 0x07) as u32 {
+            WIRE_VARINT => Value::Varint(self.varint()?),
+            WIRE_64BIT => {
+                let mut buf = [0; 8];
+                buf.copy_from_slice(self.take(8)?);
+
+                Value::Fixed64(u64::from_le_bytes(buf))
+            }
+            WIRE_LEN => {
+                let len = self.varint()? as usize;
+
+                Value::Bytes(self.take(len)?)
+            }
+            WIRE_32BIT => {
+                let mut buf = [0; 4];
+                buf.copy_from_slice(self.take(4)?);
+
+                Value::Fixed32(u32::from_le_bytes(buf))
+            }
+            _ => return Err(Self::error()),
+        };
+
+        Ok(Some((field, value)))
+    }
+
+    fn string(data: &[u8]) -> Result<String, EspError> {
+        core::str::from_utf8(data)
+            .map(Into::into)
+            .map_err(|_| Self::error())
+    }
+
+    fn metric(data: &'a [u8]) -> Result<Metric, EspError> {
+        use field::*;
+
+        let mut decoder = Decoder(data);
+
+        let mut name = None;
+        let mut alias = None;
+        let mut timestamp = None;
+        let mut data_type = None;
+        let mut is_null = false;
+        let mut raw = None;
+
+        while let Some((field, value)) = decoder.next()? {
+            match (field, value) {
+                (METRIC_NAME, Value::Bytes(data)) => name = Some(Self::string(data)?),
+                (METRIC_ALIAS, Value::Varint(value)) => alias = Some(value),
+                (METRIC_TIMESTAMP, Value::Varint(value)) => timestamp = Some(value),
+                (METRIC_DATATYPE, Value::Varint(value)) => {
+                    data_type = Some(DataType::from_u32(value as _).ok_or_else(Self::error)?)
+                }
+                (METRIC_IS_NULL, Value::Varint(value)) => is_null = value != 0,
+                (METRIC_INT_VALUE..=METRIC_BYTES_VALUE, value) => raw = Some(value),
+                _ => (),
+            }
+        }
+
+        let data_type = data_type.ok_or_else(Self::error)?;
+
+        let value = if is_null {
+            MetricValue::Null(data_type)
+        } else {
+            match (data_type, raw.ok_or_else(Self::error)?) {
+                (DataType::Int8, Value::Varint(v)) => MetricValue::Int8(v as u8 as _),
+                (DataType::Int16, Value::Varint(v)) => MetricValue::Int16(v as u16 as _),
+                (DataType::Int32, Value::Varint(v)) => MetricValue::Int32(v as u32 as _),
+                (DataType::Int64, Value::Varint(v)) => MetricValue::Int64(v as _),
+                (DataType::UInt8, Value::Varint(v)) => MetricValue::UInt8(v as _),
+                (DataType::UInt16, Value::Varint(v)) => MetricValue::UInt16(v as _),
+                (DataType::UInt32, Value::Varint(v)) => MetricValue::UInt32(v as _),
+                (DataType::UInt64, Value::Varint(v)) => MetricValue::UInt64(v),
+                (DataType::DateTime, Value::Varint(v)) => MetricValue::DateTime(v),
+                (DataType::Float, Value::Fixed32(v)) => MetricValue::Float(f32::from_bits(v)),
+                (DataType::Double, Value::Fixed64(v)) => MetricValue::Double(f64::from_bits(v)),
+                (DataType::Boolean, Value::Varint(v)) => MetricValue::Boolean(v != 0),
+                (DataType::String, Value::Bytes(data)) => MetricValue::String(Self::string(data)?),
+                (DataType::Text, Value::Bytes(data)) => MetricValue::Text(Self::string(data)?),
+                (DataType::Bytes, Value::Bytes(data)) => MetricValue::Bytes(data.to_vec()),
+                _ => return Err(Self::error()),
+            }
+        };
+
+        Ok(Metric {
+            name,
+            alias,
+            timestamp,
+            value,
+        })
+    }
+
+    fn error() -> EspError {
+        EspError::from_infallible::<ESP_ERR_INVALID_ARG>()
+    }
+}
+
+impl Payload {
+    pub fn new(metrics: Vec<Metric>) -> Self {
+        Self {
+            timestamp: Some(now()),
+            metrics,
+            ..Default::default()
+        }
+    }
+
+    /// Encodes the payload as a Sparkplug B protobuf message
+    pub fn encode(&self) -> Vec<u8> {
+        use field::*;
+
+        let mut encoder = Encoder(Vec::new());
+
+        if let Some(timestamp) = self.timestamp {
+            encoder.uint(PAYLOAD_TIMESTAMP, timestamp);
+        }
+
+        for metric in &self.metrics {
+            let mut metric_encoder = Encoder(Vec::new());
+            metric_encoder.metric(metric);
+
+            encoder.bytes(PAYLOAD_METRICS, &metric_encoder.0);
+        }
+
+        if let Some(seq) = self.seq {
+            encoder.uint(PAYLOAD_SEQ, seq);
+        }
+
+        if let Some(uuid) = self.uuid.as_ref() {
+            encoder.bytes(PAYLOAD_UUID, uuid.as_bytes());
+        }
+
+        if let Some(body) = self.body.as_ref() {
+            encoder.bytes(PAYLOAD_BODY, body);
+        }
+
+        encoder.0
+    }
+
+    /// Decodes a Sparkplug B protobuf message; metrics of unsupported data types
+    /// (data sets, templates, etc.) result in an `ESP_ERR_INVALID_ARG` error
+    pub fn decode(data: &[u8]) -> Result<Self, EspError> {
+        use field::*;
+
+        let mut decoder = Decoder(data);
+        let mut payload = Self::default();
+
+        while let Some((field, value)) = decoder.next()? {
+            match (field, value) {
+                (PAYLOAD_TIMESTAMP, Value::Varint(value)) => payload.timestamp = Some(value),
+                (PAYLOAD_METRICS, Value::Bytes(data)) => {
+                    payload.metrics.push(Decoder::metric(data)?)
+                }
+                (PAYLOAD_SEQ, Value::Varint(value)) => payload.seq = Some(value),
+                (PAYLOAD_UUID, Value::Bytes(data)) => payload.uuid = Some(Decoder::string(data)?),
+                (PAYLOAD_BODY, Value::Bytes(data)) => payload.body = Some(data.to_vec()),
+                _ => (),
+            }
+        }
+
+        Ok(payload)
+    }
+}
+
+fn now() -> u64 {
+    EspSystemTime.now().as_millis() as _
+}
+
+/// A Sparkplug B edge node
+///
+/// Keeps track of the message sequence numbers and the birth/death sequence number (`bdSeq`)
+/// of the node. The node is expected to be connected with the NDEATH certificate returned by
+/// `death_certificate` set as the MQTT last will, and to publish its NBIRTH certificate
+/// (followed by the DBIRTH certificates of its devices) on every (re)connect.
+pub struct EdgeNode {
+    group_id: String,
+    edge_node_id: String,
+    bd_seq: u64,
+    seq: u64,
+}
+
+impl EdgeNode {
+    /// Creates a new edge node; `bd_seq` should be incremented (and persisted, e.g. in NVS)
+    /// on each new MQTT session
+    pub fn new(group_id: impl Into<String>, edge_node_id: impl Into<String>, bd_seq: u64) -> Self {
+        Self {
+            group_id: group_id.into(),
+            edge_node_id: edge_node_id.into(),
+            bd_seq,
+            seq: 0,
+        }
+    }
+
+    pub fn bd_seq(&self) -> u64 {
+        self.bd_seq
+    }
+
+    pub fn topic(&self, message_type: MessageType, device_id: Option<&str>) -> String {
+        Topic {
+            group_id: &self.group_id,
+            message_type,
+            edge_node_id: &self.edge_node_id,
+            device_id,
+        }
+        .to_string()
+    }
+
+    /// Topic and payload of the NDEATH certificate, to be used as the MQTT last will
+    pub fn death_certificate(&self) -> (String, Vec<u8>) {
+        let payload = Payload {
+            timestamp: Some(now()),
+            metrics: vec![self.bd_seq_metric()],
+            ..Default::default()
+        };
+
+        (self.topic(MessageType::NodeDeath, None), payload.encode())
+    }
+
+    /// Publishes the NBIRTH certificate, resetting the message sequence number
+    pub fn publish_birth<P>(
+        &mut self,
+        client: &mut P,
+        metrics: &[Metric],
+    ) -> Result<MessageId, P::Error>
+    where
+        P: Publish,
+    {
+        self.seq = 0;
+
+        let mut all_metrics = Vec::with_capacity(metrics.len() + 1);
+        all_metrics.push(self.bd_seq_metric());
+        all_metrics.extend_from_slice(metrics);
+
+        info!(
+            "Publishing Sparkplug NBIRTH for {}/{}",
+            self.group_id, self.edge_node_id
+        );
+
+        self.publish(client, MessageType::NodeBirth, None, all_metrics)
+    }
+
+    /// Explicitly publishes the NDEATH certificate, e.g. before a graceful disconnect
+    pub fn publish_death<P>(&mut self, client: &mut P) -> Result<MessageId, P::Error>
+    where
+        P: Publish,
+    {
+        let (topic, payload) = self.death_certificate();
+
+        client.publish(&topic, QoS::AtLeastOnce, false, &payload)
+    }
+
+    pub fn publish_data<P>(
+        &mut self,
+        client: &mut P,
+        metrics: &[Metric],
+    ) -> Result<MessageId, P::Error>
+    where
+        P: Publish,
+    {
+        self.publish(client, MessageType::NodeData, None, metrics.to_vec())
+    }
+
+    pub fn publish_device_birth<P>(
+        &mut self,
+        client: &mut P,
+        device_id: &str,
+        metrics: &[Metric],
+    ) -> Result<MessageId, P::Error>
+    where
+        P: Publish,
+    {
+        self.publish(
+            client,
+            MessageType::DeviceBirth,
+            Some(device_id),
+            metrics.to_vec(),
+        )
+    }
+
+    pub fn publish_device_death<P>(
+        &mut self,
+        client: &mut P,
+        device_id: &str,
+    ) -> Result<MessageId, P::Error>
+    where
+        P: Publish,
+    {
+        self.publish(
+            client,
+            MessageType::DeviceDeath,
+            Some(device_id),
+            Vec::new(),
+        )
+    }
+
+    pub fn publish_device_data<P>(
+        &mut self,
+        client: &mut P,
+        device_id: &str,
+        metrics: &[Metric],
+    ) -> Result<MessageId, P::Error>
+    where
+        P: Publish,
+    {
+        self.publish(
+            client,
+            MessageType::DeviceData,
+            Some(device_id),
+            metrics.to_vec(),
+        )
+    }
+
+    /// Parses an incoming NCMD or DCMD message addressed to this node, returning the
+    /// target device (if any) and the decoded payload
+    pub fn parse_command<'a>(
+        &self,
+        topic: &'a str,
+        data: &[u8],
+    ) -> Option<Result<(Option<&'a str>, Payload), EspError>> {
+        let topic = Topic::parse(topic)?;
+
+        if topic.group_id != self.group_id
+            || topic.edge_node_id != self.edge_node_id
+            || !matches!(
+                topic.message_type,
+                MessageType::NodeCommand | MessageType::DeviceCommand
+            )
+        {
+            return None;
+        }
+
+        Some(Payload::decode(data).map(|payload| (topic.device_id, payload)))
+    }
+
+    fn bd_seq_metric(&self) -> Metric {
+        Metric::new(BD_SEQ, MetricValue::UInt64(self.bd_seq))
+    }
+
+    fn publish<P>(
+        &mut self,
+        client: &mut P,
+        message_type: MessageType,
+        device_id: Option<&str>,
+        metrics: Vec<Metric>,
+    ) -> Result<MessageId, P::Error>
+    where
+        P: Publish,
+    {
+        let payload = Payload {
+            timestamp: Some(now()),
+            metrics,
+            seq: Some(self.seq),
+            ..Default::default()
+        };
+
+        // Sequence numbers wrap around after 255
+        self.seq = (self.seq + 1) % 256;
+
+        client.publish(
+            &self.topic(message_type, device_id),
+            QoS::AtMostOnce,
+            false,
+            &payload.encode(),
+        )
+    }
+}
@@ -11,6 +11,8 @@
 use core::convert::TryInto;
 use core::{ffi, ptr};
 
+use ::log::*;
+
 use embedded_svc::ipv4;
 
 use esp_idf_sys::*;
@@ -101,6 +103,7 @@ pub struct NetifConfiguration {
     pub ip_configuration: ipv4::Configuration,
     pub stack: NetifStack,
     pub custom_mac: Option<[u8; 6]>,
+    pub dhcp_client_options: Option<DhcpClientOptions>,
 }
 
 impl NetifConfiguration {
@@ -112,6 +115,7 @@ impl NetifConfiguration {
             ip_configuration: ipv4::Configuration::Client(Default::default()),
             stack: NetifStack::Eth,
             custom_mac: None,
+            dhcp_client_options: None,
         }
     }
 
@@ -123,6 +127,7 @@ impl NetifConfiguration {
             ip_configuration: ipv4::Configuration::Router(Default::default()),
             stack: NetifStack::Eth,
             custom_mac: None,
+            dhcp_client_options: None,
         }
     }
 
@@ -134,6 +139,7 @@ impl NetifConfiguration {
             ip_configuration: ipv4::Configuration::Client(Default::default()),
             stack: NetifStack::Sta,
             custom_mac: None,
+            dhcp_client_options: None,
         }
     }
 
@@ -145,6 +151,7 @@ impl NetifConfiguration {
             ip_configuration: ipv4::Configuration::Router(Default::default()),
             stack: NetifStack::Ap,
             custom_mac: None,
+            dhcp_client_options: None,
         }
     }
 
@@ -157,6 +164,7 @@ impl NetifConfiguration {
             ip_configuration: ipv4::Configuration::Client(Default::default()),
             stack: NetifStack::Ppp,
             custom_mac: None,
+            dhcp_client_options: None,
         }
     }
 
@@ -169,6 +177,7 @@ impl NetifConfiguration {
             ip_configuration: ipv4::Configuration::Router(Default::default()),
             stack: NetifStack::Ppp,
             custom_mac: None,
+            dhcp_client_options: None,
         }
     }
 
@@ -181,6 +190,7 @@ impl NetifConfiguration {
             ip_configuration: ipv4::Configuration::Client(Default::default()),
             stack: NetifStack::Slip,
             custom_mac: None,
+            dhcp_client_options: None,
         }
     }
 
@@ -193,10 +203,90 @@ impl NetifConfiguration {
             ip_configuration: ipv4::Configuration::Router(Default::default()),
             stack: NetifStack::Slip,
             custom_mac: None,
+            dhcp_client_options: None,
         }
     }
 }
 
+/// Selects the network interface the sockets of a client service (HTTP, MQTT, WebSocket)
+/// should be bound to, so that its traffic goes over that interface only,
+/// regardless of the default route
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Interface {
+    /// The default WiFi STA interface
+    Sta,
+    /// The default WiFi AP interface
+    Ap,
+    /// The default Ethernet client interface
+    Eth,
+    #[cfg(esp_idf_ppp_support)]
+    /// The default PPP client interface
+    Ppp,
+    /// The interface registered with the given key (see `NetifConfiguration::key`)
+    Key(&'static str),
+}
+
+impl Interface {
+    pub fn key(&self) -> &'static str {
+        match self {
+            Self::Sta => "WIFI_STA_DEF",
+            Self::Ap => "WIFI_AP_DEF",
+            Self::Eth => "ETH_CL_DEF",
+            #[cfg(esp_idf_ppp_support)]
+            Self::Ppp => "PPP_CL_DEF",
+            Self::Key(key) => key,
+        }
+    }
+
+    /// Returns the name of the underlying lwIP interface (e.g. `st1`);
+    /// fails with `ESP_ERR_NOT_FOUND` if the interface has not been created yet
+    pub fn name(&self) -> Result<heapless::String<6>, EspError> {
+        let handle = self.handle()?;
+
+        let mut netif_name = [0u8; 7];
+
+        esp!(unsafe { esp_netif_get_netif_impl_name(handle, netif_name.as_mut_ptr() as *mut _) })?;
+
+        Ok(from_cstr(&netif_name).into())
+    }
+
+    /// Returns the `esp_netif` handle of the interface;
+    /// fails with `ESP_ERR_NOT_FOUND` if the interface has not been created yet
+    pub(crate) fn handle(&self) -> Result<*mut esp_netif_t, EspError> {
+        let mut key = heapless::String::<33>::new();
+        key.push_str(self.key())
+            .and_then(|_| key.push('\0'))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+        let handle = unsafe { esp_netif_get_handle_from_ifkey(key.as_ptr() as *const _) };
+        if handle.is_null() {
+            Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+        } else {
+            Ok(handle)
+        }
+    }
+
+    /// Returns the `ifreq` the ESP-IDF clients expect in their `if_name` configuration field
+    pub(crate) fn ifreq(&self) -> Result<ifreq, EspError> {
+        let name = self.name()?;
+
+        let mut ifreq = ifreq { ifr_name: [0; 6] };
+
+        for (index, b) in name
+            .as_bytes()
+            .iter()
+            .take(ifreq.ifr_name.len() - 1)
+            .enumerate()
+        {
+            ifreq.ifr_name[index] = *b as _;
+        }
+
+        Ok(ifreq)
+    }
+}
+
 static INITALIZED: mutex::Mutex<bool> = mutex::Mutex::wrap(mutex::RawMutex::new(), false);
 
 fn initialize_netif_stack() -> Result<(), EspError> {
@@ -211,6 +301,22 @@ fn initialize_netif_stack() -> Result<(), EspError> {
     Ok(())
 }
 
+/// The options sent by the DHCP client of an interface, so that the device shows up with a
+/// meaningful name in router UIs, and can be classified by enterprise DHCP servers
+///
+/// Note that the parameter request list (option 55) of lwIP is fixed at build time; the NTP
+/// servers (option 42) are requested only with `CONFIG_LWIP_DHCP_GET_NTP_SRV` enabled.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
+pub struct DhcpClientOptions {
+    /// The host name (option 12); takes precedence over the host name of the DHCP
+    /// client configuration
+    pub hostname: Option<heapless::String<30>>,
+    /// The vendor class identifier (option 60), e.g. `acme-sensor/1.2`
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub vendor_class: Option<heapless::String<64>>,
+}
+
 #[derive(Debug)]
 pub struct EspNetif(*mut esp_netif_t);
 
@@ -368,6 +474,10 @@ impl EspNetif {
             handle.set_hostname(hostname)?;
         }
 
+        if let Some(options) = conf.dhcp_client_options.as_ref() {
+            handle.set_dhcp_client_options(options)?;
+        }
+
         Ok(handle)
     }
 
@@ -476,14 +586,73 @@ impl EspNetif {
         }
     }
 
+    /// The host name in effect, i.e. the one the DHCP client sends; empty if none is set
     pub fn get_hostname(&self) -> Result<heapless::String<30>, EspError> {
         let mut ptr: *const ffi::c_char = ptr::null();
         esp!(unsafe { esp_netif_get_hostname(self.0, &mut ptr) })?;
 
+        if ptr.is_null() {
+            return Ok(heapless::String::new());
+        }
+
         Ok(unsafe { from_cstr_ptr(ptr).into() })
     }
 
-    fn set_hostname(&mut self, hostname: &str) -> Result<(), EspError> {
+    /// Sets the host name
+    ///
+    /// When the DHCP client is running, i.e. the interface is already up, it is restarted so
+    /// that the new name reaches the server (and, through it, the router UIs and the local DNS)
+    /// right away rather than on the next lease renewal. The lease is then acquired anew, which
+    /// usually keeps the same IP address, but briefly takes the interface down.
+    pub fn set_hostname(&mut self, hostname: &str) -> Result<(), EspError> {
+        // Not all interfaces have a DHCP client, e.g. the AP ones
+        let started = self.is_dhcp_client_started().unwrap_or(false);
+
+        self.with_dhcp_client_stopped(started, |netif| netif.apply_hostname(hostname))
+    }
+
+    /// Sets the options of the DHCP client; when it is running, it is restarted so that they
+    /// are sent to the server right away
+    pub fn set_dhcp_client_options(&mut self, options: &DhcpClientOptions) -> Result<(), EspError> {
+        let started = self.is_dhcp_client_started().unwrap_or(false);
+
+        self.with_dhcp_client_stopped(started, |netif| netif.apply_dhcp_client_options(options))
+    }
+
+    fn is_dhcp_client_started(&self) -> Result<bool, EspError> {
+        let mut status: esp_netif_dhcp_status_t = Default::default();
+        esp!(unsafe { esp_netif_dhcpc_get_status(self.0, &mut status) })?;
+
+        Ok(status == esp_netif_dhcp_status_t_ESP_NETIF_DHCP_STARTED)
+    }
+
+    // The options can only be set while the DHCP client is stopped
+    fn with_dhcp_client_stopped(
+        &mut self,
+        started: bool,
+        f: impl FnOnce(&mut Self) -> Result<(), EspError>,
+    ) -> Result<(), EspError> {
+        if started {
+            esp!(unsafe { esp_netif_dhcpc_stop(self.0) })?;
+        }
+
+        let result = f(self);
+
+        if started {
+            esp!(unsafe { esp_netif_dhcpc_start(self.0) })?;
+
+            info!("Restarted the DHCP client");
+        }
+
+        result
+    }
+
+    fn apply_hostname(&mut self, hostname: &str) -> Result<(), EspError> {
+        // At most as long as what `get_hostname` returns
+        if hostname.len() > 30 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
         if let Ok(hostname) = CString::new(hostname) {
             esp!(unsafe { esp_netif_set_hostname(self.0, hostname.as_ptr() as *const _) })?;
         } else {
@@ -493,6 +662,27 @@ impl EspNetif {
         Ok(())
     }
 
+    fn apply_dhcp_client_options(&mut self, options: &DhcpClientOptions) -> Result<(), EspError> {
+        if let Some(hostname) = options.hostname.as_ref() {
+            self.apply_hostname(hostname)?;
+        }
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        if let Some(vendor_class) = options.vendor_class.as_ref() {
+            esp!(unsafe {
+                esp_netif_dhcpc_option(
+                    self.0,
+                    esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+                    esp_netif_dhcp_option_id_t_ESP_NETIF_VENDOR_CLASS_IDENTIFIER,
+                    vendor_class.as_ptr() as *mut _,
+                    vendor_class.len() as _,
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
     #[cfg(esp_idf_lwip_ipv4_napt)]
     pub fn enable_napt(&mut self, enable: bool) {
         unsafe {
@@ -521,13 +711,16 @@ impl RawHandle for EspNetif {
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ApStaIpAssignment {
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
     pub ip: ipv4::Ipv4Addr,
     #[cfg(not(esp_idf_version_major = "4"))]
     pub mac: [u8; 6],
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DhcpIpAssignment {
     pub netif_handle: *mut esp_netif_t,
     pub ip_settings: ipv4::IpInfo,
@@ -535,6 +728,7 @@ pub struct DhcpIpAssignment {
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DhcpIp6Assignment {
     pub netif_handle: *mut esp_netif_t,
     pub ip: [u32; 4],
@@ -543,6 +737,7 @@ pub struct DhcpIp6Assignment {
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum IpEvent {
     ApStaIpAssigned(ApStaIpAssignment),
     DhcpIpAssigned(DhcpIpAssignment),
@@ -0,0 +1,291 @@
+//! Socket table and lwIP diagnostics
+//!
+//! A `netstat`-like listing of the open sockets (protocol, state, local and remote addresses,
+//! pending received data), together with the figures of the memory lwIP allocates from,
+//! so that socket leaks in long-running firmware can be detected remotely:
+//!
+//! ```ignore
+//! for socket in netstat::sockets() {
+//!     info!("{:?}", socket);
+//! }
+//!
+//! server.fn_handler("/netstat", Method::Get, netstat::handler)?;
+//! ```
+//!
+//! Note that ESP-IDF configures lwIP to allocate its buffers (pbufs) and control blocks from
+//! the heap (`MEM_LIBC_MALLOC` and `MEMP_MEM_MALLOC`), so the heap figures of the internal RAM
+//! are the ones to watch, as lwIP does not maintain pool statistics in this configuration.
+use core::ffi;
+use core::mem;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::string::String;
+use std::vec::Vec;
+
+use esp_idf_sys::*;
+
+use crate::private::json::{self, Json};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Raw,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+            Self::Raw => "raw",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum SocketState {
+    Listening,
+    /// Has a remote address: an established TCP connection, or a connected UDP socket
+    Connected,
+    /// Neither listening nor connected, e.g. a UDP socket, or a TCP socket being closed
+    Unconnected,
+}
+
+impl SocketState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Listening => "listening",
+            Self::Connected => "connected",
+            Self::Unconnected => "unconnected",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SocketInfo {
+    pub fd: i32,
+    pub protocol: Protocol,
+    pub state: SocketState,
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub local: Option<SocketAddr>,
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub remote: Option<SocketAddr>,
+    /// The received data not read by the application yet, in bytes
+    pub rx_pending: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    /// `CONFIG_LWIP_MAX_SOCKETS`
+    pub max_sockets: usize,
+    pub sockets: usize,
+    pub tcp: usize,
+    pub udp: usize,
+    pub listening: usize,
+    /// The free internal RAM, where lwIP allocates its buffers
+    pub free_internal: usize,
+    pub min_free_internal: usize,
+    pub largest_free_internal_block: usize,
+}
+
+/// The open lwIP sockets
+pub fn sockets() -> Vec<SocketInfo> {
+    let offset = socket_offset();
+
+    (offset..offset + CONFIG_LWIP_MAX_SOCKETS as i32)
+        .filter_map(socket_info)
+        .collect()
+}
+
+/// The counts of the open sockets, and the figures of the internal RAM
+pub fn stats() -> Stats {
+    stats_of(&sockets())
+}
+
+/// The socket table and the statistics, as a JSON document
+pub fn to_json() -> String {
+    let sockets = sockets();
+    let stats = stats_of(&sockets);
+
+    let addr = |addr: Option<SocketAddr>| {
+        addr.map(|addr| Json::from(addr.to_string()))
+            .unwrap_or(Json::Null)
+    };
+
+    json::object([
+        (
+            "sockets",
+            Json::Array(
+                sockets
+                    .iter()
+                    .map(|socket| {
+                        json::object([
+                            ("fd", Json::from(socket.fd as i64)),
+                            ("protocol", socket.protocol.as_str().into()),
+                            ("state", socket.state.as_str().into()),
+                            ("local", addr(socket.local)),
+                            ("remote", addr(socket.remote)),
+                            ("rx_pending", Json::from(socket.rx_pending as u64)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+        (
+            "stats",
+            json::object([
+                ("max_sockets", Json::from(stats.max_sockets as u64)),
+                ("sockets", Json::from(stats.sockets as u64)),
+                ("tcp", Json::from(stats.tcp as u64)),
+                ("udp", Json::from(stats.udp as u64)),
+                ("listening", Json::from(stats.listening as u64)),
+                ("free_internal", Json::from(stats.free_internal as u64)),
+                (
+                    "min_free_internal",
+                    Json::from(stats.min_free_internal as u64),
+                ),
+                (
+                    "largest_free_internal_block",
+                    Json::from(stats.largest_free_internal_block as u64),
+                ),
+            ]),
+        ),
+    ])
+    .to_string()
+}
+
+/// An `EspHttpServer` handler responding with `to_json`
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+pub fn handler(
+    request: embedded_svc::http::server::Request<&mut crate::http::server::EspHttpConnection>,
+) -> embedded_svc::http::server::HandlerResult {
+    use embedded_svc::io::Write;
+
+    request
+        .into_response(
+            200,
+            None,
+            &[
+                ("Content-Type", "application/json"),
+                ("Cache-Control", "no-store"),
+            ],
+        )?
+        .write_all(to_json().as_bytes())?;
+
+    Ok(())
+}
+
+fn stats_of(sockets: &[SocketInfo]) -> Stats {
+    let count = |f: &dyn Fn(&SocketInfo) -> bool| sockets.iter().filter(|socket| f(socket)).count();
+
+    Stats {
+        max_sockets: CONFIG_LWIP_MAX_SOCKETS as _,
+        sockets: sockets.len(),
+        tcp: count(&|socket| socket.protocol == Protocol::Tcp),
+        udp: count(&|socket| socket.protocol == Protocol::Udp),
+        listening: count(&|socket| socket.state == SocketState::Listening),
+        free_internal: unsafe { heap_caps_get_free_size(MALLOC_CAP_INTERNAL) } as _,
+        min_free_internal: unsafe { heap_caps_get_minimum_free_size(MALLOC_CAP_INTERNAL) } as _,
+        largest_free_internal_block: unsafe {
+            heap_caps_get_largest_free_block(MALLOC_CAP_INTERNAL)
+        } as _,
+    }
+}
+
+/// The first lwIP socket descriptor: ESP-IDF numbers the lwIP sockets after the other
+/// VFS descriptors (`LWIP_SOCKET_OFFSET`)
+fn socket_offset() -> i32 {
+    (FD_SETSIZE - CONFIG_LWIP_MAX_SOCKETS) as i32
+}
+
+fn socket_info(fd: i32) -> Option<SocketInfo> {
+    // Fails on the descriptors not in use
+    let kind: i32 = get_sockopt(fd, SOL_SOCKET, SO_TYPE)?;
+
+    let protocol = match kind as u32 {
+        SOCK_STREAM => Protocol::Tcp,
+        SOCK_DGRAM => Protocol::Udp,
+        _ => Protocol::Raw,
+    };
+
+    let local = socket_addr(|addr, len| unsafe { lwip_getsockname(fd, addr, len) });
+    let remote = socket_addr(|addr, len| unsafe { lwip_getpeername(fd, addr, len) });
+
+    let listening = protocol == Protocol::Tcp
+        && get_sockopt::<i32>(fd, SOL_SOCKET, SO_ACCEPTCONN).unwrap_or(0) != 0;
+
+    let state = if listening {
+        SocketState::Listening
+    } else if remote.is_some() {
+        SocketState::Connected
+    } else {
+        SocketState::Unconnected
+    };
+
+    let mut rx_pending: ffi::c_int = 0;
+    if unsafe { lwip_ioctl(fd, FIONREAD as _, &mut rx_pending as *mut _ as *mut _) } != 0 {
+        rx_pending = 0;
+    }
+
+    Some(SocketInfo {
+        fd,
+        protocol,
+        state,
+        local,
+        remote,
+        rx_pending: rx_pending.max(0) as _,
+    })
+}
+
+fn get_sockopt<T: Default>(fd: i32, level: u32, option: u32) -> Option<T> {
+    let mut value = T::default();
+    let mut len = mem::size_of::<T>() as socklen_t;
+
+    let result = unsafe {
+        lwip_getsockopt(
+            fd,
+            level as _,
+            option as _,
+            &mut value as *mut T as *mut _,
+            &mut len,
+        )
+    };
+
+    if result == 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn socket_addr(f: impl FnOnce(*mut sockaddr, *mut socklen_t) -> i32) -> Option<SocketAddr> {
+    let mut addr: sockaddr_in6 = Default::default();
+    let mut len = mem::size_of::<sockaddr_in6>() as socklen_t;
+
+    if f(&mut addr as *mut _ as *mut _, &mut len) != 0 {
+        return None;
+    }
+
+    match addr.sin6_family as u32 {
+        AF_INET => {
+            let addr = unsafe { (&addr as *const _ as *const sockaddr_in).as_ref() }.unwrap();
+
+            Some(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))),
+                u16::from_be(addr.sin_port),
+            ))
+        }
+        AF_INET6 => Some(SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::from(unsafe { addr.sin6_addr.un.u8_addr })),
+            u16::from_be(addr.sin6_port),
+        )),
+        _ => None,
+    }
+}
@@ -2,7 +2,9 @@
 use core::ptr;
 
 extern crate alloc;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use ::log::*;
 
@@ -134,6 +136,121 @@ impl NvsPartitionId for NvsCustom {
 #[derive(Debug)]
 pub struct EspNvsPartition<T: NvsPartitionId>(Arc<T>);
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum NvsDataType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    Str,
+    Blob,
+}
+
+impl NvsDataType {
+    #[allow(non_upper_case_globals)]
+    fn from_raw(data_type: nvs_type_t) -> Option<Self> {
+        Some(match data_type {
+            nvs_type_t_NVS_TYPE_U8 => Self::U8,
+            nvs_type_t_NVS_TYPE_I8 => Self::I8,
+            nvs_type_t_NVS_TYPE_U16 => Self::U16,
+            nvs_type_t_NVS_TYPE_I16 => Self::I16,
+            nvs_type_t_NVS_TYPE_U32 => Self::U32,
+            nvs_type_t_NVS_TYPE_I32 => Self::I32,
+            nvs_type_t_NVS_TYPE_U64 => Self::U64,
+            nvs_type_t_NVS_TYPE_I64 => Self::I64,
+            nvs_type_t_NVS_TYPE_STR => Self::Str,
+            nvs_type_t_NVS_TYPE_BLOB => Self::Blob,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NvsEntry {
+    pub namespace: String,
+    pub key: String,
+    pub data_type: NvsDataType,
+}
+
+impl<T: NvsPartitionId> EspNvsPartition<T> {
+    /// Lists the entries stored in the partition, or only those of `namespace`
+    pub fn entries(&self, namespace: Option<&str>) -> Result<Vec<NvsEntry>, EspError> {
+        let partition = if self.0.is_default() {
+            CStr::from_bytes_with_nul(b"nvs\0").unwrap()
+        } else {
+            self.0.name()
+        };
+
+        let c_namespace = namespace.map(|namespace| CString::new(namespace).unwrap());
+        let c_namespace = c_namespace
+            .as_ref()
+            .map_or(ptr::null(), |namespace| namespace.as_ptr());
+
+        let mut entries = Vec::new();
+
+        #[cfg(esp_idf_version_major = "4")]
+        {
+            let mut iterator =
+                unsafe { nvs_entry_find(partition.as_ptr(), c_namespace, nvs_type_t_NVS_TYPE_ANY) };
+
+            while !iterator.is_null() {
+                let mut info: nvs_entry_info_t = Default::default();
+                unsafe { nvs_entry_info(iterator, &mut info) };
+
+                entries.extend(Self::entry(&info));
+
+                iterator = unsafe { nvs_entry_next(iterator) };
+            }
+        }
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        {
+            let mut iterator: nvs_iterator_t = ptr::null_mut();
+
+            let mut result = unsafe {
+                nvs_entry_find(
+                    partition.as_ptr(),
+                    c_namespace,
+                    nvs_type_t_NVS_TYPE_ANY,
+                    &mut iterator,
+                )
+            };
+
+            while result == ESP_OK {
+                let mut info: nvs_entry_info_t = Default::default();
+                esp!(unsafe { nvs_entry_info(iterator, &mut info) })?;
+
+                entries.extend(Self::entry(&info));
+
+                result = unsafe { nvs_entry_next(&mut iterator) };
+            }
+
+            unsafe { nvs_release_iterator(iterator) };
+
+            if result != ESP_ERR_NVS_NOT_FOUND {
+                esp!(result)?;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn entry(info: &nvs_entry_info_t) -> Option<NvsEntry> {
+        Some(NvsEntry {
+            namespace: unsafe { from_cstr_ptr(info.namespace_name.as_ptr()) }.into(),
+            key: unsafe { from_cstr_ptr(info.key.as_ptr()) }.into(),
+            data_type: NvsDataType::from_raw(info.type_)?,
+        })
+    }
+}
+
 impl EspNvsPartition<NvsDefault> {
     pub fn take() -> Result<Self, EspError> {
         Ok(Self(Arc::new(NvsDefault::new()?)))
@@ -228,6 +345,14 @@ impl<T: NvsPartitionId> EspNvs<T> {
         }
     }
 
+    /// Removes all the entries of the namespace
+    pub fn remove_all(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { nvs_erase_all(self.1) })?;
+        esp!(unsafe { nvs_commit(self.1) })?;
+
+        Ok(())
+    }
+
     fn len(&self, name: &str) -> Result<Option<usize>, EspError> {
         let c_key = CString::new(name).unwrap();
 
@@ -0,0 +1,650 @@
+//! Signed multi-artifact OTA updates
+//!
+//! `EspOtaBundle` updates the firmware together with the data partitions it depends on
+//! (web assets in a SPIFFS or FAT partition, ML models...), as listed by a manifest:
+//!
+//! ```json
+//! {
+//!     "version": "1.3.0",
+//!     "artifacts": [
+//!         { "type": "app", "url": "firmware.bin", "sha256": "..." },
+//!         { "type": "data", "name": "www", "url": "www.bin", "size": 262144, "sha256": "..." }
+//!     ]
+//! }
+//! ```
+//!
+//! The manifest is signed with a detached signature (DER encoded ECDSA or RSA, over SHA-256),
+//! served by default next to the manifest with the `.sig` suffix:
+//!
+//! ```text
+//! openssl dgst -sha256 -sign key.pem -out manifest.json.sig manifest.json
+//! ```
+//!
+//! All artifacts are downloaded and verified against their hashes before anything is switched,
+//! and the switch itself is atomic, so that a power loss or a failed download at any stage
+//! leaves the device running its previous firmware with its previous data.
+//!
+//! To that end, every data artifact is written to the inactive partition of an A/B pair (e.g.
+//! `www_a` and `www_b`), and the selected partitions are recorded in NVS per app slot: the
+//! selection of the new app slot is written before the boot slot is switched, and the one of
+//! the running slot is left untouched. The application should therefore always look up the
+//! partition to mount with `EspOtaBundle::data_partition`, which also keeps working after a
+//! rollback of the firmware by the bootloader.
+//!
+//! Data slots with a single partition are updated in place, which is not atomic.
+use core::cmp::min;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+
+use esp_idf_sys::*;
+
+use crate::http::client::{self, EspHttpConnection};
+use crate::http::uri;
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+use crate::ota::EspOta;
+use crate::private::cstr::*;
+use crate::private::hex;
+use crate::private::json::Json;
+use crate::private::sha256::{self, Sha256};
+#[cfg(feature = "traffic")]
+use crate::traffic::{Service, Throttle};
+
+const NAMESPACE: &str = "ota_bundle";
+
+/// A data partition updated by the bundle, with its (at most two) partition labels
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DataSlot {
+    /// The name of the artifact in the manifest
+    pub name: String,
+    pub partitions: Vec<String>,
+}
+
+impl DataSlot {
+    pub fn new(name: &str, partitions: &[&str]) -> Self {
+        Self {
+            name: name.into(),
+            partitions: partitions
+                .iter()
+                .map(|label| String::from(*label))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub manifest_url: String,
+    /// Defaults to the manifest URL with the `.sig` suffix
+    pub signature_url: Option<String>,
+    /// The PEM encoded public key the manifest signature is verified with
+    pub public_key: &'static str,
+    pub data_slots: Vec<DataSlot>,
+    pub timeout: Duration,
+    pub buffer_size: usize,
+    /// Limits the bandwidth of the downloads
+    #[cfg(feature = "traffic")]
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub throttle: Option<&'static Throttle>,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            manifest_url: String::new(),
+            signature_url: None,
+            public_key: "",
+            data_slots: Vec::new(),
+            timeout: Duration::from_secs(30),
+            buffer_size: 4096,
+            #[cfg(feature = "traffic")]
+            throttle: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ArtifactKind {
+    App,
+    /// The name of a `DataSlot`
+    Data(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    /// The absolute URL of the artifact
+    pub url: String,
+    pub size: Option<u32>,
+    /// Lowercase hex
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BundleManifest {
+    pub version: String,
+    pub artifacts: Vec<Artifact>,
+}
+
+impl BundleManifest {
+    /// Parses a manifest fetched from `base`, against which relative artifact URLs are resolved
+    pub fn parse(base: &str, json: &str) -> Option<Self> {
+        let json = Json::parse(json)?;
+
+        let artifacts = json
+            .get("artifacts")?
+            .as_array()?
+            .iter()
+            .map(|artifact| {
+                let kind = match artifact.get("type")?.as_str()? {
+                    "app" => ArtifactKind::App,
+                    "data" => ArtifactKind::Data(artifact.get("name")?.as_str()?.into()),
+                    _ => return None,
+                };
+
+                Some(Artifact {
+                    kind,
+                    url: uri::join(base, artifact.get("url")?.as_str()?),
+                    size: artifact
+                        .get("size")
+                        .and_then(Json::as_u64)
+                        .map(|size| size as u32),
+                    sha256: artifact.get("sha256")?.as_str()?.to_ascii_lowercase(),
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let apps = artifacts
+            .iter()
+            .filter(|artifact| artifact.kind == ArtifactKind::App)
+            .count();
+
+        if artifacts.is_empty() || apps > 1 {
+            return None;
+        }
+
+        Some(Self {
+            version: json.get("version")?.as_str()?.into(),
+            artifacts,
+        })
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BundleError {
+    /// The manifest signature is missing or invalid
+    Signature,
+    Manifest,
+    /// The manifest lists a data artifact without a matching `DataSlot`
+    UnknownArtifact,
+    Download,
+    /// The size or the hash of an artifact do not match the manifest
+    Verification,
+    Other(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] EspError),
+}
+
+impl core::fmt::Display for BundleError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Signature => write!(f, "Invalid manifest signature"),
+            Self::Manifest => write!(f, "Invalid manifest"),
+            Self::UnknownArtifact => write!(f, "Unknown artifact"),
+            Self::Download => write!(f, "Download failed"),
+            Self::Verification => write!(f, "Artifact verification failed"),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BundleError {}
+
+impl From<EspError> for BundleError {
+    fn from(e: EspError) -> Self {
+        Self::Other(e)
+    }
+}
+
+pub struct EspOtaBundle<T: NvsPartitionId> {
+    conf: Configuration,
+    nvs: EspNvs<T>,
+}
+
+impl<T: NvsPartitionId> EspOtaBundle<T> {
+    pub fn new(conf: &Configuration, partition: EspNvsPartition<T>) -> Result<Self, EspError> {
+        if conf.manifest_url.is_empty() || conf.public_key.is_empty() || conf.buffer_size == 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        if conf
+            .data_slots
+            .iter()
+            .any(|slot| slot.partitions.is_empty() || slot.partitions.len() > 2)
+        {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        Ok(Self {
+            conf: conf.clone(),
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    /// The label of the partition holding the data of `name` for the running firmware
+    pub fn data_partition(&self, name: &str) -> Result<String, EspError> {
+        let slot = self
+            .conf
+            .data_slots
+            .iter()
+            .find(|slot| slot.name == name)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_NOT_FOUND>)?;
+
+        let selection = self.selection(&running_app_slot()?)?;
+
+        Ok(Self::selected(&selection, slot).into())
+    }
+
+    /// Fetches the manifest and verifies its signature
+    pub fn fetch_manifest(&self) -> Result<BundleManifest, BundleError> {
+        let manifest = self.fetch(&self.conf.manifest_url, 8192)?;
+
+        let signature_url = self
+            .conf
+            .signature_url
+            .clone()
+            .unwrap_or_else(|| format!("{}.sig", self.conf.manifest_url));
+
+        let signature = self.fetch(&signature_url, 1024)?;
+
+        verify_signature(self.conf.public_key, &manifest, &signature)?;
+
+        core::str::from_utf8(&manifest)
+            .ok()
+            .and_then(|manifest| BundleManifest::parse(&self.conf.manifest_url, manifest))
+            .ok_or(BundleError::Manifest)
+    }
+
+    /// Downloads, verifies and then atomically switches to all artifacts of the manifest
+    ///
+    /// `progress` is called with the index of the artifact in the manifest, and with the
+    /// downloaded and total bytes of that artifact.
+    pub fn apply(
+        &mut self,
+        manifest: &BundleManifest,
+        mut progress: impl FnMut(usize, u32, Option<u32>),
+    ) -> Result<(), BundleError> {
+        // Resolve everything upfront, so that nothing is written for an unusable manifest
+        for artifact in &manifest.artifacts {
+            if let ArtifactKind::Data(name) = &artifact.kind {
+                self.slot(name)?;
+            }
+        }
+
+        let running = running_app_slot()?;
+        let running_selection = self.selection(&running)?;
+
+        let mut ota = EspOta::new()?;
+
+        let staged = self.stage(manifest, &mut ota, &running_selection, &mut progress);
+
+        let (selection, app_slot) = match staged {
+            Ok(staged) => staged,
+            Err(e) => {
+                if let Some(update) = ota.get_update() {
+                    let _ = update.abort();
+                }
+
+                return Err(e);
+            }
+        };
+
+        // The commit point: everything is downloaded and verified
+        if let Some(app_slot) = app_slot {
+            self.nvs.set_str(&app_slot, &Self::render(&selection))?;
+
+            ota.get_update()
+                .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_STATE>)?
+                .complete()?;
+
+            info!(
+                "Installed bundle {} into slot {}, pending a restart",
+                manifest.version, app_slot
+            );
+        } else {
+            self.nvs.set_str(&running, &Self::render(&selection))?;
+
+            info!("Installed bundle {} data", manifest.version);
+        }
+
+        Ok(())
+    }
+
+    /// Downloads all artifacts into their inactive partitions, returning the data partition
+    /// selection to switch to, and the app slot to boot if the manifest contains an app
+    fn stage(
+        &self,
+        manifest: &BundleManifest,
+        ota: &mut EspOta,
+        running_selection: &[(String, String)],
+        progress: &mut impl FnMut(usize, u32, Option<u32>),
+    ) -> Result<(Vec<(String, String)>, Option<String>), BundleError> {
+        let mut selection = running_selection.to_vec();
+        let mut app_slot = None;
+
+        for (index, artifact) in manifest.artifacts.iter().enumerate() {
+            info!(
+                "Downloading artifact {:?} from {}",
+                artifact.kind, artifact.url
+            );
+
+            let mut on_progress = |downloaded, total| progress(index, downloaded, total);
+
+            match &artifact.kind {
+                ArtifactKind::App => {
+                    let update_slot = ota.get_update_slot()?.label.as_str().into();
+                    let update = ota.initiate_update()?;
+
+                    self.download(artifact, None, &mut on_progress, |_, data| {
+                        update.write(data).map(|_| ())
+                    })?;
+
+                    app_slot = Some(update_slot);
+                }
+                ArtifactKind::Data(name) => {
+                    let slot = self.slot(name)?;
+
+                    let current = Self::selected(running_selection, slot);
+
+                    let target = slot
+                        .partitions
+                        .iter()
+                        .find(|label| label.as_str() != current)
+                        .unwrap_or(&slot.partitions[0]);
+
+                    let partition = find_data_partition(target)?;
+
+                    self.download(
+                        artifact,
+                        Some(partition),
+                        &mut on_progress,
+                        |offset, data| {
+                            esp!(unsafe {
+                                esp_partition_write(
+                                    partition,
+                                    offset as _,
+                                    data.as_ptr() as *const _,
+                                    data.len() as _,
+                                )
+                            })
+                        },
+                    )
+                    .map_err(|e| {
+                        if slot.partitions.len() == 1 {
+                            error!(
+                                "Data partition {} updated in place is corrupted: {}",
+                                target, e
+                            );
+                        }
+
+                        e
+                    })?;
+
+                    Self::select(&mut selection, &slot.name, target);
+                }
+            }
+        }
+
+        Ok((selection, app_slot))
+    }
+
+    fn slot(&self, name: &str) -> Result<&DataSlot, BundleError> {
+        self.conf
+            .data_slots
+            .iter()
+            .find(|slot| slot.name == name)
+            .ok_or(BundleError::UnknownArtifact)
+    }
+
+    /// Streams `artifact` into `write`, verifying it on the fly; a data `partition`
+    /// is erased on the way, as far as it is written
+    fn download(
+        &self,
+        artifact: &Artifact,
+        partition: Option<&esp_partition_t>,
+        progress: &mut impl FnMut(u32, Option<u32>),
+        mut write: impl FnMut(u32, &[u8]) -> Result<(), EspError>,
+    ) -> Result<u32, BundleError> {
+        let mut connection = self.connect()?;
+
+        connection
+            .initiate_request(Method::Get, &artifact.url, &[])
+            .map_err(|_| BundleError::Download)?;
+        connection
+            .initiate_response()
+            .map_err(|_| BundleError::Download)?;
+
+        if connection.status() != 200 {
+            warn!(
+                "Artifact request failed with status {}",
+                connection.status()
+            );
+
+            return Err(BundleError::Download);
+        }
+
+        let total = artifact.size.or_else(|| {
+            connection
+                .header("Content-Length")
+                .and_then(|len| len.parse().ok())
+        });
+
+        if let Some(partition) = partition {
+            if total.map(|total| total > partition.size).unwrap_or(false) {
+                return Err(BundleError::Verification);
+            }
+        }
+
+        let mut sha256 = Sha256::new();
+        let mut buf = vec![0; self.conf.buffer_size];
+        let mut downloaded = 0_u32;
+        let mut erased = 0_u32;
+
+        loop {
+            let len = connection
+                .read(&mut buf)
+                .map_err(|_| BundleError::Download)?;
+            if len == 0 {
+                break;
+            }
+
+            let end = downloaded + len as u32;
+
+            if let Some(partition) = partition {
+                if end > partition.size {
+                    return Err(BundleError::Verification);
+                }
+
+                if end > erased {
+                    erased = Self::erase(partition, erased, end)?;
+                }
+            }
+
+            write(downloaded, &buf[..len])?;
+            sha256.update(&buf[..len]);
+
+            downloaded = end;
+
+            progress(downloaded, total);
+        }
+
+        if total.map(|total| total != downloaded).unwrap_or(false) {
+            return Err(BundleError::Download);
+        }
+
+        let actual = hex::encode(&sha256.finish());
+
+        if actual != artifact.sha256 {
+            warn!(
+                "Artifact {:?} SHA-256 mismatch: {} != {}",
+                artifact.kind, actual, artifact.sha256
+            );
+
+            return Err(BundleError::Verification);
+        }
+
+        Ok(downloaded)
+    }
+
+    /// Erases whole sectors from `from` on, so as to cover `to`, returning the new erased boundary
+    fn erase(partition: &esp_partition_t, from: u32, to: u32) -> Result<u32, EspError> {
+        const SECTOR: u32 = 4096;
+
+        let end = min(((to + SECTOR - 1) / SECTOR) * SECTOR, partition.size);
+
+        esp!(unsafe { esp_partition_erase_range(partition, from as _, (end - from) as _) })?;
+
+        Ok(end)
+    }
+
+    fn connect(&self) -> Result<EspHttpConnection, EspError> {
+        EspHttpConnection::new(&client::Configuration {
+            timeout: Some(self.conf.timeout),
+            #[cfg(all(not(esp_idf_version = "4.3"), esp_idf_mbedtls_certificate_bundle))]
+            crt_bundle_attach: Some(esp_crt_bundle_attach),
+            #[cfg(feature = "traffic")]
+            service: Service::Ota,
+            #[cfg(feature = "traffic")]
+            throttle: self.conf.throttle,
+            ..Default::default()
+        })
+    }
+
+    fn fetch(&self, url: &str, max_len: usize) -> Result<Vec<u8>, BundleError> {
+        let mut connection = self.connect()?;
+
+        connection
+            .initiate_request(Method::Get, url, &[])
+            .map_err(|_| BundleError::Download)?;
+        connection
+            .initiate_response()
+            .map_err(|_| BundleError::Download)?;
+
+        if connection.status() != 200 {
+            warn!(
+                "Request to {} failed with status {}",
+                url,
+                connection.status()
+            );
+
+            return Err(BundleError::Download);
+        }
+
+        let mut body = vec![];
+        let mut buf = [0; 256];
+
+        loop {
+            let len = connection
+                .read(&mut buf)
+                .map_err(|_| BundleError::Download)?;
+            if len == 0 {
+                break;
+            }
+
+            if body.len() + len > max_len {
+                return Err(BundleError::Manifest);
+            }
+
+            body.extend_from_slice(&buf[..len]);
+        }
+
+        Ok(body)
+    }
+
+    /// The data partition selection of an app slot, as `name=label` pairs
+    fn selection(&self, app_slot: &str) -> Result<Vec<(String, String)>, EspError> {
+        let len = if let Some(len) = self.nvs.str_len(app_slot)? {
+            len
+        } else {
+            return Ok(Vec::new());
+        };
+
+        let mut buf = vec![0; len];
+
+        Ok(self
+            .nvs
+            .get_str(app_slot, &mut buf)?
+            .unwrap_or_default()
+            .trim_end_matches('\0')
+            .split(';')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, label)| (name.into(), label.into()))
+            .collect())
+    }
+
+    fn selected<'a>(selection: &'a [(String, String)], slot: &'a DataSlot) -> &'a str {
+        selection
+            .iter()
+            .find(|(name, label)| *name == slot.name && slot.partitions.contains(label))
+            .map(|(_, label)| label.as_str())
+            .unwrap_or(&slot.partitions[0])
+    }
+
+    fn select(selection: &mut Vec<(String, String)>, name: &str, label: &str) {
+        selection.retain(|(selected, _)| selected != name);
+        selection.push((name.into(), label.into()));
+    }
+
+    fn render(selection: &[(String, String)]) -> String {
+        selection
+            .iter()
+            .map(|(name, label)| format!("{}={}", name, label))
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+}
+
+fn running_app_slot() -> Result<String, EspError> {
+    let partition = unsafe { esp_ota_get_running_partition().as_ref() }
+        .ok_or_else(EspError::from_infallible::<ESP_ERR_NOT_FOUND>)?;
+
+    Ok(unsafe { from_cstr_ptr(&partition.label as *const _ as *const _) }.into())
+}
+
+fn find_data_partition(label: &str) -> Result<&'static esp_partition_t, EspError> {
+    let c_label = CString::new(label).unwrap();
+
+    unsafe {
+        esp_partition_find_first(
+            esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+            c_label.as_ptr(),
+        )
+        .as_ref()
+    }
+    .ok_or_else(EspError::from_infallible::<ESP_ERR_NOT_FOUND>)
+}
+
+/// Verifies a DER encoded signature of the SHA-256 of `data`
+fn verify_signature(public_key: &str, data: &[u8], signature: &[u8]) -> Result<(), BundleError> {
+    sha256::verify_signature(public_key, data, signature).map_err(|e| {
+        if e.code() == ESP_ERR_INVALID_ARG {
+            error!("Invalid OTA bundle public key");
+        } else {
+            warn!("OTA bundle manifest signature verification failed");
+        }
+
+        BundleError::Signature
+    })
+}
@@ -0,0 +1,637 @@
+//! Scheduled OTA updates
+//!
+//! `EspOtaScheduler` is the orchestration layer around `EspOta`: it periodically polls a
+//! manifest URL, and once a new firmware is announced, installs it during the configured
+//! install window and only when the application preconditions (battery level, connectivity,
+//! no ongoing user activity...) are met.
+//!
+//! The manifest is a JSON document of the form:
+//!
+//! ```json
+//! {
+//!     "version": "1.2.0",
+//!     "url": "firmware-1.2.0.bin",
+//!     "size": 1048576,
+//!     "sha256": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+//! }
+//! ```
+//!
+//! where `url` may be relative to the manifest URL, and `size` and `sha256` are optional.
+//! The firmware is installed if its version differs from the one of the running firmware.
+//! The manifest is fetched with `If-None-Match`, so that unchanged manifests cost a
+//! `304 Not Modified` response only.
+//!
+//! The progress is published as `OtaSchedulerEvent`s on the system event loop:
+//!
+//! ```ignore
+//! let scheduler = EspOtaScheduler::new(
+//!     &Configuration {
+//!         manifest_url: "https://updates.example.com/sensor/manifest.json".into(),
+//!         window: Some(InstallWindow::new((2, 0), (4, 0))),
+//!         ..Default::default()
+//!     },
+//!     sysloop.clone(),
+//!     move || battery_percent() > 30,
+//! )?;
+//! ```
+//!
+//! The install window is evaluated in local time, hence the `TZ` environment variable should be
+//! set, and the system time synchronized (e.g. with `EspSntp`). Until then, the time is considered
+//! to be outside of any window.
+use core::ffi;
+use core::time::Duration;
+
+use std::string::String;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSystemEventLoop, EspTypedEventDeserializer,
+    EspTypedEventSerializer, EspTypedEventSource,
+};
+use crate::http::client::{self, EspHttpConnection};
+use crate::http::uri;
+use crate::ota::EspOta;
+use crate::private::cstr::*;
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+use crate::private::hex;
+use crate::private::json::Json;
+use crate::private::mutex::Mutex;
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+use crate::private::sha256::Sha256;
+#[cfg(feature = "traffic")]
+use crate::traffic::{Service, Throttle};
+
+/// A daily time range, in local time, during which updates may be installed
+///
+/// The range may span midnight, e.g. from 23:00 to 01:00.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct InstallWindow {
+    /// Minutes since midnight, inclusive
+    pub start: u16,
+    /// Minutes since midnight, exclusive
+    pub end: u16,
+}
+
+impl InstallWindow {
+    /// A window from and to the given `(hour, minute)`
+    pub const fn new(start: (u8, u8), end: (u8, u8)) -> Self {
+        Self {
+            start: start.0 as u16 * 60 + start.1 as u16,
+            end: end.0 as u16 * 60 + end.1 as u16,
+        }
+    }
+
+    pub fn contains(&self, minutes: u16) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minutes)
+        } else {
+            minutes >= self.start || minutes < self.end
+        }
+    }
+
+    /// Whether the current local time is within the window
+    pub fn is_open(&self) -> bool {
+        local_minutes()
+            .map(|minutes| self.contains(minutes))
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub manifest_url: String,
+    pub poll_interval: Duration,
+    /// The first poll happens this long after the start, so as not to compete with the
+    /// application initialization
+    pub initial_delay: Duration,
+    /// Install at any time if `None`
+    pub window: Option<InstallWindow>,
+    pub timeout: Duration,
+    /// The size of the HTTP read buffer, i.e. of the OTA writes
+    pub buffer_size: usize,
+    /// Restart into the new firmware once installed
+    pub restart: bool,
+    pub stack_size: usize,
+    /// Limits the bandwidth of the downloads
+    #[cfg(feature = "traffic")]
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub throttle: Option<&'static Throttle>,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            manifest_url: String::new(),
+            poll_interval: Duration::from_secs(6 * 60 * 60),
+            initial_delay: Duration::from_secs(60),
+            window: None,
+            timeout: Duration::from_secs(30),
+            buffer_size: 4096,
+            restart: true,
+            stack_size: 8192,
+            #[cfg(feature = "traffic")]
+            throttle: None,
+        }
+    }
+}
+
+/// A firmware announced by the manifest
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Manifest {
+    pub version: String,
+    /// The absolute URL of the firmware image
+    pub url: String,
+    pub size: Option<u32>,
+    /// Lowercase hex
+    pub sha256: Option<String>,
+}
+
+impl Manifest {
+    /// Parses a manifest fetched from `base`, against which a relative `url` is resolved
+    pub fn parse(base: &str, json: &str) -> Option<Self> {
+        let json = Json::parse(json)?;
+
+        Some(Self {
+            version: json.get("version")?.as_str()?.into(),
+            url: uri::join(base, json.get("url")?.as_str()?),
+            size: json
+                .get("size")
+                .and_then(Json::as_u64)
+                .map(|size| size as u32),
+            sha256: json
+                .get("sha256")
+                .and_then(Json::as_str)
+                .map(|sha256| sha256.to_ascii_lowercase()),
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum DeferReason {
+    OutsideWindow,
+    Preconditions,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum OtaFailure {
+    Manifest,
+    Download,
+    /// The size or the SHA-256 of the image do not match the manifest
+    Verification,
+    /// The OTA API rejected the image, or the flash could not be written
+    Install,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum OtaSchedulerEvent {
+    /// The manifest was polled
+    Checked {
+        update_available: bool,
+    },
+    /// An update is available, but cannot be installed now
+    Deferred(DeferReason),
+    Downloading {
+        downloaded: u32,
+        total: Option<u32>,
+    },
+    /// The update is installed, and will be booted on the next restart
+    Installed,
+    Failed(OtaFailure),
+}
+
+impl EspTypedEventSource for OtaSchedulerEvent {
+    fn source() -> *const ffi::c_char {
+        b"ESP-OTA-SCHEDULER\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<OtaSchedulerEvent> for OtaSchedulerEvent {
+    fn serialize<R>(
+        event: &OtaSchedulerEvent,
+        f: impl for<'a> FnOnce(&'a EspEventPostData) -> R,
+    ) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<OtaSchedulerEvent> for OtaSchedulerEvent {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a OtaSchedulerEvent) -> R,
+    ) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum OtaSchedulerState {
+    Idle,
+    Checking,
+    /// An update is pending, for the given reason
+    Deferred(DeferReason),
+    Installing,
+    Installed,
+}
+
+enum Command {
+    Check,
+    Stop,
+}
+
+pub struct EspOtaScheduler {
+    state: Arc<Mutex<OtaSchedulerState>>,
+    commands: mpsc::Sender<Command>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspOtaScheduler {
+    /// Starts the scheduler; `preconditions` is called right before installing an update,
+    /// which is deferred until the next poll if it returns `false`
+    pub fn new(
+        conf: &Configuration,
+        sysloop: EspSystemEventLoop,
+        preconditions: impl Fn() -> bool + Send + 'static,
+    ) -> Result<Self, EspError> {
+        if conf.manifest_url.is_empty() || conf.buffer_size == 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let state = Arc::new(Mutex::new(OtaSchedulerState::Idle));
+        let (commands, receiver) = mpsc::channel();
+
+        let join_handle = {
+            let conf = conf.clone();
+            let state = state.clone();
+
+            thread::Builder::new()
+                .name("ota-scheduler".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || {
+                    Scheduler {
+                        conf,
+                        sysloop,
+                        state,
+                        etag: None,
+                        pending: None,
+                    }
+                    .run(receiver, preconditions)
+                })
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        info!("Started OTA scheduler for {}", conf.manifest_url);
+
+        Ok(Self {
+            state,
+            commands,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    pub fn state(&self) -> OtaSchedulerState {
+        *self.state.lock()
+    }
+
+    /// Polls the manifest now, rather than at the end of the current interval
+    pub fn check_now(&self) {
+        let _ = self.commands.send(Command::Check);
+    }
+}
+
+impl Drop for EspOtaScheduler {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Stop);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+struct Scheduler {
+    conf: Configuration,
+    sysloop: EspSystemEventLoop,
+    state: Arc<Mutex<OtaSchedulerState>>,
+    etag: Option<String>,
+    pending: Option<Manifest>,
+}
+
+impl Scheduler {
+    fn run(mut self, commands: mpsc::Receiver<Command>, preconditions: impl Fn() -> bool) {
+        let mut timeout = self.conf.initial_delay;
+
+        loop {
+            match commands.recv_timeout(timeout) {
+                Ok(Command::Check) | Err(mpsc::RecvTimeoutError::Timeout) => (),
+                _ => break,
+            }
+
+            timeout = self.conf.poll_interval;
+
+            self.set_state(OtaSchedulerState::Checking);
+
+            match self.check() {
+                Ok(update_available) => self.post(OtaSchedulerEvent::Checked { update_available }),
+                Err(e) => {
+                    warn!("Failed to fetch the OTA manifest: {}", e);
+                    self.post(OtaSchedulerEvent::Failed(OtaFailure::Manifest));
+                }
+            }
+
+            let manifest = if let Some(manifest) = self.pending.clone() {
+                manifest
+            } else {
+                self.set_state(OtaSchedulerState::Idle);
+                continue;
+            };
+
+            let reason = if !self.conf.window.map(|w| w.is_open()).unwrap_or(true) {
+                Some(DeferReason::OutsideWindow)
+            } else if !preconditions() {
+                Some(DeferReason::Preconditions)
+            } else {
+                None
+            };
+
+            if let Some(reason) = reason {
+                info!("Deferring update to {}: {:?}", manifest.version, reason);
+
+                self.set_state(OtaSchedulerState::Deferred(reason));
+                self.post(OtaSchedulerEvent::Deferred(reason));
+
+                continue;
+            }
+
+            self.set_state(OtaSchedulerState::Installing);
+
+            match self.install(&manifest) {
+                Ok(()) => {
+                    info!("Installed firmware {}", manifest.version);
+
+                    self.pending = None;
+
+                    self.set_state(OtaSchedulerState::Installed);
+                    self.post(OtaSchedulerEvent::Installed);
+
+                    if self.conf.restart {
+                        // Give the event handlers a chance to run
+                        thread::sleep(Duration::from_secs(1));
+
+                        info!("Restarting into firmware {}", manifest.version);
+
+                        unsafe { esp_restart() };
+                    }
+
+                    // Nothing left to do until the restart
+                    break;
+                }
+                Err(failure) => {
+                    warn!(
+                        "Failed to install firmware {}: {:?}",
+                        manifest.version, failure
+                    );
+
+                    // The update stays pending, and is retried on the next poll
+                    self.set_state(OtaSchedulerState::Idle);
+                    self.post(OtaSchedulerEvent::Failed(failure));
+                }
+            }
+        }
+    }
+
+    /// Fetches the manifest, returning whether an update is pending
+    fn check(&mut self) -> Result<bool, EspError> {
+        let mut connection = self.connect()?;
+
+        let mut headers = heapless::Vec::<(&str, &str), 1>::new();
+        if let Some(etag) = self.etag.as_deref() {
+            headers.push(("If-None-Match", etag)).unwrap();
+        }
+
+        connection.initiate_request(Method::Get, &self.conf.manifest_url, &headers)?;
+        connection.initiate_response()?;
+
+        match connection.status() {
+            304 => {
+                debug!("OTA manifest not modified");
+
+                return Ok(self.pending.is_some());
+            }
+            200 => (),
+            status => {
+                warn!("OTA manifest request failed with status {}", status);
+
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+        }
+
+        let etag = connection.header("ETag").map(String::from);
+
+        let mut body = vec![];
+        let mut buf = [0; 256];
+
+        loop {
+            let len = connection.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+
+            // Manifests are small, anything larger is an error page at best
+            if body.len() + len > 4096 {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+            }
+
+            body.extend_from_slice(&buf[..len]);
+        }
+
+        let manifest = core::str::from_utf8(&body)
+            .ok()
+            .and_then(|body| Manifest::parse(&self.conf.manifest_url, body))
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?;
+
+        // Only cached once parsed successfully
+        self.etag = etag;
+
+        let running = running_version();
+
+        self.pending = if manifest.version != running {
+            info!(
+                "Firmware {} is available (running {})",
+                manifest.version, running
+            );
+
+            Some(manifest)
+        } else {
+            None
+        };
+
+        Ok(self.pending.is_some())
+    }
+
+    fn install(&self, manifest: &Manifest) -> Result<(), OtaFailure> {
+        let mut connection = self.connect().map_err(|_| OtaFailure::Download)?;
+
+        connection
+            .initiate_request(Method::Get, &manifest.url, &[])
+            .map_err(|_| OtaFailure::Download)?;
+        connection
+            .initiate_response()
+            .map_err(|_| OtaFailure::Download)?;
+
+        if connection.status() != 200 {
+            warn!(
+                "Firmware request failed with status {}",
+                connection.status()
+            );
+
+            return Err(OtaFailure::Download);
+        }
+
+        let total = manifest.size.or_else(|| {
+            connection
+                .header("Content-Length")
+                .and_then(|len| len.parse().ok())
+        });
+
+        if let (Some(expected), Some(total)) = (manifest.size, total) {
+            if expected != total {
+                return Err(OtaFailure::Verification);
+            }
+        }
+
+        let mut ota = EspOta::new().map_err(|_| OtaFailure::Install)?;
+        let update = ota.initiate_update().map_err(|_| OtaFailure::Install)?;
+
+        #[cfg(esp_idf_comp_mbedtls_enabled)]
+        let mut sha256 = Sha256::new();
+
+        let mut buf = vec![0; self.conf.buffer_size];
+        let mut downloaded = 0_u32;
+        let mut reported = 0_u32;
+
+        let result = loop {
+            let len = match connection.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(len) => len,
+                Err(_) => break Err(OtaFailure::Download),
+            };
+
+            if update.write(&buf[..len]).is_err() {
+                break Err(OtaFailure::Install);
+            }
+
+            #[cfg(esp_idf_comp_mbedtls_enabled)]
+            sha256.update(&buf[..len]);
+
+            downloaded += len as u32;
+
+            // Every 5%, or every 64KB if the size is unknown
+            let step = total.map(|total| (total / 20).max(1)).unwrap_or(65536);
+
+            if downloaded - reported >= step || Some(downloaded) == total {
+                reported = downloaded;
+
+                self.post(OtaSchedulerEvent::Downloading { downloaded, total });
+            }
+        };
+
+        let result = result.and_then(|_| {
+            if total.map(|total| total != downloaded).unwrap_or(false) {
+                return Err(OtaFailure::Download);
+            }
+
+            #[cfg(esp_idf_comp_mbedtls_enabled)]
+            if let Some(expected) = manifest.sha256.as_deref() {
+                let actual = hex::encode(&sha256.finish());
+
+                if actual != expected {
+                    warn!("Firmware SHA-256 mismatch: {} != {}", actual, expected);
+
+                    return Err(OtaFailure::Verification);
+                }
+            }
+
+            Ok(())
+        });
+
+        match result {
+            // Validates the image, and switches the boot slot
+            Ok(()) => update.complete().map_err(|_| OtaFailure::Install),
+            Err(failure) => {
+                let _ = update.abort();
+
+                Err(failure)
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<EspHttpConnection, EspError> {
+        EspHttpConnection::new(&client::Configuration {
+            timeout: Some(self.conf.timeout),
+            #[cfg(all(not(esp_idf_version = "4.3"), esp_idf_mbedtls_certificate_bundle))]
+            crt_bundle_attach: Some(esp_crt_bundle_attach),
+            #[cfg(feature = "traffic")]
+            service: Service::Ota,
+            #[cfg(feature = "traffic")]
+            throttle: self.conf.throttle,
+            ..Default::default()
+        })
+    }
+
+    fn set_state(&self, state: OtaSchedulerState) {
+        *self.state.lock() = state;
+    }
+
+    fn post(&self, event: OtaSchedulerEvent) {
+        if let Err(e) = self.sysloop.post(&event, None) {
+            warn!("Failed to post OTA scheduler event: {}", e);
+        }
+    }
+}
+
+fn running_version() -> String {
+    #[cfg(esp_idf_version_major = "4")]
+    let app_desc = unsafe { esp_ota_get_app_description().as_ref() };
+    #[cfg(not(esp_idf_version_major = "4"))]
+    let app_desc = unsafe { esp_app_get_description().as_ref() };
+
+    app_desc
+        .map(|app_desc| unsafe { from_cstr_ptr(&app_desc.version as *const _) }.into())
+        .unwrap_or_default()
+}
+
+/// The local time in minutes since midnight, if the system time is set
+fn local_minutes() -> Option<u16> {
+    let now = unsafe { time(core::ptr::null_mut()) };
+
+    let mut tm: tm = Default::default();
+    unsafe { localtime_r(&now, &mut tm) };
+
+    // Not synchronized yet, the clock starts at the epoch
+    if tm.tm_year + 1900 < 2020 {
+        return None;
+    }
+
+    Some((tm.tm_hour * 60 + tm.tm_min) as u16)
+}
@@ -0,0 +1,354 @@
+//! Zeroconf pairing
+//!
+//! Advertises a pairing service over mDNS and serves a short-lived HTTP pairing endpoint.
+//! A client which knows the device PIN (typically printed on a label) can POST it to the
+//! endpoint, together with any credentials or tokens it wants to hand over to the device.
+//! These are persisted in NVS and a `PairingEvent` is posted on the system event loop.
+//!
+//! The endpoint expects an `application/x-www-form-urlencoded` body, e.g.
+//! `pin=123456&token=abcdef`. All fields except `pin` are stored as NVS strings
+//! under their field names, so field names are limited to 15 characters.
+use core::ffi;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSystemEventLoop, EspTypedEventDeserializer,
+    EspTypedEventSerializer, EspTypedEventSource,
+};
+use crate::http::server::EspHttpServer;
+use crate::http::uri;
+use crate::mdns::EspMdns;
+use crate::nvs::{EspNvs, NvsPartitionId};
+use crate::private::mutex::Mutex;
+use crate::random;
+use crate::timer::{EspTaskTimerService, EspTimer};
+
+/// NVS key marking the device as paired
+const PAIRED_KEY: &str = "paired";
+
+/// Maximum accepted size of the pairing request body
+const MAX_BODY_LEN: usize = 512;
+
+/// Maximum length of an NVS key
+const MAX_KEY_LEN: usize = 15;
+
+#[derive(Clone, Debug)]
+pub struct PairingConfiguration<'a> {
+    /// mDNS service type the pairing service is advertised as
+    pub service_type: &'a str,
+    pub instance_name: Option<&'a str>,
+    pub port: u16,
+    /// URI of the pairing endpoint
+    pub uri: &'a str,
+    pub pin: &'a str,
+    /// How long the pairing endpoint accepts requests
+    pub window: Duration,
+    /// Number of wrong PINs after which the pairing endpoint is disabled
+    pub max_attempts: u32,
+}
+
+impl<'a> Default for PairingConfiguration<'a> {
+    fn default() -> Self {
+        Self {
+            service_type: "_esp-pairing",
+            instance_name: None,
+            port: 80,
+            uri: "/pair",
+            pin: "",
+            window: Duration::from_secs(5 * 60),
+            max_attempts: 5,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum PairingEvent {
+    Started,
+    Completed,
+    PinRejected(u32),
+    LockedOut,
+    Expired,
+}
+
+impl EspTypedEventSource for PairingEvent {
+    fn source() -> *const ffi::c_char {
+        b"ESP-PAIRING\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<PairingEvent> for PairingEvent {
+    fn serialize<R>(event: &PairingEvent, f: impl for<'a> FnOnce(&'a EspEventPostData) -> R) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<PairingEvent> for PairingEvent {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a PairingEvent) -> R,
+    ) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Phase {
+    Active,
+    Completed,
+    LockedOut,
+    Expired,
+}
+
+struct State<T: NvsPartitionId> {
+    phase: Phase,
+    failed_attempts: u32,
+    max_attempts: u32,
+    pin: String,
+    service_type: String,
+    mdns: EspMdns,
+    nvs: EspNvs<T>,
+    sysloop: EspSystemEventLoop,
+}
+
+impl<T: NvsPartitionId> State<T> {
+    fn finish(&mut self, phase: Phase, event: PairingEvent) {
+        self.phase = phase;
+
+        if let Err(e) = self.mdns.remove_service(&self.service_type, "_tcp") {
+            warn!("Failed to remove the pairing mDNS service: {}", e);
+        }
+
+        self.post(event);
+    }
+
+    fn post(&self, event: PairingEvent) {
+        if let Err(e) = self.sysloop.post(&event, None) {
+            warn!("Failed to post pairing event {:?}: {}", event, e);
+        }
+    }
+
+    fn pair(&mut self, fields: &[(String, String)]) -> Result<(), EspError> {
+        for (key, value) in fields {
+            self.nvs.set_str(key, value)?;
+        }
+
+        self.nvs.set_u8(PAIRED_KEY, 1)?;
+
+        Ok(())
+    }
+}
+
+pub struct EspPairing<T: NvsPartitionId> {
+    state: Arc<Mutex<State<T>>>,
+    _timer: EspTimer,
+}
+
+impl<T> EspPairing<T>
+where
+    T: NvsPartitionId + Send + 'static,
+{
+    /// Advertises the pairing service and registers the pairing endpoint with the server.
+    ///
+    /// The server should outlive the returned instance, as the endpoint handler stays registered
+    /// for the lifetime of the server; after the pairing window has passed, it answers with
+    /// `410 Gone`.
+    pub fn start(
+        conf: &PairingConfiguration,
+        mut mdns: EspMdns,
+        server: &mut EspHttpServer,
+        nvs: EspNvs<T>,
+        sysloop: EspSystemEventLoop,
+        timer_service: &EspTaskTimerService,
+    ) -> Result<Self, EspError> {
+        if conf.pin.is_empty() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        mdns.add_service(
+            conf.instance_name,
+            conf.service_type,
+            "_tcp",
+            conf.port,
+            &[("path", conf.uri)],
+        )?;
+
+        let state = Arc::new(Mutex::new(State {
+            phase: Phase::Active,
+            failed_attempts: 0,
+            max_attempts: conf.max_attempts,
+            pin: conf.pin.into(),
+            service_type: conf.service_type.into(),
+            mdns,
+            nvs,
+            sysloop,
+        }));
+
+        {
+            let state = state.clone();
+
+            server.fn_handler(conf.uri, Method::Post, move |mut request| {
+                let mut body = Vec::new();
+                let mut buf = [0_u8; 64];
+
+                loop {
+                    let len = request.connection().read(&mut buf)?;
+                    if len == 0 {
+                        break;
+                    }
+
+                    if body.len() + len > MAX_BODY_LEN {
+                        request.into_status_response(413)?;
+                        return Ok(());
+                    }
+
+                    body.extend_from_slice(&buf[..len]);
+                }
+
+                let (status, message) = Self::handle(&state, &body);
+
+                request
+                    .into_status_response(status)?
+                    .write_all(message.as_bytes())?;
+
+                Ok(())
+            })?;
+        }
+
+        let timer = {
+            let state = state.clone();
+
+            timer_service.timer(move || {
+                let mut state = state.lock();
+
+                if state.phase == Phase::Active {
+                    info!("Pairing window expired");
+
+                    state.finish(Phase::Expired, PairingEvent::Expired);
+                }
+            })?
+        };
+
+        timer.after(conf.window)?;
+
+        state.lock().post(PairingEvent::Started);
+
+        info!(
+            "Started pairing on port {} with endpoint {}",
+            conf.port, conf.uri
+        );
+
+        Ok(Self {
+            state,
+            _timer: timer,
+        })
+    }
+
+    /// Returns `true` if a client has successfully paired with the device
+    pub fn is_paired(&self) -> bool {
+        self.state.lock().phase == Phase::Completed
+    }
+
+    /// Checks whether the device has been paired, possibly during a previous boot
+    pub fn is_device_paired(nvs: &EspNvs<T>) -> Result<bool, EspError> {
+        Ok(nvs.get_u8(PAIRED_KEY)?.unwrap_or(0) != 0)
+    }
+
+    /// Forgets the pairing, so that the device can be paired again
+    pub fn unpair(nvs: &mut EspNvs<T>) -> Result<(), EspError> {
+        nvs.remove(PAIRED_KEY)?;
+
+        Ok(())
+    }
+
+    fn handle(state: &Mutex<State<T>>, body: &[u8]) -> (u16, &'static str) {
+        let mut state = state.lock();
+
+        match state.phase {
+            Phase::Active => (),
+            Phase::LockedOut => return (429, "Too many attempts"),
+            _ => return (410, "Pairing is not available"),
+        }
+
+        let body = match core::str::from_utf8(body) {
+            Ok(body) => body,
+            Err(_) => return (400, "Invalid request"),
+        };
+
+        let mut pin = None;
+        let mut fields = Vec::new();
+
+        for (key, value) in uri::query_pairs(body) {
+            let (key, value) = match (uri::decode_form(key), uri::decode_form(value)) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return (400, "Invalid request"),
+            };
+
+            if key == "pin" {
+                pin = Some(value);
+            } else if key.is_empty() || key.len() > MAX_KEY_LEN || key == PAIRED_KEY {
+                return (400, "Invalid field name");
+            } else {
+                fields.push((key, value));
+            }
+        }
+
+        if !pin
+            .map(|pin| Self::pin_matches(&pin, &state.pin))
+            .unwrap_or(false)
+        {
+            state.failed_attempts += 1;
+
+            warn!("Pairing PIN rejected ({} attempts)", state.failed_attempts);
+
+            if state.failed_attempts >= state.max_attempts {
+                state.finish(Phase::LockedOut, PairingEvent::LockedOut);
+            } else {
+                let attempts = state.failed_attempts;
+                state.post(PairingEvent::PinRejected(attempts));
+            }
+
+            return (403, "Invalid PIN");
+        }
+
+        if let Err(e) = state.pair(&fields) {
+            warn!("Failed to persist the pairing: {}", e);
+
+            return (500, "Failed to persist the pairing");
+        }
+
+        info!("Pairing completed");
+
+        state.finish(Phase::Completed, PairingEvent::Completed);
+
+        (200, "Paired")
+    }
+
+    /// Compares PINs in constant time, so that the PIN cannot be guessed by timing the responses
+    fn pin_matches(pin: &str, expected: &str) -> bool {
+        pin.len() == expected.len()
+            && pin
+                .bytes()
+                .zip(expected.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// Generates a random 6-digit PIN
+pub fn generate_pin() -> String {
+    format!("{:06}", random::next_u32_below(1_000_000))
+}
@@ -0,0 +1,446 @@
+//! Wi-Fi setup portal
+//!
+//! A turnkey captive portal for provisioning the credentials of a Wi-Fi network:
+//! the device brings up an open (or WPA2-protected) SoftAP, hijacks DNS so that connecting
+//! clients are sent to the portal page, serves a form listing the networks found nearby,
+//! and persists the submitted credentials in NVS.
+//!
+//! Once credentials have been stored, subsequent runs return them right away
+//! without starting the portal.
+//!
+//! Note: This module requires the `experimental` cargo feature to be enabled.
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use std::string::String;
+use std::sync::Arc;
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::http::server::{HandlerResult, Request};
+use embedded_svc::http::Method;
+use embedded_svc::io::{Read, Write};
+use embedded_svc::wifi::{
+    AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration as WifiConfiguration,
+};
+
+use esp_idf_sys::*;
+
+use crate::captive::{self, EspCaptiveDns};
+use crate::http::server::{self, EspHttpConnection, EspHttpServer};
+use crate::http::uri;
+use crate::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use crate::private::waitable::*;
+use crate::wifi::EspWifi;
+
+/// Maximum size of the submitted form
+const MAX_FORM_LEN: usize = 512;
+
+const NVS_SSID: &str = "ssid";
+const NVS_PASSWORD: &str = "password";
+
+#[derive(Clone, Debug)]
+pub struct Configuration<'a> {
+    /// The SSID of the SoftAP
+    pub ap_ssid: &'a str,
+    /// If set, the SoftAP is protected with WPA2 and this password
+    pub ap_password: Option<&'a str>,
+    pub ap_channel: u8,
+    /// The title shown on the portal page
+    pub title: &'a str,
+    /// The NVS namespace the credentials are persisted in
+    pub nvs_namespace: &'a str,
+    pub http_port: u16,
+}
+
+impl<'a> Default for Configuration<'a> {
+    fn default() -> Self {
+        Self {
+            ap_ssid: "ESP Setup",
+            ap_password: None,
+            ap_channel: 1,
+            title: "Wi-Fi Setup",
+            nvs_namespace: "wifi_portal",
+            http_port: 80,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Credentials {
+    pub ssid: heapless::String<32>,
+    pub password: heapless::String<64>,
+}
+
+impl From<&Credentials> for ClientConfiguration {
+    fn from(credentials: &Credentials) -> Self {
+        Self {
+            ssid: credentials.ssid.clone(),
+            password: credentials.password.clone(),
+            auth_method: if credentials.password.is_empty() {
+                AuthMethod::None
+            } else {
+                AuthMethod::WPA2Personal
+            },
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Network {
+    ssid: String,
+    signal_strength: i8,
+    secured: bool,
+}
+
+#[derive(Default)]
+struct State {
+    credentials: Option<Credentials>,
+    waker: Option<Waker>,
+}
+
+/// The services which are running while the portal is up
+struct Session {
+    _server: EspHttpServer,
+    _dns: EspCaptiveDns,
+}
+
+pub struct WifiSetupPortal<'a, 'd> {
+    wifi: &'a mut EspWifi<'d>,
+    nvs: EspDefaultNvs,
+    conf: Configuration<'a>,
+}
+
+impl<'a, 'd> WifiSetupPortal<'a, 'd> {
+    pub fn new(
+        wifi: &'a mut EspWifi<'d>,
+        partition: EspDefaultNvsPartition,
+        conf: &Configuration<'a>,
+    ) -> Result<Self, EspError> {
+        Ok(Self {
+            wifi,
+            nvs: EspDefaultNvs::new(partition, conf.nvs_namespace, true)?,
+            conf: conf.clone(),
+        })
+    }
+
+    /// Returns the credentials persisted by a previous run, if any
+    pub fn stored(&self) -> Result<Option<Credentials>, EspError> {
+        let mut buf = [0_u8; 65];
+
+        let ssid = match self.nvs.get_str(NVS_SSID, &mut buf)? {
+            Some(ssid) if !ssid.is_empty() && ssid.len() <= 32 => ssid.into(),
+            _ => return Ok(None),
+        };
+
+        let password = match self.nvs.get_str(NVS_PASSWORD, &mut buf)? {
+            Some(password) if password.len() <= 64 => password.into(),
+            _ => heapless::String::new(),
+        };
+
+        Ok(Some(Credentials { ssid, password }))
+    }
+
+    /// Removes the persisted credentials, so that the next run starts the portal again
+    pub fn clear(&mut self) -> Result<(), EspError> {
+        self.nvs.remove(NVS_SSID)?;
+        self.nvs.remove(NVS_PASSWORD)?;
+
+        Ok(())
+    }
+
+    /// Returns the persisted credentials or, if there are none, runs the portal until
+    /// credentials have been submitted and persists them
+    ///
+    /// The Wi-Fi driver is left running in mixed mode; it is up to the caller to switch
+    /// it to client mode with the returned credentials.
+    pub async fn run(&mut self) -> Result<Credentials, EspError> {
+        if let Some(credentials) = self.stored()? {
+            return Ok(credentials);
+        }
+
+        let state = Arc::new(Waitable::new(State::default()));
+
+        let session = self.start(&state)?;
+
+        let credentials = PortalCredentials(&state).await;
+
+        self.finish(session, &credentials)?;
+
+        Ok(credentials)
+    }
+
+    /// Same as `run`, but blocks the calling thread instead
+    pub fn run_blocking(&mut self) -> Result<Credentials, EspError> {
+        if let Some(credentials) = self.stored()? {
+            return Ok(credentials);
+        }
+
+        let state = Arc::new(Waitable::new(State::default()));
+
+        let session = self.start(&state)?;
+
+        let credentials = state.wait_while_and_get(
+            |state| state.credentials.is_none(),
+            |state| state.credentials.clone().unwrap(),
+        );
+
+        self.finish(session, &credentials)?;
+
+        Ok(credentials)
+    }
+
+    fn start(&mut self, state: &Arc<Waitable<State>>) -> Result<Session, EspError> {
+        let ap_conf = AccessPointConfiguration {
+            ssid: self.conf.ap_ssid.into(),
+            password: self.conf.ap_password.unwrap_or("").into(),
+            auth_method: if self.conf.ap_password.is_some() {
+                AuthMethod::WPA2Personal
+            } else {
+                AuthMethod::None
+            },
+            channel: self.conf.ap_channel,
+            ..Default::default()
+        };
+
+        // The client interface is needed for scanning
+        self.wifi.set_configuration(&WifiConfiguration::Mixed(
+            ClientConfiguration::default(),
+            ap_conf,
+        ))?;
+
+        if !self.wifi.is_started()? {
+            self.wifi.start()?;
+        }
+
+        let networks = Arc::new(self.scan());
+
+        let ip = self.wifi.ap_netif().get_ip_info()?.ip;
+
+        let dns = EspCaptiveDns::new(&captive::Configuration {
+            ip,
+            ..Default::default()
+        })?;
+
+        let mut server = EspHttpServer::new(&server::Configuration {
+            http_port: self.conf.http_port,
+            uri_match_wildcard: true,
+            ..Default::default()
+        })
+        .map_err(|e| e.0)?;
+
+        let title = Arc::new(String::from(self.conf.title));
+        let portal = Arc::new(format!("http://{}/", ip));
+
+        server.fn_handler("/", Method::Get, move |request| {
+            form(request, &title, &networks)
+        })?;
+
+        {
+            let state = state.clone();
+
+            server.fn_handler("/save", Method::Post, move |request| save(request, &state))?;
+        }
+
+        // Everything else, notably the connectivity checks of the clients, is redirected to the
+        // portal, which makes the clients pop up their captive portal login window
+        server.fn_handler("/*", Method::Get, move |request| {
+            request
+                .into_response(302, None, &[("Location", portal.as_str())])?
+                .flush()?;
+
+            Ok(())
+        })?;
+
+        info!(
+            "Wi-Fi setup portal started on SSID {} at {}",
+            self.conf.ap_ssid, ip
+        );
+
+        Ok(Session {
+            _server: server,
+            _dns: dns,
+        })
+    }
+
+    fn finish(&mut self, session: Session, credentials: &Credentials) -> Result<(), EspError> {
+        // Give the client a chance to receive the confirmation page
+        esp_idf_hal::delay::FreeRtos::delay_ms(500);
+
+        drop(session);
+
+        self.nvs.set_str(NVS_SSID, &credentials.ssid)?;
+        self.nvs.set_str(NVS_PASSWORD, &credentials.password)?;
+
+        info!(
+            "Wi-Fi setup portal stopped, got credentials for SSID {}",
+            credentials.ssid
+        );
+
+        Ok(())
+    }
+
+    fn scan(&mut self) -> Vec<Network> {
+        let aps = match self.wifi.scan() {
+            Ok(aps) => aps,
+            Err(e) => {
+                warn!("Wi-Fi setup portal scan failed: {}", e);
+
+                return Vec::new();
+            }
+        };
+
+        let mut networks: Vec<Network> = Vec::new();
+
+        for ap in aps.iter().filter(|ap| !ap.ssid.is_empty()) {
+            if let Some(network) = networks.iter_mut().find(|network| network.ssid == ap.ssid) {
+                network.signal_strength = network.signal_strength.max(ap.signal_strength);
+            } else {
+                networks.push(Network {
+                    ssid: ap.ssid.as_str().into(),
+                    signal_strength: ap.signal_strength,
+                    secured: ap.auth_method != AuthMethod::None,
+                });
+            }
+        }
+
+        networks.sort_by(|a, b| b.signal_strength.cmp(&a.signal_strength));
+
+        networks
+    }
+}
+
+fn form(
+    request: Request<&mut EspHttpConnection>,
+    title: &str,
+    networks: &[Network],
+) -> HandlerResult {
+    let title = escape(title);
+
+    let mut page = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+        <title>{}</title></head><body><h1>{}</h1>\
+        <form method=\"post\" action=\"/save\">\
+        <p><label>Network<br><input name=\"ssid\" list=\"networks\" maxlength=\"32\" required></label></p>\
+        <datalist id=\"networks\">",
+        title, title
+    );
+
+    for network in networks {
+        page.push_str(&format!(
+            "<option value=\"{}\">{} dBm{}</option>",
+            escape(&network.ssid),
+            network.signal_strength,
+            if network.secured { ", secured" } else { "" }
+        ));
+    }
+
+    page.push_str(
+        "</datalist>\
+        <p><label>Password<br><input name=\"password\" type=\"password\" maxlength=\"64\"></label></p>\
+        <p><button type=\"submit\">Connect</button></p>\
+        </form></body></html>",
+    );
+
+    request
+        .into_response(200, None, &[("Content-Type", "text/html")])?
+        .write_all(page.as_bytes())?;
+
+    Ok(())
+}
+
+fn save(mut request: Request<&mut EspHttpConnection>, state: &Waitable<State>) -> HandlerResult {
+    let mut buf = [0_u8; MAX_FORM_LEN];
+    let mut len = 0;
+
+    while len < buf.len() {
+        let read = request.connection().read(&mut buf[len..])?;
+        if read == 0 {
+            break;
+        }
+
+        len += read;
+    }
+
+    let credentials = core::str::from_utf8(&buf[..len]).ok().and_then(parse);
+
+    let credentials = if let Some(credentials) = credentials {
+        credentials
+    } else {
+        request
+            .into_status_response(400)?
+            .write_all(b"Invalid network name or password")?;
+
+        return Ok(());
+    };
+
+    request
+        .into_response(200, None, &[("Content-Type", "text/html")])?
+        .write_all(
+            format!(
+                "<!DOCTYPE html><html><body><p>Connecting to {}...</p></body></html>",
+                escape(&credentials.ssid)
+            )
+            .as_bytes(),
+        )?;
+
+    state.get_mut(|state| {
+        state.credentials = Some(credentials);
+
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    });
+
+    state.cvar.notify_all();
+
+    Ok(())
+}
+
+fn parse(form: &str) -> Option<Credentials> {
+    let ssid = uri::query_param(form, "ssid")?;
+    let password = uri::query_param(form, "password").unwrap_or_default();
+
+    // A WPA2 passphrase has 8 to 63 characters, or is a 64 hex digit PSK
+    if ssid.is_empty()
+        || ssid.len() > 32
+        || !(password.is_empty() || (8..=64).contains(&password.len()))
+    {
+        return None;
+    }
+
+    Some(Credentials {
+        ssid: ssid.as_str().into(),
+        password: password.as_str().into(),
+    })
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+struct PortalCredentials<'a>(&'a Waitable<State>);
+
+impl<'a> Future for PortalCredentials<'a> {
+    type Output = Credentials;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.get_mut(|state| {
+            if let Some(credentials) = state.credentials.clone() {
+                Poll::Ready(credentials)
+            } else {
+                state.waker = Some(cx.waker().clone());
+
+                Poll::Pending
+            }
+        })
+    }
+}
@@ -0,0 +1,352 @@
+//! Battery monitor
+//!
+//! `EspPowerMonitor` periodically samples the battery voltage on an ADC channel, converts it to
+//! a charge percentage with a discharge `Curve`, and posts a `PowerEvent` on the system event
+//! loop whenever the battery level crosses the low or critical threshold:
+//!
+//! ```ignore
+//! let adc = AdcDriver::new(peripherals.adc1, &adc::config::Config::new().calibration(true))?;
+//! let channel: AdcChannelDriver<'static, Gpio34, Atten11dB<_>> =
+//!     AdcChannelDriver::new(peripherals.pins.gpio34)?;
+//!
+//! let monitor = EspPowerMonitor::new(
+//!     &Configuration {
+//!         // A 100k/100k divider
+//!         divider: 2.0,
+//!         ..Default::default()
+//!     },
+//!     adc,
+//!     channel,
+//!     sysloop.clone(),
+//! )?;
+//!
+//! let _subscription = sysloop.subscribe(|event: &PowerEvent| {
+//!     if let PowerEvent::Critical(_) = event {
+//!         // Save the state, and go to deep sleep
+//!     }
+//! })?;
+//! ```
+//!
+//! The ADC driver must be created with calibration enabled, so that its readings are in
+//! millivolts.
+use core::ffi;
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_hal::adc::{Adc, AdcChannelDriver, AdcDriver, Attenuation};
+use esp_idf_hal::gpio::ADCPin;
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSystemEventLoop, EspTypedEventDeserializer,
+    EspTypedEventSerializer, EspTypedEventSource,
+};
+use crate::private::mutex::Mutex;
+
+/// A discharge curve, mapping the battery voltage to its charge percentage
+///
+/// The percentage is interpolated linearly between the points, and clamped to the first and
+/// last points.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Curve(Vec<(u16, u8)>);
+
+impl Curve {
+    /// Creates a curve from `(millivolts, percentage)` points, in any order
+    pub fn new(points: impl Into<Vec<(u16, u8)>>) -> Self {
+        let mut points = points.into();
+
+        points.sort_unstable_by_key(|(millivolts, _)| *millivolts);
+
+        Self(points)
+    }
+
+    /// A typical curve of a single cell LiPo battery, under a light load
+    pub fn lipo() -> Self {
+        Self::new([
+            (3300, 0),
+            (3500, 5),
+            (3600, 15),
+            (3700, 35),
+            (3750, 50),
+            (3800, 60),
+            (3900, 75),
+            (4000, 85),
+            (4100, 95),
+            (4200, 100),
+        ])
+    }
+
+    pub fn percentage(&self, millivolts: u16) -> u8 {
+        let above = self.0.iter().position(|(point, _)| *point > millivolts);
+
+        match above {
+            None => self
+                .0
+                .last()
+                .map(|(_, percentage)| *percentage)
+                .unwrap_or(0),
+            Some(0) => self.0[0].1,
+            Some(index) => {
+                let (low_mv, low) = self.0[index - 1];
+                let (high_mv, high) = self.0[index];
+
+                let ratio = (millivolts - low_mv) as f32 / (high_mv - low_mv) as f32;
+
+                (low as f32 + (high as f32 - low as f32) * ratio) as u8
+            }
+        }
+    }
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Self::lipo()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub interval: Duration,
+    /// The ratio of the battery voltage to the voltage on the ADC pin, e.g. 2.0 with an equal
+    /// resistor divider
+    pub divider: f32,
+    /// How many readings are averaged into each sample
+    pub samples: u8,
+    pub curve: Curve,
+    /// The percentage below which the battery is low
+    pub low: u8,
+    /// The percentage below which the battery is critical
+    pub critical: u8,
+    /// How many percents above a threshold the battery must go back to leave its level, so
+    /// that a noisy voltage does not post a stream of events
+    pub hysteresis: u8,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            divider: 1.0,
+            samples: 16,
+            curve: Default::default(),
+            low: 20,
+            critical: 5,
+            hysteresis: 3,
+            stack_size: 4096,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Level {
+    Critical,
+    Low,
+    Normal,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Status {
+    pub millivolts: u16,
+    pub percentage: u8,
+    pub level: Level,
+}
+
+/// Posted when the level of the battery changes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerEvent {
+    LowBattery(Status),
+    Critical(Status),
+    /// The battery is back above the low threshold, e.g. when charging
+    Recovered(Status),
+}
+
+impl PowerEvent {
+    pub fn status(&self) -> &Status {
+        match self {
+            Self::LowBattery(status) | Self::Critical(status) | Self::Recovered(status) => status,
+        }
+    }
+}
+
+impl EspTypedEventSource for PowerEvent {
+    fn source() -> *const ffi::c_char {
+        b"ESP-POWER\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<PowerEvent> for PowerEvent {
+    fn serialize<R>(event: &PowerEvent, f: impl for<'a> FnOnce(&'a EspEventPostData) -> R) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<PowerEvent> for PowerEvent {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a PowerEvent) -> R,
+    ) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+type Sampler = Box<dyn FnMut() -> Result<u16, EspError> + Send + 'static>;
+
+pub struct EspPowerMonitor {
+    status: Arc<Mutex<Option<Status>>>,
+    stop: mpsc::Sender<()>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspPowerMonitor {
+    pub fn new<ADC, T, ATTEN>(
+        conf: &Configuration,
+        mut adc: AdcDriver<'static, ADC>,
+        mut channel: AdcChannelDriver<'static, T, ATTEN>,
+        sysloop: EspSystemEventLoop,
+    ) -> Result<Self, EspError>
+    where
+        ADC: Adc + 'static,
+        T: ADCPin<Adc = ADC> + 'static,
+        ATTEN: Attenuation<ADC> + 'static,
+    {
+        let sampler: Sampler = Box::new(move || adc.read(&mut channel));
+
+        let status = Arc::new(Mutex::new(None));
+
+        let (stop, stopped) = mpsc::channel();
+
+        let join_handle = {
+            let conf = conf.clone();
+            let status = status.clone();
+
+            thread::Builder::new()
+                .name("power".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, sampler, sysloop, status, stopped))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        Ok(Self {
+            status,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// The last sample, if any
+    pub fn status(&self) -> Option<Status> {
+        *self.status.lock()
+    }
+
+    fn run(
+        conf: Configuration,
+        mut sampler: Sampler,
+        sysloop: EspSystemEventLoop,
+        status: Arc<Mutex<Option<Status>>>,
+        stopped: mpsc::Receiver<()>,
+    ) {
+        let mut level = Level::Normal;
+
+        loop {
+            match Self::sample(&conf, &mut sampler) {
+                Ok(millivolts) => {
+                    let percentage = conf.curve.percentage(millivolts);
+
+                    let new_level = Self::level(&conf, level, percentage);
+
+                    let current = Status {
+                        millivolts,
+                        percentage,
+                        level: new_level,
+                    };
+
+                    debug!("Battery: {:?}", current);
+
+                    *status.lock() = Some(current);
+
+                    if new_level != level {
+                        let event = match new_level {
+                            Level::Critical => PowerEvent::Critical(current),
+                            Level::Low => PowerEvent::LowBattery(current),
+                            Level::Normal => PowerEvent::Recovered(current),
+                        };
+
+                        info!("Battery level changed: {:?}", event);
+
+                        if let Err(e) = sysloop.post(&event, None) {
+                            warn!("Failed to post power event: {}", e);
+                        }
+
+                        level = new_level;
+                    }
+                }
+                Err(e) => warn!("Failed to sample the battery voltage: {}", e),
+            }
+
+            match stopped.recv_timeout(conf.interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                _ => break,
+            }
+        }
+    }
+
+    fn sample(conf: &Configuration, sampler: &mut Sampler) -> Result<u16, EspError> {
+        let samples = conf.samples.max(1) as u32;
+
+        let mut sum = 0_u32;
+
+        for _ in 0..samples {
+            sum += sampler()? as u32;
+        }
+
+        Ok(((sum / samples) as f32 * conf.divider).min(u16::MAX as f32) as u16)
+    }
+
+    /// The level at `percentage`, where each level is left upwards only `hysteresis` percents
+    /// above its threshold
+    fn level(conf: &Configuration, current: Level, percentage: u8) -> Level {
+        let above = |threshold: u8, level: Level| {
+            let threshold = if current <= level {
+                threshold.saturating_add(conf.hysteresis)
+            } else {
+                threshold
+            };
+
+            percentage >= threshold
+        };
+
+        if !above(conf.critical, Level::Critical) {
+            Level::Critical
+        } else if !above(conf.low, Level::Low) {
+            Level::Low
+        } else {
+            Level::Normal
+        }
+    }
+}
+
+impl Drop for EspPowerMonitor {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
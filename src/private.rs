@@ -1,10 +1,24 @@
 #![allow(unused)]
 
+pub mod authority;
+#[cfg(feature = "alloc")]
+pub mod base64;
+pub mod civil;
 pub mod common;
 pub mod cstr;
+#[cfg(feature = "alloc")]
+pub mod hex;
+#[cfg(feature = "alloc")]
+pub mod json;
 pub mod mutex;
 #[cfg(esp_idf_comp_esp_netif_enabled)]
 pub mod net;
+#[cfg(all(feature = "alloc", esp_idf_comp_mbedtls_enabled))]
+pub mod sha256;
+#[cfg(esp_idf_comp_lwip_enabled)]
+pub mod socket;
 pub mod waitable;
+#[cfg(all(feature = "alloc", esp_idf_comp_mbedtls_enabled))]
+pub mod x509;
 
 mod stubs;
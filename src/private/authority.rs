@@ -0,0 +1,51 @@
+//! The authority of URIs, e.g. `user@[::1]:8080`, as exported by `http::uri` and also parsed
+//! out of the `host:port` configurations of the clients outside of it
+
+/// The parts of the authority of a URI
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct Authority<'a> {
+    /// The user information before the `@`, if any
+    pub userinfo: Option<&'a str>,
+    /// The host, without the brackets of an IPv6 address
+    pub host: &'a str,
+    /// The port, if explicit
+    pub port: Option<u16>,
+    /// The host and the port as they appear in the URI, e.g. for the `Host` header
+    pub host_port: &'a str,
+}
+
+impl<'a> Authority<'a> {
+    /// Parses an authority, without the leading `//`; `None` if its port is not a number
+    pub fn parse(authority: &'a str) -> Option<Self> {
+        let (userinfo, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host_port)) => (Some(userinfo), host_port),
+            None => (None, authority),
+        };
+
+        // The port follows the brackets of an IPv6 address
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port),
+            _ => (host_port, ""),
+        };
+
+        let port = if port.is_empty() {
+            None
+        } else {
+            Some(port.parse().ok()?)
+        };
+
+        let host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+
+        Some(Self {
+            userinfo,
+            host,
+            port,
+            host_port,
+        })
+    }
+}
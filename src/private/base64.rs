@@ -0,0 +1,72 @@
+//! Base64 encoding and decoding, in the standard alphabet with padding (RFC 4648), and in the
+//! URL-safe alphabet without padding of JOSE (RFC 7515)
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const URL_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+pub fn encode(data: &[u8]) -> String {
+    let mut encoded = encode_with(data, ALPHABET);
+
+    while encoded.len() % 4 != 0 {
+        encoded.push('=');
+    }
+
+    encoded
+}
+
+pub fn encode_url(data: &[u8]) -> String {
+    encode_with(data, URL_ALPHABET)
+}
+
+/// Decodes either alphabet, with or without padding, skipping whitespace (e.g. the line breaks
+/// of MIME bodies)
+pub fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+
+    let mut acc = 0_u32;
+    let mut bits = 0;
+
+    for byte in encoded.trim_end().trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => return None,
+        };
+
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+fn encode_with(data: &[u8], alphabet: &[u8; 64]) -> String {
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+
+    for chunk in data.chunks(3) {
+        let acc = chunk.iter().enumerate().fold(0_u32, |acc, (index, byte)| {
+            acc | (*byte as u32) << (16 - 8 * index)
+        });
+
+        // 2, 3 or 4 characters for 1, 2 or 3 bytes
+        for index in 0..=chunk.len() {
+            encoded.push(alphabet[(acc >> (18 - 6 * index) & 0x3f) as usize] as char);
+        }
+    }
+
+    encoded
+}
@@ -1,8 +1,23 @@
 use core::cell::UnsafeCell;
 
+#[cfg(feature = "heapless-config")]
+use esp_idf_sys::{EspError, ESP_ERR_INVALID_SIZE};
+
 pub struct Newtype<T>(pub T);
 
 pub struct UnsafeCellSendSync<T>(pub UnsafeCell<T>);
 
 unsafe impl<T> Send for UnsafeCellSendSync<T> {}
 unsafe impl<T> Sync for UnsafeCellSendSync<T> {}
+
+/// Copies `s` into a bounded string, failing with `ESP_ERR_INVALID_SIZE` if it does not fit
+#[cfg(feature = "heapless-config")]
+pub fn bounded_str<const N: usize>(s: &str) -> Result<heapless::String<N>, EspError> {
+    let mut bounded = heapless::String::new();
+
+    bounded
+        .push_str(s)
+        .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+
+    Ok(bounded)
+}
@@ -0,0 +1,29 @@
+//! Lowercase hex encoding and decoding, as used for digests and identifiers
+use core::fmt::Write;
+
+extern crate alloc;
+use alloc::string::String;
+
+pub fn encode(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(data.len() * 2);
+
+    for byte in data {
+        write!(&mut hex, "{:02x}", byte).unwrap();
+    }
+
+    hex
+}
+
+pub fn decode<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+    }
+
+    Some(bytes)
+}
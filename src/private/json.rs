@@ -0,0 +1,395 @@
+//! A minimal JSON value, parser and serializer, for the small documents
+//! (manifests, RPC messages) exchanged by the services of the crate
+use core::fmt::{self, Display, Formatter, Write};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Documents nested deeper than this are rejected, so as to bound the stack usage
+const MAX_DEPTH: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// The members, in document order
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parser = Parser {
+            input: s.as_bytes(),
+            offset: 0,
+        };
+
+        let value = parser.value(0)?;
+
+        parser.whitespace();
+
+        if parser.offset == parser.input.len() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the member `key` of an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        self.as_object()?
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Returns the number if it is a non-negative integer
+    pub fn as_u64(&self) -> Option<u64> {
+        self.as_f64()
+            .filter(|value| *value >= 0.0 && value.fract() == 0.0 && *value < u64::MAX as f64)
+            .map(|value| value as u64)
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        self.as_f64()
+            .filter(|value| value.fract() == 0.0 && value.abs() < i64::MAX as f64)
+            .map(|value| value as i64)
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Self::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Json)]> {
+        match self {
+            Self::Object(members) => Some(members),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(value: f64) -> Self {
+        Self::Number(value)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Self {
+        Self::Number(value as f64)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(value: u64) -> Self {
+        Self::Number(value as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Self::String(value.into())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(value) => write!(f, "{}", value),
+            // JSON has no representation for these
+            Self::Number(value) if !value.is_finite() => f.write_str("null"),
+            Self::Number(value) => write!(f, "{}", value),
+            Self::String(value) => write_escaped(f, value),
+            Self::Array(values) => {
+                f.write_char('[')?;
+
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        f.write_char(',')?;
+                    }
+
+                    write!(f, "{}", value)?;
+                }
+
+                f.write_char(']')
+            }
+            Self::Object(members) => {
+                f.write_char('{')?;
+
+                for (index, (name, value)) in members.iter().enumerate() {
+                    if index > 0 {
+                        f.write_char(',')?;
+                    }
+
+                    write_escaped(f, name)?;
+                    write!(f, ":{}", value)?;
+                }
+
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+/// Writes `s` as a quoted and escaped JSON string
+pub fn write_escaped(w: &mut impl Write, s: &str) -> fmt::Result {
+    w.write_char('"')?;
+
+    for c in s.chars() {
+        match c {
+            '"' => w.write_str("\\\"")?,
+            '\\' => w.write_str("\\\\")?,
+            '\n' => w.write_str("\\n")?,
+            '\r' => w.write_str("\\r")?,
+            '\t' => w.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => w.write_char(c)?,
+        }
+    }
+
+    w.write_char('"')
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn value(&mut self, depth: usize) -> Option<Json> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        self.whitespace();
+
+        match *self.input.get(self.offset)? {
+            b'n' => self.literal("null", Json::Null),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'[' => {
+                self.offset += 1;
+
+                let mut values = Vec::new();
+
+                if !self.consume(b']') {
+                    loop {
+                        values.push(self.value(depth + 1)?);
+
+                        if self.consume(b']') {
+                            break;
+                        }
+
+                        self.expect(b',')?;
+                    }
+                }
+
+                Some(Json::Array(values))
+            }
+            b'{' => {
+                self.offset += 1;
+
+                let mut members = Vec::new();
+
+                if !self.consume(b'}') {
+                    loop {
+                        self.whitespace();
+
+                        let name = self.string()?;
+
+                        self.expect(b':')?;
+
+                        members.push((name, self.value(depth + 1)?));
+
+                        if self.consume(b'}') {
+                            break;
+                        }
+
+                        self.expect(b',')?;
+                    }
+                }
+
+                Some(Json::Object(members))
+            }
+            _ => self.number(),
+        }
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(
+            self.input.get(self.offset),
+            Some(b' ' | b'\t' | b'\n' | b'\r')
+        ) {
+            self.offset += 1;
+        }
+    }
+
+    /// Skips whitespace and consumes `c` if it is next
+    fn consume(&mut self, c: u8) -> bool {
+        self.whitespace();
+
+        if self.input.get(self.offset) == Some(&c) {
+            self.offset += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Option<()> {
+        self.consume(c).then(|| ())
+    }
+
+    fn literal(&mut self, literal: &str, value: Json) -> Option<Json> {
+        if self.input[self.offset..].starts_with(literal.as_bytes()) {
+            self.offset += literal.len();
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    fn number(&mut self) -> Option<Json> {
+        let start = self.offset;
+
+        while matches!(
+            self.input.get(self.offset),
+            Some(b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E')
+        ) {
+            self.offset += 1;
+        }
+
+        let number = core::str::from_utf8(&self.input[start..self.offset]).ok()?;
+
+        // Stricter than JSON on leading zeros and signs, but close enough
+        if number.is_empty() || number.starts_with('+') || number.starts_with('.') {
+            return None;
+        }
+
+        number.parse::<f64>().ok().map(Json::Number)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.input.get(self.offset) != Some(&b'"') {
+            return None;
+        }
+
+        self.offset += 1;
+
+        let mut bytes = Vec::new();
+
+        loop {
+            let c = *self.input.get(self.offset)?;
+            self.offset += 1;
+
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.input.get(self.offset)?;
+                    self.offset += 1;
+
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let high = self.hex4()?;
+
+                            if (0xd800..0xdc00).contains(&high) {
+                                // A surrogate pair
+                                if !self.input[self.offset..].starts_with(b"\\u") {
+                                    return None;
+                                }
+
+                                self.offset += 2;
+
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return None;
+                                }
+
+                                char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))?
+                            } else {
+                                char::from_u32(high)?
+                            }
+                        }
+                        _ => return None,
+                    };
+
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                c if c < 0x20 => return None,
+                c => bytes.push(c),
+            }
+        }
+
+        String::from_utf8(bytes).ok()
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let hex = self.input.get(self.offset..self.offset + 4)?;
+        self.offset += 4;
+
+        u32::from_str_radix(core::str::from_utf8(hex).ok()?, 16).ok()
+    }
+}
+
+/// Builds a `Json::Object` from its members
+pub fn object<const N: usize>(members: [(&str, Json); N]) -> Json {
+    Json::Object(
+        IntoIterator::into_iter(members)
+            .map(|(name, value)| (name.into(), value))
+            .collect(),
+    )
+}
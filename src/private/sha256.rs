@@ -0,0 +1,109 @@
+//! Incremental SHA-256, over the mbedTLS implementation (hardware accelerated where available)
+use core::mem::MaybeUninit;
+
+use esp_idf_sys::*;
+
+use crate::private::cstr::CString;
+
+pub struct Sha256(mbedtls_sha256_context);
+
+impl Sha256 {
+    pub fn new() -> Self {
+        let mut context = MaybeUninit::<mbedtls_sha256_context>::uninit();
+
+        unsafe {
+            mbedtls_sha256_init(context.as_mut_ptr());
+
+            #[cfg(esp_idf_version_major = "4")]
+            mbedtls_sha256_starts_ret(context.as_mut_ptr(), 0);
+            #[cfg(not(esp_idf_version_major = "4"))]
+            mbedtls_sha256_starts(context.as_mut_ptr(), 0);
+
+            Self(context.assume_init())
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        unsafe {
+            #[cfg(esp_idf_version_major = "4")]
+            mbedtls_sha256_update_ret(&mut self.0, data.as_ptr(), data.len() as _);
+            #[cfg(not(esp_idf_version_major = "4"))]
+            mbedtls_sha256_update(&mut self.0, data.as_ptr(), data.len() as _);
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let mut digest = [0; 32];
+
+        unsafe {
+            #[cfg(esp_idf_version_major = "4")]
+            mbedtls_sha256_finish_ret(&mut self.0, digest.as_mut_ptr());
+            #[cfg(not(esp_idf_version_major = "4"))]
+            mbedtls_sha256_finish(&mut self.0, digest.as_mut_ptr());
+        }
+
+        digest
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { mbedtls_sha256_free(&mut self.0) };
+    }
+}
+
+unsafe impl Send for Sha256 {}
+
+/// The SHA-256 of `data`, in one go
+pub fn digest(data: &[u8]) -> [u8; 32] {
+    let mut sha256 = Sha256::new();
+    sha256.update(data);
+    sha256.finish()
+}
+
+/// Verifies a DER encoded signature (ECDSA or RSA) of the SHA-256 of `data` with a PEM encoded
+/// public key; fails with `ESP_ERR_INVALID_ARG` if the key is invalid, and `ESP_FAIL` if the
+/// signature does not match
+pub fn verify_signature(public_key: &str, data: &[u8], signature: &[u8]) -> Result<(), EspError> {
+    let digest = digest(data);
+
+    // The PEM parser wants the terminating NUL to be included in the length
+    let c_public_key =
+        CString::new(public_key).map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+    let c_public_key = c_public_key.as_bytes_with_nul();
+
+    let mut pk: mbedtls_pk_context = Default::default();
+
+    unsafe {
+        mbedtls_pk_init(&mut pk);
+
+        let result =
+            if mbedtls_pk_parse_public_key(&mut pk, c_public_key.as_ptr(), c_public_key.len() as _)
+                != 0
+            {
+                Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+            } else if mbedtls_pk_verify(
+                &mut pk,
+                mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+                digest.as_ptr(),
+                digest.len() as _,
+                signature.as_ptr(),
+                signature.len() as _,
+            ) != 0
+            {
+                Err(EspError::from_infallible::<ESP_FAIL>())
+            } else {
+                Ok(())
+            };
+
+        mbedtls_pk_free(&mut pk);
+
+        result
+    }
+}
@@ -0,0 +1,46 @@
+use esp_idf_sys::*;
+
+use crate::tls::TcpKeepAlive;
+
+pub fn set_sockopt<T>(fd: i32, level: u32, option: u32, value: &T) -> Result<(), EspError> {
+    let result = unsafe {
+        lwip_setsockopt(
+            fd,
+            level as _,
+            option as _,
+            value as *const T as *const _,
+            core::mem::size_of::<T>() as _,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(EspError::from_infallible::<ESP_FAIL>())
+    }
+}
+
+pub fn set_keep_alive(fd: i32, keep_alive: Option<&TcpKeepAlive>) -> Result<(), EspError> {
+    if let Some(keep_alive) = keep_alive {
+        set_sockopt(fd, SOL_SOCKET, SO_KEEPALIVE, &1_i32)?;
+        set_sockopt(
+            fd,
+            IPPROTO_TCP,
+            TCP_KEEPIDLE,
+            &(keep_alive.idle.as_secs() as i32),
+        )?;
+        set_sockopt(
+            fd,
+            IPPROTO_TCP,
+            TCP_KEEPINTVL,
+            &(keep_alive.interval.as_secs() as i32),
+        )?;
+        set_sockopt(fd, IPPROTO_TCP, TCP_KEEPCNT, &(keep_alive.count as i32))
+    } else {
+        set_sockopt(fd, SOL_SOCKET, SO_KEEPALIVE, &0_i32)
+    }
+}
+
+pub fn set_nodelay(fd: i32, nodelay: bool) -> Result<(), EspError> {
+    set_sockopt(fd, IPPROTO_TCP, TCP_NODELAY, &(nodelay as i32))
+}
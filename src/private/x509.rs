@@ -0,0 +1,132 @@
+//! X.509 certificate helpers, over mbedTLS
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use esp_idf_sys::*;
+
+use crate::private::base64;
+use crate::private::civil;
+use crate::private::cstr::CString;
+
+/// The expiry of the first certificate of a PEM chain, since the UNIX epoch
+pub fn not_after(chain: &str) -> Option<Duration> {
+    let c_chain = CString::new(chain).ok()?;
+    let c_chain = c_chain.as_bytes_with_nul();
+
+    let mut crt: mbedtls_x509_crt = Default::default();
+
+    unsafe {
+        mbedtls_x509_crt_init(&mut crt);
+
+        let result = mbedtls_x509_crt_parse(&mut crt, c_chain.as_ptr(), c_chain.len() as _);
+
+        let valid_to = crt.valid_to;
+
+        mbedtls_x509_crt_free(&mut crt);
+
+        if result < 0 {
+            return None;
+        }
+
+        let days = civil::days_from_civil(valid_to.year as _, valid_to.mon as _, valid_to.day as _);
+        let secs = days * 86400
+            + valid_to.hour as i64 * 3600
+            + valid_to.min as i64 * 60
+            + valid_to.sec as i64;
+
+        (secs > 0).then(|| Duration::from_secs(secs as _))
+    }
+}
+
+/// PEM encodes a DER document, e.g. with the `CERTIFICATE` label
+pub fn to_pem(der: &[u8], label: &str) -> String {
+    let encoded = base64::encode(der);
+
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(core::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+
+    pem.push_str(&format!("-----END {}-----\n", label));
+
+    pem
+}
+
+/// Splits the first DER element off `der`, returning its tag, its value and the rest
+pub fn split_tlv(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (tag, rest) = der.split_first()?;
+    let (first, rest) = rest.split_first()?;
+
+    let (len, rest) = if *first < 0x80 {
+        (*first as usize, rest)
+    } else {
+        let count = (*first & 0x7f) as usize;
+
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+
+        let len = rest[..count]
+            .iter()
+            .fold(0_usize, |len, byte| len << 8 | *byte as usize);
+
+        (len, &rest[count..])
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+
+    let (value, rest) = rest.split_at(len);
+
+    Some((*tag, value, rest))
+}
+
+/// The DER certificates of a degenerate, certs-only PKCS #7 (RFC 2315) `SignedData`, as
+/// returned by EST and SCEP servers
+pub fn pkcs7_certificates(der: &[u8]) -> Option<Vec<&[u8]>> {
+    // 1.2.840.113549.1.7.2
+    const OID_SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+
+    let expect = |tag: u8, der: &[u8]| match split_tlv(der) {
+        Some((actual, value, rest)) if actual == tag => Some((value, rest)),
+        _ => None,
+    };
+
+    // ContentInfo
+    let (content_info, _) = expect(0x30, der)?;
+    let (oid, rest) = expect(0x06, content_info)?;
+
+    if oid != OID_SIGNED_DATA {
+        return None;
+    }
+
+    // [0] EXPLICIT SignedData
+    let (explicit, _) = expect(0xa0, rest)?;
+    let (signed_data, _) = expect(0x30, explicit)?;
+
+    // The version, the digest algorithms and the (empty) content
+    let (_, rest) = expect(0x02, signed_data)?;
+    let (_, rest) = expect(0x31, rest)?;
+    let (_, rest) = expect(0x30, rest)?;
+
+    // [0] IMPLICIT SET OF Certificate
+    let (mut certificates, _) = expect(0xa0, rest)?;
+
+    let mut ders = Vec::new();
+
+    while !certificates.is_empty() {
+        let (_, _, rest) = split_tlv(certificates)?;
+
+        ders.push(&certificates[..certificates.len() - rest.len()]);
+
+        certificates = rest;
+    }
+
+    Some(ders)
+}
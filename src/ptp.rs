@@ -0,0 +1,805 @@
+//! PTP time synchronization
+//!
+//! A minimal IEEE 1588-2008 (PTPv2) ordinary clock over UDP/IPv4, for the devices of a LAN
+//! to share a time base more precisely than SNTP allows, e.g. for multi-node data acquisition.
+//! One device runs as the master, serving its system time; the others run as slaves, which
+//! measure their offset to the master and the path delay to it with the delay request-response
+//! mechanism, and correct their system time accordingly:
+//!
+//! ```ignore
+//! let ptp = EspPtp::new(&Configuration {
+//!     role: Role::Slave,
+//!     ..Default::default()
+//! })?;
+//!
+//! if let Some(offset) = ptp.status().offset {
+//!     info!("{} ns from the master", offset);
+//! }
+//! ```
+//!
+//! The slaves also follow the masters of other implementations (e.g. `ptp4l` of linuxptp)
+//! announcing themselves on the same domain, the best one being selected with the comparison of
+//! their announced datasets, as the best master clock algorithm does.
+//!
+//! The timestamps are taken in software, when the messages reach the application, so the
+//! accuracy depends on the latency of the network stack: tens of microseconds over Ethernet on
+//! a quiet network, much worse over Wi-Fi. The sync messages are sent through the default
+//! interface, which should be the one of `Configuration::interface`.
+use core::cmp::Ordering as CmpOrdering;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::private::mutex::Mutex;
+use crate::systime::EspSystemTime;
+
+const EVENT_PORT: u16 = 319;
+const GENERAL_PORT: u16 = 320;
+
+/// The primary multicast group of PTP over IPv4
+const PRIMARY_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 129);
+
+const VERSION: u8 = 2;
+
+const HEADER_LEN: usize = 34;
+const TIMESTAMP_LEN: usize = 10;
+const PORT_IDENTITY_LEN: usize = 10;
+
+const SYNC: u8 = 0x0;
+const DELAY_REQ: u8 = 0x1;
+const FOLLOW_UP: u8 = 0x8;
+const DELAY_RESP: u8 = 0x9;
+const ANNOUNCE: u8 = 0xb;
+
+/// The flags of the first byte of the flag field
+const FLAG_TWO_STEP: u8 = 0x02;
+
+/// The flags of the second byte of the flag field
+const FLAG_UTC_OFFSET_VALID: u8 = 0x04;
+const FLAG_PTP_TIMESCALE: u8 = 0x08;
+
+/// The time source of the announces: the internal oscillator
+const TIME_SOURCE_INTERNAL_OSCILLATOR: u8 = 0xa0;
+
+/// A master is dropped when it has not been announced for that many of its announce intervals
+const ANNOUNCE_RECEIPT_TIMEOUT: u32 = 3;
+
+/// The poll interval of the sockets, and thus the resolution of the timers of the master
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Role {
+    /// Serves the system time
+    Master,
+    /// Corrects the system time to that of the best master
+    Slave,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub role: Role,
+    /// Only the clocks of the same domain synchronize with each other
+    pub domain: u8,
+    /// The address of the interface joining the multicast group, or `UNSPECIFIED` for the
+    /// default one
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub interface: Ipv4Addr,
+    /// The interval of the sync messages of the master
+    pub sync_interval: Duration,
+    /// The interval of the announce messages of the master
+    pub announce_interval: Duration,
+    /// The priorities of the master, the lowest winning, before and after its clock quality
+    pub priority1: u8,
+    pub priority2: u8,
+    /// The class of the clock of the master, e.g. 6 when it is disciplined by a GPS receiver,
+    /// 248 for a free running one
+    pub clock_class: u8,
+    /// The seconds between TAI, the timescale of PTP, and UTC, the one of the system time
+    ///
+    /// The slaves use the offset announced by the master instead, if it announces one.
+    pub utc_offset: i16,
+    /// Have the slave correct the system time, rather than only measure its offset
+    pub adjust_time: bool,
+    /// Offsets larger than this are corrected by stepping the system time rather than slewing it
+    pub step_threshold: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            role: Role::Slave,
+            domain: 0,
+            interface: Ipv4Addr::UNSPECIFIED,
+            sync_interval: Duration::from_secs(1),
+            announce_interval: Duration::from_secs(2),
+            priority1: 128,
+            priority2: 128,
+            clock_class: 248,
+            utc_offset: 37,
+            adjust_time: true,
+            step_threshold: Duration::from_millis(100),
+            stack_size: 4096,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct PtpStatus {
+    /// The clock identity of the master the slave follows
+    pub master: Option<[u8; 8]>,
+    /// The offset of the master to the system time, in nanoseconds, as of the last measurement,
+    /// before its correction
+    pub offset: Option<i64>,
+    /// The one-way delay of the path to the master, as of the last measurement
+    pub path_delay: Option<Duration>,
+    /// The measurements of the slave so far
+    pub measurements: u64,
+    /// The delay requests the master answered so far
+    pub delay_requests: u64,
+}
+
+pub struct EspPtp {
+    identity: [u8; 8],
+    status: Arc<Mutex<PtpStatus>>,
+    stop: Arc<AtomicBool>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspPtp {
+    pub fn new(conf: &Configuration) -> Result<Self, EspError> {
+        let event = Self::bind(conf, EVENT_PORT)?;
+        let general = Self::bind(conf, GENERAL_PORT)?;
+
+        event
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+        general
+            .set_nonblocking(true)
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+
+        let identity = clock_identity();
+
+        let status = Arc::new(Mutex::new(PtpStatus::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let join_handle = {
+            let mut port = Port {
+                conf: conf.clone(),
+                identity: port_identity(&identity),
+                event,
+                general,
+                status: status.clone(),
+                sequence: 0,
+                master: None,
+                pending: None,
+            };
+            let stop = stop.clone();
+
+            thread::Builder::new()
+                .name("ptp".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || port.run(&stop))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        info!(
+            "Started as {:?} of domain {}, clock identity {:02x?}",
+            conf.role, conf.domain, identity
+        );
+
+        Ok(Self {
+            identity,
+            status,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// The clock identity, derived from the MAC address (EUI-64)
+    pub fn clock_identity(&self) -> [u8; 8] {
+        self.identity
+    }
+
+    pub fn status(&self) -> PtpStatus {
+        *self.status.lock()
+    }
+
+    fn bind(conf: &Configuration, port: u16) -> Result<UdpSocket, EspError> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_STATE>())?;
+
+        socket
+            .join_multicast_v4(&PRIMARY_GROUP, &conf.interface)
+            .and_then(|_| socket.set_multicast_ttl_v4(1))
+            .and_then(|_| socket.set_multicast_loop_v4(false))
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+
+        Ok(socket)
+    }
+}
+
+impl Drop for EspPtp {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+/// The master a slave follows, as announced
+#[derive(Clone, Debug)]
+struct Master {
+    port_identity: [u8; PORT_IDENTITY_LEN],
+    /// The announced dataset, in the order of its comparison
+    dataset: [u8; 14],
+    utc_offset: Option<i16>,
+    /// When the master is dropped if not announced again
+    expires: Instant,
+}
+
+/// The timestamps of a measurement in progress, in nanoseconds of the system time
+#[derive(Clone, Debug)]
+struct Pending {
+    sync_sequence: u16,
+    /// When the master sent the sync message, once known
+    t1: Option<i64>,
+    /// When the sync message was received
+    t2: i64,
+    delay_req_sequence: Option<u16>,
+    /// When the delay request was sent
+    t3: i64,
+}
+
+struct Port {
+    conf: Configuration,
+    identity: [u8; PORT_IDENTITY_LEN],
+    event: UdpSocket,
+    general: UdpSocket,
+    status: Arc<Mutex<PtpStatus>>,
+    sequence: u16,
+    master: Option<Master>,
+    pending: Option<Pending>,
+}
+
+impl Port {
+    fn run(&mut self, stop: &AtomicBool) {
+        let mut buf = [0_u8; 128];
+
+        let mut next_sync = Instant::now();
+        let mut next_announce = Instant::now();
+
+        while !stop.load(Ordering::SeqCst) {
+            match self.event.recv_from(&mut buf) {
+                Ok((len, _)) => {
+                    // As close to the reception as it gets in software
+                    let received_at = now();
+
+                    self.handle(&buf[..len], received_at);
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => {
+                    warn!("Receive failed: {}", e);
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+
+            loop {
+                match self.general.recv_from(&mut buf) {
+                    Ok((len, _)) => {
+                        let received_at = now();
+
+                        self.handle(&buf[..len], received_at);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        warn!("Receive failed: {}", e);
+                        break;
+                    }
+                }
+            }
+
+            match self.conf.role {
+                Role::Master => {
+                    let instant = Instant::now();
+
+                    if instant >= next_announce {
+                        next_announce = instant + self.conf.announce_interval;
+                        self.send_announce();
+                    }
+
+                    if instant >= next_sync {
+                        next_sync = instant + self.conf.sync_interval;
+                        self.send_sync();
+                    }
+                }
+                Role::Slave => {
+                    if let Some(master) = self.master.as_ref() {
+                        if Instant::now() >= master.expires {
+                            warn!("Lost master {:02x?}", &master.port_identity[..8]);
+
+                            self.master = None;
+                            self.pending = None;
+                            self.status.lock().master = None;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, message: &[u8], received_at: i64) {
+        let header = match Header::parse(message) {
+            Some(header) if header.domain == self.conf.domain => header,
+            _ => return,
+        };
+
+        // Our own messages, if the multicast loop could not be disabled
+        if header.source == self.identity {
+            return;
+        }
+
+        match (self.conf.role, header.message_type) {
+            (Role::Master, DELAY_REQ) => self.answer_delay_req(&header, received_at),
+            (Role::Slave, ANNOUNCE) => self.handle_announce(&header, message),
+            (Role::Slave, SYNC) => self.handle_sync(&header, message, received_at),
+            (Role::Slave, FOLLOW_UP) => self.handle_follow_up(&header, message),
+            (Role::Slave, DELAY_RESP) => self.handle_delay_resp(&header, message),
+            _ => (),
+        }
+    }
+
+    fn send_announce(&mut self) {
+        let mut message = [0_u8; HEADER_LEN + 30];
+
+        let sequence = self.next_sequence();
+        self.header(
+            &mut message,
+            ANNOUNCE,
+            sequence,
+            5,
+            self.conf.announce_interval,
+        );
+        message[7] |= FLAG_UTC_OFFSET_VALID;
+
+        let body = &mut message[HEADER_LEN..];
+        body[..TIMESTAMP_LEN].copy_from_slice(&self.timestamp(now()));
+        body[10..12].copy_from_slice(&self.conf.utc_offset.to_be_bytes());
+        body[13] = self.conf.priority1;
+        body[14] = self.conf.clock_class;
+        // Clock accuracy: unknown, and the variance: the maximum
+        body[15] = 0xfe;
+        body[16..18].copy_from_slice(&0xffff_u16.to_be_bytes());
+        body[18] = self.conf.priority2;
+        body[19..27].copy_from_slice(&self.identity[..8]);
+        // Steps removed: 0
+        body[29] = TIME_SOURCE_INTERNAL_OSCILLATOR;
+
+        self.send(&message, GENERAL_PORT);
+    }
+
+    fn send_sync(&mut self) {
+        let sequence = self.next_sequence();
+
+        let mut sync = [0_u8; HEADER_LEN + TIMESTAMP_LEN];
+        self.header(&mut sync, SYNC, sequence, 0, self.conf.sync_interval);
+        sync[6] |= FLAG_TWO_STEP;
+
+        if !self.send(&sync, EVENT_PORT) {
+            return;
+        }
+
+        // Two-step: the time the sync message went out follows it
+        let sent_at = now();
+
+        let mut follow_up = [0_u8; HEADER_LEN + TIMESTAMP_LEN];
+        self.header(
+            &mut follow_up,
+            FOLLOW_UP,
+            sequence,
+            2,
+            self.conf.sync_interval,
+        );
+        follow_up[HEADER_LEN..].copy_from_slice(&self.timestamp(sent_at));
+
+        self.send(&follow_up, GENERAL_PORT);
+    }
+
+    fn answer_delay_req(&mut self, request: &Header, received_at: i64) {
+        let mut response = [0_u8; HEADER_LEN + TIMESTAMP_LEN + PORT_IDENTITY_LEN];
+        self.header(
+            &mut response,
+            DELAY_RESP,
+            request.sequence,
+            3,
+            self.conf.sync_interval,
+        );
+
+        response[HEADER_LEN..HEADER_LEN + TIMESTAMP_LEN]
+            .copy_from_slice(&self.timestamp(received_at));
+        response[HEADER_LEN + TIMESTAMP_LEN..].copy_from_slice(&request.source);
+
+        if self.send(&response, GENERAL_PORT) {
+            self.status.lock().delay_requests += 1;
+        }
+    }
+
+    fn handle_announce(&mut self, header: &Header, message: &[u8]) {
+        if message.len() < HEADER_LEN + 30 {
+            return;
+        }
+
+        let body = &message[HEADER_LEN..];
+
+        // Priority 1, clock class, accuracy and variance, priority 2, and the grandmaster
+        // identity as the tie-breaker
+        let mut dataset = [0_u8; 14];
+        dataset[..5].copy_from_slice(&body[13..18]);
+        dataset[5] = body[18];
+        dataset[6..].copy_from_slice(&body[19..27]);
+
+        let utc_offset = (header.flags[1] & FLAG_UTC_OFFSET_VALID != 0)
+            .then(|| i16::from_be_bytes([body[10], body[11]]));
+
+        let timeout = interval(header.log_interval) * ANNOUNCE_RECEIPT_TIMEOUT;
+        let expires = Instant::now() + timeout;
+
+        let better = match self.master.as_ref() {
+            Some(master) if master.port_identity == header.source => true,
+            Some(master) => dataset.cmp(&master.dataset) == CmpOrdering::Less,
+            None => true,
+        };
+
+        if !better {
+            return;
+        }
+
+        if self
+            .master
+            .as_ref()
+            .map(|master| master.port_identity != header.source)
+            .unwrap_or(true)
+        {
+            info!("Following master {:02x?}", &header.source[..8]);
+
+            let mut identity = [0_u8; 8];
+            identity.copy_from_slice(&header.source[..8]);
+
+            self.status.lock().master = Some(identity);
+            self.pending = None;
+        }
+
+        self.master = Some(Master {
+            port_identity: header.source,
+            dataset,
+            utc_offset,
+            expires,
+        });
+    }
+
+    fn handle_sync(&mut self, header: &Header, message: &[u8], received_at: i64) {
+        if !self.is_master(header) {
+            return;
+        }
+
+        let t1 = if header.flags[0] & FLAG_TWO_STEP != 0 {
+            None
+        } else {
+            match self.parse_timestamp(message) {
+                None => return,
+                t1 => t1,
+            }
+        };
+
+        self.pending = Some(Pending {
+            sync_sequence: header.sequence,
+            t1,
+            // Without the residence time of the sync message in the bridges on the way
+            t2: received_at - header.correction,
+            delay_req_sequence: None,
+            t3: 0,
+        });
+
+        if t1.is_some() {
+            self.send_delay_req();
+        }
+    }
+
+    fn handle_follow_up(&mut self, header: &Header, message: &[u8]) {
+        if !self.is_master(header) {
+            return;
+        }
+
+        let t1 = match self.parse_timestamp(message) {
+            Some(t1) => t1 + header.correction,
+            None => return,
+        };
+
+        match self.pending.as_mut() {
+            Some(pending) if pending.sync_sequence == header.sequence && pending.t1.is_none() => {
+                pending.t1 = Some(t1);
+            }
+            _ => return,
+        }
+
+        self.send_delay_req();
+    }
+
+    fn send_delay_req(&mut self) {
+        let sequence = self.next_sequence();
+
+        let mut request = [0_u8; HEADER_LEN + TIMESTAMP_LEN];
+        self.header(&mut request, DELAY_REQ, sequence, 1, Duration::ZERO);
+        // The log message interval of the delay requests is not relevant
+        request[33] = 0x7f;
+
+        if !self.send(&request, EVENT_PORT) {
+            self.pending = None;
+            return;
+        }
+
+        let sent_at = now();
+
+        if let Some(pending) = self.pending.as_mut() {
+            pending.delay_req_sequence = Some(sequence);
+            pending.t3 = sent_at;
+        }
+    }
+
+    fn handle_delay_resp(&mut self, header: &Header, message: &[u8]) {
+        if !self.is_master(header)
+            || message.len() < HEADER_LEN + TIMESTAMP_LEN + PORT_IDENTITY_LEN
+            || message[HEADER_LEN + TIMESTAMP_LEN..][..PORT_IDENTITY_LEN] != self.identity
+        {
+            return;
+        }
+
+        let t4 = match self.parse_timestamp(message) {
+            Some(t4) => t4 - header.correction,
+            None => return,
+        };
+
+        let (t1, t2, t3) = match self.pending.take() {
+            Some(Pending {
+                t1: Some(t1),
+                t2,
+                delay_req_sequence: Some(sequence),
+                t3,
+                ..
+            }) if sequence == header.sequence => (t1, t2, t3),
+            pending => {
+                self.pending = pending;
+                return;
+            }
+        };
+
+        let offset = ((t1 - t2) + (t4 - t3)) / 2;
+        let path_delay = ((t2 - t1) + (t4 - t3)) / 2;
+
+        if path_delay < 0 {
+            debug!("Ignoring a measurement with a negative path delay");
+            return;
+        }
+
+        debug!("Offset {} ns, path delay {} ns", offset, path_delay);
+
+        {
+            let mut status = self.status.lock();
+
+            status.offset = Some(offset);
+            status.path_delay = Some(Duration::from_nanos(path_delay as u64));
+            status.measurements += 1;
+        }
+
+        if self.conf.adjust_time {
+            if let Err(e) = self.correct(offset) {
+                warn!("Failed to correct the system time: {}", e);
+            }
+        }
+    }
+
+    /// Corrects the system time by `offset` nanoseconds
+    fn correct(&self, offset: i64) -> Result<(), EspError> {
+        let offset = offset / 1000;
+
+        if offset.unsigned_abs() > self.conf.step_threshold.as_micros() as u64 {
+            info!("Stepping the system time by {} ms", offset / 1000);
+
+            let time = EspSystemTime.now().as_micros() as i64 + offset;
+
+            let tv = timeval {
+                tv_sec: (time / 1_000_000) as _,
+                tv_usec: (time % 1_000_000) as _,
+            };
+
+            if unsafe { settimeofday(&tv, core::ptr::null()) } != 0 {
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+        } else {
+            let delta = timeval {
+                tv_sec: (offset / 1_000_000) as _,
+                tv_usec: (offset % 1_000_000) as _,
+            };
+
+            if unsafe { adjtime(&delta, core::ptr::null_mut()) } != 0 {
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_master(&self, header: &Header) -> bool {
+        self.master
+            .as_ref()
+            .map(|master| master.port_identity == header.source)
+            .unwrap_or(false)
+    }
+
+    fn next_sequence(&mut self) -> u16 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sequence
+    }
+
+    fn header(
+        &self,
+        message: &mut [u8],
+        message_type: u8,
+        sequence: u16,
+        control: u8,
+        interval: Duration,
+    ) {
+        message[0] = message_type;
+        message[1] = VERSION;
+        message[2..4].copy_from_slice(&(message.len() as u16).to_be_bytes());
+        message[4] = self.conf.domain;
+        message[7] = FLAG_PTP_TIMESCALE;
+        message[20..30].copy_from_slice(&self.identity);
+        message[30..32].copy_from_slice(&sequence.to_be_bytes());
+        message[32] = control;
+        message[33] = log_interval(interval) as u8;
+    }
+
+    fn send(&self, message: &[u8], port: u16) -> bool {
+        let socket = if port == EVENT_PORT {
+            &self.event
+        } else {
+            &self.general
+        };
+
+        match socket.send_to(message, SocketAddrV4::new(PRIMARY_GROUP, port)) {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Send failed: {}", e);
+                false
+            }
+        }
+    }
+
+    /// The PTP (TAI) timestamp of `time`, in nanoseconds of the system time
+    fn timestamp(&self, time: i64) -> [u8; TIMESTAMP_LEN] {
+        let time = time + self.conf.utc_offset as i64 * 1_000_000_000;
+
+        let secs = (time / 1_000_000_000) as u64;
+        let nanos = (time % 1_000_000_000) as u32;
+
+        let mut timestamp = [0_u8; TIMESTAMP_LEN];
+        timestamp[..6].copy_from_slice(&secs.to_be_bytes()[2..]);
+        timestamp[6..].copy_from_slice(&nanos.to_be_bytes());
+
+        timestamp
+    }
+
+    /// The system time, in nanoseconds, of the PTP timestamp following the header of `message`
+    fn parse_timestamp(&self, message: &[u8]) -> Option<i64> {
+        let timestamp = message.get(HEADER_LEN..HEADER_LEN + TIMESTAMP_LEN)?;
+
+        let mut secs = [0_u8; 8];
+        secs[2..].copy_from_slice(&timestamp[..6]);
+
+        let secs = u64::from_be_bytes(secs) as i64;
+        let nanos = u32::from_be_bytes([timestamp[6], timestamp[7], timestamp[8], timestamp[9]]);
+
+        let utc_offset = self
+            .master
+            .as_ref()
+            .and_then(|master| master.utc_offset)
+            .unwrap_or(self.conf.utc_offset);
+
+        Some((secs - utc_offset as i64) * 1_000_000_000 + nanos as i64)
+    }
+}
+
+/// The common header of the PTP messages
+struct Header {
+    message_type: u8,
+    domain: u8,
+    flags: [u8; 2],
+    /// In nanoseconds
+    correction: i64,
+    source: [u8; PORT_IDENTITY_LEN],
+    sequence: u16,
+    log_interval: i8,
+}
+
+impl Header {
+    fn parse(message: &[u8]) -> Option<Self> {
+        if message.len() < HEADER_LEN || message[1] & 0x0f != VERSION {
+            return None;
+        }
+
+        let mut correction = [0_u8; 8];
+        correction.copy_from_slice(&message[8..16]);
+
+        let mut source = [0_u8; PORT_IDENTITY_LEN];
+        source.copy_from_slice(&message[20..30]);
+
+        Some(Self {
+            message_type: message[0] & 0x0f,
+            domain: message[4],
+            flags: [message[6], message[7]],
+            // Scaled nanoseconds: nanoseconds multiplied by 2^16
+            correction: i64::from_be_bytes(correction) >> 16,
+            source,
+            sequence: u16::from_be_bytes([message[30], message[31]]),
+            log_interval: message[33] as i8,
+        })
+    }
+}
+
+/// The system time, in nanoseconds since the UNIX epoch
+fn now() -> i64 {
+    EspSystemTime.now().as_nanos() as i64
+}
+
+/// The EUI-64 of the default MAC address
+fn clock_identity() -> [u8; 8] {
+    let mut mac = [0_u8; 6];
+    unsafe { esp_efuse_mac_get_default(mac.as_mut_ptr()) };
+
+    [mac[0], mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5]]
+}
+
+/// The clock identity, and the port number: 1, as for all the ordinary clocks
+fn port_identity(clock_identity: &[u8; 8]) -> [u8; PORT_IDENTITY_LEN] {
+    let mut identity = [0_u8; PORT_IDENTITY_LEN];
+    identity[..8].copy_from_slice(clock_identity);
+    identity[9] = 1;
+
+    identity
+}
+
+/// The interval as the log2 of its seconds, as in the headers
+fn log_interval(interval: Duration) -> i8 {
+    if interval == Duration::ZERO {
+        return 0;
+    }
+
+    interval.as_secs_f32().log2().round().clamp(-128.0, 127.0) as i8
+}
+
+fn interval(log_interval: i8) -> Duration {
+    Duration::from_secs_f32(2_f32.powi(log_interval.clamp(-7, 7) as i32))
+}
@@ -0,0 +1,235 @@
+//! Random numbers, from the hardware RNG
+//!
+//! The numbers of the hardware RNG are truly random only while it is fed with entropy, i.e.
+//! while the RF subsystem is enabled (the Wi-Fi or the Bluetooth driver is started), or while
+//! the ADC entropy source of the bootloader is enabled with `EspAdcEntropy`. Otherwise, they
+//! are merely pseudo-random, and must not be used for keys, nonces and the like.
+//!
+//! `EspRng` is always available, for the non-cryptographic uses, while `EspCryptoRng` checks for
+//! an entropy source, on creation and on every use:
+//!
+//! ```ignore
+//! let wifi = EspWifi::new(peripherals.modem, sysloop, Some(nvs))?;
+//! wifi.start()?;
+//!
+//! let mut rng = EspCryptoRng::new()?;
+//!
+//! let mut key = [0_u8; 32];
+//! rng.try_fill_bytes(&mut key)?;
+//! ```
+//!
+//! With the `rand_core` cargo feature enabled, both implement `rand_core::RngCore`, and
+//! `EspCryptoRng` also implements `rand_core::CryptoRng`.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use esp_idf_sys::*;
+
+/// The number of live `EspAdcEntropy` instances
+static ADC_ENTROPY: AtomicUsize = AtomicUsize::new(0);
+
+/// Fills `buf` with random bytes, whether there is an entropy source or not
+pub fn fill(buf: &mut [u8]) {
+    unsafe { esp_fill_random(buf.as_mut_ptr() as *mut _, buf.len() as _) };
+}
+
+/// A random `u32`, whether there is an entropy source or not
+pub fn next_u32() -> u32 {
+    unsafe { esp_random() }
+}
+
+/// A uniformly distributed random `u32` below `bound`, whether there is an entropy source or not
+///
+/// The numbers at the top of the `u32` range that would make the low results more likely are
+/// rejected and drawn again, instead of being folded with a plain `%`.
+pub fn next_u32_below(bound: u32) -> u32 {
+    assert!(bound > 0, "The bound must not be zero");
+
+    // The largest multiple of `bound` that fits, minus one
+    let zone = u32::MAX - (u32::MAX - bound + 1) % bound;
+
+    loop {
+        let value = next_u32();
+
+        if value <= zone {
+            return value % bound;
+        }
+    }
+}
+
+/// Whether the Wi-Fi or the Bluetooth driver keeps the RF subsystem enabled
+pub fn is_rf_enabled() -> bool {
+    #[cfg(esp_idf_comp_esp_wifi_enabled)]
+    {
+        let mut mode: wifi_mode_t = wifi_mode_t_WIFI_MODE_NULL;
+
+        if unsafe { esp_wifi_get_mode(&mut mode) } == ESP_OK && mode != wifi_mode_t_WIFI_MODE_NULL {
+            return true;
+        }
+    }
+
+    #[cfg(esp_idf_bt_enabled)]
+    {
+        if unsafe { esp_bt_controller_get_status() }
+            == esp_bt_controller_status_t_ESP_BT_CONTROLLER_STATUS_ENABLED
+        {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Whether the hardware RNG is currently fed with entropy, i.e. its numbers are truly random
+pub fn is_entropy_available() -> bool {
+    ADC_ENTROPY.load(Ordering::SeqCst) > 0 || is_rf_enabled()
+}
+
+/// Feeds the hardware RNG with the noise of the SAR ADC, while the RF subsystem is not enabled,
+/// e.g. to generate keys before the Wi-Fi driver is started
+///
+/// The ADC, the I2S and the Wi-Fi and Bluetooth drivers cannot be used while an instance is
+/// live.
+#[cfg(esp_idf_comp_bootloader_support_enabled)]
+pub struct EspAdcEntropy(());
+
+#[cfg(esp_idf_comp_bootloader_support_enabled)]
+impl EspAdcEntropy {
+    /// Fails with `ESP_ERR_INVALID_STATE` if the RF subsystem is enabled, as it uses the ADC
+    /// already
+    pub fn new() -> Result<Self, EspError> {
+        if is_rf_enabled() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        if ADC_ENTROPY.fetch_add(1, Ordering::SeqCst) == 0 {
+            unsafe { bootloader_random_enable() };
+        }
+
+        Ok(Self(()))
+    }
+}
+
+#[cfg(esp_idf_comp_bootloader_support_enabled)]
+impl Drop for EspAdcEntropy {
+    fn drop(&mut self) {
+        if ADC_ENTROPY.fetch_sub(1, Ordering::SeqCst) == 1 {
+            unsafe { bootloader_random_disable() };
+        }
+    }
+}
+
+/// The hardware RNG, with or without an entropy source
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EspRng;
+
+impl EspRng {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        next_u32()
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        (next_u32() as u64) << 32 | next_u32() as u64
+    }
+
+    pub fn fill_bytes(&mut self, dest: &mut [u8]) {
+        fill(dest)
+    }
+}
+
+/// The hardware RNG, while fed with entropy
+#[derive(Clone, Debug)]
+pub struct EspCryptoRng(());
+
+impl EspCryptoRng {
+    /// Fails with `ESP_ERR_INVALID_STATE` if there is no entropy source
+    pub fn new() -> Result<Self, EspError> {
+        Self::check()?;
+
+        Ok(Self(()))
+    }
+
+    /// Fails with `ESP_ERR_INVALID_STATE`, leaving `dest` untouched, if there is no entropy
+    /// source anymore
+    pub fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), EspError> {
+        Self::check()?;
+
+        fill(dest);
+
+        Ok(())
+    }
+
+    fn check() -> Result<(), EspError> {
+        if is_entropy_available() {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+        }
+    }
+}
+
+#[cfg(feature = "rand_core")]
+mod rand_core_impl {
+    use core::num::NonZeroU32;
+
+    use rand_core::{CryptoRng, Error, RngCore};
+
+    use super::{EspCryptoRng, EspRng};
+
+    impl RngCore for EspRng {
+        fn next_u32(&mut self) -> u32 {
+            EspRng::next_u32(self)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            EspRng::next_u64(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            EspRng::fill_bytes(self, dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+            EspRng::fill_bytes(self, dest);
+
+            Ok(())
+        }
+    }
+
+    impl RngCore for EspCryptoRng {
+        /// Panics if there is no entropy source anymore
+        fn next_u32(&mut self) -> u32 {
+            let mut buf = [0_u8; 4];
+            self.fill_bytes(&mut buf);
+
+            u32::from_le_bytes(buf)
+        }
+
+        /// Panics if there is no entropy source anymore
+        fn next_u64(&mut self) -> u64 {
+            let mut buf = [0_u8; 8];
+            self.fill_bytes(&mut buf);
+
+            u64::from_le_bytes(buf)
+        }
+
+        /// Panics if there is no entropy source anymore
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            if let Err(e) = RngCore::try_fill_bytes(self, dest) {
+                panic!("Hardware RNG without an entropy source: {}", e);
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+            EspCryptoRng::try_fill_bytes(self, dest).map_err(|e| {
+                // The codes of the ESP errors are positive, and below `Error::CUSTOM_START`
+                Error::from(NonZeroU32::new(Error::CUSTOM_START + e.code() as u32).unwrap())
+            })
+        }
+    }
+
+    impl CryptoRng for EspCryptoRng {}
+}
@@ -0,0 +1,957 @@
+//! RTSP server
+//!
+//! A minimal RTSP server, streaming the frames of a user-provided source over RTP, so that
+//! NVRs, VLC or ffmpeg can consume a camera stream with the standard protocols.
+//!
+//! The frames are passed through as they are: JPEG frames (as produced by the ESP32 camera
+//! driver) are sent as RTP/JPEG (RFC 2435), and H.264 access units in Annex B format are sent
+//! as RTP/H.264 (RFC 6184), fragmented as needed. RTP is sent over UDP, or interleaved in the
+//! RTSP connection for clients asking for TCP transport.
+//!
+//! The source captures a frame and passes it to the closure it is given, returning `false` if
+//! no frame is available:
+//!
+//! ```ignore
+//! let server = EspRtspServer::new(
+//!     &Configuration {
+//!         codec: Codec::Mjpeg,
+//!         fps: 15,
+//!         ..Default::default()
+//!     },
+//!     |frame: &mut dyn FnMut(&[u8])| {
+//!         let fb = unsafe { esp_camera_fb_get() };
+//!         if fb.is_null() {
+//!             return false;
+//!         }
+//!
+//!         frame(unsafe { core::slice::from_raw_parts((*fb).buf, (*fb).len) });
+//!
+//!         unsafe { esp_camera_fb_return(fb) };
+//!
+//!         true
+//!     },
+//! )?;
+//!
+//! // vlc rtsp://<device>:554/stream
+//! ```
+//!
+//! The frames are captured only while at least one client is playing; all clients share the
+//! same stream. RTCP is not implemented.
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::string::String;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+const RTP_HEADER_LEN: usize = 12;
+const RTP_CLOCK_RATE: u64 = 90_000;
+
+const PAYLOAD_TYPE_JPEG: u8 = 26;
+const PAYLOAD_TYPE_H264: u8 = 96;
+
+const MAX_REQUEST_LEN: usize = 4096;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Codec {
+    /// Baseline JPEG frames, 4:2:2 or 4:2:0
+    Mjpeg,
+    /// H.264 access units, in Annex B format (with start codes)
+    H264,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub port: u16,
+    /// The path of the stream, e.g. `/stream`
+    pub path: String,
+    pub codec: Codec,
+    pub fps: u32,
+    /// The maximum size of the RTP packets
+    pub mtu: usize,
+    pub max_clients: usize,
+    /// Sessions are closed when their client stays silent for this long; clients keep their
+    /// session alive with `OPTIONS` or `GET_PARAMETER` requests
+    pub session_timeout: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            port: 554,
+            path: "/stream".into(),
+            codec: Codec::Mjpeg,
+            fps: 10,
+            mtu: 1400,
+            max_clients: 2,
+            session_timeout: Duration::from_secs(60),
+            stack_size: 8192,
+        }
+    }
+}
+
+pub struct EspRtspServer {
+    clients: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspRtspServer {
+    pub fn new<S>(conf: &Configuration, source: S) -> Result<Self, EspError>
+    where
+        S: FnMut(&mut dyn FnMut(&[u8])) -> bool + Send + 'static,
+    {
+        if conf.fps == 0 || conf.mtu < 256 || !conf.path.starts_with('/') {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let bind = || -> io::Result<(TcpListener, UdpSocket)> {
+            let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, conf.port))?;
+            listener.set_nonblocking(true)?;
+
+            let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+
+            Ok((listener, socket))
+        };
+
+        let (listener, socket) = bind().map_err(|e| {
+            warn!("Failed to bind RTSP sockets: {}", e);
+
+            EspError::from_infallible::<ESP_FAIL>()
+        })?;
+
+        let clients = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let join_handle = {
+            let server = Server {
+                conf: conf.clone(),
+                listener,
+                socket,
+                clients: Vec::new(),
+                packetizer: Packetizer::new(conf),
+                started: Instant::now(),
+                active: clients.clone(),
+            };
+
+            let stop = stop.clone();
+
+            thread::Builder::new()
+                .name("rtsp".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || server.run(source, stop))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        info!(
+            "Started RTSP server on port {}, streaming {:?} on {}",
+            conf.port, conf.codec, conf.path
+        );
+
+        Ok(Self {
+            clients,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// The number of connected clients
+    pub fn clients(&self) -> usize {
+        self.clients.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for EspRtspServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Transport {
+    Udp(SocketAddr),
+    /// The RTP channel of the interleaved transport
+    Interleaved(u8),
+}
+
+struct Session {
+    id: u32,
+    transport: Transport,
+    playing: bool,
+}
+
+struct Client {
+    stream: TcpStream,
+    peer: SocketAddr,
+    buf: Vec<u8>,
+    session: Option<Session>,
+    last_activity: Instant,
+    closed: bool,
+}
+
+struct Request<'a> {
+    method: &'a str,
+    url: &'a str,
+    cseq: &'a str,
+    transport: Option<&'a str>,
+}
+
+impl<'a> Request<'a> {
+    fn parse(head: &'a str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next()?.split(' ');
+
+        let method = request_line.next()?;
+        let url = request_line.next()?;
+
+        let mut cseq = "0";
+        let mut transport = None;
+
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                let value = value.trim();
+
+                if name.eq_ignore_ascii_case("CSeq") {
+                    cseq = value;
+                } else if name.eq_ignore_ascii_case("Transport") {
+                    transport = Some(value);
+                }
+            }
+        }
+
+        Some(Self {
+            method,
+            url,
+            cseq,
+            transport,
+        })
+    }
+
+    /// The path of the URL, which is either absolute (`rtsp://host:port/path`) or just a path
+    fn path(&self) -> &'a str {
+        let url = self
+            .url
+            .strip_prefix("rtsp://")
+            .or_else(|| self.url.strip_prefix("rtsps://"));
+
+        match url {
+            Some(url) => url.find('/').map(|index| &url[index..]).unwrap_or("/"),
+            None => self.url,
+        }
+    }
+}
+
+struct Server {
+    conf: Configuration,
+    listener: TcpListener,
+    socket: UdpSocket,
+    clients: Vec<Client>,
+    packetizer: Packetizer,
+    started: Instant,
+    active: Arc<AtomicUsize>,
+}
+
+impl Server {
+    fn run<S>(mut self, mut source: S, stop: Arc<AtomicBool>)
+    where
+        S: FnMut(&mut dyn FnMut(&[u8])) -> bool,
+    {
+        let interval = Duration::from_secs(1) / self.conf.fps;
+        let mut last_frame: Option<Instant> = None;
+
+        while !stop.load(Ordering::SeqCst) {
+            self.accept();
+
+            for index in 0..self.clients.len() {
+                self.poll(index);
+            }
+
+            let timeout = self.conf.session_timeout;
+
+            self.clients.retain(|client| {
+                let keep = !client.closed && client.last_activity.elapsed() < timeout;
+
+                if !keep {
+                    info!("RTSP client {} disconnected", client.peer);
+                }
+
+                keep
+            });
+
+            self.active.store(self.clients.len(), Ordering::SeqCst);
+
+            let playing = self.clients.iter().any(|client| {
+                client
+                    .session
+                    .as_ref()
+                    .map(|session| session.playing)
+                    .unwrap_or(false)
+            });
+
+            let due = last_frame
+                .map(|last_frame| last_frame.elapsed() >= interval)
+                .unwrap_or(true);
+
+            if playing && due {
+                last_frame = Some(Instant::now());
+
+                let timestamp =
+                    (self.started.elapsed().as_micros() as u64 * RTP_CLOCK_RATE / 1_000_000) as u32;
+
+                let mut packets = Vec::new();
+                let packetizer = &mut self.packetizer;
+
+                if source(&mut |frame| packets = packetizer.packetize(frame, timestamp)) {
+                    self.send(&packets);
+                }
+            } else {
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer)) => {
+                    if self.clients.len() >= self.conf.max_clients {
+                        warn!("Refusing RTSP client {}: too many clients", peer);
+                        continue;
+                    }
+
+                    if let Err(e) = stream
+                        .set_nonblocking(true)
+                        .and_then(|_| stream.set_nodelay(true))
+                    {
+                        warn!("Failed to set up RTSP client {}: {}", peer, e);
+                        continue;
+                    }
+
+                    info!("RTSP client {} connected", peer);
+
+                    self.clients.push(Client {
+                        stream,
+                        peer,
+                        buf: Vec::new(),
+                        session: None,
+                        last_activity: Instant::now(),
+                        closed: false,
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to accept RTSP client: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn poll(&mut self, index: usize) {
+        let mut chunk = [0_u8; 512];
+
+        loop {
+            let client = &mut self.clients[index];
+
+            match client.stream.read(&mut chunk) {
+                Ok(0) => {
+                    client.closed = true;
+                    return;
+                }
+                Ok(len) => {
+                    client.buf.extend_from_slice(&chunk[..len]);
+                    client.last_activity = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(_) => {
+                    client.closed = true;
+                    return;
+                }
+            }
+        }
+
+        loop {
+            let client = &mut self.clients[index];
+
+            if client.buf.first() == Some(&b'$') {
+                // Interleaved data (i.e. RTCP reports) from the client, which is ignored
+                if client.buf.len() < 4 {
+                    break;
+                }
+
+                let len = 4 + u16::from_be_bytes([client.buf[2], client.buf[3]]) as usize;
+                if client.buf.len() < len {
+                    break;
+                }
+
+                client.buf.drain(..len);
+                continue;
+            }
+
+            let end = match client
+                .buf
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+            {
+                Some(end) => end + 4,
+                None => {
+                    if client.buf.len() > MAX_REQUEST_LEN {
+                        warn!("RTSP request from {} too long", client.peer);
+                        client.closed = true;
+                    }
+
+                    break;
+                }
+            };
+
+            let head: Vec<u8> = client.buf.drain(..end).collect();
+
+            let response = core::str::from_utf8(&head)
+                .ok()
+                .and_then(Request::parse)
+                .map(|request| self.handle(index, &request))
+                .unwrap_or_else(|| "RTSP/1.0 400 Bad Request\r\n\r\n".into());
+
+            let client = &mut self.clients[index];
+
+            if write_all(&mut client.stream, response.as_bytes()).is_err() {
+                client.closed = true;
+                break;
+            }
+        }
+    }
+
+    fn handle(&mut self, index: usize, request: &Request) -> String {
+        debug!("RTSP {} {}", request.method, request.url);
+
+        let cseq = request.cseq;
+
+        if !matches!(
+            request.method,
+            "OPTIONS" | "GET_PARAMETER" | "SET_PARAMETER"
+        ) && !request.path().starts_with(self.conf.path.as_str())
+        {
+            return response(cseq, "404 Not Found", &[], "");
+        }
+
+        let server_port = self
+            .socket
+            .local_addr()
+            .map(|addr| addr.port())
+            .unwrap_or(0);
+
+        let client = &mut self.clients[index];
+
+        match request.method {
+            "OPTIONS" => response(
+                cseq,
+                "200 OK",
+                &[(
+                    "Public",
+                    "OPTIONS, DESCRIBE, SETUP, PLAY, PAUSE, TEARDOWN, GET_PARAMETER".into(),
+                )],
+                "",
+            ),
+            "DESCRIBE" => {
+                let local = client
+                    .stream
+                    .local_addr()
+                    .map(|addr| addr.ip())
+                    .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+                let sdp = self.packetizer.sdp(local);
+
+                response(
+                    cseq,
+                    "200 OK",
+                    &[
+                        ("Content-Type", "application/sdp".into()),
+                        (
+                            "Content-Base",
+                            format!("{}/", request.url.trim_end_matches('/')),
+                        ),
+                    ],
+                    &sdp,
+                )
+            }
+            "SETUP" => {
+                let transport = match request
+                    .transport
+                    .and_then(|transport| parse_transport(transport, client.peer.ip()))
+                {
+                    Some(transport) => transport,
+                    None => return response(cseq, "461 Unsupported Transport", &[], ""),
+                };
+
+                let transport_header = match transport {
+                    Transport::Udp(addr) => format!(
+                        "RTP/AVP;unicast;client_port={}-{};server_port={}-{}",
+                        addr.port(),
+                        addr.port() + 1,
+                        server_port,
+                        server_port + 1
+                    ),
+                    Transport::Interleaved(channel) => format!(
+                        "RTP/AVP/TCP;unicast;interleaved={}-{}",
+                        channel,
+                        channel + 1
+                    ),
+                };
+
+                let id = client
+                    .session
+                    .as_ref()
+                    .map(|session| session.id)
+                    .unwrap_or_else(|| unsafe { esp_random() });
+
+                client.session = Some(Session {
+                    id,
+                    transport,
+                    playing: false,
+                });
+
+                response(
+                    cseq,
+                    "200 OK",
+                    &[
+                        ("Transport", transport_header),
+                        (
+                            "Session",
+                            format!("{:08X};timeout={}", id, self.conf.session_timeout.as_secs()),
+                        ),
+                    ],
+                    "",
+                )
+            }
+            "PLAY" | "PAUSE" => match &mut client.session {
+                Some(session) => {
+                    session.playing = request.method == "PLAY";
+
+                    let session = format!("{:08X}", session.id);
+
+                    if request.method == "PLAY" {
+                        response(
+                            cseq,
+                            "200 OK",
+                            &[("Session", session), ("Range", "npt=0.000-".into())],
+                            "",
+                        )
+                    } else {
+                        response(cseq, "200 OK", &[("Session", session)], "")
+                    }
+                }
+                None => response(cseq, "454 Session Not Found", &[], ""),
+            },
+            "TEARDOWN" => {
+                client.session = None;
+
+                response(cseq, "200 OK", &[], "")
+            }
+            "GET_PARAMETER" | "SET_PARAMETER" => response(cseq, "200 OK", &[], ""),
+            _ => response(cseq, "501 Not Implemented", &[], ""),
+        }
+    }
+
+    fn send(&mut self, packets: &[Vec<u8>]) {
+        for client in &mut self.clients {
+            let transport = match &client.session {
+                Some(session) if session.playing => session.transport,
+                _ => continue,
+            };
+
+            for packet in packets {
+                let result = match transport {
+                    Transport::Udp(addr) => self.socket.send_to(packet, addr).map(|_| ()),
+                    Transport::Interleaved(channel) => {
+                        let len = (packet.len() as u16).to_be_bytes();
+
+                        write_all(&mut client.stream, &[b'$', channel, len[0], len[1]])
+                            .and_then(|_| write_all(&mut client.stream, packet))
+                    }
+                };
+
+                if let Err(e) = result {
+                    match transport {
+                        // Probably a transient lack of buffers: skip the rest of the frame
+                        Transport::Udp(_) => debug!("Failed to send RTP packet: {}", e),
+                        Transport::Interleaved(_) => client.closed = true,
+                    }
+
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn response(cseq: &str, status: &str, headers: &[(&str, String)], body: &str) -> String {
+    let mut response = format!("RTSP/1.0 {}\r\nCSeq: {}\r\n", status, cseq);
+
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    response.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    response.push_str(body);
+
+    response
+}
+
+fn parse_transport(transport: &str, peer: IpAddr) -> Option<Transport> {
+    // Clients may offer several transports, in order of preference
+    transport.split(',').find_map(|transport| {
+        let mut params = transport.trim().split(';');
+
+        let profile = params.next()?;
+        let params: Vec<_> = params.filter_map(|param| param.split_once('=')).collect();
+
+        let first_port = |name: &str| {
+            params
+                .iter()
+                .find(|(param, _)| *param == name)
+                .and_then(|(_, value)| value.split('-').next())
+                .and_then(|value| value.parse::<u16>().ok())
+        };
+
+        match profile {
+            "RTP/AVP" | "RTP/AVP/UDP" => {
+                first_port("client_port").map(|port| Transport::Udp(SocketAddr::new(peer, port)))
+            }
+            "RTP/AVP/TCP" => Some(Transport::Interleaved(
+                first_port("interleaved").unwrap_or(0) as u8,
+            )),
+            _ => None,
+        }
+    })
+}
+
+/// Writes all of `data` to the non-blocking `stream`, waiting for a while for room in its
+/// send buffer
+fn write_all(stream: &mut TcpStream, mut data: &[u8]) -> io::Result<()> {
+    let started = Instant::now();
+
+    while !data.is_empty() {
+        match stream.write(data) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(len) => data = &data[len..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if started.elapsed() > Duration::from_secs(2) {
+                    return Err(e);
+                }
+
+                thread::sleep(Duration::from_millis(2));
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+struct Packetizer {
+    codec: Codec,
+    mtu: usize,
+    seq: u16,
+    ssrc: u32,
+}
+
+impl Packetizer {
+    fn new(conf: &Configuration) -> Self {
+        Self {
+            codec: conf.codec,
+            mtu: conf.mtu,
+            seq: unsafe { esp_random() } as u16,
+            ssrc: unsafe { esp_random() },
+        }
+    }
+
+    fn sdp(&self, local: IpAddr) -> String {
+        let media = match self.codec {
+            Codec::Mjpeg => format!(
+                "m=video 0 RTP/AVP {pt}\r\na=rtpmap:{pt} JPEG/90000\r\n",
+                pt = PAYLOAD_TYPE_JPEG
+            ),
+            Codec::H264 => format!(
+                "m=video 0 RTP/AVP {pt}\r\na=rtpmap:{pt} H264/90000\r\na=fmtp:{pt} packetization-mode=1\r\n",
+                pt = PAYLOAD_TYPE_H264
+            ),
+        };
+
+        format!(
+            "v=0\r\no=- {ssrc} 1 IN IP4 {ip}\r\ns=ESP-IDF\r\nc=IN IP4 0.0.0.0\r\nt=0 0\r\n{media}a=control:track1\r\n",
+            ssrc = self.ssrc,
+            ip = local,
+            media = media
+        )
+    }
+
+    fn packetize(&mut self, frame: &[u8], timestamp: u32) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+
+        match self.codec {
+            Codec::Mjpeg => match parse_jpeg(frame) {
+                Some(jpeg) => self.packetize_jpeg(&jpeg, timestamp, &mut packets),
+                None => warn!("Dropping frame: not a supported JPEG image"),
+            },
+            Codec::H264 => self.packetize_h264(frame, timestamp, &mut packets),
+        }
+
+        packets
+    }
+
+    fn packetize_jpeg(&mut self, jpeg: &Jpeg, timestamp: u32, packets: &mut Vec<Vec<u8>>) {
+        let mut offset = 0;
+
+        loop {
+            let mut payload = Vec::with_capacity(self.mtu - RTP_HEADER_LEN);
+
+            // Main JPEG header, with Q = 255: the quantization tables are sent in-band
+            payload.push(0);
+            payload.extend_from_slice(&(offset as u32).to_be_bytes()[1..]);
+            payload.push(jpeg.kind + if jpeg.restart_interval > 0 { 64 } else { 0 });
+            payload.push(255);
+            payload.push((jpeg.width / 8) as u8);
+            payload.push((jpeg.height / 8) as u8);
+
+            if jpeg.restart_interval > 0 {
+                payload.extend_from_slice(&jpeg.restart_interval.to_be_bytes());
+                payload.extend_from_slice(&0xffff_u16.to_be_bytes());
+            }
+
+            if offset == 0 {
+                payload.extend_from_slice(&[0, 0]);
+                payload.extend_from_slice(&(jpeg.qtables.len() as u16).to_be_bytes());
+                payload.extend_from_slice(&jpeg.qtables);
+            }
+
+            let room = (self.mtu - RTP_HEADER_LEN)
+                .saturating_sub(payload.len())
+                .max(1);
+            let end = (offset + room).min(jpeg.scan.len());
+
+            payload.extend_from_slice(&jpeg.scan[offset..end]);
+
+            offset = end;
+
+            packets.push(self.packet(
+                PAYLOAD_TYPE_JPEG,
+                offset >= jpeg.scan.len(),
+                timestamp,
+                &payload,
+            ));
+
+            if offset >= jpeg.scan.len() {
+                break;
+            }
+        }
+    }
+
+    fn packetize_h264(&mut self, frame: &[u8], timestamp: u32, packets: &mut Vec<Vec<u8>>) {
+        let nals: Vec<_> = split_annex_b(frame).collect();
+        let room = self.mtu - RTP_HEADER_LEN;
+
+        for (index, nal) in nals.iter().enumerate() {
+            let last_nal = index + 1 == nals.len();
+
+            if nal.len() <= room {
+                packets.push(self.packet(PAYLOAD_TYPE_H264, last_nal, timestamp, nal));
+                continue;
+            }
+
+            // FU-A fragmentation
+            let indicator = (nal[0] & 0xe0) | 28;
+            let kind = nal[0] & 0x1f;
+
+            let chunks: Vec<_> = nal[1..].chunks(room - 2).collect();
+
+            for (chunk_index, chunk) in chunks.iter().enumerate() {
+                let start = chunk_index == 0;
+                let end = chunk_index + 1 == chunks.len();
+
+                let mut payload = Vec::with_capacity(chunk.len() + 2);
+
+                payload.push(indicator);
+                payload.push(kind | if start { 0x80 } else { 0 } | if end { 0x40 } else { 0 });
+                payload.extend_from_slice(chunk);
+
+                packets.push(self.packet(PAYLOAD_TYPE_H264, last_nal && end, timestamp, &payload));
+            }
+        }
+    }
+
+    fn packet(
+        &mut self,
+        payload_type: u8,
+        marker: bool,
+        timestamp: u32,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(RTP_HEADER_LEN + payload.len());
+
+        packet.push(0x80);
+        packet.push(payload_type | if marker { 0x80 } else { 0 });
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+
+        self.seq = self.seq.wrapping_add(1);
+
+        packet
+    }
+}
+
+/// The NAL units of an Annex B byte stream, without their start codes
+fn split_annex_b(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+
+    let mut index = 0;
+    while index + 3 <= data.len() {
+        if data[index..index + 3] == [0, 0, 1] {
+            starts.push(index + 3);
+            index += 3;
+        } else {
+            index += 1;
+        }
+    }
+
+    let mut nals = Vec::with_capacity(starts.len());
+
+    for (position, start) in starts.iter().enumerate() {
+        let mut end = starts
+            .get(position + 1)
+            .map(|next| next - 3)
+            .unwrap_or(data.len());
+
+        // The zero of a 4 bytes start code, or trailing zeros
+        while end > *start && data[end - 1] == 0 {
+            end -= 1;
+        }
+
+        if end > *start {
+            nals.push(&data[*start..end]);
+        }
+    }
+
+    nals.into_iter()
+}
+
+struct Jpeg<'a> {
+    /// The RFC 2435 type: 0 for 4:2:2, 1 for 4:2:0
+    kind: u8,
+    width: u16,
+    height: u16,
+    restart_interval: u16,
+    qtables: Vec<u8>,
+    scan: &'a [u8],
+}
+
+fn parse_jpeg(data: &[u8]) -> Option<Jpeg> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    let mut kind = None;
+    let mut width = 0;
+    let mut height = 0;
+    let mut restart_interval = 0;
+    let mut qtables = Vec::new();
+
+    let mut index = 2;
+
+    loop {
+        // Fill bytes
+        while data.get(index) == Some(&0xff) && data.get(index + 1) == Some(&0xff) {
+            index += 1;
+        }
+
+        if *data.get(index)? != 0xff {
+            return None;
+        }
+
+        let marker = *data.get(index + 1)?;
+        let len = u16::from_be_bytes([*data.get(index + 2)?, *data.get(index + 3)?]) as usize;
+        let segment = data.get(index + 4..index + 2 + len)?;
+
+        match marker {
+            // DQT
+            0xdb => {
+                let mut offset = 0;
+
+                while offset < segment.len() {
+                    // Only 8 bits tables are supported
+                    if segment[offset] >> 4 != 0 {
+                        return None;
+                    }
+
+                    qtables.extend_from_slice(segment.get(offset + 1..offset + 65)?);
+                    offset += 65;
+                }
+            }
+            // SOF0 and SOF1
+            0xc0 | 0xc1 => {
+                height = u16::from_be_bytes([*segment.get(1)?, *segment.get(2)?]);
+                width = u16::from_be_bytes([*segment.get(3)?, *segment.get(4)?]);
+
+                // The sampling factors of the luma component
+                kind = match *segment.get(7)? {
+                    0x21 => Some(0),
+                    0x22 => Some(1),
+                    _ => return None,
+                };
+            }
+            // DRI
+            0xdd => {
+                restart_interval = u16::from_be_bytes([*segment.first()?, *segment.get(1)?]);
+            }
+            // Progressive and other unsupported encodings
+            0xc2..=0xcf if marker != 0xc4 && marker != 0xc8 && marker != 0xcc => return None,
+            // SOS
+            0xda => {
+                let start = index + 2 + len;
+                let end = if data.ends_with(&[0xff, 0xd9]) {
+                    data.len() - 2
+                } else {
+                    data.len()
+                };
+
+                if width == 0 || height == 0 || width > 2040 || height > 2040 || start > end {
+                    return None;
+                }
+
+                return Some(Jpeg {
+                    kind: kind?,
+                    width,
+                    height,
+                    restart_interval,
+                    qtables,
+                    scan: &data[start..end],
+                });
+            }
+            _ => (),
+        }
+
+        index += 2 + len;
+    }
+}
@@ -0,0 +1,868 @@
+//! Edge rule engine
+//!
+//! `EspRuleEngine` evaluates a set of condition/action rules against the MQTT messages and the
+//! event loop events seen by the device, so that simple automations (switching on a fan above
+//! a temperature, reporting a lost connection...) keep working while the device is offline.
+//!
+//! The rules are defined at runtime as a JSON document, stored in NVS, and optionally replaced
+//! by publishing a new document on `Configuration::rules_topic`:
+//!
+//! ```json
+//! {
+//!     "rules": [
+//!         {
+//!             "name": "fan-on",
+//!             "when": { "topic": "sensors/+/temperature", "path": "celsius", "op": ">", "value": 30 },
+//!             "then": [
+//!                 { "publish": { "topic": "actuators/fan", "payload": "on", "retain": true } },
+//!                 { "gpio": { "pin": 5, "level": 1 } }
+//!             ],
+//!             "cooldown": 60
+//!         },
+//!         {
+//!             "name": "wifi-lost",
+//!             "when": { "event": "WIFI_EVENT", "id": 5 },
+//!             "then": [
+//!                 { "http": { "method": "POST", "url": "http://gateway.local/alerts", "body": "wifi lost" } }
+//!             ]
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! - `when` is either a message condition, matching the messages of an MQTT topic filter, or an
+//!   event condition, matching the events of an event base, and optionally of a single id
+//! - a message condition compares the payload (or the member at the dotted `path` of a JSON
+//!   payload) with `value`, using one of `==`, `!=`, `>`, `>=`, `<` and `<=`; the op `any`
+//!   matches all messages
+//! - comparison rules are edge triggered: they fire when the condition becomes true, and
+//!   again only once it has been false in between; other rules fire on every match
+//! - `cooldown` is the minimum number of seconds between two firings of the rule
+//! - `{{value}}` in the `payload` of a `publish` action, or in the `body` of an `http` action,
+//!   is replaced with the value which triggered the rule
+//!
+//! Note that a rule publishing on a topic it matches fires again on its own messages.
+//!
+//! ```ignore
+//! let engine = EspRuleEngine::new(
+//!     &Default::default(),
+//!     RuleSet::parse(include_str!("default_rules.json"))?,
+//!     &sysloop,
+//!     Some(EspDefaultNvs::new(nvs_partition, "rules", true)?),
+//!     Some(("mqtt://broker.local", &MqttClientConfiguration::default())),
+//! )?;
+//! ```
+use core::fmt::{self, Display};
+use core::ptr;
+use core::time::Duration;
+
+use std::string::{String, ToString};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+use embedded_svc::mqtt::client::{Details, Event, QoS};
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{EspSubscription, EspSystemEventLoop, System};
+use crate::http::client::{self, EspHttpConnection};
+use crate::mqtt::client::{EspMqttClient, EspMqttMessage, MqttClientConfiguration};
+use crate::nvs::EspDefaultNvs;
+use crate::private::cstr::*;
+use crate::private::json::Json;
+
+/// The maximum size of a rule set document stored in NVS
+const MAX_RULES_LEN: usize = 4096;
+
+/// A value compared by a message condition
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Value {
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+impl Value {
+    fn from_json(json: &Json) -> Option<Self> {
+        match json {
+            Json::Bool(value) => Some(Self::Bool(*value)),
+            Json::Number(value) => Some(Self::Number(*value)),
+            Json::String(value) => Some(Self::String(value.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bool(value) => write!(f, "{}", value),
+            Self::Number(value) => write!(f, "{}", value),
+            Self::String(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    /// Matches all messages, whatever their payload
+    Any,
+}
+
+impl Op {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "==" => Self::Eq,
+            "!=" => Self::Ne,
+            ">" => Self::Gt,
+            ">=" => Self::Ge,
+            "<" => Self::Lt,
+            "<=" => Self::Le,
+            "any" => Self::Any,
+            _ => return None,
+        })
+    }
+
+    fn eval(&self, actual: Option<&Value>, expected: Option<&Value>) -> bool {
+        match (self, actual, expected) {
+            (Self::Any, _, _) => true,
+            (Self::Eq, Some(actual), Some(expected)) => Self::equals(actual, expected),
+            (Self::Ne, Some(actual), Some(expected)) => !Self::equals(actual, expected),
+            (op, Some(actual), Some(Value::Number(expected))) => {
+                let actual = match actual {
+                    Value::Number(actual) => *actual,
+                    Value::String(actual) => match actual.trim().parse() {
+                        Ok(actual) => actual,
+                        Err(_) => return false,
+                    },
+                    Value::Bool(_) => return false,
+                };
+
+                match op {
+                    Self::Gt => actual > *expected,
+                    Self::Ge => actual >= *expected,
+                    Self::Lt => actual < *expected,
+                    Self::Le => actual <= *expected,
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Plain text payloads are compared with numbers by their value, e.g. `"21.0"` equals `21`
+    fn equals(actual: &Value, expected: &Value) -> bool {
+        match (actual, expected) {
+            (Value::String(actual), Value::Number(expected)) => {
+                actual.trim().parse::<f64>().ok() == Some(*expected)
+            }
+            (actual, expected) => actual == expected,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Condition {
+    /// The messages of the MQTT topic `filter`, which may contain `+` and `#` wildcards
+    Message {
+        filter: String,
+        /// The dotted path of the compared member of a JSON payload, or `None` to compare
+        /// the whole payload
+        path: Option<String>,
+        op: Op,
+        value: Option<Value>,
+    },
+    /// The events of the event loop `base` (e.g. `WIFI_EVENT`), of any id if `id` is `None`
+    Event { base: String, id: Option<i32> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Action {
+    Publish {
+        topic: String,
+        payload: String,
+        #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+        qos: QoS,
+        retain: bool,
+    },
+    /// Drives a GPIO, which is configured as an output the first time
+    Gpio { pin: i32, level: bool },
+    Http {
+        #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+        method: Method,
+        url: String,
+        body: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rule {
+    pub name: String,
+    pub condition: Condition,
+    pub actions: Vec<Action>,
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub cooldown: Option<Duration>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RuleError {
+    /// The index of the invalid rule, or `None` if the document itself is invalid
+    pub rule: Option<usize>,
+    pub message: &'static str,
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rule {
+            Some(rule) => write!(f, "{} in rule {}", self.message, rule),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RuleError {}
+
+/// A parsed rule set, along with its JSON source
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    source: String,
+}
+
+impl RuleSet {
+    pub fn parse(source: &str) -> Result<Self, RuleError> {
+        let document_error = |message| RuleError {
+            rule: None,
+            message,
+        };
+
+        let json = Json::parse(source).ok_or_else(|| document_error("Invalid JSON"))?;
+
+        let rules = json
+            .get("rules")
+            .and_then(Json::as_array)
+            .ok_or_else(|| document_error("Missing rules"))?;
+
+        let rules = rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| {
+                Self::parse_rule(rule).map_err(|message| RuleError {
+                    rule: Some(index),
+                    message,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            rules,
+            source: source.into(),
+        })
+    }
+
+    /// Loads the rule set stored under `key`, if any
+    ///
+    /// An invalid stored rule set is ignored, with a warning.
+    pub fn load(nvs: &EspDefaultNvs, key: &str) -> Result<Option<Self>, EspError> {
+        let mut buf = vec![0; MAX_RULES_LEN];
+
+        Ok(nvs.get_str(key, &mut buf)?.and_then(|source| {
+            match Self::parse(source.trim_end_matches('\0')) {
+                Ok(rules) => Some(rules),
+                Err(e) => {
+                    warn!("Ignoring the invalid stored rules: {}", e);
+                    None
+                }
+            }
+        }))
+    }
+
+    pub fn store(&self, nvs: &mut EspDefaultNvs, key: &str) -> Result<(), EspError> {
+        if self.source.len() >= MAX_RULES_LEN {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        nvs.set_str(key, &self.source)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    fn parse_rule(rule: &Json) -> Result<Rule, &'static str> {
+        let name = rule
+            .get("name")
+            .and_then(Json::as_str)
+            .ok_or("Missing name")?
+            .into();
+
+        let condition = Self::parse_condition(rule.get("when").ok_or("Missing condition")?)?;
+
+        let actions = rule
+            .get("then")
+            .and_then(Json::as_array)
+            .ok_or("Missing actions")?
+            .iter()
+            .map(Self::parse_action)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let cooldown = match rule.get("cooldown") {
+            None => None,
+            Some(cooldown) => Some(Duration::from_secs(
+                cooldown.as_u64().ok_or("Invalid cooldown")?,
+            )),
+        };
+
+        Ok(Rule {
+            name,
+            condition,
+            actions,
+            cooldown,
+        })
+    }
+
+    fn parse_condition(when: &Json) -> Result<Condition, &'static str> {
+        if let Some(filter) = when.get("topic") {
+            let op = match when.get("op") {
+                None => Op::Any,
+                Some(op) => op.as_str().and_then(Op::from_name).ok_or("Invalid op")?,
+            };
+
+            let value = when.get("value").and_then(Value::from_json);
+
+            if op != Op::Any && value.is_none() {
+                return Err("Missing value");
+            }
+
+            Ok(Condition::Message {
+                filter: filter.as_str().ok_or("Invalid topic")?.into(),
+                path: when.get("path").and_then(Json::as_str).map(Into::into),
+                op,
+                value,
+            })
+        } else if let Some(base) = when.get("event") {
+            Ok(Condition::Event {
+                base: base.as_str().ok_or("Invalid event")?.into(),
+                id: match when.get("id") {
+                    None => None,
+                    Some(id) => Some(id.as_i64().ok_or("Invalid event id")? as i32),
+                },
+            })
+        } else {
+            Err("Invalid condition")
+        }
+    }
+
+    fn parse_action(action: &Json) -> Result<Action, &'static str> {
+        let string = |json: &Json, name| {
+            json.get(name)
+                .and_then(Json::as_str)
+                .map(String::from)
+                .ok_or("Invalid action")
+        };
+
+        if let Some(publish) = action.get("publish") {
+            Ok(Action::Publish {
+                topic: string(publish, "topic")?,
+                payload: string(publish, "payload")?,
+                qos: match publish.get("qos").and_then(Json::as_u64) {
+                    None | Some(0) => QoS::AtMostOnce,
+                    Some(1) => QoS::AtLeastOnce,
+                    Some(2) => QoS::ExactlyOnce,
+                    Some(_) => return Err("Invalid QoS"),
+                },
+                retain: publish
+                    .get("retain")
+                    .and_then(Json::as_bool)
+                    .unwrap_or(false),
+            })
+        } else if let Some(gpio) = action.get("gpio") {
+            Ok(Action::Gpio {
+                pin: gpio
+                    .get("pin")
+                    .and_then(Json::as_u64)
+                    .ok_or("Invalid pin")? as i32,
+                level: match gpio.get("level") {
+                    Some(Json::Bool(level)) => *level,
+                    Some(level) => level.as_u64().ok_or("Invalid level")? != 0,
+                    None => return Err("Invalid level"),
+                },
+            })
+        } else if let Some(http) = action.get("http") {
+            Ok(Action::Http {
+                method: match http.get("method").and_then(Json::as_str).unwrap_or("GET") {
+                    "GET" => Method::Get,
+                    "POST" => Method::Post,
+                    "PUT" => Method::Put,
+                    _ => return Err("Invalid method"),
+                },
+                url: string(http, "url")?,
+                body: http.get("body").and_then(Json::as_str).map(Into::into),
+            })
+        } else {
+            Err("Invalid action")
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The topic on which new rule sets are published, if any
+    pub rules_topic: Option<&'static str>,
+    /// The NVS key of the rule set
+    pub nvs_key: &'static str,
+    pub http_timeout: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            rules_topic: None,
+            nvs_key: "rules",
+            http_timeout: Duration::from_secs(10),
+            stack_size: 6144,
+        }
+    }
+}
+
+enum Command {
+    Connected,
+    Message { topic: String, payload: Vec<u8> },
+    Event { base: String, id: i32 },
+    SetRules(RuleSet),
+    Stop,
+}
+
+pub struct EspRuleEngine {
+    sender: mpsc::Sender<Command>,
+    join_handle: Option<thread::JoinHandle<()>>,
+    _subscription: EspSubscription<System>,
+}
+
+impl EspRuleEngine {
+    /// Starts evaluating the rule set stored in `nvs`, or `rules` if there is none
+    ///
+    /// Without an MQTT connection, message conditions only match the messages passed to
+    /// `input`, and `publish` actions are skipped.
+    pub fn new<'a>(
+        conf: &Configuration,
+        rules: RuleSet,
+        sysloop: &EspSystemEventLoop,
+        nvs: Option<EspDefaultNvs>,
+        mqtt: Option<(&str, &'a MqttClientConfiguration<'a>)>,
+    ) -> Result<Self, EspError> {
+        let rules = match &nvs {
+            Some(nvs) => RuleSet::load(nvs, conf.nvs_key)?.unwrap_or(rules),
+            None => rules,
+        };
+
+        let (sender, receiver) = mpsc::channel();
+
+        let client = match mqtt {
+            Some((url, mqtt_conf)) => {
+                let sender = sender.clone();
+
+                // Publishing from within the event callback might deadlock, hence the messages
+                // are only queued here
+                Some(EspMqttClient::new(url, mqtt_conf, move |event| {
+                    let command = match event {
+                        Ok(Event::Connected(_)) => Command::Connected,
+                        Ok(Event::Received(message)) => match Self::to_command(message) {
+                            Some(command) => command,
+                            None => return,
+                        },
+                        Ok(_) => return,
+                        Err(e) => {
+                            warn!("Rule engine MQTT client error: {:?}", e);
+                            return;
+                        }
+                    };
+
+                    let _ = sender.send(command);
+                })?)
+            }
+            None => None,
+        };
+
+        let subscription = {
+            let sender = sender.clone();
+
+            sysloop.subscribe_raw(ptr::null(), ESP_EVENT_ANY_ID, move |data| {
+                let base = unsafe { from_cstr_ptr(data.source) };
+
+                let _ = sender.send(Command::Event {
+                    base: base.into(),
+                    id: data.event_id,
+                });
+            })?
+        };
+
+        info!("Starting rule engine with {} rules", rules.rules().len());
+
+        let mut evaluator = Evaluator {
+            states: vec![Default::default(); rules.rules().len()],
+            rules,
+            client,
+            connected: false,
+            nvs,
+            conf: conf.clone(),
+            outputs: Vec::new(),
+        };
+
+        let join_handle = thread::Builder::new()
+            .name("rule-engine".into())
+            .stack_size(conf.stack_size)
+            .spawn(move || evaluator.run(receiver))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        Ok(Self {
+            sender,
+            join_handle: Some(join_handle),
+            _subscription: subscription,
+        })
+    }
+
+    /// Replaces the rule set, and stores it in NVS
+    pub fn set_rules(&self, rules: RuleSet) -> Result<(), EspError> {
+        self.send(Command::SetRules(rules))
+    }
+
+    /// Evaluates the message conditions against a message received by the application
+    pub fn input(&self, topic: &str, payload: &[u8]) -> Result<(), EspError> {
+        self.send(Command::Message {
+            topic: topic.into(),
+            payload: payload.to_vec(),
+        })
+    }
+
+    fn send(&self, command: Command) -> Result<(), EspError> {
+        self.sender
+            .send(command)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+    }
+
+    fn to_command(message: &EspMqttMessage) -> Option<Command> {
+        if message.details() != &Details::Complete {
+            warn!("Rule engine cannot evaluate chunked messages, skipping");
+            return None;
+        }
+
+        Some(Command::Message {
+            topic: message.topic()?.into(),
+            payload: message.data().to_vec(),
+        })
+    }
+}
+
+impl Drop for EspRuleEngine {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Command::Stop);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct RuleState {
+    /// Whether the condition of an edge triggered rule was true the last time it was evaluated
+    active: bool,
+    fired: Option<Instant>,
+}
+
+struct Evaluator {
+    rules: RuleSet,
+    states: Vec<RuleState>,
+    client: Option<EspMqttClient>,
+    connected: bool,
+    nvs: Option<EspDefaultNvs>,
+    conf: Configuration,
+    /// The GPIOs already configured as outputs
+    outputs: Vec<i32>,
+}
+
+impl Evaluator {
+    fn run(&mut self, receiver: mpsc::Receiver<Command>) {
+        while let Ok(command) = receiver.recv() {
+            match command {
+                Command::Connected => {
+                    self.connected = true;
+                    self.subscribe();
+                }
+                Command::Message { topic, payload } => {
+                    if Some(topic.as_str()) == self.conf.rules_topic {
+                        self.update_rules(&payload);
+                    } else {
+                        self.message(&topic, &payload);
+                    }
+                }
+                Command::Event { base, id } => self.event(&base, id),
+                Command::SetRules(rules) => self.set_rules(rules),
+                Command::Stop => break,
+            }
+        }
+    }
+
+    fn subscribe(&mut self) {
+        let client = match (&mut self.client, self.connected) {
+            (Some(client), true) => client,
+            _ => return,
+        };
+
+        let mut filters = self
+            .rules
+            .rules()
+            .iter()
+            .filter_map(|rule| match &rule.condition {
+                Condition::Message { filter, .. } => Some(filter.as_str()),
+                _ => None,
+            })
+            .chain(self.conf.rules_topic)
+            .collect::<Vec<_>>();
+
+        filters.sort_unstable();
+        filters.dedup();
+
+        for filter in filters {
+            if let Err(e) = client.subscribe(filter, QoS::AtLeastOnce) {
+                warn!("Rule engine failed to subscribe to {}: {}", filter, e);
+            }
+        }
+    }
+
+    fn update_rules(&mut self, payload: &[u8]) {
+        match core::str::from_utf8(payload)
+            .map_err(|_| RuleError {
+                rule: None,
+                message: "Invalid UTF-8",
+            })
+            .and_then(RuleSet::parse)
+        {
+            Ok(rules) => self.set_rules(rules),
+            Err(e) => warn!("Ignoring the invalid published rules: {}", e),
+        }
+    }
+
+    fn set_rules(&mut self, rules: RuleSet) {
+        if let Some(nvs) = &mut self.nvs {
+            if let Err(e) = rules.store(nvs, self.conf.nvs_key) {
+                warn!("Failed to store the rules: {}", e);
+            }
+        }
+
+        info!("Updated the rules: {} rules", rules.rules().len());
+
+        self.states = vec![Default::default(); rules.rules().len()];
+        self.rules = rules;
+
+        // The topics of the previous rules remain subscribed, their messages are just not
+        // matched anymore
+        self.subscribe();
+    }
+
+    fn message(&mut self, topic: &str, payload: &[u8]) {
+        let payload = core::str::from_utf8(payload)
+            .ok()
+            .map(|payload| Json::parse(payload).unwrap_or_else(|| Json::from(payload)));
+
+        for index in 0..self.rules.rules().len() {
+            let (matched, edge, value) = match &self.rules.rules()[index].condition {
+                Condition::Message {
+                    filter,
+                    path,
+                    op,
+                    value,
+                } if topic_matches(filter, topic) => {
+                    let actual = payload
+                        .as_ref()
+                        .and_then(|payload| match path {
+                            Some(path) => path
+                                .split('.')
+                                .try_fold(payload, |json, segment| json.get(segment)),
+                            None => Some(payload),
+                        })
+                        .and_then(Value::from_json);
+
+                    (
+                        op.eval(actual.as_ref(), value.as_ref()),
+                        *op != Op::Any,
+                        actual,
+                    )
+                }
+                _ => continue,
+            };
+
+            self.trigger(index, matched, edge, value.as_ref());
+        }
+    }
+
+    fn event(&mut self, base: &str, id: i32) {
+        for index in 0..self.rules.rules().len() {
+            let matched = matches!(
+                &self.rules.rules()[index].condition,
+                Condition::Event { base: rule_base, id: rule_id }
+                    if rule_base == base && rule_id.map(|rule_id| rule_id == id).unwrap_or(true)
+            );
+
+            if matched {
+                self.trigger(index, true, false, Some(&Value::Number(id as f64)));
+            }
+        }
+    }
+
+    fn trigger(&mut self, index: usize, matched: bool, edge: bool, value: Option<&Value>) {
+        let state = &mut self.states[index];
+
+        let rising = matched && !(edge && state.active);
+        state.active = matched;
+
+        if !rising {
+            return;
+        }
+
+        let rule = &self.rules.rules()[index];
+
+        if let (Some(cooldown), Some(fired)) = (rule.cooldown, state.fired) {
+            if fired.elapsed() < cooldown {
+                debug!("Rule {} is cooling down", rule.name);
+                return;
+            }
+        }
+
+        state.fired = Some(Instant::now());
+
+        info!("Rule {} fired", rule.name);
+
+        let actions = rule.actions.clone();
+        let value = value.map(ToString::to_string).unwrap_or_default();
+
+        for action in &actions {
+            if let Err(e) = self.execute(action, &value) {
+                warn!(
+                    "Rule {} action failed: {}",
+                    self.rules.rules()[index].name,
+                    e
+                );
+            }
+        }
+    }
+
+    fn execute(&mut self, action: &Action, value: &str) -> Result<(), EspError> {
+        match action {
+            Action::Publish {
+                topic,
+                payload,
+                qos,
+                retain,
+            } => match &mut self.client {
+                Some(client) => {
+                    let payload = payload.replace("{{value}}", value);
+
+                    client.enqueue(topic, *qos, *retain, payload.as_bytes())?;
+                }
+                None => warn!("No MQTT connection, not publishing to {}", topic),
+            },
+            Action::Gpio { pin, level } => {
+                if !self.outputs.contains(pin) {
+                    esp!(unsafe { gpio_reset_pin(*pin) })?;
+                    esp!(unsafe { gpio_set_direction(*pin, gpio_mode_t_GPIO_MODE_OUTPUT) })?;
+
+                    self.outputs.push(*pin);
+                }
+
+                esp!(unsafe { gpio_set_level(*pin, *level as u32) })?;
+            }
+            Action::Http { method, url, body } => {
+                let body = body
+                    .as_deref()
+                    .map(|body| body.replace("{{value}}", value))
+                    .unwrap_or_default();
+
+                self.http(*method, url, body.as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn http(&self, method: Method, url: &str, body: &[u8]) -> Result<(), EspError> {
+        let mut connection = EspHttpConnection::new(&client::Configuration {
+            timeout: Some(self.conf.http_timeout),
+            #[cfg(all(not(esp_idf_version = "4.3"), esp_idf_mbedtls_certificate_bundle))]
+            crt_bundle_attach: Some(esp_crt_bundle_attach),
+            ..Default::default()
+        })?;
+
+        let len = body.len().to_string();
+
+        let headers = if method == Method::Get {
+            vec![]
+        } else {
+            vec![("Content-Length", len.as_str())]
+        };
+
+        connection.initiate_request(method, url, &headers)?;
+
+        let mut offset = 0;
+        while offset < body.len() {
+            offset += connection.write(&body[offset..])?;
+        }
+
+        connection.initiate_response()?;
+
+        let status = connection.status();
+
+        if (200..300).contains(&status) {
+            Ok(())
+        } else {
+            warn!("Rule HTTP action answered with status {}", status);
+
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>())
+        }
+    }
+}
+
+/// Whether `topic` matches the MQTT topic `filter`
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => (),
+            (Some(filter), Some(topic)) if filter == topic => (),
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
@@ -0,0 +1,500 @@
+//! Job scheduler
+//!
+//! `EspScheduler` runs jobs on cron-style or interval schedules, on wall clock time. The next
+//! run of each job is persisted in NVS, so that the schedules survive restarts and deep sleep:
+//! before going to sleep, `EspScheduler::arm_wakeup` enables the timer wake-up source for the
+//! next run, and on the next boot, the jobs which became due while the chip was sleeping (or
+//! off) are run right away, if they are not later than `Configuration::missed_run_window`:
+//!
+//! ```ignore
+//! let scheduler = EspScheduler::new(
+//!     &Default::default(),
+//!     nvs_partition.clone(),
+//!     vec![
+//!         Job::new("irrigate", "0 6,18 * * *".parse::<Cron>()?, || open_valve()),
+//!         Job::new("report", Duration::from_secs(15 * 60), || report()),
+//!         Job::new_async("sync", "*/30 8-20 * * 1-5".parse::<Cron>()?, || async {
+//!             sync().await
+//!         }),
+//!     ],
+//! )?;
+//!
+//! loop {
+//!     thread::sleep(Duration::from_secs(10));
+//!
+//!     if idle() && scheduler.arm_wakeup()?.is_some() {
+//!         unsafe { esp_deep_sleep_start() };
+//!     }
+//! }
+//! ```
+//!
+//! Cron expressions have the five usual fields - minute, hour, day of the month, month and day
+//! of the week (0 or 7 being Sunday) - each being `*`, a value, a range (`1-5`), a step
+//! (`*/15`, `0-30/10`) or a list of those (`6,18`). They are evaluated in local time, and only
+//! once the system time is set (e.g. by SNTP); interval jobs run regardless.
+//!
+//! The jobs run one after the other on the scheduler thread, including the async ones, whose
+//! futures are driven to completion on that thread.
+use core::future::Future;
+use core::pin::Pin;
+use core::str::FromStr;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::string::String;
+use std::sync::{mpsc, Arc};
+use std::task::Wake;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+use crate::private::civil::*;
+use crate::private::mutex::Mutex;
+
+const NAMESPACE: &str = "scheduler";
+
+/// 2020-01-01, i.e. the earliest time considered as set
+const MIN_TIMESTAMP: i64 = 1577836800;
+
+/// The longest wait between two checks, so that changes of the system time are noticed
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// How late a job can be on boot to still be run; later jobs are rescheduled instead
+    pub missed_run_window: Duration,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            missed_run_window: Duration::from_secs(60 * 60),
+            stack_size: 8192,
+        }
+    }
+}
+
+/// A cron schedule, parsed from a five fields expression
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct Cron {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    /// The first matching time strictly after `time`, both in seconds since the (local) epoch
+    pub fn next_after(&self, time: i64) -> Option<i64> {
+        let start = time.div_euclid(60) * 60 + 60;
+
+        let mut day = start.div_euclid(86400);
+        let mut minute = start.rem_euclid(86400) / 60;
+
+        // Long enough for any valid expression to match, e.g. on February 29
+        for _ in 0..366 * 8 {
+            let (_, month, day_of_month) = civil_from_days(day);
+
+            if self.matches_day(month, day_of_month, (day + 4).rem_euclid(7) as u32) {
+                while minute < 24 * 60 {
+                    if self.hours & (1 << (minute / 60)) != 0
+                        && self.minutes & (1 << (minute % 60)) != 0
+                    {
+                        return Some(day * 86400 + minute * 60);
+                    }
+
+                    minute += 1;
+                }
+            }
+
+            day += 1;
+            minute = 0;
+        }
+
+        None
+    }
+
+    fn matches_day(&self, month: u32, day: u32, weekday: u32) -> bool {
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+
+        // As in the original cron, a day matches either field when both are restricted
+        self.months & (1 << month) != 0
+            && match (self.any_day, self.any_weekday) {
+                (true, true) => true,
+                (true, false) => weekday_matches,
+                (false, true) => day_matches,
+                (false, false) => day_matches || weekday_matches,
+            }
+    }
+
+    fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, EspError> {
+        let invalid = EspError::from_infallible::<ESP_ERR_INVALID_ARG>;
+
+        let mut bits = 0;
+
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+                None => (part, 1),
+            };
+
+            let (from, to) = if range == "*" {
+                (min, max)
+            } else if let Some((from, to)) = range.split_once('-') {
+                (
+                    from.parse().map_err(|_| invalid())?,
+                    to.parse().map_err(|_| invalid())?,
+                )
+            } else {
+                let value = range.parse().map_err(|_| invalid())?;
+
+                (value, if part.contains('/') { max } else { value })
+            };
+
+            if step == 0 || from < min || to > max || from > to {
+                return Err(invalid());
+            }
+
+            for value in (from..=to).step_by(step as usize) {
+                bits |= 1 << value;
+            }
+        }
+
+        Ok(bits)
+    }
+}
+
+impl FromStr for Cron {
+    type Err = EspError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+
+        if fields.len() != 5 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let mut weekdays = Self::parse_field(fields[4], 0, 7)?;
+
+        // Sunday is both 0 and 7
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Ok(Self {
+            minutes: Self::parse_field(fields[0], 0, 59)?,
+            hours: Self::parse_field(fields[1], 0, 23)? as _,
+            days: Self::parse_field(fields[2], 1, 31)? as _,
+            months: Self::parse_field(fields[3], 1, 12)? as _,
+            weekdays: weekdays as _,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Schedule {
+    Interval(Duration),
+    Cron(Cron),
+}
+
+impl From<Duration> for Schedule {
+    fn from(interval: Duration) -> Self {
+        Self::Interval(interval)
+    }
+}
+
+impl From<Cron> for Schedule {
+    fn from(cron: Cron) -> Self {
+        Self::Cron(cron)
+    }
+}
+
+impl Schedule {
+    /// The next run after `now`, in seconds since the UNIX epoch, if it can be computed yet
+    fn next_after(&self, now: i64) -> Option<i64> {
+        match self {
+            Self::Interval(interval) => Some(now + interval.as_secs().max(1) as i64),
+            Self::Cron(cron) => {
+                if now < MIN_TIMESTAMP {
+                    None
+                } else {
+                    let offset = local_offset(now);
+
+                    cron.next_after(now + offset).map(|next| next - offset)
+                }
+            }
+        }
+    }
+}
+
+pub struct Job {
+    name: String,
+    schedule: Schedule,
+    callback: Box<dyn FnMut() + Send + 'static>,
+}
+
+impl Job {
+    /// `name` identifies the job in NVS, so it must be unique and at most 15 characters long
+    pub fn new(
+        name: impl Into<String>,
+        schedule: impl Into<Schedule>,
+        callback: impl FnMut() + Send + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            schedule: schedule.into(),
+            callback: Box::new(callback),
+        }
+    }
+
+    pub fn new_async<F>(
+        name: impl Into<String>,
+        schedule: impl Into<Schedule>,
+        mut callback: impl FnMut() -> F + Send + 'static,
+    ) -> Self
+    where
+        F: Future<Output = ()>,
+    {
+        Self::new(name, schedule, move || block_on(callback()))
+    }
+}
+
+pub struct EspScheduler {
+    next_runs: Arc<Mutex<Vec<(String, Option<i64>)>>>,
+    stop: mpsc::Sender<()>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspScheduler {
+    pub fn new<T>(
+        conf: &Configuration,
+        partition: EspNvsPartition<T>,
+        jobs: Vec<Job>,
+    ) -> Result<Self, EspError>
+    where
+        T: NvsPartitionId + Send + 'static,
+        EspNvsPartition<T>: Send,
+    {
+        for (index, job) in jobs.iter().enumerate() {
+            if job.name.is_empty()
+                || job.name.len() > 15
+                || jobs[..index].iter().any(|other| other.name == job.name)
+            {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
+        }
+
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+
+        let now = now();
+        let missed_run_window = conf.missed_run_window.as_secs() as i64;
+
+        let mut next_runs = Vec::with_capacity(jobs.len());
+
+        for job in &jobs {
+            let next = match nvs.get_u64(&job.name)? {
+                Some(next) if next as i64 > now - missed_run_window => {
+                    if next as i64 <= now {
+                        info!("Job {} is due since boot", job.name);
+                    }
+
+                    Some(next as i64)
+                }
+                Some(_) => {
+                    warn!("Job {} missed its run, rescheduling", job.name);
+
+                    job.schedule.next_after(now)
+                }
+                None => job.schedule.next_after(now),
+            };
+
+            Self::persist(&nvs, &job.name, next);
+
+            next_runs.push((job.name.clone(), next));
+        }
+
+        let next_runs = Arc::new(Mutex::new(next_runs));
+
+        let (stop, stopped) = mpsc::channel();
+
+        let join_handle = {
+            let next_runs = next_runs.clone();
+
+            thread::Builder::new()
+                .name("scheduler".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(jobs, nvs, next_runs, stopped))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        Ok(Self {
+            next_runs,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// The next run of the job named `name`, as the duration since the UNIX epoch
+    pub fn next_run(&self, name: &str) -> Option<Duration> {
+        self.next_runs
+            .lock()
+            .iter()
+            .find(|(job_name, _)| job_name == name)
+            .and_then(|(_, next)| *next)
+            .map(|next| Duration::from_secs(next.max(0) as u64))
+    }
+
+    /// How long until the next run of any job, if one is scheduled
+    pub fn next_due(&self) -> Option<Duration> {
+        let now = now();
+
+        self.next_runs
+            .lock()
+            .iter()
+            .filter_map(|(_, next)| *next)
+            .min()
+            .map(|next| Duration::from_secs((next - now).max(0) as u64))
+    }
+
+    /// Enables the timer wake-up source for the next run of any job, to be called right
+    /// before entering deep (or light) sleep, and returns the duration until then
+    pub fn arm_wakeup(&self) -> Result<Option<Duration>, EspError> {
+        let next_due = self.next_due();
+
+        if let Some(next_due) = next_due {
+            info!("Waking up in {:?} for the next job", next_due);
+
+            esp!(unsafe { esp_sleep_enable_timer_wakeup(next_due.as_micros() as u64) })?;
+        }
+
+        Ok(next_due)
+    }
+
+    fn run<T: NvsPartitionId>(
+        mut jobs: Vec<Job>,
+        nvs: EspNvs<T>,
+        next_runs: Arc<Mutex<Vec<(String, Option<i64>)>>>,
+        stopped: mpsc::Receiver<()>,
+    ) {
+        loop {
+            for (index, job) in jobs.iter_mut().enumerate() {
+                let now = now();
+                let due = next_runs.lock()[index].1;
+
+                let next = match due {
+                    Some(due) if due <= now => {
+                        info!("Running job {}", job.name);
+
+                        (job.callback)();
+
+                        job.schedule.next_after(self::now())
+                    }
+                    // A cron job waiting for the system time to be set
+                    None => job.schedule.next_after(now),
+                    _ => continue,
+                };
+
+                next_runs.lock()[index].1 = next;
+
+                Self::persist(&nvs, &job.name, next);
+            }
+
+            let wait = next_runs
+                .lock()
+                .iter()
+                .filter_map(|(_, next)| *next)
+                .min()
+                .map(|next| Duration::from_secs((next - self::now()).max(0) as u64))
+                .unwrap_or(MAX_WAIT)
+                .min(MAX_WAIT);
+
+            match stopped.recv_timeout(wait) {
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                _ => break,
+            }
+        }
+    }
+
+    fn persist<T: NvsPartitionId>(nvs: &EspNvs<T>, name: &str, next: Option<i64>) {
+        if let Some(next) = next {
+            if let Err(e) = nvs.set_u64(name, next as u64) {
+                warn!("Failed to persist the next run of job {}: {}", name, e);
+            }
+        }
+    }
+}
+
+impl Drop for EspScheduler {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+fn now() -> i64 {
+    unsafe { time(core::ptr::null_mut()) as i64 }
+}
+
+/// The offset of the local time zone at `now`, in seconds
+fn local_offset(now: i64) -> i64 {
+    let now_t = now as time_t;
+    let mut tm: tm = Default::default();
+
+    unsafe { localtime_r(&now_t, &mut tm) };
+
+    let local = days_from_civil(
+        tm.tm_year as i64 + 1900,
+        tm.tm_mon as u32 + 1,
+        tm.tm_mday as u32,
+    ) * 86400
+        + tm.tm_hour as i64 * 3600
+        + tm.tm_min as i64 * 60
+        + tm.tm_sec as i64;
+
+    local - now
+}
+
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = Pin::as_mut(&mut future).poll(&mut context) {
+            return output;
+        }
+
+        thread::park();
+    }
+}
@@ -0,0 +1,76 @@
+//! `Send` adapters for work-stealing executors
+//!
+//! Work-stealing executors, like the multi-threaded runtime of tokio, require the spawned
+//! futures to be `Send`, as they may be polled from any of their worker threads. The services
+//! of the crate whose handles may be moved between FreeRTOS tasks are `Send` (e.g.
+//! `EspHttpConnection`, `EspWebSocketClient`, `EspMqttClient` and `EspWifi`), so the futures
+//! using them are `Send` too.
+//!
+//! The services holding raw pointers which are not `Send` are usually safe to move between tasks
+//! as well, as ESP-IDF handles are not bound to the task which created them. `Sendable` states
+//! that for a value, or a future, which the compiler cannot prove to be `Send`:
+//!
+//! ```ignore
+//! let handle = tokio::spawn(unsafe {
+//!     Sendable::new(async move {
+//!         let frame = uart.frame().await;
+//!         ...
+//!     })
+//! });
+//! ```
+//!
+//! The output of a wrapped future is not wrapped, so it still needs to be `Send` to be
+//! returned from a spawned task.
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// A value, or a future, asserted to be `Send`
+///
+/// Only created with the `unsafe` `new`, as `Send` is implemented for any `T`
+#[derive(Debug)]
+pub struct Sendable<T>(T);
+
+impl<T> Sendable<T> {
+    /// # Safety
+    ///
+    /// The value should not contain state bound to the thread which created it or shared with
+    /// other values without synchronization, like an `Rc` or thread-local storage, nor ESP-IDF
+    /// handles which may only be used from the task which created them.
+    pub unsafe fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+unsafe impl<T> Send for Sendable<T> {}
+
+impl<T> Deref for Sendable<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Sendable<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<F> Future for Sendable<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The wrapped future is structurally pinned
+        unsafe { self.map_unchecked_mut(|sendable| &mut sendable.0) }.poll(cx)
+    }
+}
@@ -0,0 +1,657 @@
+//! SNMP agent
+//!
+//! A small, read-only SNMP v2c agent, answering `Get`, `GetNext` and `GetBulk` requests for
+//! the `system` and `interfaces` groups of MIB-II, as well as for custom objects whose
+//! values are provided by Rust callbacks.
+//!
+//! ```ignore
+//! let agent = EspSnmpAgent::new(&Configuration {
+//!     sys_name: "gateway-1".into(),
+//!     interfaces: vec![Interface::Sta],
+//!     ..Default::default()
+//! })?;
+//!
+//! // enterprises.99999.1.0: the temperature, in tenths of degrees
+//! agent.register(&[1, 3, 6, 1, 4, 1, 99999, 1, 0], || Value::Integer(read_temperature()));
+//! ```
+use core::cmp::min;
+use core::ops::Bound;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::string::String;
+use std::sync::Arc;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::netif::Interface;
+use crate::private::mutex::{Mutex, RawMutex};
+
+const VERSION_2C: i64 = 1;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OBJECT_ID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_IP_ADDRESS: u8 = 0x40;
+const TAG_COUNTER32: u8 = 0x41;
+const TAG_GAUGE32: u8 = 0x42;
+const TAG_TIME_TICKS: u8 = 0x43;
+const TAG_COUNTER64: u8 = 0x46;
+
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_NO_SUCH_INSTANCE: u8 = 0x81;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+
+const PDU_GET: u8 = 0xa0;
+const PDU_GET_NEXT: u8 = 0xa1;
+const PDU_RESPONSE: u8 = 0xa2;
+const PDU_SET: u8 = 0xa3;
+const PDU_GET_BULK: u8 = 0xa5;
+
+const ERROR_TOO_BIG: i64 = 1;
+const ERROR_NOT_WRITABLE: i64 = 17;
+
+/// Responses are kept below the usual Ethernet MTU, to avoid IP fragmentation
+const MAX_RESPONSE_LEN: usize = 1400;
+
+const MIB2_SYSTEM: &[u32] = &[1, 3, 6, 1, 2, 1, 1];
+const MIB2_INTERFACES: &[u32] = &[1, 3, 6, 1, 2, 1, 2];
+
+/// The value of an object
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Integer(i32),
+    OctetString(Vec<u8>),
+    ObjectId(Vec<u32>),
+    IpAddress(Ipv4Addr),
+    Counter32(u32),
+    Gauge32(u32),
+    /// Hundredths of a second
+    TimeTicks(u32),
+    Counter64(u64),
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Self::Integer(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Self::OctetString(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Self::OctetString(value.into_bytes())
+    }
+}
+
+impl From<Ipv4Addr> for Value {
+    fn from(value: Ipv4Addr) -> Self {
+        Self::IpAddress(value)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Configuration {
+    /// The (read-only) community requests have to present
+    pub community: String,
+    pub port: u16,
+    pub sys_descr: String,
+    /// Defaults to the `enterprises` arc of Espressif
+    pub sys_object_id: Vec<u32>,
+    pub sys_contact: String,
+    pub sys_name: String,
+    pub sys_location: String,
+    /// The interfaces exposed in `ifTable`, with `ifIndex` being their position, starting at 1
+    pub interfaces: Vec<Interface>,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            community: "public".into(),
+            port: 161,
+            sys_descr: "ESP-IDF".into(),
+            sys_object_id: vec![1, 3, 6, 1, 4, 1, 51_855],
+            sys_contact: String::new(),
+            sys_name: String::new(),
+            sys_location: String::new(),
+            interfaces: Vec::new(),
+            stack_size: 6144,
+        }
+    }
+}
+
+type Getter = Box<dyn Fn() -> Option<Value> + Send>;
+
+type Objects = Mutex<BTreeMap<Vec<u32>, Getter>>;
+
+pub struct EspSnmpAgent {
+    objects: Arc<Objects>,
+    stop: Arc<AtomicBool>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspSnmpAgent {
+    pub fn new(conf: &Configuration) -> Result<Self, EspError> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, conf.port))
+            .and_then(|socket| {
+                // The stop flag is only checked between receives
+                socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+
+                Ok(socket)
+            })
+            .map_err(|e| {
+                warn!("Failed to bind SNMP socket: {}", e);
+
+                EspError::from_infallible::<ESP_FAIL>()
+            })?;
+
+        let objects = Arc::new(Mutex::wrap(RawMutex::new(), BTreeMap::new()));
+
+        let mut agent = Self {
+            objects: objects.clone(),
+            stop: Arc::new(AtomicBool::new(false)),
+            join_handle: None,
+        };
+
+        agent.register_mib2(conf);
+
+        agent.join_handle = {
+            let community = conf.community.clone();
+            let stop = agent.stop.clone();
+
+            Some(
+                thread::Builder::new()
+                    .name("snmp".into())
+                    .stack_size(conf.stack_size)
+                    .spawn(move || Self::run(community, socket, objects, stop))
+                    .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?,
+            )
+        };
+
+        info!("Started SNMP agent on port {}", conf.port);
+
+        Ok(agent)
+    }
+
+    /// Registers (or replaces) the object with the given OID, whose value is
+    /// obtained by calling `getter` on every request
+    pub fn register<V>(&self, oid: &[u32], getter: impl Fn() -> V + Send + 'static)
+    where
+        V: Into<Value>,
+    {
+        self.register_optional(oid, move || Some(getter().into()));
+    }
+
+    /// Same as `register`, except that `getter` can report the object as
+    /// currently unavailable (`noSuchInstance`)
+    pub fn register_optional(
+        &self,
+        oid: &[u32],
+        getter: impl Fn() -> Option<Value> + Send + 'static,
+    ) {
+        self.objects.lock().insert(oid.to_vec(), Box::new(getter));
+    }
+
+    pub fn unregister(&self, oid: &[u32]) -> bool {
+        self.objects.lock().remove(oid).is_some()
+    }
+
+    fn register_mib2(&self, conf: &Configuration) {
+        let system = |index: u32| [MIB2_SYSTEM, &[index, 0]].concat();
+
+        let sys_descr = conf.sys_descr.clone();
+        let sys_object_id = conf.sys_object_id.clone();
+        let sys_contact = conf.sys_contact.clone();
+        let sys_name = conf.sys_name.clone();
+        let sys_location = conf.sys_location.clone();
+
+        self.register(&system(1), move || Value::from(sys_descr.as_str()));
+        self.register(&system(2), move || Value::ObjectId(sys_object_id.clone()));
+        self.register(&system(3), || {
+            Value::TimeTicks((unsafe { esp_timer_get_time() } / 10_000) as u32)
+        });
+        self.register(&system(4), move || Value::from(sys_contact.as_str()));
+        self.register(&system(5), move || Value::from(sys_name.as_str()));
+        self.register(&system(6), move || Value::from(sys_location.as_str()));
+        // Internet, end-to-end and application services
+        self.register(&system(7), || Value::Integer(0x4c));
+
+        let interfaces = conf.interfaces.len() as i32;
+        self.register(&[MIB2_INTERFACES, &[1, 0]].concat(), move || {
+            Value::Integer(interfaces)
+        });
+
+        // ifTable.ifEntry.<column>.<ifIndex>
+        let column = |column: u32, index: u32| [MIB2_INTERFACES, &[2, 1, column, index]].concat();
+
+        for (position, interface) in conf.interfaces.iter().enumerate() {
+            let index = position as u32 + 1;
+            let interface = *interface;
+
+            self.register(&column(1, index), move || Value::Integer(index as _));
+            self.register(&column(2, index), move || Value::from(interface.key()));
+            self.register(&column(3, index), move || {
+                Value::Integer(match interface {
+                    // ieee80211
+                    Interface::Sta | Interface::Ap => 71,
+                    // ethernetCsmacd
+                    Interface::Eth => 6,
+                    // other
+                    _ => 1,
+                })
+            });
+            self.register_optional(&column(6, index), move || {
+                let handle = interface.handle().ok()?;
+
+                let mut mac = [0_u8; 6];
+                esp!(unsafe { esp_netif_get_mac(handle, mac.as_mut_ptr()) }).ok()?;
+
+                Some(Value::OctetString(mac.to_vec()))
+            });
+            // The administrative status: the interfaces are always supposed to be up
+            self.register(&column(7, index), || Value::Integer(1));
+            self.register(&column(8, index), move || {
+                let up = interface
+                    .handle()
+                    .map(|handle| unsafe { esp_netif_is_netif_up(handle) })
+                    .unwrap_or(false);
+
+                // up(1) or down(2)
+                Value::Integer(if up { 1 } else { 2 })
+            });
+        }
+    }
+
+    fn run(community: String, socket: UdpSocket, objects: Arc<Objects>, stop: Arc<AtomicBool>) {
+        let mut request = [0_u8; 1500];
+
+        while !stop.load(Ordering::SeqCst) {
+            let (len, peer) = match socket.recv_from(&mut request) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    warn!("SNMP receive failed: {}", e);
+                    continue;
+                }
+            };
+
+            if let Some(response) = Self::answer(&community, &objects, &request[..len]) {
+                if let Err(e) = socket.send_to(&response, peer) {
+                    warn!("SNMP send to {} failed: {}", peer, e);
+                }
+            } else {
+                debug!("Dropped SNMP request from {}", peer);
+            }
+        }
+    }
+
+    /// Builds the response to a request, or returns `None` if the request is malformed,
+    /// of an unsupported version or presents another community, and should be dropped
+    fn answer(community: &str, objects: &Objects, request: &[u8]) -> Option<Vec<u8>> {
+        let mut message = Reader::new(request).expect(TAG_SEQUENCE)?;
+
+        if message.integer()? != VERSION_2C
+            || message.expect(TAG_OCTET_STRING)?.0 != community.as_bytes()
+        {
+            return None;
+        }
+
+        let (pdu_type, mut pdu) = message.read()?;
+
+        let request_id = pdu.integer()?;
+        // For `GetBulk`, the non-repeaters and the max-repetitions
+        let first = pdu.integer()?;
+        let second = pdu.integer()?;
+
+        let mut bindings = pdu.expect(TAG_SEQUENCE)?;
+        let mut oids = Vec::new();
+
+        while !bindings.is_empty() {
+            let mut binding = bindings.expect(TAG_SEQUENCE)?;
+            oids.push(binding.oid()?);
+        }
+
+        let objects = objects.lock();
+
+        let mut error = 0;
+        let mut error_index = 0;
+        let mut varbinds = Vec::new();
+
+        match pdu_type {
+            PDU_GET => {
+                for oid in &oids {
+                    let value = objects
+                        .get(oid)
+                        .map(|getter| getter().ok_or(TAG_NO_SUCH_INSTANCE));
+
+                    encode_binding(
+                        oid,
+                        value
+                            .as_ref()
+                            .map(|value| value.as_ref().map_err(|tag| *tag)),
+                        &mut varbinds,
+                    );
+                }
+            }
+            PDU_GET_NEXT => {
+                for oid in &oids {
+                    Self::encode_next(&objects, oid, &mut varbinds);
+                }
+            }
+            PDU_GET_BULK => {
+                let non_repeaters = min(first.max(0) as usize, oids.len());
+                let max_repetitions = second.max(0) as usize;
+
+                for oid in &oids[..non_repeaters] {
+                    Self::encode_next(&objects, oid, &mut varbinds);
+                }
+
+                let mut repeaters = oids[non_repeaters..].to_vec();
+
+                'repetitions: for _ in 0..max_repetitions {
+                    if repeaters.is_empty() {
+                        break;
+                    }
+
+                    for oid in repeaters.iter_mut() {
+                        let len = varbinds.len();
+
+                        if let Some(next) = Self::encode_next(&objects, oid, &mut varbinds) {
+                            *oid = next;
+                        }
+
+                        if varbinds.len() > MAX_RESPONSE_LEN {
+                            // A partial response is fine for `GetBulk`
+                            varbinds.truncate(len);
+                            break 'repetitions;
+                        }
+                    }
+                }
+            }
+            PDU_SET => {
+                encode_bindings_null(&oids, &mut varbinds);
+
+                error = ERROR_NOT_WRITABLE;
+                error_index = 1;
+            }
+            _ => return None,
+        }
+
+        if varbinds.len() > MAX_RESPONSE_LEN {
+            varbinds.clear();
+            encode_bindings_null(&oids, &mut varbinds);
+
+            error = ERROR_TOO_BIG;
+            error_index = 0;
+        }
+
+        let mut pdu = Vec::new();
+        encode_integer(request_id, &mut pdu);
+        encode_integer(error, &mut pdu);
+        encode_integer(error_index, &mut pdu);
+        encode_tlv(TAG_SEQUENCE, &varbinds, &mut pdu);
+
+        let mut message = Vec::new();
+        encode_integer(VERSION_2C, &mut message);
+        encode_tlv(TAG_OCTET_STRING, community.as_bytes(), &mut message);
+        encode_tlv(PDU_RESPONSE, &pdu, &mut message);
+
+        let mut response = Vec::new();
+        encode_tlv(TAG_SEQUENCE, &message, &mut response);
+
+        Some(response)
+    }
+
+    /// Encodes the binding of the first available object after `oid`, returning its OID,
+    /// or `endOfMibView` if there is no further object
+    fn encode_next(
+        objects: &BTreeMap<Vec<u32>, Getter>,
+        oid: &[u32],
+        varbinds: &mut Vec<u8>,
+    ) -> Option<Vec<u32>> {
+        let next = objects
+            .range::<[u32], _>((Bound::Excluded(oid), Bound::Unbounded))
+            .find_map(|(oid, getter)| getter().map(|value| (oid, value)));
+
+        if let Some((next, value)) = next {
+            encode_binding(next, Some(Ok(&value)), varbinds);
+
+            Some(next.clone())
+        } else {
+            encode_binding(oid, Some(Err(TAG_END_OF_MIB_VIEW)), varbinds);
+
+            None
+        }
+    }
+}
+
+impl Drop for EspSnmpAgent {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+/// A cursor on BER-encoded data
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Reads the next TLV, returning its tag and a reader on its content
+    fn read(&mut self) -> Option<(u8, Reader<'a>)> {
+        let tag = *self.0.first()?;
+        let first = *self.0.get(1)? as usize;
+
+        let (len, header) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let octets = first & 0x7f;
+            if octets == 0 || octets > 2 {
+                return None;
+            }
+
+            let len = self
+                .0
+                .get(2..2 + octets)?
+                .iter()
+                .fold(0, |len, octet| (len << 8) | *octet as usize);
+
+            (len, 2 + octets)
+        };
+
+        let content = self.0.get(header..header + len)?;
+        self.0 = &self.0[header + len..];
+
+        Some((tag, Reader(content)))
+    }
+
+    fn expect(&mut self, tag: u8) -> Option<Reader<'a>> {
+        match self.read()? {
+            (read, content) if read == tag => Some(content),
+            _ => None,
+        }
+    }
+
+    fn integer(&mut self) -> Option<i64> {
+        let content = self.expect(TAG_INTEGER)?.0;
+
+        if content.is_empty() || content.len() > 8 {
+            return None;
+        }
+
+        let sign = if content[0] & 0x80 != 0 { -1 } else { 0 };
+
+        Some(
+            content
+                .iter()
+                .fold(sign, |value, octet| (value << 8) | *octet as i64),
+        )
+    }
+
+    fn oid(&mut self) -> Option<Vec<u32>> {
+        let content = self.expect(TAG_OBJECT_ID)?.0;
+
+        let (first, rest) = content.split_first()?;
+
+        let mut oid = vec![(*first / 40) as u32, (*first % 40) as u32];
+        let mut arc: u32 = 0;
+
+        for octet in rest {
+            arc = arc.checked_mul(128)? | (*octet & 0x7f) as u32;
+
+            if *octet & 0x80 == 0 {
+                oid.push(arc);
+                arc = 0;
+            }
+        }
+
+        Some(oid)
+    }
+}
+
+fn encode_tlv(tag: u8, content: &[u8], out: &mut Vec<u8>) {
+    out.push(tag);
+
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else if len <= 0xff {
+        out.extend_from_slice(&[0x81, len as u8]);
+    } else {
+        out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]);
+    }
+
+    out.extend_from_slice(content);
+}
+
+fn encode_integer(value: i64, out: &mut Vec<u8>) {
+    let bytes = value.to_be_bytes();
+
+    // Drop the leading octets which only repeat the sign bit
+    let mut start = 0;
+    while start < bytes.len() - 1
+        && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+
+    encode_tlv(TAG_INTEGER, &bytes[start..], out);
+}
+
+fn encode_unsigned(tag: u8, value: u64, out: &mut Vec<u8>) {
+    let bytes = value.to_be_bytes();
+
+    let mut start = 0;
+    while start < bytes.len() - 1 && bytes[start] == 0 {
+        start += 1;
+    }
+
+    if bytes[start] & 0x80 != 0 {
+        let mut content = vec![0];
+        content.extend_from_slice(&bytes[start..]);
+
+        encode_tlv(tag, &content, out);
+    } else {
+        encode_tlv(tag, &bytes[start..], out);
+    }
+}
+
+fn encode_oid(oid: &[u32], out: &mut Vec<u8>) {
+    let mut content = Vec::new();
+
+    if oid.len() >= 2 {
+        content.push((oid[0] * 40 + oid[1]) as u8);
+    } else {
+        content.push(oid.first().map(|arc| *arc * 40).unwrap_or(0) as u8);
+    }
+
+    for arc in oid.iter().skip(2) {
+        let mut encoded = [0_u8; 5];
+        let mut start = encoded.len() - 1;
+        let mut arc = *arc;
+
+        encoded[start] = (arc & 0x7f) as u8;
+        arc >>= 7;
+
+        while arc > 0 {
+            start -= 1;
+            encoded[start] = 0x80 | (arc & 0x7f) as u8;
+            arc >>= 7;
+        }
+
+        content.extend_from_slice(&encoded[start..]);
+    }
+
+    encode_tlv(TAG_OBJECT_ID, &content, out);
+}
+
+fn encode_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Integer(value) => encode_integer(*value as _, out),
+        Value::OctetString(value) => encode_tlv(TAG_OCTET_STRING, value, out),
+        Value::ObjectId(value) => encode_oid(value, out),
+        Value::IpAddress(value) => encode_tlv(TAG_IP_ADDRESS, &value.octets(), out),
+        Value::Counter32(value) => encode_unsigned(TAG_COUNTER32, *value as _, out),
+        Value::Gauge32(value) => encode_unsigned(TAG_GAUGE32, *value as _, out),
+        Value::TimeTicks(value) => encode_unsigned(TAG_TIME_TICKS, *value as _, out),
+        Value::Counter64(value) => encode_unsigned(TAG_COUNTER64, *value, out),
+    }
+}
+
+/// Encodes a variable binding; `None` stands for `noSuchObject`, and an `Err` for
+/// the exception (or `NULL`) with the given tag
+fn encode_binding(oid: &[u32], value: Option<Result<&Value, u8>>, out: &mut Vec<u8>) {
+    let mut binding = Vec::new();
+    encode_oid(oid, &mut binding);
+
+    match value {
+        Some(Ok(value)) => encode_value(value, &mut binding),
+        Some(Err(tag)) => encode_tlv(tag, &[], &mut binding),
+        None => encode_tlv(TAG_NO_SUCH_OBJECT, &[], &mut binding),
+    }
+
+    encode_tlv(TAG_SEQUENCE, &binding, out);
+}
+
+fn encode_bindings_null(oids: &[Vec<u32>], out: &mut Vec<u8>) {
+    for oid in oids {
+        encode_binding(oid, Some(Err(TAG_NULL)), out);
+    }
+}
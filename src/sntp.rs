@@ -163,6 +163,17 @@ type SyncCallback = alloc::boxed::Box<dyn FnMut(Duration) + Send + 'static>;
 static SYNC_CB: mutex::Mutex<Option<SyncCallback>> =
     mutex::Mutex::wrap(mutex::RawMutex::new(), None);
 static TAKEN: mutex::Mutex<bool> = mutex::Mutex::wrap(mutex::RawMutex::new(), false);
+/// The time since boot of the last synchronization
+static LAST_SYNC: mutex::Mutex<Option<Duration>> = mutex::Mutex::wrap(mutex::RawMutex::new(), None);
+
+/// How long ago the system time was last synchronized, if it ever was since boot
+pub fn since_last_sync() -> Option<Duration> {
+    let now = Duration::from_micros(unsafe { esp_timer_get_time() } as u64);
+
+    LAST_SYNC
+        .lock()
+        .map(|last_sync| now.saturating_sub(last_sync))
+}
 
 pub struct EspSntp {
     // Needs to be kept around because the C bindings only have a pointer.
@@ -247,6 +258,8 @@ impl EspSntp {
             (*tv).tv_usec,
         );
 
+        *LAST_SYNC.lock() = Some(Duration::from_micros(esp_timer_get_time() as u64));
+
         #[cfg(feature = "alloc")]
         if let Some(cb) = &mut *SYNC_CB.lock() {
             let duration = Duration::from_secs((*tv).tv_sec as u64)
@@ -0,0 +1,267 @@
+//! SNTP server
+//!
+//! A small (S)NTP server (RFC 4330) answering the time requests of the hosts of isolated
+//! networks - e.g. sensors connected to the SoftAP of a gateway without internet access - with
+//! the system time of the device, so that their timestamps stay consistent with each other.
+//!
+//! The system time should be kept accurate by other means, e.g. by an RTC, a GPS receiver or a
+//! `timesync::TimeDiscipline`. The server does not answer until it is told that the system time
+//! is synchronized, so that the clients are not set to the epoch after a reboot:
+//!
+//! ```ignore
+//! let server = EspSntpServer::new(&Configuration {
+//!     stratum: 1,
+//!     reference_id: *b"GPS\0",
+//!     ..Default::default()
+//! })?;
+//!
+//! sysloop.subscribe(move |fix: &gps::Fix| {
+//!     if fix.quality.is_valid() {
+//!         server.set_synchronized();
+//!     }
+//! })?;
+//! ```
+//!
+//! The clients of the network are pointed to the server with the NTP server option of the
+//! DHCP server of the SoftAP, or their own SNTP configuration.
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::private::mutex::Mutex;
+use crate::systime::EspSystemTime;
+
+/// The seconds between the NTP era (1900) and the UNIX epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+const PACKET_LEN: usize = 48;
+
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+
+/// The precision of the system time, as a power of two: microseconds
+const PRECISION: i8 = -20;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub port: u16,
+    /// 1 for a server disciplined by a reference clock (GPS), one more than the stratum of the
+    /// upstream server otherwise
+    pub stratum: u8,
+    /// The reference clock for stratum 1 servers (e.g. `GPS`, `PPS`), or the IPv4 address of
+    /// the upstream server
+    pub reference_id: [u8; 4],
+    /// The estimated error of the system time, reported to the clients as the root dispersion
+    pub dispersion: Duration,
+    /// Answer even when the system time has not been marked as synchronized
+    pub serve_unsynchronized: bool,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            port: 123,
+            stratum: 2,
+            reference_id: *b"LOCL",
+            dispersion: Duration::from_millis(10),
+            serve_unsynchronized: false,
+            stack_size: 3072,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// The system time of the last synchronization
+    synchronized_at: Option<Duration>,
+    requests: u64,
+}
+
+pub struct EspSntpServer {
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspSntpServer {
+    pub fn new(conf: &Configuration) -> Result<Self, EspError> {
+        if conf.stratum == 0 || conf.stratum > 15 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, conf.port))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_STATE>())?;
+
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+
+        let state = Arc::new(Mutex::new(State::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let join_handle = {
+            let conf = conf.clone();
+            let state = state.clone();
+            let stop = stop.clone();
+
+            thread::Builder::new()
+                .name("sntp-server".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, socket, state, stop))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        info!("Started on port {}", conf.port);
+
+        Ok(Self {
+            state,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// Marks the system time as synchronized with the reference, now
+    ///
+    /// To be called after each synchronization, as the time of the last one is reported to the
+    /// clients.
+    pub fn set_synchronized(&self) {
+        self.state.lock().synchronized_at = Some(EspSystemTime.now());
+    }
+
+    /// Stops answering the clients, e.g. when the reference has been lost for too long
+    pub fn set_unsynchronized(&self) {
+        self.state.lock().synchronized_at = None;
+    }
+
+    pub fn is_synchronized(&self) -> bool {
+        self.state.lock().synchronized_at.is_some()
+    }
+
+    /// The number of requests answered so far
+    pub fn requests(&self) -> u64 {
+        self.state.lock().requests
+    }
+
+    fn run(
+        conf: Configuration,
+        socket: UdpSocket,
+        state: Arc<Mutex<State>>,
+        stop: Arc<AtomicBool>,
+    ) {
+        let mut buf = [0_u8; 128];
+
+        while !stop.load(Ordering::SeqCst) {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    warn!("Receive failed: {}", e);
+                    thread::sleep(Duration::from_millis(500));
+                    continue;
+                }
+            };
+
+            let received_at = EspSystemTime.now();
+
+            if len < PACKET_LEN || buf[0] & 0x07 != MODE_CLIENT {
+                continue;
+            }
+
+            let synchronized_at = {
+                let mut state = state.lock();
+
+                if state.synchronized_at.is_none() && !conf.serve_unsynchronized {
+                    debug!("Ignoring the request of {}: not synchronized", peer);
+                    continue;
+                }
+
+                state.requests += 1;
+                state.synchronized_at
+            };
+
+            let response = Self::response(&conf, &buf[..PACKET_LEN], synchronized_at, received_at);
+
+            if let Err(e) = socket.send_to(&response, peer) {
+                warn!("Failed to answer {}: {}", peer, e);
+            }
+        }
+    }
+
+    fn response(
+        conf: &Configuration,
+        request: &[u8],
+        synchronized_at: Option<Duration>,
+        received_at: Duration,
+    ) -> [u8; PACKET_LEN] {
+        let mut response = [0_u8; PACKET_LEN];
+
+        // Leap indicator: no warning, or unknown (unsynchronized)
+        let leap = if synchronized_at.is_some() { 0 } else { 3 };
+        // Answer with the version of the client
+        let version = (request[0] >> 3) & 0x07;
+
+        response[0] = (leap << 6) | (version << 3) | MODE_SERVER;
+        response[1] = conf.stratum;
+        // Poll interval: the one of the client
+        response[2] = request[2];
+        response[3] = PRECISION as u8;
+
+        // Root delay: 0, root dispersion: 16.16 fixed point seconds
+        let dispersion = (conf.dispersion.as_micros() as u64 * 65536 / 1_000_000) as u32;
+        response[8..12].copy_from_slice(&dispersion.to_be_bytes());
+
+        response[12..16].copy_from_slice(&conf.reference_id);
+        response[16..24].copy_from_slice(&Self::timestamp(synchronized_at.unwrap_or_default()));
+        // Originate: the transmit timestamp of the request
+        response[24..32].copy_from_slice(&request[40..48]);
+        response[32..40].copy_from_slice(&Self::timestamp(received_at));
+        response[40..48].copy_from_slice(&Self::timestamp(EspSystemTime.now()));
+
+        response
+    }
+
+    /// An NTP timestamp: seconds since 1900, and 32 bits of fractional seconds
+    fn timestamp(time: Duration) -> [u8; 8] {
+        if time == Duration::ZERO {
+            return [0; 8];
+        }
+
+        let secs = (time.as_secs() + NTP_UNIX_OFFSET) as u32;
+        let fraction = ((time.subsec_nanos() as u64) << 32) / 1_000_000_000;
+
+        let mut timestamp = [0_u8; 8];
+        timestamp[..4].copy_from_slice(&secs.to_be_bytes());
+        timestamp[4..].copy_from_slice(&(fraction as u32).to_be_bytes());
+
+        timestamp
+    }
+}
+
+impl Drop for EspSntpServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
@@ -0,0 +1,371 @@
+//! SOCKS5 client
+//!
+//! `connect` opens a TCP connection through a SOCKS5 proxy (RFC 1928), optionally
+//! authenticating with a username and a password (RFC 1929). With `Configuration::remote_dns`,
+//! the host name is resolved by the proxy, so that no DNS query leaks out of it:
+//!
+//! ```ignore
+//! let conf = Configuration {
+//!     proxy: "10.0.0.1:1080".into(),
+//!     credentials: Some(("device".into(), "secret".into())),
+//!     ..Default::default()
+//! };
+//!
+//! let mut stream = socks5::connect(&conf, "example.com", 80)?;
+//! ```
+//!
+//! The HTTP, MQTT and WebSocket clients of ESP-IDF open their own sockets, so they are
+//! tunneled with `EspSocks5Forwarder` instead: it listens on a local port, and forwards every
+//! connection it accepts to the target, through the proxy. The clients then connect to the
+//! local port:
+//!
+//! ```ignore
+//! let forwarder = EspSocks5Forwarder::new(&conf, "broker.example.com", 8883)?;
+//!
+//! let url = format!("mqtts://127.0.0.1:{}", forwarder.port());
+//! ```
+//!
+//! Over TLS, the server name the clients send and check is then `127.0.0.1`, so the clients
+//! must be set up not to check it (e.g. `skip_cert_common_name_check` of the MQTT client),
+//! and the servers must not depend on SNI.
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::string::String;
+use std::sync::Arc;
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::private::authority::Authority;
+use crate::tcp;
+
+const VERSION: u8 = 0x05;
+
+const METHOD_NONE: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+
+const COMMAND_CONNECT: u8 = 0x01;
+
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The proxy, as in `host:port` or `[ipv6]:port`
+    pub proxy: String,
+    /// The username and password, if the proxy requires them
+    pub credentials: Option<(String, String)>,
+    /// Have the proxy resolve the host names, rather than resolving them locally
+    pub remote_dns: bool,
+    pub timeout: Duration,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            proxy: String::new(),
+            credentials: None,
+            remote_dns: true,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Connects to `host:port` through the proxy
+pub fn connect(conf: &Configuration, host: &str, port: u16) -> io::Result<TcpStream> {
+    let (proxy_host, proxy_port) = Authority::parse(&conf.proxy)
+        .and_then(|proxy| Some((proxy.host, proxy.port?)))
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Not a host:port proxy"))?;
+
+    // Dual-stack proxies get all their addresses tried
+    let mut stream = tcp::connect(
+        proxy_host,
+        proxy_port,
+        &tcp::Configuration {
+            timeout: conf.timeout,
+            ..Default::default()
+        },
+    )?;
+
+    stream.set_read_timeout(Some(conf.timeout))?;
+    stream.set_write_timeout(Some(conf.timeout))?;
+
+    authenticate(conf, &mut stream)?;
+
+    request(conf, &mut stream, host, port)?;
+
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+
+    debug!("Connected to {}:{} through {}", host, port, conf.proxy);
+
+    Ok(stream)
+}
+
+fn authenticate(conf: &Configuration, stream: &mut TcpStream) -> io::Result<()> {
+    let method = if conf.credentials.is_some() {
+        METHOD_PASSWORD
+    } else {
+        METHOD_NONE
+    };
+
+    stream.write_all(&[VERSION, 1, method])?;
+
+    let mut reply = [0_u8; 2];
+    stream.read_exact(&mut reply)?;
+
+    if reply[0] != VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Not a SOCKS5 proxy"));
+    }
+
+    if reply[1] == METHOD_UNACCEPTABLE || reply[1] != method {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "The proxy does not accept the authentication method",
+        ));
+    }
+
+    if let Some((username, password)) = &conf.credentials {
+        let username_len: u8 = username.len().try_into().map_err(|_| too_long())?;
+        let password_len: u8 = password.len().try_into().map_err(|_| too_long())?;
+
+        let mut message = Vec::with_capacity(3 + username.len() + password.len());
+
+        message.push(0x01);
+        message.push(username_len);
+        message.extend_from_slice(username.as_bytes());
+        message.push(password_len);
+        message.extend_from_slice(password.as_bytes());
+
+        stream.write_all(&message)?;
+
+        stream.read_exact(&mut reply)?;
+
+        if reply[1] != 0 {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "The proxy rejected the credentials",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn request(conf: &Configuration, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+    let mut message = vec![VERSION, COMMAND_CONNECT, 0];
+
+    let address = host.parse::<IpAddr>().ok().or_else(|| {
+        if conf.remote_dns {
+            None
+        } else {
+            (host, port)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .map(|addr| addr.ip())
+        }
+    });
+
+    match address {
+        Some(IpAddr::V4(address)) => {
+            message.push(ADDRESS_IPV4);
+            message.extend_from_slice(&address.octets());
+        }
+        Some(IpAddr::V6(address)) => {
+            message.push(ADDRESS_IPV6);
+            message.extend_from_slice(&address.octets());
+        }
+        None if conf.remote_dns => {
+            let host_len: u8 = host.len().try_into().map_err(|_| too_long())?;
+
+            message.push(ADDRESS_DOMAIN);
+            message.push(host_len);
+            message.extend_from_slice(host.as_bytes());
+        }
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                "Cannot resolve the host",
+            ))
+        }
+    }
+
+    message.extend_from_slice(&port.to_be_bytes());
+
+    stream.write_all(&message)?;
+
+    let mut reply = [0_u8; 4];
+    stream.read_exact(&mut reply)?;
+
+    if reply[1] != 0 {
+        return Err(io::Error::new(
+            reply_error_kind(reply[1]),
+            reply_message(reply[1]),
+        ));
+    }
+
+    // The address the proxy bound, which is of no use for a CONNECT
+    let bound_len = match reply[3] {
+        ADDRESS_IPV4 => 4,
+        ADDRESS_IPV6 => 16,
+        ADDRESS_DOMAIN => {
+            let mut len = [0_u8; 1];
+            stream.read_exact(&mut len)?;
+
+            len[0] as usize
+        }
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Invalid SOCKS5 reply",
+            ))
+        }
+    };
+
+    let mut bound = vec![0_u8; bound_len + 2];
+    stream.read_exact(&mut bound)?;
+
+    Ok(())
+}
+
+fn reply_error_kind(reply: u8) -> ErrorKind {
+    match reply {
+        0x02 => ErrorKind::PermissionDenied,
+        0x05 => ErrorKind::ConnectionRefused,
+        0x06 => ErrorKind::TimedOut,
+        0x07 | 0x08 => ErrorKind::Unsupported,
+        _ => ErrorKind::Other,
+    }
+}
+
+fn reply_message(reply: u8) -> &'static str {
+    match reply {
+        0x01 => "General SOCKS server failure",
+        0x02 => "Connection not allowed by ruleset",
+        0x03 => "Network unreachable",
+        0x04 => "Host unreachable",
+        0x05 => "Connection refused",
+        0x06 => "TTL expired",
+        0x07 => "Command not supported",
+        0x08 => "Address type not supported",
+        _ => "Unknown SOCKS5 error",
+    }
+}
+
+fn too_long() -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, "Longer than 255 bytes")
+}
+
+/// Forwards the connections to a local port to a target, through a SOCKS5 proxy
+pub struct EspSocks5Forwarder {
+    port: u16,
+    running: Arc<AtomicBool>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspSocks5Forwarder {
+    /// Listens on a free local port, to be used by the clients
+    pub fn new(conf: &Configuration, host: &str, port: u16) -> Result<Self, EspError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+
+        let local_port = listener
+            .local_addr()
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?
+            .port();
+
+        let running = Arc::new(AtomicBool::new(true));
+
+        let join_handle = {
+            let conf = conf.clone();
+            let host: String = host.into();
+            let running = running.clone();
+
+            thread::Builder::new()
+                .name("socks5".into())
+                .stack_size(4096)
+                .spawn(move || Self::run(conf, host, port, listener, running))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        info!(
+            "Forwarding 127.0.0.1:{} to {}:{} through {}",
+            local_port, host, port, conf.proxy
+        );
+
+        Ok(Self {
+            port: local_port,
+            running,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// The local port
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, self.port).into()
+    }
+
+    fn run(
+        conf: Configuration,
+        host: String,
+        port: u16,
+        listener: TcpListener,
+        running: Arc<AtomicBool>,
+    ) {
+        for client in listener.incoming() {
+            if !running.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let client = match client {
+                Ok(client) => client,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+
+            let upstream = match connect(&conf, &host, port) {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    warn!("Failed to connect to {}:{}: {}", host, port, e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = tcp::relay("socks5", client, upstream) {
+                warn!("Failed to relay a connection: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for EspSocks5Forwarder {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+
+        // Wakes the accepting thread up
+        let _ = TcpStream::connect(self.local_addr());
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
@@ -0,0 +1,476 @@
+//! Service supervisor
+//!
+//! `Supervisor` owns the services of the application (WiFi, SNTP, MQTT, the HTTP server...),
+//! starts them in the order of their declared dependencies, and restarts them with an
+//! exponential backoff when they fail to start or become unhealthy. When a service is
+//! restarted, the services depending on it are restarted too:
+//!
+//! ```ignore
+//! let mut supervisor = Supervisor::new(&Default::default());
+//!
+//! supervisor
+//!     .register("wifi", &[], move || connect_wifi(), |wifi: &mut BlockingWifi<EspWifi>| {
+//!         wifi.is_connected().unwrap_or(false)
+//!     })
+//!     .register("sntp", &["wifi"], || EspSntp::new_default(), supervisor::healthy)
+//!     .register("mqtt", &["wifi", "sntp"], move || connect_mqtt(), supervisor::healthy);
+//!
+//! supervisor.start()?;
+//!
+//! loop {
+//!     info!("Health: {:?}", supervisor.health());
+//!     thread::sleep(Duration::from_secs(60));
+//! }
+//! ```
+//!
+//! Services are stopped by dropping them, as all services of this crate do.
+//!
+//! The supervisor thread can be subscribed to the task watchdog, which then restarts the
+//! chip if the supervisor gets stuck; the start functions should then not block for longer
+//! than the watchdog timeout.
+use core::any::Any;
+use core::ptr;
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Instant;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::private::mutex::Mutex;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// How often the health of the services is checked
+    pub check_interval: Duration,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How long a service must be running for its backoff to be reset
+    pub stable_after: Duration,
+    /// Subscribe the supervisor thread to the task watchdog
+    pub watchdog: bool,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(1),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+            stable_after: Duration::from_secs(60),
+            watchdog: false,
+            stack_size: 8192,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum ServiceState {
+    /// Waiting for its dependencies
+    Waiting,
+    Running,
+    /// Failed to start or became unhealthy, and waiting for its next start attempt
+    Failed {
+        failures: u32,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Health {
+    /// All services are running
+    Healthy,
+    /// Some services are not running
+    Degraded,
+    /// No service is running
+    Unhealthy,
+}
+
+/// A health check for services which are healthy as long as they are running
+pub fn healthy<S>(_service: &mut S) -> bool {
+    true
+}
+
+type Factory = Box<dyn FnMut() -> Result<Box<dyn Any + Send>, EspError> + Send>;
+type HealthCheck = Box<dyn FnMut(&mut (dyn Any + Send)) -> bool + Send>;
+
+struct Entry {
+    name: &'static str,
+    depends_on: Vec<&'static str>,
+    /// The indexes of `depends_on`, once sorted
+    dependencies: Vec<usize>,
+    factory: Factory,
+    health: HealthCheck,
+    instance: Option<Box<dyn Any + Send>>,
+    failures: u32,
+    retry_at: Option<Instant>,
+    started_at: Option<Instant>,
+}
+
+type States = Arc<Mutex<Vec<(&'static str, ServiceState)>>>;
+
+pub struct Supervisor {
+    conf: Configuration,
+    entries: Vec<Entry>,
+    states: States,
+    stop: Option<mpsc::Sender<()>>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Supervisor {
+    pub fn new(conf: &Configuration) -> Self {
+        Self {
+            conf: conf.clone(),
+            entries: Vec::new(),
+            states: Arc::new(Mutex::new(Vec::new())),
+            stop: None,
+            join_handle: None,
+        }
+    }
+
+    /// Registers a service, created by `start` once all the services named in
+    /// `depends_on` are running, and restarted whenever `health` returns `false`
+    ///
+    /// Services must be registered before the supervisor is started.
+    pub fn register<S, F, H>(
+        &mut self,
+        name: &'static str,
+        depends_on: &[&'static str],
+        mut start: F,
+        mut health: H,
+    ) -> &mut Self
+    where
+        S: Send + 'static,
+        F: FnMut() -> Result<S, EspError> + Send + 'static,
+        H: FnMut(&mut S) -> bool + Send + 'static,
+    {
+        self.entries.push(Entry {
+            name,
+            depends_on: depends_on.to_vec(),
+            dependencies: Vec::new(),
+            factory: Box::new(move || start().map(|service| Box::new(service) as _)),
+            health: Box::new(move |service| {
+                service
+                    .downcast_mut::<S>()
+                    .map(|service| health(service))
+                    .unwrap_or(false)
+            }),
+            instance: None,
+            failures: 0,
+            retry_at: None,
+            started_at: None,
+        });
+
+        self
+    }
+
+    /// Sorts the services by their dependencies and starts supervising them
+    ///
+    /// Fails with `ESP_ERR_INVALID_ARG` on unknown, duplicate or circular dependencies.
+    pub fn start(&mut self) -> Result<(), EspError> {
+        if self.join_handle.is_some() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let mut entries = Self::sort(core::mem::take(&mut self.entries))?;
+
+        *self.states.lock() = entries
+            .iter()
+            .map(|entry| (entry.name, ServiceState::Waiting))
+            .collect();
+
+        let (stop, stopped) = mpsc::channel();
+
+        let join_handle = {
+            let conf = self.conf.clone();
+            let states = self.states.clone();
+
+            thread::Builder::new()
+                .name("supervisor".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || {
+                    Self::run(&conf, &mut entries, &states, stopped);
+
+                    // Dependents first
+                    for entry in entries.iter_mut().rev() {
+                        entry.instance = None;
+                    }
+                })
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        self.stop = Some(stop);
+        self.join_handle = Some(join_handle);
+
+        info!("Started supervisor");
+
+        Ok(())
+    }
+
+    pub fn state(&self, name: &str) -> Option<ServiceState> {
+        self.states
+            .lock()
+            .iter()
+            .find(|(service, _)| *service == name)
+            .map(|(_, state)| *state)
+    }
+
+    /// The states of all services, in their start order
+    pub fn states(&self) -> Vec<(&'static str, ServiceState)> {
+        self.states.lock().clone()
+    }
+
+    pub fn health(&self) -> Health {
+        let states = self.states.lock();
+
+        let running = states
+            .iter()
+            .filter(|(_, state)| *state == ServiceState::Running)
+            .count();
+
+        if running == states.len() {
+            Health::Healthy
+        } else if running > 0 {
+            Health::Degraded
+        } else {
+            Health::Unhealthy
+        }
+    }
+
+    fn sort(mut entries: Vec<Entry>) -> Result<Vec<Entry>, EspError> {
+        let invalid = EspError::from_infallible::<ESP_ERR_INVALID_ARG>;
+
+        for (index, entry) in entries.iter().enumerate() {
+            let duplicate = entries[..index]
+                .iter()
+                .any(|other| other.name == entry.name);
+
+            let unknown = entry
+                .depends_on
+                .iter()
+                .find(|name| !entries.iter().any(|other| other.name == **name));
+
+            if duplicate {
+                error!("Service {} is registered twice", entry.name);
+                return Err(invalid());
+            }
+
+            if let Some(unknown) = unknown {
+                error!(
+                    "Service {} depends on unknown service {}",
+                    entry.name, unknown
+                );
+                return Err(invalid());
+            }
+        }
+
+        let mut sorted = Vec::<Entry>::with_capacity(entries.len());
+
+        while !entries.is_empty() {
+            let ready = entries.iter().position(|entry| {
+                entry
+                    .depends_on
+                    .iter()
+                    .all(|name| sorted.iter().any(|started| started.name == *name))
+            });
+
+            if let Some(ready) = ready {
+                sorted.push(entries.remove(ready));
+            } else {
+                error!(
+                    "Circular dependencies between services {:?}",
+                    entries.iter().map(|entry| entry.name).collect::<Vec<_>>()
+                );
+                return Err(invalid());
+            }
+        }
+
+        for index in 0..sorted.len() {
+            let dependencies = sorted[index]
+                .depends_on
+                .iter()
+                .map(|name| sorted.iter().position(|entry| entry.name == *name).unwrap())
+                .collect();
+
+            sorted[index].dependencies = dependencies;
+        }
+
+        Ok(sorted)
+    }
+
+    fn run(
+        conf: &Configuration,
+        entries: &mut [Entry],
+        states: &Mutex<Vec<(&'static str, ServiceState)>>,
+        stopped: mpsc::Receiver<()>,
+    ) {
+        let watchdog =
+            conf.watchdog && Self::check_watchdog(unsafe { esp_task_wdt_add(ptr::null_mut()) });
+
+        loop {
+            for index in 0..entries.len() {
+                Self::supervise(conf, entries, index);
+            }
+
+            {
+                let mut states = states.lock();
+
+                for (state, entry) in states.iter_mut().zip(entries.iter()) {
+                    state.1 = Self::service_state(entry);
+                }
+            }
+
+            if watchdog {
+                Self::check_watchdog(unsafe { esp_task_wdt_reset() });
+            }
+
+            match stopped.recv_timeout(conf.check_interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                _ => break,
+            }
+        }
+
+        if watchdog {
+            Self::check_watchdog(unsafe { esp_task_wdt_delete(ptr::null_mut()) });
+        }
+    }
+
+    fn supervise(conf: &Configuration, entries: &mut [Entry], index: usize) {
+        let now = Instant::now();
+
+        let ready = entries[index]
+            .dependencies
+            .iter()
+            .all(|dependency| entries[*dependency].instance.is_some());
+
+        let entry = &mut entries[index];
+
+        if let Some(instance) = entry.instance.as_mut() {
+            if (entry.health)(instance.as_mut()) {
+                let stable = entry
+                    .started_at
+                    .map(|started_at| now - started_at >= conf.stable_after)
+                    .unwrap_or(false);
+
+                if stable && entry.failures > 0 {
+                    debug!("Service {} is stable again", entry.name);
+
+                    entry.failures = 0;
+                }
+
+                return;
+            }
+
+            warn!("Service {} is unhealthy, restarting", entry.name);
+
+            Self::stop_dependents(entries, index);
+            Self::fail(conf, &mut entries[index], now);
+        } else if ready && entry.retry_at.map(|at| now >= at).unwrap_or(true) {
+            info!("Starting service {}", entry.name);
+
+            match (entry.factory)() {
+                Ok(instance) => {
+                    entry.instance = Some(instance);
+                    entry.started_at = Some(now);
+                    entry.retry_at = None;
+                }
+                Err(e) => {
+                    warn!("Failed to start service {}: {}", entry.name, e);
+
+                    Self::fail(conf, entry, now);
+                }
+            }
+        }
+    }
+
+    /// Stops the services depending, directly or not, on the service at `index`
+    fn stop_dependents(entries: &mut [Entry], index: usize) {
+        let mut stopped = vec![false; entries.len()];
+        stopped[index] = true;
+
+        // The entries are sorted, so all dependents come after
+        for dependent in index + 1..entries.len() {
+            stopped[dependent] = entries[dependent]
+                .dependencies
+                .iter()
+                .any(|dependency| stopped[*dependency]);
+        }
+
+        for dependent in (index + 1..entries.len()).rev() {
+            if stopped[dependent] && entries[dependent].instance.is_some() {
+                info!(
+                    "Stopping service {}, which depends on {}",
+                    entries[dependent].name, entries[index].name
+                );
+
+                entries[dependent].instance = None;
+                entries[dependent].started_at = None;
+            }
+        }
+    }
+
+    fn fail(conf: &Configuration, entry: &mut Entry, now: Instant) {
+        entry.instance = None;
+        entry.started_at = None;
+        entry.failures += 1;
+
+        let backoff = conf
+            .min_backoff
+            .checked_mul(1 << (entry.failures - 1).min(16))
+            .unwrap_or(conf.max_backoff)
+            .min(conf.max_backoff);
+
+        info!(
+            "Retrying service {} in {:?} (failure {})",
+            entry.name, backoff, entry.failures
+        );
+
+        entry.retry_at = Some(now + backoff);
+    }
+
+    fn service_state(entry: &Entry) -> ServiceState {
+        if entry.instance.is_some() {
+            ServiceState::Running
+        } else if entry.failures > 0 {
+            ServiceState::Failed {
+                failures: entry.failures,
+            }
+        } else {
+            ServiceState::Waiting
+        }
+    }
+
+    fn check_watchdog(err: esp_err_t) -> bool {
+        if let Err(e) = esp!(err) {
+            warn!("Task watchdog error: {}", e);
+
+            false
+        } else {
+            true
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
@@ -0,0 +1,222 @@
+//! Structured shutdown
+//!
+//! Restarting with `esp_restart` while services are still active leaves the MQTT broker to
+//! publish the last will of the device (and its retained messages to go stale until the device
+//! is back), flash writes half done, and HTTP clients with reset connections.
+//!
+//! `reboot_after` restarts the device only once the services are shut down: services register
+//! a shutdown hook with `on_shutdown`, which `reboot_after` runs on their own thread, and waits
+//! for - up to a timeout - after having posted `ShutdownEvent::Started` on the system event loop:
+//!
+//! ```ignore
+//! let client = Arc::new(Mutex::new(EspMqttClient::new(url, &conf, |_| {})?));
+//!
+//! let _mqtt_hook = system::on_shutdown("mqtt", {
+//!     let client = client.clone();
+//!
+//!     // Disconnect gracefully, so that the broker does not publish the last will
+//!     move || client.lock().unwrap().disconnect().unwrap()
+//! });
+//!
+//! let _server_hook = system::on_shutdown("http", move || drop(server));
+//!
+//! ...
+//!
+//! system::reboot_after(|| {
+//!     nvs.set_u32("boots", boots + 1).unwrap();
+//! });
+//! ```
+//!
+//! `EspNvs` commits on every `set_*` call, so NVS needs no hook once the pending writes are done,
+//! which is what `cleanup` is for. Dropping an `EspHttpServer` in a hook stops it gracefully.
+use core::ffi;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspTypedEventDeserializer, EspTypedEventSerializer,
+    EspTypedEventSource,
+};
+use crate::private::mutex::{Mutex, RawMutex};
+
+/// How long `reboot_after` waits for the shutdown hooks
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The stack size of the threads running the shutdown hooks
+const HOOK_STACK_SIZE: usize = 4096;
+
+type Hook = Box<dyn FnOnce() + Send + 'static>;
+
+static HOOKS: Mutex<Vec<(u32, &'static str, Hook)>> = Mutex::wrap(RawMutex::new(), Vec::new());
+
+static NEXT_HOOK_ID: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum ShutdownEvent {
+    /// The shutdown hooks are about to run
+    Started,
+    /// All the hooks completed, or the timeout expired; the device restarts right after
+    Completed { pending: u32 },
+}
+
+impl EspTypedEventSource for ShutdownEvent {
+    fn source() -> *const ffi::c_char {
+        b"ESP-SHUTDOWN\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<ShutdownEvent> for ShutdownEvent {
+    fn serialize<R>(event: &ShutdownEvent, f: impl for<'a> FnOnce(&'a EspEventPostData) -> R) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<ShutdownEvent> for ShutdownEvent {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a ShutdownEvent) -> R,
+    ) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+/// A registered shutdown hook, which is unregistered when dropped
+#[must_use]
+#[derive(Debug)]
+pub struct ShutdownHook(u32);
+
+impl Drop for ShutdownHook {
+    fn drop(&mut self) {
+        HOOKS.lock().retain(|(id, _, _)| *id != self.0);
+    }
+}
+
+/// Registers `hook`, to be run by `shutdown` before the device restarts
+///
+/// The hooks run concurrently, so they should not depend on each other.
+pub fn on_shutdown<F>(name: &'static str, hook: F) -> ShutdownHook
+where
+    F: FnOnce() + Send + 'static,
+{
+    let id = NEXT_HOOK_ID.fetch_add(1, Ordering::SeqCst);
+
+    HOOKS.lock().push((id, name, Box::new(hook)));
+
+    ShutdownHook(id)
+}
+
+/// Runs the registered shutdown hooks, and waits up to `timeout` for them to complete
+///
+/// Returns the names of the hooks which did not complete in time. The hooks are unregistered,
+/// so calling `shutdown` again only runs the hooks registered since.
+pub fn shutdown(timeout: Duration) -> Vec<&'static str> {
+    let hooks = core::mem::take(&mut *HOOKS.lock());
+
+    post(&ShutdownEvent::Started);
+
+    info!("Shutting down {} services", hooks.len());
+
+    let (sender, receiver) = mpsc::channel();
+
+    let mut pending = Vec::new();
+
+    for (_, name, hook) in hooks {
+        let sender = sender.clone();
+
+        let spawned = thread::Builder::new()
+            .name("shutdown".into())
+            .stack_size(HOOK_STACK_SIZE)
+            .spawn(move || {
+                hook();
+
+                let _ = sender.send(name);
+            });
+
+        match spawned {
+            Ok(_) => pending.push(name),
+            Err(e) => warn!("Failed to run the shutdown hook {}: {}", name, e),
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+
+    while !pending.is_empty() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        match receiver.recv_timeout(remaining) {
+            Ok(name) => {
+                debug!("Shut down {}", name);
+
+                if let Some(index) = pending.iter().position(|pending| *pending == name) {
+                    pending.remove(index);
+                }
+            }
+            Err(_) => break,
+        }
+    }
+
+    if !pending.is_empty() {
+        warn!("Shutdown timed out, pending: {:?}", pending);
+    }
+
+    post(&ShutdownEvent::Completed {
+        pending: pending.len() as u32,
+    });
+
+    pending
+}
+
+/// Calls `cleanup`, shuts down the services with `shutdown`, and restarts the device
+pub fn reboot_after<F>(cleanup: F) -> !
+where
+    F: FnOnce(),
+{
+    reboot_after_timeout(DEFAULT_TIMEOUT, cleanup)
+}
+
+/// Like `reboot_after`, waiting up to `timeout` for the shutdown hooks
+pub fn reboot_after_timeout<F>(timeout: Duration, cleanup: F) -> !
+where
+    F: FnOnce(),
+{
+    cleanup();
+
+    shutdown(timeout);
+
+    info!("Restarting");
+
+    unsafe { esp_restart() };
+
+    unreachable!()
+}
+
+/// Posts `event` on the system event loop, if it is created
+fn post(event: &ShutdownEvent) {
+    let result = ShutdownEvent::serialize(event, |data| {
+        esp!(unsafe {
+            esp_event_post(
+                data.source,
+                data.event_id,
+                data.payload as *mut _,
+                data.payload_len as _,
+                0,
+            )
+        })
+    });
+
+    if let Err(e) = result {
+        debug!("Failed to post the shutdown event: {}", e);
+    }
+}
@@ -0,0 +1,445 @@
+//! TCP client connections to dual-stack hosts
+//!
+//! `connect` resolves both the IPv6 and the IPv4 addresses of a host, and races the connection
+//! attempts to them, Happy Eyeballs style (RFC 8305): the addresses are interleaved by family,
+//! preferring IPv6, and each attempt gets a head start of `Configuration::attempt_delay` before
+//! the next one is started in parallel. The first attempt to succeed wins, and the others are
+//! abandoned, so a network with broken IPv6 costs a quarter of a second rather than a timeout:
+//!
+//! ```ignore
+//! let mut stream = tcp::connect("example.com", 80, &Configuration::default())?;
+//! ```
+//!
+//! The HTTP, MQTT and WebSocket clients of ESP-IDF connect their own sockets, and do not use it.
+use core::mem;
+use core::ptr;
+use core::time::Duration;
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+#[cfg(esp_idf_lwip_ipv6)]
+use std::net::{Ipv6Addr, SocketAddrV6};
+use std::os::unix::io::FromRawFd;
+use std::string::String;
+use std::thread;
+use std::time::Instant;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::private::cstr::CString;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The head start of an attempt before the next one is started (RFC 8305 recommends 250 ms)
+    pub attempt_delay: Duration,
+    /// The time after which the connection fails if no attempt has succeeded
+    pub timeout: Duration,
+    /// Attempts the IPv6 addresses first
+    pub prefer_ipv6: bool,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            attempt_delay: Duration::from_millis(250),
+            timeout: Duration::from_secs(10),
+            prefer_ipv6: true,
+        }
+    }
+}
+
+/// Connects to `host` (a host name, or an IPv4 or an IPv6 address) and `port`
+pub fn connect(host: &str, port: u16, conf: &Configuration) -> io::Result<TcpStream> {
+    let addrs = resolve(host, port, conf.prefer_ipv6)?;
+
+    connect_to(&addrs, conf)
+}
+
+/// The addresses of `host`, interleaved by family, in the order `connect` attempts them
+pub fn resolve(host: &str, port: u16, prefer_ipv6: bool) -> io::Result<Vec<SocketAddr>> {
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+
+    if let Ok(ip) = literal.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    #[cfg(esp_idf_lwip_ipv6)]
+    let ipv6 = lookup(host, port, AF_INET6)?;
+    #[cfg(not(esp_idf_lwip_ipv6))]
+    let ipv6 = Vec::new();
+
+    let ipv4 = lookup(host, port, AF_INET)?;
+
+    let (first, second) = if prefer_ipv6 {
+        (ipv6, ipv4)
+    } else {
+        (ipv4, ipv6)
+    };
+
+    let mut addrs = Vec::with_capacity(first.len() + second.len());
+
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    if addrs.is_empty() {
+        Err(io::Error::new(
+            ErrorKind::NotFound,
+            "Cannot resolve the host",
+        ))
+    } else {
+        Ok(addrs)
+    }
+}
+
+/// Races the connection attempts to `addrs`, in their order
+pub fn connect_to(addrs: &[SocketAddr], conf: &Configuration) -> io::Result<TcpStream> {
+    let deadline = Instant::now() + conf.timeout;
+
+    let mut pending = addrs.iter();
+    let mut attempts: Vec<Attempt> = Vec::new();
+    let mut next_attempt = Instant::now();
+    let mut last_error = None;
+
+    loop {
+        let now = Instant::now();
+
+        if now >= deadline {
+            return Err(io::Error::new(ErrorKind::TimedOut, "Connecting timed out"));
+        }
+
+        if now >= next_attempt {
+            if let Some(addr) = pending.next() {
+                match Attempt::start(*addr) {
+                    Ok(attempt) => attempts.push(attempt),
+                    Err(e) => {
+                        debug!("Connecting to {} failed: {}", addr, e);
+                        last_error = Some(e);
+
+                        // On to the next address straight away
+                        continue;
+                    }
+                }
+
+                next_attempt = now + conf.attempt_delay;
+            }
+        }
+
+        if attempts.is_empty() {
+            if pending.len() == 0 {
+                return Err(
+                    last_error.unwrap_or_else(|| io::Error::new(ErrorKind::NotFound, "No address"))
+                );
+            }
+
+            next_attempt = now;
+            continue;
+        }
+
+        let wake = if pending.len() > 0 {
+            next_attempt.min(deadline)
+        } else {
+            deadline
+        };
+
+        let ready = select(&attempts, wake.saturating_duration_since(now))?;
+
+        for index in ready.into_iter().rev() {
+            let attempt = attempts.swap_remove(index);
+
+            match attempt.error() {
+                Ok(()) => {
+                    debug!("Connected to {}", attempt.addr);
+
+                    // The other attempts are closed on dropping
+                    return attempt.into_stream();
+                }
+                Err(e) => {
+                    debug!("Connecting to {} failed: {}", attempt.addr, e);
+                    last_error = Some(e);
+
+                    next_attempt = now;
+                }
+            }
+        }
+    }
+}
+
+/// A non-blocking connection attempt
+struct Attempt {
+    fd: i32,
+    addr: SocketAddr,
+}
+
+impl Attempt {
+    fn start(addr: SocketAddr) -> io::Result<Self> {
+        let family = match addr {
+            SocketAddr::V4(_) => AF_INET,
+            SocketAddr::V6(_) => AF_INET6,
+        };
+
+        let fd = unsafe { lwip_socket(family as _, SOCK_STREAM as _, IPPROTO_TCP as _) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // From here on, `fd` is closed on error by `Drop`
+        let attempt = Self { fd, addr };
+
+        attempt.set_nonblocking(true)?;
+
+        if attempt.connect() < 0 {
+            let e = io::Error::last_os_error();
+
+            if e.raw_os_error() != Some(EINPROGRESS as _) {
+                return Err(e);
+            }
+        }
+
+        Ok(attempt)
+    }
+
+    fn connect(&self) -> i32 {
+        match self.addr {
+            SocketAddr::V4(addr) => {
+                let mut raw: sockaddr_in = unsafe { mem::zeroed() };
+
+                raw.sin_len = mem::size_of::<sockaddr_in>() as _;
+                raw.sin_family = AF_INET as _;
+                raw.sin_port = addr.port().to_be();
+                raw.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+
+                unsafe {
+                    lwip_connect(
+                        self.fd,
+                        &raw as *const _ as *const sockaddr,
+                        mem::size_of::<sockaddr_in>() as _,
+                    )
+                }
+            }
+            #[cfg(esp_idf_lwip_ipv6)]
+            SocketAddr::V6(addr) => {
+                let mut raw: sockaddr_in6 = unsafe { mem::zeroed() };
+
+                raw.sin6_len = mem::size_of::<sockaddr_in6>() as _;
+                raw.sin6_family = AF_INET6 as _;
+                raw.sin6_port = addr.port().to_be();
+                raw.sin6_addr.un.u8_addr = addr.ip().octets();
+                raw.sin6_scope_id = addr.scope_id();
+
+                unsafe {
+                    lwip_connect(
+                        self.fd,
+                        &raw as *const _ as *const sockaddr,
+                        mem::size_of::<sockaddr_in6>() as _,
+                    )
+                }
+            }
+            #[cfg(not(esp_idf_lwip_ipv6))]
+            SocketAddr::V6(_) => -1,
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let flags = unsafe { lwip_fcntl(self.fd, F_GETFL as _, 0) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let flags = if nonblocking {
+            flags | O_NONBLOCK as i32
+        } else {
+            flags & !(O_NONBLOCK as i32)
+        };
+
+        if unsafe { lwip_fcntl(self.fd, F_SETFL as _, flags) } < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The outcome of the attempt, once its socket is writable
+    fn error(&self) -> io::Result<()> {
+        let mut error = 0_i32;
+        let mut len = mem::size_of::<i32>() as socklen_t;
+
+        let result = unsafe {
+            lwip_getsockopt(
+                self.fd,
+                SOL_SOCKET as _,
+                SO_ERROR as _,
+                &mut error as *mut _ as *mut _,
+                &mut len,
+            )
+        };
+
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else if error != 0 {
+            Err(io::Error::from_raw_os_error(error))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn into_stream(mut self) -> io::Result<TcpStream> {
+        self.set_nonblocking(false)?;
+
+        let fd = mem::replace(&mut self.fd, -1);
+
+        Ok(unsafe { TcpStream::from_raw_fd(fd) })
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            unsafe { lwip_close(self.fd) };
+        }
+    }
+}
+
+/// Copies the data between two streams both ways, each on a thread of its own named after
+/// `name`, until either closes
+pub(crate) fn relay(name: &str, a: TcpStream, b: TcpStream) -> io::Result<()> {
+    let spawn = |name: String, mut from: TcpStream, mut to: TcpStream| {
+        thread::Builder::new()
+            .name(name)
+            .stack_size(3072)
+            .spawn(move || {
+                let _ = io::copy(&mut from, &mut to);
+
+                // Closes the other direction too
+                let _ = to.shutdown(std::net::Shutdown::Both);
+                let _ = from.shutdown(std::net::Shutdown::Both);
+            })
+            .map(|_| ())
+    };
+
+    spawn(format!("{}-up", name), a.try_clone()?, b.try_clone()?)?;
+    spawn(format!("{}-down", name), b, a)
+}
+
+/// The indices of the attempts whose sockets became writable (i.e. connected or failed) within
+/// `timeout`, in ascending order
+fn select(attempts: &[Attempt], timeout: Duration) -> io::Result<Vec<usize>> {
+    let mut writable: fd_set = unsafe { mem::zeroed() };
+
+    for attempt in attempts {
+        fd_set_insert(&mut writable, attempt.fd);
+    }
+
+    let mut tv = timeval {
+        tv_sec: timeout.as_secs() as _,
+        tv_usec: timeout.subsec_micros() as _,
+    };
+
+    let max_fd = attempts.iter().map(|attempt| attempt.fd).max().unwrap_or(0);
+
+    let result = unsafe {
+        lwip_select(
+            max_fd + 1,
+            ptr::null_mut(),
+            &mut writable,
+            ptr::null_mut(),
+            &mut tv,
+        )
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(attempts
+        .iter()
+        .enumerate()
+        .filter(|(_, attempt)| fd_set_contains(&writable, attempt.fd))
+        .map(|(index, _)| index)
+        .collect())
+}
+
+fn fd_set_insert(set: &mut fd_set, fd: i32) {
+    let bits = mem::size_of_val(&set.fds_bits[0]) * 8;
+
+    set.fds_bits[fd as usize / bits] |= 1 << (fd as usize % bits);
+}
+
+fn fd_set_contains(set: &fd_set, fd: i32) -> bool {
+    let bits = mem::size_of_val(&set.fds_bits[0]) * 8;
+
+    set.fds_bits[fd as usize / bits] & (1 << (fd as usize % bits)) != 0
+}
+
+/// The addresses of `host` of the given family; none if it has none
+fn lookup(host: &str, port: u16, family: u32) -> io::Result<Vec<SocketAddr>> {
+    let c_host = CString::new(host).map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+
+    let mut hints: addrinfo = unsafe { mem::zeroed() };
+    hints.ai_family = family as _;
+    hints.ai_socktype = SOCK_STREAM as _;
+
+    let mut result: *mut addrinfo = ptr::null_mut();
+
+    if unsafe { lwip_getaddrinfo(c_host.as_ptr(), ptr::null(), &hints, &mut result) } != 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut addrs = Vec::new();
+    let mut info = result;
+
+    while !info.is_null() {
+        let current = unsafe { &*info };
+
+        if let Some(addr) = unsafe { socket_addr(current.ai_addr, port) } {
+            addrs.push(addr);
+        }
+
+        info = current.ai_next;
+    }
+
+    unsafe { lwip_freeaddrinfo(result) };
+
+    Ok(addrs)
+}
+
+unsafe fn socket_addr(addr: *const sockaddr, port: u16) -> Option<SocketAddr> {
+    if addr.is_null() {
+        return None;
+    }
+
+    match (*addr).sa_family as u32 {
+        AF_INET => {
+            let addr = &*(addr as *const sockaddr_in);
+
+            let ip = Ipv4Addr::from(addr.sin_addr.s_addr.to_ne_bytes());
+
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        #[cfg(esp_idf_lwip_ipv6)]
+        AF_INET6 => {
+            let addr = &*(addr as *const sockaddr_in6);
+
+            let ip = Ipv6Addr::from(addr.sin6_addr.un.u8_addr);
+
+            // Keeps the zone of the link-local addresses
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                port,
+                0,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,718 @@
+//! Telemetry batching
+//!
+//! `EspTelemetry` decouples the production of telemetry samples from their transmission: the
+//! application pushes typed samples into a bounded buffer, and a background thread ships them
+//! in batches, every `interval` or as soon as a batch is full, to a `Sink` (an MQTT topic, an
+//! HTTP endpoint, or any custom transport).
+//!
+//! A batch is the array of its samples, encoded as JSON or CBOR, and optionally compressed
+//! with gzip. When the sink fails (e.g. because the device is offline), the batches are
+//! spilled to files in `spill_dir` (e.g. on an SD card or a FAT partition), and sent again
+//! once the sink is back, oldest first:
+//!
+//! ```ignore
+//! #[derive(Serialize)]
+//! struct Sample {
+//!     time: u64,
+//!     temperature: f32,
+//! }
+//!
+//! let telemetry = EspTelemetry::new(
+//!     &Configuration {
+//!         encoding: Encoding::Cbor,
+//!         compress: true,
+//!         spill_dir: Some("/sdcard/telemetry".into()),
+//!         ..Default::default()
+//!     },
+//!     MqttSink::new(client, "devices/sensor-1/telemetry", QoS::AtLeastOnce),
+//! )?;
+//!
+//! telemetry.push(Sample { time, temperature });
+//! ```
+//!
+//! Note: This module requires the `telemetry` cargo feature to be enabled.
+use core::fmt::Debug;
+use core::time::Duration;
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::string::String;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::mqtt::client::{Publish, QoS};
+
+use esp_idf_sys::*;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::private::mutex::Mutex;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Encoding {
+    Json,
+    Cbor,
+}
+
+impl Encoding {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Cbor => "application/cbor",
+        }
+    }
+}
+
+/// What to do with a sample pushed into a full buffer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Overflow {
+    DropOldest,
+    DropNewest,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The maximum number of buffered samples
+    pub capacity: usize,
+    pub overflow: Overflow,
+    /// The maximum number of samples per batch; a full batch is shipped right away
+    pub batch_size: usize,
+    /// The buffered samples are shipped at least this often
+    pub interval: Duration,
+    pub encoding: Encoding,
+    /// Compress the batches with gzip
+    pub compress: bool,
+    /// The directory of the batches which could not be sent, or `None` to drop them
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub spill_dir: Option<PathBuf>,
+    /// The maximum number of spilled batches; the oldest ones are removed beyond that
+    pub max_spilled: usize,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            overflow: Overflow::DropOldest,
+            batch_size: 32,
+            interval: Duration::from_secs(60),
+            encoding: Encoding::Json,
+            compress: false,
+            spill_dir: None,
+            max_spilled: 64,
+            stack_size: 8192,
+        }
+    }
+}
+
+/// An encoded batch of samples
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Batch<'a> {
+    pub payload: &'a [u8],
+    pub encoding: Encoding,
+    /// Whether the payload is compressed with gzip
+    pub compressed: bool,
+}
+
+impl<'a> Batch<'a> {
+    /// The value of the `Content-Encoding` header of the batch, if any
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        if self.compressed {
+            Some("gzip")
+        } else {
+            None
+        }
+    }
+}
+
+/// The transport of the batches
+pub trait Sink {
+    fn send(&mut self, batch: &Batch<'_>) -> Result<(), EspError>;
+}
+
+impl<F> Sink for F
+where
+    F: FnMut(&Batch<'_>) -> Result<(), EspError>,
+{
+    fn send(&mut self, batch: &Batch<'_>) -> Result<(), EspError> {
+        self(batch)
+    }
+}
+
+/// Publishes the batches on an MQTT topic
+///
+/// The client should publish synchronously (as `EspMqttClient::publish` does), so that the
+/// batches are spilled rather than queued in memory while the client is disconnected.
+pub struct MqttSink<C> {
+    client: C,
+    topic: String,
+    qos: QoS,
+}
+
+impl<C> MqttSink<C>
+where
+    C: Publish,
+    C::Error: Debug,
+{
+    pub fn new(client: C, topic: impl Into<String>, qos: QoS) -> Self {
+        Self {
+            client,
+            topic: topic.into(),
+            qos,
+        }
+    }
+}
+
+impl<C> Sink for MqttSink<C>
+where
+    C: Publish,
+    C::Error: Debug,
+{
+    fn send(&mut self, batch: &Batch<'_>) -> Result<(), EspError> {
+        self.client
+            .publish(&self.topic, self.qos, false, batch.payload)
+            .map_err(|e| {
+                debug!("Failed to publish telemetry to {}: {:?}", self.topic, e);
+
+                EspError::from_infallible::<ESP_FAIL>()
+            })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(esp_idf_comp_esp_http_client_enabled)]
+pub use http::*;
+
+#[cfg(esp_idf_comp_esp_http_client_enabled)]
+mod http {
+    use core::time::Duration;
+
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    use ::log::*;
+
+    use embedded_svc::http::Method;
+
+    use esp_idf_sys::*;
+
+    use crate::http::client::{Configuration, EspHttpConnection};
+
+    use super::{Batch, Sink};
+
+    /// Posts the batches to an HTTP endpoint, with the `Content-Type` and `Content-Encoding`
+    /// headers of their encoding
+    #[derive(Clone, Debug)]
+    pub struct HttpSink {
+        url: String,
+        headers: Vec<(String, String)>,
+        timeout: Duration,
+    }
+
+    impl HttpSink {
+        pub fn new(url: impl Into<String>) -> Self {
+            Self {
+                url: url.into(),
+                headers: Vec::new(),
+                timeout: Duration::from_secs(10),
+            }
+        }
+
+        /// Adds a request header, e.g. for authentication
+        pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+            self.headers.push((name.into(), value.into()));
+            self
+        }
+
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+    }
+
+    impl Sink for HttpSink {
+        fn send(&mut self, batch: &Batch<'_>) -> Result<(), EspError> {
+            let mut connection = EspHttpConnection::new(&Configuration {
+                timeout: Some(self.timeout),
+                #[cfg(all(not(esp_idf_version = "4.3"), esp_idf_mbedtls_certificate_bundle))]
+                crt_bundle_attach: Some(esp_crt_bundle_attach),
+                ..Default::default()
+            })?;
+
+            let len = batch.payload.len().to_string();
+
+            let mut headers = vec![
+                ("Content-Type", batch.encoding.content_type()),
+                ("Content-Length", len.as_str()),
+            ];
+
+            if let Some(content_encoding) = batch.content_encoding() {
+                headers.push(("Content-Encoding", content_encoding));
+            }
+
+            headers.extend(
+                self.headers
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str())),
+            );
+
+            connection.initiate_request(Method::Post, &self.url, &headers)?;
+
+            let mut offset = 0;
+            while offset < batch.payload.len() {
+                offset += connection.write(&batch.payload[offset..])?;
+            }
+
+            connection.initiate_response()?;
+
+            let status = connection.status();
+
+            if (200..300).contains(&status) {
+                Ok(())
+            } else {
+                warn!("Telemetry endpoint answered with status {}", status);
+
+                Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>())
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    /// The number of batches sent, including the spilled ones sent later on
+    pub sent: u32,
+    /// The number of batches currently spilled
+    pub spilled: u32,
+    /// The number of samples dropped, because the buffer was full or a batch could be neither
+    /// sent nor spilled
+    pub dropped: u32,
+}
+
+enum Command {
+    Flush,
+    Stop,
+}
+
+struct Shared<S> {
+    samples: VecDeque<S>,
+    stats: Stats,
+}
+
+pub struct EspTelemetry<S>
+where
+    S: Send + 'static,
+{
+    shared: Arc<Mutex<Shared<S>>>,
+    conf: Configuration,
+    sender: mpsc::Sender<Command>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl<S> EspTelemetry<S>
+where
+    S: Serialize + Send + 'static,
+{
+    pub fn new<K>(conf: &Configuration, sink: K) -> Result<Self, EspError>
+    where
+        K: Sink + Send + 'static,
+    {
+        if let Some(spill_dir) = &conf.spill_dir {
+            fs::create_dir_all(spill_dir)
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+        }
+
+        let shared = Arc::new(Mutex::new(Shared {
+            samples: VecDeque::with_capacity(conf.capacity),
+            stats: Default::default(),
+        }));
+
+        let (sender, receiver) = mpsc::channel();
+
+        let mut shipper = Shipper {
+            shared: shared.clone(),
+            conf: conf.clone(),
+            sink,
+            next_spill: 0,
+        };
+
+        shipper.scan_spilled();
+
+        let join_handle = thread::Builder::new()
+            .name("telemetry".into())
+            .stack_size(conf.stack_size)
+            .spawn(move || shipper.run(receiver))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        info!("Started telemetry");
+
+        Ok(Self {
+            shared,
+            conf: conf.clone(),
+            sender,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// Buffers a sample, returning `false` if it was dropped because the buffer is full
+    pub fn push(&self, sample: S) -> bool {
+        let mut shared = self.shared.lock();
+
+        let pushed = if shared.samples.len() < self.conf.capacity {
+            shared.samples.push_back(sample);
+            true
+        } else {
+            shared.stats.dropped += 1;
+
+            match self.conf.overflow {
+                Overflow::DropOldest => {
+                    shared.samples.pop_front();
+                    shared.samples.push_back(sample);
+                    true
+                }
+                Overflow::DropNewest => false,
+            }
+        };
+
+        let full = shared.samples.len() >= self.conf.batch_size;
+
+        drop(shared);
+
+        if full {
+            let _ = self.sender.send(Command::Flush);
+        }
+
+        pushed
+    }
+
+    /// Ships the buffered samples now, rather than at the end of the interval
+    pub fn flush(&self) {
+        let _ = self.sender.send(Command::Flush);
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.lock().samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.shared.lock().stats
+    }
+}
+
+impl<S> Drop for EspTelemetry<S>
+where
+    S: Send + 'static,
+{
+    fn drop(&mut self) {
+        // The buffered samples are shipped, or spilled, before the thread exits
+        let _ = self.sender.send(Command::Stop);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+struct Shipper<S, K> {
+    shared: Arc<Mutex<Shared<S>>>,
+    conf: Configuration,
+    sink: K,
+    /// The sequence number of the next spilled batch, which orders the spill files
+    next_spill: u32,
+}
+
+impl<S, K> Shipper<S, K>
+where
+    S: Serialize,
+    K: Sink,
+{
+    fn run(&mut self, receiver: mpsc::Receiver<Command>) {
+        loop {
+            let stop = match receiver.recv_timeout(self.conf.interval) {
+                Ok(Command::Flush) | Err(mpsc::RecvTimeoutError::Timeout) => false,
+                Ok(Command::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => true,
+            };
+
+            self.ship();
+
+            if stop {
+                break;
+            }
+        }
+    }
+
+    fn ship(&mut self) {
+        // Once the sink fails, the remaining batches are spilled without trying again
+        let mut online = self.send_spilled();
+
+        loop {
+            let samples = {
+                let mut shared = self.shared.lock();
+                let len = shared.samples.len().min(self.conf.batch_size.max(1));
+
+                shared.samples.drain(..len).collect::<Vec<_>>()
+            };
+
+            if samples.is_empty() {
+                break;
+            }
+
+            let payload = match self.encode(&samples) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Failed to encode telemetry: {}", e);
+
+                    self.shared.lock().stats.dropped += samples.len() as u32;
+                    continue;
+                }
+            };
+
+            if online {
+                match self.sink.send(&self.batch(&payload)) {
+                    Ok(()) => {
+                        self.shared.lock().stats.sent += 1;
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Failed to send telemetry: {}", e);
+
+                        online = false;
+                    }
+                }
+            }
+
+            if !self.spill(&payload) {
+                self.shared.lock().stats.dropped += samples.len() as u32;
+            }
+        }
+    }
+
+    fn batch<'a>(&self, payload: &'a [u8]) -> Batch<'a> {
+        Batch {
+            payload,
+            encoding: self.conf.encoding,
+            compressed: self.conf.compress,
+        }
+    }
+
+    fn encode(&self, samples: &[S]) -> Result<Vec<u8>, serde_json::Error> {
+        let payload = match self.conf.encoding {
+            Encoding::Json => serde_json::to_vec(samples)?,
+            Encoding::Cbor => {
+                let mut payload = Vec::new();
+                encode_cbor(&mut payload, &serde_json::to_value(samples)?);
+
+                payload
+            }
+        };
+
+        Ok(if self.conf.compress {
+            gzip(&payload)
+        } else {
+            payload
+        })
+    }
+
+    /// The spilled batches, oldest first
+    fn spilled(&self) -> Vec<(u32, PathBuf)> {
+        let spill_dir = match &self.conf.spill_dir {
+            Some(spill_dir) => spill_dir,
+            None => return Vec::new(),
+        };
+
+        let mut spilled = fs::read_dir(spill_dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter_map(|entry| {
+                        let name = entry.file_name();
+                        let seq = name.to_str()?.strip_suffix(".bin")?.parse().ok()?;
+
+                        Some((seq, entry.path()))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        spilled.sort();
+
+        spilled
+    }
+
+    fn scan_spilled(&mut self) {
+        let spilled = self.spilled();
+
+        self.next_spill = spilled.last().map(|(seq, _)| seq + 1).unwrap_or(0);
+        self.shared.lock().stats.spilled = spilled.len() as u32;
+
+        if !spilled.is_empty() {
+            info!("Found {} spilled telemetry batches", spilled.len());
+        }
+    }
+
+    /// Sends the spilled batches, returning `false` if the sink failed
+    fn send_spilled(&mut self) -> bool {
+        for (_, path) in self.spilled() {
+            let payload = match fs::read(&path) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Failed to read spilled telemetry {:?}: {}", path, e);
+
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.sink.send(&self.batch(&payload)) {
+                debug!("Failed to send spilled telemetry: {}", e);
+
+                return false;
+            }
+
+            let _ = fs::remove_file(&path);
+
+            let mut shared = self.shared.lock();
+            shared.stats.sent += 1;
+            shared.stats.spilled = shared.stats.spilled.saturating_sub(1);
+        }
+
+        true
+    }
+
+    /// Spills a batch, returning `false` if it could not be written
+    fn spill(&mut self, payload: &[u8]) -> bool {
+        let spill_dir = match &self.conf.spill_dir {
+            Some(spill_dir) => spill_dir.clone(),
+            None => return false,
+        };
+
+        let spilled = self.spilled();
+
+        // Makes room by dropping the oldest batches
+        for (_, path) in spilled
+            .iter()
+            .take((spilled.len() + 1).saturating_sub(self.conf.max_spilled.max(1)))
+        {
+            warn!("Dropping spilled telemetry {:?}", path);
+
+            let _ = fs::remove_file(path);
+        }
+
+        // 8 characters, for the FAT short names
+        let path = spill_dir.join(format!("{:08}.bin", self.next_spill % 100_000_000));
+        self.next_spill += 1;
+
+        match fs::write(&path, payload) {
+            Ok(()) => {
+                self.shared.lock().stats.spilled = self.spilled().len() as u32;
+                true
+            }
+            Err(e) => {
+                warn!("Failed to spill telemetry to {:?}: {}", path, e);
+
+                let _ = fs::remove_file(&path);
+                false
+            }
+        }
+    }
+}
+
+/// Encodes `value` as CBOR (RFC 8949)
+fn encode_cbor(out: &mut Vec<u8>, value: &Value) {
+    fn head(out: &mut Vec<u8>, major: u8, value: u64) {
+        let major = major << 5;
+
+        if value < 24 {
+            out.push(major | value as u8);
+        } else if value <= u8::MAX as u64 {
+            out.push(major | 24);
+            out.push(value as u8);
+        } else if value <= u16::MAX as u64 {
+            out.push(major | 25);
+            out.extend_from_slice(&(value as u16).to_be_bytes());
+        } else if value <= u32::MAX as u64 {
+            out.push(major | 26);
+            out.extend_from_slice(&(value as u32).to_be_bytes());
+        } else {
+            out.push(major | 27);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Number(number) => {
+            if let Some(number) = number.as_u64() {
+                head(out, 0, number);
+            } else if let Some(number) = number.as_i64() {
+                head(out, 1, (-1 - number) as u64);
+            } else {
+                out.push(0xfb);
+                out.extend_from_slice(&number.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        }
+        Value::String(string) => {
+            head(out, 3, string.len() as u64);
+            out.extend_from_slice(string.as_bytes());
+        }
+        Value::Array(array) => {
+            head(out, 4, array.len() as u64);
+
+            for value in array {
+                encode_cbor(out, value);
+            }
+        }
+        Value::Object(object) => {
+            head(out, 5, object.len() as u64);
+
+            for (key, value) in object {
+                head(out, 3, key.len() as u64);
+                out.extend_from_slice(key.as_bytes());
+
+                encode_cbor(out, value);
+            }
+        }
+    }
+}
+
+/// Compresses `data` into a gzip member (RFC 1952)
+fn gzip(data: &[u8]) -> Vec<u8> {
+    // No file name or modification time, unknown OS
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+    out.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(data, 6));
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0_u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
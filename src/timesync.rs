@@ -0,0 +1,419 @@
+//! Time sources and clock discipline
+//!
+//! A `TimeSource` provides reference UTC time samples - from SNTP, from the NMEA sentences of a
+//! GPS receiver or from the clock of a cellular modem. `TimeDiscipline` consumes these samples,
+//! estimates the drift of the local clock against them and keeps the system time corrected
+//! between samples, so that time stamps stay accurate even when syncs are rare
+//! (e.g. on networks where NTP is blocked).
+//!
+//! When combined with `EspSntp`, SNTP should not be left to adjust the system time on its own
+//! in `SyncMode::Smooth`, as this would fight the discipline.
+use core::convert::TryFrom;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::sync::Arc;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::private::civil::days_from_civil;
+use crate::private::mutex::Mutex;
+use crate::systime::EspSystemTime;
+
+/// Offsets larger than this are corrected by stepping the system time rather than slewing it
+const STEP_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Drift is only estimated from samples which are at least this far apart,
+/// as the sample jitter would otherwise dominate the estimate
+const MIN_DRIFT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Weight of a new drift measurement in the drift estimate
+const DRIFT_SMOOTHING: f64 = 0.3;
+
+/// Drift estimates beyond this are considered bogus (e.g. a source reporting wrong time)
+const MAX_DRIFT_PPM: f64 = 500.0;
+
+fn monotonic() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() as _ })
+}
+
+/// A reference UTC time, as it was at the given monotonic (`esp_timer`) instant
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct TimeSample {
+    /// Time since the UNIX epoch
+    pub utc: Duration,
+    /// Monotonic time since boot at which `utc` was valid
+    pub monotonic: Duration,
+}
+
+impl TimeSample {
+    /// A sample of the given UTC time, valid right now
+    pub fn now(utc: Duration) -> Self {
+        Self {
+            utc,
+            monotonic: monotonic(),
+        }
+    }
+
+    /// Extrapolates the sample to the current moment, assuming no drift
+    pub fn extrapolate(&self) -> Duration {
+        self.utc + monotonic().saturating_sub(self.monotonic)
+    }
+}
+
+pub trait TimeSource {
+    /// Returns the most recent reference time sample, if any
+    fn sample(&mut self) -> Option<TimeSample>;
+}
+
+/// Time source fed by the SNTP sync notifications
+///
+/// ```ignore
+/// let source = SntpTimeSource::new();
+/// let sntp = EspSntp::new_with_callback(&Default::default(), source.callback())?;
+/// ```
+#[derive(Clone)]
+pub struct SntpTimeSource(Arc<Mutex<Option<TimeSample>>>);
+
+impl SntpTimeSource {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(None)))
+    }
+
+    /// Returns the callback to be passed to `EspSntp::new_with_callback`
+    pub fn callback(&self) -> impl FnMut(Duration) + Send + 'static {
+        let sample = self.0.clone();
+
+        move |utc| *sample.lock() = Some(TimeSample::now(utc))
+    }
+}
+
+impl Default for SntpTimeSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeSource for SntpTimeSource {
+    fn sample(&mut self) -> Option<TimeSample> {
+        *self.0.lock()
+    }
+}
+
+/// Time source fed with the NMEA 0183 sentences of a GPS (or any GNSS) receiver
+///
+/// Both `RMC` (with a valid fix) and `ZDA` sentences from any talker are used.
+/// The receiver should output these once per second, right after the second they refer to
+/// has started, which is how most receivers behave.
+#[derive(Clone, Debug, Default)]
+pub struct NmeaTimeSource {
+    last: Option<TimeSample>,
+}
+
+impl NmeaTimeSource {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Feeds a single sentence (e.g. `$GPRMC,...*hh`), with or without the trailing line break;
+    /// returns `true` if a time sample was extracted from it
+    pub fn feed(&mut self, sentence: &str) -> bool {
+        if let Some(utc) = Self::parse(sentence.trim_end()) {
+            self.last = Some(TimeSample::now(utc));
+
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse(sentence: &str) -> Option<Duration> {
+        let sentence = sentence.strip_prefix('$')?;
+        let (data, checksum) = sentence.split_once('*')?;
+
+        let checksum = u8::from_str_radix(checksum, 16).ok()?;
+        if data.bytes().fold(0, |acc, b| acc ^ b) != checksum {
+            return None;
+        }
+
+        let mut fields = data.split(',');
+        let kind = fields.next()?;
+
+        if kind.len() != 5 {
+            return None;
+        }
+
+        match &kind[2..] {
+            "RMC" => {
+                let time = fields.next()?;
+                let status = fields.next()?;
+
+                if status != "A" {
+                    return None;
+                }
+
+                // Skip latitude, longitude, speed and course
+                let date = fields.nth(6)?;
+
+                if date.len() != 6 {
+                    return None;
+                }
+
+                let day = date[0..2].parse().ok()?;
+                let month = date[2..4].parse().ok()?;
+                let year = 2000 + date[4..6].parse::<i64>().ok()?;
+
+                Self::utc(year, month, day, time)
+            }
+            "ZDA" => {
+                let time = fields.next()?;
+                let day = fields.next()?.parse().ok()?;
+                let month = fields.next()?.parse().ok()?;
+                let year = fields.next()?.parse().ok()?;
+
+                Self::utc(year, month, day, time)
+            }
+            _ => None,
+        }
+    }
+
+    /// Parses a `hhmmss[.sss]` time of the given day
+    fn utc(year: i64, month: u32, day: u32, time: &str) -> Option<Duration> {
+        if time.len() < 6 || !time.is_char_boundary(6) {
+            return None;
+        }
+
+        let hours: u64 = time[0..2].parse().ok()?;
+        let minutes: u64 = time[2..4].parse().ok()?;
+        let seconds: u64 = time[4..6].parse().ok()?;
+
+        let fraction = match time[6..].strip_prefix('.') {
+            Some(fraction) if !fraction.is_empty() => {
+                let digits = fraction.len().min(6);
+                let value: u64 = fraction[..digits].parse().ok()?;
+
+                Duration::from_micros(value * 10_u64.pow(6 - digits as u32))
+            }
+            _ => Duration::ZERO,
+        };
+
+        let days = days_since_epoch(year, month, day)?;
+
+        Some(Duration::from_secs(days * 86400 + hours * 3600 + minutes * 60 + seconds) + fraction)
+    }
+}
+
+impl TimeSource for NmeaTimeSource {
+    fn sample(&mut self) -> Option<TimeSample> {
+        self.last
+    }
+}
+
+/// Time source fed with the network time reported by a cellular modem
+/// in response to the `AT+CCLK?` command
+#[derive(Clone, Debug, Default)]
+pub struct ModemTimeSource {
+    last: Option<TimeSample>,
+}
+
+impl ModemTimeSource {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Feeds a `+CCLK: "yy/MM/dd,hh:mm:ss±zz"` response line, where `zz` is the
+    /// time zone offset in quarters of an hour; returns `true` if it was parsed successfully
+    pub fn feed(&mut self, response: &str) -> bool {
+        if let Some(utc) = Self::parse(response.trim()) {
+            self.last = Some(TimeSample::now(utc));
+
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse(response: &str) -> Option<Duration> {
+        let value = response.strip_prefix("+CCLK:")?.trim().trim_matches('"');
+        let (date, time) = value.split_once(',')?;
+
+        let mut date = date.split('/');
+        let year = 2000 + date.next()?.parse::<i64>().ok()?;
+        let month = date.next()?.parse().ok()?;
+        let day = date.next()?.parse().ok()?;
+
+        let (time, zone) = match time.find(['+', '-'].as_ref()) {
+            Some(index) => (&time[..index], &time[index..]),
+            None => (time, "+0"),
+        };
+
+        let mut time = time.split(':');
+        let hours: i64 = time.next()?.parse().ok()?;
+        let minutes: i64 = time.next()?.parse().ok()?;
+        let seconds: i64 = time.next()?.parse().ok()?;
+
+        let zone: i64 = zone.parse().ok()?;
+
+        let local = days_since_epoch(year, month, day)? as i64 * 86400
+            + hours * 3600
+            + minutes * 60
+            + seconds;
+
+        u64::try_from(local - zone * 15 * 60)
+            .ok()
+            .map(Duration::from_secs)
+    }
+}
+
+impl TimeSource for ModemTimeSource {
+    fn sample(&mut self) -> Option<TimeSample> {
+        self.last
+    }
+}
+
+/// The number of days since the UNIX epoch of a received date, `None` if it is invalid or
+/// before the epoch
+fn days_since_epoch(year: i64, month: u32, day: u32) -> Option<u64> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    u64::try_from(days_from_civil(year, month, day)).ok()
+}
+
+/// Disciplines the system time with the samples of a time source
+///
+/// `update` should be called whenever the source might have a new sample, and `correct`
+/// periodically in between (e.g. every few minutes from a timer), so that the estimated
+/// drift of the local clock is compensated for.
+#[derive(Clone, Debug, Default)]
+pub struct TimeDiscipline {
+    reference: Option<TimeSample>,
+    last_sample: Option<TimeSample>,
+    drift_ppm: Option<f64>,
+}
+
+impl TimeDiscipline {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Consumes the latest sample of the source (if it is a new one), updates the drift estimate
+    /// and corrects the system time; returns `true` if a new sample was consumed
+    pub fn update(&mut self, source: &mut impl TimeSource) -> Result<bool, EspError> {
+        let sample = match source.sample() {
+            Some(sample) if Some(sample) != self.last_sample => sample,
+            _ => return Ok(false),
+        };
+
+        self.last_sample = Some(sample);
+
+        match self.reference {
+            Some(reference) if sample.monotonic - reference.monotonic >= MIN_DRIFT_INTERVAL => {
+                let elapsed = (sample.monotonic - reference.monotonic).as_micros() as f64;
+                let reference_elapsed =
+                    sample.utc.as_micros() as f64 - reference.utc.as_micros() as f64;
+
+                let drift_ppm = (reference_elapsed - elapsed) / elapsed * 1_000_000.0;
+
+                if drift_ppm.abs() > MAX_DRIFT_PPM {
+                    warn!(
+                        "Ignoring implausible clock drift of {:.1} ppm, resetting the estimate",
+                        drift_ppm
+                    );
+
+                    self.drift_ppm = None;
+                } else {
+                    let estimate = self
+                        .drift_ppm
+                        .map(|estimate| {
+                            estimate * (1.0 - DRIFT_SMOOTHING) + drift_ppm * DRIFT_SMOOTHING
+                        })
+                        .unwrap_or(drift_ppm);
+
+                    debug!(
+                        "Measured clock drift {:.2} ppm, estimate {:.2} ppm",
+                        drift_ppm, estimate
+                    );
+
+                    self.drift_ppm = Some(estimate);
+                }
+
+                self.reference = Some(sample);
+            }
+            Some(_) => {
+                // Too close to the previous reference for a drift measurement; keep the
+                // older reference for the measurement, but correct the time right away
+                self.apply(sample.extrapolate())?;
+
+                return Ok(true);
+            }
+            None => self.reference = Some(sample),
+        }
+
+        self.correct()?;
+
+        Ok(true)
+    }
+
+    /// Returns the current time, as extrapolated from the last reference sample
+    /// with the estimated drift compensated for
+    pub fn now(&self) -> Option<Duration> {
+        self.reference.map(|reference| {
+            let elapsed = monotonic().saturating_sub(reference.monotonic);
+            let correction =
+                elapsed.as_micros() as f64 * self.drift_ppm.unwrap_or(0.0) / 1_000_000.0;
+
+            let micros =
+                reference.utc.as_micros() as i64 + elapsed.as_micros() as i64 + correction as i64;
+
+            Duration::from_micros(micros.max(0) as u64)
+        })
+    }
+
+    /// The estimated drift of the local clock in parts per million; positive if the local clock is slow
+    pub fn drift_ppm(&self) -> Option<f32> {
+        self.drift_ppm.map(|drift| drift as f32)
+    }
+
+    /// Corrects the system time towards `now`
+    pub fn correct(&self) -> Result<(), EspError> {
+        match self.now() {
+            Some(now) => self.apply(now),
+            None => Ok(()),
+        }
+    }
+
+    fn apply(&self, now: Duration) -> Result<(), EspError> {
+        let system = EspSystemTime.now();
+        let offset = now.as_micros() as i64 - system.as_micros() as i64;
+
+        if offset.unsigned_abs() > STEP_THRESHOLD.as_micros() as u64 {
+            info!("Stepping the system time by {} ms", offset / 1000);
+
+            let tv = timeval {
+                tv_sec: now.as_secs() as _,
+                tv_usec: now.subsec_micros() as _,
+            };
+
+            if unsafe { settimeofday(&tv, core::ptr::null()) } != 0 {
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+        } else {
+            let delta = timeval {
+                tv_sec: (offset / 1_000_000) as _,
+                tv_usec: (offset % 1_000_000) as _,
+            };
+
+            if unsafe { adjtime(&delta, core::ptr::null_mut()) } != 0 {
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,6 +1,18 @@
-//! TLS-related helper types
+//! TLS-related helper types and a raw TLS client
 use core::ffi::{c_char, CStr};
 use core::fmt::Debug;
+use core::time::Duration;
+
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_esp_tls_enabled,
+    esp_idf_comp_esp_netif_enabled,
+    not(esp_idf_version = "4.3")
+))]
+pub use client::*;
+
+#[cfg(all(feature = "alloc", esp_idf_comp_mbedtls_enabled))]
+pub mod keys;
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct X509<'a>(&'a [u8]);
@@ -46,3 +58,380 @@ impl<'a> Debug for X509<'a> {
         write!(f, "X509(...)")
     }
 }
+
+/// TCP keepalive probing of an otherwise idle connection
+///
+/// Without it, a connection silently dropped by a NAT gateway is only noticed when
+/// the device next tries to send something, which for subscribers might be never.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct TcpKeepAlive {
+    /// Idle time after which the first probe is sent
+    pub idle: Duration,
+    /// Time between unanswered probes
+    pub interval: Duration,
+    /// Number of unanswered probes after which the connection is considered dead
+    pub count: u16,
+}
+
+impl Default for TcpKeepAlive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            count: 5,
+        }
+    }
+}
+
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_esp_tls_enabled,
+    esp_idf_comp_esp_netif_enabled,
+    not(esp_idf_version = "4.3")
+))]
+mod client {
+    use core::time::Duration;
+
+    extern crate alloc;
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    use alloc::string::String;
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    use alloc::vec::Vec;
+
+    use embedded_svc::io::{Io, Read, Write};
+
+    use esp_idf_sys::*;
+
+    use super::{TcpKeepAlive, X509};
+
+    use crate::errors::EspIOError;
+    use crate::heap::BufferMemory;
+    use crate::private::cstr::CString;
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    use crate::private::mutex::{Mutex, RawMutex};
+    use crate::private::socket;
+
+    #[derive(Clone, Debug, Default)]
+    pub struct Configuration<'a> {
+        /// Server name to verify the certificate against, if different from the host name
+        pub common_name: Option<&'a str>,
+        pub ca_cert: Option<X509<'a>>,
+        pub client_cert: Option<X509<'a>>,
+        pub client_key: Option<X509<'a>>,
+        pub timeout: Option<Duration>,
+        pub use_global_ca_store: bool,
+        pub crt_bundle_attach:
+            Option<unsafe extern "C" fn(conf: *mut core::ffi::c_void) -> esp_err_t>,
+        pub skip_common_name: bool,
+        /// Connect over plain TCP, without TLS
+        pub plain_tcp: bool,
+        pub keep_alive: Option<TcpKeepAlive>,
+        /// Disables Nagle's algorithm, so that small writes are sent immediately
+        pub nodelay: bool,
+        /// Where the TLS buffers of the connection are allocated
+        pub buffer_memory: BufferMemory,
+        /// Resumes the TLS session of a previous connection to the same host and port, if any,
+        /// and saves the session of this connection for the next one
+        #[cfg(esp_idf_esp_tls_client_session_tickets)]
+        pub session_cache: Option<&'a SessionCache>,
+    }
+
+    /// A TLS session which can be resumed
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    struct Session(*mut esp_tls_client_session_t);
+
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    impl Drop for Session {
+        fn drop(&mut self) {
+            unsafe { esp_tls_free_client_session(self.0) };
+        }
+    }
+
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    unsafe impl Send for Session {}
+
+    /// The sessions of the recent TLS connections, by host and port
+    ///
+    /// Resuming a session skips the certificate exchange and the public key operations of the
+    /// handshake, which cuts the time, the traffic and the energy of a reconnection:
+    ///
+    /// ```ignore
+    /// static SESSIONS: SessionCache = SessionCache::new(4);
+    ///
+    /// let conf = Configuration {
+    ///     session_cache: Some(&SESSIONS),
+    ///     ..Default::default()
+    /// };
+    /// ```
+    ///
+    /// A session is taken out of the cache when it is resumed, as the session tickets of TLS 1.3
+    /// are single-use, and the session of the new connection replaces it.
+    ///
+    /// Requires `CONFIG_ESP_TLS_CLIENT_SESSION_TICKETS`.
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    pub struct SessionCache {
+        capacity: usize,
+        /// The most recently saved last
+        sessions: Mutex<Vec<(String, Session)>>,
+    }
+
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    impl SessionCache {
+        /// A cache of the sessions of up to `capacity` hosts, evicting the least recently
+        /// saved ones
+        pub const fn new(capacity: usize) -> Self {
+            Self {
+                capacity,
+                sessions: Mutex::wrap(RawMutex::new(), Vec::new()),
+            }
+        }
+
+        pub fn capacity(&self) -> usize {
+            self.capacity
+        }
+
+        pub fn len(&self) -> usize {
+            self.sessions.lock().len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        /// Forgets the session of `host` and `port`, e.g. after its certificate has been revoked
+        pub fn remove(&self, host: &str, port: u16) {
+            let key = Self::key(host, port);
+
+            self.sessions.lock().retain(|(other, _)| *other != key);
+        }
+
+        pub fn clear(&self) {
+            self.sessions.lock().clear();
+        }
+
+        fn take(&self, host: &str, port: u16) -> Option<Session> {
+            let key = Self::key(host, port);
+
+            let mut sessions = self.sessions.lock();
+
+            let index = sessions.iter().position(|(other, _)| *other == key)?;
+
+            Some(sessions.remove(index).1)
+        }
+
+        fn save(&self, host: &str, port: u16, session: Session) {
+            if self.capacity == 0 {
+                return;
+            }
+
+            let key = Self::key(host, port);
+
+            let mut sessions = self.sessions.lock();
+
+            sessions.retain(|(other, _)| *other != key);
+
+            if sessions.len() >= self.capacity {
+                sessions.remove(0);
+            }
+
+            sessions.push((key, session));
+        }
+
+        fn key(host: &str, port: u16) -> String {
+            format!("{}:{}", host, port)
+        }
+    }
+
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    impl core::fmt::Debug for SessionCache {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.debug_struct("SessionCache")
+                .field("capacity", &self.capacity)
+                .field("len", &self.len())
+                .finish()
+        }
+    }
+
+    /// A blocking TLS (or plain TCP) connection on top of ESP-TLS
+    pub struct EspTls(*mut esp_tls_t);
+
+    impl EspTls {
+        pub fn connect(host: &str, port: u16, conf: &Configuration) -> Result<Self, EspError> {
+            let raw = unsafe { esp_tls_init() };
+            if raw.is_null() {
+                return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+            }
+
+            // From here on, `raw` is destroyed on error by `Drop`
+            let tls = Self(raw);
+
+            let common_name = conf.common_name.map(|name| CString::new(name).unwrap());
+
+            let mut keep_alive_cfg = conf.keep_alive.map(|keep_alive| tls_keep_alive_cfg_t {
+                keep_alive_enable: true,
+                keep_alive_idle: keep_alive.idle.as_secs() as _,
+                keep_alive_interval: keep_alive.interval.as_secs() as _,
+                keep_alive_count: keep_alive.count as _,
+            });
+
+            let mut cfg = esp_tls_cfg_t {
+                timeout_ms: conf
+                    .timeout
+                    .map(|timeout| timeout.as_millis() as _)
+                    .unwrap_or(0),
+                use_global_ca_store: conf.use_global_ca_store,
+                crt_bundle_attach: conf.crt_bundle_attach,
+                skip_common_name: conf.skip_common_name,
+                is_plain_tcp: conf.plain_tcp,
+                common_name: common_name
+                    .as_ref()
+                    .map(|name| name.as_ptr())
+                    .unwrap_or(core::ptr::null()),
+                ..Default::default()
+            };
+
+            if let Some(keep_alive_cfg) = keep_alive_cfg.as_mut() {
+                cfg.keep_alive_cfg = keep_alive_cfg as *mut _;
+            }
+
+            if let Some(ca_cert) = conf.ca_cert {
+                cfg.__bindgen_anon_1.cacert_buf = ca_cert.data().as_ptr();
+                cfg.__bindgen_anon_2.cacert_bytes = ca_cert.data().len() as _;
+            }
+
+            if let (Some(cert), Some(key)) = (conf.client_cert, conf.client_key) {
+                cfg.__bindgen_anon_3.clientcert_buf = cert.data().as_ptr();
+                cfg.__bindgen_anon_4.clientcert_bytes = cert.data().len() as _;
+                cfg.__bindgen_anon_5.clientkey_buf = key.data().as_ptr();
+                cfg.__bindgen_anon_6.clientkey_bytes = key.data().len() as _;
+            }
+
+            // Copied into the connection during the handshake
+            #[cfg(esp_idf_esp_tls_client_session_tickets)]
+            let session = conf
+                .session_cache
+                .filter(|_| !conf.plain_tcp)
+                .and_then(|cache| cache.take(host, port));
+
+            #[cfg(esp_idf_esp_tls_client_session_tickets)]
+            if let Some(session) = session.as_ref() {
+                cfg.client_session = session.0;
+            }
+
+            let result = conf.buffer_memory.scope(|| unsafe {
+                esp_tls_conn_new_sync(
+                    host.as_ptr() as *const _,
+                    host.len() as _,
+                    port as _,
+                    &cfg,
+                    raw,
+                )
+            });
+
+            if result != 1 {
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+
+            #[cfg(esp_idf_esp_tls_client_session_tickets)]
+            if let Some(cache) = conf.session_cache.filter(|_| !conf.plain_tcp) {
+                let session = unsafe { esp_tls_get_client_session(raw) };
+
+                if !session.is_null() {
+                    cache.save(host, port, Session(session));
+                }
+            }
+
+            if conf.nodelay {
+                tls.set_nodelay(true)?;
+            }
+
+            Ok(tls)
+        }
+
+        pub fn set_keep_alive(&self, keep_alive: Option<TcpKeepAlive>) -> Result<(), EspError> {
+            socket::set_keep_alive(self.socket()?, keep_alive.as_ref())
+        }
+
+        pub fn set_nodelay(&self, nodelay: bool) -> Result<(), EspError> {
+            socket::set_nodelay(self.socket()?, nodelay)
+        }
+
+        pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+            #[cfg(esp_idf_version_major = "4")]
+            let result = unsafe {
+                ((*self.0).read.unwrap())(self.0, buf.as_mut_ptr() as *mut _, buf.len() as _)
+            };
+
+            #[cfg(not(esp_idf_version_major = "4"))]
+            let result =
+                unsafe { esp_tls_conn_read(self.0, buf.as_mut_ptr() as *mut _, buf.len() as _) };
+
+            Self::check(result as _)
+        }
+
+        pub fn write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
+            #[cfg(esp_idf_version_major = "4")]
+            let result = unsafe {
+                ((*self.0).write.unwrap())(self.0, buf.as_ptr() as *const _, buf.len() as _)
+            };
+
+            #[cfg(not(esp_idf_version_major = "4"))]
+            let result =
+                unsafe { esp_tls_conn_write(self.0, buf.as_ptr() as *const _, buf.len() as _) };
+
+            Self::check(result as _)
+        }
+
+        fn socket(&self) -> Result<i32, EspError> {
+            let mut fd = -1;
+
+            esp!(unsafe { esp_tls_get_conn_sockfd(self.0, &mut fd) })?;
+
+            Ok(fd)
+        }
+
+        fn check(result: isize) -> Result<usize, EspError> {
+            if result < 0 {
+                Err(EspError::from(result as _).unwrap())
+            } else {
+                Ok(result as _)
+            }
+        }
+    }
+
+    impl Drop for EspTls {
+        fn drop(&mut self) {
+            unsafe {
+                esp_tls_conn_destroy(self.0);
+            }
+        }
+    }
+
+    unsafe impl Send for EspTls {}
+
+    impl Io for EspTls {
+        type Error = EspIOError;
+    }
+
+    impl Read for EspTls {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let size = EspTls::read(self, buf)?;
+
+            Ok(size)
+        }
+    }
+
+    impl Write for EspTls {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            let size = EspTls::write(self, buf)?;
+
+            Ok(size)
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,511 @@
+//! Key pairs and CSRs
+//!
+//! `KeyPair` generates EC or RSA key pairs with mbedTLS, exports them, and signs certificate
+//! signing requests for them, the first step of any certificate enrollment:
+//!
+//! ```ignore
+//! let mut key = KeyPair::generate(KeyType::Ec(Curve::P256))?;
+//!
+//! let csr = key.csr_pem(&CsrConfiguration {
+//!     subject: "CN=sensor-1,O=Example".into(),
+//!     dns_names: vec!["sensor-1.example.com".into()],
+//!     ..Default::default()
+//! })?;
+//!
+//! nvs.set_raw("key", key.private_key_pem()?.as_bytes())?;
+//! ```
+//!
+//! The keys are generated from the hardware RNG, so the RF subsystem (Wi-Fi or Bluetooth)
+//! should be enabled, or the bootloader entropy source still active. Generating an RSA key
+//! takes seconds to tens of seconds, an EC one a fraction of a second.
+use core::ffi;
+use core::ptr;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use embedded_svc::ipv4::IpAddr;
+
+use esp_idf_sys::*;
+
+use crate::private::cstr::CString;
+use crate::private::sha256;
+
+/// The OID of the subjectAltName extension, 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+const RSA_EXPONENT: ffi::c_int = 65537;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Curve {
+    P256,
+    P384,
+}
+
+impl Curve {
+    fn group_id(&self) -> mbedtls_ecp_group_id {
+        match self {
+            Self::P256 => mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1,
+            Self::P384 => mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP384R1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum KeyType {
+    Ec(Curve),
+    /// An RSA key of 2048 bits or more
+    Rsa(u32),
+}
+
+impl Default for KeyType {
+    fn default() -> Self {
+        Self::Ec(Curve::P256)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CsrConfiguration {
+    /// The subject, e.g. `CN=sensor-1,O=Example,C=DE`
+    pub subject: String,
+    /// The DNS names of the subject alternative names
+    pub dns_names: Vec<String>,
+    /// The IP addresses of the subject alternative names
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub ip_addresses: Vec<IpAddr>,
+}
+
+pub struct KeyPair(mbedtls_pk_context);
+
+impl KeyPair {
+    pub fn generate(key_type: KeyType) -> Result<Self, EspError> {
+        let mut this = Self::new();
+
+        unsafe {
+            match key_type {
+                KeyType::Ec(curve) => {
+                    check(mbedtls_pk_setup(
+                        &mut this.0,
+                        mbedtls_pk_info_from_type(mbedtls_pk_type_t_MBEDTLS_PK_ECKEY),
+                    ))?;
+
+                    check(mbedtls_ecp_gen_key(
+                        curve.group_id(),
+                        this.context() as *mut mbedtls_ecp_keypair,
+                        Some(random),
+                        ptr::null_mut(),
+                    ))?;
+                }
+                KeyType::Rsa(bits) => {
+                    if bits < 2048 {
+                        return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+                    }
+
+                    check(mbedtls_pk_setup(
+                        &mut this.0,
+                        mbedtls_pk_info_from_type(mbedtls_pk_type_t_MBEDTLS_PK_RSA),
+                    ))?;
+
+                    check(mbedtls_rsa_gen_key(
+                        this.context() as *mut mbedtls_rsa_context,
+                        Some(random),
+                        ptr::null_mut(),
+                        bits as _,
+                        RSA_EXPONENT,
+                    ))?;
+                }
+            }
+        }
+
+        Ok(this)
+    }
+
+    /// Parses a PEM or DER encoded private key, failing with `ESP_ERR_INVALID_ARG` if it is
+    /// neither an EC nor an RSA one
+    pub fn from_private_key(key: &[u8]) -> Result<Self, EspError> {
+        // The PEM parser wants the terminating NUL to be included in the length
+        let mut key = key.to_vec();
+        if key.starts_with(b"-----BEGIN") && key.last() != Some(&0) {
+            key.push(0);
+        }
+
+        let mut this = Self::new();
+
+        let result = unsafe {
+            #[cfg(esp_idf_version_major = "4")]
+            let result =
+                mbedtls_pk_parse_key(&mut this.0, key.as_ptr(), key.len() as _, ptr::null(), 0);
+            #[cfg(not(esp_idf_version_major = "4"))]
+            let result = mbedtls_pk_parse_key(
+                &mut this.0,
+                key.as_ptr(),
+                key.len() as _,
+                ptr::null(),
+                0,
+                Some(random),
+                ptr::null_mut(),
+            );
+
+            result
+        };
+
+        if result != 0 || this.key_type().is_none() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        Ok(this)
+    }
+
+    pub fn key_type(&self) -> Option<KeyType> {
+        let bits = unsafe { mbedtls_pk_get_bitlen(&self.0) };
+
+        match unsafe { mbedtls_pk_get_type(&self.0) } {
+            mbedtls_pk_type_t_MBEDTLS_PK_ECKEY => match bits {
+                256 => Some(KeyType::Ec(Curve::P256)),
+                384 => Some(KeyType::Ec(Curve::P384)),
+                _ => None,
+            },
+            mbedtls_pk_type_t_MBEDTLS_PK_RSA => Some(KeyType::Rsa(bits as _)),
+            _ => None,
+        }
+    }
+
+    pub fn private_key_pem(&mut self) -> Result<String, EspError> {
+        let mut buf = vec![0_u8; 4096];
+
+        check(unsafe { mbedtls_pk_write_key_pem(&mut self.0, buf.as_mut_ptr(), buf.len() as _) })?;
+
+        to_string(buf)
+    }
+
+    pub fn public_key_pem(&mut self) -> Result<String, EspError> {
+        let mut buf = vec![0_u8; 2048];
+
+        check(unsafe {
+            mbedtls_pk_write_pubkey_pem(&mut self.0, buf.as_mut_ptr(), buf.len() as _)
+        })?;
+
+        to_string(buf)
+    }
+
+    /// The DER encoded SubjectPublicKeyInfo
+    pub fn public_key_der(&mut self) -> Result<Vec<u8>, EspError> {
+        let mut buf = vec![0_u8; 1024];
+
+        // The DER is written at the end of the buffer
+        let len =
+            unsafe { mbedtls_pk_write_pubkey_der(&mut self.0, buf.as_mut_ptr(), buf.len() as _) };
+
+        check(len.min(0))?;
+
+        Ok(buf.split_off(buf.len() - len as usize))
+    }
+
+    /// Signs the SHA-256 of `data`, returning a DER encoded ECDSA signature, or a PKCS #1 v1.5
+    /// RSA one
+    pub fn sign_sha256(&mut self, data: &[u8]) -> Result<Vec<u8>, EspError> {
+        let digest = sha256::digest(data);
+
+        let mut signature = vec![0_u8; MBEDTLS_PK_SIGNATURE_MAX_SIZE as usize];
+        let mut len = 0;
+
+        check(unsafe {
+            #[cfg(esp_idf_version_major = "4")]
+            let result = mbedtls_pk_sign(
+                &mut self.0,
+                mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+                digest.as_ptr(),
+                digest.len() as _,
+                signature.as_mut_ptr(),
+                &mut len,
+                Some(random),
+                ptr::null_mut(),
+            );
+            #[cfg(not(esp_idf_version_major = "4"))]
+            let result = mbedtls_pk_sign(
+                &mut self.0,
+                mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+                digest.as_ptr(),
+                digest.len() as _,
+                signature.as_mut_ptr(),
+                signature.len() as _,
+                &mut len,
+                Some(random),
+                ptr::null_mut(),
+            );
+
+            result
+        })?;
+
+        signature.truncate(len as usize);
+
+        Ok(signature)
+    }
+
+    pub fn csr_pem(&mut self, conf: &CsrConfiguration) -> Result<String, EspError> {
+        let mut buf = vec![0_u8; 4096];
+
+        self.write_csr(conf, |csr| unsafe {
+            check(mbedtls_x509write_csr_pem(
+                csr,
+                buf.as_mut_ptr(),
+                buf.len() as _,
+                Some(random),
+                ptr::null_mut(),
+            ))
+        })?;
+
+        to_string(buf)
+    }
+
+    pub fn csr_der(&mut self, conf: &CsrConfiguration) -> Result<Vec<u8>, EspError> {
+        let mut buf = vec![0_u8; 4096];
+
+        let len = self.write_csr(conf, |csr| unsafe {
+            // The DER is written at the end of the buffer
+            let len = mbedtls_x509write_csr_der(
+                csr,
+                buf.as_mut_ptr(),
+                buf.len() as _,
+                Some(random),
+                ptr::null_mut(),
+            );
+
+            check(len.min(0)).map(|_| len as usize)
+        })?;
+
+        Ok(buf.split_off(buf.len() - len))
+    }
+
+    /// The X and Y coordinates of a P-256 public key, e.g. for a JWK
+    pub(crate) fn public_point(&mut self) -> Result<([u8; 32], [u8; 32]), EspError> {
+        if self.key_type() != Some(KeyType::Ec(Curve::P256)) {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        // The SubjectPublicKeyInfo ends with the uncompressed point
+        let der = self.public_key_der()?;
+
+        let point = der
+            .len()
+            .checked_sub(65)
+            .map(|start| &der[start..])
+            .filter(|point| point[0] == 0x04)
+            .ok_or_else(EspError::from_infallible::<ESP_FAIL>)?;
+
+        let mut x = [0_u8; 32];
+        let mut y = [0_u8; 32];
+
+        x.copy_from_slice(&point[1..33]);
+        y.copy_from_slice(&point[33..]);
+
+        Ok((x, y))
+    }
+
+    /// Signs the SHA-256 of `data` with a P-256 key, returning the signature in the raw
+    /// `r || s` form of JWS
+    pub(crate) fn sign_es256(&mut self, data: &[u8]) -> Result<[u8; 64], EspError> {
+        if self.key_type() != Some(KeyType::Ec(Curve::P256)) {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let signature = self.sign_sha256(data)?;
+
+        der_to_raw(&signature).ok_or_else(EspError::from_infallible::<ESP_FAIL>)
+    }
+
+    fn write_csr<R>(
+        &mut self,
+        conf: &CsrConfiguration,
+        write: impl FnOnce(*mut mbedtls_x509write_csr) -> Result<R, EspError>,
+    ) -> Result<R, EspError> {
+        let subject = CString::new(conf.subject.as_str())
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+        let alt_names = subject_alt_names(conf);
+
+        let mut csr: mbedtls_x509write_csr = Default::default();
+
+        unsafe {
+            mbedtls_x509write_csr_init(&mut csr);
+
+            mbedtls_x509write_csr_set_md_alg(&mut csr, mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+            mbedtls_x509write_csr_set_key(&mut csr, &mut self.0);
+
+            let result = check(mbedtls_x509write_csr_set_subject_name(
+                &mut csr,
+                subject.as_ptr(),
+            ))
+            .and_then(|_| {
+                let alt_names = match alt_names.as_ref() {
+                    Some(alt_names) => alt_names,
+                    None => return Ok(()),
+                };
+
+                #[cfg(esp_idf_version_major = "4")]
+                let result = mbedtls_x509write_csr_set_extension(
+                    &mut csr,
+                    OID_SUBJECT_ALT_NAME.as_ptr() as *const _,
+                    OID_SUBJECT_ALT_NAME.len() as _,
+                    alt_names.as_ptr(),
+                    alt_names.len() as _,
+                );
+                #[cfg(not(esp_idf_version_major = "4"))]
+                let result = mbedtls_x509write_csr_set_extension(
+                    &mut csr,
+                    OID_SUBJECT_ALT_NAME.as_ptr() as *const _,
+                    OID_SUBJECT_ALT_NAME.len() as _,
+                    0,
+                    alt_names.as_ptr(),
+                    alt_names.len() as _,
+                );
+
+                check(result)
+            })
+            .and_then(|_| write(&mut csr));
+
+            mbedtls_x509write_csr_free(&mut csr);
+
+            result
+        }
+    }
+
+    fn new() -> Self {
+        let mut pk: mbedtls_pk_context = Default::default();
+
+        unsafe { mbedtls_pk_init(&mut pk) };
+
+        Self(pk)
+    }
+
+    fn context(&mut self) -> *mut ffi::c_void {
+        #[cfg(esp_idf_version_major = "4")]
+        let context = self.0.pk_ctx;
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let context = self.0.private_pk_ctx;
+
+        context
+    }
+}
+
+impl Drop for KeyPair {
+    fn drop(&mut self) {
+        unsafe { mbedtls_pk_free(&mut self.0) };
+    }
+}
+
+unsafe impl Send for KeyPair {}
+
+/// The random generator callback of mbedTLS, over the hardware RNG
+pub(crate) unsafe extern "C" fn random(
+    _context: *mut ffi::c_void,
+    output: *mut ffi::c_uchar,
+    len: usize,
+) -> ffi::c_int {
+    esp_fill_random(output as *mut _, len as _);
+
+    0
+}
+
+fn check(result: ffi::c_int) -> Result<(), EspError> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(EspError::from_infallible::<ESP_FAIL>())
+    }
+}
+
+/// The NUL terminated string written by mbedTLS
+fn to_string(mut buf: Vec<u8>) -> Result<String, EspError> {
+    let len = buf.iter().position(|byte| *byte == 0).unwrap_or(buf.len());
+    buf.truncate(len);
+
+    String::from_utf8(buf).map_err(|_| EspError::from_infallible::<ESP_FAIL>())
+}
+
+/// Converts a DER encoded ECDSA P-256 signature, i.e. `SEQUENCE { INTEGER r, INTEGER s }`,
+/// to `r || s`
+fn der_to_raw(der: &[u8]) -> Option<[u8; 64]> {
+    let mut raw = [0_u8; 64];
+
+    let body = match der {
+        [0x30, len, body @ ..] if *len as usize == body.len() => body,
+        _ => return None,
+    };
+
+    let mut rest = body;
+
+    for half in raw.chunks_mut(32) {
+        let (integer, next) = match rest {
+            [0x02, len, tail @ ..] if *len as usize <= tail.len() => tail.split_at(*len as usize),
+            _ => return None,
+        };
+
+        // Without the sign byte
+        let integer = &integer[integer.iter().take_while(|byte| **byte == 0).count()..];
+
+        if integer.len() > 32 {
+            return None;
+        }
+
+        half[32 - integer.len()..].copy_from_slice(integer);
+
+        rest = next;
+    }
+
+    Some(raw)
+}
+
+/// The DER encoded value of the subjectAltName extension, if there are any alternative names
+fn subject_alt_names(conf: &CsrConfiguration) -> Option<Vec<u8>> {
+    if conf.dns_names.is_empty() && conf.ip_addresses.is_empty() {
+        return None;
+    }
+
+    let mut names = Vec::new();
+
+    for dns_name in &conf.dns_names {
+        // [2] IMPLICIT IA5String
+        names.push(0x82);
+        push_der_len(&mut names, dns_name.len());
+        names.extend_from_slice(dns_name.as_bytes());
+    }
+
+    for ip_address in &conf.ip_addresses {
+        // [7] IMPLICIT OCTET STRING
+        names.push(0x87);
+
+        match ip_address {
+            IpAddr::V4(address) => {
+                names.push(4);
+                names.extend_from_slice(&address.octets());
+            }
+            IpAddr::V6(address) => {
+                names.push(16);
+                names.extend_from_slice(&address.octets());
+            }
+        }
+    }
+
+    let mut der = vec![0x30];
+    push_der_len(&mut der, names.len());
+    der.extend_from_slice(&names);
+
+    Some(der)
+}
+
+fn push_der_len(der: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        der.push(len as u8);
+    } else if len < 0x100 {
+        der.extend_from_slice(&[0x81, len as u8]);
+    } else {
+        der.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]);
+    }
+}
@@ -0,0 +1,630 @@
+//! Span recorder and OTLP trace exporter
+//!
+//! A minimal tracing facility: `Tracer` records finished spans into a bounded ring buffer
+//! (the oldest spans being dropped when it is full), and `EspOtlpExporter` periodically
+//! sends them in batches to an OpenTelemetry collector, as OTLP/HTTP JSON.
+//!
+//! Spans are timestamped with the monotonic `esp_timer` clock, and only converted to wall
+//! clock time on export, so the traces are only properly placed in time once the system
+//! time has been set (e.g. with `EspSntp`).
+//!
+//! To make the on-device work part of a distributed trace, continue the trace of an incoming
+//! request with `SpanContext::from_traceparent` and `Tracer::child_span`, and pass
+//! `SpanContext::traceparent` along outgoing requests.
+use core::fmt::Write;
+
+use std::collections::VecDeque;
+use std::string::String;
+use std::sync::Arc;
+use std::vec::Vec;
+
+use esp_idf_sys::*;
+
+use crate::private::hex;
+use crate::private::mutex::{Mutex, RawMutex};
+
+#[cfg(all(feature = "experimental", esp_idf_comp_esp_http_client_enabled))]
+pub use exporter::*;
+
+pub type TraceId = [u8; 16];
+pub type SpanId = [u8; 8];
+
+/// The identity of a span, as propagated across services
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct SpanContext {
+    pub trace_id: TraceId,
+    pub span_id: SpanId,
+    pub sampled: bool,
+}
+
+impl SpanContext {
+    /// Parses a W3C Trace Context `traceparent` header, as in
+    /// `00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+
+        if version.len() != 2 || version == "ff" || flags.len() != 2 {
+            return None;
+        }
+
+        let context = Self {
+            trace_id: hex::decode(trace_id)?,
+            span_id: hex::decode(span_id)?,
+            sampled: u8::from_str_radix(flags, 16).ok()? & 0x01 != 0,
+        };
+
+        // All-zero identifiers are invalid
+        if context.trace_id == [0; 16] || context.span_id == [0; 8] {
+            None
+        } else {
+            Some(context)
+        }
+    }
+
+    /// Renders the `traceparent` header to propagate this context
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex::encode(&self.trace_id),
+            hex::encode(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+    Producer = 4,
+    Consumer = 5,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.into())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<f64> for AttributeValue {
+    fn from(value: f64) -> Self {
+        Self::Double(value)
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SpanStatus {
+    Unset,
+    Ok,
+    Error(String),
+}
+
+/// A finished span
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SpanData {
+    pub context: SpanContext,
+    pub parent_span_id: Option<SpanId>,
+    pub name: String,
+    pub kind: SpanKind,
+    /// The start time, in microseconds of the `esp_timer` clock
+    pub start: i64,
+    /// The end time, in microseconds of the `esp_timer` clock
+    pub end: i64,
+    pub attributes: Vec<(String, AttributeValue)>,
+    pub status: SpanStatus,
+}
+
+struct Ring {
+    spans: VecDeque<SpanData>,
+    capacity: usize,
+    dropped: u64,
+}
+
+/// Records spans into a bounded ring buffer; cheap to clone
+#[derive(Clone)]
+pub struct Tracer(Arc<Mutex<Ring>>);
+
+impl Tracer {
+    /// Creates a tracer keeping at most `capacity` finished spans until they are exported
+    pub fn new(capacity: usize) -> Self {
+        Self(Arc::new(Mutex::wrap(
+            RawMutex::new(),
+            Ring {
+                spans: VecDeque::with_capacity(capacity),
+                capacity,
+                dropped: 0,
+            },
+        )))
+    }
+
+    /// Starts a span of a new trace
+    pub fn span(&self, name: impl Into<String>) -> Span {
+        let mut trace_id = [0; 16];
+        fill_random(&mut trace_id);
+
+        self.start(name.into(), trace_id, None, true)
+    }
+
+    /// Starts a span continuing the trace of `parent`, which can be the context
+    /// of another span of this tracer, or come from an incoming request
+    pub fn child_span(&self, name: impl Into<String>, parent: &SpanContext) -> Span {
+        self.start(
+            name.into(),
+            parent.trace_id,
+            Some(parent.span_id),
+            parent.sampled,
+        )
+    }
+
+    /// Removes and returns up to `max` of the oldest finished spans
+    pub fn drain(&self, max: usize) -> Vec<SpanData> {
+        let mut ring = self.0.lock();
+
+        let len = ring.spans.len().min(max);
+
+        ring.spans.drain(..len).collect()
+    }
+
+    /// The number of finished spans waiting to be exported
+    pub fn len(&self) -> usize {
+        self.0.lock().spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of spans dropped because the ring buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.0.lock().dropped
+    }
+
+    fn start(
+        &self,
+        name: String,
+        trace_id: TraceId,
+        parent_span_id: Option<SpanId>,
+        sampled: bool,
+    ) -> Span {
+        let mut span_id = [0; 8];
+        fill_random(&mut span_id);
+
+        Span {
+            tracer: self.clone(),
+            data: Some(SpanData {
+                context: SpanContext {
+                    trace_id,
+                    span_id,
+                    sampled,
+                },
+                parent_span_id,
+                name,
+                kind: SpanKind::Internal,
+                start: now(),
+                end: 0,
+                attributes: Vec::new(),
+                status: SpanStatus::Unset,
+            }),
+        }
+    }
+
+    fn record(&self, span: SpanData) {
+        if !span.context.sampled {
+            return;
+        }
+
+        let mut ring = self.0.lock();
+
+        if ring.capacity == 0 {
+            ring.dropped += 1;
+            return;
+        }
+
+        if ring.spans.len() >= ring.capacity {
+            ring.spans.pop_front();
+            ring.dropped += 1;
+        }
+
+        ring.spans.push_back(span);
+    }
+}
+
+/// A span in progress, recorded when ended or dropped
+pub struct Span {
+    tracer: Tracer,
+    data: Option<SpanData>,
+}
+
+impl Span {
+    pub fn context(&self) -> SpanContext {
+        self.data().context
+    }
+
+    /// Starts a child span of this span
+    pub fn child(&self, name: impl Into<String>) -> Span {
+        self.tracer.child_span(name, &self.context())
+    }
+
+    pub fn set_kind(&mut self, kind: SpanKind) -> &mut Self {
+        self.data_mut().kind = kind;
+        self
+    }
+
+    pub fn set_attribute(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<AttributeValue>,
+    ) -> &mut Self {
+        self.data_mut().attributes.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn set_status(&mut self, status: SpanStatus) -> &mut Self {
+        self.data_mut().status = status;
+        self
+    }
+
+    pub fn end(self) {}
+
+    fn data(&self) -> &SpanData {
+        self.data.as_ref().unwrap()
+    }
+
+    fn data_mut(&mut self) -> &mut SpanData {
+        self.data.as_mut().unwrap()
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            data.end = now();
+
+            self.tracer.record(data);
+        }
+    }
+}
+
+fn now() -> i64 {
+    unsafe { esp_timer_get_time() }
+}
+
+fn fill_random(buf: &mut [u8]) {
+    unsafe { esp_fill_random(buf.as_mut_ptr() as *mut _, buf.len() as _) };
+}
+
+/// Renders `spans` as an OTLP `ExportTraceServiceRequest` in the JSON encoding
+///
+/// `offset` is the Unix time of the `esp_timer` origin, in microseconds.
+pub fn render_otlp_json(service_name: &str, spans: &[SpanData], offset: i64) -> String {
+    let mut json = String::new();
+
+    json.push_str("{\"resourceSpans\":[{\"resource\":{\"attributes\":[");
+    render_attribute(
+        &mut json,
+        "service.name",
+        &AttributeValue::String(service_name.into()),
+    );
+    json.push_str("]},\"scopeSpans\":[{\"scope\":{\"name\":\"esp-idf-svc\"},\"spans\":[");
+
+    for (index, span) in spans.iter().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+
+        write!(
+            &mut json,
+            "{{\"traceId\":\"{}\",\"spanId\":\"{}\",",
+            hex::encode(&span.context.trace_id),
+            hex::encode(&span.context.span_id)
+        )
+        .unwrap();
+
+        if let Some(parent_span_id) = &span.parent_span_id {
+            write!(
+                &mut json,
+                "\"parentSpanId\":\"{}\",",
+                hex::encode(parent_span_id)
+            )
+            .unwrap();
+        }
+
+        json.push_str("\"name\":");
+        render_string(&mut json, &span.name);
+
+        // 64-bit integers are rendered as strings in the OTLP JSON encoding
+        write!(
+            &mut json,
+            ",\"kind\":{},\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[",
+            span.kind as u8,
+            (span.start + offset).max(0) as u64 * 1000,
+            (span.end + offset).max(0) as u64 * 1000
+        )
+        .unwrap();
+
+        for (index, (key, value)) in span.attributes.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+
+            render_attribute(&mut json, key, value);
+        }
+
+        json.push_str("],\"status\":");
+
+        match &span.status {
+            SpanStatus::Unset => json.push_str("{}"),
+            SpanStatus::Ok => json.push_str("{\"code\":1}"),
+            SpanStatus::Error(message) => {
+                json.push_str("{\"code\":2,\"message\":");
+                render_string(&mut json, message);
+                json.push('}');
+            }
+        }
+
+        json.push('}');
+    }
+
+    json.push_str("]}]}]}");
+
+    json
+}
+
+fn render_attribute(json: &mut String, key: &str, value: &AttributeValue) {
+    json.push_str("{\"key\":");
+    render_string(json, key);
+    json.push_str(",\"value\":{");
+
+    match value {
+        AttributeValue::String(value) => {
+            json.push_str("\"stringValue\":");
+            render_string(json, value);
+        }
+        AttributeValue::Int(value) => write!(json, "\"intValue\":\"{}\"", value).unwrap(),
+        AttributeValue::Double(value) if value.is_finite() => {
+            write!(json, "\"doubleValue\":{}", value).unwrap()
+        }
+        AttributeValue::Double(value) => write!(json, "\"doubleValue\":\"{}\"", value).unwrap(),
+        AttributeValue::Bool(value) => write!(json, "\"boolValue\":{}", value).unwrap(),
+    }
+
+    json.push_str("}}");
+}
+
+fn render_string(json: &mut String, s: &str) {
+    json.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+}
+
+#[cfg(all(feature = "experimental", esp_idf_comp_esp_http_client_enabled))]
+mod exporter {
+    use core::time::Duration;
+
+    use std::string::String;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use std::vec::Vec;
+
+    use ::log::*;
+
+    use embedded_svc::http::Method;
+
+    use esp_idf_sys::*;
+
+    use crate::http::client::{Configuration, EspHttpConnection};
+
+    use super::{now, render_otlp_json, Tracer};
+
+    #[derive(Clone, Debug)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub struct ExporterConfiguration {
+        /// The OTLP/HTTP traces endpoint, as in `http://collector:4318/v1/traces`
+        pub endpoint: String,
+        /// The `service.name` resource attribute of the spans
+        pub service_name: String,
+        /// Additional request headers, e.g. for authentication
+        pub headers: Vec<(String, String)>,
+        pub interval: Duration,
+        /// The maximum number of spans sent per request
+        pub batch_size: usize,
+        pub timeout: Duration,
+        pub stack_size: usize,
+    }
+
+    impl Default for ExporterConfiguration {
+        fn default() -> Self {
+            Self {
+                endpoint: String::new(),
+                service_name: "esp-idf".into(),
+                headers: Vec::new(),
+                interval: Duration::from_secs(10),
+                batch_size: 64,
+                timeout: Duration::from_secs(10),
+                stack_size: 8192,
+            }
+        }
+    }
+
+    /// Periodically exports the spans recorded by a `Tracer`
+    ///
+    /// The remaining spans are exported when dropped. Spans which fail to be
+    /// exported are dropped rather than retried, so as not to fill up the ring buffer.
+    pub struct EspOtlpExporter {
+        stop: mpsc::Sender<()>,
+        join_handle: Option<thread::JoinHandle<()>>,
+    }
+
+    impl EspOtlpExporter {
+        pub fn new(conf: &ExporterConfiguration, tracer: &Tracer) -> Result<Self, EspError> {
+            let (stop, stopped) = mpsc::channel();
+
+            let join_handle = {
+                let conf = conf.clone();
+                let tracer = tracer.clone();
+
+                thread::Builder::new()
+                    .name("otlp-exporter".into())
+                    .stack_size(conf.stack_size)
+                    .spawn(move || Self::run(conf, tracer, stopped))
+                    .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+            };
+
+            info!("Started OTLP exporter to {}", conf.endpoint);
+
+            Ok(Self {
+                stop,
+                join_handle: Some(join_handle),
+            })
+        }
+
+        /// Exports all spans recorded so far, returning their number
+        pub fn export(conf: &ExporterConfiguration, tracer: &Tracer) -> Result<usize, EspError> {
+            let mut exported = 0;
+
+            loop {
+                let spans = tracer.drain(conf.batch_size.max(1));
+                if spans.is_empty() {
+                    break;
+                }
+
+                let json = render_otlp_json(&conf.service_name, &spans, Self::offset());
+
+                Self::post(conf, json.as_bytes())?;
+
+                exported += spans.len();
+            }
+
+            Ok(exported)
+        }
+
+        fn run(conf: ExporterConfiguration, tracer: Tracer, stopped: mpsc::Receiver<()>) {
+            loop {
+                let stop = !matches!(
+                    stopped.recv_timeout(conf.interval),
+                    Err(mpsc::RecvTimeoutError::Timeout)
+                );
+
+                match Self::export(&conf, &tracer) {
+                    Ok(0) => (),
+                    Ok(exported) => debug!("Exported {} spans", exported),
+                    Err(e) => warn!("Failed to export spans: {}", e),
+                }
+
+                if stop {
+                    break;
+                }
+            }
+        }
+
+        /// The Unix time of the `esp_timer` origin, in microseconds
+        fn offset() -> i64 {
+            let unix = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_micros() as i64)
+                .unwrap_or(0);
+
+            unix - now()
+        }
+
+        fn post(conf: &ExporterConfiguration, body: &[u8]) -> Result<(), EspError> {
+            let mut connection = EspHttpConnection::new(&Configuration {
+                timeout: Some(conf.timeout),
+                ..Default::default()
+            })?;
+
+            let len = body.len().to_string();
+
+            let mut headers = vec![
+                ("Content-Type", "application/json"),
+                ("Content-Length", len.as_str()),
+            ];
+
+            headers.extend(
+                conf.headers
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str())),
+            );
+
+            connection.initiate_request(Method::Post, &conf.endpoint, &headers)?;
+
+            let mut offset = 0;
+            while offset < body.len() {
+                offset += connection.write(&body[offset..])?;
+            }
+
+            connection.initiate_response()?;
+
+            let status = connection.status();
+
+            if (200..300).contains(&status) {
+                Ok(())
+            } else {
+                warn!("OTLP collector answered with status {}", status);
+
+                Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>())
+            }
+        }
+    }
+
+    impl Drop for EspOtlpExporter {
+        fn drop(&mut self) {
+            let _ = self.stop.send(());
+
+            if let Some(join_handle) = self.join_handle.take() {
+                let _ = join_handle.join();
+            }
+
+            info!("Dropped");
+        }
+    }
+}
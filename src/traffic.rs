@@ -0,0 +1,191 @@
+//! Per-service traffic accounting and throttling
+//!
+//! The HTTP, MQTT and WebSocket clients account the bytes they send and receive to the
+//! `Service` they belong to, so that the data usage of each of them can be monitored,
+//! e.g. on metered cellular links. The OTA services account their downloads to `Service::Ota`,
+//! and HTTP client connections can be accounted to another service with
+//! `Configuration::service`.
+//!
+//! A `Throttle` is a token bucket limiting the bandwidth of background transfers, so that they
+//! do not starve the interactive traffic:
+//!
+//! ```ignore
+//! static OTA_THROTTLE: Throttle = Throttle::new(16 * 1024, 4 * 1024);
+//!
+//! let scheduler = EspOtaScheduler::new(&ota_scheduler::Configuration {
+//!     throttle: Some(&OTA_THROTTLE),
+//!     ..Default::default()
+//! }, ...)?;
+//!
+//! info!("OTA downloads: {} bytes", traffic::bytes(Service::Ota).rx);
+//! ```
+//!
+//! The counts are the application payloads: protocol overhead (TLS, TCP/IP) is not included.
+//!
+//! Note: This module requires the `traffic` cargo feature to be enabled.
+use core::fmt::{self, Debug};
+use core::time::Duration;
+
+use std::thread;
+
+use esp_idf_sys::*;
+
+use crate::private::mutex::{Mutex, RawMutex};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Service {
+    Http,
+    Mqtt,
+    Ws,
+    Ota,
+    /// Transfers of the application, e.g. log uploads
+    Other,
+}
+
+impl Service {
+    pub const ALL: [Service; 5] = [Self::Http, Self::Mqtt, Self::Ws, Self::Ota, Self::Other];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::Mqtt => "mqtt",
+            Self::Ws => "ws",
+            Self::Ota => "ota",
+            Self::Other => "other",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl Default for Service {
+    fn default() -> Self {
+        Self::Http
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Bytes {
+    pub rx: u64,
+    pub tx: u64,
+}
+
+static COUNTERS: Mutex<[Bytes; 5]> = Mutex::wrap(RawMutex::new(), [Bytes { rx: 0, tx: 0 }; 5]);
+
+/// Accounts `len` received bytes to `service`
+pub fn record_rx(service: Service, len: usize) {
+    COUNTERS.lock()[service.index()].rx += len as u64;
+}
+
+/// Accounts `len` sent bytes to `service`
+pub fn record_tx(service: Service, len: usize) {
+    COUNTERS.lock()[service.index()].tx += len as u64;
+}
+
+/// The bytes accounted to `service` since the start, or the last `reset`
+pub fn bytes(service: Service) -> Bytes {
+    COUNTERS.lock()[service.index()]
+}
+
+/// The bytes accounted to each service
+pub fn all() -> [(Service, Bytes); 5] {
+    let counters = *COUNTERS.lock();
+
+    Service::ALL.map(|service| (service, counters[service.index()]))
+}
+
+pub fn reset() {
+    *COUNTERS.lock() = Default::default();
+}
+
+struct Bucket {
+    /// Bytes per second, 0 meaning unlimited
+    rate: u32,
+    burst: u32,
+    /// Negative when a transfer larger than the available tokens has been let through
+    tokens: i64,
+    /// The time of the last refill, in microseconds since boot
+    refilled_at: i64,
+}
+
+/// A token bucket throttle, shared by the transfers it limits
+pub struct Throttle(Mutex<Bucket>);
+
+impl Throttle {
+    /// A throttle letting `rate` bytes per second through on average, and bursts of up to
+    /// `burst` bytes
+    pub const fn new(rate: u32, burst: u32) -> Self {
+        Self(Mutex::wrap(
+            RawMutex::new(),
+            Bucket {
+                rate,
+                burst,
+                tokens: burst as i64,
+                refilled_at: 0,
+            },
+        ))
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.0.lock().rate
+    }
+
+    /// Changes the rate, 0 disabling the throttling, e.g. when switching from a cellular
+    /// link to WiFi
+    pub fn set_rate(&self, rate: u32) {
+        self.0.lock().rate = rate;
+    }
+
+    /// Takes `len` bytes from the bucket, waiting for it to refill as needed
+    ///
+    /// Transfers larger than the bucket are let through, and the following ones delayed
+    /// accordingly.
+    pub fn acquire(&self, len: usize) {
+        let wait = {
+            let mut bucket = self.0.lock();
+
+            if bucket.rate == 0 {
+                return;
+            }
+
+            let now = unsafe { esp_timer_get_time() };
+
+            if bucket.refilled_at > 0 {
+                let refill = (now - bucket.refilled_at) * bucket.rate as i64 / 1_000_000;
+
+                bucket.tokens = (bucket.tokens + refill).min(bucket.burst as i64);
+            }
+
+            bucket.refilled_at = now;
+            bucket.tokens -= len as i64;
+
+            if bucket.tokens < 0 {
+                Some(Duration::from_micros(
+                    (-bucket.tokens) as u64 * 1_000_000 / bucket.rate as u64,
+                ))
+            } else {
+                None
+            }
+        };
+
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl Debug for Throttle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bucket = self.0.lock();
+
+        f.debug_struct("Throttle")
+            .field("rate", &bucket.rate)
+            .field("burst", &bucket.burst)
+            .finish()
+    }
+}
@@ -0,0 +1,512 @@
+//! TWAI (CAN) service
+//!
+//! `EspTwai` takes over a `CanDriver` and services it on a background thread: the received
+//! frames are filtered and queued, to be read with the `recv` future or blocking with
+//! `recv_timeout`, and the frames passed to `send` are queued for transmission, so that
+//! neither direction blocks the caller. When the controller goes bus-off, the recovery is
+//! initiated and the controller is restarted once it has recovered:
+//!
+//! ```ignore
+//! let driver = CanDriver::new(
+//!     peripherals.can,
+//!     pins.gpio5,
+//!     pins.gpio4,
+//!     &can::config::Config::new().timing(can::config::Timing::B500K),
+//! )?;
+//!
+//! let twai = Arc::new(EspTwai::new(
+//!     &Configuration {
+//!         filters: vec![IdFilter::new(0x100, 0x700)],
+//!         ..Default::default()
+//!     },
+//!     driver,
+//! )?);
+//!
+//! twai.send(Frame::new(0x123, false, &[1, 2, 3]).unwrap())?;
+//!
+//! let frame = twai.recv().await;
+//! ```
+//!
+//! The hardware acceptance filter is configured on the driver; `Configuration::filters` are
+//! additional software filters, as the hardware can only match a single identifier pattern.
+//!
+//! With `EspTwaiBridge`, the bus can be bridged to a UDP peer, or to anything else - like an
+//! MQTT topic - which can carry the text encoding of the frames (`encode` / `decode`):
+//!
+//! ```ignore
+//! let _bridge = EspTwaiBridge::new(twai.clone(), move |frame| {
+//!     let _ = mqtt.lock().publish("can/rx", QoS::AtMostOnce, false, frame.as_bytes());
+//! })?;
+//!
+//! // In the MQTT callback of the `can/tx` subscription
+//! if let Some(frame) = twai::decode(payload) {
+//!     twai.send(frame)?;
+//! }
+//! ```
+use core::fmt::Write as _;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket};
+use std::string::String;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_hal::can::{CanDriver, Frame};
+use esp_idf_hal::delay::TickType;
+
+use esp_idf_sys::*;
+
+use crate::private::mutex::Mutex;
+use crate::private::waitable::*;
+
+/// How long the service thread waits for a received frame, between two transmissions
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A software acceptance filter: a frame is accepted if its identifier, masked with `mask`,
+/// equals `id`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct IdFilter {
+    pub id: u32,
+    pub mask: u32,
+    /// Only match extended (or standard, if `false`) frames; both if `None`
+    pub extended: Option<bool>,
+}
+
+impl IdFilter {
+    pub fn new(id: u32, mask: u32) -> Self {
+        Self {
+            id,
+            mask,
+            extended: None,
+        }
+    }
+
+    pub fn matches(&self, frame: &Frame) -> bool {
+        self.extended
+            .map(|extended| extended == frame.is_extended())
+            .unwrap_or(true)
+            && frame.identifier() & self.mask == self.id & self.mask
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The frames not matching any of these are dropped; all frames are accepted if empty
+    pub filters: Vec<IdFilter>,
+    /// How many received frames are kept until they are read; the oldest frames are dropped
+    pub rx_queue: usize,
+    pub tx_timeout: Duration,
+    /// Recover automatically from the bus-off state
+    pub recovery: bool,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            filters: Vec::new(),
+            rx_queue: 32,
+            tx_timeout: Duration::from_millis(100),
+            recovery: true,
+            stack_size: 4096,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct TwaiStats {
+    pub received: u32,
+    /// The received frames dropped because the queue was full
+    pub dropped: u32,
+    pub transmitted: u32,
+    pub tx_failed: u32,
+    pub bus_off: u32,
+}
+
+#[derive(Default)]
+struct State {
+    frames: VecDeque<Frame>,
+    waker: Option<Waker>,
+    stats: TwaiStats,
+}
+
+enum Command {
+    Send(Frame),
+    Stop,
+}
+
+pub struct EspTwai {
+    state: Arc<Waitable<State>>,
+    commands: Mutex<mpsc::Sender<Command>>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspTwai {
+    pub fn new(conf: &Configuration, driver: CanDriver<'static>) -> Result<Self, EspError> {
+        if conf.rx_queue == 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let state = Arc::new(Waitable::new(State::default()));
+
+        let (commands, receiver) = mpsc::channel();
+
+        let join_handle = {
+            let conf = conf.clone();
+            let state = state.clone();
+
+            thread::Builder::new()
+                .name("twai".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, driver, state, receiver))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        Ok(Self {
+            state,
+            commands: Mutex::new(commands),
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// Queues `frame` for transmission
+    pub fn send(&self, frame: Frame) -> Result<(), EspError> {
+        self.commands
+            .lock()
+            .send(Command::Send(frame))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+    }
+
+    /// Returns a future resolving with the next received frame
+    pub fn recv(&self) -> TwaiFrame<'_> {
+        TwaiFrame(self)
+    }
+
+    pub fn try_recv(&self) -> Option<Frame> {
+        self.state.get_mut(|state| state.frames.pop_front())
+    }
+
+    /// Waits for the next received frame, forever if `timeout` is `None`
+    pub fn recv_timeout(&self, timeout: Option<Duration>) -> Option<Frame> {
+        let condition = |state: &State| state.frames.is_empty();
+
+        if let Some(timeout) = timeout {
+            self.state.wait_timeout_while(timeout, condition);
+        } else {
+            self.state.wait_while(condition);
+        }
+
+        self.try_recv()
+    }
+
+    pub fn stats(&self) -> TwaiStats {
+        self.state.get(|state| state.stats)
+    }
+
+    fn run(
+        conf: Configuration,
+        mut driver: CanDriver<'static>,
+        state: Arc<Waitable<State>>,
+        commands: mpsc::Receiver<Command>,
+    ) {
+        let mut recovering = false;
+
+        loop {
+            loop {
+                match commands.try_recv() {
+                    Ok(Command::Send(frame)) => {
+                        let result = driver.transmit(&frame, TickType::from(conf.tx_timeout).0);
+
+                        if let Err(e) = &result {
+                            debug!("TWAI transmission failed: {}", e);
+                        }
+
+                        state.get_mut(|state| {
+                            if result.is_ok() {
+                                state.stats.transmitted += 1;
+                            } else {
+                                state.stats.tx_failed += 1;
+                            }
+                        });
+                    }
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    _ => return,
+                }
+            }
+
+            match driver.receive(TickType::from(POLL_INTERVAL).0) {
+                Ok(frame) => {
+                    if conf.filters.is_empty()
+                        || conf.filters.iter().any(|filter| filter.matches(&frame))
+                    {
+                        state.get_mut(|state| {
+                            state.stats.received += 1;
+
+                            if state.frames.len() >= conf.rx_queue {
+                                state.frames.pop_front();
+                                state.stats.dropped += 1;
+                            }
+
+                            state.frames.push_back(frame);
+
+                            if let Some(waker) = state.waker.take() {
+                                waker.wake();
+                            }
+                        });
+
+                        state.cvar.notify_all();
+                    }
+                }
+                Err(e) if e.code() == ESP_ERR_TIMEOUT as esp_err_t => (),
+                Err(_) => {
+                    // Not driven by the receive timeout, e.g. while bus-off
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+
+            if conf.recovery {
+                recovering = Self::recover(&state, recovering);
+            }
+        }
+    }
+
+    /// Initiates the recovery when bus-off, and restarts the controller once recovered
+    fn recover(state: &Waitable<State>, recovering: bool) -> bool {
+        let mut status: twai_status_info_t = Default::default();
+
+        if esp!(unsafe { twai_get_status_info(&mut status) }).is_err() {
+            return recovering;
+        }
+
+        #[allow(non_upper_case_globals)]
+        match status.state {
+            twai_state_t_TWAI_STATE_BUS_OFF if !recovering => {
+                warn!("TWAI bus-off, initiating the recovery");
+
+                state.get_mut(|state| state.stats.bus_off += 1);
+
+                if let Err(e) = esp!(unsafe { twai_initiate_recovery() }) {
+                    warn!("Failed to initiate the TWAI recovery: {}", e);
+                    return false;
+                }
+
+                true
+            }
+            twai_state_t_TWAI_STATE_STOPPED if recovering => {
+                info!("TWAI recovered, restarting");
+
+                if let Err(e) = esp!(unsafe { twai_start() }) {
+                    warn!("Failed to restart TWAI: {}", e);
+                }
+
+                false
+            }
+            _ => recovering,
+        }
+    }
+}
+
+impl Drop for EspTwai {
+    fn drop(&mut self) {
+        let _ = self.commands.lock().send(Command::Stop);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+pub struct TwaiFrame<'a>(&'a EspTwai);
+
+impl<'a> Future for TwaiFrame<'a> {
+    type Output = Frame;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.state.get_mut(|state| {
+            if let Some(frame) = state.frames.pop_front() {
+                Poll::Ready(frame)
+            } else {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+/// Encodes a frame in the `cansend` format of the Linux SocketCAN tools, i.e. `123#DEADBEEF`
+/// for a standard frame, `1234ABCD#00` for an extended one, and `123#R` for a remote frame
+pub fn encode(frame: &Frame) -> String {
+    let mut text = String::with_capacity(8 + 1 + 16);
+
+    if frame.is_extended() {
+        write!(&mut text, "{:08X}#", frame.identifier()).unwrap();
+    } else {
+        write!(&mut text, "{:03X}#", frame.identifier()).unwrap();
+    }
+
+    if frame.is_remote_frame() {
+        text.push('R');
+
+        if frame.dlc() > 0 {
+            write!(&mut text, "{}", frame.dlc()).unwrap();
+        }
+    } else {
+        for byte in frame.data() {
+            write!(&mut text, "{:02X}", byte).unwrap();
+        }
+    }
+
+    text
+}
+
+/// Decodes a frame encoded by `encode`
+pub fn decode(text: &str) -> Option<Frame> {
+    let (id, data) = text.trim().split_once('#')?;
+
+    let extended = id.len() > 3;
+
+    let id = u32::from_str_radix(id, 16).ok()?;
+
+    if id > if extended { 0x1FFF_FFFF } else { 0x7FF } {
+        return None;
+    }
+
+    if let Some(dlc) = data.strip_prefix('R') {
+        let dlc = if dlc.is_empty() { 0 } else { dlc.parse().ok()? };
+
+        return Frame::new_remote(id, extended, dlc);
+    }
+
+    // `.` separators are allowed between the bytes, as in `cansend`
+    let data = data.replace('.', "");
+
+    if data.len() % 2 != 0 {
+        return None;
+    }
+
+    let data = (0..data.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(data.get(index..index + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    Frame::new(id, extended, &data)
+}
+
+/// Forwards the received frames of an `EspTwai`, in their text encoding, and optionally
+/// forwards the frames received from a UDP peer to the bus
+pub struct EspTwaiBridge {
+    stop: Arc<Mutex<bool>>,
+    join_handles: Vec<thread::JoinHandle<()>>,
+}
+
+impl EspTwaiBridge {
+    /// Passes the encoding of each received frame to `sink`, e.g. to publish it over MQTT
+    pub fn new(
+        twai: Arc<EspTwai>,
+        mut sink: impl FnMut(&str) + Send + 'static,
+    ) -> Result<Self, EspError> {
+        let stop = Arc::new(Mutex::new(false));
+
+        let join_handle = {
+            let stop = stop.clone();
+
+            Self::spawn("twai-bridge", move || {
+                while !*stop.lock() {
+                    if let Some(frame) = twai.recv_timeout(Some(Duration::from_millis(100))) {
+                        sink(&encode(&frame));
+                    }
+                }
+            })?
+        };
+
+        Ok(Self {
+            stop,
+            join_handles: vec![join_handle],
+        })
+    }
+
+    /// Bridges the bus with `peer`, both ways, with one encoded frame per datagram
+    pub fn udp(twai: Arc<EspTwai>, socket: UdpSocket, peer: SocketAddr) -> Result<Self, EspError> {
+        let rx_socket = socket
+            .try_clone()
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        rx_socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_STATE>())?;
+
+        let mut bridge = Self::new(twai.clone(), move |frame| {
+            if let Err(e) = socket.send_to(frame.as_bytes(), peer) {
+                debug!("Failed to forward a TWAI frame to {}: {}", peer, e);
+            }
+        })?;
+
+        let join_handle = {
+            let stop = bridge.stop.clone();
+
+            Self::spawn("twai-bridge-udp", move || {
+                let mut buf = [0; 64];
+
+                while !*stop.lock() {
+                    let (len, from) = match rx_socket.recv_from(&mut buf) {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+
+                    if from.ip() != peer.ip() {
+                        continue;
+                    }
+
+                    let frame = core::str::from_utf8(&buf[..len]).ok().and_then(decode);
+
+                    if let Some(frame) = frame {
+                        let _ = twai.send(frame);
+                    } else {
+                        debug!("Invalid TWAI frame from {}", from);
+                    }
+                }
+            })?
+        };
+
+        bridge.join_handles.push(join_handle);
+
+        Ok(bridge)
+    }
+
+    fn spawn(
+        name: &str,
+        f: impl FnOnce() + Send + 'static,
+    ) -> Result<thread::JoinHandle<()>, EspError> {
+        thread::Builder::new()
+            .name(name.into())
+            .stack_size(4096)
+            .spawn(f)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())
+    }
+}
+
+impl Drop for EspTwaiBridge {
+    fn drop(&mut self) {
+        *self.stop.lock() = true;
+
+        for join_handle in self.join_handles.drain(..) {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
@@ -0,0 +1,454 @@
+//! Framed UART
+//!
+//! `EspUart` wraps a UART driver and splits the received byte stream into frames - lines,
+//! length-prefixed packets or bursts separated by a silence - on a background thread, so that
+//! serial sensors and modems can be bridged to the network services of this crate. The frames
+//! are received with the `recv` future, or blocking with `recv_timeout`:
+//!
+//! ```ignore
+//! let driver = UartDriver::new(
+//!     peripherals.uart1,
+//!     pins.gpio17,
+//!     pins.gpio16,
+//!     Option::<AnyIOPin>::None,
+//!     Option::<AnyIOPin>::None,
+//!     &config::Config::new().baudrate(Hertz(9600)),
+//! )?;
+//!
+//! let uart = EspUart::new(
+//!     &Configuration {
+//!         framing: Framing::Line { delimiter: b'\n', max_len: 128 },
+//!         ..Default::default()
+//!     },
+//!     driver,
+//! )?;
+//!
+//! loop {
+//!     let line = uart.recv().await;
+//!     mqtt.publish("sensor/raw", QoS::AtMostOnce, false, &line)?;
+//! }
+//! ```
+//!
+//! Hardware (RTS/CTS) flow control is configured on the driver itself; software (XON/XOFF)
+//! flow control is enabled with `Configuration::software_flow_control`.
+//!
+//! `send` frames the data the same way the received data is framed. It only blocks while the
+//! TX ring buffer of the driver is full, so it does not block for frames fitting in it.
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use std::collections::VecDeque;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_hal::delay::TickType;
+use esp_idf_hal::uart::{UartDriver, UartRxDriver, UartTxDriver};
+
+use esp_idf_sys::*;
+
+use crate::private::mutex::Mutex;
+use crate::private::waitable::*;
+
+/// How often the reader thread checks whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum PrefixWidth {
+    U8,
+    /// Big endian
+    U16,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum Framing {
+    /// Frames ending with `delimiter`, which is not part of the received frames; with a `\n`
+    /// delimiter, a preceding `\r` is removed as well
+    Line { delimiter: u8, max_len: usize },
+    /// Frames preceded by their length
+    LengthPrefixed { width: PrefixWidth, max_len: usize },
+    /// Frames ending with a silence of at least `gap`, or once `max_len` bytes are received
+    Timeout { gap: Duration, max_len: usize },
+}
+
+impl Framing {
+    fn max_len(&self) -> usize {
+        match self {
+            Self::Line { max_len, .. }
+            | Self::LengthPrefixed { max_len, .. }
+            | Self::Timeout { max_len, .. } => *max_len,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub framing: Framing,
+    /// How many received frames are kept until they are read; the oldest frames are dropped
+    pub rx_queue: usize,
+    /// The XON and XOFF thresholds of the RX FIFO, enabling software flow control
+    pub software_flow_control: Option<(u8, u8)>,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            framing: Framing::Line {
+                delimiter: b'\n',
+                max_len: 256,
+            },
+            rx_queue: 16,
+            software_flow_control: None,
+            stack_size: 4096,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    frames: VecDeque<Vec<u8>>,
+    waker: Option<Waker>,
+}
+
+pub struct EspUart {
+    framing: Framing,
+    tx: Mutex<UartTxDriver<'static>>,
+    state: Arc<Waitable<State>>,
+    stop: mpsc::Sender<()>,
+    join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl EspUart {
+    pub fn new(conf: &Configuration, driver: UartDriver<'static>) -> Result<Self, EspError> {
+        if conf.framing.max_len() == 0 || conf.rx_queue == 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        if let Framing::LengthPrefixed { width, max_len } = conf.framing {
+            let limit = match width {
+                PrefixWidth::U8 => u8::MAX as usize,
+                PrefixWidth::U16 => u16::MAX as usize,
+            };
+
+            if max_len > limit {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
+        }
+
+        if let Some((xon, xoff)) = conf.software_flow_control {
+            esp!(unsafe { uart_set_sw_flow_ctrl(driver.port(), true, xon, xoff) })?;
+        }
+
+        let (tx, rx) = driver.into_split();
+
+        let state = Arc::new(Waitable::new(State::default()));
+
+        let (stop, stopped) = mpsc::channel();
+
+        let join_handle = {
+            let conf = conf.clone();
+            let state = state.clone();
+
+            thread::Builder::new()
+                .name("uart".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, rx, state, stopped))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        Ok(Self {
+            framing: conf.framing,
+            tx: Mutex::new(tx),
+            state,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    /// Returns a future resolving with the next received frame
+    pub fn recv(&self) -> UartFrame<'_> {
+        UartFrame(self)
+    }
+
+    pub fn try_recv(&self) -> Option<Vec<u8>> {
+        self.state.get_mut(|state| state.frames.pop_front())
+    }
+
+    /// Waits for the next received frame, forever if `timeout` is `None`
+    pub fn recv_timeout(&self, timeout: Option<Duration>) -> Option<Vec<u8>> {
+        let condition = |state: &State| state.frames.is_empty();
+
+        if let Some(timeout) = timeout {
+            self.state.wait_timeout_while(timeout, condition);
+        } else {
+            self.state.wait_while(condition);
+        }
+
+        self.try_recv()
+    }
+
+    /// Sends `frame`, framed as the received frames are
+    pub fn send(&self, frame: &[u8]) -> Result<(), EspError> {
+        if frame.len() > self.framing.max_len() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut tx = self.tx.lock();
+
+        match self.framing {
+            Framing::Line { delimiter, .. } => {
+                Self::write_all(&mut tx, frame)?;
+                Self::write_all(&mut tx, &[delimiter])?;
+            }
+            Framing::LengthPrefixed { width, .. } => {
+                match width {
+                    PrefixWidth::U8 => Self::write_all(&mut tx, &[frame.len() as u8])?,
+                    PrefixWidth::U16 => {
+                        Self::write_all(&mut tx, &(frame.len() as u16).to_be_bytes())?
+                    }
+                }
+
+                Self::write_all(&mut tx, frame)?;
+            }
+            Framing::Timeout { .. } => Self::write_all(&mut tx, frame)?,
+        }
+
+        Ok(())
+    }
+
+    /// Waits until all the sent data is transmitted
+    pub fn flush(&self) -> Result<(), EspError> {
+        self.tx.lock().flush()
+    }
+
+    fn write_all(tx: &mut UartTxDriver<'static>, mut data: &[u8]) -> Result<(), EspError> {
+        while !data.is_empty() {
+            let written = tx.write(data)?;
+
+            data = &data[written..];
+        }
+
+        Ok(())
+    }
+
+    fn run(
+        conf: Configuration,
+        rx: UartRxDriver<'static>,
+        state: Arc<Waitable<State>>,
+        stopped: mpsc::Receiver<()>,
+    ) {
+        let timeout = match conf.framing {
+            Framing::Timeout { gap, .. } => gap.min(POLL_INTERVAL),
+            _ => POLL_INTERVAL,
+        };
+
+        let mut framer = Framer::new(conf.framing);
+        let mut buf = [0; 64];
+
+        loop {
+            match stopped.try_recv() {
+                Err(mpsc::TryRecvError::Empty) => (),
+                _ => break,
+            }
+
+            let read = match rx.read(&mut buf, TickType::from(timeout).0) {
+                Ok(read) => read,
+                Err(e) => {
+                    warn!("UART read failed: {}", e);
+                    continue;
+                }
+            };
+
+            let mut frames = Vec::new();
+
+            if read == 0 {
+                frames.extend(framer.idle(timeout));
+            } else {
+                for byte in &buf[..read] {
+                    frames.extend(framer.push(*byte));
+                }
+            }
+
+            if !frames.is_empty() {
+                state.get_mut(|state| {
+                    for frame in frames {
+                        if state.frames.len() >= conf.rx_queue {
+                            warn!("UART RX queue full, dropping the oldest frame");
+
+                            state.frames.pop_front();
+                        }
+
+                        state.frames.push_back(frame);
+                    }
+
+                    if let Some(waker) = state.waker.take() {
+                        waker.wake();
+                    }
+                });
+
+                state.cvar.notify_all();
+            }
+        }
+    }
+}
+
+impl Drop for EspUart {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        info!("Dropped");
+    }
+}
+
+pub struct UartFrame<'a>(&'a EspUart);
+
+impl<'a> Future for UartFrame<'a> {
+    type Output = Vec<u8>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.state.get_mut(|state| {
+            if let Some(frame) = state.frames.pop_front() {
+                Poll::Ready(frame)
+            } else {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+/// Splits a byte stream into frames
+struct Framer {
+    framing: Framing,
+    frame: Vec<u8>,
+    /// The length of the current length-prefixed frame, once its prefix is received
+    expected: Option<usize>,
+    /// For how long no byte was received
+    idle: Duration,
+    /// Whether the bytes are discarded until the next delimiter, after an overlong line
+    discarding: bool,
+    /// The number of bytes still to be discarded, after an overlong packet
+    skip: usize,
+}
+
+impl Framer {
+    fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            frame: Vec::new(),
+            expected: None,
+            idle: Duration::ZERO,
+            discarding: false,
+            skip: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        self.idle = Duration::ZERO;
+
+        match self.framing {
+            Framing::Line { delimiter, max_len } => {
+                if byte == delimiter {
+                    if core::mem::take(&mut self.discarding) {
+                        return None;
+                    }
+
+                    if delimiter == b'\n' && self.frame.last() == Some(&b'\r') {
+                        self.frame.pop();
+                    }
+
+                    Some(core::mem::take(&mut self.frame))
+                } else if self.discarding {
+                    None
+                } else if self.frame.len() >= max_len {
+                    warn!("UART line longer than {} bytes, discarding it", max_len);
+
+                    self.frame.clear();
+                    self.discarding = true;
+
+                    None
+                } else {
+                    self.frame.push(byte);
+                    None
+                }
+            }
+            Framing::LengthPrefixed { width, max_len } => {
+                if self.skip > 0 {
+                    self.skip -= 1;
+                    return None;
+                }
+
+                self.frame.push(byte);
+
+                if let Some(expected) = self.expected {
+                    if self.frame.len() == expected {
+                        self.expected = None;
+
+                        return Some(core::mem::take(&mut self.frame));
+                    }
+                } else {
+                    let prefix_len = match width {
+                        PrefixWidth::U8 => 1,
+                        PrefixWidth::U16 => 2,
+                    };
+
+                    if self.frame.len() == prefix_len {
+                        let len = self
+                            .frame
+                            .iter()
+                            .fold(0, |len, byte| (len << 8) | *byte as usize);
+
+                        self.frame.clear();
+
+                        if len > max_len {
+                            warn!("UART packet of {} bytes is too long, discarding it", len);
+
+                            self.skip = len;
+                        } else if len == 0 {
+                            return Some(Vec::new());
+                        } else {
+                            self.expected = Some(len);
+                        }
+                    }
+                }
+
+                None
+            }
+            Framing::Timeout { max_len, .. } => {
+                self.frame.push(byte);
+
+                if self.frame.len() >= max_len {
+                    Some(core::mem::take(&mut self.frame))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// To be called when no byte was received for `elapsed`
+    fn idle(&mut self, elapsed: Duration) -> Option<Vec<u8>> {
+        self.idle += elapsed;
+
+        match self.framing {
+            Framing::Timeout { gap, .. } if self.idle >= gap && !self.frame.is_empty() => {
+                Some(core::mem::take(&mut self.frame))
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,492 @@
+//! Encrypted credentials vault
+//!
+//! `EspVault` keeps the secrets of all services - WiFi passwords, MQTT credentials, API tokens
+//! and TLS private keys - in a single NVS namespace, each entry encrypted and authenticated
+//! with AES-256-GCM under a `VaultKey` supplied by the application (e.g. read from the eFuses
+//! or from an encrypted NVS keys partition):
+//!
+//! ```ignore
+//! let mut vault = EspVault::new(nvs_partition.clone(), VaultKey::new(key))?;
+//!
+//! vault.set_wifi_password("home", "secret")?;
+//!
+//! let password = vault.wifi_password("home")?.unwrap();
+//!
+//! wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+//!     ssid: "home".into(),
+//!     password: password.as_str().unwrap().into(),
+//!     ..Default::default()
+//! }))?;
+//!
+//! if vault.needs_rotation(SecretKind::ApiToken, "cloud", Duration::from_secs(30 * 24 * 3600))? {
+//!     request_new_token();
+//! }
+//! ```
+//!
+//! Secrets are returned as `Secret`s, whose `Debug` impl does not print their content and
+//! which are wiped from memory when dropped, so that they do not leak into logs by accident.
+//!
+//! Every entry records when it was last set, as a UNIX timestamp, which is only meaningful
+//! if the system time was set (e.g. by SNTP) at that time.
+use core::fmt;
+use core::sync::atomic::{compiler_fence, Ordering};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+use crate::private::hex;
+use crate::private::sha256;
+
+const NAMESPACE: &str = "vault";
+
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = 1 + 8 + NONCE_LEN + TAG_LEN;
+
+/// 2020-01-01, i.e. the earliest time considered as set
+const MIN_TIMESTAMP: u64 = 1577836800;
+
+fn wipe(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// The AES-256 key the vault entries are encrypted with
+pub struct VaultKey([u8; 32]);
+
+impl VaultKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+impl fmt::Debug for VaultKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VaultKey(<redacted>)")
+    }
+}
+
+impl Drop for VaultKey {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+/// A secret, not printed by `Debug` and wiped from memory when dropped
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self(secret.into())
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The secret as text, if it is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(&self.0).ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(secret: &str) -> Self {
+        Self::new(secret.as_bytes())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MqttCredentials {
+    pub username: String,
+    pub password: Secret,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum SecretKind {
+    WifiPassword,
+    MqttCredentials,
+    ApiToken,
+    /// A TLS private key, in PEM or DER format
+    TlsKey,
+}
+
+impl SecretKind {
+    fn prefix(&self) -> char {
+        match self {
+            Self::WifiPassword => 'w',
+            Self::MqttCredentials => 'm',
+            Self::ApiToken => 't',
+            Self::TlsKey => 'k',
+        }
+    }
+}
+
+pub struct EspVault<T: NvsPartitionId> {
+    nvs: EspNvs<T>,
+    key: VaultKey,
+}
+
+impl<T: NvsPartitionId> EspVault<T> {
+    pub fn new(partition: EspNvsPartition<T>, key: VaultKey) -> Result<Self, EspError> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+            key,
+        })
+    }
+
+    pub fn wifi_password(&self, name: &str) -> Result<Option<Secret>, EspError> {
+        self.get(SecretKind::WifiPassword, name)
+    }
+
+    pub fn set_wifi_password(&mut self, name: &str, password: &str) -> Result<(), EspError> {
+        self.set(SecretKind::WifiPassword, name, password.as_bytes())
+    }
+
+    pub fn mqtt_credentials(&self, name: &str) -> Result<Option<MqttCredentials>, EspError> {
+        let secret = if let Some(secret) = self.get(SecretKind::MqttCredentials, name)? {
+            secret
+        } else {
+            return Ok(None);
+        };
+
+        let data = secret.expose();
+
+        let credentials = data
+            .first()
+            .map(|len| 1 + *len as usize)
+            .filter(|end| *end <= data.len())
+            .and_then(|end| {
+                let username = core::str::from_utf8(&data[1..end]).ok()?;
+
+                Some(MqttCredentials {
+                    username: username.into(),
+                    password: Secret::new(&data[end..]),
+                })
+            })
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>)?;
+
+        Ok(Some(credentials))
+    }
+
+    pub fn set_mqtt_credentials(
+        &mut self,
+        name: &str,
+        credentials: &MqttCredentials,
+    ) -> Result<(), EspError> {
+        let username = credentials.username.as_bytes();
+
+        if username.len() > u8::MAX as usize {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let mut data = Secret::new(Vec::with_capacity(
+            1 + username.len() + credentials.password.len(),
+        ));
+
+        data.0.push(username.len() as u8);
+        data.0.extend_from_slice(username);
+        data.0.extend_from_slice(credentials.password.expose());
+
+        self.set(SecretKind::MqttCredentials, name, data.expose())
+    }
+
+    pub fn api_token(&self, name: &str) -> Result<Option<Secret>, EspError> {
+        self.get(SecretKind::ApiToken, name)
+    }
+
+    pub fn set_api_token(&mut self, name: &str, token: &str) -> Result<(), EspError> {
+        self.set(SecretKind::ApiToken, name, token.as_bytes())
+    }
+
+    pub fn tls_key(&self, name: &str) -> Result<Option<Secret>, EspError> {
+        self.get(SecretKind::TlsKey, name)
+    }
+
+    pub fn set_tls_key(&mut self, name: &str, key: &[u8]) -> Result<(), EspError> {
+        self.set(SecretKind::TlsKey, name, key)
+    }
+
+    pub fn contains(&self, kind: SecretKind, name: &str) -> Result<bool, EspError> {
+        self.nvs.contains(&Self::nvs_key(kind, name))
+    }
+
+    pub fn remove(&mut self, kind: SecretKind, name: &str) -> Result<bool, EspError> {
+        self.nvs.remove(&Self::nvs_key(kind, name))
+    }
+
+    /// Removes all the secrets
+    pub fn clear(&mut self) -> Result<(), EspError> {
+        self.nvs.remove_all()
+    }
+
+    /// When the secret was last set, as the duration since the UNIX epoch
+    pub fn rotated_at(&self, kind: SecretKind, name: &str) -> Result<Option<Duration>, EspError> {
+        let record = if let Some(record) = self.record(&Self::nvs_key(kind, name))? {
+            record
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(Duration::from_secs(Self::timestamp(&record))))
+    }
+
+    /// Whether the secret exists and was set more than `max_age` ago
+    ///
+    /// Always `false` while the system time is not set, or if it was not set when the secret was.
+    pub fn needs_rotation(
+        &self,
+        kind: SecretKind,
+        name: &str,
+        max_age: Duration,
+    ) -> Result<bool, EspError> {
+        let rotated_at = if let Some(rotated_at) = self.rotated_at(kind, name)? {
+            rotated_at.as_secs()
+        } else {
+            return Ok(false);
+        };
+
+        let now = Self::now();
+
+        Ok(rotated_at >= MIN_TIMESTAMP
+            && now >= MIN_TIMESTAMP
+            && now.saturating_sub(rotated_at) > max_age.as_secs())
+    }
+
+    fn get(&self, kind: SecretKind, name: &str) -> Result<Option<Secret>, EspError> {
+        let nvs_key = Self::nvs_key(kind, name);
+
+        let record = if let Some(record) = self.record(&nvs_key)? {
+            record
+        } else {
+            return Ok(None);
+        };
+
+        let timestamp = Self::timestamp(&record);
+
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&record[9..9 + NONCE_LEN]);
+
+        let mut tag = [0; TAG_LEN];
+        tag.copy_from_slice(&record[9 + NONCE_LEN..HEADER_LEN]);
+
+        let ciphertext = &record[HEADER_LEN..];
+
+        let mut secret = Secret::new(vec![0; ciphertext.len()]);
+
+        gcm(
+            &self.key,
+            false,
+            &nonce,
+            &Self::aad(&nvs_key, timestamp),
+            ciphertext,
+            &mut secret.0,
+            &mut tag,
+        )
+        .map_err(|e| {
+            warn!(
+                "Failed to decrypt vault entry {:?} {}, wrong key or tampered entry",
+                kind, name
+            );
+
+            e
+        })?;
+
+        Ok(Some(secret))
+    }
+
+    fn set(&mut self, kind: SecretKind, name: &str, secret: &[u8]) -> Result<(), EspError> {
+        let nvs_key = Self::nvs_key(kind, name);
+        let timestamp = Self::now();
+
+        let mut nonce = [0; NONCE_LEN];
+        unsafe { esp_fill_random(nonce.as_mut_ptr() as *mut _, nonce.len() as _) };
+
+        let mut record = vec![0; HEADER_LEN + secret.len()];
+
+        let mut tag = [0; TAG_LEN];
+
+        gcm(
+            &self.key,
+            true,
+            &nonce,
+            &Self::aad(&nvs_key, timestamp),
+            secret,
+            &mut record[HEADER_LEN..],
+            &mut tag,
+        )?;
+
+        record[0] = VERSION;
+        record[1..9].copy_from_slice(&timestamp.to_le_bytes());
+        record[9..9 + NONCE_LEN].copy_from_slice(&nonce);
+        record[9 + NONCE_LEN..HEADER_LEN].copy_from_slice(&tag);
+
+        self.nvs.set_blob(&nvs_key, &record)?;
+
+        info!("Vault entry {:?} {} set", kind, name);
+
+        Ok(())
+    }
+
+    fn record(&self, nvs_key: &str) -> Result<Option<Vec<u8>>, EspError> {
+        let len = if let Some(len) = self.nvs.blob_len(nvs_key)? {
+            len
+        } else {
+            return Ok(None);
+        };
+
+        let mut record = vec![0; len];
+
+        let len = self
+            .nvs
+            .get_blob(nvs_key, &mut record)?
+            .map(|record| record.len())
+            .unwrap_or(0);
+
+        record.truncate(len);
+
+        if record.len() < HEADER_LEN || record[0] != VERSION {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_VERSION>());
+        }
+
+        Ok(Some(record))
+    }
+
+    fn timestamp(record: &[u8]) -> u64 {
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&record[1..9]);
+
+        u64::from_le_bytes(timestamp)
+    }
+
+    /// The NVS key of an entry, which is limited to 15 characters: the kind prefix and a part
+    /// of the hash of the name, so that names of any length can be used
+    fn nvs_key(kind: SecretKind, name: &str) -> String {
+        let mut key = String::with_capacity(15);
+        key.push(kind.prefix());
+        key.push_str(&hex::encode(&sha256::digest(name.as_bytes())[..7]));
+
+        key
+    }
+
+    /// The data authenticated with each entry, so that entries cannot be swapped or backdated
+    fn aad(nvs_key: &str, timestamp: u64) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(nvs_key.len() + 8);
+
+        aad.extend_from_slice(nvs_key.as_bytes());
+        aad.extend_from_slice(&timestamp.to_le_bytes());
+
+        aad
+    }
+
+    fn now() -> u64 {
+        let now = unsafe { time(core::ptr::null_mut()) };
+
+        if now > 0 {
+            now as u64
+        } else {
+            0
+        }
+    }
+}
+
+fn gcm(
+    key: &VaultKey,
+    encrypt: bool,
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    input: &[u8],
+    output: &mut [u8],
+    tag: &mut [u8; TAG_LEN],
+) -> Result<(), EspError> {
+    let mut context: mbedtls_gcm_context = Default::default();
+
+    unsafe {
+        mbedtls_gcm_init(&mut context);
+
+        let result = if mbedtls_gcm_setkey(
+            &mut context,
+            mbedtls_cipher_id_t_MBEDTLS_CIPHER_ID_AES,
+            key.0.as_ptr(),
+            (key.0.len() * 8) as _,
+        ) != 0
+        {
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        } else if encrypt {
+            if mbedtls_gcm_crypt_and_tag(
+                &mut context,
+                MBEDTLS_GCM_ENCRYPT as _,
+                input.len() as _,
+                nonce.as_ptr(),
+                nonce.len() as _,
+                aad.as_ptr(),
+                aad.len() as _,
+                input.as_ptr(),
+                output.as_mut_ptr(),
+                tag.len() as _,
+                tag.as_mut_ptr(),
+            ) != 0
+            {
+                Err(EspError::from_infallible::<ESP_FAIL>())
+            } else {
+                Ok(())
+            }
+        } else if mbedtls_gcm_auth_decrypt(
+            &mut context,
+            input.len() as _,
+            nonce.as_ptr(),
+            nonce.len() as _,
+            aad.as_ptr(),
+            aad.len() as _,
+            tag.as_ptr(),
+            tag.len() as _,
+            input.as_ptr(),
+            output.as_mut_ptr(),
+        ) != 0
+        {
+            wipe(output);
+
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_CRC>())
+        } else {
+            Ok(())
+        };
+
+        mbedtls_gcm_free(&mut context);
+
+        result
+    }
+}
@@ -18,6 +18,7 @@ use esp_idf_hal::peripheral::Peripheral;
 
 use esp_idf_sys::*;
 
+use crate::errors::{esp_svc, Context, SvcError};
 use crate::eventloop::EspEventLoop;
 use crate::eventloop::{
     EspSubscription, EspSystemEventLoop, EspTypedEventDeserializer, EspTypedEventSource, System,
@@ -31,12 +32,22 @@ use crate::private::cstr::*;
 use crate::private::mutex;
 use crate::private::waitable::*;
 
+#[cfg(feature = "alloc")]
+pub mod mac_filter;
+#[cfg(all(
+    esp_idf_esp_wifi_nan_enable,
+    not(any(esp_idf_version_major = "4", esp_idf_version = "5.0"))
+))]
+pub mod nan;
+pub mod scan;
+
 pub mod config {
     use core::time::Duration;
 
     use esp_idf_sys::*;
 
     #[derive(Clone, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     pub enum ScanType {
         Active { min: Duration, max: Duration },
         Passive(Duration),
@@ -57,7 +68,35 @@ pub mod config {
         }
     }
 
+    /// Protected Management Frames (802.11w) mode
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[cfg_attr(feature = "std", derive(Hash))]
+    pub enum Pmf {
+        Disabled,
+        /// PMF is used if the peer supports it
+        Capable,
+        /// Only peers supporting PMF are accepted
+        Required,
+    }
+
+    impl Default for Pmf {
+        fn default() -> Self {
+            Self::Disabled
+        }
+    }
+
+    impl From<Pmf> for wifi_pmf_config_t {
+        fn from(pmf: Pmf) -> Self {
+            Self {
+                capable: pmf != Pmf::Disabled,
+                required: pmf == Pmf::Required,
+            }
+        }
+    }
+
     #[derive(Clone, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     pub struct ScanConfig {
         pub bssid: Option<[u8; 6]>,
         pub ssid: Option<heapless::String<32>>,
@@ -266,6 +305,7 @@ impl From<Newtype<&wifi_ap_record_t>> for AccessPointInfo {
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum WifiDeviceId {
     Ap,
     Sta,
@@ -321,6 +361,9 @@ static mut TX_CALLBACK: Option<Box<dyn FnMut(WifiDeviceId, &[u8], bool) + 'stati
 
 pub struct WifiDriver<'d> {
     status: Arc<mutex::Mutex<(WifiEvent, WifiEvent)>>,
+    sta_pmf: config::Pmf,
+    #[cfg(not(esp_idf_version_major = "4"))]
+    ap_pmf: config::Pmf,
     _subscription: EspSubscription<System>,
     #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
     _nvs: Option<EspDefaultNvsPartition>,
@@ -340,6 +383,9 @@ impl<'d> WifiDriver<'d> {
 
         Ok(Self {
             status,
+            sta_pmf: Default::default(),
+            #[cfg(not(esp_idf_version_major = "4"))]
+            ap_pmf: Default::default(),
             _subscription: subscription,
             _nvs: nvs,
             _p: PhantomData,
@@ -357,6 +403,9 @@ impl<'d> WifiDriver<'d> {
 
         Ok(Self {
             status,
+            sta_pmf: Default::default(),
+            #[cfg(not(esp_idf_version_major = "4"))]
+            ap_pmf: Default::default(),
             _subscription: subscription,
             _p: PhantomData,
         })
@@ -442,7 +491,7 @@ impl<'d> WifiDriver<'d> {
     pub fn start(&mut self) -> Result<(), EspError> {
         info!("Start requested");
 
-        esp!(unsafe { esp_wifi_start() })?;
+        esp_svc!("wifi", esp_wifi_start()).map_err(SvcError::into_logged)?;
 
         info!("Starting");
 
@@ -452,7 +501,7 @@ impl<'d> WifiDriver<'d> {
     pub fn stop(&mut self) -> Result<(), EspError> {
         info!("Stop requested");
 
-        esp!(unsafe { esp_wifi_stop() })?;
+        esp_svc!("wifi", esp_wifi_stop()).map_err(SvcError::into_logged)?;
 
         info!("Stopping");
 
@@ -462,7 +511,7 @@ impl<'d> WifiDriver<'d> {
     pub fn connect(&mut self) -> Result<(), EspError> {
         info!("Connect requested");
 
-        esp!(unsafe { esp_wifi_connect() })?;
+        esp_svc!("wifi", esp_wifi_connect()).map_err(SvcError::into_logged)?;
 
         info!("Connecting");
 
@@ -472,7 +521,7 @@ impl<'d> WifiDriver<'d> {
     pub fn disconnect(&mut self) -> Result<(), EspError> {
         info!("Disconnect requested");
 
-        esp!(unsafe { esp_wifi_disconnect() })?;
+        esp_svc!("wifi", esp_wifi_disconnect()).map_err(SvcError::into_logged)?;
 
         info!("Disconnecting");
 
@@ -560,35 +609,32 @@ impl<'d> WifiDriver<'d> {
     }
 
     pub fn set_configuration(&mut self, conf: &Configuration) -> Result<(), EspError> {
+        self.apply_configuration(conf)
+            .map_err(SvcError::into_logged)
+    }
+
+    fn apply_configuration(&mut self, conf: &Configuration) -> Result<(), SvcError> {
         info!("Setting configuration: {:?}", conf);
 
         match conf {
             Configuration::None => {
-                unsafe {
-                    esp!(esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_NULL))?;
-                }
+                esp_svc!("wifi", esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_NULL))?;
                 info!("Wifi mode NULL set");
             }
             Configuration::AccessPoint(ap_conf) => {
-                unsafe {
-                    esp!(esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_AP))?;
-                }
+                esp_svc!("wifi", esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_AP))?;
                 info!("Wifi mode AP set");
 
                 self.set_ap_conf(ap_conf)?;
             }
             Configuration::Client(client_conf) => {
-                unsafe {
-                    esp!(esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_STA))?;
-                }
+                esp_svc!("wifi", esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_STA))?;
                 info!("Wifi mode STA set");
 
                 self.set_sta_conf(client_conf)?;
             }
             Configuration::Mixed(client_conf, ap_conf) => {
-                unsafe {
-                    esp!(esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_APSTA))?;
-                }
+                esp_svc!("wifi", esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_APSTA))?;
                 info!("Wifi mode APSTA set");
 
                 self.set_sta_conf(client_conf)?;
@@ -601,6 +647,65 @@ impl<'d> WifiDriver<'d> {
         Ok(())
     }
 
+    /// Applies a new configuration to a started driver, without restarting it
+    ///
+    /// Only the interfaces whose configuration changed are touched: the access point keeps
+    /// serving its clients when only the client credentials change, and vice versa. If the
+    /// client was connected, it is disconnected and reconnected with the new configuration.
+    /// A change of the mode (e.g. from `Client` to `Mixed`) falls back to `set_configuration`.
+    pub fn update_configuration(&mut self, conf: &Configuration) -> Result<(), EspError> {
+        let current = self.get_configuration()?;
+
+        if current == *conf {
+            info!("Configuration unchanged");
+            return Ok(());
+        }
+
+        let sta_started = self.is_sta_started()?;
+        let sta_connected = self.is_sta_connected()?;
+
+        let mut reconnect = false;
+
+        if core::mem::discriminant(&current) != core::mem::discriminant(conf) {
+            if sta_connected {
+                self.disconnect()?;
+            }
+
+            self.set_configuration(conf)?;
+
+            reconnect = true;
+        } else {
+            info!("Updating configuration: {:?}", conf);
+
+            if let Some(ap_conf) = conf.as_ap_conf_ref() {
+                if current.as_ap_conf_ref() != Some(ap_conf) {
+                    self.set_ap_conf(ap_conf).map_err(SvcError::into_logged)?;
+                }
+            }
+
+            if let Some(client_conf) = conf.as_client_conf_ref() {
+                if current.as_client_conf_ref() != Some(client_conf) {
+                    if sta_connected {
+                        self.disconnect()?;
+                    }
+
+                    self.set_sta_conf(client_conf)
+                        .map_err(SvcError::into_logged)?;
+
+                    reconnect = true;
+                }
+            }
+
+            info!("Configuration updated");
+        }
+
+        if reconnect && sta_started && conf.as_client_conf_ref().is_some() {
+            self.connect()?;
+        }
+
+        Ok(())
+    }
+
     /// Scan for nearby, visible access points.
     ///
     /// It scans for all available access points nearby, but returns only the first `N` access points found.
@@ -748,6 +853,85 @@ impl<'d> WifiDriver<'d> {
         Ok(result)
     }
 
+    /// Scan all the channels, and recommend the least congested one of the country for the
+    /// SoftAP.
+    ///
+    /// The STA interface must be enabled, i.e. the driver started in the client or the mixed
+    /// mode, for scanning. See [`scan::best_channel()`] for how the channels are scored.
+    #[cfg(feature = "alloc")]
+    pub fn recommend_ap_channel(&mut self) -> Result<scan::ChannelScore, EspError> {
+        let aps = self.scan()?;
+
+        let mut country: wifi_country_t = Default::default();
+        esp!(unsafe { esp_wifi_get_country(&mut country) })?;
+
+        let first = country.schan.max(1);
+        let last = (country.schan + country.nchan).saturating_sub(1).min(14);
+
+        let score = scan::best_channel(&aps, first..=last)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_NOT_FOUND>)?;
+
+        info!("Recommending channel {:?}", score);
+
+        Ok(score)
+    }
+
+    /// Scan all the channels, and switch the SoftAP to the least congested one, returning it.
+    ///
+    /// Fails with `ESP_ERR_INVALID_STATE` if the configuration has no access point. As the
+    /// SoftAP follows the channel of the STA connection, it only makes a difference while the
+    /// STA is not connected.
+    ///
+    /// For more details see [`WifiDriver::recommend_ap_channel()`].
+    #[cfg(feature = "alloc")]
+    pub fn select_ap_channel(&mut self) -> Result<u8, EspError> {
+        let channel = self.recommend_ap_channel()?.channel;
+
+        let mut conf = self.get_configuration()?;
+
+        match &mut conf {
+            Configuration::AccessPoint(ap_conf) | Configuration::Mixed(_, ap_conf) => {
+                ap_conf.channel = channel;
+            }
+            _ => return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()),
+        }
+
+        self.set_configuration(&conf)?;
+
+        Ok(channel)
+    }
+
+    /// Get the results of an access point scan, filtered, deduplicated and sorted by `filter`,
+    /// into `buf`.
+    ///
+    /// The access points are fetched one at a time, so that neither the raw records nor the
+    /// access points dropped by the filter are stored. When more access points pass the
+    /// filter than fit in `buf`, those ranking first in the order of the filter are kept.
+    ///
+    /// Returns the number of access points stored in `buf`, and that of the found ones.
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    pub fn get_scan_result_into(
+        &mut self,
+        buf: &mut [AccessPointInfo],
+        filter: &scan::ScanFilter,
+    ) -> Result<(usize, usize), EspError> {
+        let scanned_count = self.get_scan_count()?;
+
+        let mut len = 0;
+        let mut ap_info_raw: wifi_ap_record_t = Default::default();
+
+        // Fails once all the records are fetched, and their list freed
+        while unsafe { esp_wifi_scan_get_ap_record(&mut ap_info_raw) } == ESP_OK {
+            len = filter.insert(buf, len, Newtype(&ap_info_raw).into());
+        }
+
+        filter.finish(&mut buf[..len]);
+
+        info!("Kept {} of {} access points", len, scanned_count);
+
+        Ok((len, scanned_count))
+    }
+
     pub fn set_callbacks<R, T>(
         &mut self,
         mut rx_callback: R,
@@ -799,6 +983,62 @@ impl<'d> WifiDriver<'d> {
         })
     }
 
+    pub fn get_sta_pmf(&self) -> config::Pmf {
+        self.sta_pmf
+    }
+
+    /// Sets the Protected Management Frames mode of the STA interface
+    ///
+    /// The `ClientConfiguration` of `embedded-svc` has no PMF setting, so the driver keeps the mode and
+    /// applies it together with that configuration: right away to the current one, and then to
+    /// every one set with `set_configuration` or `update_configuration`, which thus keep the
+    /// mode rather than resetting it. On an error, the previous mode stays in effect.
+    pub fn set_sta_pmf(&mut self, pmf: config::Pmf) -> Result<(), EspError> {
+        let mut wifi_config: wifi_config_t = Default::default();
+        esp!(unsafe { esp_wifi_get_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })?;
+
+        unsafe {
+            wifi_config.sta.pmf_cfg = pmf.into();
+        }
+
+        esp!(unsafe { esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })?;
+
+        self.sta_pmf = pmf;
+
+        info!("STA PMF set to {:?}", pmf);
+
+        Ok(())
+    }
+
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub fn get_ap_pmf(&self) -> config::Pmf {
+        self.ap_pmf
+    }
+
+    /// Sets the Protected Management Frames mode of the AP interface
+    ///
+    /// The `AccessPointConfiguration` of `embedded-svc` has no PMF setting, so the driver keeps
+    /// the mode and applies it together with that configuration: right away to the current one,
+    /// and then to every one set with `set_configuration` or `update_configuration`, which thus
+    /// keep the mode rather than resetting it. On an error, the previous mode stays in effect.
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub fn set_ap_pmf(&mut self, pmf: config::Pmf) -> Result<(), EspError> {
+        let mut wifi_config: wifi_config_t = Default::default();
+        esp!(unsafe { esp_wifi_get_config(wifi_interface_t_WIFI_IF_AP, &mut wifi_config) })?;
+
+        unsafe {
+            wifi_config.ap.pmf_cfg = pmf.into();
+        }
+
+        esp!(unsafe { esp_wifi_set_config(wifi_interface_t_WIFI_IF_AP, &mut wifi_config) })?;
+
+        self.ap_pmf = pmf;
+
+        info!("AP PMF set to {:?}", pmf);
+
+        Ok(())
+    }
+
     fn get_sta_conf(&self) -> Result<ClientConfiguration, EspError> {
         let mut wifi_config: wifi_config_t = Default::default();
         esp!(unsafe { esp_wifi_get_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })?;
@@ -810,9 +1050,9 @@ impl<'d> WifiDriver<'d> {
         Ok(result)
     }
 
-    fn set_sta_conf(&mut self, conf: &ClientConfiguration) -> Result<(), EspError> {
+    fn set_sta_conf(&mut self, conf: &ClientConfiguration) -> Result<(), SvcError> {
         info!("Checking current STA configuration");
-        let current_config = self.get_sta_conf()?;
+        let current_config = self.get_sta_conf().context("wifi", "esp_wifi_get_config")?;
 
         if current_config != *conf {
             info!("Setting STA configuration: {:?}", conf);
@@ -821,7 +1061,14 @@ impl<'d> WifiDriver<'d> {
                 sta: Newtype::<wifi_sta_config_t>::from(conf).0,
             };
 
-            esp!(unsafe { esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })?;
+            unsafe {
+                wifi_config.sta.pmf_cfg = self.sta_pmf.into();
+            }
+
+            esp_svc!(
+                "wifi",
+                esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config)
+            )?;
         } else {
             info!("Same STA configuration already present");
         }
@@ -842,9 +1089,9 @@ impl<'d> WifiDriver<'d> {
         Ok(result)
     }
 
-    fn set_ap_conf(&mut self, conf: &AccessPointConfiguration) -> Result<(), EspError> {
+    fn set_ap_conf(&mut self, conf: &AccessPointConfiguration) -> Result<(), SvcError> {
         info!("Checking current AP configuration");
-        let current_config = self.get_ap_conf()?;
+        let current_config = self.get_ap_conf().context("wifi", "esp_wifi_get_config")?;
 
         if current_config != *conf {
             info!("Setting AP configuration: {:?}", conf);
@@ -853,7 +1100,15 @@ impl<'d> WifiDriver<'d> {
                 ap: Newtype::<wifi_ap_config_t>::from(conf).0,
             };
 
-            esp!(unsafe { esp_wifi_set_config(wifi_interface_t_WIFI_IF_AP, &mut wifi_config) })?;
+            #[cfg(not(esp_idf_version_major = "4"))]
+            unsafe {
+                wifi_config.ap.pmf_cfg = self.ap_pmf.into();
+            }
+
+            esp_svc!(
+                "wifi",
+                esp_wifi_set_config(wifi_interface_t_WIFI_IF_AP, &mut wifi_config)
+            )?;
         } else {
             info!("Same AP configuration already present");
         }
@@ -1128,6 +1383,10 @@ impl<'d> EspWifi<'d> {
         self.driver_mut().set_configuration(conf)
     }
 
+    pub fn update_configuration(&mut self, conf: &Configuration) -> Result<(), EspError> {
+        self.driver_mut().update_configuration(conf)
+    }
+
     pub fn start(&mut self) -> Result<(), EspError> {
         self.driver_mut().start()
     }
@@ -1144,6 +1403,24 @@ impl<'d> EspWifi<'d> {
         self.driver_mut().disconnect()
     }
 
+    pub fn get_sta_pmf(&self) -> config::Pmf {
+        self.driver().get_sta_pmf()
+    }
+
+    pub fn set_sta_pmf(&mut self, pmf: config::Pmf) -> Result<(), EspError> {
+        self.driver_mut().set_sta_pmf(pmf)
+    }
+
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub fn get_ap_pmf(&self) -> config::Pmf {
+        self.driver().get_ap_pmf()
+    }
+
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub fn set_ap_pmf(&mut self, pmf: config::Pmf) -> Result<(), EspError> {
+        self.driver_mut().set_ap_pmf(pmf)
+    }
+
     /// Scan for nearby, visible access points.
     ///
     /// For more details see [`WifiDriver::scan_n()`].
@@ -1194,6 +1471,34 @@ impl<'d> EspWifi<'d> {
         self.driver_mut().get_scan_result()
     }
 
+    /// Scan all the channels, and recommend the least congested one for the SoftAP.
+    ///
+    /// For more details see [`WifiDriver::recommend_ap_channel()`].
+    #[cfg(feature = "alloc")]
+    pub fn recommend_ap_channel(&mut self) -> Result<scan::ChannelScore, EspError> {
+        self.driver_mut().recommend_ap_channel()
+    }
+
+    /// Scan all the channels, and switch the SoftAP to the least congested one.
+    ///
+    /// For more details see [`WifiDriver::select_ap_channel()`].
+    #[cfg(feature = "alloc")]
+    pub fn select_ap_channel(&mut self) -> Result<u8, EspError> {
+        self.driver_mut().select_ap_channel()
+    }
+
+    /// Get the results of an access point scan, filtered, deduplicated and sorted, into `buf`.
+    ///
+    /// For more details see [`WifiDriver::get_scan_result_into()`].
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    pub fn get_scan_result_into(
+        &mut self,
+        buf: &mut [AccessPointInfo],
+        filter: &scan::ScanFilter,
+    ) -> Result<(usize, usize), EspError> {
+        self.driver_mut().get_scan_result_into(buf, filter)
+    }
+
     fn attach_netif(&mut self) -> Result<(), EspError> {
         let _ = self.driver.stop();
 
@@ -1287,6 +1592,7 @@ impl<'d> Wifi for EspWifi<'d> {
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum WifiEvent {
     Ready,
 
@@ -1315,6 +1621,23 @@ pub enum WifiEvent {
     FtmReport,
     ActionTxStatus,
     RocDone,
+
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    NanStarted,
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    NanStopped,
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    NanServiceMatch,
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    NanReplied,
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    NanReceive,
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    NdpIndication,
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    NdpConfirm,
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    NdpTerminated,
 }
 
 impl EspTypedEventSource for WifiEvent {
@@ -1376,6 +1699,11 @@ impl EspTypedEventDeserializer<WifiEvent> for WifiEvent {
         } else if event_id == wifi_event_t_WIFI_EVENT_ROC_DONE {
             WifiEvent::RocDone
         } else {
+            #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+            if let Some(event) = Self::deserialize_nan(event_id) {
+                return f(&event);
+            }
+
             panic!("Unknown event ID: {}", event_id);
         };
 
@@ -1383,6 +1711,180 @@ impl EspTypedEventDeserializer<WifiEvent> for WifiEvent {
     }
 }
 
+impl WifiEvent {
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    #[allow(non_upper_case_globals)]
+    fn deserialize_nan(event_id: u32) -> Option<Self> {
+        let event = match event_id {
+            wifi_event_t_WIFI_EVENT_NAN_STARTED => WifiEvent::NanStarted,
+            wifi_event_t_WIFI_EVENT_NAN_STOPPED => WifiEvent::NanStopped,
+            wifi_event_t_WIFI_EVENT_NAN_SVC_MATCH => WifiEvent::NanServiceMatch,
+            wifi_event_t_WIFI_EVENT_NAN_REPLIED => WifiEvent::NanReplied,
+            wifi_event_t_WIFI_EVENT_NAN_RECEIVE => WifiEvent::NanReceive,
+            wifi_event_t_WIFI_EVENT_NDP_INDICATION => WifiEvent::NdpIndication,
+            wifi_event_t_WIFI_EVENT_NDP_CONFIRM => WifiEvent::NdpConfirm,
+            wifi_event_t_WIFI_EVENT_NDP_TERMINATED => WifiEvent::NdpTerminated,
+            _ => return None,
+        };
+
+        Some(event)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum DisconnectInitiator {
+    /// The peer sent a deauthentication or disassociation frame
+    Peer,
+    /// The disconnection was requested locally, or detected by the driver itself
+    /// (beacon timeout, failed authentication and the like)
+    Local,
+    /// The reason code is used by both sides
+    Unknown,
+}
+
+/// The details of a `WifiEvent::StaDisconnected` event
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StaDisconnectedEvent {
+    pub ssid: heapless::String<32>,
+    pub bssid: [u8; 6],
+    /// The reason code, either from the deauthentication or disassociation frame (below 200)
+    /// or assigned by the driver (200 and above)
+    pub reason: u8,
+}
+
+impl StaDisconnectedEvent {
+    /// Returns who initiated the disconnection
+    ///
+    /// As the driver reports a single reason code, this is a best guess from the codes which
+    /// only one side uses; the others, e.g. `UNSPECIFIED`, are `Unknown`.
+    #[allow(non_upper_case_globals)]
+    pub fn initiator(&self) -> DisconnectInitiator {
+        match self.reason as u32 {
+            wifi_err_reason_t_WIFI_REASON_AUTH_LEAVE
+            | wifi_err_reason_t_WIFI_REASON_ASSOC_TOOMANY
+            | wifi_err_reason_t_WIFI_REASON_NOT_AUTHED
+            | wifi_err_reason_t_WIFI_REASON_NOT_ASSOCED
+            | wifi_err_reason_t_WIFI_REASON_ASSOC_NOT_AUTHED => DisconnectInitiator::Peer,
+            wifi_err_reason_t_WIFI_REASON_AUTH_EXPIRE
+            | wifi_err_reason_t_WIFI_REASON_ASSOC_EXPIRE
+            | wifi_err_reason_t_WIFI_REASON_ASSOC_LEAVE
+            | wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT
+            | wifi_err_reason_t_WIFI_REASON_GROUP_KEY_UPDATE_TIMEOUT => DisconnectInitiator::Local,
+            reason if reason >= wifi_err_reason_t_WIFI_REASON_BEACON_TIMEOUT => {
+                DisconnectInitiator::Local
+            }
+            _ => DisconnectInitiator::Unknown,
+        }
+    }
+
+    /// Returns `true` if the AP deauthenticated or disassociated the station, as far as
+    /// `initiator` can tell
+    pub fn is_deauth(&self) -> bool {
+        self.initiator() == DisconnectInitiator::Peer
+    }
+}
+
+impl EspTypedEventSource for StaDisconnectedEvent {
+    fn source() -> *const ffi::c_char {
+        unsafe { WIFI_EVENT }
+    }
+
+    fn event_id() -> Option<i32> {
+        Some(wifi_event_t_WIFI_EVENT_STA_DISCONNECTED as _)
+    }
+}
+
+impl EspTypedEventDeserializer<StaDisconnectedEvent> for StaDisconnectedEvent {
+    fn deserialize<R>(
+        data: &crate::eventloop::EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a StaDisconnectedEvent) -> R,
+    ) -> R {
+        let event: &wifi_event_sta_disconnected_t = unsafe { data.as_payload() };
+
+        let ssid = &event.ssid[..(event.ssid_len as usize).min(event.ssid.len())];
+
+        f(&StaDisconnectedEvent {
+            ssid: core::str::from_utf8(ssid).unwrap_or("").into(),
+            bssid: event.bssid,
+            reason: event.reason,
+        })
+    }
+}
+
+/// The details of a `WifiEvent::ApStaConnected` event
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct ApStaConnectedEvent {
+    pub mac: [u8; 6],
+    pub aid: u8,
+}
+
+impl EspTypedEventSource for ApStaConnectedEvent {
+    fn source() -> *const ffi::c_char {
+        unsafe { WIFI_EVENT }
+    }
+
+    fn event_id() -> Option<i32> {
+        Some(wifi_event_t_WIFI_EVENT_AP_STACONNECTED as _)
+    }
+}
+
+impl EspTypedEventDeserializer<ApStaConnectedEvent> for ApStaConnectedEvent {
+    fn deserialize<R>(
+        data: &crate::eventloop::EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a ApStaConnectedEvent) -> R,
+    ) -> R {
+        let event: &wifi_event_ap_staconnected_t = unsafe { data.as_payload() };
+
+        f(&ApStaConnectedEvent {
+            mac: event.mac,
+            aid: event.aid,
+        })
+    }
+}
+
+/// The details of a `WifiEvent::ApStaDisconnected` event
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct ApStaDisconnectedEvent {
+    pub mac: [u8; 6],
+    pub aid: u8,
+    /// The reason code, as for `StaDisconnectedEvent::reason`
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    pub reason: u8,
+}
+
+impl EspTypedEventSource for ApStaDisconnectedEvent {
+    fn source() -> *const ffi::c_char {
+        unsafe { WIFI_EVENT }
+    }
+
+    fn event_id() -> Option<i32> {
+        Some(wifi_event_t_WIFI_EVENT_AP_STADISCONNECTED as _)
+    }
+}
+
+impl EspTypedEventDeserializer<ApStaDisconnectedEvent> for ApStaDisconnectedEvent {
+    fn deserialize<R>(
+        data: &crate::eventloop::EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a ApStaDisconnectedEvent) -> R,
+    ) -> R {
+        let event: &wifi_event_ap_stadisconnected_t = unsafe { data.as_payload() };
+
+        f(&ApStaDisconnectedEvent {
+            mac: event.mac,
+            aid: event.aid,
+            #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+            reason: event.reason as _,
+        })
+    }
+}
+
 pub struct WifiWait {
     _subscription: EspSubscription<System>,
     waitable: Arc<Waitable<()>>,
@@ -0,0 +1,244 @@
+//! MAC address filtering for the SoftAP
+//!
+//! An allow or a deny list of the stations which may associate with the AP, e.g. for closed
+//! installations. The list can be changed at runtime, and every station turned away is
+//! reported with a `MacFilterRejected` event on the system event loop:
+//!
+//! ```ignore
+//! let filter = EspMacFilter::new(
+//!     &sysloop,
+//!     &Configuration {
+//!         policy: MacFilterPolicy::Allow,
+//!         macs: vec![[0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56]],
+//!     },
+//! )?;
+//!
+//! let _subscription = sysloop.subscribe(|event: &MacFilterRejected| {
+//!     warn!("Rejected {:02x?}", event.mac);
+//! })?;
+//!
+//! filter.add([0x24, 0x0a, 0xc4, 0x65, 0x43, 0x21]);
+//! ```
+//!
+//! The Wi-Fi driver has no MAC filtering of its own, so a rejected station is deauthenticated
+//! as soon as it has associated, rather than refused the association. The stations already
+//! associated are checked against the list on every change of it.
+use core::ffi;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use super::ApStaConnectedEvent;
+
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSystemEventLoop, EspSystemSubscription,
+    EspTypedEventDeserializer, EspTypedEventSerializer, EspTypedEventSource,
+};
+use crate::private::mutex::Mutex;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum MacFilterPolicy {
+    /// Only the listed stations may associate
+    Allow,
+    /// All the stations but the listed ones may associate
+    Deny,
+}
+
+impl Default for MacFilterPolicy {
+    fn default() -> Self {
+        Self::Deny
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    pub policy: MacFilterPolicy,
+    pub macs: Vec<[u8; 6]>,
+}
+
+impl Configuration {
+    pub fn is_allowed(&self, mac: &[u8; 6]) -> bool {
+        self.macs.contains(mac) == (self.policy == MacFilterPolicy::Allow)
+    }
+}
+
+/// Posted on the system event loop when a station is deauthenticated by the filter
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct MacFilterRejected {
+    pub mac: [u8; 6],
+}
+
+impl EspTypedEventSource for MacFilterRejected {
+    fn source() -> *const ffi::c_char {
+        b"ESP-WIFI-MAC-FILTER\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<MacFilterRejected> for MacFilterRejected {
+    fn serialize<R>(
+        event: &MacFilterRejected,
+        f: impl for<'a> FnOnce(&'a EspEventPostData) -> R,
+    ) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<MacFilterRejected> for MacFilterRejected {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a MacFilterRejected) -> R,
+    ) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+pub struct EspMacFilter {
+    conf: Arc<Mutex<Configuration>>,
+    sysloop: EspSystemEventLoop,
+    _subscription: EspSystemSubscription,
+}
+
+impl EspMacFilter {
+    /// Starts filtering, deauthenticating the associated stations which are not allowed
+    pub fn new(sysloop: &EspSystemEventLoop, conf: &Configuration) -> Result<Self, EspError> {
+        let conf = Arc::new(Mutex::new(conf.clone()));
+
+        let subscription = {
+            let conf = conf.clone();
+            let s_sysloop = sysloop.clone();
+
+            sysloop.subscribe(move |event: &ApStaConnectedEvent| {
+                if !conf.lock().is_allowed(&event.mac) {
+                    Self::reject(&s_sysloop, &event.mac, event.aid as _);
+                }
+            })?
+        };
+
+        let filter = Self {
+            conf,
+            sysloop: sysloop.clone(),
+            _subscription: subscription,
+        };
+
+        filter.enforce();
+
+        info!("Started MAC filter");
+
+        Ok(filter)
+    }
+
+    pub fn configuration(&self) -> Configuration {
+        self.conf.lock().clone()
+    }
+
+    pub fn policy(&self) -> MacFilterPolicy {
+        self.conf.lock().policy
+    }
+
+    pub fn set_policy(&self, policy: MacFilterPolicy) {
+        self.conf.lock().policy = policy;
+
+        self.enforce();
+    }
+
+    pub fn macs(&self) -> Vec<[u8; 6]> {
+        self.conf.lock().macs.clone()
+    }
+
+    pub fn contains(&self, mac: &[u8; 6]) -> bool {
+        self.conf.lock().macs.contains(mac)
+    }
+
+    pub fn is_allowed(&self, mac: &[u8; 6]) -> bool {
+        self.conf.lock().is_allowed(mac)
+    }
+
+    /// Adds `mac` to the list, returning `false` if it is already in it
+    pub fn add(&self, mac: [u8; 6]) -> bool {
+        {
+            let mut conf = self.conf.lock();
+
+            if conf.macs.contains(&mac) {
+                return false;
+            }
+
+            conf.macs.push(mac);
+        }
+
+        self.enforce();
+
+        true
+    }
+
+    /// Removes `mac` from the list, returning `false` if it is not in it
+    pub fn remove(&self, mac: &[u8; 6]) -> bool {
+        {
+            let mut conf = self.conf.lock();
+
+            let len = conf.macs.len();
+            conf.macs.retain(|other| other != mac);
+
+            if conf.macs.len() == len {
+                return false;
+            }
+        }
+
+        self.enforce();
+
+        true
+    }
+
+    /// Deauthenticates the associated stations which are not allowed anymore
+    fn enforce(&self) {
+        let mut stas: wifi_sta_list_t = Default::default();
+
+        // Fails if the AP is not started, and then there is nobody to check
+        if unsafe { esp_wifi_ap_get_sta_list(&mut stas) } != ESP_OK {
+            return;
+        }
+
+        let rejected = stas.sta[..(stas.num as usize).min(stas.sta.len())]
+            .iter()
+            .map(|sta| sta.mac)
+            .filter(|mac| !self.is_allowed(mac))
+            .collect::<Vec<_>>();
+
+        for mac in rejected {
+            let mut aid = 0_u16;
+
+            if unsafe { esp_wifi_ap_get_sta_aid(mac.as_ptr(), &mut aid) } == ESP_OK {
+                Self::reject(&self.sysloop, &mac, aid);
+            }
+        }
+    }
+
+    fn reject(sysloop: &EspSystemEventLoop, mac: &[u8; 6], aid: u16) {
+        warn!("Rejecting station {:02x?}", mac);
+
+        if let Err(e) = esp!(unsafe { esp_wifi_deauth_sta(aid) }) {
+            warn!("Failed to deauthenticate station {:02x?}: {}", mac, e);
+        }
+
+        // Not waiting, as this may run on the event loop itself
+        if let Err(e) = sysloop.post(&MacFilterRejected { mac: *mac }, Some(Duration::ZERO)) {
+            warn!("Failed to post MAC filter event: {}", e);
+        }
+    }
+}
+
+impl Drop for EspMacFilter {
+    fn drop(&mut self) {
+        info!("Dropped");
+    }
+}
@@ -0,0 +1,512 @@
+//! Wi-Fi Aware (NAN) service discovery and datapaths
+//!
+//! Neighbor Awareness Networking lets the devices discover the services of each other, exchange
+//! short follow-up messages and set up IPv6 datapaths directly, without an access point, e.g.
+//! for a phone app to find and configure the devices around it:
+//!
+//! ```ignore
+//! let mut nan = EspNan::new(&mut wifi_driver, &Configuration::default())?;
+//!
+//! let publish_id = nan.publish(&PublishConfiguration {
+//!     service_name: "thermostat",
+//!     service_info: "v1",
+//!     datapath_required: true,
+//!     ..Default::default()
+//! })?;
+//!
+//! sysloop.subscribe(move |event: &NanReceiveEvent| {
+//!     info!("Message from {:02x?}: {}", event.peer_mac, event.service_info);
+//! })?;
+//!
+//! sysloop.subscribe(move |event: &NdpIndicationEvent| {
+//!     // Accepted from the event loop, or from a thread of the application
+//! })?;
+//! ```
+//!
+//! Requires ESP-IDF 5.1 or later, with `CONFIG_ESP_WIFI_NAN_ENABLE`. The Wi-Fi driver runs in
+//! the NAN mode while an `EspNan` is live, so it cannot be used as a station or an AP then.
+use core::ffi;
+use core::marker::PhantomData;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use super::WifiDriver;
+
+use crate::eventloop::{EspEventFetchData, EspTypedEventDeserializer, EspTypedEventSource};
+use crate::private::cstr::c_char;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The channel of the discovery windows
+    pub op_channel: u8,
+    /// The preference of the device for becoming the master of the cluster, 1 to 254
+    pub master_preference: u8,
+    /// The time scanning for the existing clusters, in seconds
+    pub scan_time: u8,
+    /// The time before the device may become the master of the cluster, in seconds
+    pub warm_up: u16,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            op_channel: 6,
+            master_preference: 2,
+            scan_time: 3,
+            warm_up: 5,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum PublishType {
+    /// The service is only announced to the subscribers looking for it
+    Solicited,
+    /// The service is also announced in every discovery window
+    Unsolicited,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum SubscribeType {
+    /// Looks for the service, which the solicited publishers answer
+    Active,
+    /// Only listens for the unsolicited publishers
+    Passive,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PublishConfiguration<'a> {
+    pub service_name: &'a str,
+    pub publish_type: PublishType,
+    /// Only the subscribers with that filter match, if not empty
+    pub matching_filter: &'a str,
+    /// Sent along with the announcements of the service
+    pub service_info: &'a str,
+    /// Posts a single `NanRepliedEvent` per subscriber, rather than one per reply
+    pub single_replied_event: bool,
+    /// Whether the subscribers are expected to request a datapath
+    pub datapath_required: bool,
+}
+
+impl<'a> Default for PublishConfiguration<'a> {
+    fn default() -> Self {
+        Self {
+            service_name: "",
+            publish_type: PublishType::Unsolicited,
+            matching_filter: "",
+            service_info: "",
+            single_replied_event: true,
+            datapath_required: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SubscribeConfiguration<'a> {
+    pub service_name: &'a str,
+    pub subscribe_type: SubscribeType,
+    /// Only the publishers with that filter match, if not empty
+    pub matching_filter: &'a str,
+    pub service_info: &'a str,
+    /// Posts a single `NanServiceMatchEvent` per publisher, rather than one per announcement
+    pub single_match_event: bool,
+}
+
+impl<'a> Default for SubscribeConfiguration<'a> {
+    fn default() -> Self {
+        Self {
+            service_name: "",
+            subscribe_type: SubscribeType::Passive,
+            matching_filter: "",
+            service_info: "",
+            single_match_event: true,
+        }
+    }
+}
+
+/// The NAN mode of the Wi-Fi driver, with the NAN network interface the datapaths are set up
+/// on
+pub struct EspNan<'a> {
+    netif: *mut esp_netif_t,
+    _p: PhantomData<&'a mut ()>,
+}
+
+impl<'a> EspNan<'a> {
+    /// Starts the NAN mode, joining or creating a cluster
+    ///
+    /// The driver must not be started, as it is switched to the NAN mode.
+    pub fn new(_driver: &'a mut WifiDriver<'_>, conf: &Configuration) -> Result<Self, EspError> {
+        let netif = unsafe { esp_netif_create_default_wifi_nan() };
+        if netif.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        let nan_conf = wifi_nan_config_t {
+            op_channel: conf.op_channel,
+            master_pref: conf.master_preference,
+            scan_time: conf.scan_time,
+            warm_up_sec: conf.warm_up,
+        };
+
+        if let Err(e) = esp!(unsafe { esp_wifi_nan_start(&nan_conf) }) {
+            unsafe { esp_netif_destroy_default_wifi(netif as *mut _) };
+
+            return Err(e);
+        }
+
+        info!("Started NAN on channel {}", conf.op_channel);
+
+        Ok(Self {
+            netif,
+            _p: PhantomData,
+        })
+    }
+
+    /// Publishes a service, returning its ID
+    pub fn publish(&mut self, conf: &PublishConfiguration) -> Result<u8, EspError> {
+        let mut publish_conf: wifi_nan_publish_cfg_t = Default::default();
+
+        copy_str(&mut publish_conf.service_name, conf.service_name)?;
+        copy_str(&mut publish_conf.matching_filter, conf.matching_filter)?;
+        copy_str(&mut publish_conf.svc_info, conf.service_info)?;
+
+        publish_conf.type_ = match conf.publish_type {
+            PublishType::Solicited => wifi_nan_service_type_t_NAN_PUBLISH_SOLICITED,
+            PublishType::Unsolicited => wifi_nan_service_type_t_NAN_PUBLISH_UNSOLICITED,
+        };
+        publish_conf.set_single_replied_event(conf.single_replied_event as _);
+        publish_conf.set_datapath_reqd(conf.datapath_required as _);
+
+        let id = unsafe { esp_wifi_nan_publish_service(&publish_conf) };
+
+        Self::service_id(id, conf.service_name)
+    }
+
+    /// Subscribes to a service, returning the ID of the subscription
+    pub fn subscribe(&mut self, conf: &SubscribeConfiguration) -> Result<u8, EspError> {
+        let mut subscribe_conf: wifi_nan_subscribe_cfg_t = Default::default();
+
+        copy_str(&mut subscribe_conf.service_name, conf.service_name)?;
+        copy_str(&mut subscribe_conf.matching_filter, conf.matching_filter)?;
+        copy_str(&mut subscribe_conf.svc_info, conf.service_info)?;
+
+        subscribe_conf.type_ = match conf.subscribe_type {
+            SubscribeType::Active => wifi_nan_service_type_t_NAN_SUBSCRIBE_ACTIVE,
+            SubscribeType::Passive => wifi_nan_service_type_t_NAN_SUBSCRIBE_PASSIVE,
+        };
+        subscribe_conf.set_single_match_event(conf.single_match_event as _);
+
+        let id = unsafe { esp_wifi_nan_subscribe_service(&subscribe_conf) };
+
+        Self::service_id(id, conf.service_name)
+    }
+
+    /// Cancels a publication or a subscription
+    pub fn cancel(&mut self, service_id: u8) -> Result<(), EspError> {
+        esp!(unsafe { esp_wifi_nan_cancel_service(service_id) })
+    }
+
+    /// Sends a follow-up message to a peer from one of the services, e.g. in answer to a
+    /// `NanServiceMatchEvent` or a `NanReceiveEvent`
+    pub fn send_message(
+        &mut self,
+        service_id: u8,
+        peer_service_id: u8,
+        peer_mac: &[u8; 6],
+        message: &str,
+    ) -> Result<(), EspError> {
+        let mut params = wifi_nan_followup_params_t {
+            inst_id: service_id,
+            peer_inst_id: peer_service_id,
+            peer_mac: *peer_mac,
+            ..Default::default()
+        };
+
+        copy_str(&mut params.svc_info, message)?;
+
+        esp!(unsafe { esp_wifi_nan_send_message(&mut params) })
+    }
+
+    /// Requests a datapath to the publisher of a matched service, returning the ID of the
+    /// datapath; its setup completes with a `NdpConfirmEvent`
+    pub fn request_datapath(
+        &mut self,
+        publish_id: u8,
+        peer_mac: &[u8; 6],
+        confirm_required: bool,
+    ) -> Result<u8, EspError> {
+        let mut request = wifi_nan_datapath_req_t {
+            pub_id: publish_id,
+            peer_mac: *peer_mac,
+            confirm_required,
+        };
+
+        match unsafe { esp_wifi_nan_datapath_req(&mut request) } {
+            0 => Err(EspError::from_infallible::<ESP_FAIL>()),
+            ndp_id => Ok(ndp_id),
+        }
+    }
+
+    /// Accepts or refuses the datapath requested by a `NdpIndicationEvent`
+    pub fn respond_datapath(
+        &mut self,
+        ndp_id: u8,
+        peer_mac: &[u8; 6],
+        accept: bool,
+    ) -> Result<(), EspError> {
+        let mut response = wifi_nan_datapath_resp_t {
+            accept,
+            ndp_id,
+            peer_mac: *peer_mac,
+        };
+
+        esp!(unsafe { esp_wifi_nan_datapath_resp(&mut response) })
+    }
+
+    pub fn end_datapath(&mut self, ndp_id: u8, peer_mac: &[u8; 6]) -> Result<(), EspError> {
+        let mut request = wifi_nan_datapath_end_req_t {
+            ndp_id,
+            peer_mac: *peer_mac,
+        };
+
+        esp!(unsafe { esp_wifi_nan_datapath_end(&mut request) })
+    }
+
+    /// The network interface of the datapaths
+    pub fn netif_handle(&self) -> *mut esp_netif_t {
+        self.netif
+    }
+
+    fn service_id(id: u8, service_name: &str) -> Result<u8, EspError> {
+        if id == 0 {
+            warn!("Failed to start NAN service {}", service_name);
+
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        } else {
+            info!("Started NAN service {} with ID {}", service_name, id);
+
+            Ok(id)
+        }
+    }
+}
+
+impl<'a> Drop for EspNan<'a> {
+    fn drop(&mut self) {
+        esp!(unsafe { esp_wifi_nan_stop() }).unwrap();
+
+        unsafe { esp_netif_destroy_default_wifi(self.netif as *mut _) };
+
+        info!("Dropped");
+    }
+}
+
+/// A subscriber matched a publisher
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct NanServiceMatchEvent {
+    pub subscribe_id: u8,
+    pub publish_id: u8,
+    pub peer_mac: [u8; 6],
+    /// Whether the publisher changed the ID of its service
+    pub publish_id_updated: bool,
+}
+
+/// A publisher answered a subscriber
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct NanRepliedEvent {
+    pub publish_id: u8,
+    pub subscribe_id: u8,
+    pub peer_mac: [u8; 6],
+}
+
+/// A follow-up message was received
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct NanReceiveEvent {
+    pub service_id: u8,
+    pub peer_service_id: u8,
+    pub peer_mac: [u8; 6],
+    pub service_info: heapless::String<64>,
+}
+
+/// A peer requested a datapath, to be answered with `EspNan::respond_datapath`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct NdpIndicationEvent {
+    pub publish_id: u8,
+    pub ndp_id: u8,
+    /// The NAN management interface address of the peer
+    pub peer_nmi: [u8; 6],
+    /// The NAN data interface address of the peer
+    pub peer_ndi: [u8; 6],
+}
+
+/// The setup of a datapath completed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct NdpConfirmEvent {
+    /// 0 if the datapath was set up
+    pub status: u8,
+    pub ndp_id: u8,
+    pub peer_nmi: [u8; 6],
+    pub peer_ndi: [u8; 6],
+    pub own_ndi: [u8; 6],
+}
+
+impl NdpConfirmEvent {
+    pub fn is_accepted(&self) -> bool {
+        self.status == 0
+    }
+}
+
+/// A datapath ended
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct NdpTerminatedEvent {
+    pub reason: u8,
+    pub ndp_id: u8,
+    pub peer_ndi: [u8; 6],
+}
+
+macro_rules! nan_event {
+    ($event:ty, $event_id:ident, $raw:ty, |$data:ident| $convert:expr) => {
+        impl EspTypedEventSource for $event {
+            fn source() -> *const ffi::c_char {
+                unsafe { WIFI_EVENT }
+            }
+
+            fn event_id() -> Option<i32> {
+                Some($event_id as _)
+            }
+        }
+
+        impl EspTypedEventDeserializer<$event> for $event {
+            fn deserialize<R>(
+                data: &EspEventFetchData,
+                f: &mut impl for<'a> FnMut(&'a $event) -> R,
+            ) -> R {
+                let $data: &$raw = unsafe { data.as_payload() };
+
+                f(&$convert)
+            }
+        }
+    };
+}
+
+nan_event!(
+    NanServiceMatchEvent,
+    wifi_event_t_WIFI_EVENT_NAN_SVC_MATCH,
+    wifi_event_nan_svc_match_t,
+    |event| NanServiceMatchEvent {
+        subscribe_id: event.subscribe_id,
+        publish_id: event.publish_id,
+        peer_mac: event.pub_if_mac,
+        publish_id_updated: event.update_pub_id,
+    }
+);
+
+nan_event!(
+    NanRepliedEvent,
+    wifi_event_t_WIFI_EVENT_NAN_REPLIED,
+    wifi_event_nan_replied_t,
+    |event| NanRepliedEvent {
+        publish_id: event.publish_id,
+        subscribe_id: event.subscribe_id,
+        peer_mac: event.sub_if_mac,
+    }
+);
+
+nan_event!(
+    NanReceiveEvent,
+    wifi_event_t_WIFI_EVENT_NAN_RECEIVE,
+    wifi_event_nan_receive_t,
+    |event| NanReceiveEvent {
+        service_id: event.inst_id,
+        peer_service_id: event.peer_inst_id,
+        peer_mac: event.peer_if_mac,
+        service_info: to_str(&event.peer_svc_info),
+    }
+);
+
+nan_event!(
+    NdpIndicationEvent,
+    wifi_event_t_WIFI_EVENT_NDP_INDICATION,
+    wifi_event_ndp_indication_t,
+    |event| NdpIndicationEvent {
+        publish_id: event.publish_id,
+        ndp_id: event.ndp_id,
+        peer_nmi: event.peer_nmi,
+        peer_ndi: event.peer_ndi,
+    }
+);
+
+nan_event!(
+    NdpConfirmEvent,
+    wifi_event_t_WIFI_EVENT_NDP_CONFIRM,
+    wifi_event_ndp_confirm_t,
+    |event| NdpConfirmEvent {
+        status: event.status,
+        ndp_id: event.ndp_id,
+        peer_nmi: event.peer_nmi,
+        peer_ndi: event.peer_ndi,
+        own_ndi: event.own_ndi,
+    }
+);
+
+nan_event!(
+    NdpTerminatedEvent,
+    wifi_event_t_WIFI_EVENT_NDP_TERMINATED,
+    wifi_event_ndp_terminated_t,
+    |event| NdpTerminatedEvent {
+        reason: event.reason,
+        ndp_id: event.ndp_id,
+        peer_ndi: event.init_ndi,
+    }
+);
+
+/// Copies `s` into a NUL-terminated `char` array, failing with `ESP_ERR_INVALID_SIZE` if it
+/// does not fit
+fn copy_str(buf: &mut [c_char], s: &str) -> Result<(), EspError> {
+    if s.len() >= buf.len() {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+    }
+
+    for (c, b) in buf.iter_mut().zip(s.bytes()) {
+        *c = b as _;
+    }
+
+    buf[s.len()] = 0;
+
+    Ok(())
+}
+
+/// The NUL-terminated string of a `char` array, up to its first non-ASCII byte
+fn to_str<const N: usize>(buf: &[c_char]) -> heapless::String<N> {
+    let mut s = heapless::String::new();
+
+    let bytes = buf.iter().map(|c| *c as u8).take_while(|b| *b != 0);
+
+    for b in bytes {
+        if !b.is_ascii() || s.push(b as char).is_err() {
+            break;
+        }
+    }
+
+    s
+}
@@ -0,0 +1,319 @@
+//! Post-processing of the access points found by a scan
+//!
+//! A scan reports every BSSID it heard, so the same network is usually listed once per access
+//! point (and per band) of it, in no particular order. A `ScanFilter` turns that into the list
+//! of networks a provisioning UI displays: one entry per SSID, with the strongest signal, of
+//! the accepted security and bands, sorted.
+//!
+//! ```ignore
+//! let filter = ScanFilter {
+//!     auth_methods: AuthMethod::None | AuthMethod::WPA2Personal | AuthMethod::WPA3Personal,
+//!     min_signal_strength: Some(-85),
+//!     ..Default::default()
+//! };
+//!
+//! let mut aps = wifi.scan()?;
+//! filter.apply_vec(&mut aps);
+//!
+//! // Or, without allocating, straight from the driver into a fixed buffer
+//! wifi.start_scan(&Default::default(), true)?;
+//!
+//! let mut aps: [AccessPointInfo; 10] = Default::default();
+//! let (len, _) = wifi.get_scan_result_into(&mut aps, &filter)?;
+//!
+//! for ap in &aps[..len] {
+//!     info!("{} ({} dBm)", ap.ssid, ap.signal_strength);
+//! }
+//! ```
+//!
+//! The scan also tells how congested the channels are, for the SoftAP to pick the least
+//! congested one with `best_channel`, or with `EspWifi::select_ap_channel` directly.
+use core::cmp::Ordering;
+
+use enumset::*;
+
+use embedded_svc::wifi::{AccessPointInfo, AuthMethod, SecondaryChannel};
+
+#[derive(EnumSetType, Debug, PartialOrd)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Band {
+    TwoPointFourGhz,
+    FiveGhz,
+}
+
+impl Band {
+    pub fn of(ap: &AccessPointInfo) -> Self {
+        if ap.channel > 14 {
+            Self::FiveGhz
+        } else {
+            Self::TwoPointFourGhz
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum SortOrder {
+    /// The strongest first
+    SignalStrength,
+    /// Alphabetically, ignoring the case
+    Ssid,
+    /// The lowest first
+    Channel,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScanFilter {
+    /// The security of the networks kept
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub auth_methods: EnumSet<AuthMethod>,
+    /// The bands of the networks kept
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub bands: EnumSet<Band>,
+    /// Drops the access points with a weaker RSSI, in dBm
+    pub min_signal_strength: Option<i8>,
+    /// Keeps the hidden networks, i.e. those with an empty SSID
+    pub include_hidden: bool,
+    /// Keeps one access point per SSID, the one with the strongest signal
+    ///
+    /// The hidden networks are never merged, as their SSIDs are unknown.
+    pub dedup: bool,
+    pub sort: Option<SortOrder>,
+}
+
+impl Default for ScanFilter {
+    fn default() -> Self {
+        Self {
+            auth_methods: EnumSet::all(),
+            bands: EnumSet::all(),
+            min_signal_strength: None,
+            include_hidden: false,
+            dedup: true,
+            sort: Some(SortOrder::SignalStrength),
+        }
+    }
+}
+
+impl ScanFilter {
+    /// Whether `ap` passes the filter, deduplication aside
+    pub fn matches(&self, ap: &AccessPointInfo) -> bool {
+        self.auth_methods.contains(ap.auth_method)
+            && self.bands.contains(Band::of(ap))
+            && self
+                .min_signal_strength
+                .map(|min| ap.signal_strength >= min)
+                .unwrap_or(true)
+            && (self.include_hidden || !ap.ssid.is_empty())
+    }
+
+    /// Filters, deduplicates and sorts `aps` in place, the access points kept moved to its
+    /// start
+    ///
+    /// Returns the number of access points kept, e.g. for a `heapless::Vec` to be truncated
+    /// to.
+    pub fn apply(&self, aps: &mut [AccessPointInfo]) -> usize {
+        let mut len = 0;
+
+        for index in 0..aps.len() {
+            if self.matches(&aps[index]) {
+                aps.swap(len, index);
+                len += 1;
+            }
+        }
+
+        if self.dedup {
+            // The strongest access point of every SSID first
+            aps[..len].sort_unstable_by(|a, b| {
+                a.ssid
+                    .as_str()
+                    .cmp(b.ssid.as_str())
+                    .then(b.signal_strength.cmp(&a.signal_strength))
+            });
+
+            let mut deduped = 0;
+
+            for index in 0..len {
+                if deduped == 0
+                    || aps[index].ssid.is_empty()
+                    || aps[index].ssid != aps[deduped - 1].ssid
+                {
+                    aps.swap(deduped, index);
+                    deduped += 1;
+                }
+            }
+
+            len = deduped;
+        }
+
+        if let Some(sort) = self.sort {
+            aps[..len].sort_unstable_by(|a, b| compare(sort, a, b));
+        }
+
+        len
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn apply_vec(&self, aps: &mut alloc::vec::Vec<AccessPointInfo>) {
+        let len = self.apply(aps);
+
+        aps.truncate(len);
+    }
+
+    /// Adds `ap` to the `len` access points kept so far in `buf`, as they are fetched one by
+    /// one, and returns their new number
+    ///
+    /// When `buf` is full, `ap` replaces the access point ranking last in the order of the
+    /// filter (or of the signal strength, without one) if it ranks before it.
+    pub(crate) fn insert(
+        &self,
+        buf: &mut [AccessPointInfo],
+        len: usize,
+        ap: AccessPointInfo,
+    ) -> usize {
+        if !self.matches(&ap) {
+            return len;
+        }
+
+        if self.dedup && !ap.ssid.is_empty() {
+            if let Some(other) = buf[..len].iter_mut().find(|other| other.ssid == ap.ssid) {
+                if ap.signal_strength > other.signal_strength {
+                    *other = ap;
+                }
+
+                return len;
+            }
+        }
+
+        if len < buf.len() {
+            buf[len] = ap;
+
+            return len + 1;
+        }
+
+        let sort = self.sort.unwrap_or(SortOrder::SignalStrength);
+
+        let last = buf
+            .iter_mut()
+            .max_by(|a, b| compare(sort, a, b))
+            .filter(|last| compare(sort, &ap, last) == Ordering::Less);
+
+        if let Some(last) = last {
+            *last = ap;
+        }
+
+        len
+    }
+
+    pub(crate) fn finish(&self, aps: &mut [AccessPointInfo]) {
+        if let Some(sort) = self.sort {
+            aps.sort_unstable_by(|a, b| compare(sort, a, b));
+        }
+    }
+}
+
+fn compare(sort: SortOrder, a: &AccessPointInfo, b: &AccessPointInfo) -> Ordering {
+    let signal_strength = b.signal_strength.cmp(&a.signal_strength);
+
+    match sort {
+        SortOrder::SignalStrength => signal_strength.then_with(|| compare_ssids(a, b)),
+        SortOrder::Ssid => compare_ssids(a, b).then(signal_strength),
+        SortOrder::Channel => a.channel.cmp(&b.channel).then(signal_strength),
+    }
+}
+
+fn compare_ssids(a: &AccessPointInfo, b: &AccessPointInfo) -> Ordering {
+    let a = a.ssid.bytes().map(|b| b.to_ascii_lowercase());
+    let b = b.ssid.bytes().map(|b| b.to_ascii_lowercase());
+
+    a.cmp(b)
+}
+
+/// The congestion of a 2.4 GHz channel
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct ChannelScore {
+    pub channel: u8,
+    /// The access points on the channel itself
+    pub ap_count: u16,
+    /// The access points on the channels overlapping with it, i.e. within 4 channels
+    pub overlapping_count: u16,
+    /// The sum, over the access points on the channel and on the overlapping ones, of their
+    /// signal strength above -100 dBm weighted by how much their channels overlap; the
+    /// lower the better
+    pub congestion: u32,
+}
+
+impl ChannelScore {
+    /// Scores `channel` from the access points found by a scan
+    ///
+    /// An access point using a 40 MHz channel counts on both its primary and its secondary
+    /// channel.
+    pub fn of(aps: &[AccessPointInfo], channel: u8) -> Self {
+        let mut score = Self {
+            channel,
+            ap_count: 0,
+            overlapping_count: 0,
+            congestion: 0,
+        };
+
+        for ap in aps
+            .iter()
+            .filter(|ap| Band::of(ap) == Band::TwoPointFourGhz)
+        {
+            let secondary = match ap.secondary_channel {
+                SecondaryChannel::None => None,
+                SecondaryChannel::Above => Some(ap.channel + 4),
+                SecondaryChannel::Below => ap.channel.checked_sub(4),
+            };
+
+            let distance = core::iter::once(ap.channel)
+                .chain(secondary)
+                .map(|other| (other as i16 - channel as i16).unsigned_abs())
+                .min()
+                .unwrap_or(u16::MAX);
+
+            if distance > 4 {
+                continue;
+            }
+
+            if distance == 0 {
+                score.ap_count += 1;
+            } else {
+                score.overlapping_count += 1;
+            }
+
+            let strength = (ap.signal_strength as i32 + 100).clamp(0, 100) as u32;
+
+            score.congestion += strength * (5 - distance as u32);
+        }
+
+        score
+    }
+
+    /// Whether the channel is one of 1, 6 and 11, which do not overlap with each other
+    pub fn is_non_overlapping(&self) -> bool {
+        matches!(self.channel, 1 | 6 | 11)
+    }
+}
+
+/// The least congested of `channels`, from the access points found by a scan
+///
+/// On a tie, the channels 1, 6 and 11 are preferred, as the networks around are likelier to
+/// settle on them and to stay off the others, and then the lowest channel.
+pub fn best_channel(
+    aps: &[AccessPointInfo],
+    channels: impl IntoIterator<Item = u8>,
+) -> Option<ChannelScore> {
+    channels
+        .into_iter()
+        .map(|channel| ChannelScore::of(aps, channel))
+        .min_by(|a, b| {
+            a.congestion
+                .cmp(&b.congestion)
+                .then(b.is_non_overlapping().cmp(&a.is_non_overlapping()))
+                .then(a.channel.cmp(&b.channel))
+        })
+}
@@ -0,0 +1,457 @@
+//! Wi-Fi link metrics collector
+//!
+//! Periodically samples the statistics of the STA connection (RSSI, channel and PHY mode)
+//! and publishes them as a `WifiMetrics` event on the system event loop.
+//!
+//! The Wi-Fi driver does not expose TX retry counters, the noise floor or the current PHY
+//! rate of the connection. With `Configuration::sniff` enabled, the metrics are therefore
+//! complemented with statistics of the frames received from the AP, gathered in
+//! promiscuous mode: their noise floor, the share of retransmitted frames and the PHY rate
+//! they were received at. Note that promiscuous mode has a CPU cost and cannot be used
+//! together with any other promiscuous mode user.
+use core::ffi;
+use core::time::Duration;
+
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSystemEventLoop, EspTypedEventDeserializer,
+    EspTypedEventSerializer, EspTypedEventSource,
+};
+#[cfg(feature = "metrics")]
+use crate::metrics::Gauge;
+use crate::private::mutex::{Mutex, RawMutex};
+
+#[cfg(feature = "metrics")]
+static CONNECTED: Gauge = Gauge::new("esp_wifi_connected", "Whether the STA is connected");
+#[cfg(feature = "metrics")]
+static RSSI: Gauge = Gauge::new("esp_wifi_rssi_dbm", "Average RSSI of the STA connection");
+#[cfg(feature = "metrics")]
+static CHANNEL: Gauge = Gauge::new("esp_wifi_channel", "Channel of the STA connection");
+#[cfg(feature = "metrics")]
+static DISCONNECTS: Gauge = Gauge::new(
+    "esp_wifi_disconnects",
+    "Connection losses since the collector was started",
+);
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The interval at which the metrics are published
+    pub interval: Duration,
+    /// The number of RSSI samples taken per interval
+    pub samples: u32,
+    /// Gather statistics of the frames received from the AP in promiscuous mode
+    pub sniff: bool,
+    pub stack_size: usize,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            samples: 5,
+            sniff: false,
+            stack_size: 3072,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum PhyMode {
+    LowRate,
+    B,
+    G,
+    Ht20,
+    Ht40,
+    He20,
+    Unknown,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct WifiMetrics {
+    pub connected: bool,
+    /// The RSSI of the last sample
+    pub rssi: Option<i8>,
+    pub rssi_min: Option<i8>,
+    pub rssi_max: Option<i8>,
+    pub rssi_avg: Option<i8>,
+    pub channel: u8,
+    pub phy_mode: PhyMode,
+    /// The number of connection losses observed since the collector was started
+    pub disconnects: u32,
+    /// The average noise floor of the frames received from the AP
+    pub noise_floor: Option<i8>,
+    /// The number of frames received from the AP during the interval
+    pub rx_frames: u32,
+    /// The number of those frames which were retransmissions
+    pub rx_retries: u32,
+    /// The PHY rate of the last frame received from the AP
+    pub rx_rate_kbps: Option<u32>,
+}
+
+impl EspTypedEventSource for WifiMetrics {
+    fn source() -> *const ffi::c_char {
+        b"ESP-WIFI-METRICS\0".as_ptr() as *const _
+    }
+}
+
+impl EspTypedEventSerializer<WifiMetrics> for WifiMetrics {
+    fn serialize<R>(event: &WifiMetrics, f: impl for<'a> FnOnce(&'a EspEventPostData) -> R) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Self::event_id(), event) })
+    }
+}
+
+impl EspTypedEventDeserializer<WifiMetrics> for WifiMetrics {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a WifiMetrics) -> R,
+    ) -> R {
+        f(unsafe { data.as_payload() })
+    }
+}
+
+#[derive(Default)]
+struct Sniffed {
+    mac: [u8; 6],
+    bssid: Option<[u8; 6]>,
+    frames: u32,
+    retries: u32,
+    noise_floor_sum: i32,
+    rate_kbps: Option<u32>,
+}
+
+static SNIFFED: Mutex<Sniffed> = Mutex::wrap(
+    RawMutex::new(),
+    Sniffed {
+        mac: [0; 6],
+        bssid: None,
+        frames: 0,
+        retries: 0,
+        noise_floor_sum: 0,
+        rate_kbps: None,
+    },
+);
+
+pub struct EspWifiMetrics {
+    latest: Arc<Mutex<Option<WifiMetrics>>>,
+    stop: mpsc::Sender<()>,
+    join_handle: Option<thread::JoinHandle<()>>,
+    sniff: bool,
+}
+
+impl EspWifiMetrics {
+    pub fn new(conf: &Configuration, sysloop: EspSystemEventLoop) -> Result<Self, EspError> {
+        if conf.samples == 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        if conf.sniff {
+            Self::start_sniffing()?;
+        }
+
+        let latest = Arc::new(Mutex::new(None));
+        let (stop, stopped) = mpsc::channel();
+
+        let join_handle = {
+            let conf = conf.clone();
+            let latest = latest.clone();
+
+            thread::Builder::new()
+                .name("wifi-metrics".into())
+                .stack_size(conf.stack_size)
+                .spawn(move || Self::run(conf, latest, sysloop, stopped))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?
+        };
+
+        info!("Started Wi-Fi metrics collector");
+
+        Ok(Self {
+            latest,
+            stop,
+            join_handle: Some(join_handle),
+            sniff: conf.sniff,
+        })
+    }
+
+    /// Returns the metrics of the last completed interval
+    pub fn latest(&self) -> Option<WifiMetrics> {
+        *self.latest.lock()
+    }
+
+    fn run(
+        conf: Configuration,
+        latest: Arc<Mutex<Option<WifiMetrics>>>,
+        sysloop: EspSystemEventLoop,
+        stopped: mpsc::Receiver<()>,
+    ) {
+        let period = conf.interval / conf.samples;
+
+        let mut connected = false;
+        let mut disconnects = 0;
+
+        loop {
+            let mut rssi = None;
+            let mut rssi_min = i8::MAX;
+            let mut rssi_max = i8::MIN;
+            let mut rssi_sum = 0_i32;
+            let mut rssi_count = 0_i32;
+            let mut channel = 0;
+            let mut phy_mode = PhyMode::Unknown;
+
+            for _ in 0..conf.samples {
+                match stopped.recv_timeout(period) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => (),
+                    _ => return,
+                }
+
+                let mut ap_info: wifi_ap_record_t = Default::default();
+
+                if unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) } == ESP_OK {
+                    connected = true;
+
+                    rssi = Some(ap_info.rssi);
+                    rssi_min = rssi_min.min(ap_info.rssi);
+                    rssi_max = rssi_max.max(ap_info.rssi);
+                    rssi_sum += ap_info.rssi as i32;
+                    rssi_count += 1;
+
+                    channel = ap_info.primary;
+                    phy_mode = Self::phy_mode(&ap_info);
+
+                    SNIFFED.lock().bssid = Some(ap_info.bssid);
+                } else {
+                    if connected {
+                        disconnects += 1;
+                    }
+
+                    connected = false;
+                    SNIFFED.lock().bssid = None;
+                }
+            }
+
+            let sniffed = {
+                let mut sniffed = SNIFFED.lock();
+
+                // Keep filtering on the current connection
+                let current = Sniffed {
+                    mac: sniffed.mac,
+                    bssid: sniffed.bssid,
+                    ..Default::default()
+                };
+
+                core::mem::replace(&mut *sniffed, current)
+            };
+
+            let metrics = WifiMetrics {
+                connected,
+                rssi,
+                rssi_min: (rssi_count > 0).then(|| rssi_min),
+                rssi_max: (rssi_count > 0).then(|| rssi_max),
+                rssi_avg: (rssi_count > 0).then(|| (rssi_sum / rssi_count) as i8),
+                channel,
+                phy_mode,
+                disconnects,
+                noise_floor: (sniffed.frames > 0)
+                    .then(|| (sniffed.noise_floor_sum / sniffed.frames as i32) as i8),
+                rx_frames: sniffed.frames,
+                rx_retries: sniffed.retries,
+                rx_rate_kbps: sniffed.rate_kbps,
+            };
+
+            debug!("Wi-Fi metrics: {:?}", metrics);
+
+            #[cfg(feature = "metrics")]
+            Self::publish(&metrics);
+
+            *latest.lock() = Some(metrics);
+
+            if let Err(e) = sysloop.post(&metrics, None) {
+                warn!("Failed to post Wi-Fi metrics event: {}", e);
+            }
+        }
+    }
+
+    #[allow(non_upper_case_globals)]
+    #[cfg(feature = "metrics")]
+    fn publish(metrics: &WifiMetrics) {
+        CONNECTED.set(&[], metrics.connected as u8 as f64);
+        CHANNEL.set(&[], metrics.channel as f64);
+        DISCONNECTS.set(&[], metrics.disconnects as f64);
+
+        if let Some(rssi) = metrics.rssi_avg {
+            RSSI.set(&[], rssi as f64);
+        } else {
+            RSSI.remove(&[]);
+        }
+    }
+
+    fn phy_mode(ap_info: &wifi_ap_record_t) -> PhyMode {
+        #[cfg(not(esp_idf_version_major = "4"))]
+        {
+            let mut mode: wifi_phy_mode_t = 0;
+
+            if unsafe { esp_wifi_sta_get_negotiated_phymode(&mut mode) } == ESP_OK {
+                return match mode {
+                    wifi_phy_mode_t_WIFI_PHY_MODE_LR => PhyMode::LowRate,
+                    wifi_phy_mode_t_WIFI_PHY_MODE_11B => PhyMode::B,
+                    wifi_phy_mode_t_WIFI_PHY_MODE_11G => PhyMode::G,
+                    wifi_phy_mode_t_WIFI_PHY_MODE_HT20 => PhyMode::Ht20,
+                    wifi_phy_mode_t_WIFI_PHY_MODE_HT40 => PhyMode::Ht40,
+                    wifi_phy_mode_t_WIFI_PHY_MODE_HE20 => PhyMode::He20,
+                    _ => PhyMode::Unknown,
+                };
+            }
+        }
+
+        // Best effort: the modes supported by the AP
+        if ap_info.phy_lr() != 0 {
+            PhyMode::LowRate
+        } else if ap_info.phy_11n() != 0 {
+            if ap_info.second == wifi_second_chan_t_WIFI_SECOND_CHAN_NONE {
+                PhyMode::Ht20
+            } else {
+                PhyMode::Ht40
+            }
+        } else if ap_info.phy_11g() != 0 {
+            PhyMode::G
+        } else if ap_info.phy_11b() != 0 {
+            PhyMode::B
+        } else {
+            PhyMode::Unknown
+        }
+    }
+
+    #[cfg(esp32c6)]
+    fn start_sniffing() -> Result<(), EspError> {
+        Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())
+    }
+
+    #[cfg(not(esp32c6))]
+    fn start_sniffing() -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_wifi_get_mac(
+                wifi_interface_t_WIFI_IF_STA,
+                SNIFFED.lock().mac.as_mut_ptr(),
+            )
+        })?;
+
+        let filter = wifi_promiscuous_filter_t {
+            filter_mask: WIFI_PROMIS_FILTER_MASK_DATA,
+        };
+
+        esp!(unsafe { esp_wifi_set_promiscuous_filter(&filter) })?;
+        esp!(unsafe { esp_wifi_set_promiscuous_rx_cb(Some(Self::sniffed)) })?;
+        esp!(unsafe { esp_wifi_set_promiscuous(true) })?;
+
+        Ok(())
+    }
+
+    #[cfg(not(esp32c6))]
+    unsafe extern "C" fn sniffed(buf: *mut ffi::c_void, _type: wifi_promiscuous_pkt_type_t) {
+        let packet = &*(buf as *const wifi_promiscuous_pkt_t);
+        let rx_ctrl = &packet.rx_ctrl;
+
+        // Frame control, duration, and the receiver and transmitter addresses
+        if (rx_ctrl.sig_len() as usize) < 16 {
+            return;
+        }
+
+        let header = packet.payload.as_slice(16);
+
+        let mut sniffed = SNIFFED.lock();
+
+        // Only count the frames sent by the AP to this station
+        if header[4..10] != sniffed.mac
+            || sniffed
+                .bssid
+                .map(|bssid| header[10..16] != bssid)
+                .unwrap_or(true)
+        {
+            return;
+        }
+
+        sniffed.frames += 1;
+
+        // The retry bit of the frame control flags
+        if header[1] & 0x08 != 0 {
+            sniffed.retries += 1;
+        }
+
+        sniffed.noise_floor_sum += rx_ctrl.noise_floor() as i8 as i32;
+        sniffed.rate_kbps = Self::rate_kbps(rx_ctrl);
+    }
+
+    #[cfg(not(esp32c6))]
+    fn rate_kbps(rx_ctrl: &wifi_pkt_rx_ctrl_t) -> Option<u32> {
+        // Single stream HT rates for MCS 0 - 7, in 100 kbps
+        const HT20: [u32; 8] = [65, 130, 195, 260, 390, 520, 585, 650];
+        const HT20_SGI: [u32; 8] = [72, 144, 217, 289, 433, 578, 650, 722];
+        const HT40: [u32; 8] = [135, 270, 405, 540, 810, 1080, 1215, 1350];
+        const HT40_SGI: [u32; 8] = [150, 300, 450, 600, 900, 1200, 1350, 1500];
+
+        match rx_ctrl.sig_mode() {
+            // Non-HT; the rate is a `wifi_phy_rate_t` value
+            0 => Some(match rx_ctrl.rate() {
+                0x00 => 1_000,
+                0x01 | 0x05 => 2_000,
+                0x02 | 0x06 => 5_500,
+                0x03 | 0x07 => 11_000,
+                0x08 => 48_000,
+                0x09 => 24_000,
+                0x0a => 12_000,
+                0x0b => 6_000,
+                0x0c => 54_000,
+                0x0d => 36_000,
+                0x0e => 18_000,
+                0x0f => 9_000,
+                _ => return None,
+            }),
+            // HT
+            1 => {
+                let mcs = rx_ctrl.mcs() as usize;
+                let streams = mcs / 8 + 1;
+
+                let table = match (rx_ctrl.cwb() != 0, rx_ctrl.sgi() != 0) {
+                    (false, false) => &HT20,
+                    (false, true) => &HT20_SGI,
+                    (true, false) => &HT40,
+                    (true, true) => &HT40_SGI,
+                };
+
+                Some(table[mcs % 8] * streams as u32 * 100)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Drop for EspWifiMetrics {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        if self.sniff {
+            unsafe {
+                esp_wifi_set_promiscuous(false);
+                esp_wifi_set_promiscuous_rx_cb(None);
+            }
+        }
+
+        *SNIFFED.lock() = Default::default();
+
+        info!("Dropped");
+    }
+}
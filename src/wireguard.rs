@@ -0,0 +1,256 @@
+//! WireGuard VPN client
+//!
+//! `EspWireguard` brings up a WireGuard tunnel to a peer, as an lwIP network interface, with
+//! the `esp_wireguard` component (<https://github.com/trombik/esp_wireguard>), which has to be
+//! added to the build as an extra component:
+//!
+//! ```ignore
+//! let mut wireguard = EspWireguard::new(&Configuration {
+//!     private_key: "IsvT72MAXzA8EtV0FSD1QT59B4x0oe6Uea5rd/dDzhE=".into(),
+//!     address: Ipv4Addr::new(192, 168, 4, 58),
+//!     netmask: Ipv4Addr::new(255, 255, 255, 0),
+//!     peer: Peer {
+//!         public_key: "FjrsQ/HD1Q8fUlFILIasDlOuajMeZov4NGqMJpkswiw=".into(),
+//!         endpoint: "vpn.example.com".into(),
+//!         persistent_keepalive: Some(Duration::from_secs(25)),
+//!         ..Default::default()
+//!     },
+//!     ..Default::default()
+//! })?;
+//!
+//! wireguard.connect()?;
+//!
+//! while !wireguard.is_up() {
+//!     thread::sleep(Duration::from_secs(1));
+//! }
+//! ```
+//!
+//! The handshakes are timestamped, so the system time has to be set (e.g. with `EspSntp`)
+//! before connecting, or the peer rejects them after it has seen a later one. The persistent
+//! keepalive keeps the NAT mappings between the device and the peer open, so that the peer
+//! can reach the device at any time.
+//!
+//! The component supports a single peer, whose allowed IPs are the subnet of the tunnel
+//! address.
+use core::ffi;
+use core::ptr;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+
+use ::log::*;
+
+use embedded_svc::ipv4::Ipv4Addr;
+
+use esp_idf_sys::*;
+
+use crate::private::cstr::{from_cstr_ptr, RawCstrs};
+
+/// The WireGuard port
+pub const DEFAULT_PORT: u16 = 51820;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct wireguard_config_t {
+    private_key: *const ffi::c_char,
+    listen_port: ffi::c_int,
+    fw_mark: u32,
+    public_key: *const ffi::c_char,
+    preshared_key: *const ffi::c_char,
+    allowed_ip: *const ffi::c_char,
+    allowed_ip_mask: *const ffi::c_char,
+    endpoint: *const ffi::c_char,
+    port: ffi::c_int,
+    persistent_keepalive: ffi::c_int,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct wireguard_ctx_t {
+    config: *mut wireguard_config_t,
+    netif: *mut ffi::c_void,
+    netif_default: *mut ffi::c_void,
+}
+
+extern "C" {
+    fn esp_wireguard_init(config: *mut wireguard_config_t, ctx: *mut wireguard_ctx_t) -> esp_err_t;
+
+    fn esp_wireguard_connect(ctx: *mut wireguard_ctx_t) -> esp_err_t;
+
+    fn esp_wireguard_set_default(ctx: *mut wireguard_ctx_t) -> esp_err_t;
+
+    fn esp_wireguard_disconnect(ctx: *mut wireguard_ctx_t) -> esp_err_t;
+
+    fn esp_wireguardif_peer_is_up(ctx: *mut wireguard_ctx_t) -> esp_err_t;
+
+    fn esp_wireguard_latest_handshake(ctx: *mut wireguard_ctx_t, result: *mut time_t) -> esp_err_t;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Peer {
+    /// The public key of the peer, in base64
+    pub public_key: String,
+    /// An optional pre-shared key, in base64, for post-quantum resistance
+    pub preshared_key: Option<String>,
+    /// The host name or IP address of the peer
+    pub endpoint: String,
+    pub port: u16,
+    /// How often an empty packet is sent to the peer, so as to keep the NAT mappings open
+    pub persistent_keepalive: Option<Duration>,
+}
+
+impl Default for Peer {
+    fn default() -> Self {
+        Self {
+            public_key: String::new(),
+            preshared_key: None,
+            endpoint: String::new(),
+            port: DEFAULT_PORT,
+            persistent_keepalive: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Configuration {
+    /// The private key of the device, in base64
+    pub private_key: String,
+    /// The local UDP port, or 0 for any
+    pub listen_port: u16,
+    /// The address of the device in the tunnel
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub address: Ipv4Addr,
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub netmask: Ipv4Addr,
+    pub peer: Peer,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            private_key: String::new(),
+            listen_port: 0,
+            address: Ipv4Addr::UNSPECIFIED,
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            peer: Default::default(),
+        }
+    }
+}
+
+/// A WireGuard tunnel, which is disconnected when dropped
+pub struct EspWireguard {
+    /// Both are referenced by the component until disconnected
+    config: Box<wireguard_config_t>,
+    ctx: Box<wireguard_ctx_t>,
+    _cstrs: RawCstrs,
+    connected: bool,
+}
+
+impl EspWireguard {
+    pub fn new(conf: &Configuration) -> Result<Self, EspError> {
+        let mut cstrs = RawCstrs::new();
+
+        let keepalive = conf
+            .peer
+            .persistent_keepalive
+            .map(|keepalive| keepalive.as_secs().max(1))
+            .unwrap_or(0);
+
+        let mut config = Box::new(wireguard_config_t {
+            private_key: cstrs.as_ptr(&conf.private_key),
+            listen_port: conf.listen_port as _,
+            fw_mark: 0,
+            public_key: cstrs.as_ptr(&conf.peer.public_key),
+            preshared_key: cstrs.as_nptr(conf.peer.preshared_key.as_ref()),
+            allowed_ip: cstrs.as_ptr(conf.address.to_string()),
+            allowed_ip_mask: cstrs.as_ptr(conf.netmask.to_string()),
+            endpoint: cstrs.as_ptr(&conf.peer.endpoint),
+            port: conf.peer.port as _,
+            persistent_keepalive: keepalive as _,
+        });
+
+        let mut ctx = Box::new(wireguard_ctx_t {
+            config: ptr::null_mut(),
+            netif: ptr::null_mut(),
+            netif_default: ptr::null_mut(),
+        });
+
+        esp!(unsafe { esp_wireguard_init(&mut *config, &mut *ctx) })?;
+
+        Ok(Self {
+            config,
+            ctx,
+            _cstrs: cstrs,
+            connected: false,
+        })
+    }
+
+    /// Adds the tunnel interface and starts the handshakes with the peer
+    ///
+    /// The endpoint of the peer is resolved first, so the device must be online.
+    pub fn connect(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_wireguard_connect(&mut *self.ctx) })?;
+
+        self.connected = true;
+
+        info!("Connecting to {:?}:{}", self.endpoint(), self.config.port);
+
+        Ok(())
+    }
+
+    /// Removes the tunnel interface, restoring the default interface if needed
+    pub fn disconnect(&mut self) -> Result<(), EspError> {
+        if self.connected {
+            esp!(unsafe { esp_wireguard_disconnect(&mut *self.ctx) })?;
+
+            self.connected = false;
+
+            info!("Disconnected");
+        }
+
+        Ok(())
+    }
+
+    /// Routes all the traffic through the tunnel, rather than only the one to its subnet
+    pub fn set_default(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_wireguard_set_default(&mut *self.ctx) })
+    }
+
+    /// Whether a handshake with the peer completed recently, i.e. the tunnel works
+    pub fn is_up(&mut self) -> bool {
+        self.connected && unsafe { esp_wireguardif_peer_is_up(&mut *self.ctx) } == ESP_OK
+    }
+
+    /// The time of the latest handshake with the peer, since the UNIX epoch
+    pub fn latest_handshake(&mut self) -> Result<Option<Duration>, EspError> {
+        if !self.connected {
+            return Ok(None);
+        }
+
+        let mut time: time_t = 0;
+
+        esp!(unsafe { esp_wireguard_latest_handshake(&mut *self.ctx, &mut time) })?;
+
+        Ok((time > 0).then(|| Duration::from_secs(time as _)))
+    }
+
+    fn endpoint(&self) -> &str {
+        unsafe { from_cstr_ptr(self.config.endpoint) }
+    }
+}
+
+impl Drop for EspWireguard {
+    fn drop(&mut self) {
+        if let Err(e) = self.disconnect() {
+            warn!("Failed to disconnect: {}", e);
+        }
+
+        info!("Dropped");
+    }
+}
+
+unsafe impl Send for EspWireguard {}
@@ -15,11 +15,17 @@ use esp_idf_sys::*;
 
 use crate::errors::EspIOError;
 use crate::handle::RawHandle;
+use crate::heap::BufferMemory;
+#[cfg(all(esp_idf_version = "4.4", esp_idf_comp_esp_netif_enabled))]
+use crate::netif::Interface;
 use crate::private::common::Newtype;
 use crate::private::cstr::RawCstrs;
 use crate::private::mutex::{Condvar, Mutex};
+#[cfg(feature = "traffic")]
+use crate::traffic::{self, Service};
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum EspWebSocketTransport {
     TransportUnknown,
     TransportOverTCP,
@@ -79,6 +85,7 @@ impl<'a> Drop for WebSocketEvent<'a> {
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum WebSocketClosingReason {
     PurposeFulfilled,
     GoingAway,
@@ -111,6 +118,7 @@ impl WebSocketClosingReason {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum WebSocketEventType<'a> {
     Connected,
     Disconnected,
@@ -187,6 +195,10 @@ pub struct EspWebSocketClientConfig<'a> {
     pub disable_pingpong_discon: bool,
     pub use_global_ca_store: bool,
     pub skip_cert_common_name_check: bool,
+    /// TCP keepalive of the connection, with `keep_alive_interval` and `keep_alive_count`
+    ///
+    /// Unlike the MQTT and raw TLS clients, there is no `TCP_NODELAY` option, as the WebSocket
+    /// client neither exposes its socket nor takes an external transport to set it on.
     pub keep_alive_idle: Option<time::Duration>,
     pub keep_alive_interval: Option<time::Duration>,
     pub keep_alive_count: Option<u16>,
@@ -195,9 +207,15 @@ pub struct EspWebSocketClientConfig<'a> {
     pub ping_interval_sec: time::Duration,
     #[cfg(esp_idf_version = "4.4")]
     pub if_name: Option<&'a str>,
+    /// Binds the client to the given network interface; takes precedence over `if_name`
+    #[cfg(all(esp_idf_version = "4.4", esp_idf_comp_esp_netif_enabled))]
+    pub interface: Option<Interface>,
     pub cert_pem: Option<&'a str>,
     pub client_cert: Option<&'a str>,
     pub client_key: Option<&'a str>,
+    /// Where the buffer of the client is allocated; the TLS buffers are allocated
+    /// later on by the client task and follow the `malloc` policy of the sdkconfig
+    pub buffer_memory: BufferMemory,
 }
 
 impl<'a> TryFrom<&'a EspWebSocketClientConfig<'a>> for (esp_websocket_client_config_t, RawCstrs) {
@@ -240,27 +258,13 @@ impl<'a> TryFrom<&'a EspWebSocketClientConfig<'a>> for (esp_websocket_client_con
 
             // NOTE: default keep_alive_* values are set below, so they are not explicitly listed
             // here
-            // some validation has to be done on if_name, so it is set to a default value first
-            // before overwriting it later after the validation
+            // if_name has to outlive this conversion, so it is set by the client itself,
+            // after the validation
             // to compile, the values are being set to a default value first before possibly
             // overwriting them
             ..Default::default()
         };
 
-        #[cfg(esp_idf_version = "4.4")]
-        if let Some(if_name) = conf.if_name {
-            if !(if_name.len() == 6 && if_name.is_ascii()) {
-                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>().into());
-            }
-            let mut s: [ffi::c_char; 6] = [ffi::c_char::default(); 6];
-            for (i, c) in if_name.chars().enumerate() {
-                s[i] = c as _;
-            }
-
-            let mut ifreq = ifreq { ifr_name: s };
-            c_conf.if_name = &mut ifreq as *mut ifreq;
-        }
-
         if let Some(idle) = conf.keep_alive_idle {
             c_conf.keep_alive_enable = true;
             c_conf.keep_alive_idle = idle.as_secs() as _;
@@ -285,10 +289,34 @@ impl<'a> TryFrom<&'a EspWebSocketClientConfig<'a>> for (esp_websocket_client_con
     }
 }
 
-struct UnsafeCallback(*mut Box<dyn FnMut(i32, *mut esp_websocket_event_data_t)>);
+impl<'a> EspWebSocketClientConfig<'a> {
+    #[cfg(esp_idf_version = "4.4")]
+    fn ifreq(&self) -> Result<Option<ifreq>, EspIOError> {
+        #[cfg(esp_idf_comp_esp_netif_enabled)]
+        if let Some(interface) = self.interface {
+            return Ok(Some(interface.ifreq()?));
+        }
+
+        if let Some(if_name) = self.if_name {
+            if !(if_name.len() == 6 && if_name.is_ascii()) {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>().into());
+            }
+            let mut s: [ffi::c_char; 6] = [ffi::c_char::default(); 6];
+            for (i, c) in if_name.chars().enumerate() {
+                s[i] = c as _;
+            }
+
+            Ok(Some(ifreq { ifr_name: s }))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+struct UnsafeCallback(*mut Box<dyn FnMut(i32, *mut esp_websocket_event_data_t) + Send>);
 
 impl UnsafeCallback {
-    fn from(boxed: &mut Box<Box<dyn FnMut(i32, *mut esp_websocket_event_data_t)>>) -> Self {
+    fn from(boxed: &mut Box<Box<dyn FnMut(i32, *mut esp_websocket_event_data_t) + Send>>) -> Self {
         Self(boxed.as_mut())
     }
 
@@ -375,7 +403,7 @@ pub struct EspWebSocketClient {
     // used for the timeout in every call to a send method in the c lib as the
     // `send` method in the `Sender` trait in embedded_svc::ws does not take a timeout itself
     timeout: TickType_t,
-    _callback: Box<dyn FnMut(i32, *mut esp_websocket_event_data_t)>,
+    _callback: Box<dyn FnMut(i32, *mut esp_websocket_event_data_t) + Send>,
 }
 
 impl EspWebSocketClient {
@@ -423,7 +451,7 @@ impl EspWebSocketClient {
         uri: impl AsRef<str>,
         config: &EspWebSocketClientConfig,
         timeout: time::Duration,
-        raw_callback: Box<dyn FnMut(i32, *mut esp_websocket_event_data_t) + 'static>,
+        raw_callback: Box<dyn FnMut(i32, *mut esp_websocket_event_data_t) + Send + 'static>,
     ) -> Result<Self, EspIOError> {
         let mut boxed_raw_callback = Box::new(raw_callback);
         let unsafe_callback = UnsafeCallback::from(&mut boxed_raw_callback);
@@ -433,7 +461,17 @@ impl EspWebSocketClient {
         let (mut conf, mut cstrs): (esp_websocket_client_config_t, RawCstrs) = config.try_into()?;
         conf.uri = cstrs.as_ptr(uri);
 
-        let handle = unsafe { esp_websocket_client_init(&conf) };
+        // The client copies the interface name during initialization
+        #[cfg(esp_idf_version = "4.4")]
+        let mut ifreq = config.ifreq()?;
+        #[cfg(esp_idf_version = "4.4")]
+        if let Some(ifreq) = ifreq.as_mut() {
+            conf.if_name = ifreq as *mut _;
+        }
+
+        let handle = config
+            .buffer_memory
+            .scope(|| unsafe { esp_websocket_client_init(&conf) });
 
         if handle.is_null() {
             return Err(EspError::from_infallible::<ESP_FAIL>().into());
@@ -491,6 +529,15 @@ impl EspWebSocketClient {
         event_id: i32,
         event_data: *mut ffi::c_void,
     ) {
+        #[cfg(feature = "traffic")]
+        if event_id == esp_websocket_event_id_t_WEBSOCKET_EVENT_DATA {
+            if let Some(event_data) =
+                unsafe { (event_data as *const esp_websocket_event_data_t).as_ref() }
+            {
+                traffic::record_rx(Service::Ws, event_data.data_len as _);
+            }
+        }
+
         unsafe {
             UnsafeCallback::from_ptr(event_handler_arg).call(event_id, event_data as _);
         }
@@ -507,7 +554,7 @@ impl EspWebSocketClient {
         let content = frame_data.as_ref().as_ptr();
         let content_length = frame_data.as_ref().len();
 
-        Self::check(match frame_type {
+        let result = Self::check(match frame_type {
             FrameType::Binary(false) => unsafe {
                 esp_websocket_client_send_bin(
                     self.handle,
@@ -527,7 +574,14 @@ impl EspWebSocketClient {
             _ => {
                 panic!("Unsupported sending operation");
             }
-        })
+        });
+
+        #[cfg(feature = "traffic")]
+        if let Ok(len) = result {
+            traffic::record_tx(Service::Ws, len);
+        }
+
+        result
     }
 }
 
@@ -540,6 +594,9 @@ impl Drop for EspWebSocketClient {
     }
 }
 
+// The C client synchronizes the calls on its handle with its own task, and the callback is `Send`
+unsafe impl Send for EspWebSocketClient {}
+
 impl RawHandle for EspWebSocketClient {
     type Handle = esp_websocket_client_handle_t;
 